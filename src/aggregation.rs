@@ -0,0 +1,603 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use tantivy::collector::{Collector, SegmentCollector};
+use tantivy::fastfield::FastFieldReader;
+use tantivy::schema::{Cardinality, Field, FieldType, IndexRecordOption, Schema};
+use tantivy::{DocId, DocSet, InvertedIndexReader, Score, SegmentLocalId, SegmentReader, TantivyError};
+
+use toshi_types::{
+    Aggregation, AggregationResult, DateHistogram, Error, HyperLogLog, RangeAggregation, TDigest, TermsAggregation, TermsBucket,
+};
+
+use crate::Result;
+
+/// Look up the field an aggregation reads, which must be a single valued fast field of one of `kinds`
+fn fast_field(schema: &Schema, name: &str, kinds: &[&str]) -> Result<(Field, FieldType)> {
+    let field = schema.get_field(name).ok_or_else(|| Error::UnknownIndexField(name.into()))?;
+    let kind = schema.get_field_entry(field).field_type().clone();
+    let (kind_name, cardinality) = match &kind {
+        FieldType::U64(o) => ("u64", o.get_fastfield_cardinality()),
+        FieldType::I64(o) => ("i64", o.get_fastfield_cardinality()),
+        FieldType::F64(o) => ("f64", o.get_fastfield_cardinality()),
+        FieldType::Date(o) => ("date", o.get_fastfield_cardinality()),
+        _ => ("", None),
+    };
+    if !kinds.contains(&kind_name) || cardinality != Some(Cardinality::SingleValue) {
+        return Err(Error::QueryError(format!(
+            "{} must be a single valued {} fast field to aggregate",
+            name,
+            kinds.join(" or ")
+        )));
+    }
+    Ok((field, kind))
+}
+
+/// The values of a single valued numeric or date fast field in one segment
+enum Numbers {
+    U64(FastFieldReader<u64>),
+    I64(FastFieldReader<i64>),
+    F64(FastFieldReader<f64>),
+    Date(FastFieldReader<tantivy::DateTime>),
+}
+
+impl Numbers {
+    fn open(reader: &SegmentReader, field: Field, kind: &FieldType) -> tantivy::Result<Self> {
+        let fast = reader.fast_fields();
+        let numbers = match kind {
+            FieldType::I64(_) => fast.i64(field).map(Numbers::I64),
+            FieldType::F64(_) => fast.f64(field).map(Numbers::F64),
+            FieldType::Date(_) => fast.date(field).map(Numbers::Date),
+            _ => fast.u64(field).map(Numbers::U64),
+        };
+        numbers.ok_or_else(|| TantivyError::SchemaError("Aggregated field is not a fast field".into()))
+    }
+
+    /// A document's value as a number, dates as seconds since the epoch, and its bits to tell
+    /// values apart by
+    fn get(&self, doc: DocId) -> (f64, u64) {
+        match self {
+            Numbers::U64(r) => {
+                let v = r.get(doc);
+                (v as f64, v)
+            }
+            Numbers::I64(r) => {
+                let v = r.get(doc);
+                (v as f64, v as u64)
+            }
+            Numbers::F64(r) => {
+                let v = r.get(doc);
+                (v, v.to_bits())
+            }
+            Numbers::Date(r) => {
+                let v = r.get(doc).timestamp();
+                (v as f64, v as u64)
+            }
+        }
+    }
+}
+
+/// What a metric aggregation keeps track of while values are collected
+#[derive(Debug, Clone)]
+pub enum Metric {
+    Avg { sum: f64, count: u64 },
+    Percentiles(TDigest, Vec<f64>),
+    Cardinality(HyperLogLog),
+}
+
+impl Metric {
+    /// The empty metric `aggregation` computes, `None` if it isn't a metric aggregation
+    fn empty(aggregation: &Aggregation) -> Result<Option<Self>> {
+        let metric = match aggregation {
+            Aggregation::Avg(_) => Metric::Avg { sum: 0.0, count: 0 },
+            Aggregation::Percentiles(p) => {
+                p.validate()?;
+                Metric::Percentiles(TDigest::default(), p.percents.clone())
+            }
+            Aggregation::Cardinality(c) => {
+                c.validate()?;
+                Metric::Cardinality(HyperLogLog::new(c.precision))
+            }
+            Aggregation::DateHistogram(_) | Aggregation::Range(_) | Aggregation::Terms(_) => return Ok(None),
+        };
+        Ok(Some(metric))
+    }
+
+    fn add(&mut self, (value, bits): (f64, u64)) {
+        match self {
+            Metric::Avg { sum, count } => {
+                *sum += value;
+                *count += 1;
+            }
+            Metric::Percentiles(digest, _) => digest.add(value),
+            Metric::Cardinality(distinct) => distinct.insert(bits),
+        }
+    }
+
+    fn merge(self, other: Metric) -> Self {
+        match (self, other) {
+            (Metric::Avg { sum, count }, Metric::Avg { sum: s, count: c }) => Metric::Avg {
+                sum: sum + s,
+                count: count + c,
+            },
+            (Metric::Percentiles(digest, percents), Metric::Percentiles(other, _)) => Metric::Percentiles(digest.merge(other), percents),
+            (Metric::Cardinality(distinct), Metric::Cardinality(other)) => Metric::Cardinality(distinct.merge(other)),
+            (this, _) => this,
+        }
+    }
+
+    /// The result the metric is returned as
+    pub fn result(self) -> AggregationResult {
+        match self {
+            Metric::Avg { sum, count } => AggregationResult::Average {
+                value: Some(sum / count as f64).filter(|_| count > 0),
+                count,
+            },
+            Metric::Percentiles(digest, percents) => AggregationResult::percentiles(digest, percents),
+            Metric::Cardinality(distinct) => AggregationResult::Cardinality {
+                value: distinct.count(),
+                hll: Some(distinct),
+            },
+        }
+    }
+}
+
+/// A metric aggregation's field and the empty metric each bucket starts from
+#[derive(Clone)]
+struct MetricSpec {
+    field: Field,
+    kind: FieldType,
+    empty: Metric,
+}
+
+impl MetricSpec {
+    fn new(aggregation: &Aggregation, schema: &Schema) -> Result<Self> {
+        let empty = Metric::empty(aggregation)?.ok_or_else(|| Error::QueryError("Not a metric aggregation".into()))?;
+        let (field, kind) = fast_field(schema, aggregation.field(), &["u64", "i64", "f64", "date"])?;
+        Ok(Self { field, kind, empty })
+    }
+
+    /// The metrics nested in a bucket aggregation, `within` names the kind of bucket aggregation
+    /// in the error for one that isn't a metric
+    fn nested(aggs: &BTreeMap<String, Aggregation>, schema: &Schema, within: &str) -> Result<Vec<(String, Self)>> {
+        aggs.iter()
+            .map(|(name, aggregation)| {
+                if !aggregation.is_metric() {
+                    return Err(Error::QueryError(format!(
+                        "Only metric aggregations can be nested in a {}, {} isn't one",
+                        within, name
+                    )));
+                }
+                Ok((name.clone(), Self::new(aggregation, schema)?))
+            })
+            .collect()
+    }
+
+    /// The segment's values of each metric's field, with the empty metric a bucket starts from
+    fn open_all(nested: &[(String, Self)], reader: &SegmentReader) -> tantivy::Result<Vec<(Numbers, Metric)>> {
+        nested
+            .iter()
+            .map(|(_, spec)| Ok((Numbers::open(reader, spec.field, &spec.kind)?, spec.empty.clone())))
+            .collect()
+    }
+}
+
+/// Computes a metric aggregation, like an average, over the documents a search matches
+pub struct MetricCollector(MetricSpec);
+
+/// The metric of a [`MetricCollector`] in one segment
+pub struct MetricSegment {
+    numbers: Numbers,
+    metric: Metric,
+}
+
+impl MetricCollector {
+    /// A collector for the metric `aggregation` over an index with `schema`
+    pub fn new(aggregation: &Aggregation, schema: &Schema) -> Result<Self> {
+        MetricSpec::new(aggregation, schema).map(MetricCollector)
+    }
+}
+
+impl Collector for MetricCollector {
+    type Fruit = Metric;
+    type Child = MetricSegment;
+
+    fn for_segment(&self, _: SegmentLocalId, reader: &SegmentReader) -> tantivy::Result<Self::Child> {
+        Ok(MetricSegment {
+            numbers: Numbers::open(reader, self.0.field, &self.0.kind)?,
+            metric: self.0.empty.clone(),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        false
+    }
+
+    fn merge_fruits(&self, fruits: Vec<Self::Fruit>) -> tantivy::Result<Self::Fruit> {
+        Ok(fruits.into_iter().fold(self.0.empty.clone(), Metric::merge))
+    }
+}
+
+impl SegmentCollector for MetricSegment {
+    type Fruit = Metric;
+
+    fn collect(&mut self, doc: DocId, _: Score) {
+        self.metric.add(self.numbers.get(doc));
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        self.metric
+    }
+}
+
+/// The documents in one bucket of a histogram and the metrics nested in it
+#[derive(Debug, Clone)]
+pub struct Bucket {
+    doc_count: u64,
+    metrics: Vec<Metric>,
+}
+
+impl Bucket {
+    fn merge(mut self, other: Bucket) -> Self {
+        self.doc_count += other.doc_count;
+        self.metrics = self.metrics.into_iter().zip(other.metrics).map(|(m, o)| m.merge(o)).collect();
+        self
+    }
+}
+
+/// Counts the documents a search matches in each bucket of a [`DateHistogram`], keyed by the
+/// start of the bucket in seconds since the epoch, and computes the metrics nested in the
+/// histogram over the documents of each bucket
+pub struct HistogramCollector {
+    field: Field,
+    kind: FieldType,
+    interval: i64,
+    offset: i32,
+    nested: Vec<(String, MetricSpec)>,
+}
+
+/// The buckets of a [`HistogramCollector`] in one segment, or the error for a timestamp that
+/// couldn't be bucketed
+pub struct HistogramSegment {
+    timestamps: Numbers,
+    interval: i64,
+    offset: i32,
+    nested: Vec<(Numbers, Metric)>,
+    buckets: Result<BTreeMap<i64, Bucket>>,
+}
+
+impl HistogramCollector {
+    /// A collector for `histogram` over an index with `schema`, the field it buckets by must be a
+    /// single valued date or i64 fast field and only metric aggregations can be nested in it
+    pub fn new(histogram: &DateHistogram, schema: &Schema) -> Result<Self> {
+        let (field, kind) = fast_field(schema, &histogram.field, &["date", "i64"])?;
+        let nested = MetricSpec::nested(&histogram.aggs, schema, "date histogram")?;
+        Ok(Self {
+            field,
+            kind,
+            interval: histogram.interval_secs()?,
+            offset: histogram.offset_secs()?,
+            nested,
+        })
+    }
+
+    /// The results of the aggregations nested in `histogram` for the bucket starting at each key,
+    /// buckets that held no documents getting the results of empty metrics
+    pub fn nested_results(
+        histogram: &DateHistogram,
+        mut buckets: BTreeMap<i64, Bucket>,
+    ) -> impl FnMut(i64) -> BTreeMap<String, AggregationResult> + '_ {
+        move |key| {
+            let mut metrics = buckets.remove(&key).map(|b| b.metrics.into_iter());
+            histogram
+                .aggs
+                .iter()
+                .filter_map(|(name, aggregation)| {
+                    let metric = match &mut metrics {
+                        Some(metrics) => metrics.next(),
+                        None => Metric::empty(aggregation).ok().flatten(),
+                    };
+                    metric.map(|m| (name.clone(), m.result()))
+                })
+                .collect()
+        }
+    }
+
+    /// How many documents are in each bucket
+    pub fn counts(buckets: &BTreeMap<i64, Bucket>) -> BTreeMap<i64, u64> {
+        buckets.iter().map(|(key, bucket)| (*key, bucket.doc_count)).collect()
+    }
+}
+
+impl Collector for HistogramCollector {
+    type Fruit = Result<BTreeMap<i64, Bucket>>;
+    type Child = HistogramSegment;
+
+    fn for_segment(&self, _: SegmentLocalId, reader: &SegmentReader) -> tantivy::Result<Self::Child> {
+        Ok(HistogramSegment {
+            timestamps: Numbers::open(reader, self.field, &self.kind)?,
+            interval: self.interval,
+            offset: self.offset,
+            nested: MetricSpec::open_all(&self.nested, reader)?,
+            buckets: Ok(BTreeMap::new()),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        false
+    }
+
+    fn merge_fruits(&self, fruits: Vec<Self::Fruit>) -> tantivy::Result<Self::Fruit> {
+        let mut buckets: BTreeMap<i64, Bucket> = BTreeMap::new();
+        for fruit in fruits {
+            let fruit = match fruit {
+                Ok(fruit) => fruit,
+                Err(e) => return Ok(Err(e)),
+            };
+            for (key, bucket) in fruit {
+                let merged = match buckets.remove(&key) {
+                    Some(existing) => existing.merge(bucket),
+                    None => bucket,
+                };
+                buckets.insert(key, merged);
+            }
+        }
+        Ok(Ok(buckets))
+    }
+}
+
+impl SegmentCollector for HistogramSegment {
+    type Fruit = Result<BTreeMap<i64, Bucket>>;
+
+    fn collect(&mut self, doc: DocId, _: Score) {
+        // Date and i64 timestamps are both whole seconds since the epoch
+        let (_, timestamp) = self.timestamps.get(doc);
+        let key = match DateHistogram::bucket_start(timestamp as i64, self.interval, self.offset) {
+            Ok(key) => key,
+            Err(e) => {
+                self.buckets = Err(e);
+                return;
+            }
+        };
+        let buckets = match &mut self.buckets {
+            Ok(buckets) => buckets,
+            Err(_) => return,
+        };
+        let nested = &self.nested;
+        let bucket = buckets.entry(key).or_insert_with(|| Bucket {
+            doc_count: 0,
+            metrics: nested.iter().map(|(_, empty)| empty.clone()).collect(),
+        });
+        bucket.doc_count += 1;
+        for ((numbers, _), metric) in self.nested.iter().zip(&mut bucket.metrics) {
+            metric.add(numbers.get(doc));
+        }
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        self.buckets
+    }
+}
+
+/// Counts the documents a search matches in each range of a [`RangeAggregation`], keyed the way
+/// [`RangeAggregation::keys`] keys them
+pub struct RangeCollector {
+    field: Field,
+    kind: FieldType,
+    ranges: RangeAggregation,
+}
+
+/// The counts of a [`RangeCollector`] in one segment, or the error for counting values in more
+/// ranges than a range aggregation can return
+pub struct RangeSegment {
+    numbers: Numbers,
+    ranges: RangeAggregation,
+    counts: Result<BTreeMap<i64, u64>>,
+    keys: Vec<i64>,
+}
+
+impl RangeCollector {
+    /// A collector for `ranges` over an index with `schema`, the field it buckets by must be a
+    /// single valued u64, i64 or f64 fast field
+    pub fn new(ranges: &RangeAggregation, schema: &Schema) -> Result<Self> {
+        ranges.validate()?;
+        let (field, kind) = fast_field(schema, &ranges.field, &["u64", "i64", "f64"])?;
+        Ok(Self {
+            field,
+            kind,
+            ranges: ranges.clone(),
+        })
+    }
+}
+
+impl Collector for RangeCollector {
+    type Fruit = Result<BTreeMap<i64, u64>>;
+    type Child = RangeSegment;
+
+    fn for_segment(&self, _: SegmentLocalId, reader: &SegmentReader) -> tantivy::Result<Self::Child> {
+        Ok(RangeSegment {
+            numbers: Numbers::open(reader, self.field, &self.kind)?,
+            ranges: self.ranges.clone(),
+            counts: Ok(BTreeMap::new()),
+            keys: Vec::new(),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        false
+    }
+
+    fn merge_fruits(&self, fruits: Vec<Self::Fruit>) -> tantivy::Result<Self::Fruit> {
+        let mut counts = BTreeMap::new();
+        for fruit in fruits {
+            let fruit = match fruit {
+                Ok(fruit) => fruit,
+                Err(e) => return Ok(Err(e)),
+            };
+            for (key, count) in fruit {
+                *counts.entry(key).or_insert(0) += count;
+            }
+        }
+        Ok(self.ranges.check_bucket_count(counts.len()).map(|_| counts))
+    }
+}
+
+impl SegmentCollector for RangeSegment {
+    type Fruit = Result<BTreeMap<i64, u64>>;
+
+    fn collect(&mut self, doc: DocId, _: Score) {
+        let counts = match &mut self.counts {
+            Ok(counts) => counts,
+            Err(_) => return,
+        };
+        let (value, _) = self.numbers.get(doc);
+        self.ranges.keys(value, &mut self.keys);
+        for key in &self.keys {
+            *counts.entry(*key).or_insert(0) += 1;
+        }
+        // Stop as soon as there are too many ranges, rather than counting every one of them first
+        if let Err(e) = self.ranges.check_bucket_count(counts.len()) {
+            self.counts = Err(e);
+        }
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        self.counts
+    }
+}
+
+/// Counts the documents a search matches that hold each term of a [`TermsAggregation`]'s field,
+/// and computes the metrics nested in it over the documents holding each term. The matching
+/// documents of a segment are gathered first, then the field's postings are walked once for the
+/// terms those documents hold.
+pub struct TermsCollector {
+    field: Field,
+    nested: Vec<(String, MetricSpec)>,
+}
+
+/// The matching documents of a [`TermsCollector`] in one segment
+pub struct TermsSegment {
+    inverted: Arc<InvertedIndexReader>,
+    nested: Vec<(Numbers, Metric)>,
+    docs: Vec<DocId>,
+}
+
+impl TermsCollector {
+    /// A collector for `terms` over an index with `schema`, the field it buckets by must be an
+    /// indexed text field and only metric aggregations can be nested in it
+    pub fn new(terms: &TermsAggregation, schema: &Schema) -> Result<Self> {
+        terms.validate()?;
+        let field = schema
+            .get_field(&terms.field)
+            .ok_or_else(|| Error::UnknownIndexField(terms.field.clone()))?;
+        match schema.get_field_entry(field).field_type() {
+            FieldType::Str(options) if options.get_indexing_options().is_some() => (),
+            _ => {
+                return Err(Error::QueryError(format!(
+                    "{} must be an indexed text field to aggregate its terms",
+                    terms.field
+                )))
+            }
+        }
+        Ok(Self {
+            field,
+            nested: MetricSpec::nested(&terms.aggs, schema, "terms aggregation")?,
+        })
+    }
+
+    /// The buckets of `terms` from the documents counted under each term, with the results of
+    /// the metrics nested in it
+    pub fn buckets(terms: &TermsAggregation, buckets: BTreeMap<String, Bucket>) -> Vec<TermsBucket> {
+        let buckets = buckets
+            .into_iter()
+            .map(|(key, bucket)| TermsBucket {
+                key,
+                doc_count: bucket.doc_count,
+                aggregations: terms
+                    .aggs
+                    .keys()
+                    .cloned()
+                    .zip(bucket.metrics.into_iter().map(Metric::result))
+                    .collect(),
+            })
+            .collect();
+        terms.top(buckets)
+    }
+}
+
+impl Collector for TermsCollector {
+    type Fruit = BTreeMap<String, Bucket>;
+    type Child = TermsSegment;
+
+    fn for_segment(&self, _: SegmentLocalId, reader: &SegmentReader) -> tantivy::Result<Self::Child> {
+        Ok(TermsSegment {
+            inverted: reader.inverted_index(self.field),
+            nested: MetricSpec::open_all(&self.nested, reader)?,
+            docs: Vec::new(),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        false
+    }
+
+    fn merge_fruits(&self, fruits: Vec<Self::Fruit>) -> tantivy::Result<Self::Fruit> {
+        let mut buckets: BTreeMap<String, Bucket> = BTreeMap::new();
+        for (key, bucket) in fruits.into_iter().flatten() {
+            let merged = match buckets.remove(&key) {
+                Some(existing) => existing.merge(bucket),
+                None => bucket,
+            };
+            buckets.insert(key, merged);
+        }
+        Ok(buckets)
+    }
+}
+
+impl SegmentCollector for TermsSegment {
+    type Fruit = BTreeMap<String, Bucket>;
+
+    fn collect(&mut self, doc: DocId, _: Score) {
+        self.docs.push(doc);
+    }
+
+    fn harvest(mut self) -> Self::Fruit {
+        let mut buckets = BTreeMap::new();
+        if self.docs.is_empty() {
+            return buckets;
+        }
+        self.docs.sort_unstable();
+        self.docs.dedup();
+        let mut terms = self.inverted.terms().stream();
+        while terms.advance() {
+            let mut postings = self.inverted.read_postings_from_terminfo(terms.value(), IndexRecordOption::Basic);
+            let mut bucket: Option<Bucket> = None;
+            // Both the postings and the matching documents are in order of their ids
+            let mut docs = self.docs.iter().peekable();
+            while postings.advance() {
+                let doc = postings.doc();
+                while docs.peek().map_or(false, |d| **d < doc) {
+                    docs.next();
+                }
+                match docs.peek() {
+                    Some(d) if **d == doc => (),
+                    Some(_) => continue,
+                    None => break,
+                }
+                let nested = &self.nested;
+                let bucket = bucket.get_or_insert_with(|| Bucket {
+                    doc_count: 0,
+                    metrics: nested.iter().map(|(_, empty)| empty.clone()).collect(),
+                });
+                bucket.doc_count += 1;
+                for ((numbers, _), metric) in self.nested.iter().zip(&mut bucket.metrics) {
+                    metric.add(numbers.get(doc));
+                }
+            }
+            if let Some(bucket) = bucket {
+                buckets.insert(String::from_utf8_lossy(terms.key()).into_owned(), bucket);
+            }
+        }
+        buckets
+    }
+}
@@ -5,14 +5,15 @@ use std::sync::{atomic::AtomicBool, Arc};
 
 use futures::prelude::*;
 use tokio::sync::oneshot::{self, Receiver, Sender};
-use tokio::sync::Mutex;
 use tracing::*;
 
 use toshi_server::cluster::rpc_server::RpcServer;
+use toshi_server::cluster::supervisor::supervisor;
 use toshi_server::commit::watcher;
 use toshi_server::index::{IndexCatalog, SharedCatalog};
 use toshi_server::router::Router;
 use toshi_server::settings::{Settings, HEADER, RPC_HEADER};
+use toshi_server::ttl::sweeper;
 use toshi_server::{shutdown, support};
 
 #[cfg_attr(tarpaulin, skip)]
@@ -32,10 +33,15 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let toshi = setup_toshi(s_clone.clone(), Arc::clone(&index_catalog), tx);
     if settings.experimental && settings.experimental_features.master {
         let update_cat = Arc::clone(&index_catalog);
-        tokio::spawn(async move {
-            let update = update_cat.lock().await;
-            update.update_remote_indexes().await
-        });
+        tokio::spawn(async move { update_cat.update_remote_indexes().await });
+        let features = &settings.experimental_features;
+        let supervisor = supervisor(
+            Arc::clone(&index_catalog),
+            features.health_check_interval,
+            features.health_check_timeout,
+            features.health_check_misses,
+        );
+        tokio::spawn(supervisor);
     }
     tokio::spawn(toshi);
     info!("Toshi running on {}:{}", &settings.host, &settings.port);
@@ -54,7 +60,7 @@ fn setup_logging(level: &str) {
 async fn setup_shutdown(shutdown_signal: Receiver<()>, index_catalog: SharedCatalog) -> Result<(), oneshot::error::RecvError> {
     shutdown_signal.await?;
     info!("Shutting down...");
-    index_catalog.lock().await.clear().await;
+    index_catalog.shutdown().await;
     Ok(())
 }
 
@@ -82,14 +88,11 @@ fn setup_catalog(settings: &Settings) -> SharedCatalog {
     };
 
     info!("Indexes: {:?}", index_catalog.get_collection().keys());
-    Arc::new(Mutex::new(index_catalog))
+    Arc::new(index_catalog)
 }
 
 #[cfg_attr(tarpaulin, skip)]
-fn run_data(
-    catalog: Arc<Mutex<IndexCatalog>>,
-    settings: Settings,
-) -> impl Future<Output = Result<(), tonic::transport::Error>> + Unpin + Send {
+fn run_data(catalog: SharedCatalog, settings: Settings) -> impl Future<Output = Result<(), tonic::transport::Error>> + Unpin + Send {
     let lock = Arc::new(AtomicBool::new(false));
     let commit_watcher = watcher(Arc::clone(&catalog), settings.auto_commit_duration, Arc::clone(&lock));
     let addr: IpAddr = settings
@@ -102,11 +105,12 @@ fn run_data(
     println!("{}", RPC_HEADER);
     info!("I am a data node...Binding to: {}", addr);
     tokio::spawn(commit_watcher);
+    tokio::spawn(sweeper(Arc::clone(&catalog), settings.ttl_sweep_interval));
     Box::pin(RpcServer::serve(bind, catalog))
 }
 
 #[cfg_attr(tarpaulin, skip)]
-fn run_master(catalog: Arc<Mutex<IndexCatalog>>, settings: Settings) -> impl Future<Output = Result<(), hyper::Error>> + Unpin + Send {
+fn run_master(catalog: SharedCatalog, settings: Settings) -> impl Future<Output = Result<(), hyper::Error>> + Unpin + Send {
     let bulk_lock = Arc::new(AtomicBool::new(false));
     let commit_watcher = watcher(Arc::clone(&catalog), settings.auto_commit_duration, Arc::clone(&bulk_lock));
     let addr: IpAddr = settings
@@ -118,6 +122,7 @@ fn run_master(catalog: Arc<Mutex<IndexCatalog>>, settings: Settings) -> impl Fut
     println!("{}", HEADER);
 
     tokio::spawn(commit_watcher);
+    tokio::spawn(sweeper(Arc::clone(&catalog), settings.ttl_sweep_interval));
     let watcher_clone = Arc::clone(&bulk_lock);
     let router = Router::new(catalog, watcher_clone);
     Box::pin(router.router_with_catalog(bind))
@@ -0,0 +1,63 @@
+use std::sync::Arc;
+
+use tokio::sync::Semaphore;
+
+use toshi_types::Error;
+
+use crate::Result;
+
+/// Runs the CPU and IO heavy work on indexes, such as commits and copies, on tokio's blocking
+/// threads so it never holds up the tasks serving requests. At most `size` pieces of work run at
+/// once, the rest wait for a free slot without taking up a thread.
+#[derive(Debug, Clone)]
+pub struct BlockingPool {
+    permits: Arc<Semaphore>,
+}
+
+impl BlockingPool {
+    pub fn new(size: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(size.max(1))),
+        }
+    }
+
+    pub async fn run<F, T>(&self, work: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let _permit = self.permits.acquire().await;
+        tokio::task::spawn_blocking(work).await.map_err(|_| Error::SpawnError)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_pool_is_bounded() -> Result<()> {
+        let pool = BlockingPool::new(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(std::sync::Mutex::new(0));
+        let work = (0..6).map(|_| {
+            let (running, most) = (Arc::clone(&running), Arc::clone(&most));
+            pool.run(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                {
+                    let mut most = most.lock().unwrap();
+                    *most = now.max(*most);
+                }
+                std::thread::sleep(Duration::from_millis(20));
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            })
+        });
+        futures::future::try_join_all(work).await?;
+        assert_eq!(*most.lock().unwrap(), 2);
+        Ok(())
+    }
+}
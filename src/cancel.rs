@@ -0,0 +1,225 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tantivy::query::{Explanation, Query, Scorer, Weight};
+use tantivy::{DocId, DocSet, Score, Searcher, SegmentReader, SkipResult};
+
+/// Set once whoever asked for a piece of work is no longer waiting on it, the work checks it as it
+/// goes and stops early
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// The flag behind this token, for work outside this crate to check
+    pub fn flag(&self) -> &Arc<AtomicBool> {
+        &self.0
+    }
+
+    /// A guard that cancels this token when it is dropped. Handlers hold one across their awaits
+    /// so that hyper dropping the handler when the client goes away stops the work behind it.
+    pub fn guard(&self) -> CancelGuard {
+        CancelGuard(Some(self.clone()))
+    }
+
+    /// Wrap a query so it stops matching documents once this token is cancelled
+    pub fn wrap(&self, query: Box<dyn Query>) -> Box<dyn Query> {
+        Box::new(CancellableQuery {
+            inner: query,
+            token: self.clone(),
+        })
+    }
+}
+
+/// Cancels its token when dropped, unless it was disarmed because the work finished
+#[derive(Debug)]
+pub struct CancelGuard(Option<CancelToken>);
+
+impl CancelGuard {
+    pub fn disarm(mut self) {
+        self.0.take();
+    }
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        if let Some(token) = self.0.take() {
+            token.cancel();
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CancellableQuery {
+    inner: Box<dyn Query>,
+    token: CancelToken,
+}
+
+impl Clone for CancellableQuery {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.box_clone(),
+            token: self.token.clone(),
+        }
+    }
+}
+
+impl Query for CancellableQuery {
+    fn weight(&self, searcher: &Searcher, scoring_enabled: bool) -> tantivy::Result<Box<dyn Weight>> {
+        Ok(Box::new(CancellableWeight {
+            inner: self.inner.weight(searcher, scoring_enabled)?,
+            token: self.token.clone(),
+        }))
+    }
+
+    fn query_terms(&self, term_set: &mut std::collections::BTreeSet<tantivy::Term>) {
+        self.inner.query_terms(term_set)
+    }
+}
+
+struct CancellableWeight {
+    inner: Box<dyn Weight>,
+    token: CancelToken,
+}
+
+impl Weight for CancellableWeight {
+    fn scorer(&self, reader: &SegmentReader) -> tantivy::Result<Box<dyn Scorer>> {
+        Ok(Box::new(CancellableScorer {
+            inner: self.inner.scorer(reader)?,
+            token: self.token.clone(),
+        }))
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> tantivy::Result<Explanation> {
+        self.inner.explain(reader, doc)
+    }
+}
+
+/// Ends the scorer's documents early once the token is cancelled, which stops the collection
+struct CancellableScorer {
+    inner: Box<dyn Scorer>,
+    token: CancelToken,
+}
+
+impl DocSet for CancellableScorer {
+    fn advance(&mut self) -> bool {
+        !self.token.is_cancelled() && self.inner.advance()
+    }
+
+    fn skip_next(&mut self, target: DocId) -> SkipResult {
+        if self.token.is_cancelled() {
+            return SkipResult::End;
+        }
+        self.inner.skip_next(target)
+    }
+
+    fn doc(&self) -> DocId {
+        self.inner.doc()
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.inner.size_hint()
+    }
+}
+
+impl Scorer for CancellableScorer {
+    fn score(&mut self) -> Score {
+        self.inner.score()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::collector::{Collector, SegmentCollector};
+    use tantivy::query::AllQuery;
+    use tantivy::schema::{SchemaBuilder, INDEXED};
+    use tantivy::{doc, Index, SegmentLocalId};
+
+    use super::*;
+
+    /// Counts the documents it sees and cancels the search after `stop_after` of them
+    struct CancelAfter {
+        token: CancelToken,
+        stop_after: usize,
+    }
+
+    struct CancelAfterSegment {
+        token: CancelToken,
+        stop_after: usize,
+        seen: usize,
+    }
+
+    impl Collector for CancelAfter {
+        type Fruit = usize;
+        type Child = CancelAfterSegment;
+
+        fn for_segment(&self, _: SegmentLocalId, _: &SegmentReader) -> tantivy::Result<Self::Child> {
+            Ok(CancelAfterSegment {
+                token: self.token.clone(),
+                stop_after: self.stop_after,
+                seen: 0,
+            })
+        }
+
+        fn requires_scoring(&self) -> bool {
+            false
+        }
+
+        fn merge_fruits(&self, fruits: Vec<usize>) -> tantivy::Result<usize> {
+            Ok(fruits.into_iter().sum())
+        }
+    }
+
+    impl SegmentCollector for CancelAfterSegment {
+        type Fruit = usize;
+
+        fn collect(&mut self, _: DocId, _: Score) {
+            self.seen += 1;
+            if self.seen == self.stop_after {
+                self.token.cancel();
+            }
+        }
+
+        fn harvest(self) -> usize {
+            self.seen
+        }
+    }
+
+    #[test]
+    fn test_guard_cancels_on_drop() {
+        let token = CancelToken::default();
+        token.guard().disarm();
+        assert!(!token.is_cancelled());
+        drop(token.guard());
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn test_cancel_stops_collection() -> tantivy::Result<()> {
+        let mut builder = SchemaBuilder::new();
+        let num = builder.add_u64_field("num", INDEXED);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 30_000_000)?;
+        for i in 0..1000u64 {
+            writer.add_document(doc!(num => i));
+        }
+        writer.commit()?;
+
+        let searcher = index.reader()?.searcher();
+        let token = CancelToken::default();
+        let collector = CancelAfter {
+            token: token.clone(),
+            stop_after: 10,
+        };
+        let seen = searcher.search(&*token.wrap(Box::new(AllQuery)), &collector)?;
+        assert_eq!(seen, 10);
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tracing::*;
+
+use toshi_proto::cluster_rpc::PingRequest;
+use toshi_types::Error;
+
+use crate::cluster::msgpack;
+use crate::cluster::rpc_server::RpcClient;
+
+/// How documents, searches and results are encoded on the cluster RPC. `Json` is understood by
+/// every node, `MessagePack` is a more compact binary encoding that is only used with peers that
+/// advertise it when pinged. A peer that doesn't list any formats is talked to in `Json`.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum WireFormat {
+    Json,
+    MessagePack,
+}
+
+impl WireFormat {
+    /// Every format this node can read, in the order it prefers them
+    pub const SUPPORTED: [WireFormat; 2] = [WireFormat::MessagePack, WireFormat::Json];
+
+    /// The name a format goes by in RPC messages, an empty name is `Json` for peers that predate
+    /// formats being negotiated
+    pub fn name(self) -> &'static str {
+        match self {
+            WireFormat::Json => "json",
+            WireFormat::MessagePack => "msgpack",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, Error> {
+        match name {
+            "" | "json" => Ok(WireFormat::Json),
+            "msgpack" => Ok(WireFormat::MessagePack),
+            other => Err(Error::RPCError(format!("Unknown wire format '{}'", other))),
+        }
+    }
+
+    /// The format to talk to a peer in given the formats it advertised
+    pub fn negotiate<S: AsRef<str>>(preferred: WireFormat, peer: &[S]) -> WireFormat {
+        if peer.iter().any(|f| f.as_ref() == preferred.name()) {
+            preferred
+        } else {
+            WireFormat::Json
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, Error> {
+        match self {
+            WireFormat::Json => Ok(serde_json::to_vec(value)?),
+            WireFormat::MessagePack => msgpack::to_vec(value),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, Error> {
+        match self {
+            WireFormat::Json => Ok(serde_json::from_slice(bytes)?),
+            WireFormat::MessagePack => msgpack::from_slice(bytes),
+        }
+    }
+}
+
+/// The format negotiated with each node by name, so a node is only pinged for the formats it reads
+/// the first time it's talked to
+#[derive(Debug, Default, Clone)]
+pub struct FormatCache(Arc<RwLock<HashMap<String, WireFormat>>>);
+
+impl FormatCache {
+    /// The format to talk to `node` in, pinging it over `client` if it hasn't been asked yet. A node
+    /// that can't be pinged is talked to in `Json` and asked again next time.
+    pub async fn format(&self, node: &str, preferred: WireFormat, client: &mut RpcClient) -> WireFormat {
+        if preferred == WireFormat::Json {
+            return preferred;
+        }
+        if let Some(format) = self.0.read().ok().and_then(|f| f.get(node).copied()) {
+            return format;
+        }
+        match client.ping(tonic::Request::new(PingRequest {})).await {
+            Ok(reply) => {
+                let format = WireFormat::negotiate(preferred, &reply.into_inner().formats);
+                if let Ok(mut formats) = self.0.write() {
+                    formats.insert(node.into(), format);
+                }
+                format
+            }
+            Err(e) => {
+                warn!("Could not negotiate a wire format with {}, using json: {}", node, e);
+                WireFormat::Json
+            }
+        }
+    }
+
+    /// Forget the format of a node that went away or came back, it may have been upgraded since
+    pub fn forget(&self, node: &str) {
+        if let Ok(mut formats) = self.0.write() {
+            formats.remove(node);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use toshi_types::{DeleteDoc, Search};
+
+    use crate::{AddDocument, SearchResults};
+
+    use super::*;
+
+    fn round_trip<T: Serialize + DeserializeOwned>(value: &T) -> Result<(), Error> {
+        let json: T = WireFormat::Json.decode(&WireFormat::Json.encode(value)?)?;
+        let packed = WireFormat::MessagePack.encode(value)?;
+        let binary: T = WireFormat::MessagePack.decode(&packed)?;
+        assert_eq!(serde_json::to_value(&binary)?, serde_json::to_value(&json)?);
+        assert!(packed.len() <= WireFormat::Json.encode(value)?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_binary_round_trip() -> Result<(), Error> {
+        let long = "a much longer body of text that takes more than one byte to give the length of ".repeat(10);
+        let doc: AddDocument = serde_json::from_value(serde_json::json!({
+            "options": { "commit": true },
+            "document": {
+                "test_text": long, "test_i64": -12_345_678_901i64, "test_u64": 18_446_744_073_709_551_615u64,
+                "small": -3, "ratio": 0.25, "missing": null, "tags": ["a", "b"], "nested": { "ok": true }
+            }
+        }))?;
+        round_trip(&doc)?;
+        round_trip(&vec![doc.clone(); 20])?;
+
+        let search: Search = serde_json::from_str(r#"{"query": {"term": {"test_text": "document"}}, "limit": 10}"#)?;
+        round_trip(&search)?;
+        round_trip(&serde_json::from_str::<DeleteDoc>(r#"{"terms": {"test_text": "document"}}"#)?)?;
+        let results: SearchResults = serde_json::from_value(serde_json::json!({
+            "hits": 1,
+            "docs": [{ "score": 1.5, "doc": { "test_text": ["a hit"], "test_i64": [-3], "test_u64": [300] } }],
+            "facets": []
+        }))?;
+        round_trip(&results)?;
+
+        assert!(WireFormat::MessagePack.decode::<Search>(&[0x92, 0x01]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_negotiate_falls_back_to_json() -> Result<(), Error> {
+        let peer: Vec<String> = WireFormat::SUPPORTED.iter().map(|f| f.name().to_string()).collect();
+        assert_eq!(WireFormat::negotiate(WireFormat::MessagePack, &peer), WireFormat::MessagePack);
+        assert_eq!(WireFormat::negotiate::<String>(WireFormat::MessagePack, &[]), WireFormat::Json);
+        assert_eq!(WireFormat::from_name("")?, WireFormat::Json);
+        assert!(WireFormat::from_name("bson").is_err());
+        Ok(())
+    }
+}
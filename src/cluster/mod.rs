@@ -1,7 +1,13 @@
 pub use self::node::*;
 
+pub mod codec;
+pub mod msgpack;
 pub mod node;
 pub mod ops;
+pub mod placement;
 pub mod remote_handle;
+pub mod replication;
+pub mod routing;
 pub mod rpc_server;
 pub mod shard;
+pub mod supervisor;
@@ -0,0 +1,666 @@
+use std::fmt;
+
+use serde::de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::ser::{self, Serialize};
+use serde::{forward_to_deserialize_any, Deserialize};
+
+use toshi_types::Error;
+
+/// Why a value couldn't be packed or unpacked, it becomes an [`Error::RPCError`]
+#[derive(Debug)]
+pub struct PackError(String);
+
+impl fmt::Display for PackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid MessagePack: {}", self.0)
+    }
+}
+
+impl std::error::Error for PackError {}
+
+impl ser::Error for PackError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        PackError(msg.to_string())
+    }
+}
+
+impl de::Error for PackError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        PackError(msg.to_string())
+    }
+}
+
+impl From<PackError> for Error {
+    fn from(e: PackError) -> Self {
+        Error::RPCError(e.to_string())
+    }
+}
+
+fn invalid(reason: &str) -> PackError {
+    PackError(reason.into())
+}
+
+/// Pack `value` into MessagePack, in the same shape serde_json gives it: structs are maps keyed by
+/// field name and enum variants are their name, or a map of their name to their content
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::new();
+    value.serialize(Packer(&mut buf))?;
+    Ok(buf)
+}
+
+/// Unpack a value packed by [`to_vec`], every byte of `bytes` has to be part of it
+pub fn from_slice<'de, T: Deserialize<'de>>(bytes: &'de [u8]) -> Result<T, Error> {
+    let mut reader = Reader { bytes, pos: 0 };
+    let value = T::deserialize(&mut reader)?;
+    if reader.pos != bytes.len() {
+        return Err(invalid("trailing bytes").into());
+    }
+    Ok(value)
+}
+
+fn write_len(buf: &mut Vec<u8>, len: usize, fix: u8, fix_max: usize, markers: [u8; 3]) {
+    if len <= fix_max {
+        buf.push(fix | len as u8);
+    } else if len <= 0xff && markers[0] != 0 {
+        buf.push(markers[0]);
+        buf.push(len as u8);
+    } else if len <= 0xffff {
+        buf.push(markers[1]);
+        buf.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buf.push(markers[2]);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+fn write_u64(buf: &mut Vec<u8>, u: u64) {
+    if u < 0x80 {
+        buf.push(u as u8);
+    } else if u <= 0xff {
+        buf.extend_from_slice(&[0xcc, u as u8]);
+    } else if u <= 0xffff {
+        buf.push(0xcd);
+        buf.extend_from_slice(&(u as u16).to_be_bytes());
+    } else if u <= 0xffff_ffff {
+        buf.push(0xce);
+        buf.extend_from_slice(&(u as u32).to_be_bytes());
+    } else {
+        buf.push(0xcf);
+        buf.extend_from_slice(&u.to_be_bytes());
+    }
+}
+
+fn write_i64(buf: &mut Vec<u8>, i: i64) {
+    if i >= 0 {
+        write_u64(buf, i as u64);
+    } else if i >= -32 {
+        buf.push(i as i8 as u8);
+    } else if i >= i64::from(i8::MIN) {
+        buf.extend_from_slice(&[0xd0, i as i8 as u8]);
+    } else if i >= i64::from(i16::MIN) {
+        buf.push(0xd1);
+        buf.extend_from_slice(&(i as i16).to_be_bytes());
+    } else if i >= i64::from(i32::MIN) {
+        buf.push(0xd2);
+        buf.extend_from_slice(&(i as i32).to_be_bytes());
+    } else {
+        buf.push(0xd3);
+        buf.extend_from_slice(&i.to_be_bytes());
+    }
+}
+
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    write_len(buf, s.len(), 0xa0, 31, [0xd9, 0xda, 0xdb]);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// Writes one value to the end of a buffer
+struct Packer<'a>(&'a mut Vec<u8>);
+
+/// The items of an array or map being packed. Their number isn't always known up front, so they're
+/// packed on their own and written after the header once they're all there.
+struct Compound<'a> {
+    out: &'a mut Vec<u8>,
+    items: Vec<u8>,
+    len: usize,
+    map: bool,
+}
+
+impl<'a> Compound<'a> {
+    fn new(out: &'a mut Vec<u8>, map: bool) -> Self {
+        Self {
+            out,
+            items: Vec::new(),
+            len: 0,
+            map,
+        }
+    }
+
+    /// An enum variant with content is packed as a map of its name to the content
+    fn variant(out: &'a mut Vec<u8>, variant: &str, map: bool) -> Self {
+        out.push(0x81);
+        write_str(out, variant);
+        Self::new(out, map)
+    }
+
+    fn item<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), PackError> {
+        self.len += 1;
+        value.serialize(Packer(&mut self.items))
+    }
+
+    fn field<T: Serialize + ?Sized>(&mut self, key: &str, value: &T) -> Result<(), PackError> {
+        write_str(&mut self.items, key);
+        self.item(value)
+    }
+
+    fn finish(self) -> Result<(), PackError> {
+        if self.map {
+            write_len(self.out, self.len, 0x80, 15, [0, 0xde, 0xdf]);
+        } else {
+            write_len(self.out, self.len, 0x90, 15, [0, 0xdc, 0xdd]);
+        }
+        self.out.extend_from_slice(&self.items);
+        Ok(())
+    }
+}
+
+impl<'a> ser::Serializer for Packer<'a> {
+    type Ok = ();
+    type Error = PackError;
+    type SerializeSeq = Compound<'a>;
+    type SerializeTuple = Compound<'a>;
+    type SerializeTupleStruct = Compound<'a>;
+    type SerializeTupleVariant = Compound<'a>;
+    type SerializeMap = Compound<'a>;
+    type SerializeStruct = Compound<'a>;
+    type SerializeStructVariant = Compound<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), PackError> {
+        self.0.push(if v { 0xc3 } else { 0xc2 });
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), PackError> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), PackError> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), PackError> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), PackError> {
+        write_i64(self.0, v);
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), PackError> {
+        self.serialize_u64(u64::from(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), PackError> {
+        self.serialize_u64(u64::from(v))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), PackError> {
+        self.serialize_u64(u64::from(v))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), PackError> {
+        write_u64(self.0, v);
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), PackError> {
+        self.0.push(0xca);
+        self.0.extend_from_slice(&v.to_bits().to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), PackError> {
+        self.0.push(0xcb);
+        self.0.extend_from_slice(&v.to_bits().to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), PackError> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), PackError> {
+        write_str(self.0, v);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), PackError> {
+        if v.len() <= 0xff {
+            self.0.extend_from_slice(&[0xc4, v.len() as u8]);
+        } else if v.len() <= 0xffff {
+            self.0.push(0xc5);
+            self.0.extend_from_slice(&(v.len() as u16).to_be_bytes());
+        } else {
+            self.0.push(0xc6);
+            self.0.extend_from_slice(&(v.len() as u32).to_be_bytes());
+        }
+        self.0.extend_from_slice(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), PackError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), PackError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), PackError> {
+        self.0.push(0xc0);
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<(), PackError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(self, _: &'static str, _: u32, variant: &'static str) -> Result<(), PackError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T) -> Result<(), PackError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), PackError> {
+        self.0.push(0x81);
+        write_str(self.0, variant);
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Compound<'a>, PackError> {
+        Ok(Compound::new(self.0, false))
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Compound<'a>, PackError> {
+        Ok(Compound::new(self.0, false))
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Compound<'a>, PackError> {
+        Ok(Compound::new(self.0, false))
+    }
+
+    fn serialize_tuple_variant(self, _: &'static str, _: u32, variant: &'static str, _: usize) -> Result<Compound<'a>, PackError> {
+        Ok(Compound::variant(self.0, variant, false))
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Compound<'a>, PackError> {
+        Ok(Compound::new(self.0, true))
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Compound<'a>, PackError> {
+        Ok(Compound::new(self.0, true))
+    }
+
+    fn serialize_struct_variant(self, _: &'static str, _: u32, variant: &'static str, _: usize) -> Result<Compound<'a>, PackError> {
+        Ok(Compound::variant(self.0, variant, true))
+    }
+}
+
+impl<'a> ser::SerializeSeq for Compound<'a> {
+    type Ok = ();
+    type Error = PackError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), PackError> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<(), PackError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTuple for Compound<'a> {
+    type Ok = ();
+    type Error = PackError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), PackError> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<(), PackError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTupleStruct for Compound<'a> {
+    type Ok = ();
+    type Error = PackError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), PackError> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<(), PackError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTupleVariant for Compound<'a> {
+    type Ok = ();
+    type Error = PackError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), PackError> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<(), PackError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeMap for Compound<'a> {
+    type Ok = ();
+    type Error = PackError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), PackError> {
+        self.item(key)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), PackError> {
+        value.serialize(Packer(&mut self.items))
+    }
+
+    fn end(self) -> Result<(), PackError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeStruct for Compound<'a> {
+    type Ok = ();
+    type Error = PackError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), PackError> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), PackError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeStructVariant for Compound<'a> {
+    type Ok = ();
+    type Error = PackError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), PackError> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), PackError> {
+        self.finish()
+    }
+}
+
+struct Reader<'de> {
+    bytes: &'de [u8],
+    pos: usize,
+}
+
+impl<'de> Reader<'de> {
+    fn take(&mut self, n: usize) -> Result<&'de [u8], PackError> {
+        let end = self.pos.checked_add(n).filter(|end| *end <= self.bytes.len());
+        let end = end.ok_or_else(|| invalid("unexpected end of input"))?;
+        let taken = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(taken)
+    }
+
+    fn peek(&self) -> Result<u8, PackError> {
+        self.bytes.get(self.pos).copied().ok_or_else(|| invalid("unexpected end of input"))
+    }
+
+    fn be(&mut self, n: usize) -> Result<u64, PackError> {
+        Ok(self.take(n)?.iter().fold(0u64, |acc, b| (acc << 8) | u64::from(*b)))
+    }
+
+    fn len(&mut self, marker: u8, fix_mask: u8) -> Result<usize, PackError> {
+        Ok(match marker {
+            0xc4 | 0xd9 => self.be(1)?,
+            0xc5 | 0xda | 0xdc | 0xde => self.be(2)?,
+            0xc6 | 0xdb | 0xdd | 0xdf => self.be(4)?,
+            _ => u64::from(marker & fix_mask),
+        } as usize)
+    }
+
+    fn str(&mut self, marker: u8) -> Result<&'de str, PackError> {
+        let len = self.len(marker, 0x1f)?;
+        std::str::from_utf8(self.take(len)?).map_err(|_| invalid("string is not utf-8"))
+    }
+}
+
+/// The items left of an array or the entries left of a map being unpacked
+struct Items<'a, 'de> {
+    reader: &'a mut Reader<'de>,
+    left: usize,
+}
+
+impl<'a, 'de> SeqAccess<'de> for Items<'a, 'de> {
+    type Error = PackError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, PackError> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(&mut *self.reader).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+}
+
+impl<'a, 'de> MapAccess<'de> for Items<'a, 'de> {
+    type Error = PackError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, PackError> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(&mut *self.reader).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, PackError> {
+        seed.deserialize(&mut *self.reader)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+}
+
+/// An enum variant, either packed as its name or as a map of its name to its content
+struct Variant<'a, 'de> {
+    reader: &'a mut Reader<'de>,
+    has_content: bool,
+}
+
+impl<'a, 'de> de::EnumAccess<'de> for Variant<'a, 'de> {
+    type Error = PackError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), PackError> {
+        let variant = seed.deserialize(&mut *self.reader)?;
+        Ok((variant, self))
+    }
+}
+
+impl<'a, 'de> de::VariantAccess<'de> for Variant<'a, 'de> {
+    type Error = PackError;
+
+    fn unit_variant(self) -> Result<(), PackError> {
+        if self.has_content {
+            IgnoredAny::deserialize(&mut *self.reader)?;
+        }
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, PackError> {
+        if !self.has_content {
+            return Err(invalid("variant has no content"));
+        }
+        seed.deserialize(&mut *self.reader)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value, PackError> {
+        if !self.has_content {
+            return Err(invalid("variant has no content"));
+        }
+        de::Deserializer::deserialize_any(&mut *self.reader, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _: &'static [&'static str], visitor: V) -> Result<V::Value, PackError> {
+        if !self.has_content {
+            return Err(invalid("variant has no content"));
+        }
+        de::Deserializer::deserialize_any(&mut *self.reader, visitor)
+    }
+}
+
+impl<'a, 'de> de::Deserializer<'de> for &'a mut Reader<'de> {
+    type Error = PackError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackError> {
+        let marker = self.take(1)?[0];
+        match marker {
+            0x00..=0x7f => visitor.visit_u64(u64::from(marker)),
+            0x80..=0x8f | 0xde | 0xdf => {
+                let left = self.len(marker, 0x0f)?;
+                visitor.visit_map(Items { reader: self, left })
+            }
+            0x90..=0x9f | 0xdc | 0xdd => {
+                let left = self.len(marker, 0x0f)?;
+                visitor.visit_seq(Items { reader: self, left })
+            }
+            0xa0..=0xbf | 0xd9..=0xdb => visitor.visit_borrowed_str(self.str(marker)?),
+            0xc0 => visitor.visit_unit(),
+            0xc2 => visitor.visit_bool(false),
+            0xc3 => visitor.visit_bool(true),
+            0xc4..=0xc6 => {
+                let len = self.len(marker, 0)?;
+                visitor.visit_borrowed_bytes(self.take(len)?)
+            }
+            0xca => visitor.visit_f32(f32::from_bits(self.be(4)? as u32)),
+            0xcb => visitor.visit_f64(f64::from_bits(self.be(8)?)),
+            0xcc => visitor.visit_u64(self.be(1)?),
+            0xcd => visitor.visit_u64(self.be(2)?),
+            0xce => visitor.visit_u64(self.be(4)?),
+            0xcf => visitor.visit_u64(self.be(8)?),
+            0xd0 => visitor.visit_i64(i64::from(self.be(1)? as u8 as i8)),
+            0xd1 => visitor.visit_i64(i64::from(self.be(2)? as u16 as i16)),
+            0xd2 => visitor.visit_i64(i64::from(self.be(4)? as u32 as i32)),
+            0xd3 => visitor.visit_i64(self.be(8)? as i64),
+            0xe0..=0xff => visitor.visit_i64(i64::from(marker as i8)),
+            _ => Err(invalid("unsupported type")),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackError> {
+        if self.peek()? == 0xc0 {
+            self.pos += 1;
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V) -> Result<V::Value, PackError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _: &'static str, _: &'static [&'static str], visitor: V) -> Result<V::Value, PackError> {
+        match self.peek()? {
+            0x81 => {
+                self.pos += 1;
+                visitor.visit_enum(Variant {
+                    reader: self,
+                    has_content: true,
+                })
+            }
+            0xa0..=0xbf | 0xd9..=0xdb => visitor.visit_enum(Variant {
+                reader: self,
+                has_content: false,
+            }),
+            _ => Err(invalid("expected an enum variant")),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Shape {
+        Empty,
+        Circle(f64),
+        Point(i64, i64),
+        Rect { w: u32, h: u32 },
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Packed {
+        shapes: Vec<Shape>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        skipped: Option<String>,
+        present: Option<String>,
+        counts: BTreeMap<String, u64>,
+        extra: serde_json::Value,
+    }
+
+    #[test]
+    fn test_round_trip_without_json() -> Result<(), Error> {
+        let packed = Packed {
+            shapes: vec![
+                Shape::Empty,
+                Shape::Circle(0.5),
+                Shape::Point(-200, 70_000),
+                Shape::Rect { w: 3, h: 4 },
+            ],
+            skipped: None,
+            present: None,
+            counts: vec![("a".to_string(), 1), ("b".to_string(), u64::MAX)].into_iter().collect(),
+            extra: serde_json::json!({ "min": i64::MIN, "small": -5, "nested": [true, null, "x"] }),
+        };
+        let bytes = to_vec(&packed)?;
+        assert_eq!(from_slice::<Packed>(&bytes)?, packed);
+
+        // Integers take the fewest bytes that hold them
+        assert_eq!(to_vec(&200u64)?, vec![0xcc, 200]);
+        assert_eq!(to_vec(&-200i64)?, vec![0xd1, 0xff, 0x38]);
+        assert_eq!(to_vec(&Shape::Empty)?, vec![0xa5, b'E', b'm', b'p', b't', b'y']);
+
+        assert!(from_slice::<u64>(&[0x01, 0x02]).is_err());
+        assert!(from_slice::<Vec<u64>>(&[0x92, 0x01]).is_err());
+        Ok(())
+    }
+}
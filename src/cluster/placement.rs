@@ -0,0 +1,175 @@
+use toshi_types::Error;
+
+use crate::cluster::ops::NodeData;
+use crate::cluster::shard::{PrimaryShard, ReplicaShard, Shard};
+
+/// A node shards can be placed on along with the load it is currently carrying
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeLoad {
+    pub node: String,
+    pub num_docs: u64,
+    pub disk_size: u64,
+}
+
+impl NodeLoad {
+    /// A node that holds no shards yet
+    pub fn new(node: String) -> Self {
+        Self {
+            node,
+            num_docs: 0,
+            disk_size: 0,
+        }
+    }
+
+    /// Sum up the load of every primary and replica shard a node is hosting
+    pub fn from_data(node: String, data: &NodeData) -> Self {
+        let primaries = data.primaries.iter().map(|s| (s.num_docs(), s.disk_size()));
+        let replicas = data.shards.iter().map(|s| (s.num_docs(), s.disk_size()));
+        let (num_docs, disk_size) = primaries.chain(replicas).fold((0, 0), |(d, s), (nd, ns)| (d + nd, s + ns));
+        Self { node, num_docs, disk_size }
+    }
+}
+
+/// Where a new primary shard and its replicas have been assigned
+pub struct ShardPlacement {
+    pub primary: PrimaryShard,
+    pub primary_node: String,
+    pub replicas: Vec<(String, ReplicaShard)>,
+    /// The number of requested replicas that could not be placed without sharing a node with
+    /// another copy of the shard
+    pub unassigned: usize,
+}
+
+impl ShardPlacement {
+    /// Every node holding a copy of this shard, primary first
+    pub fn nodes(&self) -> Vec<&str> {
+        let replicas = self.replicas.iter().map(|(n, _)| n.as_str());
+        std::iter::once(self.primary_node.as_str()).chain(replicas).collect()
+    }
+}
+
+/// Decides which node hosts a new shard. Given the same nodes and the same strategy state a
+/// placement always makes the same choice.
+pub trait Placement {
+    /// Pick a node out of `nodes` for a new shard, never one of the nodes in `exclude`
+    fn select(&mut self, nodes: &[NodeLoad], exclude: &[String]) -> Option<String>;
+
+    /// Place a new primary shard and up to `replicas` replicas of it. No two copies of the shard
+    /// are ever put on the same node, replicas that can't be placed are counted as unassigned.
+    fn place_shard(&mut self, nodes: &[NodeLoad], replicas: usize) -> Result<ShardPlacement, Error> {
+        let primary_node = self
+            .select(nodes, &[])
+            .ok_or_else(|| Error::IOError("No nodes available to place shard on".into()))?;
+        let primary = PrimaryShard::new();
+        let mut used = vec![primary_node.clone()];
+        let mut placed = Vec::with_capacity(replicas);
+        for _ in 0..replicas {
+            match self.select(nodes, &used) {
+                Some(node) => {
+                    used.push(node.clone());
+                    placed.push((node, ReplicaShard::new(primary.shard_id())));
+                }
+                None => break,
+            }
+        }
+        Ok(ShardPlacement {
+            unassigned: replicas - placed.len(),
+            primary,
+            primary_node,
+            replicas: placed,
+        })
+    }
+}
+
+/// Hands out nodes in turn, skipping over excluded ones
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    next: usize,
+}
+
+impl Placement for RoundRobin {
+    fn select(&mut self, nodes: &[NodeLoad], exclude: &[String]) -> Option<String> {
+        let start = self.next;
+        (0..nodes.len())
+            .map(|i| (start + i) % nodes.len())
+            .find(|i| !exclude.contains(&nodes[*i].node))
+            .map(|i| {
+                self.next = i + 1;
+                nodes[i].node.clone()
+            })
+    }
+}
+
+/// Picks the node with the smallest disk footprint, then the fewest documents, ties are broken
+/// by node name
+#[derive(Debug, Default)]
+pub struct LeastLoaded;
+
+impl Placement for LeastLoaded {
+    fn select(&mut self, nodes: &[NodeLoad], exclude: &[String]) -> Option<String> {
+        nodes
+            .iter()
+            .filter(|n| !exclude.contains(&n.node))
+            .min_by(|a, b| (a.disk_size, a.num_docs, &a.node).cmp(&(b.disk_size, b.num_docs, &b.node)))
+            .map(|n| n.node.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes() -> Vec<NodeLoad> {
+        vec![
+            NodeLoad {
+                node: "127.0.0.1:8081".into(),
+                num_docs: 10,
+                disk_size: 500,
+            },
+            NodeLoad {
+                node: "127.0.0.1:8082".into(),
+                num_docs: 20,
+                disk_size: 100,
+            },
+            NodeLoad {
+                node: "127.0.0.1:8083".into(),
+                num_docs: 5,
+                disk_size: 100,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_replica_anti_affinity() -> Result<(), Error> {
+        let nodes = nodes();
+        let strategies: Vec<Box<dyn Placement>> = vec![Box::new(RoundRobin::default()), Box::new(LeastLoaded)];
+        for mut strategy in strategies {
+            let placement = strategy.place_shard(&nodes[..2], 1)?;
+            assert_eq!(placement.replicas.len(), 1);
+            assert_ne!(placement.primary_node, placement.replicas[0].0);
+            assert_eq!(placement.replicas[0].1.primary_shard_id(), Some(placement.primary.shard_id()));
+
+            let placement = strategy.place_shard(&nodes[..1], 2)?;
+            assert!(placement.replicas.is_empty());
+            assert_eq!(placement.unassigned, 2);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_least_loaded() -> Result<(), Error> {
+        let placement = LeastLoaded.place_shard(&nodes(), 2)?;
+        assert_eq!(placement.nodes(), vec!["127.0.0.1:8083", "127.0.0.1:8082", "127.0.0.1:8081"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_robin() -> Result<(), Error> {
+        let nodes = nodes();
+        let mut strategy = RoundRobin::default();
+        assert_eq!(strategy.place_shard(&nodes, 1)?.nodes(), vec!["127.0.0.1:8081", "127.0.0.1:8082"]);
+        assert_eq!(strategy.place_shard(&nodes, 1)?.nodes(), vec!["127.0.0.1:8083", "127.0.0.1:8081"]);
+        assert!(strategy.place_shard(&[], 1).is_err());
+        Ok(())
+    }
+}
@@ -1,15 +1,19 @@
 use std::hash::{Hash, Hasher};
+use std::time::Instant;
 
 use rand::prelude::*;
 use tracing::*;
 
 use toshi_proto::cluster_rpc::*;
 use toshi_proto::cluster_rpc::{DocumentRequest, SearchRequest};
-use toshi_types::{DeleteDoc, DocsAffected, Error, Search};
+use toshi_types::{DeleteDoc, DocsAffected, Error, Search, SearchProfile, ShardsInfo};
 
+use crate::cluster::codec::WireFormat;
+use crate::cluster::routing::{HashRing, PartitionTable, ShardRouter};
 use crate::cluster::rpc_server::RpcClient;
 use crate::handle::{IndexHandle, IndexLocation};
 use crate::handlers::fold_results;
+use crate::settings::Settings;
 use crate::AddDocument;
 use crate::SearchResults;
 
@@ -17,6 +21,14 @@ use crate::SearchResults;
 pub struct RemoteIndex {
     name: String,
     remotes: Vec<RpcClient>,
+    /// The id of the node each remote is on, which the index's routing values are placed by
+    nodes: Vec<String>,
+    format: WireFormat,
+    router: ShardRouter,
+    /// The number of the first shard in `remotes`, and how many of the index's shards were left out
+    /// of them, for an index narrowed down to one shard by [`RemoteIndex::routed`]
+    first_shard: usize,
+    skipped: usize,
 }
 
 impl PartialEq for RemoteIndex {
@@ -35,11 +47,95 @@ impl Hash for RemoteIndex {
 
 impl RemoteIndex {
     pub fn new(name: String, remote: RpcClient) -> Self {
-        RemoteIndex::with_clients(name, vec![remote])
+        Self {
+            router: ShardRouter::Ring(HashRing::new::<&str>(&[], Settings::default_virtual_nodes())),
+            name,
+            remotes: vec![remote],
+            nodes: Vec::new(),
+            format: WireFormat::Json,
+            first_shard: 0,
+            skipped: 0,
+        }
+    }
+
+    /// An index with one shard on each of `remotes`, a pair of the id of the node, such as its
+    /// address, and a client of it
+    pub fn with_clients(name: String, remotes: Vec<(String, RpcClient)>) -> Self {
+        let (nodes, remotes): (Vec<_>, Vec<_>) = remotes.into_iter().unzip();
+        Self {
+            router: ShardRouter::Ring(HashRing::new(&nodes, Settings::default_virtual_nodes())),
+            name,
+            remotes,
+            nodes,
+            format: WireFormat::Json,
+            first_shard: 0,
+            skipped: 0,
+        }
+    }
+
+    /// Place routing values on the index's shards with `virtual_nodes` points per shard
+    pub fn with_virtual_nodes(self, virtual_nodes: usize) -> Self {
+        Self {
+            router: ShardRouter::Ring(HashRing::new(&self.nodes, virtual_nodes)),
+            ..self
+        }
+    }
+
+    /// Pin routing values to the partitions of `table` instead of placing them on the shards
+    /// directly. Shards the table doesn't deal partitions to yet are given their share of them.
+    pub fn with_partitions(self, mut table: PartitionTable) -> Self {
+        table.grow(self.shards());
+        Self {
+            router: ShardRouter::Partitions(table),
+            ..self
+        }
     }
 
-    pub fn with_clients(name: String, remotes: Vec<RpcClient>) -> Self {
-        Self { name, remotes }
+    /// The partitions routing values are pinned to and the shards holding them, none when routing
+    /// values are placed on the shards directly
+    pub fn partitions(&self) -> Option<&PartitionTable> {
+        match &self.router {
+            ShardRouter::Partitions(table) => Some(table),
+            ShardRouter::Ring(_) => None,
+        }
+    }
+
+    /// Have `shard` hold `partition` from now on, the routing values hashed to the partition stay in it
+    pub fn move_partition(&mut self, partition: usize, shard: usize) -> Result<(), Error> {
+        if shard >= self.shards() {
+            return Err(Error::QueryError(format!(
+                "Shard {} doesn't exist, the index has {} shards",
+                shard,
+                self.shards()
+            )));
+        }
+        match &mut self.router {
+            ShardRouter::Partitions(table) => table.move_partition(partition, shard),
+            ShardRouter::Ring(_) => Err(Error::QueryError(format!("Index {} has no routing partitions", self.name))),
+        }
+    }
+
+    /// How many shards the index is spread over, one per remote
+    pub fn shards(&self) -> usize {
+        self.remotes.len()
+    }
+
+    /// The index narrowed down to the one shard operations with `routing` are pinned to. A document
+    /// written with a routing value is only found by reads that use the same value.
+    pub fn routed(&self, routing: &str) -> Self {
+        let shard = self.router.shard_for(routing).unwrap_or(0);
+        Self {
+            remotes: self.remotes.iter().skip(shard).take(1).cloned().collect(),
+            nodes: self.nodes.iter().skip(shard).take(1).cloned().collect(),
+            first_shard: shard,
+            skipped: self.shards().saturating_sub(1),
+            ..self.clone()
+        }
+    }
+
+    /// Talk to the remotes in `format`, which every one of them has to read
+    pub fn with_format(self, format: WireFormat) -> Self {
+        Self { format, ..self }
     }
 }
 
@@ -57,18 +153,57 @@ impl IndexHandle for RemoteIndex {
         let name = self.get_name();
         let clients = self.remotes.clone();
         info!("REQ = {:?}", search);
+        let bytes = self.format.encode(&search)?;
+        let normalization = search.score_normalization;
         let mut results = vec![];
-        for mut client in clients {
-            let bytes = serde_json::to_vec(&search)?;
+        let mut failures = Vec::new();
+        let mut last_error = None;
+        for (i, mut client) in clients.into_iter().enumerate() {
+            let shard = self.first_shard + i;
+            let start = Instant::now();
             let req = tonic::Request::new(SearchRequest {
                 index: name.clone(),
-                query: bytes,
+                query: bytes.clone(),
+                format: self.format.name().into(),
             });
-            let search: SearchReply = client.search_index(req).await?.into_inner();
-            let search_results: SearchResults = serde_json::from_slice(&search.doc)?;
-            results.push(search_results);
+            let answer = match client.search_index(req).await {
+                Ok(reply) => {
+                    let reply: SearchReply = reply.into_inner();
+                    WireFormat::from_name(&reply.format).and_then(|f| f.decode::<SearchResults>(&reply.doc))
+                }
+                Err(e) => Err(Error::from(e)),
+            };
+            let took_ms = SearchProfile::millis(start.elapsed());
+            match answer {
+                Ok(mut search_results) => {
+                    search_results.shards = Some(ShardsInfo::single(&name, shard, took_ms));
+                    results.push(search_results);
+                }
+                // A shard that fails leaves its documents out of the results rather than failing the search
+                Err(e) => {
+                    warn!("Shard {} of {} failed to search: {}", shard, name, e);
+                    failures.push(ShardsInfo::failure(&name, shard, took_ms, e.to_string()));
+                    last_error = Some(e);
+                }
+            }
         }
-        Ok(fold_results(results))
+        if results.is_empty() {
+            if let Some(e) = last_error {
+                return Err(e);
+            }
+        }
+        let mut shards = ShardsInfo {
+            total: self.skipped,
+            skipped: self.skipped,
+            ..ShardsInfo::default()
+        };
+        for failure in failures {
+            shards = shards.merge(failure);
+        }
+        // An index spread over shards is normalized as a whole, the same as an index that isn't
+        let mut folded = fold_results(results, normalization);
+        folded.shards = ShardsInfo::merge_opt(folded.shards, Some(shards));
+        Ok(folded)
     }
 
     async fn add_document(&self, add: AddDocument) -> Result<(), Error> {
@@ -77,10 +212,11 @@ impl IndexHandle for RemoteIndex {
         info!("REQ = {:?}", add);
         let mut random = rand::rngs::SmallRng::from_entropy();
         if let Some(mut client) = clients.choose(&mut random).cloned() {
-            let bytes = serde_json::to_vec(&add)?;
+            let bytes = self.format.encode(&add)?;
             let req = tonic::Request::new(DocumentRequest {
                 index: name,
                 document: bytes,
+                format: self.format.name().into(),
             });
             client.place_document(req).await?;
         }
@@ -92,10 +228,11 @@ impl IndexHandle for RemoteIndex {
         let clients = self.remotes.clone();
         let mut total = 0u64;
         for mut client in clients {
-            let bytes = serde_json::to_vec(&delete)?;
+            let bytes = self.format.encode(&delete)?;
             let req = tonic::Request::new(DeleteRequest {
                 index: name.clone(),
                 terms: bytes,
+                format: self.format.name().into(),
             });
             let response = client.delete_document(req).await?.into_inner();
             total += response.docs_affected;
@@ -104,3 +241,40 @@ impl IndexHandle for RemoteIndex {
         Ok(DocsAffected { docs_affected: total })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::cluster::rpc_server::tests::spawn_node;
+    use crate::index::tests::create_test_catalog;
+
+    use super::*;
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_search_reports_shards() -> Result<(), Box<dyn std::error::Error>> {
+        let shards = vec![create_test_catalog("test_index"), create_test_catalog("test_index")];
+        let mut clients = Vec::new();
+        for shard in &shards {
+            let (uri, client) = spawn_node(Arc::clone(shard)).await?;
+            clients.push((uri.authority().unwrap().to_string(), client));
+        }
+        let index = RemoteIndex::with_clients("test_index".into(), clients);
+
+        let results = index.search_index(Search::all_docs()).await?;
+        assert_eq!(results.hits, 10);
+        let info = results.shards.unwrap();
+        assert_eq!((info.total, info.successful, info.skipped, info.failed), (2, 2, 0, 0));
+        let mut reported: Vec<usize> = info.shards.iter().map(|s| s.shard).collect();
+        reported.sort();
+        assert_eq!(reported, vec![0, 1]);
+        assert!(info.shards.iter().all(|s| s.index == "test_index" && s.error.is_none()));
+
+        // A routed search is sent to one shard and skips the other
+        let routed = index.routed("user-1").search_index(Search::all_docs()).await?;
+        let info = routed.shards.unwrap();
+        assert_eq!((info.total, info.successful, info.skipped, info.failed), (2, 1, 1, 0));
+        assert_eq!(info.shards.len(), 1);
+        Ok(())
+    }
+}
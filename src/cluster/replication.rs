@@ -0,0 +1,278 @@
+use std::future::Future;
+use std::time::Duration;
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use tantivy::schema::Value;
+use tantivy::DocAddress;
+use tokio::time;
+
+use toshi_types::{Error, IndexOptions, KeyValue, ID_FIELD};
+
+use crate::cluster::codec::{FormatCache, WireFormat};
+use crate::cluster::remote_handle::RemoteIndex;
+use crate::handle::IndexHandle;
+use crate::index::IndexCatalog;
+use crate::AddDocument;
+
+/// How many copies of an index have to confirm a write before it is acknowledged to the client.
+/// One only waits on the primary, Quorum waits until a majority of all copies have the write and
+/// All waits on every replica.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Consistency {
+    One,
+    Quorum,
+    All,
+}
+
+impl Consistency {
+    /// The number of replica acknowledgements needed on top of the primary's
+    pub fn required_acks(self, replicas: usize) -> usize {
+        match self {
+            Consistency::One => 0,
+            Consistency::Quorum => {
+                // A majority of every copy, the primary has already applied the write itself
+                let copies = replicas + 1;
+                let majority = copies / 2 + 1;
+                majority - 1
+            }
+            Consistency::All => replicas,
+        }
+    }
+}
+
+/// Wait until `required` of the replica writes succeed. Writes still in flight once enough have
+/// been acknowledged, or once the timeout is hit, keep running in the background.
+pub async fn await_replication<I, F>(writes: I, required: usize, timeout: Duration) -> Result<usize, Error>
+where
+    I: IntoIterator<Item = F>,
+    F: Future<Output = Result<(), Error>> + Send + 'static,
+{
+    let mut pending: FuturesUnordered<_> = writes.into_iter().map(tokio::spawn).collect();
+    let mut acknowledged = 0;
+    let acks = async {
+        while acknowledged < required {
+            match pending.next().await {
+                Some(Ok(Ok(()))) => acknowledged += 1,
+                Some(Ok(Err(e))) => tracing::warn!("Replica write failed: {}", e),
+                Some(Err(e)) => tracing::warn!("Replica write panicked: {}", e),
+                None => return,
+            }
+        }
+    };
+    let _ = time::timeout(timeout, acks).await;
+    if acknowledged < required {
+        Err(Error::PartialReplication { acknowledged, required })
+    } else {
+        Ok(acknowledged)
+    }
+}
+
+/// Send writes that the primary already applied to every healthy replica of the index, waiting
+/// on as many of them as the consistency level asks for
+pub async fn replicate(cat: &IndexCatalog, index: &str, docs: Vec<AddDocument>, consistency: Consistency) -> Result<usize, Error> {
+    let routing = cat.get_routing(index)?;
+    let nodes: Vec<String> = routing
+        .replicas
+        .iter()
+        .map(|(n, _)| n.clone())
+        .filter(|n| cat.is_node_healthy(n))
+        .collect();
+    let required = consistency.required_acks(routing.replicas.len());
+    let mut writes = Vec::with_capacity(nodes.len());
+    for node in nodes {
+        // Replicas registered when the index was placed are reused, ones that rejoined since are connected to
+        let registered = cat.get_replica_index(index, &node).await;
+        let (index, docs) = (index.to_string(), docs.clone());
+        let (formats, preferred) = (cat.wire_formats(), cat.settings.experimental_features.wire_format);
+        writes.push(async move {
+            let remote = match registered {
+                Some(remote) => remote,
+                None => connect(index, &node, &formats, preferred).await?,
+            };
+            for doc in docs {
+                remote.add_document(doc).await?;
+            }
+            Ok(())
+        });
+    }
+    let timeout = Duration::from_secs_f32(cat.settings.experimental_features.replication_timeout);
+    await_replication(writes, required, timeout).await
+}
+
+/// Connect to the copy of `index` on `node`, in the best format the node reads
+async fn connect(index: String, node: &str, formats: &FormatCache, preferred: WireFormat) -> Result<RemoteIndex, Error> {
+    let mut client = IndexCatalog::create_client(node.into()).await?;
+    let format = formats.format(node, preferred, &mut client).await;
+    Ok(RemoteIndex::new(index, client).with_format(format))
+}
+
+/// Bootstrap the replica of `index` on `node` that rejoined it from a snapshot of this primary's
+/// committed documents, returning how many were sent. Documents replace the replica's out of date
+/// copies by their [`ID_FIELD`] when the index has one. The replica is as far behind as the
+/// operations the primary had queued but not committed when the snapshot was taken, and it serves
+/// reads once that's at most `max_bootstrap_lag`.
+pub async fn bootstrap(cat: &IndexCatalog, index: &str, node: &str) -> Result<usize, Error> {
+    let primary = cat.get_index(index)?;
+    let remote = match cat.get_replica_index(index, node).await {
+        Some(remote) => remote,
+        None => {
+            connect(
+                index.into(),
+                node,
+                &cat.wire_formats(),
+                cat.settings.experimental_features.wire_format,
+            )
+            .await?
+        }
+    };
+    let searcher = primary.searcher();
+    let lag = primary.get_opstamp() as u64;
+    let schema = searcher.schema();
+    let id = schema.get_field(ID_FIELD).filter(|f| {
+        let entry = schema.get_field_entry(*f);
+        entry.is_indexed() && entry.is_stored()
+    });
+
+    // Each document is sent once the next one is read, so the last one can commit the snapshot
+    let mut sent = 0;
+    let mut pending: Option<AddDocument> = None;
+    for (ord, segment) in searcher.segment_readers().iter().enumerate() {
+        for doc_id in (0..segment.max_doc()).filter(|d| !segment.is_deleted(*d)) {
+            let doc = searcher.doc(DocAddress(ord as u32, doc_id))?;
+            let key = id
+                .and_then(|f| doc.get_first(f))
+                .and_then(Value::text)
+                .map(|v| KeyValue::new(ID_FIELD.to_string(), v.to_string()));
+            let next = AddDocument {
+                options: None,
+                document: serde_json::to_value(schema.to_named_doc(&doc))?,
+                key,
+                if_version: None,
+            };
+            if let Some(doc) = pending.replace(next) {
+                remote.add_document(doc).await?;
+                sent += 1;
+            }
+        }
+    }
+    if let Some(mut doc) = pending {
+        doc.options = Some(IndexOptions { commit: true });
+        remote.add_document(doc).await?;
+        sent += 1;
+    }
+
+    cat.set_replica_lag(index, node, lag)?;
+    cat.snapshot_restored(index, node)?;
+    tracing::info!(
+        "Bootstrapped the replica of {} on {} with {} documents, {} behind",
+        index,
+        node,
+        sent,
+        lag
+    );
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use futures::future::BoxFuture;
+    use futures::FutureExt;
+
+    use super::*;
+
+    fn replica(delay: Option<u64>) -> BoxFuture<'static, Result<(), Error>> {
+        async move {
+            match delay {
+                Some(ms) => {
+                    time::delay_for(Duration::from_millis(ms)).await;
+                    Ok(())
+                }
+                None => futures::future::pending().await,
+            }
+        }
+        .boxed()
+    }
+
+    #[test]
+    fn test_required_acks() {
+        assert_eq!(Consistency::One.required_acks(2), 0);
+        assert_eq!(Consistency::Quorum.required_acks(2), 1);
+        assert_eq!(Consistency::Quorum.required_acks(1), 1);
+        assert_eq!(Consistency::Quorum.required_acks(0), 0);
+        assert_eq!(Consistency::All.required_acks(2), 2);
+    }
+
+    #[tokio::test]
+    async fn test_quorum_waits_for_replica() -> Result<(), Error> {
+        // A primary with two replicas, one that is slow to confirm and one that never does
+        let required = Consistency::Quorum.required_acks(2);
+        let start = Instant::now();
+        let acked = await_replication(vec![replica(Some(50)), replica(None)], required, Duration::from_secs(5)).await?;
+        assert_eq!(acked, 1);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let required = Consistency::All.required_acks(2);
+        let all = await_replication(vec![replica(Some(10)), replica(None)], required, Duration::from_millis(100)).await;
+        match all {
+            Err(Error::PartialReplication { acknowledged, required }) => assert_eq!((acknowledged, required), (1, 2)),
+            _ => panic!("Expected a partial replication error"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_rejoined_replica_serves_once_bootstrapped() -> Result<(), Box<dyn std::error::Error>> {
+        use std::sync::Arc;
+
+        use toshi_types::Search;
+
+        use crate::cluster::routing::{IndexHealth, ReadPreference};
+        use crate::cluster::rpc_server::tests::spawn_node;
+        use crate::index::tests::idle_nodes;
+
+        // The node that goes away comes back with an empty copy of the index
+        let schema = toshi_test::create_test_index().schema();
+        let away = Arc::new(IndexCatalog::with_index(
+            "test_index".into(),
+            tantivy::Index::create_in_ram(schema),
+        )?);
+        let (uri, _) = spawn_node(Arc::clone(&away)).await?;
+        let node = uri.authority().unwrap().to_string();
+
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.experimental = true;
+        catalog.settings.place_addr = "127.0.0.1:8082".into();
+        catalog.settings.experimental_features.nodes = vec![node.clone(), "127.0.0.1:8082".into()];
+        catalog.settings.experimental_features.max_bootstrap_lag = 0;
+        let cat = Arc::new(catalog);
+        cat.plan_index("test_index", 1, &idle_nodes(&cat))?;
+        cat.observe_node(&node, false)?;
+        assert_eq!(cat.write_node("test_index")?, "127.0.0.1:8082");
+        assert_eq!(cat.observe_node(&node, true)?, vec!["test_index"]);
+
+        let serves = |cat: &IndexCatalog| -> Result<bool, Error> {
+            let routing = cat.get_routing("test_index")?;
+            Ok((0..4).any(|t| routing.read_node(ReadPreference::Replica, "127.0.0.1:8082", t, |_| true) == node))
+        };
+        assert!(!serves(&cat)?);
+
+        // A write the primary hasn't committed isn't in the snapshot, which leaves the replica behind
+        let doc = r#"{ "test_text": "uncommitted", "test_u64": 10, "test_i64": -10 }"#;
+        let primary = cat.get_index("test_index")?;
+        primary.add_document(AddDocument::new(None, serde_json::from_str(doc)?)).await?;
+        assert_eq!(bootstrap(&cat, "test_index", &node).await?, 5);
+        assert_eq!(away.get_index("test_index")?.search_index(Search::all_docs()).await?.hits, 5);
+        assert!(!serves(&cat)?);
+        assert_eq!(cat.get_routing("test_index")?.health(), IndexHealth::Yellow);
+
+        primary.commit().await?;
+        bootstrap(&cat, "test_index", &node).await?;
+        assert!(serves(&cat)?);
+        assert_eq!(cat.get_routing("test_index")?.health(), IndexHealth::Green);
+        Ok(())
+    }
+}
@@ -0,0 +1,630 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+use toshi_types::Error;
+
+use crate::cluster::placement::ShardPlacement;
+use crate::cluster::shard::{PrimaryShard, ReplicaShard, ReplicaState, Shard};
+
+/// The replication health of an index, Yellow means the primary is placed but some of its
+/// replicas could not be without breaking anti-affinity or are still bootstrapping, Red means
+/// there is no primary.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexHealth {
+    Green,
+    Yellow,
+    Red,
+}
+
+/// Which copy of an index a search should be served by. Replica spreads reads over the healthy
+/// replicas, Nearest prefers a copy on the local node, both fall back to the primary when there is
+/// no healthy replica to use.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadPreference {
+    Primary,
+    Replica,
+    Nearest,
+}
+
+/// What a `preference` pins a caller's reads to a copy of an index with, followed by the session's id
+const SESSION_PREFIX: &str = "session:";
+
+impl ReadPreference {
+    /// The read preference a request's `preference` names, if it names one
+    pub fn named(name: &str) -> Option<Self> {
+        match name {
+            "primary" => Some(ReadPreference::Primary),
+            "replica" => Some(ReadPreference::Replica),
+            "nearest" => Some(ReadPreference::Nearest),
+            _ => None,
+        }
+    }
+
+    /// Read a request's `preference`, either a read preference or `session:` followed by the id of a
+    /// session whose reads all go to the same copy. Reads of a session are spread like replica reads
+    /// are when it has no copy yet. Anything else is a [`Error::QueryError`].
+    pub fn parse(preference: &str) -> Result<(Self, Option<&str>), Error> {
+        if let Some(named) = ReadPreference::named(preference) {
+            return Ok((named, None));
+        }
+        match preference.find(SESSION_PREFIX) {
+            Some(0) if preference.len() > SESSION_PREFIX.len() => Ok((ReadPreference::Replica, Some(&preference[SESSION_PREFIX.len()..]))),
+            _ => Err(Error::QueryError(format!(
+                "Unknown preference '{}', expected primary, replica, nearest or session:{{id}}",
+                preference
+            ))),
+        }
+    }
+}
+
+/// Where every copy of an index lives in the cluster
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IndexRouting {
+    pub primary: PrimaryShard,
+    pub primary_node: String,
+    pub replicas: Vec<(String, ReplicaShard)>,
+    pub unassigned: usize,
+    /// Bumped every time a replica is promoted so the same failure can't be acted on twice
+    pub generation: u64,
+    /// How many operations each replica's node is behind the primary
+    #[serde(default)]
+    pub replica_lag: HashMap<String, u64>,
+    /// Nodes that held a copy of the index before becoming unreachable
+    #[serde(default)]
+    pub departed: Vec<String>,
+}
+
+impl IndexRouting {
+    pub fn health(&self) -> IndexHealth {
+        if self.unassigned == 0 && self.replicas.iter().all(|(_, r)| r.is_ready()) {
+            IndexHealth::Green
+        } else {
+            IndexHealth::Yellow
+        }
+    }
+
+    /// The nodes of the healthy replicas that are ready to serve reads, a replica that is still
+    /// bootstrapping or catching up never serves one
+    fn serving_replicas<F>(&self, healthy: F) -> Vec<&String>
+    where
+        F: Fn(&str) -> bool,
+    {
+        self.replicas
+            .iter()
+            .filter(|(n, r)| r.is_ready() && healthy(n))
+            .map(|(n, _)| n)
+            .collect()
+    }
+
+    /// Pick the node that serves a read for this index, `turn` is used to spread reads across the
+    /// healthy replicas.
+    pub fn read_node<F>(&self, preference: ReadPreference, local: &str, turn: usize, healthy: F) -> String
+    where
+        F: Fn(&str) -> bool,
+    {
+        let replicas = self.serving_replicas(&healthy);
+        let serves_local = self.primary_node == local || replicas.iter().any(|n| *n == local);
+        match preference {
+            ReadPreference::Primary => self.primary_node.clone(),
+            ReadPreference::Nearest if serves_local && healthy(local) => local.into(),
+            _ if replicas.is_empty() => self.primary_node.clone(),
+            _ => replicas[turn % replicas.len()].clone(),
+        }
+    }
+
+    /// Pick the node that serves every read made with `session`, such as a user's session id. The
+    /// same session gets the same healthy replica for as long as the index's copies and their health
+    /// stay the same, so paging through results never switches between copies part way.
+    pub fn session_node<F>(&self, session: &str, healthy: F) -> String
+    where
+        F: Fn(&str) -> bool,
+    {
+        let replicas = self.serving_replicas(healthy);
+        if replicas.is_empty() {
+            self.primary_node.clone()
+        } else {
+            replicas[shard_for(session, replicas.len())].clone()
+        }
+    }
+
+    /// Promote the most caught up healthy replica to primary, returning its node. `generation` is
+    /// the generation the caller saw the failed primary at, if the index has failed over since
+    /// then nothing is promoted.
+    pub fn promote<F>(&mut self, generation: u64, healthy: F) -> Result<String, Error>
+    where
+        F: Fn(&str) -> bool,
+    {
+        if generation != self.generation {
+            return Err(Error::IOError(format!(
+                "Failover for generation {} already happened, index is at generation {}",
+                generation, self.generation
+            )));
+        }
+        let lag = |n: &String| self.replica_lag.get(n).copied().unwrap_or(0);
+        let (idx, _) = self
+            .replicas
+            .iter()
+            .enumerate()
+            .filter(|(_, (n, r))| r.is_ready() && healthy(n))
+            .min_by(|(_, (a, _)), (_, (b, _))| (lag(a), a).cmp(&(lag(b), b)))
+            .ok_or_else(|| Error::IOError("No healthy replica to promote".into()))?;
+
+        let (node, replica) = self.replicas.remove(idx);
+        let mut primary = replica.promote();
+        for (_, r) in &mut self.replicas {
+            r.follow(primary.shard_id());
+            primary.add_replica(r.shard_id());
+        }
+        let old = std::mem::replace(&mut self.primary_node, node.clone());
+        self.departed.push(old);
+        self.primary = primary;
+        self.replica_lag.remove(&node);
+        self.generation += 1;
+        Ok(node)
+    }
+
+    /// Bring a node that held a copy before it went away back as a replica of the current primary.
+    /// Its copy is out of date, so it bootstraps from a snapshot before it serves any reads.
+    pub fn rejoin(&mut self, node: &str) -> bool {
+        match self.departed.iter().position(|n| n == node) {
+            Some(idx) => {
+                self.departed.remove(idx);
+                let replica = ReplicaShard::bootstrapping(self.primary.shard_id());
+                self.primary.add_replica(replica.shard_id());
+                self.replicas.push((node.into(), replica));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Record that the bootstrapping replica on `node` has loaded its snapshot, it still only serves
+    /// reads once it's at most `max_lag` operations behind the primary
+    pub fn snapshot_restored(&mut self, node: &str, max_lag: u64) -> bool {
+        match self.replicas.iter_mut().find(|(n, _)| n == node) {
+            Some((_, replica)) if replica.state() == ReplicaState::Bootstrapping => {
+                replica.set_state(ReplicaState::CatchingUp);
+                self.catch_up(node, max_lag);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Mark the replica on `node` ready once it has its snapshot and is at most `max_lag`
+    /// operations behind the primary, returning whether it became ready. A replica whose lag
+    /// hasn't been measured yet isn't taken to be caught up.
+    pub fn catch_up(&mut self, node: &str, max_lag: u64) -> bool {
+        let caught_up = self.replica_lag.get(node).map_or(false, |lag| *lag <= max_lag);
+        match self.replicas.iter_mut().find(|(n, _)| n == node) {
+            Some((_, replica)) if replica.state() == ReplicaState::CatchingUp && caught_up => {
+                replica.set_state(ReplicaState::Ready);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Every node holding a copy of the index, primary first
+    pub fn nodes(&self) -> Vec<String> {
+        let replicas = self.replicas.iter().map(|(n, _)| n.clone());
+        std::iter::once(self.primary_node.clone()).chain(replicas).collect()
+    }
+}
+
+/// The shard out of `shards` that operations sent with the routing value `routing` are pinned to.
+/// This is FNV-1a rather than std's hasher, whose output isn't promised to stay the same between
+/// releases, so every node and every restart agrees on where a routing value lives.
+pub fn shard_for(routing: &str, shards: usize) -> usize {
+    (fnv1a(routing) % shards.max(1) as u64) as usize
+}
+
+fn fnv1a(value: &str) -> u64 {
+    value
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325u64, |h, b| (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// Where `value` lands on a [`HashRing`]. FNV-1a of short keys that only differ in their last
+/// byte stay close together, so the hash is mixed with MurmurHash3's finalizer to scatter them.
+fn ring_hash(value: &str) -> u64 {
+    let mut h = fnv1a(value);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+/// Places routing values on shards by consistent hashing. Every shard owns `virtual_nodes` points
+/// on a ring of hashes and a routing value belongs to the shard owning the first point at or after
+/// its own hash. A shard's points are hashed from the id of the node holding it, so they don't move
+/// when the shards are listed in another order. Adding or removing a shard only moves the values
+/// next to the points it gains or loses, about one in every n, where [`shard_for`] moves nearly all of them.
+#[derive(Clone, Debug)]
+pub struct HashRing {
+    virtual_nodes: usize,
+    /// The id of the node holding each shard, by shard number
+    nodes: Vec<String>,
+    points: BTreeMap<u64, String>,
+}
+
+impl HashRing {
+    /// A ring of the shards held by `nodes`, each with `virtual_nodes` points
+    pub fn new<S: AsRef<str>>(nodes: &[S], virtual_nodes: usize) -> Self {
+        let mut ring = Self {
+            virtual_nodes: virtual_nodes.max(1),
+            nodes: Vec::new(),
+            points: BTreeMap::new(),
+        };
+        for node in nodes {
+            ring.add_node(node.as_ref());
+        }
+        ring
+    }
+
+    /// Put the shard on `node` on the ring, taking over the routing values just before each of its points
+    fn add_node(&mut self, node: &str) {
+        for point in 0..self.virtual_nodes {
+            self.points.insert(ring_hash(&format!("{}-{}", node, point)), node.into());
+        }
+        self.nodes.push(node.into());
+    }
+
+    /// Take the shard on `node` off the ring, its routing values going to the shards owning the
+    /// points after its own
+    #[cfg(test)]
+    fn remove_node(&mut self, node: &str) {
+        self.points.retain(|_, n| n != node);
+        self.nodes.retain(|n| n != node);
+    }
+
+    /// The node holding the shard operations sent with `routing` are pinned to, none when the ring has no shards
+    pub fn node_for(&self, routing: &str) -> Option<&str> {
+        let hash = ring_hash(routing);
+        self.points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+
+    /// The number of the shard operations sent with `routing` are pinned to, none when the ring has no shards
+    pub fn shard_for(&self, routing: &str) -> Option<usize> {
+        let node = self.node_for(routing)?;
+        self.nodes.iter().position(|n| n == node)
+    }
+}
+
+/// Pins routing values to a fixed number of partitions and partitions to shards. A routing value's
+/// partition is its hash modulo the number of partitions, which never changes for the index, while
+/// which shard holds each partition is a table that can be changed. Moving a partition to another
+/// shard moves only the routing values in it, and no routing value is ever hashed to another partition.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PartitionTable {
+    /// The shard holding each partition, by partition number
+    assignments: Vec<usize>,
+}
+
+impl PartitionTable {
+    /// `partitions` partitions dealt out over the shards `0..shards` in turn
+    pub fn new(partitions: usize, shards: usize) -> Self {
+        let shards = shards.max(1);
+        Self {
+            assignments: (0..partitions.max(1)).map(|p| p % shards).collect(),
+        }
+    }
+
+    /// How many partitions routing values are hashed to
+    pub fn partitions(&self) -> usize {
+        self.assignments.len()
+    }
+
+    /// The partition operations sent with `routing` belong to, which only depends on the number of partitions
+    pub fn partition_for(&self, routing: &str) -> usize {
+        shard_for(routing, self.partitions())
+    }
+
+    /// How many shards the partitions are dealt out over, counting every shard up to the highest
+    /// numbered one holding a partition
+    pub fn shards(&self) -> usize {
+        self.assignments.iter().max().map_or(0, |s| s + 1)
+    }
+
+    /// The shard holding `partition`
+    pub fn shard_of(&self, partition: usize) -> Option<usize> {
+        self.assignments.get(partition).copied()
+    }
+
+    /// The shard operations sent with `routing` are pinned to
+    pub fn shard_for(&self, routing: &str) -> usize {
+        self.assignments[self.partition_for(routing)]
+    }
+
+    /// Have `shard` hold `partition` from now on
+    pub fn move_partition(&mut self, partition: usize, shard: usize) -> Result<(), Error> {
+        let partitions = self.partitions();
+        match self.assignments.get_mut(partition) {
+            Some(held_by) => {
+                *held_by = shard;
+                Ok(())
+            }
+            None => Err(Error::QueryError(format!(
+                "Partition {} doesn't exist, the index has {} partitions",
+                partition, partitions
+            ))),
+        }
+    }
+
+    /// Give the new `shard` its share of the partitions, taking them from whichever shards hold the
+    /// most. The other partitions stay where they are.
+    pub fn add_shard(&mut self, shard: usize) {
+        let mut held: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (partition, s) in self.assignments.iter().enumerate() {
+            held.entry(*s).or_default().push(partition);
+        }
+        let share = self.partitions() / (held.len() + if held.contains_key(&shard) { 0 } else { 1 });
+        while held.get(&shard).map_or(0, Vec::len) < share {
+            let fullest = held
+                .iter()
+                .filter(|(s, _)| **s != shard)
+                .max_by_key(|(s, p)| (p.len(), std::cmp::Reverse(**s)))
+                .map(|(s, _)| *s);
+            let partition = match fullest.and_then(|s| held.get_mut(&s)).and_then(Vec::pop) {
+                Some(partition) => partition,
+                None => break,
+            };
+            self.assignments[partition] = shard;
+            held.entry(shard).or_default().push(partition);
+        }
+    }
+
+    /// Give every shard the index grew to beyond the ones holding partitions its share of them
+    pub fn grow(&mut self, shards: usize) {
+        for shard in self.shards()..shards {
+            self.add_shard(shard);
+        }
+    }
+}
+
+/// How a sharded index decides which shard a routing value is pinned to
+#[derive(Clone, Debug)]
+pub enum ShardRouter {
+    Ring(HashRing),
+    Partitions(PartitionTable),
+}
+
+impl ShardRouter {
+    /// The shard operations sent with `routing` are pinned to, none when there are no shards
+    pub fn shard_for(&self, routing: &str) -> Option<usize> {
+        match self {
+            ShardRouter::Ring(ring) => ring.shard_for(routing),
+            ShardRouter::Partitions(table) => Some(table.shard_for(routing)),
+        }
+    }
+}
+
+impl From<ShardPlacement> for IndexRouting {
+    fn from(placement: ShardPlacement) -> Self {
+        let mut primary = placement.primary;
+        for (_, replica) in &placement.replicas {
+            primary.add_replica(replica.shard_id());
+        }
+        Self {
+            primary,
+            primary_node: placement.primary_node,
+            replicas: placement.replicas,
+            unassigned: placement.unassigned,
+            generation: 0,
+            replica_lag: HashMap::new(),
+            departed: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cluster::placement::{Placement, RoundRobin};
+
+    use super::*;
+
+    fn routing(replicas: usize) -> IndexRouting {
+        let nodes: Vec<_> = vec!["a:8081", "b:8081", "c:8081"]
+            .into_iter()
+            .map(|n| crate::cluster::placement::NodeLoad::new(n.into()))
+            .collect();
+        IndexRouting::from(RoundRobin::default().place_shard(&nodes, replicas).unwrap())
+    }
+
+    #[test]
+    fn test_read_preference() {
+        let routing = routing(2);
+        assert_eq!(routing.nodes(), vec!["a:8081", "b:8081", "c:8081"]);
+
+        let replica = routing.read_node(ReadPreference::Replica, "z:8081", 0, |_| true);
+        assert_ne!(replica, routing.primary_node);
+        let reads: Vec<_> = (0..4)
+            .map(|t| routing.read_node(ReadPreference::Replica, "z:8081", t, |_| true))
+            .collect();
+        assert_eq!(reads, vec!["b:8081", "c:8081", "b:8081", "c:8081"]);
+
+        assert_eq!(routing.read_node(ReadPreference::Primary, "c:8081", 0, |_| true), "a:8081");
+        assert_eq!(routing.read_node(ReadPreference::Nearest, "c:8081", 0, |_| true), "c:8081");
+        assert_eq!(routing.read_node(ReadPreference::Nearest, "z:8081", 0, |_| true), "b:8081");
+    }
+
+    #[test]
+    fn test_parse_preference() {
+        assert_eq!(ReadPreference::parse("nearest").unwrap(), (ReadPreference::Nearest, None));
+        assert_eq!(
+            ReadPreference::parse("session:u-1").unwrap(),
+            (ReadPreference::Replica, Some("u-1"))
+        );
+        for bad in &["session:", "u-1", "Primary", ""] {
+            assert!(ReadPreference::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_session_node() {
+        let routing = routing(2);
+        let first = routing.session_node("session-1", |_| true);
+        assert_ne!(first, routing.primary_node);
+        assert!((0..10).all(|_| routing.session_node("session-1", |_| true) == first));
+
+        let used: std::collections::HashSet<String> = (0..20).map(|i| routing.session_node(&format!("session-{}", i), |_| true)).collect();
+        assert_eq!(used.len(), 2);
+        assert_eq!(routing(0).session_node("session-1", |_| true), "a:8081");
+    }
+
+    #[test]
+    fn test_read_falls_back_to_primary() {
+        let single = routing(1);
+        assert_eq!(single.read_node(ReadPreference::Replica, "z:8081", 0, |n| n != "b:8081"), "a:8081");
+        assert_eq!(routing(0).read_node(ReadPreference::Replica, "z:8081", 0, |_| true), "a:8081");
+    }
+
+    #[test]
+    fn test_shard_for() {
+        assert_eq!(shard_for("user-1", 3), shard_for("user-1", 3));
+        assert_eq!(shard_for("", 3), (0xcbf2_9ce4_8422_2325u64 % 3) as usize);
+        assert_eq!(shard_for("user-1", 0), 0);
+        let used: std::collections::HashSet<usize> = (0..100).map(|i| shard_for(&format!("user-{}", i), 3)).collect();
+        assert_eq!(used.len(), 3);
+    }
+
+    #[test]
+    fn test_hash_ring_remaps_few_keys() {
+        let keys: Vec<String> = (0..10_000).map(|i| format!("user-{}", i)).collect();
+        let nodes: Vec<String> = (0..4).map(|n| format!("10.0.0.{}:8081", n)).collect();
+        let mut ring = HashRing::new(&nodes, 128);
+        let before: Vec<&str> = keys.iter().map(|k| ring.node_for(k).unwrap()).collect();
+        for node in &nodes {
+            let owned = before.iter().filter(|n| *n == node).count();
+            assert!(owned > 1_500 && owned < 3_500, "{} owns {}", node, owned);
+        }
+        let before: Vec<String> = before.into_iter().map(String::from).collect();
+
+        ring.add_node("10.0.0.4:8081");
+        let after: Vec<&str> = keys.iter().map(|k| ring.node_for(k).unwrap()).collect();
+        let moved: Vec<usize> = (0..keys.len()).filter(|i| before[*i] != after[*i]).collect();
+        // Every key that moved went to the new shard, and roughly a fifth of them did
+        assert!(moved.iter().all(|i| after[*i] == "10.0.0.4:8081"));
+        let by_mod = keys.iter().filter(|k| shard_for(k, 4) != shard_for(k, 5)).count();
+        assert!(moved.len() < 3_000, "{} keys moved", moved.len());
+        assert!(by_mod > 7_000, "{} keys moved", by_mod);
+
+        ring.remove_node("10.0.0.4:8081");
+        assert!(keys.iter().zip(&before).all(|(k, n)| ring.node_for(k) == Some(n.as_str())));
+        assert_eq!(HashRing::new::<&str>(&[], 128).shard_for("user-1"), None);
+    }
+
+    #[test]
+    fn test_hash_ring_keeps_keys_on_their_node() {
+        let keys: Vec<String> = (0..1_000).map(|i| format!("user-{}", i)).collect();
+        let nodes = ["10.0.0.1:8081", "10.0.0.2:8081", "10.0.0.3:8081"];
+        let ring = HashRing::new(&nodes, 128);
+        let reversed: Vec<&str> = nodes.iter().rev().copied().collect();
+        let listed_again = HashRing::new(&reversed, 128);
+        for key in &keys {
+            let node = ring.node_for(key).unwrap();
+            assert_eq!(listed_again.node_for(key), Some(node));
+            assert_eq!(nodes[ring.shard_for(key).unwrap()], node);
+            assert_eq!(reversed[listed_again.shard_for(key).unwrap()], node);
+        }
+    }
+
+    #[test]
+    fn test_partitions_stay_put_while_shards_move() -> Result<(), Error> {
+        let keys: Vec<String> = (0..10_000).map(|i| format!("user-{}", i)).collect();
+        let mut table = PartitionTable::new(64, 4);
+        assert_eq!(table.partitions(), 64);
+        assert!((0..64).all(|p| table.shard_of(p) == Some(p % 4)));
+        let partitions: Vec<usize> = keys.iter().map(|k| table.partition_for(k)).collect();
+        let before: Vec<usize> = keys.iter().map(|k| table.shard_for(k)).collect();
+
+        // Moving a partition only moves the routing values hashed to it
+        table.move_partition(5, 2)?;
+        assert!(keys.iter().zip(&partitions).all(|(k, p)| table.partition_for(k) == *p));
+        for (i, k) in keys.iter().enumerate() {
+            let expected = if partitions[i] == 5 { 2 } else { before[i] };
+            assert_eq!(table.shard_for(k), expected);
+        }
+        assert!(matches!(table.move_partition(64, 0), Err(Error::QueryError(_))));
+
+        // A new shard takes its share of partitions from the others, every routing value keeps its
+        // partition and the only ones that change shard go to the new one
+        let before: Vec<usize> = keys.iter().map(|k| table.shard_for(k)).collect();
+        table.add_shard(4);
+        let held = |s: usize| (0..64).filter(|p| table.shard_of(*p) == Some(s)).count();
+        assert_eq!(held(4), 12);
+        assert!((0..4).all(|s| held(s) == 13));
+        assert!(keys.iter().zip(&partitions).all(|(k, p)| table.partition_for(k) == *p));
+        let moved: Vec<usize> = (0..keys.len()).filter(|i| table.shard_for(&keys[*i]) != before[*i]).collect();
+        assert!(moved.iter().all(|i| table.shard_for(&keys[*i]) == 4));
+        assert!(moved.len() < 3_000, "{} keys moved", moved.len());
+
+        // Growing to more shards deals each new one its share the same way
+        let mut grown = PartitionTable::new(64, 1);
+        assert_eq!(grown.shards(), 1);
+        grown.grow(4);
+        assert_eq!(grown.shards(), 4);
+        assert!((0..4).all(|s| (0..64).filter(|p| grown.shard_of(*p) == Some(s)).count() == 16));
+
+        let router = ShardRouter::Partitions(table.clone());
+        assert!(keys.iter().all(|k| router.shard_for(k) == Some(table.shard_for(k))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_promote_and_rejoin() -> Result<(), Error> {
+        let mut routing = routing(2);
+        routing.replica_lag.insert("b:8081".into(), 10);
+        routing.replica_lag.insert("c:8081".into(), 2);
+
+        assert_eq!(routing.promote(0, |n| n != "a:8081")?, "c:8081");
+        assert_eq!(routing.generation, 1);
+        assert_eq!(routing.nodes(), vec!["c:8081", "b:8081"]);
+        assert_eq!(routing.replicas[0].1.primary_shard_id(), Some(routing.primary.shard_id()));
+        assert!(routing.promote(0, |n| n != "a:8081").is_err());
+
+        assert!(routing.rejoin("a:8081"));
+        assert!(!routing.rejoin("a:8081"));
+        assert_eq!(routing.nodes(), vec!["c:8081", "b:8081", "a:8081"]);
+        assert_eq!(routing.primary.replicas().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bootstrapping_replica_serves_no_reads() -> Result<(), Error> {
+        let mut routing = routing(2);
+        routing.promote(0, |n| n != "a:8081")?;
+        assert!(routing.rejoin("a:8081"));
+        assert_eq!(routing.health(), IndexHealth::Yellow);
+
+        let reads = |routing: &IndexRouting| -> std::collections::HashSet<String> {
+            let mut nodes: std::collections::HashSet<String> = (0..6)
+                .map(|t| routing.read_node(ReadPreference::Replica, "z:8081", t, |_| true))
+                .collect();
+            nodes.extend((0..20).map(|i| routing.session_node(&format!("session-{}", i), |_| true)));
+            nodes
+        };
+        assert!(!reads(&routing).contains("a:8081"));
+        assert_ne!(routing.read_node(ReadPreference::Nearest, "a:8081", 0, |_| true), "a:8081");
+
+        // Loading the snapshot isn't enough while the replica is still far behind the primary
+        routing.replica_lag.insert("a:8081".into(), 500);
+        assert!(routing.snapshot_restored("a:8081", 100));
+        assert!(!reads(&routing).contains("a:8081"));
+        assert!(routing.promote(1, |n| n == "a:8081").is_err());
+
+        routing.replica_lag.insert("a:8081".into(), 20);
+        assert!(routing.catch_up("a:8081", 100));
+        assert!(reads(&routing).contains("a:8081"));
+        assert_eq!(routing.read_node(ReadPreference::Nearest, "a:8081", 0, |_| true), "a:8081");
+        assert_eq!(routing.health(), IndexHealth::Green);
+        Ok(())
+    }
+}
@@ -1,8 +1,9 @@
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::pin::Pin;
 
+use hyper::server::accept::Accept;
+use hyper::server::conn::AddrIncoming;
 use tantivy::schema::Schema;
-use tokio::sync::Mutex;
 use tonic::transport::Server;
 use tonic::{Code, Request, Response, Status, Streaming};
 use tracing::*;
@@ -10,23 +11,32 @@ use tracing::*;
 use toshi_proto::cluster_rpc::*;
 use toshi_types::{DeleteDoc, DocsAffected, Error, Search};
 
+use crate::cluster::codec::WireFormat;
 use crate::handle::IndexHandle;
-use crate::index::IndexCatalog;
+use crate::index::SharedCatalog;
 use crate::AddDocument;
 
 pub type Buf = tonic::transport::Channel;
 pub type RpcClient = client::IndexServiceClient<Buf>;
 
 pub struct RpcServer {
-    catalog: Arc<Mutex<IndexCatalog>>,
+    catalog: SharedCatalog,
 }
 
 impl RpcServer {
-    pub async fn serve(addr: SocketAddr, catalog: Arc<Mutex<IndexCatalog>>) -> Result<(), tonic::transport::Error> {
+    pub async fn serve(addr: SocketAddr, catalog: SharedCatalog) -> Result<(), tonic::transport::Error> {
         let service = server::IndexServiceServer::new(RpcServer { catalog });
         Server::builder().add_service(service).serve(addr).await
     }
 
+    /// Serve on an address that's already bound, so a node bound to port 0 can be told which port
+    /// it was given before it starts
+    pub async fn serve_incoming(mut incoming: AddrIncoming, catalog: SharedCatalog) -> Result<(), tonic::transport::Error> {
+        let service = server::IndexServiceServer::new(RpcServer { catalog });
+        let incoming = futures::stream::poll_fn(move |cx| Pin::new(&mut incoming).poll_accept(cx));
+        Server::builder().add_service(service).serve_with_incoming(incoming).await
+    }
+
     //TODO: Make DNS Threads and Buffer Requests Configurable options
     pub async fn create_client(uri: http::Uri) -> Result<RpcClient, Error> {
         info!("Creating Client to: {:?}", uri);
@@ -41,8 +51,12 @@ impl RpcServer {
         ResultReply { code, message }
     }
 
-    pub fn create_search_reply(result: Option<ResultReply>, doc: Vec<u8>) -> SearchReply {
-        SearchReply { result, doc }
+    pub fn create_search_reply(result: Option<ResultReply>, doc: Vec<u8>, format: WireFormat) -> SearchReply {
+        SearchReply {
+            result,
+            doc,
+            format: format.name().into(),
+        }
     }
 
     pub fn error_response<T>(code: Code, msg: String) -> Result<Response<T>, Status> {
@@ -50,8 +64,8 @@ impl RpcServer {
         Err(status)
     }
 
-    pub fn query_or_all(b: &[u8]) -> Result<Search, Box<dyn std::error::Error>> {
-        let deser: Search = serde_json::from_slice(b)?;
+    pub fn query_or_all(b: &[u8], format: WireFormat) -> Result<Search, Box<dyn std::error::Error>> {
+        let deser: Search = format.decode(b)?;
         if deser.query.is_none() {
             return Ok(Search::all_docs());
         }
@@ -62,22 +76,24 @@ impl RpcServer {
 #[async_trait::async_trait]
 impl server::IndexService for RpcServer {
     async fn ping(&self, _: Request<PingRequest>) -> Result<Response<PingReply>, Status> {
-        Ok(Response::new(PingReply { status: "OK".into() }))
+        let formats = WireFormat::SUPPORTED.iter().map(|f| f.name().into()).collect();
+        let load = self.catalog.local_load();
+        Ok(Response::new(PingReply {
+            status: "OK".into(),
+            formats,
+            num_docs: load.num_docs,
+            disk_size: load.disk_size,
+        }))
     }
 
     async fn place_index(&self, request: Request<PlaceRequest>) -> Result<Response<ResultReply>, Status> {
         let PlaceRequest { index, schema } = request.into_inner();
-        let mut cat = self.catalog.lock().await;
+        let cat = &self.catalog;
         if let Ok(schema) = serde_json::from_slice::<Schema>(&schema) {
-            let ip = cat.base_path().clone();
-            if let Ok(new_index) = IndexCatalog::create_from_managed(ip, &index.clone(), schema) {
-                if cat.add_index(index.clone(), new_index).is_ok() {
-                    Ok(Response::new(RpcServer::ok_result()))
-                } else {
-                    Self::error_response(Code::Internal, format!("Insert: {} failed", index))
-                }
-            } else {
-                Self::error_response(Code::Internal, format!("Could not create index: {}", index))
+            match cat.create_local_index(&index, schema, |i| i.mark_created()).await {
+                Ok(_) => Ok(Response::new(RpcServer::ok_result())),
+                Err(Error::IndexExists(_)) => Self::error_response(Code::AlreadyExists, format!("Index {} already exists", index)),
+                Err(_) => Self::error_response(Code::Internal, format!("Could not create index: {}", index)),
             }
         } else {
             Self::error_response(Code::NotFound, "Invalid schema in request".into())
@@ -85,7 +101,7 @@ impl server::IndexService for RpcServer {
     }
 
     async fn list_indexes(&self, req: Request<ListRequest>) -> Result<Response<ListReply>, Status> {
-        let cat = self.catalog.lock().await;
+        let cat = &self.catalog;
         info!("Request From: {:?}", req);
         let indexes = cat.get_collection();
         let lists: Vec<String> = indexes.into_iter().map(|(t, _)| t.to_string()).collect();
@@ -96,10 +112,10 @@ impl server::IndexService for RpcServer {
 
     async fn place_document(&self, request: Request<DocumentRequest>) -> Result<Response<ResultReply>, Status> {
         info!("REQ = {:?}", &request);
-        let DocumentRequest { index, document } = request.into_inner();
-        let cat = self.catalog.lock().await;
+        let DocumentRequest { index, document, format } = request.into_inner();
+        let cat = &self.catalog;
         if let Ok(idx) = cat.get_index(&index) {
-            if let Ok(doc) = serde_json::from_slice::<AddDocument>(&document) {
+            if let Ok(doc) = WireFormat::from_name(&format).and_then(|f| f.decode::<AddDocument>(&document)) {
                 if idx.add_document(doc).await.is_ok() {
                     Ok(Response::new(RpcServer::ok_result()))
                 } else {
@@ -114,10 +130,10 @@ impl server::IndexService for RpcServer {
     }
 
     async fn delete_document(&self, request: Request<DeleteRequest>) -> Result<Response<DeleteReply>, Status> {
-        let DeleteRequest { index, terms } = request.into_inner();
-        let cat = self.catalog.lock().await;
+        let DeleteRequest { index, terms, format } = request.into_inner();
+        let cat = &self.catalog;
         if let Ok(idx) = cat.get_index(&index) {
-            if let Ok(delete_docs) = serde_json::from_slice::<DeleteDoc>(&terms) {
+            if let Ok(delete_docs) = WireFormat::from_name(&format).and_then(|f| f.decode::<DeleteDoc>(&terms)) {
                 let DocsAffected { docs_affected } = idx.delete_term(delete_docs).await?;
                 Ok(Response::new(DeleteReply { index, docs_affected }))
             } else {
@@ -130,10 +146,14 @@ impl server::IndexService for RpcServer {
 
     async fn search_index(&self, request: Request<SearchRequest>) -> Result<Response<SearchReply>, Status> {
         let inner = request.into_inner();
-        let cat = self.catalog.lock().await;
+        let cat = &self.catalog;
         {
             if let Ok(index) = cat.get_index(&inner.index) {
-                let query = match Self::query_or_all(&inner.query) {
+                let format = match WireFormat::from_name(&inner.format) {
+                    Ok(f) => f,
+                    Err(e) => return Self::error_response(Code::InvalidArgument, e.to_string()),
+                };
+                let query = match Self::query_or_all(&inner.query, format) {
                     Ok(v) => v,
                     Err(e) => return Self::error_response(Code::Internal, e.to_string()),
                 };
@@ -142,9 +162,9 @@ impl server::IndexService for RpcServer {
                 match index.search_index(query).await {
                     Ok(query_results) => {
                         info!("Query Response = {:?} hits", query_results.hits);
-                        let query_bytes: Vec<u8> = serde_json::to_vec(&query_results).unwrap();
+                        let query_bytes: Vec<u8> = format.encode(&query_results)?;
                         let result = Some(RpcServer::ok_result());
-                        Ok(Response::new(RpcServer::create_search_reply(result, query_bytes)))
+                        Ok(Response::new(RpcServer::create_search_reply(result, query_bytes, format)))
                     }
                     Err(e) => Self::error_response(Code::Internal, e.to_string()),
                 }
@@ -156,7 +176,7 @@ impl server::IndexService for RpcServer {
 
     async fn get_summary(&self, request: Request<SummaryRequest>) -> Result<Response<SummaryReply>, Status> {
         let SummaryRequest { index } = request.into_inner();
-        if let Ok(idx) = self.catalog.lock().await.get_index(&index) {
+        if let Ok(idx) = self.catalog.get_index(&index) {
             if let Ok(metas) = idx.get_index().load_metas() {
                 let meta_json = serde_json::to_vec(&metas).unwrap();
                 Ok(Response::new(SummaryReply { summary: meta_json }))
@@ -174,7 +194,9 @@ impl server::IndexService for RpcServer {
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
+    use std::sync::Arc;
+
     use futures::future::{try_select, Either};
     use http::Uri;
 
@@ -182,6 +204,15 @@ mod tests {
 
     use super::*;
 
+    /// Serve `catalog` on a port the OS picks, returning the node's address and a client of it
+    pub async fn spawn_node(catalog: SharedCatalog) -> Result<(Uri, RpcClient), Box<dyn std::error::Error>> {
+        let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse()?)?;
+        let uri = format!("http://{}/", incoming.local_addr()).parse::<Uri>()?;
+        tokio::spawn(RpcServer::serve_incoming(incoming, catalog));
+        let client = RpcServer::create_client(uri.clone()).await?;
+        Ok((uri, client))
+    }
+
     pub fn routes(port: i16) -> Result<(SocketAddr, Uri), Box<dyn std::error::Error>> {
         let addr = format!("127.0.0.1:{}", port).parse::<SocketAddr>()?;
         let uri = format!("http://127.0.0.1:{}/", port).parse::<Uri>()?;
@@ -257,6 +288,7 @@ mod tests {
                 .search_index(Request::new(SearchRequest {
                     index: "test_index".into(),
                     query: query_bytes,
+                    format: String::new(),
                 }))
                 .await
         });
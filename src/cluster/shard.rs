@@ -14,21 +14,46 @@ pub trait Shard: Serialize {
     fn primary_shard_id(&self) -> Option<Uuid>;
     fn is_primary(&self) -> bool;
     fn index_name(&self) -> Result<String, Error>;
+    fn num_docs(&self) -> u64;
+    fn disk_size(&self) -> u64;
 }
 
 /// A PrimaryShard is a writable partition of an Index
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct PrimaryShard {
     shard_id: Uuid,
+    #[serde(default)]
+    replicas: Vec<Uuid>,
     #[serde(skip_serializing, skip_deserializing)]
     index_handle: Option<LocalIndex>,
 }
 
+/// How far a replica is in becoming a full copy of its primary. Only a `Ready` replica serves
+/// reads, before that its index is missing documents the primary has.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicaState {
+    /// Copying a snapshot of the primary's index
+    Bootstrapping,
+    /// Has the snapshot and is replaying the writes made since it was taken
+    CatchingUp,
+    /// Close enough behind the primary to serve reads
+    Ready,
+}
+
+impl Default for ReplicaState {
+    fn default() -> Self {
+        ReplicaState::Ready
+    }
+}
+
 /// A ReplicaShard is a copy of a specific PrimaryShard that is read-only
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ReplicaShard {
     shard_id: Uuid,
     primary_shard_id: Uuid,
+    #[serde(default)]
+    state: ReplicaState,
     #[serde(skip_serializing, skip_deserializing)]
     index_handle: Option<LocalIndex>,
 }
@@ -47,15 +72,28 @@ impl PrimaryShard {
                 self.index_handle = Some(lh);
                 Ok(self)
             }
-            Err(e) => Err(Error::IOError(e.to_string())),
+            Err(e) => Err(e),
         }
     }
+
+    /// Records a ReplicaShard as a copy of this shard
+    pub fn add_replica(&mut self, replica_id: Uuid) {
+        if !self.replicas.contains(&replica_id) {
+            self.replicas.push(replica_id);
+        }
+    }
+
+    /// Returns the IDs of the ReplicaShards copying this shard
+    pub fn replicas(&self) -> &[Uuid] {
+        &self.replicas
+    }
 }
 
 impl Default for PrimaryShard {
     fn default() -> Self {
         PrimaryShard {
             shard_id: Uuid::new_v4(),
+            replicas: Vec::new(),
             index_handle: None,
         }
     }
@@ -81,9 +119,19 @@ impl Shard for PrimaryShard {
     fn index_name(&self) -> Result<String, Error> {
         match self.index_handle {
             Some(ref handle) => Ok(handle.get_name()),
-            None => Err(Error::IOError("Unable to get index handle".to_string())),
+            None => Err(Error::UnknownIndex(format!("shard {}", self.shard_id))),
         }
     }
+
+    /// Returns the number of live documents in this shard, 0 if it has no index yet
+    fn num_docs(&self) -> u64 {
+        self.index_handle.as_ref().map(LocalIndex::num_docs).unwrap_or(0)
+    }
+
+    /// Returns the bytes this shard's segments take up, 0 if it has no index yet
+    fn disk_size(&self) -> u64 {
+        self.index_handle.as_ref().map(|h| h.get_space().total() as u64).unwrap_or(0)
+    }
 }
 
 impl ReplicaShard {
@@ -92,10 +140,47 @@ impl ReplicaShard {
         ReplicaShard {
             primary_shard_id,
             shard_id: Uuid::new_v4(),
+            state: ReplicaState::Ready,
             index_handle: None,
         }
     }
 
+    /// Creates a ReplicaShard that still has to bootstrap from a snapshot of its primary
+    pub fn bootstrapping(primary_shard_id: Uuid) -> ReplicaShard {
+        ReplicaShard {
+            state: ReplicaState::Bootstrapping,
+            ..ReplicaShard::new(primary_shard_id)
+        }
+    }
+
+    /// How far this replica is in becoming a full copy of its primary
+    pub fn state(&self) -> ReplicaState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: ReplicaState) {
+        self.state = state;
+    }
+
+    /// Whether this replica is complete enough to serve reads
+    pub fn is_ready(&self) -> bool {
+        self.state == ReplicaState::Ready
+    }
+
+    /// Turns this replica into the primary of its partition, keeping its ID and index
+    pub fn promote(self) -> PrimaryShard {
+        PrimaryShard {
+            shard_id: self.shard_id,
+            replicas: Vec::new(),
+            index_handle: self.index_handle,
+        }
+    }
+
+    /// Points this replica at a new primary after a failover
+    pub fn follow(&mut self, primary_shard_id: Uuid) {
+        self.primary_shard_id = primary_shard_id;
+    }
+
     /// Adds an IndexHandle to a ReplicaShard
     pub fn with_index(mut self, index: Index, name: String) -> Result<ReplicaShard, Error> {
         let settings = Settings::default();
@@ -104,7 +189,7 @@ impl ReplicaShard {
                 self.index_handle = Some(lh);
                 Ok(self)
             }
-            Err(e) => Err(Error::IOError(e.to_string())),
+            Err(e) => Err(e),
         }
     }
 }
@@ -130,9 +215,19 @@ impl Shard for ReplicaShard {
     fn index_name(&self) -> Result<String, Error> {
         match self.index_handle {
             Some(ref handle) => Ok(handle.get_name()),
-            None => Err(Error::IOError("No index with that name exists".to_string())),
+            None => Err(Error::UnknownIndex(format!("shard {}", self.shard_id))),
         }
     }
+
+    /// Returns the number of live documents in this shard, 0 if it has no index yet
+    fn num_docs(&self) -> u64 {
+        self.index_handle.as_ref().map(LocalIndex::num_docs).unwrap_or(0)
+    }
+
+    /// Returns the bytes this shard's segments take up, 0 if it has no index yet
+    fn disk_size(&self) -> u64 {
+        self.index_handle.as_ref().map(|h| h.get_space().total() as u64).unwrap_or(0)
+    }
 }
 
 #[cfg(test)]
@@ -151,4 +246,12 @@ mod tests {
         let test_replica_shard = ReplicaShard::new(test_primary_shard.shard_id());
         assert!(!test_replica_shard.is_primary());
     }
+
+    #[test]
+    fn test_shard_without_index() {
+        let primary = PrimaryShard::new();
+        let replica = ReplicaShard::new(primary.shard_id());
+        assert!(matches!(primary.index_name(), Err(Error::UnknownIndex(_))));
+        assert!(matches!(replica.index_name(), Err(Error::UnknownIndex(_))));
+    }
 }
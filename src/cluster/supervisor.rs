@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time;
+use tracing::*;
+
+use toshi_proto::cluster_rpc::PingRequest;
+
+use crate::cluster::replication::bootstrap;
+use crate::index::{IndexCatalog, SharedCatalog};
+
+/// Ping a node, a node that errors or doesn't answer within the timeout is considered unreachable
+pub async fn ping_node(node: String, timeout: Duration) -> bool {
+    let ping = async {
+        let mut client = IndexCatalog::create_client(node).await?;
+        client.ping(tonic::Request::new(PingRequest {})).await?;
+        Ok::<_, toshi_types::Error>(())
+    };
+    match time::timeout(timeout, ping).await {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            debug!("Ping failed: {}", e);
+            false
+        }
+        Err(_) => false,
+    }
+}
+
+/// Counts the health checks each node missed in a row, a node is only taken to be down once it
+/// has missed `required` of them
+#[derive(Debug)]
+pub struct MissedPings {
+    required: usize,
+    misses: HashMap<String, usize>,
+}
+
+impl MissedPings {
+    pub fn new(required: usize) -> Self {
+        Self {
+            required: required.max(1),
+            misses: HashMap::new(),
+        }
+    }
+
+    /// Record whether `node` answered its latest health check, returning whether it's healthy
+    pub fn record(&mut self, node: &str, answered: bool) -> bool {
+        if answered {
+            self.misses.remove(node);
+            return true;
+        }
+        let misses = self.misses.entry(node.into()).or_insert(0);
+        *misses += 1;
+        *misses < self.required
+    }
+}
+
+/// Health checks every node in the cluster on an interval, failing over any index whose primary
+/// lives on a node that missed `misses` checks in a row and bringing nodes that come back in as
+/// replicas, bootstrapped from the primary
+pub async fn supervisor(cat: SharedCatalog, check_interval: f32, check_timeout: f32, misses: usize) -> Result<(), ()> {
+    let timeout = Duration::from_secs_f32(check_timeout);
+    let mut interval = time::interval(Duration::from_secs_f32(check_interval));
+    let mut missed = MissedPings::new(misses);
+    loop {
+        interval.tick().await;
+        for node in cat.settings.get_nodes() {
+            let healthy = missed.record(&node, ping_node(node.clone(), timeout).await);
+            let rejoined = match cat.observe_node(&node, healthy) {
+                Ok(rejoined) => rejoined,
+                Err(e) => {
+                    error!("Could not update health of node {}: {}", node, e);
+                    continue;
+                }
+            };
+            // The primary of an index sends the snapshot to the replicas that rejoined it
+            for index in rejoined {
+                if cat.write_node(&index).ok().as_deref() != Some(cat.settings.place_addr.as_str()) {
+                    continue;
+                }
+                let (cat, node) = (Arc::clone(&cat), node.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = bootstrap(&cat, &index, &node).await {
+                        error!("Could not bootstrap the replica of {} on {}: {}", index, node, e);
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missed_pings() {
+        let mut missed = MissedPings::new(3);
+        assert!(missed.record("a:8081", false));
+        assert!(missed.record("a:8081", false));
+        // An answer in between starts the count over
+        assert!(missed.record("a:8081", true));
+        assert!(missed.record("a:8081", false));
+        assert!(missed.record("a:8081", false));
+        assert!(missed.record("b:8081", false));
+        assert!(!missed.record("a:8081", false));
+        assert!(!missed.record("a:8081", false));
+        assert!(missed.record("a:8081", true));
+    }
+}
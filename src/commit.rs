@@ -1,30 +1,42 @@
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use tantivy::{IndexMeta, IndexWriter, Opstamp};
 use tokio::time;
 use tracing::*;
 
 use crate::index::SharedCatalog;
 
-#[allow(irrefutable_let_patterns)]
+/// Commit everything a writer holds, keeping the time of the commit, in milliseconds since the
+/// epoch, as the commit's payload so it can be read back from the index's metas
+pub fn commit(writer: &mut IndexWriter) -> tantivy::Result<Opstamp> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+    let mut prepared = writer.prepare_commit()?;
+    prepared.set_payload(&now.to_string());
+    prepared.commit()
+}
+
+/// When the last commit of an index was made, in milliseconds since the epoch. Indexes that were
+/// never committed, or only by something other than [`commit`], have no time.
+pub fn committed_at(metas: &IndexMeta) -> Option<u64> {
+    metas.payload.as_ref().and_then(|p| p.parse().ok())
+}
+
 pub async fn watcher(cat: SharedCatalog, commit_duration: f32, lock: Arc<AtomicBool>) -> Result<(), ()> {
-    while let _ = time::interval(Duration::from_secs_f32(commit_duration)).tick().await {
-        let cat = cat.lock().await;
+    let mut interval = time::interval(Duration::from_secs_f32(commit_duration));
+    loop {
+        interval.tick().await;
         for (key, index) in cat.get_collection().into_iter() {
-            let writer = index.get_writer();
             let current_ops = index.get_opstamp();
             if current_ops == 0 {
                 debug!("No update to index={}, opstamp={}", key, current_ops);
             } else if !lock.load(Ordering::SeqCst) {
-                let mut w = writer.lock().await;
                 debug!("Committing {}...", key);
-                w.commit().unwrap();
-                index.set_opstamp(0);
+                index.commit().await.unwrap();
             }
         }
     }
-    Ok(())
 }
 
 #[cfg(test)]
@@ -35,6 +47,7 @@ pub mod tests {
 
     use crate::handlers::{add_document, all_docs};
     use crate::index::tests::*;
+    use crate::router::QueryOptions;
     use crate::SearchResults;
 
     use super::*;
@@ -49,7 +62,7 @@ pub mod tests {
 
         let body = r#"{"document": { "test_text": "Babbaboo!", "test_u64": 10 , "test_i64": -10, "test_unindex": "asdf1234" } }"#;
 
-        add_document(Arc::clone(&catalog), Body::from(body), "test_index".into())
+        add_document(Arc::clone(&catalog), Body::from(body), "test_index".into(), QueryOptions::default())
             .await
             .unwrap();
 
@@ -0,0 +1,101 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use serde::{Deserialize, Serialize};
+use tantivy::schema::{Schema, SchemaBuilder, STORED, STRING};
+
+use toshi_types::{Error, IndexOptions};
+
+use crate::handle::{IndexHandle, LocalIndex};
+use crate::index::IndexCatalog;
+use crate::{AddDocument, Result};
+
+/// Where documents that fail to index during a bulk load are kept so they can be inspected and
+/// reprocessed later
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeadLetter {
+    /// Append every failed document to this file as a line of JSON
+    File(String),
+    /// Add every failed document to this index, it is created if it doesn't exist
+    Index(String),
+}
+
+/// A document that could not be indexed along with why
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct DeadLetterDoc {
+    pub index: String,
+    pub document: String,
+    pub error: String,
+}
+
+impl DeadLetter {
+    /// The schema of a dead letter index, every field is stored so the documents can be read back out
+    pub fn schema() -> Schema {
+        let mut builder = SchemaBuilder::new();
+        builder.add_text_field("index", STRING | STORED);
+        builder.add_text_field("document", STORED);
+        builder.add_text_field("error", STORED);
+        builder.build()
+    }
+
+    pub async fn send(&self, catalog: &IndexCatalog, docs: &[DeadLetterDoc]) -> Result<usize> {
+        match self {
+            DeadLetter::File(path) => {
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                for doc in docs {
+                    let mut line = serde_json::to_vec(doc)?;
+                    line.push(b'\n');
+                    file.write_all(&line)?;
+                }
+                Ok(docs.len())
+            }
+            DeadLetter::Index(name) => {
+                // Two bulk loads failing at once may both create the index, the second finds it there
+                let handle = match catalog.create_local_index(name, Self::schema(), LocalIndex::mark_created).await {
+                    Err(Error::IndexExists(_)) => catalog.get_index(name)?,
+                    created => created?,
+                };
+                for (i, doc) in docs.iter().enumerate() {
+                    let options = IndexOptions {
+                        commit: i + 1 == docs.len(),
+                    };
+                    handle
+                        .add_document(AddDocument::new(Some(options), serde_json::to_value(doc)?))
+                        .await?;
+                }
+                Ok(docs.len())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::collector::Count;
+    use tantivy::query::AllQuery;
+
+    use crate::settings::DirectoryKind;
+
+    use super::*;
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_concurrent_dead_letter_index() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = DirectoryKind::Ram;
+        let sink = DeadLetter::Index("dead_letters".into());
+        let doc = |error: &str| DeadLetterDoc {
+            index: "test_index".into(),
+            document: "{}".into(),
+            error: error.into(),
+        };
+
+        // Both loads find the index missing, only one of them creates it and both of their documents land in it
+        let (first, second) = futures::join!(sink.send(&catalog, &[doc("first")]), sink.send(&catalog, &[doc("second")]));
+        assert_eq!((first?, second?), (1, 1));
+        let handle = catalog.get_index("dead_letters")?;
+        handle.reload()?;
+        assert_eq!(handle.get_index().reader()?.searcher().search(&AllQuery, &Count)?, 2);
+        Ok(())
+    }
+}
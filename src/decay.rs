@@ -0,0 +1,142 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tantivy::chrono::{DateTime, Utc};
+use tantivy::fastfield::{FastFieldReader, MultiValueIntFastFieldReader};
+use tantivy::schema::{Cardinality, Field, FieldType, Schema};
+use tantivy::{DocId, Score, SegmentReader};
+
+use toshi_types::{Decay, Error};
+
+use crate::Result;
+
+/// Multiplies the scores of a search's hits by how far a date of theirs is from an origin, see
+/// [`Decay`]. The dates are read from the field's fast field. A multi valued field tells documents
+/// without a date apart by their having no values, a single valued one holds 0 for them, so a
+/// document dated at the epoch counts as having no date.
+#[derive(Debug, Clone)]
+pub struct DecayScore {
+    decay: Decay,
+    field: Field,
+    multi: bool,
+    origin: i64,
+    scale: i64,
+    offset: i64,
+}
+
+/// The dates of one segment's documents as seconds since the epoch, whichever kind of fast field
+/// they're kept in
+enum Dates {
+    Single(FastFieldReader<i64>),
+    SingleDate(FastFieldReader<tantivy::DateTime>),
+    Multi(MultiValueIntFastFieldReader<i64>),
+    MultiDate(MultiValueIntFastFieldReader<tantivy::DateTime>),
+    Missing,
+}
+
+impl Dates {
+    fn open(reader: &SegmentReader, field: Field, multi: bool) -> Self {
+        let fast = reader.fast_fields();
+        let dates = if multi {
+            fast.i64s(field)
+                .map(Dates::Multi)
+                .or_else(|| fast.dates(field).map(Dates::MultiDate))
+        } else {
+            fast.i64(field)
+                .map(Dates::Single)
+                .or_else(|| fast.date(field).map(Dates::SingleDate))
+        };
+        dates.unwrap_or(Dates::Missing)
+    }
+
+    /// The dates of `doc`, read into `dates`, which is emptied first. The 0 a single valued field
+    /// holds for a document without a date isn't read as one.
+    fn read(&self, doc: DocId, dates: &mut Vec<i64>) {
+        dates.clear();
+        match self {
+            Dates::Single(r) => dates.extend(Some(r.get(doc)).filter(|d| *d != 0)),
+            Dates::SingleDate(r) => dates.extend(Some(r.get(doc).timestamp()).filter(|d| *d != 0)),
+            Dates::Multi(r) => r.get_vals(doc, dates),
+            Dates::MultiDate(r) => {
+                let mut values = Vec::new();
+                r.get_vals(doc, &mut values);
+                dates.extend(values.iter().map(|d| d.timestamp()));
+            }
+            Dates::Missing => (),
+        }
+    }
+}
+
+impl DecayScore {
+    /// Check `decay` against the fields of `schema`, its field has to be a date fast field or an
+    /// i64 fast field of seconds since the epoch
+    pub fn new(decay: &Decay, schema: &Schema) -> Result<Self> {
+        let field = schema
+            .get_field(&decay.field)
+            .ok_or_else(|| Error::UnknownIndexField(decay.field.clone()))?;
+        let cardinality = match schema.get_field_entry(field).field_type() {
+            FieldType::Date(options) | FieldType::I64(options) => options.get_fastfield_cardinality(),
+            _ => None,
+        };
+        let multi = match cardinality {
+            Some(cardinality) => cardinality == Cardinality::MultiValues,
+            None => {
+                return Err(Error::QueryError(format!(
+                    "{} must be a date or i64 fast field to decay",
+                    decay.field
+                )))
+            }
+        };
+        let (scale, offset) = decay.scale_and_offset_secs()?;
+        let origin = match &decay.origin {
+            Some(origin) => DateTime::parse_from_rfc3339(origin)
+                .map_err(|_| Error::QueryError(format!("Invalid origin '{}', expected an RFC 3339 date", origin)))?
+                .with_timezone(&Utc)
+                .timestamp(),
+            None => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64),
+        };
+        Ok(Self {
+            decay: decay.clone(),
+            field,
+            multi,
+            origin,
+            scale,
+            offset,
+        })
+    }
+
+    /// The function that rescores the documents of one segment, it's given each hit's id and score.
+    /// A document with several values for the field is as close to the origin as its closest one.
+    pub fn for_segment(&self, reader: &SegmentReader) -> impl FnMut(DocId, Score) -> Score {
+        let dates = Dates::open(reader, self.field, self.multi);
+        let decay = self.decay.clone();
+        let (origin, scale, offset) = (self.origin, self.scale, self.offset);
+        let mut values = Vec::new();
+        move |doc, score| {
+            dates.read(doc, &mut values);
+            let factor = match values.iter().map(|d| distance(*d, origin)).min() {
+                Some(distance) => decay.factor(distance, scale, offset),
+                None => decay.missing,
+            };
+            (f64::from(score) * factor) as Score
+        }
+    }
+}
+
+/// How many seconds `date` is from `origin` either way, dates further apart than an i64 can count
+/// are as far as it can count
+fn distance(date: i64, origin: i64) -> i64 {
+    (i128::from(date) - i128::from(origin)).abs().min(i128::from(i64::max_value())) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distance() {
+        assert_eq!(distance(10, 4), 6);
+        assert_eq!(distance(4, 10), 6);
+        assert_eq!(distance(i64::min_value(), i64::max_value()), i64::max_value());
+        assert_eq!(distance(i64::max_value(), i64::min_value()), i64::max_value());
+    }
+}
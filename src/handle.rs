@@ -1,22 +1,132 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::hash::{Hash, Hasher};
-use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use tantivy::collector::{FacetCollector, MultiCollector, TopDocs};
-use tantivy::query::{AllQuery, QueryParser};
+use futures::future::{BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use tantivy::collector::{Collector, Count, FacetCollector, MultiCollector, SegmentCollector, TopDocs};
+use tantivy::directory::error::OpenReadError;
+use tantivy::directory::Directory;
+use tantivy::query::{AllQuery, BooleanQuery, Occur, QueryParser, RangeQuery, Scorer, TermQuery, Weight};
 use tantivy::schema::*;
 use tantivy::space_usage::SearcherSpaceUsage;
-use tantivy::{Document, Index, IndexReader, IndexWriter, ReloadPolicy, Term};
-use tokio::sync::Mutex;
+use tantivy::store::COMPRESSION;
+use tantivy::tokenizer::TokenStream;
+use tantivy::{
+    DocAddress, DocId, DocSet, Document, Index, IndexReader, IndexWriter, LeasedItem, Opstamp, ReloadPolicy, Score, Searcher, SegmentId,
+    SegmentLocalId, SegmentReader, SkipResult, Term,
+};
+use tokio::sync::{Mutex, OwnedMutexGuard};
 use tracing::*;
 
-use toshi_types::{CreateQuery, DeleteDoc, DocsAffected, Error, KeyValue, Query, ScoredDoc, Search};
+use toshi_types::{
+    Aggregation, AggregationResult, ChangedDoc, ChangesResponse, CreateQuery, DateHistogram, DefaultOperator, DeleteDoc, DocRef,
+    DocsAffected, Error, FieldStats, Highlight, IndexRates, KeyValue, Query, RangeAggregation, ScoredDoc, Search, SearchProfile,
+    ShardsInfo, SortValue, TermCount, TermsAggregation, UpdateByQuery, WriterStats, ID_FIELD, SEQUENCE_FIELD, TOMBSTONE_FIELD,
+    VERSION_FIELD,
+};
 
-use crate::settings::Settings;
+use crate::aggregation::{HistogramCollector, MetricCollector, RangeCollector, TermsCollector};
+use crate::blocking::BlockingPool;
+use crate::cancel::CancelToken;
+use crate::cluster::routing::{IndexRouting, PartitionTable};
+use crate::commit::commit;
+use crate::decay::DecayScore;
+use crate::merge::MergeTracker;
+use crate::pipeline::Pipeline;
+use crate::rate::RateCounter;
+use crate::script::Script;
+use crate::sequence::Sequencer;
+use crate::settings::{ReaderReloadPolicy, Settings};
+use crate::version::VersionCache;
+use crate::wal::{Logged, WriteAheadLog};
 use crate::Result;
 use crate::{AddDocument, SearchResults};
 
+/// Where an index's own metadata is kept inside its directory. Files starting with a `.` are left
+/// alone by tantivy's garbage collection so it outlives every commit.
+pub(crate) const METADATA_FILE: &str = ".toshi.json";
+
+/// The smallest heap tantivy gives a writer, for the writer that stands in while an index is reopened
+const PLACEHOLDER_HEAP: usize = 3_000_000;
+
+/// What Toshi keeps about an index alongside tantivy's own files
+#[derive(Deserialize, Serialize, Clone, Default)]
+struct IndexMetadata {
+    #[serde(default)]
+    read_only: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    routing: Option<IndexRouting>,
+    /// The u64 fields that hold booleans, they're returned as `true` or `false` instead of 1 or 0
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    bool_fields: Vec<String>,
+    /// The text fields scored without regard to their length
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    without_norms: Vec<String>,
+    /// The text fields whose values are given as the tokens they're indexed as
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pre_tokenized: Vec<String>,
+    /// The text fields whose values longer than a number of characters are stored but not indexed
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    ignore_above: HashMap<String, usize>,
+    /// When the index was created, in milliseconds since the epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<u64>,
+    /// When the index's schema or settings were last changed, in milliseconds since the epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    modified_at: Option<u64>,
+    /// The codec the index's stored fields are compressed with, unknown for indexes created before
+    /// it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compression: Option<String>,
+    /// The partitions routing values are hashed to and the shard holding each, for an index created
+    /// with routing partitions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    partitions: Option<PartitionTable>,
+}
+
+impl IndexMetadata {
+    /// Record a change made to the index now. Every change is recorded as later than the one
+    /// before, even when both were made within the same millisecond.
+    fn touch(&mut self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        self.modified_at = Some(self.modified_at.map_or(now, |last| now.max(last + 1)));
+    }
+
+    /// Fails when the index's stored fields were compressed with a codec this build of tantivy
+    /// can't read
+    fn check_compression(&self, name: &str) -> Result<()> {
+        match &self.compression {
+            Some(codec) if codec != COMPRESSION => Err(Error::IOError(format!(
+                "Index {} stores its fields compressed with {}, but Toshi was built to compress them with {}",
+                name, codec, COMPRESSION
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    fn load(index: &Index) -> Result<Self> {
+        match index.directory().atomic_read(Path::new(METADATA_FILE)) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(OpenReadError::FileDoesNotExist(_)) => Ok(Self::default()),
+            Err(e) => Err(Error::IOError(e.to_string())),
+        }
+    }
+
+    fn save(&self, index: &Index) -> Result<()> {
+        let mut directory = index.directory().clone();
+        directory.atomic_write(Path::new(METADATA_FILE), &serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+}
+
 pub enum IndexLocation {
     LOCAL,
     REMOTE,
@@ -40,6 +150,24 @@ pub struct LocalIndex {
     reader: IndexReader,
     current_opstamp: Arc<AtomicUsize>,
     deleted_docs: Arc<AtomicU64>,
+    versions: Arc<VersionCache>,
+    pipeline: Arc<std::sync::RwLock<Option<Pipeline>>>,
+    last_reload: Arc<std::sync::Mutex<Instant>>,
+    read_only: Arc<AtomicBool>,
+    // Whether the ingestion breaker has tripped and writes are turned away until the writer catches up
+    ingestion_tripped: Arc<AtomicBool>,
+    bool_fields: Arc<std::sync::RwLock<HashSet<String>>>,
+    without_norms: Arc<std::sync::RwLock<HashSet<String>>>,
+    pre_tokenized: Arc<std::sync::RwLock<HashSet<String>>>,
+    ignore_above: Arc<std::sync::RwLock<HashMap<String, usize>>>,
+    // When the index was created and last changed, kept in step with its metadata
+    audit_times: Arc<std::sync::RwLock<(Option<u64>, Option<u64>)>>,
+    merges: Arc<MergeTracker>,
+    queries: Arc<RateCounter>,
+    indexed: Arc<RateCounter>,
+    wal: Option<Arc<WriteAheadLog>>,
+    sequencer: Option<Sequencer>,
+    blocking: BlockingPool,
     settings: Settings,
     name: String,
 }
@@ -52,6 +180,22 @@ impl Clone for LocalIndex {
             reader: self.reader.clone(),
             current_opstamp: Arc::clone(&self.current_opstamp),
             deleted_docs: Arc::clone(&self.deleted_docs),
+            versions: Arc::clone(&self.versions),
+            pipeline: Arc::clone(&self.pipeline),
+            last_reload: Arc::clone(&self.last_reload),
+            read_only: Arc::clone(&self.read_only),
+            ingestion_tripped: Arc::clone(&self.ingestion_tripped),
+            bool_fields: Arc::clone(&self.bool_fields),
+            without_norms: Arc::clone(&self.without_norms),
+            pre_tokenized: Arc::clone(&self.pre_tokenized),
+            ignore_above: Arc::clone(&self.ignore_above),
+            audit_times: Arc::clone(&self.audit_times),
+            merges: Arc::clone(&self.merges),
+            queries: Arc::clone(&self.queries),
+            indexed: Arc::clone(&self.indexed),
+            wal: self.wal.clone(),
+            sequencer: self.sequencer.clone(),
+            blocking: self.blocking.clone(),
             settings: self.settings.clone(),
             name: self.name.clone(),
         }
@@ -83,23 +227,357 @@ impl IndexHandle for LocalIndex {
     }
 
     async fn search_index(&'_ self, search: Search) -> Result<SearchResults> {
-        let searcher = self.reader.searcher();
-        let schema = self.index.schema();
-        let mut multi_collector = MultiCollector::new();
+        self.search_with_cancel(search, &CancelToken::default())
+    }
+
+    async fn add_document(&self, add_doc: AddDocument) -> Result<()> {
+        self.index_document(add_doc, true).await
+    }
 
-        let sorted_top_handle = search.sort_by.clone().and_then(|sort_by| {
-            info!("Sorting with: {}", sort_by);
-            if let Some(f) = schema.get_field(&sort_by) {
-                let entry = schema.get_field_entry(f);
-                if entry.is_int_fast() && entry.is_stored() {
-                    let c = TopDocs::with_limit(search.limit).order_by_u64_field(f);
-                    return Some(multi_collector.add_collector(c));
+    async fn delete_term(&self, term: DeleteDoc) -> Result<DocsAffected> {
+        self.delete_document(term, true).await
+    }
+}
+
+impl LocalIndex {
+    /// Delete by term, writing the delete to the index's write-ahead log first when `log` is set and
+    /// the index has one. Deletes replayed from the log aren't logged again.
+    async fn delete_document(&self, term: DeleteDoc, log: bool) -> Result<DocsAffected> {
+        self.check_writable()?;
+        let index_schema = self.index.schema();
+        let tombstone = self.tombstone_field()?;
+        if tombstone.is_some() {
+            self.check_soft_deletable(&index_schema)?;
+        }
+        let deleted_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+            .max(1);
+        let before: u64;
+        let mut tombstoned = 0;
+        let mut log_full = false;
+        {
+            let mut index_writer = self.get_writer().lock_owned().await;
+            // A soft delete only copies the documents it can read, so writes made since the last
+            // commit are committed first rather than deleted for good without a copy
+            if tombstone.is_some() {
+                index_writer = self.commit_writer(index_writer).await?.0;
+                self.reload()?;
+            }
+            before = self.searcher().num_docs();
+            if let (Some(wal), true) = (&self.wal, log) {
+                let logged = DeleteDoc {
+                    options: None,
+                    terms: term.terms.clone(),
+                };
+                // Any opstamp after the last commit's keeps the delete until a commit covers it
+                log_full = wal.append(index_writer.commit_opstamp() + 1, Logged::Delete(logged))?;
+            }
+
+            for (field, value) in term.terms {
+                if let Some(f) = index_schema.get_field(&field) {
+                    let term = Term::from_field_text(f, &value);
+                    self.versions.remove(&term)?;
+                    match tombstone {
+                        Some(t) => tombstoned += self.tombstone(&index_writer, term, t, deleted_at)?,
+                        None => {
+                            index_writer.delete_term(term);
+                        }
+                    }
                 }
             }
-            None
-        });
+        }
+        if log_full || term.options.map_or(false, |o| o.commit) {
+            self.commit().await?;
+        }
+        let docs_affected = match tombstone {
+            Some(_) => tombstoned,
+            None => before.saturating_sub(self.searcher().num_docs()),
+        };
+        let current = self.deleted_docs.load(Ordering::SeqCst);
+        self.deleted_docs.store(current + docs_affected, Ordering::SeqCst);
+        Ok(DocsAffected { docs_affected })
+    }
 
-        let top_handle = multi_collector.add_collector(TopDocs::with_limit(search.limit));
+    pub fn new(index: Index, settings: Settings, name: &str) -> Result<Self> {
+        let metadata = IndexMetadata::load(&index)?;
+        metadata.check_compression(name)?;
+        let writer = Arc::new(Mutex::new(LocalIndex::open_writer(&index, &settings)?));
+        let current_opstamp = Arc::new(AtomicUsize::new(0));
+        let reader = LocalIndex::open_reader(&index, &settings)?;
+        // A Ram index loses its commits on restart too, so there's nothing a log could be replayed onto
+        let wal = match &settings.write_ahead_log {
+            Some(wal) if settings.directory_kind.is_durable() => Some(Arc::new(WriteAheadLog::open(
+                &WriteAheadLog::path_for(&settings.path, name),
+                wal.clone(),
+            )?)),
+            _ => None,
+        };
+        let sequencer = Sequencer::open(&index)?;
+        Ok(Self {
+            index,
+            reader,
+            writer,
+            current_opstamp,
+            deleted_docs: Arc::new(AtomicU64::new(0)),
+            versions: Arc::new(VersionCache::default()),
+            pipeline: Arc::new(std::sync::RwLock::new(None)),
+            last_reload: Arc::new(std::sync::Mutex::new(Instant::now())),
+            read_only: Arc::new(AtomicBool::new(metadata.read_only)),
+            ingestion_tripped: Arc::new(AtomicBool::new(false)),
+            bool_fields: Arc::new(std::sync::RwLock::new(metadata.bool_fields.into_iter().collect())),
+            without_norms: Arc::new(std::sync::RwLock::new(metadata.without_norms.into_iter().collect())),
+            pre_tokenized: Arc::new(std::sync::RwLock::new(metadata.pre_tokenized.into_iter().collect())),
+            ignore_above: Arc::new(std::sync::RwLock::new(metadata.ignore_above)),
+            audit_times: Arc::new(std::sync::RwLock::new((metadata.created_at, metadata.modified_at))),
+            merges: Arc::new(MergeTracker::default()),
+            queries: Arc::new(RateCounter::default()),
+            indexed: Arc::new(RateCounter::default()),
+            wal,
+            sequencer,
+            blocking: BlockingPool::new(settings.blocking_pool_size),
+            settings,
+            name: name.into(),
+        })
+    }
+
+    /// Commit on `blocking` instead of a pool of the index's own, so the commits of every index on
+    /// the node share one bound
+    pub fn with_blocking(mut self, blocking: BlockingPool) -> Self {
+        self.blocking = blocking;
+        self
+    }
+
+    fn open_writer(index: &Index, settings: &Settings) -> Result<IndexWriter> {
+        let writer = index.writer(settings.writer_memory)?;
+        writer.set_merge_policy(settings.get_merge_policy());
+        Ok(writer)
+    }
+
+    fn open_reader(index: &Index, settings: &Settings) -> Result<IndexReader> {
+        let policy = match settings.reader_reload_policy {
+            ReaderReloadPolicy::OnCommit => ReloadPolicy::OnCommit,
+            ReaderReloadPolicy::Manual | ReaderReloadPolicy::Interval(_) => ReloadPolicy::Manual,
+        };
+        Ok(index.reader_builder().reload_policy(policy).try_into()?)
+    }
+
+    /// Swap the index this handle writes to for the one `reopen` returns, the same index reopened with
+    /// fields added to its schema. Everything written so far is committed first. The writer is shared
+    /// with every other handle of the index so none of them can write with the old schema afterwards,
+    /// the returned handle is the one that reads with the new schema.
+    pub async fn reopen<F>(&self, reopen: F) -> Result<Self>
+    where
+        F: FnOnce(&Index) -> Result<Index>,
+    {
+        let (mut writer, _) = self.commit_writer(self.get_writer().lock_owned().await).await?;
+        // Tantivy only lets one writer hold an index's lock, so the old writer is swapped for a
+        // throwaway one and shut down before the reopened index can get a writer of its own
+        let placeholder = Index::create_in_ram(Schema::builder().build()).writer_with_num_threads(1, PLACEHOLDER_HEAP)?;
+        let shut_down = std::mem::replace(&mut *writer, placeholder).wait_merging_threads();
+        let index = match shut_down.map_err(Error::from).and_then(|_| reopen(&self.index)) {
+            Ok(index) => index,
+            Err(e) => {
+                *writer = LocalIndex::open_writer(&self.index, &self.settings)?;
+                return Err(e);
+            }
+        };
+        *writer = LocalIndex::open_writer(&index, &self.settings)?;
+        let reader = LocalIndex::open_reader(&index, &self.settings)?;
+        Ok(Self {
+            index,
+            reader,
+            ..self.clone()
+        })
+    }
+
+    /// Rewrite a document the way it is indexed, with nested fields flattened, the ingest pipeline
+    /// applied and keyword fields filled in. Pre-tokenized fields are only turned into their tokens,
+    /// and values too long to be indexed are turned into tokens of their own, none at all.
+    fn prepare(&self, document: &mut serde_json::Value, schema: &Schema) -> Result<()> {
+        let tokens = crate::pretokenized::take(document, &self.pre_tokenized()?)?;
+        crate::nested::flatten(document)?;
+        if let Some(pipeline) = self.get_pipeline()? {
+            pipeline.apply(document, schema)?;
+        }
+        crate::nested::copy_keywords(document, schema);
+        crate::pretokenized::ignore_above(document, &self.ignore_above()?)?;
+        crate::pretokenized::restore(document, tokens);
+        Ok(())
+    }
+
+    /// Add a document, writing it to the index's write-ahead log first when `log` is set and the
+    /// index has one. Documents replayed from the log aren't logged again.
+    async fn index_document(&self, add_doc: AddDocument, log: bool) -> Result<()> {
+        self.check_writable()?;
+        self.check_ingestion()?;
+        let logged = match &self.wal {
+            Some(_) if log => Some(AddDocument {
+                options: None,
+                if_version: None,
+                ..add_doc.clone()
+            }),
+            _ => None,
+        };
+        let index_schema = self.index.schema();
+        let index_writer = self.get_writer().lock_owned().await;
+        let mut document = add_doc.document;
+        self.prepare(&mut document, &index_schema)?;
+        crate::mapping::apply(self.settings.get_unknown_fields(&self.name), &mut document, &index_schema)?;
+        self.encode_bools(&mut document)?;
+        let mut doc: Document = LocalIndex::parse_doc(&index_schema, &document.to_string())?;
+
+        if let Some(key) = add_doc.key {
+            let key_field = index_schema
+                .get_field(&key.field)
+                .ok_or_else(|| Error::UnknownIndexField(key.field.clone()))?;
+            let key_term = Term::from_field_text(key_field, &key.value);
+            if let Some(version_field) = index_schema.get_field(VERSION_FIELD) {
+                let found = self.current_version(&key_term, version_field)?;
+                if let Some(expected) = add_doc.if_version {
+                    if expected != found {
+                        return Err(Error::VersionConflict { expected, found });
+                    }
+                }
+                doc.filter_fields(|f| f != version_field);
+                doc.add_u64(version_field, found + 1);
+                self.versions.insert(key_term.clone(), found + 1)?;
+            } else if add_doc.if_version.is_some() {
+                return Err(Error::QueryError(format!(
+                    "Index has no {} field to version documents with",
+                    VERSION_FIELD
+                )));
+            }
+            index_writer.delete_term(key_term);
+        } else if add_doc.if_version.is_some() {
+            return Err(Error::QueryError("if_version requires a document key".into()));
+        }
+        self.stamp(&mut doc);
+        let opstamp = index_writer.add_document(doc);
+        self.indexed.record(1);
+        // A full log is emptied by committing right away, whether or not the add asked for a commit
+        let log_full = match (&self.wal, logged) {
+            (Some(wal), Some(logged)) => wal.append(opstamp, Logged::Add(logged))?,
+            _ => false,
+        };
+
+        if log_full || add_doc.options.map(|o| o.commit).unwrap_or(false) {
+            self.commit_writer(index_writer).await?;
+            if self.settings.reader_reload_policy == ReaderReloadPolicy::OnCommit {
+                self.reload()?;
+            }
+        } else {
+            self.set_opstamp(self.get_opstamp() + 1);
+        }
+        Ok(())
+    }
+
+    /// The fields a dynamic mapping adds to the schema for `document` to be indexed
+    pub fn dynamic_fields(&self, document: &serde_json::Value) -> Result<Vec<FieldEntry>> {
+        let schema = self.index.schema();
+        let mut document = document.clone();
+        self.prepare(&mut document, &schema)?;
+        Ok(crate::mapping::unknown_fields(&document, &schema)
+            .into_iter()
+            .filter_map(|(name, value)| crate::mapping::dynamic_field(name, value))
+            .collect())
+    }
+
+    /// Run a search, stopping early with [`Error::Cancelled`] if `cancel` is cancelled while
+    /// documents are still being collected. Every search is counted in the index's query rates.
+    pub fn search_with_cancel(&self, search: Search, cancel: &CancelToken) -> Result<SearchResults> {
+        self.search_with_searcher(&self.searcher(), search, false, cancel)
+    }
+
+    /// Run a search like [`LocalIndex::search_with_cancel`] over the commit `searcher` sees. With
+    /// `addresses` none of the hits' stored fields are loaded, each hit only says where it is in
+    /// the index, so it can be loaded from the same searcher afterwards.
+    pub fn search_with_searcher(
+        &self,
+        searcher: &Searcher,
+        search: Search,
+        addresses: bool,
+        cancel: &CancelToken,
+    ) -> Result<SearchResults> {
+        let start = Instant::now();
+        self.queries.record(1);
+        let schema = self.index.schema();
+        self.check_memory(&search, searcher, &schema)?;
+        self.check_limits(&search)?;
+        let script = match &search.script_score {
+            Some(source) => Some(Arc::new(Script::parse(source, &schema)?)),
+            None => None,
+        };
+        let decay = match &search.decay {
+            Some(decay) => Some(Arc::new(DecayScore::new(decay, &schema)?)),
+            None => None,
+        };
+        let synonyms = self.settings.get_synonyms(&self.name);
+        // The post filter only decides which of the matching documents can be hits, so it's never scored
+        let post_filter: Option<Arc<dyn Weight>> = match search.post_filter.clone() {
+            Some(filter) => {
+                let filter = filter.expand_synonyms(&synonyms).cancel_joins(cancel.flag());
+                let filter = self.build_query(filter, &schema, search.default_operator)?;
+                Some(Arc::from(filter.weight(searcher, false)?))
+            }
+            None => None,
+        };
+        let mut multi_collector = MultiCollector::new();
+
+        // Hits ranked by a field or by document order don't need a score, unless one is asked for
+        // every collector skips scoring and tantivy never computes it
+        let limit = search.get_limit();
+        let track_scores = search.track_scores;
+        let skip_source = search.skip_source;
+        // A hit without its source still says which document it is, when the index has its ids' terms
+        let id_field = schema.get_field(ID_FIELD).filter(|f| schema.get_field_entry(*f).is_indexed());
+        let order = HitOrder::of(&search, &schema)?;
+        let rescore = Some((script, decay)).filter(|(script, decay)| script.is_some() || decay.is_some());
+        let top_handle = match (order, rescore) {
+            (HitOrder::Score, Some((script, decay))) => {
+                // The script replaces the query's score, and the decay then lowers whichever score the hit has
+                let c = TopDocs::with_limit(limit).tweak_score(move |reader: &SegmentReader| {
+                    let mut script = script.as_ref().map(|s| s.for_segment(reader));
+                    let mut decay = decay.as_ref().map(|d| d.for_segment(reader));
+                    move |doc: DocId, score: Score| {
+                        let score = script.as_mut().map_or(score, |s| s(doc, score));
+                        decay.as_mut().map_or(score, |d| d(doc, score))
+                    }
+                });
+                TopHandle::Script(multi_collector.add_collector(PostFilter::new(c, post_filter.clone())))
+            }
+            (_, Some((script, _))) => {
+                let option = if script.is_some() { "script_score" } else { "decay" };
+                return Err(Error::QueryError(format!("{} can't be used with sort_by", option)));
+            }
+            (HitOrder::Score, None) => {
+                TopHandle::Score(multi_collector.add_collector(PostFilter::new(TopDocs::with_limit(limit), post_filter.clone())))
+            }
+            (HitOrder::Field(f, signed), None) if track_scores => {
+                let c = TopDocs::with_limit(limit).tweak_score(move |reader: &SegmentReader| {
+                    let key = HitOrder::sort_key(reader, f, signed);
+                    move |doc: DocId, score: Score| (key(doc), score)
+                });
+                TopHandle::ScoredField(multi_collector.add_collector(PostFilter::new(c, post_filter.clone())))
+            }
+            (HitOrder::Field(f, signed), None) => {
+                let c = TopDocs::with_limit(limit).custom_score(move |reader: &SegmentReader| HitOrder::sort_key(reader, f, signed));
+                TopHandle::Field(multi_collector.add_collector(PostFilter::new(c, post_filter.clone())))
+            }
+            (HitOrder::Doc, None) => {
+                let c = DocOrder { limit, track_scores };
+                TopHandle::Doc(multi_collector.add_collector(PostFilter::new(c, post_filter)))
+            }
+        };
+        let highlight = search
+            .highlight
+            .iter()
+            .map(|name| {
+                let field = schema.get_field(name).ok_or_else(|| Error::UnknownIndexField(name.clone()))?;
+                Ok((name.clone(), field, self.index.tokenizer_for_field(field)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
         let facet_handle = search.facets.clone().and_then(|f| {
             if let Some(field) = schema.get_field(&f.get_facets_fields()) {
                 let mut col = FacetCollector::for_field(field);
@@ -111,151 +589,1332 @@ impl IndexHandle for LocalIndex {
                 None
             }
         });
+        let aggregations = search
+            .aggs
+            .iter()
+            .map(|(name, aggregation)| {
+                let handle = match aggregation {
+                    Aggregation::DateHistogram(histogram) => {
+                        let collector = HistogramCollector::new(histogram, &schema)?;
+                        AggregationHandle::Histogram(histogram, multi_collector.add_collector(collector))
+                    }
+                    Aggregation::Range(ranges) => {
+                        let collector = RangeCollector::new(ranges, &schema)?;
+                        AggregationHandle::Range(ranges, multi_collector.add_collector(collector))
+                    }
+                    Aggregation::Terms(terms) => {
+                        let collector = TermsCollector::new(terms, &schema)?;
+                        AggregationHandle::Terms(terms, multi_collector.add_collector(collector))
+                    }
+                    // Every other kind is a metric
+                    _ => {
+                        let collector = MetricCollector::new(aggregation, &schema)?;
+                        AggregationHandle::Metric(multi_collector.add_collector(collector))
+                    }
+                };
+                Ok((name.clone(), handle))
+            })
+            .collect::<Result<Vec<_>>>()?;
 
-        if let Some(query) = search.query {
-            let gen_query = match query {
-                Query::Regex(regex) => regex.create_query(&schema)?,
-                Query::Phrase(phrase) => phrase.create_query(&schema)?,
-                Query::Fuzzy(fuzzy) => fuzzy.create_query(&schema)?,
-                Query::Exact(term) => term.create_query(&schema)?,
-                Query::Range(range) => range.create_query(&schema)?,
-                Query::Boolean { bool } => bool.create_query(&schema)?,
-                Query::Raw { raw } => {
-                    let fields: Vec<Field> = schema.fields().filter_map(|f| schema.get_field(f.1.name())).collect();
-                    let query_parser = QueryParser::for_index(&self.index, fields);
-                    query_parser.parse_query(&raw)?
+        if let Some(query) = search.query.map(|q| q.expand_synonyms(&synonyms).cancel_joins(cancel.flag())) {
+            let build_start = Instant::now();
+            let gen_query = self.build_query(query, &schema, search.default_operator)?;
+
+            let mut boosts = HashMap::new();
+            for (name, boost) in self.settings.get_field_boosts(&self.name, &search.boosts) {
+                match schema.get_field(&name) {
+                    Some(field) => {
+                        boosts.insert(field, boost);
+                    }
+                    None if search.boosts.contains_key(&name) => {
+                        return Err(Error::QueryError(format!("Unknown field to boost: {}", name)));
+                    }
+                    None => debug!("Ignoring boost for {}, {} has no such field", name, self.name),
+                }
+            }
+            let gen_query: Box<dyn tantivy::query::Query> = match self.tombstone_field()? {
+                Some(field) if !search.include_deleted => {
+                    let deleted = RangeQuery::new_u64_bounds(field, Bound::Excluded(0), Bound::Unbounded);
+                    Box::new(BooleanQuery::from(vec![
+                        (Occur::Must, gen_query),
+                        (Occur::MustNot, Box::new(deleted) as Box<_>),
+                    ]))
                 }
-                Query::All => Box::new(AllQuery),
+                _ => gen_query,
             };
-
+            let similarity = self.settings.get_similarity(&self.name);
+            let without_norms = self.without_norms()?.iter().filter_map(|name| schema.get_field(name)).collect();
+            let gen_query = cancel.wrap(similarity.wrap(gen_query, boosts, without_norms));
             debug!("{:?}", gen_query);
+            let build_ms = SearchProfile::millis(build_start.elapsed());
+            let collect_start = Instant::now();
             let mut scored_docs = searcher.search(&*gen_query, &multi_collector)?;
+            if cancel.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+            // Taken once the query has run, queries that expand terms only know them after searching
+            let mut query_terms = BTreeSet::new();
+            gen_query.query_terms(&mut query_terms);
+            let collect_ms = SearchProfile::millis(collect_start.elapsed());
+            let fetch_start = Instant::now();
 
-            // FruitHandle isn't a public type which leads to some duplicate code like this.
-            let docs: Vec<ScoredDoc<BTreeMap<_, _>>> = if let Some(h) = sorted_top_handle {
-                h.extract(&mut scored_docs)
+            // Hits sorted by a field carry its value, so hits of several searches can be merged by it
+            let sort_value = |key: u64| order.sort_value(key);
+            let hits: Vec<(Option<Score>, Option<SortValue>, DocAddress)> = match top_handle {
+                TopHandle::Score(h) => h.extract(&mut scored_docs).into_iter().map(|(s, d)| (Some(s), None, d)).collect(),
+                TopHandle::Script(h) => h.extract(&mut scored_docs).into_iter().map(|(s, d)| (Some(s), None, d)).collect(),
+                TopHandle::ScoredField(h) => h
+                    .extract(&mut scored_docs)
                     .into_iter()
-                    .map(|(score, doc)| {
-                        let d = searcher.doc(doc).expect("Doc not found in segment");
-                        ScoredDoc::<BTreeMap<_, _>>::new(Some(score as f32), schema.to_named_doc(&d).0)
-                    })
-                    .collect()
-            } else {
-                top_handle
+                    .map(|((k, s), d)| (Some(s), sort_value(k), d))
+                    .collect(),
+                TopHandle::Field(h) => h
                     .extract(&mut scored_docs)
                     .into_iter()
-                    .map(|(score, doc)| {
-                        let d = searcher.doc(doc).expect("Doc not found in segment");
-                        ScoredDoc::<BTreeMap<_, _>>::new(Some(score), schema.to_named_doc(&d).0)
-                    })
-                    .collect()
+                    .map(|(k, d)| (None, sort_value(k), d))
+                    .collect(),
+                TopHandle::Doc(h) => h
+                    .extract(&mut scored_docs)
+                    .into_iter()
+                    .map(|(s, d)| (Some(s).filter(|_| track_scores), None, d))
+                    .collect(),
             };
-
-            if let Some(facets) = facet_handle {
-                if let Some(t) = &search.facets {
-                    let facet_counts = facets
-                        .extract(&mut scored_docs)
-                        .get(&t.get_facets_values()[0])
-                        .map(|(f, c)| KeyValue::new(f.to_string(), c))
-                        .collect();
-                    return Ok(SearchResults::with_facets(docs, facet_counts));
+            let mut ids = match id_field {
+                Some(field) if skip_source && !addresses => {
+                    LocalIndex::hit_ids(searcher, field, hits.iter().map(|(_, _, doc)| *doc).collect())
                 }
+                _ => BTreeMap::new(),
+            };
+            let docs: Vec<ScoredDoc<BTreeMap<_, _>>> = hits
+                .into_iter()
+                .map(|(score, sort, doc)| {
+                    if addresses || skip_source {
+                        let mut scored = ScoredDoc::<BTreeMap<_, _>>::new(score, BTreeMap::new());
+                        scored.sort = sort;
+                        if addresses {
+                            scored.address = Some(DocRef {
+                                segment: doc.segment_ord(),
+                                doc: doc.doc(),
+                            });
+                        } else {
+                            scored.id = ids.remove(&doc);
+                        }
+                        return scored;
+                    }
+                    let d = searcher.doc(doc).expect("Doc not found in segment");
+                    let mut scored = ScoredDoc::<BTreeMap<_, _>>::new(score, schema.to_named_doc(&d).0);
+                    scored.sort = sort;
+                    for (name, field, tokenizer) in &highlight {
+                        let matches = d
+                            .get_all(*field)
+                            .into_iter()
+                            .filter_map(Value::text)
+                            .map(|text| highlights(text, tokenizer.token_stream(text), *field, &query_terms))
+                            .collect();
+                        scored.highlights.insert(name.clone(), matches);
+                    }
+                    scored
+                })
+                .collect();
+
+            let facet_counts = match (facet_handle, &search.facets) {
+                (Some(facets), Some(t)) => facets
+                    .extract(&mut scored_docs)
+                    .get(&t.get_facets_values()[0])
+                    .map(|(f, c)| KeyValue::new(f.to_string(), c))
+                    .collect(),
+                _ => Vec::new(),
+            };
+            let mut results = SearchResults::with_facets(docs, facet_counts);
+            for (name, handle) in aggregations {
+                let result = match handle {
+                    AggregationHandle::Histogram(histogram, h) => {
+                        let buckets = h.extract(&mut scored_docs)?;
+                        let counts = HistogramCollector::counts(&buckets);
+                        AggregationResult::Buckets {
+                            buckets: histogram.buckets(&counts, HistogramCollector::nested_results(histogram, buckets))?,
+                        }
+                    }
+                    AggregationHandle::Range(ranges, h) => AggregationResult::Ranges {
+                        ranges: ranges.buckets(&h.extract(&mut scored_docs)?)?,
+                    },
+                    AggregationHandle::Terms(terms, h) => AggregationResult::Terms {
+                        terms: TermsCollector::buckets(terms, h.extract(&mut scored_docs)),
+                    },
+                    AggregationHandle::Metric(h) => h.extract(&mut scored_docs).result(),
+                };
+                results.aggregations.insert(name, result);
             }
-            Ok(SearchResults::new(docs))
+            results.profile = Some(SearchProfile {
+                build_ms,
+                collect_ms,
+                fetch_ms: SearchProfile::millis(fetch_start.elapsed()),
+                ..SearchProfile::default()
+            });
+            // A local index is a single shard, a remote index renumbers it after the shard it is
+            results.shards = Some(ShardsInfo::single(&self.name, 0, SearchProfile::millis(start.elapsed())));
+            Ok(results)
         } else {
             Err(Error::QueryError("Empty Query Provided".into()))
         }
     }
 
-    async fn add_document(&self, add_doc: AddDocument) -> Result<()> {
-        let index_schema = self.index.schema();
-        let writer_lock = self.get_writer();
+    /// The terms `field` holds for the hits at `addresses`, read from each segment's term dictionary
+    /// so their stored fields are never loaded. A segment's terms are walked once, and only until
+    /// every hit in it has been found.
+    fn hit_ids(searcher: &Searcher, field: Field, addresses: BTreeSet<DocAddress>) -> BTreeMap<DocAddress, String> {
+        let mut ids = BTreeMap::new();
+        let mut segments: BTreeMap<SegmentLocalId, HashSet<DocId>> = BTreeMap::new();
+        for address in addresses {
+            segments.entry(address.segment_ord()).or_default().insert(address.doc());
+        }
+        for (segment, mut docs) in segments {
+            let inverted = searcher.segment_reader(segment).inverted_index(field);
+            let mut terms = inverted.terms().stream();
+            while !docs.is_empty() && terms.advance() {
+                let mut postings = inverted.read_postings_from_terminfo(terms.value(), IndexRecordOption::Basic);
+                while postings.advance() {
+                    if docs.remove(&postings.doc()) {
+                        let id = String::from_utf8_lossy(terms.key()).into_owned();
+                        ids.insert(DocAddress(segment, postings.doc()), id);
+                    }
+                }
+            }
+        }
+        ids
+    }
+
+    /// The stored fields of the hit at `address` of a search `searcher` ran, typed the way they're
+    /// returned to clients
+    pub fn load_hit(&self, searcher: &Searcher, address: &DocRef) -> Result<BTreeMap<String, Vec<serde_json::Value>>> {
+        let doc = searcher.doc(DocAddress(address.segment, address.doc))?;
+        let named = self.index.schema().to_named_doc(&doc).0;
+        Ok(LocalIndex::typed_doc(named, &self.bool_fields()?))
+    }
+
+    /// Fail when `search` uses a kind of query the index forbids or has more boolean clauses than
+    /// the limit
+    fn check_limits(&self, search: &Search) -> Result<()> {
+        if let Some(kind) = search
+            .query
+            .iter()
+            .chain(&search.post_filter)
+            .find_map(|q| self.settings.forbidden_query_kind(&self.name, q))
         {
-            let index_writer = writer_lock.lock().await;
-            let doc: Document = LocalIndex::parse_doc(&index_schema, &add_doc.document.to_string())?;
-            index_writer.add_document(doc);
-        }
-        if let Some(opts) = add_doc.options {
-            if opts.commit {
-                let mut commit_writer = writer_lock.lock().await;
-                commit_writer.commit()?;
-                self.set_opstamp(0);
-            } else {
-                self.set_opstamp(self.get_opstamp() + 1);
+            return Err(Error::ForbiddenQuery {
+                kind: kind.into(),
+                index: self.name.clone(),
+            });
+        }
+        let mut clauses = 0;
+        for query in search.query.iter().chain(&search.post_filter) {
+            clauses += match query {
+                // A raw query is parsed into a boolean query with a clause for each of its terms in
+                // each field it searches, only parsing it tells how many that is
+                Query::Raw { raw } => {
+                    let mut terms = BTreeSet::new();
+                    self.raw_parser(search.default_operator).parse_query(raw)?.query_terms(&mut terms);
+                    terms.len()
+                }
+                q => q.clause_count(),
+            };
+        }
+        if clauses > self.settings.max_clause_count {
+            return Err(Error::QueryError(format!(
+                "Query has {} boolean clauses, more than the limit of {}",
+                clauses, self.settings.max_clause_count
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check that `search` could be run against this index, building its queries, script, decay and
+    /// aggregations the way running it would without collecting any documents
+    pub fn validate(&self, search: &Search) -> Result<()> {
+        let schema = self.index.schema();
+        self.check_limits(search)?;
+        HitOrder::of(search, &schema)?;
+        if let Some(source) = &search.script_score {
+            Script::parse(source, &schema)?;
+            if search.sort_by.is_some() {
+                return Err(Error::QueryError("script_score can't be used with sort_by".into()));
             }
-        } else {
-            self.set_opstamp(self.get_opstamp() + 1);
+        }
+        if let Some(decay) = &search.decay {
+            DecayScore::new(decay, &schema)?;
+            if search.sort_by.is_some() {
+                return Err(Error::QueryError("decay can't be used with sort_by".into()));
+            }
+        }
+        let synonyms = self.settings.get_synonyms(&self.name);
+        let query = search
+            .query
+            .clone()
+            .ok_or_else(|| Error::QueryError("Empty Query Provided".into()))?;
+        self.build_query(query.expand_synonyms(&synonyms), &schema, search.default_operator)?;
+        if let Some(filter) = search.post_filter.clone() {
+            self.build_query(filter.expand_synonyms(&synonyms), &schema, search.default_operator)?;
+        }
+        for name in &search.highlight {
+            schema.get_field(name).ok_or_else(|| Error::UnknownIndexField(name.clone()))?;
+        }
+        for aggregation in search.aggs.values() {
+            let built = match aggregation {
+                Aggregation::DateHistogram(histogram) => HistogramCollector::new(histogram, &schema).map(|_| ()),
+                Aggregation::Range(ranges) => RangeCollector::new(ranges, &schema).map(|_| ()),
+                Aggregation::Terms(terms) => TermsCollector::new(terms, &schema).map(|_| ()),
+                _ => MetricCollector::new(aggregation, &schema).map(|_| ()),
+            };
+            built?;
         }
         Ok(())
     }
 
-    async fn delete_term(&self, term: DeleteDoc) -> Result<DocsAffected> {
-        let index_schema = self.index.schema();
-        let writer_lock = self.get_writer();
-        let before: u64;
-        {
-            let index_writer = writer_lock.lock().await;
-            before = self.reader.searcher().num_docs();
+    /// The tantivy query that searches this index for `query`. The terms of a raw query are combined
+    /// with `default_operator` when given, otherwise with the operator configured for the index.
+    fn build_query(
+        &self,
+        query: Query,
+        schema: &Schema,
+        default_operator: Option<DefaultOperator>,
+    ) -> Result<Box<dyn tantivy::query::Query>> {
+        Ok(match query {
+            Query::Regex(regex) => regex.create_query(schema)?,
+            Query::Phrase(phrase) => phrase.create_query(schema)?,
+            Query::PhrasePrefix(phrase) => phrase.create_query(schema)?,
+            Query::Fuzzy(fuzzy) => fuzzy.create_query(schema)?,
+            Query::Exact(term) => term.create_query(schema)?,
+            Query::Terms(terms) => terms.create_query(schema)?,
+            Query::Range(range) => range.create_query(schema)?,
+            Query::Boolean { bool } => bool.create_query(schema)?,
+            Query::HasChild { has_child } => has_child.create_query(schema)?,
+            Query::HasParent { has_parent } => has_parent.create_query(schema)?,
+            Query::Raw { raw } => self.raw_parser(default_operator).parse_query(&raw)?,
+            Query::MatchAll { match_all } => match_all.create_query(schema)?,
+            Query::All => Box::new(AllQuery),
+        })
+    }
 
-            for (field, value) in term.terms {
-                if let Some(f) = index_schema.get_field(&field) {
-                    let term = Term::from_field_text(f, &value);
-                    index_writer.delete_term(term);
+    /// The parser of raw queries, searching every indexed field of the index
+    fn raw_parser(&self, default_operator: Option<DefaultOperator>) -> QueryParser {
+        let schema = self.index.schema();
+        let fields: Vec<Field> = schema
+            .fields()
+            .filter(|f| f.1.is_indexed())
+            .filter_map(|f| schema.get_field(f.1.name()))
+            .collect();
+        let mut query_parser = QueryParser::for_index(&self.index, fields);
+        if self.settings.get_default_operator(&self.name, default_operator) == DefaultOperator::And {
+            query_parser.set_conjunction_by_default();
+        }
+        query_parser
+    }
+
+    /// How many documents deleting `term` would remove, without touching the writer. Documents
+    /// that are already soft deleted aren't counted again, just like a real delete.
+    pub fn count_deletes(&self, term: &DeleteDoc) -> Result<u64> {
+        let schema = self.index.schema();
+        let mut clauses: Vec<(Occur, Box<dyn tantivy::query::Query>)> = term
+            .terms
+            .iter()
+            .filter_map(|(field, value)| schema.get_field(field).map(|f| Term::from_field_text(f, value)))
+            .map(|t| (Occur::Should, Box::new(TermQuery::new(t, IndexRecordOption::Basic)) as Box<_>))
+            .collect();
+        if clauses.is_empty() {
+            return Ok(0);
+        }
+        if let Some(field) = self.tombstone_field()? {
+            let deleted = RangeQuery::new_u64_bounds(field, Bound::Excluded(0), Bound::Unbounded);
+            clauses.push((Occur::MustNot, Box::new(deleted)));
+        }
+        let count = self.searcher().search(&BooleanQuery::from(clauses), &Count)?;
+        Ok(count as u64)
+    }
+
+    /// The first `limit` documents committed after the sequence number `since`, see [`Sequencer`].
+    /// Tombstones of soft deleted documents are returned as deleted, documents deleted for good are
+    /// gone without a trace.
+    pub fn changes(&self, since: u64, limit: usize) -> Result<ChangesResponse> {
+        let sequencer = self
+            .sequencer
+            .as_ref()
+            .ok_or_else(|| Error::QueryError(format!("Index {} has no {} field to track changes with", self.name, SEQUENCE_FIELD)))?;
+        let searcher = self.searcher();
+        let schema = self.index.schema();
+        let tombstone = self.tombstone_field()?;
+        let bools = self.bool_fields()?;
+        let mut changes = Vec::new();
+        for (seq, addr) in sequencer.since(&searcher, since, limit)? {
+            let deleted = tombstone.map_or(false, |field| {
+                searcher
+                    .segment_reader(addr.segment_ord())
+                    .fast_fields()
+                    .u64(field)
+                    .map_or(false, |r| r.get(addr.doc()) > 0)
+            });
+            let doc = schema.to_named_doc(&searcher.doc(addr)?).0;
+            changes.push(ChangedDoc {
+                seq,
+                deleted,
+                doc: LocalIndex::typed_doc(doc, &bools),
+            });
+        }
+        let watermark = changes.last().map_or(since, |c| c.seq);
+        Ok(ChangesResponse { changes, watermark })
+    }
+
+    /// The stored fields of the documents whose [`ID_FIELD`] is one of `ids`, keyed by id, found
+    /// with a single query. Soft deleted documents aren't found.
+    pub fn get_by_ids(&self, ids: &[String]) -> Result<HashMap<String, BTreeMap<String, Vec<Value>>>> {
+        let schema = self.index.schema();
+        let id_field = schema
+            .get_field(ID_FIELD)
+            .filter(|f| schema.get_field_entry(*f).is_indexed() && schema.get_field_entry(*f).is_stored())
+            .ok_or_else(|| Error::QueryError(format!("Index {} has no indexed and stored {} field", self.name, ID_FIELD)))?;
+        let mut clauses: Vec<(Occur, Box<dyn tantivy::query::Query>)> = ids
+            .iter()
+            .map(|id| {
+                let term = Term::from_field_text(id_field, id);
+                (Occur::Should, Box::new(TermQuery::new(term, IndexRecordOption::Basic)) as Box<_>)
+            })
+            .collect();
+        if clauses.is_empty() {
+            return Ok(HashMap::new());
+        }
+        if let Some(field) = self.tombstone_field()? {
+            let deleted = RangeQuery::new_u64_bounds(field, Bound::Excluded(0), Bound::Unbounded);
+            clauses.push((Occur::MustNot, Box::new(deleted)));
+        }
+        let searcher = self.searcher();
+        // Every matching document, unscored, rather than a top-n heap sized for the whole index
+        let every = DocOrder {
+            limit: usize::max_value(),
+            track_scores: false,
+        };
+        let mut docs = HashMap::new();
+        for (_, addr) in searcher.search(&BooleanQuery::from(clauses), &every)? {
+            let doc = searcher.doc(addr)?;
+            if let Some(id) = doc.get_first(id_field).and_then(Value::text) {
+                docs.entry(id.to_string()).or_insert_with(|| schema.to_named_doc(&doc).0);
+            }
+        }
+        Ok(docs)
+    }
+
+    /// The field soft deleted documents are marked in, `None` when the index deletes documents
+    /// right away
+    fn tombstone_field(&self) -> Result<Option<Field>> {
+        let schema = self.index.schema();
+        match schema.get_field(TOMBSTONE_FIELD) {
+            Some(field) => {
+                let entry = schema.get_field_entry(field);
+                match entry.field_type() {
+                    FieldType::U64(_) if entry.is_indexed() && entry.is_int_fast() => Ok(Some(field)),
+                    _ => Err(Error::QueryError(format!("{} must be an indexed u64 fast field", TOMBSTONE_FIELD))),
                 }
             }
+            None => Ok(None),
+        }
+    }
+
+    /// Fail unless every indexed field of `schema` is stored. A soft deleted document is a copy of
+    /// its stored fields, so a field that's only indexed would be lost and the document couldn't be
+    /// found through it again. The tombstone and sequence fields are set on the copy rather than
+    /// copied, they don't need to be stored.
+    fn check_soft_deletable(&self, schema: &Schema) -> Result<()> {
+        let lost = schema
+            .fields()
+            .filter(|(_, entry)| entry.is_indexed() && !entry.is_stored())
+            .map(|(_, entry)| entry.name())
+            .filter(|name| *name != TOMBSTONE_FIELD && *name != SEQUENCE_FIELD)
+            .collect::<Vec<_>>();
+        if lost.is_empty() {
+            return Ok(());
         }
-        if let Some(opts) = term.options {
-            if opts.commit {
-                let mut commit_writer = writer_lock.lock().await;
-                commit_writer.commit()?;
-                self.set_opstamp(0);
+        Err(Error::QueryError(format!(
+            "Index {} can't soft delete, its indexed fields {} aren't stored and would be lost",
+            self.name,
+            lost.join(", ")
+        )))
+    }
+
+    /// Replace every committed document matching `term` with a copy marked as deleted at
+    /// `deleted_at`, returning how many were newly deleted. Documents that were already soft deleted
+    /// keep the time they were first deleted. The old copies are deleted before the new ones are
+    /// added so the delete doesn't apply to them.
+    fn tombstone(&self, writer: &IndexWriter, term: Term, field: Field, deleted_at: u64) -> Result<u64> {
+        let searcher = self.reader.searcher();
+        let query = TermQuery::new(term.clone(), IndexRecordOption::Basic);
+        // Every matching document, unscored, rather than a top-n heap sized for the whole index
+        let every = DocOrder {
+            limit: usize::max_value(),
+            track_scores: false,
+        };
+        let mut copies = Vec::new();
+        let mut tombstoned = 0;
+        for (_, addr) in searcher.search(&query, &every)? {
+            let stored = searcher.doc(addr)?;
+            let previous = searcher
+                .segment_reader(addr.segment_ord())
+                .fast_fields()
+                .u64(field)
+                .map(|r| r.get(addr.doc()))
+                .unwrap_or(0);
+            let mut doc = Document::default();
+            stored
+                .field_values()
+                .iter()
+                .filter(|v| v.field() != field)
+                .for_each(|v| doc.add(v.clone()));
+            if previous == 0 {
+                tombstoned += 1;
+                doc.add_u64(field, deleted_at);
+            } else {
+                doc.add_u64(field, previous);
             }
+            // The tombstone is a change of its own, it shows up in the index's changes as a delete
+            self.stamp(&mut doc);
+            copies.push(doc);
+        }
+        writer.delete_term(term);
+        copies.into_iter().for_each(|doc| {
+            writer.add_document(doc);
+        });
+        Ok(tombstoned)
+    }
+
+    /// Set fields of every document the query of `update` matches, replacing each with a copy of its
+    /// stored fields holding the new values. Documents are replaced by their [`ID_FIELD`], so the index
+    /// needs an indexed and stored one and every matching document has to have a value for it. Only
+    /// stored fields can be copied, the fields of an updated document that aren't stored are lost.
+    /// Soft deleted documents aren't updated. With `dry_run` nothing is changed and the count is how
+    /// many documents would be.
+    pub async fn update_by_query(&self, update: UpdateByQuery, dry_run: bool) -> Result<DocsAffected> {
+        let schema = self.index.schema();
+        let UpdateByQuery { options, query, set } = update;
+        let mut clauses = vec![(Occur::Must, query.create_query(&schema)?)];
+        if let Some(field) = self.tombstone_field()? {
+            let deleted = RangeQuery::new_u64_bounds(field, Bound::Excluded(0), Bound::Unbounded);
+            clauses.push((Occur::MustNot, Box::new(deleted)));
+        }
+        let query = BooleanQuery::from(clauses);
+        if dry_run {
+            let docs_affected = self.searcher().search(&query, &Count)? as u64;
+            return Ok(DocsAffected { docs_affected });
+        }
+        self.check_writable()?;
+        self.check_ingestion()?;
+        let id_field = schema
+            .get_field(ID_FIELD)
+            .filter(|f| schema.get_field_entry(*f).is_indexed() && schema.get_field_entry(*f).is_stored())
+            .ok_or_else(|| Error::QueryError(format!("Index {} has no indexed and stored {} field", self.name, ID_FIELD)))?;
+        // The new values are prepared and parsed the way the fields of an added document are, and the
+        // fields they replace are the ones left once the pipeline has run
+        let mut values = serde_json::Value::Object(set);
+        self.prepare(&mut values, &schema)?;
+        let changed = values
+            .as_object()
+            .into_iter()
+            .flat_map(|set| set.keys())
+            .map(|name| schema.get_field(name).ok_or_else(|| Error::UnknownIndexField(name.clone())))
+            .collect::<Result<HashSet<Field>>>()?;
+        self.encode_bools(&mut values)?;
+        let values = LocalIndex::parse_doc(&schema, &values.to_string())?;
+
+        // Writes made since the last commit are committed first so the copies read back below are
+        // the latest ones and not the documents those writes replaced
+        let writer = self.get_writer().lock_owned().await;
+        let (writer, _) = self.commit_writer(writer).await?;
+        self.reload()?;
+        let searcher = self.reader.searcher();
+        let collector = DocOrder {
+            limit: usize::max_value(),
+            track_scores: false,
+        };
+        let mut updated = Vec::new();
+        for (_, addr) in searcher.search(&query, &collector)? {
+            let stored = searcher.doc(addr)?;
+            let id = stored
+                .get_first(id_field)
+                .and_then(Value::text)
+                .ok_or_else(|| Error::QueryError(format!("A document the update matches has no {} to replace it by", ID_FIELD)))?;
+            let mut doc = Document::default();
+            stored
+                .field_values()
+                .iter()
+                .filter(|v| !changed.contains(&v.field()))
+                .for_each(|v| doc.add(v.clone()));
+            values.field_values().iter().for_each(|v| doc.add(v.clone()));
+            updated.push((id.to_string(), Term::from_field_text(id_field, id), doc));
+        }
+        for (_, id, doc) in updated.iter_mut() {
+            if let Some(version_field) = schema.get_field(VERSION_FIELD) {
+                let version = self.current_version(id, version_field)? + 1;
+                doc.filter_fields(|f| f != version_field);
+                doc.add_u64(version_field, version);
+                self.versions.insert(id.clone(), version)?;
+            }
+            self.stamp(doc);
+        }
+        // Every old copy is deleted before the new ones are added so the deletes don't apply to them
+        for (_, id, _) in &updated {
+            writer.delete_term(id.clone());
+        }
+        let docs_affected = updated.len() as u64;
+        // Each new copy is logged as an add keyed by its id, which replaces the old copy when replayed
+        let mut log_full = false;
+        for (key, _, doc) in updated {
+            let logged = match &self.wal {
+                Some(_) => Some(AddDocument {
+                    options: None,
+                    document: serde_json::to_value(schema.to_named_doc(&doc))?,
+                    key: Some(KeyValue::new(ID_FIELD.to_string(), key)),
+                    if_version: None,
+                }),
+                None => None,
+            };
+            let opstamp = writer.add_document(doc);
+            if let Some(logged) = logged {
+                log_full |= self.log_write(opstamp, Logged::Add(logged))?;
+            }
+        }
+        self.indexed.record(docs_affected);
+
+        if log_full || options.map_or(false, |o| o.commit) {
+            self.commit_writer(writer).await?;
+            if self.settings.reader_reload_policy == ReaderReloadPolicy::OnCommit {
+                self.reload()?;
+            }
+        } else {
+            self.set_opstamp(self.get_opstamp() + docs_affected as usize);
         }
-        let docs_affected = before - self.reader.searcher().num_docs();
-        let current = self.deleted_docs.load(Ordering::SeqCst);
-        self.deleted_docs.store(current + docs_affected, Ordering::SeqCst);
         Ok(DocsAffected { docs_affected })
     }
-}
 
-impl LocalIndex {
-    pub fn new(index: Index, settings: Settings, name: &str) -> Result<Self> {
-        let i = index.writer(settings.writer_memory)?;
-        i.set_merge_policy(settings.get_merge_policy());
-        let current_opstamp = Arc::new(AtomicUsize::new(0));
-        let writer = Arc::new(Mutex::new(i));
-        let reader = index.reader_builder().reload_policy(ReloadPolicy::OnCommit).try_into()?;
-        Ok(Self {
-            index,
-            reader,
-            writer,
-            current_opstamp,
-            deleted_docs: Arc::new(AtomicU64::new(0)),
-            settings,
-            name: name.into(),
+    /// Remove for good the soft deleted documents that were deleted at least `older_than` ago, then
+    /// merge the index's segments so the space they took up is reclaimed
+    pub async fn purge(&self, older_than: Duration) -> Result<u64> {
+        self.check_writable()?;
+        let field = self
+            .tombstone_field()?
+            .ok_or_else(|| Error::QueryError(format!("Index {} has no {} field to soft delete with", self.name, TOMBSTONE_FIELD)))?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let cutoff = now.saturating_sub(older_than.as_secs());
+        let mut purged = 0;
+        {
+            let writer = self.get_writer().lock_owned().await;
+            let searcher = self.reader.searcher();
+            let mut expired = HashSet::new();
+            for segment in searcher.segment_readers() {
+                let deleted_at = match segment.fast_fields().u64(field) {
+                    Some(r) => r,
+                    None => continue,
+                };
+                for doc in (0..segment.max_doc()).filter(|d| !segment.is_deleted(*d)) {
+                    let at = deleted_at.get(doc);
+                    if at > 0 && at <= cutoff {
+                        expired.insert(at);
+                        purged += 1;
+                    }
+                }
+            }
+            expired.into_iter().for_each(|at| {
+                writer.delete_term(Term::from_field_u64(field, at));
+            });
+            self.commit_writer(writer).await?;
+        }
+        if purged > 0 {
+            if let Some(merge) = self.merge_free_segments(1).await? {
+                merge.await?;
+            }
+        }
+        self.reload()?;
+        Ok(purged)
+    }
+
+    /// Delete every document whose `field_name` holds a time before `cutoff`, in seconds since the
+    /// epoch, and commit, returning how many were deleted. Documents without a value for the field
+    /// have no term in it and are never deleted.
+    pub async fn expire(&self, field_name: &str, cutoff: i64) -> Result<u64> {
+        self.check_writable()?;
+        let schema = self.index.schema();
+        let field = schema
+            .get_field(field_name)
+            .ok_or_else(|| Error::UnknownIndexField(field_name.into()))?;
+        let entry = schema.get_field_entry(field);
+        let (value_type, bound) = match entry.field_type() {
+            FieldType::I64(_) if entry.is_indexed() => (Type::I64, Term::from_field_i64(field, cutoff)),
+            FieldType::Date(_) if entry.is_indexed() => (Type::Date, Term::from_field_i64(field, cutoff)),
+            FieldType::U64(_) if entry.is_indexed() => (Type::U64, Term::from_field_u64(field, cutoff.max(0) as u64)),
+            _ => {
+                return Err(Error::QueryError(format!(
+                    "{} must be an indexed u64, i64 or date field",
+                    field_name
+                )))
+            }
+        };
+
+        let writer = self.get_writer().lock_owned().await;
+        let searcher = self.reader.searcher();
+        let query = RangeQuery::new_term_bounds(field, value_type, &Bound::Unbounded, &Bound::Excluded(bound.clone()));
+        let expired = searcher.search(&query, &Count)? as u64;
+        if expired == 0 {
+            return Ok(0);
+        }
+        let mut keys = HashSet::new();
+        for segment in searcher.segment_readers() {
+            let inverted = segment.inverted_index(field);
+            let mut stream = inverted.terms().range().lt(bound.value_bytes()).into_stream();
+            while stream.advance() {
+                let mut key = [0u8; 8];
+                key.copy_from_slice(stream.key());
+                keys.insert(key);
+            }
+        }
+        keys.into_iter().for_each(|key| {
+            writer.delete_term(Term::from_field_u64(field, u64::from_be_bytes(key)));
+        });
+        self.commit_writer(writer).await?;
+        self.reload()?;
+        Ok(expired)
+    }
+
+    /// How many distinct terms a field holds and the `top` of them found in the most documents, read
+    /// from the term dictionary of every segment
+    pub fn field_stats(&self, field_name: &str, top: usize) -> Result<FieldStats> {
+        let schema = self.index.schema();
+        let field = schema
+            .get_field(field_name)
+            .ok_or_else(|| Error::UnknownIndexField(field_name.into()))?;
+        let entry = schema.get_field_entry(field);
+        if !entry.is_indexed() {
+            return Err(Error::QueryError(format!("{} is not indexed, it has no terms", field_name)));
+        }
+        let mut doc_freqs: HashMap<Vec<u8>, u64> = HashMap::new();
+        for segment in self.searcher().segment_readers() {
+            let inverted = segment.inverted_index(field);
+            let mut stream = inverted.terms().stream();
+            while stream.advance() {
+                *doc_freqs.entry(stream.key().to_vec()).or_insert(0) += u64::from(stream.value().doc_freq);
+            }
+        }
+
+        let decode = |key: &[u8]| -> String {
+            let mut bytes = [0u8; 8];
+            if key.len() == 8 {
+                bytes.copy_from_slice(key);
+            }
+            let n = u64::from_be_bytes(bytes);
+            match entry.field_type() {
+                FieldType::U64(_) => n.to_string(),
+                FieldType::I64(_) | FieldType::Date(_) => tantivy::u64_to_i64(n).to_string(),
+                FieldType::F64(_) => tantivy::u64_to_f64(n).to_string(),
+                _ => String::from_utf8_lossy(key).into_owned(),
+            }
+        };
+        let mut terms: Vec<(Vec<u8>, u64)> = doc_freqs.into_iter().collect();
+        let distinct_terms = terms.len() as u64;
+        // Ties are broken by the term itself so the ranking doesn't change from one call to the next
+        terms.sort_by(|(a, a_freq), (b, b_freq)| b_freq.cmp(a_freq).then_with(|| a.cmp(b)));
+        let top_terms = terms
+            .into_iter()
+            .take(top)
+            .map(|(term, doc_freq)| TermCount {
+                term: decode(&term),
+                doc_freq,
+            })
+            .collect();
+        Ok(FieldStats {
+            field: field_name.into(),
+            distinct_terms,
+            top_terms,
+        })
+    }
+
+    /// The latest version of the document identified by `key`, or 0 if there is none. This must be
+    /// called with the writer lock held so that uncommitted versions are accounted for.
+    fn current_version(&self, key: &Term, version_field: Field) -> Result<u64> {
+        if let Some(v) = self.versions.get(key)? {
+            return Ok(v);
+        }
+        let searcher = self.reader.searcher();
+        let query = TermQuery::new(key.clone(), IndexRecordOption::Basic);
+        let top = searcher.search(&query, &TopDocs::with_limit(1))?;
+        match top.first() {
+            Some((_, addr)) => searcher
+                .segment_reader(addr.segment_ord())
+                .fast_fields()
+                .u64(version_field)
+                .map(|r| r.get(addr.doc()))
+                .ok_or_else(|| Error::QueryError(format!("{} must be a u64 fast field", VERSION_FIELD))),
+            None => Ok(0),
+        }
+    }
+
+    /// Parse a document against an index's schema, a value that doesn't fit its field's type is
+    /// reported with the field, the type it expects and the value that was given
+    pub fn parse_doc(schema: &Schema, bytes: &str) -> Result<Document> {
+        schema.parse_document(bytes).map_err(|e| match e {
+            DocParsingError::ValueError(field, _) => {
+                let field_type = schema.get_field(&field).map(|f| schema.get_field_entry(f).field_type());
+                let expected = match field_type {
+                    Some(FieldType::Str(_)) => "text",
+                    Some(FieldType::U64(_)) => "u64",
+                    Some(FieldType::I64(_)) => "i64",
+                    Some(FieldType::F64(_)) => "f64",
+                    Some(FieldType::Date(_)) => "date",
+                    Some(FieldType::HierarchicalFacet) => "hierarchical_facet",
+                    Some(FieldType::Bytes) => "bytes",
+                    None => "known",
+                };
+                // Of a multi valued field only the first value that doesn't parse is reported
+                let found = serde_json::from_str::<serde_json::Value>(bytes)
+                    .ok()
+                    .and_then(|doc| match (doc.get(&field), field_type) {
+                        (Some(serde_json::Value::Array(values)), Some(ft)) => {
+                            values.iter().find(|v| ft.value_from_json(v).is_err()).cloned()
+                        }
+                        (value, _) => value.cloned(),
+                    })
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|| "an invalid value".into());
+                Error::SchemaMismatch {
+                    field,
+                    expected: expected.into(),
+                    found,
+                }
+            }
+            e => e.into(),
         })
     }
 
-    fn parse_doc(schema: &Schema, bytes: &str) -> Result<Document> {
-        schema.parse_document(bytes).map_err(Into::into)
+    /// Make the reader pick up every commit made so far
+    pub fn reload(&self) -> Result<()> {
+        // Versions can only be forgotten once the reader they're looked up in has seen their commit
+        self.versions.reloaded(|| self.reader.reload().map_err(Error::from))?;
+        *self.last_reload.lock().map_err(|_| Error::PoisonedError)? = Instant::now();
+        Ok(())
+    }
+
+    /// A searcher over the latest commit the reader has loaded, with an `Interval` reload policy
+    /// this reloads first if the interval has passed since the last reload
+    pub fn searcher(&self) -> LeasedItem<Searcher> {
+        if let ReaderReloadPolicy::Interval(ms) = self.settings.reader_reload_policy {
+            let due = self
+                .last_reload
+                .lock()
+                .map(|last| last.elapsed() >= Duration::from_millis(ms))
+                .unwrap_or(false);
+            if due {
+                if let Err(e) = self.reload() {
+                    warn!("Could not reload reader of {}: {}", self.name, e);
+                }
+            }
+        }
+        self.reader.searcher()
+    }
+
+    pub fn num_docs(&self) -> u64 {
+        self.searcher().num_docs()
+    }
+
+    /// Reject a search with [`Error::CircuitBreaker`] if the memory its collectors would need is
+    /// estimated to go over the configured budget
+    fn check_memory(&self, search: &Search, searcher: &Searcher, schema: &Schema) -> Result<()> {
+        let breaker = &self.settings.circuit_breaker;
+        if breaker.budget == 0 {
+            return Ok(());
+        }
+        let hits = search.get_limit() as u64;
+        let facet_values: u64 = search
+            .facets
+            .as_ref()
+            .and_then(|f| schema.get_field(f.get_facets_fields()))
+            .map(|field| {
+                searcher
+                    .segment_readers()
+                    .iter()
+                    .map(|r| r.inverted_index(field).terms().num_terms() as u64)
+                    .sum()
+            })
+            .unwrap_or(0);
+        let estimated = hits
+            .saturating_mul(breaker.bytes_per_hit)
+            .saturating_add(facet_values.saturating_mul(breaker.bytes_per_facet_value));
+        if estimated > breaker.budget {
+            warn!("Rejecting search on {}, estimated to use {} bytes", self.name, estimated);
+            return Err(Error::CircuitBreaker {
+                estimated,
+                budget: breaker.budget,
+            });
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn merge_tracker(&self) -> &MergeTracker {
+        &self.merges
+    }
+
+    /// What the index's writer is busy with, merges are sampled from the segments in the index's
+    /// last commit
+    pub fn writer_stats(&self) -> Result<WriterStats> {
+        let live: HashSet<SegmentId> = self.index.searchable_segment_ids()?.into_iter().collect();
+        let active_merges = self.merges.active(&live);
+        let merging = self.merges.merging();
+        Ok(WriterStats {
+            active_merges,
+            pending_segments: live.iter().filter(|id| !merging.contains(id)).count(),
+            queued_operations: self.get_opstamp(),
+            rates: self.rates(),
+        })
+    }
+
+    /// How many searches and documents the index has taken a second over the last minute and five minutes
+    pub fn rates(&self) -> IndexRates {
+        let (minute, five_minutes) = (Duration::from_secs(60), Duration::from_secs(300));
+        IndexRates {
+            queries_per_sec_1m: self.queries.per_second(minute),
+            queries_per_sec_5m: self.queries.per_second(five_minutes),
+            docs_per_sec_1m: self.indexed.per_second(minute),
+            docs_per_sec_5m: self.indexed.per_second(five_minutes),
+        }
+    }
+
+    /// Count documents added to the index for its rates, documents added one at a time are counted
+    /// as they're added
+    pub fn record_indexed(&self, docs: u64) {
+        self.indexed.record(docs);
+    }
+
+    /// Start merging every segment of the index into one, returning the merge to wait on. There is
+    /// nothing to merge when the index has less than two segments that aren't already being merged.
+    pub async fn start_merge(&self) -> Result<Option<BoxFuture<'static, Result<()>>>> {
+        self.check_writable()?;
+        self.merge_free_segments(2).await
+    }
+
+    /// Merge the segments that aren't already being merged if there are at least `min` of them
+    async fn merge_free_segments(&self, min: usize) -> Result<Option<BoxFuture<'static, Result<()>>>> {
+        let merging = self.merges.merging();
+        let segments: Vec<SegmentId> = self
+            .index
+            .searchable_segment_ids()?
+            .into_iter()
+            .filter(|id| !merging.contains(id))
+            .collect();
+        if segments.len() < min {
+            return Ok(None);
+        }
+        let merge = self.writer.lock().await.merge(&segments);
+        self.merges.started(segments.clone());
+        let merges = Arc::clone(&self.merges);
+        Ok(Some(
+            async move {
+                merge.await.map(|_| ()).map_err(|e| {
+                    merges.failed(&segments);
+                    Error::from(e)
+                })
+            }
+            .boxed(),
+        ))
+    }
+
+    /// Commit everything written to the index so far
+    pub async fn commit(&self) -> Result<Opstamp> {
+        let (_, opstamp) = self.commit_writer(self.get_writer().lock_owned().await).await?;
+        Ok(opstamp)
+    }
+
+    /// Whether writes to the index are kept in a write-ahead log until they're committed
+    pub fn has_wal(&self) -> bool {
+        self.wal.is_some()
+    }
+
+    /// Write `op`, which the writer gave `opstamp`, to the index's write-ahead log, returning
+    /// whether the log is full and the index should be committed. Does nothing without a log.
+    pub fn log_write(&self, opstamp: Opstamp, op: Logged) -> Result<bool> {
+        match &self.wal {
+            Some(wal) => wal.append(opstamp, op),
+            None => Ok(false),
+        }
+    }
+
+    /// Discard everything written to the index since its last commit, emptying the write-ahead log
+    /// too so the discarded writes aren't replayed when the index is opened again
+    pub async fn rollback(&self) -> Result<Opstamp> {
+        let opstamp = self.writer.lock().await.rollback()?;
+        if let Some(wal) = &self.wal {
+            wal.truncate()?;
+        }
+        self.set_opstamp(0);
+        Ok(opstamp)
+    }
+
+    /// Commit everything `writer` holds and empty the write-ahead log, the commit made it redundant
+    fn commit_logged(writer: &mut IndexWriter, wal: Option<&WriteAheadLog>, versions: &VersionCache) -> Result<Opstamp> {
+        let opstamp = commit(writer)?;
+        versions.committed()?;
+        if let Some(wal) = wal {
+            wal.truncate()?;
+        }
+        Ok(opstamp)
+    }
+
+    /// Commit what `writer` holds on the index's blocking pool, the lock on it is taken beforehand
+    /// so no blocking thread sits waiting for it. The writer is handed back still locked, for the
+    /// callers that keep writing after the commit.
+    pub async fn commit_writer(&self, mut writer: OwnedMutexGuard<IndexWriter>) -> Result<(OwnedMutexGuard<IndexWriter>, Opstamp)> {
+        let (wal, versions) = (self.wal.clone(), Arc::clone(&self.versions));
+        let committed = self
+            .blocking
+            .run(move || {
+                let opstamp = LocalIndex::commit_logged(&mut writer, wal.as_deref(), &versions)?;
+                Ok((writer, opstamp))
+            })
+            .await?;
+        self.set_opstamp(0);
+        Ok(committed)
+    }
+
+    /// Make the writes in the write-ahead log that the index never committed again, such as the
+    /// ones made right before a crash, and commit them. The log is only emptied by the commit, so if
+    /// replaying is cut short it's replayed in full the next time. Returns how many were made.
+    pub fn replay_wal(&self) -> Result<usize> {
+        let wal = match &self.wal {
+            Some(wal) => wal,
+            None => return Ok(0),
+        };
+        let ops = wal.uncommitted(self.index.load_metas()?.opstamp)?;
+        let replayed = ops.len();
+        if replayed == 0 {
+            return Ok(0);
+        }
+        for op in ops {
+            match op {
+                Logged::Add(doc) => futures::executor::block_on(self.index_document(doc, false))?,
+                Logged::Delete(term) => {
+                    futures::executor::block_on(self.delete_document(term, false))?;
+                }
+            }
+        }
+        let mut writer = futures::executor::block_on(self.writer.lock());
+        LocalIndex::commit_logged(&mut writer, self.wal.as_deref(), &self.versions)?;
+        self.set_opstamp(0);
+        Ok(replayed)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    /// Freeze or unfreeze the index for writes, the flag is saved with the index so it is still set
+    /// after a restart
+    pub fn set_read_only(&self, read_only: bool) -> Result<()> {
+        let mut metadata = IndexMetadata::load(&self.index)?;
+        metadata.read_only = read_only;
+        metadata.touch();
+        self.save_metadata(&metadata)?;
+        self.read_only.store(read_only, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Drop what a copy of another index shouldn't keep from it, a copy starts out writable, isn't
+    /// placed anywhere but this node and counts as created when it was copied
+    pub fn detach_copy(&self) -> Result<()> {
+        let mut metadata = IndexMetadata::load(&self.index)?;
+        metadata.read_only = false;
+        metadata.routing = None;
+        metadata.touch();
+        metadata.created_at = metadata.modified_at;
+        self.save_metadata(&metadata)?;
+        self.read_only.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// The fields of the index that hold booleans
+    pub fn bool_fields(&self) -> Result<HashSet<String>> {
+        Ok(self.bool_fields.read().map_err(|_| Error::PoisonedError)?.clone())
+    }
+
+    /// Mark u64 fields of the index as holding booleans, saved with the index so they are still
+    /// booleans after a restart
+    pub fn set_bool_fields(&self, fields: &[String]) -> Result<()> {
+        let schema = self.index.schema();
+        for name in fields {
+            if let Some(FieldType::U64(_)) = schema.get_field(name).map(|f| schema.get_field_entry(f).field_type()) {
+                continue;
+            }
+            return Err(Error::QueryError(format!("{} is not a u64 field that can hold booleans", name)));
+        }
+        let mut metadata = IndexMetadata::load(&self.index)?;
+        metadata.bool_fields = fields.to_vec();
+        metadata.touch();
+        self.save_metadata(&metadata)?;
+        *self.bool_fields.write().map_err(|_| Error::PoisonedError)? = fields.iter().cloned().collect();
+        Ok(())
+    }
+
+    /// The text fields of the index that are scored without regard to their length
+    pub fn without_norms(&self) -> Result<HashSet<String>> {
+        Ok(self.without_norms.read().map_err(|_| Error::PoisonedError)?.clone())
+    }
+
+    /// Score text fields of the index without regard to their length. Tantivy still records the
+    /// length of every field, they are left out when the field is scored.
+    pub fn set_without_norms(&self, fields: &[String]) -> Result<()> {
+        let schema = self.index.schema();
+        for name in fields {
+            if let Some(FieldType::Str(_)) = schema.get_field(name).map(|f| schema.get_field_entry(f).field_type()) {
+                continue;
+            }
+            return Err(Error::QueryError(format!(
+                "{} is not a text field that norms can be disabled for",
+                name
+            )));
+        }
+        let mut metadata = IndexMetadata::load(&self.index)?;
+        metadata.without_norms = fields.to_vec();
+        metadata.touch();
+        self.save_metadata(&metadata)?;
+        *self.without_norms.write().map_err(|_| Error::PoisonedError)? = fields.iter().cloned().collect();
+        Ok(())
+    }
+
+    /// The text fields of the index whose values are given as tokens
+    pub fn pre_tokenized(&self) -> Result<HashSet<String>> {
+        Ok(self.pre_tokenized.read().map_err(|_| Error::PoisonedError)?.clone())
+    }
+
+    /// Mark text fields of the index as taking the tokens they're indexed as instead of text,
+    /// saved with the index so they still do after a restart
+    pub fn set_pre_tokenized(&self, fields: &[String]) -> Result<()> {
+        let schema = self.index.schema();
+        for name in fields {
+            if let Some(FieldType::Str(_)) = schema.get_field(name).map(|f| schema.get_field_entry(f).field_type()) {
+                continue;
+            }
+            return Err(Error::QueryError(format!("{} is not a text field that can be pre-tokenized", name)));
+        }
+        let mut metadata = IndexMetadata::load(&self.index)?;
+        metadata.pre_tokenized = fields.to_vec();
+        metadata.touch();
+        self.save_metadata(&metadata)?;
+        *self.pre_tokenized.write().map_err(|_| Error::PoisonedError)? = fields.iter().cloned().collect();
+        Ok(())
+    }
+
+    /// The text fields of the index whose values are only indexed up to a number of characters
+    pub fn ignore_above(&self) -> Result<HashMap<String, usize>> {
+        Ok(self.ignore_above.read().map_err(|_| Error::PoisonedError)?.clone())
+    }
+
+    /// Only index the values of text fields up to a number of characters each, longer values are
+    /// still stored. Saved with the index so the limits still apply after a restart.
+    pub fn set_ignore_above(&self, fields: &HashMap<String, usize>) -> Result<()> {
+        let schema = self.index.schema();
+        for name in fields.keys() {
+            if let Some(FieldType::Str(_)) = schema.get_field(name).map(|f| schema.get_field_entry(f).field_type()) {
+                continue;
+            }
+            return Err(Error::QueryError(format!(
+                "{} is not a text field that ignore_above applies to",
+                name
+            )));
+        }
+        let mut metadata = IndexMetadata::load(&self.index)?;
+        metadata.ignore_above = fields.clone();
+        metadata.touch();
+        self.save_metadata(&metadata)?;
+        *self.ignore_above.write().map_err(|_| Error::PoisonedError)? = fields.clone();
+        Ok(())
+    }
+
+    /// Replace `true` and `false` in the bool fields of a document with the 1 and 0 they're kept as
+    fn encode_bools(&self, document: &mut serde_json::Value) -> Result<()> {
+        fn encode(value: &mut serde_json::Value) {
+            match value {
+                serde_json::Value::Bool(b) => *value = serde_json::Value::from(*b as u64),
+                serde_json::Value::Array(values) => values.iter_mut().for_each(encode),
+                _ => (),
+            }
+        }
+        let bools = self.bool_fields.read().map_err(|_| Error::PoisonedError)?;
+        if let serde_json::Value::Object(fields) = document {
+            for name in bools.iter() {
+                if let Some(value) = fields.get_mut(name) {
+                    encode(value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A document's stored values as the JSON types of their fields, pre-tokenized values as the text
+    /// of their tokens
+    pub fn typed_doc(
+        doc: BTreeMap<String, Vec<tantivy::schema::Value>>,
+        bools: &HashSet<String>,
+    ) -> BTreeMap<String, Vec<serde_json::Value>> {
+        doc.into_iter()
+            .map(|(name, values)| {
+                let is_bool = bools.contains(&name);
+                let values = values
+                    .into_iter()
+                    .map(|v| match v {
+                        tantivy::schema::Value::U64(n) if is_bool => serde_json::Value::Bool(n != 0),
+                        tantivy::schema::Value::PreTokStr(tokens) => serde_json::Value::String(tokens.text),
+                        v => serde_json::to_value(v).unwrap_or(serde_json::Value::Null),
+                    })
+                    .collect();
+                (name, values)
+            })
+            .collect()
+    }
+
+    /// When the index was created and when its schema or settings were last changed, in milliseconds
+    /// since the epoch. Indexes created before these were recorded have neither until they're changed.
+    pub fn audit_times(&self) -> Result<(Option<u64>, Option<u64>)> {
+        Ok(*self.audit_times.read().map_err(|_| Error::PoisonedError)?)
+    }
+
+    /// Save `metadata` with the index and keep the times it was created and changed at in step
+    fn save_metadata(&self, metadata: &IndexMetadata) -> Result<()> {
+        metadata.save(&self.index)?;
+        *self.audit_times.write().map_err(|_| Error::PoisonedError)? = (metadata.created_at, metadata.modified_at);
+        Ok(())
+    }
+
+    /// Record that the index was just created
+    pub fn mark_created(&self) -> Result<()> {
+        let mut metadata = IndexMetadata::load(&self.index)?;
+        metadata.touch();
+        metadata.created_at = metadata.modified_at;
+        metadata.compression = Some(COMPRESSION.into());
+        self.save_metadata(&metadata)
+    }
+
+    /// The codec the index's stored fields are compressed with, `None` when the index was created
+    /// before it was recorded
+    pub fn compression(&self) -> Result<Option<String>> {
+        Ok(IndexMetadata::load(&self.index)?.compression)
+    }
+
+    /// Record that the index's schema or settings were just changed
+    pub fn mark_modified(&self) -> Result<()> {
+        let mut metadata = IndexMetadata::load(&self.index)?;
+        metadata.touch();
+        self.save_metadata(&metadata)
+    }
+
+    /// The shard routing last saved with the index, if it has ever been planned
+    pub fn saved_routing(&self) -> Result<Option<IndexRouting>> {
+        IndexMetadata::load(&self.index).map(|m| m.routing)
+    }
+
+    /// Save where the index's shards live so it can be routed again after a restart
+    pub fn save_routing(&self, routing: &IndexRouting) -> Result<()> {
+        let mut metadata = IndexMetadata::load(&self.index)?;
+        metadata.routing = Some(routing.clone());
+        self.save_metadata(&metadata)
+    }
+
+    /// The partitions the index's routing values are hashed to and the shards holding them, none
+    /// when routing values are placed on the shards directly
+    pub fn saved_partitions(&self) -> Result<Option<PartitionTable>> {
+        IndexMetadata::load(&self.index).map(|m| m.partitions)
+    }
+
+    /// Save which shard holds each of the index's routing partitions so moves outlast a restart
+    pub fn save_partitions(&self, partitions: &PartitionTable) -> Result<()> {
+        let mut metadata = IndexMetadata::load(&self.index)?;
+        metadata.partitions = Some(partitions.clone());
+        self.save_metadata(&metadata)
+    }
+
+    /// Fails with [`Error::ReadOnly`] when the index is not accepting writes
+    pub fn check_writable(&self) -> Result<()> {
+        if self.is_read_only() {
+            Err(Error::ReadOnly(self.name.clone()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Fails with [`Error::IngestionBreaker`] while the index's writer is too far behind to take
+    /// more writes, see [`crate::settings::IngestionBreaker`]
+    pub fn check_ingestion(&self) -> Result<()> {
+        let breaker = &self.settings.ingestion_breaker;
+        if breaker.high_water == 0 {
+            return Ok(());
+        }
+        let queued = self.get_opstamp();
+        let tripped = if self.ingestion_tripped.load(Ordering::SeqCst) {
+            queued > breaker.low_water
+        } else {
+            queued >= breaker.high_water
+        };
+        if tripped != self.ingestion_tripped.swap(tripped, Ordering::SeqCst) {
+            if tripped {
+                warn!("Ingestion breaker of {} tripped at {} queued writes", self.name, queued);
+            } else {
+                info!("Ingestion breaker of {} reset at {} queued writes", self.name, queued);
+            }
+        }
+        if tripped {
+            Err(Error::IngestionBreaker {
+                index: self.name.clone(),
+                queued,
+                resume_at: breaker.low_water,
+            })
+        } else {
+            Ok(())
+        }
     }
 
     pub fn get_space(&self) -> SearcherSpaceUsage {
-        self.reader.searcher().space_usage()
+        self.searcher().space_usage()
+    }
+
+    pub fn get_settings(&self) -> &Settings {
+        &self.settings
     }
 
     pub fn get_index(&self) -> &Index {
         &self.index
     }
 
+    /// The ingest pipeline documents are run through before being indexed. Pipelines are only held
+    /// in memory and have to be set again after a restart.
+    pub fn get_pipeline(&self) -> Result<Option<Pipeline>> {
+        Ok(self.pipeline.read().map_err(|_| Error::PoisonedError)?.clone())
+    }
+
+    pub fn set_pipeline(&self, pipeline: Option<Pipeline>) -> Result<()> {
+        *self.pipeline.write().map_err(|_| Error::PoisonedError)? = pipeline;
+        Ok(())
+    }
+
     pub fn recreate_writer(self) -> Result<Self> {
-        LocalIndex::new(self.index, self.settings.clone(), &self.name)
+        let mut handle = LocalIndex::new(self.index, self.settings.clone(), &self.name)?;
+        handle.pipeline = self.pipeline;
+        handle.wal = self.wal;
+        handle.blocking = self.blocking;
+        Ok(handle)
     }
 
     pub fn get_writer(&self) -> Arc<Mutex<IndexWriter>> {
         Arc::clone(&self.writer)
     }
 
+    /// Give `doc` the next number of the index's [`Sequencer`] when it tracks changes. Every path
+    /// that hands a document to the writer goes through here, so none is missing from the changes.
+    pub fn stamp(&self, doc: &mut Document) {
+        if let Some(sequencer) = &self.sequencer {
+            sequencer.stamp(doc);
+        }
+    }
+
     pub fn get_opstamp(&self) -> usize {
         trace!("Got the opstamp");
         self.current_opstamp.load(Ordering::SeqCst)
@@ -266,3 +1925,222 @@ impl LocalIndex {
         self.current_opstamp.store(opstamp, Ordering::SeqCst)
     }
 }
+
+/// How the hits of a search are ranked
+#[derive(Clone, Copy)]
+enum HitOrder {
+    Score,
+    /// Highest value of a fast field first, `true` when the field is an `i64` one
+    Field(Field, bool),
+    Doc,
+}
+
+impl HitOrder {
+    /// How the hits of `search` are ranked, only `u64` and `i64` fast fields can be sorted by
+    fn of(search: &Search, schema: &Schema) -> Result<Self> {
+        let sort_by = match &search.sort_by {
+            Some(sort_by) if sort_by == Search::DOC_ORDER => return Ok(HitOrder::Doc),
+            Some(sort_by) => sort_by,
+            None => return Ok(HitOrder::Score),
+        };
+        info!("Sorting with: {}", sort_by);
+        let field = schema.get_field(sort_by).ok_or_else(|| Error::UnknownIndexField(sort_by.clone()))?;
+        let single = |options: &IntOptions| options.get_fastfield_cardinality() == Some(Cardinality::SingleValue);
+        match schema.get_field_entry(field).field_type() {
+            FieldType::U64(options) if single(options) => Ok(HitOrder::Field(field, false)),
+            FieldType::I64(options) if single(options) => Ok(HitOrder::Field(field, true)),
+            _ => Err(Error::QueryError(format!(
+                "Can't sort by {}, it isn't a single valued u64 or i64 fast field",
+                sort_by
+            ))),
+        }
+    }
+
+    /// The value of `field` for each document of a segment, as a key that orders the documents
+    /// the way their values do
+    fn sort_key(reader: &SegmentReader, field: Field, signed: bool) -> Box<dyn Fn(DocId) -> u64> {
+        let fast = reader.fast_fields();
+        if signed {
+            match fast.i64(field) {
+                Some(values) => Box::new(move |doc| tantivy::i64_to_u64(values.get(doc))),
+                None => Box::new(|_| 0),
+            }
+        } else {
+            match fast.u64(field) {
+                Some(values) => Box::new(move |doc| values.get(doc)),
+                None => Box::new(|_| 0),
+            }
+        }
+    }
+
+    /// The value a key of [`HitOrder::sort_key`] came from
+    fn sort_value(self, key: u64) -> Option<SortValue> {
+        match self {
+            HitOrder::Field(_, true) => Some(SortValue::I64(tantivy::u64_to_i64(key))),
+            HitOrder::Field(_, false) => Some(SortValue::U64(key)),
+            _ => None,
+        }
+    }
+}
+
+/// The tokens of `text` that are one of `terms`, with their byte offsets turned into character offsets
+fn highlights<'a>(text: &'a str, mut tokens: Box<dyn TokenStream + 'a>, field: Field, terms: &BTreeSet<Term>) -> Vec<Highlight> {
+    let chars = |byte: usize| text[..byte].chars().count();
+    let mut matches = Vec::new();
+    while tokens.advance() {
+        let token = tokens.token();
+        if terms.contains(&Term::from_field_text(field, &token.text)) {
+            matches.push(Highlight {
+                start: chars(token.offset_from),
+                end: chars(token.offset_to),
+            });
+        }
+    }
+    matches
+}
+
+/// FruitHandle isn't a public type, so each way of ranking hits holds its own handle as the type
+/// the collector it was added with returns
+enum TopHandle<S, SC, SF, F, D> {
+    Score(S),
+    Script(SC),
+    ScoredField(SF),
+    Field(F),
+    Doc(D),
+}
+
+/// The handle of each kind of aggregation's collector, with the aggregation it computes
+enum AggregationHandle<'a, H, R, T, M> {
+    Histogram(&'a DateHistogram, H),
+    Range(&'a RangeAggregation, R),
+    Terms(&'a TermsAggregation, T),
+    Metric(M),
+}
+
+/// Passes on to `collector` only the documents `filter` matches too, or every document without a filter
+struct PostFilter<C> {
+    collector: C,
+    filter: Option<Arc<dyn Weight>>,
+}
+
+impl<C> PostFilter<C> {
+    fn new(collector: C, filter: Option<Arc<dyn Weight>>) -> Self {
+        Self { collector, filter }
+    }
+}
+
+struct PostFilterSegment<C> {
+    collector: C,
+    filter: Option<Box<dyn Scorer>>,
+    /// Whether the filter has been moved onto its first document, and whether it has run out of them
+    started: bool,
+    ended: bool,
+}
+
+impl<C: Collector> Collector for PostFilter<C> {
+    type Fruit = C::Fruit;
+    type Child = PostFilterSegment<C::Child>;
+
+    fn for_segment(&self, segment: SegmentLocalId, reader: &SegmentReader) -> tantivy::Result<Self::Child> {
+        Ok(PostFilterSegment {
+            collector: self.collector.for_segment(segment, reader)?,
+            filter: match &self.filter {
+                Some(weight) => Some(weight.scorer(reader)?),
+                None => None,
+            },
+            started: false,
+            ended: false,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.collector.requires_scoring()
+    }
+
+    fn merge_fruits(&self, fruits: Vec<Self::Fruit>) -> tantivy::Result<Self::Fruit> {
+        self.collector.merge_fruits(fruits)
+    }
+}
+
+impl<C: SegmentCollector> SegmentCollector for PostFilterSegment<C> {
+    type Fruit = C::Fruit;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        if let Some(filter) = &mut self.filter {
+            // Documents are collected in order within a segment, so the filter only ever moves forward
+            if self.ended {
+                return;
+            }
+            if !self.started || filter.doc() < doc {
+                self.started = true;
+                match filter.skip_next(doc) {
+                    SkipResult::Reached => (),
+                    SkipResult::OverStep => return,
+                    SkipResult::End => {
+                        self.ended = true;
+                        return;
+                    }
+                }
+            } else if filter.doc() > doc {
+                return;
+            }
+        }
+        self.collector.collect(doc, score);
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        self.collector.harvest()
+    }
+}
+
+/// Collects the first `limit` matching documents in the order they are kept in the index,
+/// scoring them only when `track_scores` is set
+struct DocOrder {
+    limit: usize,
+    track_scores: bool,
+}
+
+struct DocOrderSegment {
+    segment: SegmentLocalId,
+    limit: usize,
+    hits: Vec<(Score, DocAddress)>,
+}
+
+impl Collector for DocOrder {
+    type Fruit = Vec<(Score, DocAddress)>;
+    type Child = DocOrderSegment;
+
+    fn for_segment(&self, segment: SegmentLocalId, _: &SegmentReader) -> tantivy::Result<Self::Child> {
+        Ok(DocOrderSegment {
+            segment,
+            limit: self.limit,
+            hits: Vec::new(),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.track_scores
+    }
+
+    fn merge_fruits(&self, fruits: Vec<Self::Fruit>) -> tantivy::Result<Self::Fruit> {
+        let mut hits: Vec<(Score, DocAddress)> = fruits.into_iter().flatten().collect();
+        hits.sort_by_key(|(_, addr)| *addr);
+        hits.truncate(self.limit);
+        Ok(hits)
+    }
+}
+
+impl SegmentCollector for DocOrderSegment {
+    type Fruit = Vec<(Score, DocAddress)>;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        // Documents are collected in order within a segment, so only the first ones are kept
+        if self.hits.len() < self.limit {
+            self.hits.push((score, DocAddress(self.segment, doc)));
+        }
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        self.hits
+    }
+}
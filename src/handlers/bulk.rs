@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::str::from_utf8;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
@@ -5,93 +6,356 @@ use std::time::Instant;
 
 use crossbeam::channel::{unbounded, Receiver, Sender};
 use futures::StreamExt;
-use hyper::Body;
-use hyper::StatusCode;
+use hyper::{Body, Response, StatusCode};
 use tantivy::schema::Schema;
-use tantivy::{Document, IndexWriter};
-use tokio::sync::Mutex;
+use tantivy::Document;
 use tracing::*;
 use tracing_futures::Instrument;
 
+use toshi_types::{BulkItem, BulkResponse, Error};
+
+use crate::cancel::CancelToken;
+use crate::cluster::replication::replicate;
+use crate::dead_letter::{DeadLetter, DeadLetterDoc};
+use crate::handle::LocalIndex;
 use crate::handlers::ResponseFuture;
 use crate::index::SharedCatalog;
-use crate::utils::empty_with_code;
+use crate::mapping;
+use crate::nested::{copy_keywords, flatten, has_nested_fields};
+use crate::pipeline::Pipeline;
+use crate::pretokenized;
+use crate::router::QueryOptions;
+use crate::settings::UnknownFields;
+use crate::utils::with_body;
+use crate::wal::Logged;
+use crate::AddDocument;
 
-async fn index_documents(iw: Arc<Mutex<IndexWriter>>, dr: Receiver<Document>, wr: Arc<AtomicBool>) -> Result<(), ()> {
+/// Add every parsed document to the index, committing each time `commit_every` documents were
+/// added since the last commit and once more for the rest at the end. With `commit_every` at 0 the
+/// documents are left for the next commit. Returns how many commits were made.
+async fn index_documents(
+    index: LocalIndex,
+    dr: Receiver<Parsed>,
+    wr: Arc<AtomicBool>,
+    cancel: CancelToken,
+    commit_every: usize,
+) -> Result<usize, Error> {
     let parsing_span = info_span!("PipingDocuments");
     let _enter = parsing_span.enter();
     let start = Instant::now();
-    let w = iw.lock().await;
-    for doc in dr {
-        w.add_document(doc);
+    let result = write_documents(&index, dr, &cancel, commit_every).await;
+    info!("Piping Documents took: {:?}, commits made: {:?}", start.elapsed(), result);
+    wr.store(false, Ordering::SeqCst);
+    result
+}
+
+/// The writer is held for the whole insert, each commit hands it to the blocking pool and gets it
+/// back once the commit is done. A full write-ahead log is emptied by committing right away.
+async fn write_documents(index: &LocalIndex, dr: Receiver<Parsed>, cancel: &CancelToken, commit_every: usize) -> Result<usize, Error> {
+    let mut w = index.get_writer().lock_owned().await;
+    let mut commits = 0;
+    let mut uncommitted = 0;
+    for (mut doc, logged) in dr {
+        if cancel.is_cancelled() {
+            info!("Bulk insert was cancelled, no longer indexing documents");
+            break;
+        }
+        index.stamp(&mut doc);
+        let opstamp = w.add_document(doc);
+        // Counted like single adds, so the ingestion breaker sees the writes waiting on a commit
+        index.set_opstamp(index.get_opstamp() + 1);
+        uncommitted += 1;
+        let log_full = match logged {
+            Some(document) => index.log_write(opstamp, Logged::Add(AddDocument::new(None, document)))?,
+            None => false,
+        };
+        if log_full || (commit_every > 0 && uncommitted >= commit_every) {
+            w = index.commit_writer(w).await?.0;
+            commits += 1;
+            uncommitted = 0;
+        }
     }
+    if commit_every > 0 && uncommitted > 0 && !cancel.is_cancelled() {
+        index.commit_writer(w).await?;
+        commits += 1;
+    }
+    Ok(commits)
+}
 
-    info!("Piping Documents took: {:?}", start.elapsed());
-    wr.store(false, Ordering::SeqCst);
-    Ok(())
+/// Bulk inserts hold the index's writer until they're done, so fields can't be added to the schema
+/// part way through one and fields a `Dynamic` index doesn't have yet are rejected
+fn parse_line(
+    s: &Schema,
+    nested: bool,
+    pipeline: Option<&Pipeline>,
+    unknown: UnknownFields,
+    tokenized: &HashSet<String>,
+    line: &[u8],
+) -> Result<Document, String> {
+    let text = from_utf8(line).map_err(|e| e.to_string())?;
+    // Documents only have to be parsed twice when they could need rewriting before indexing
+    if !nested && pipeline.is_none() && tokenized.is_empty() {
+        match LocalIndex::parse_doc(s, text) {
+            Err(Error::UnknownIndexField(_)) if unknown == UnknownFields::Ignore => (),
+            parsed => return parsed.map_err(|e| e.to_string()),
+        }
+    }
+    let mut value: serde_json::Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    let tokens = pretokenized::take(&mut value, tokenized).map_err(|e| e.to_string())?;
+    flatten(&mut value).map_err(|e| e.to_string())?;
+    if let Some(p) = pipeline {
+        p.apply(&mut value, s).map_err(|e| e.to_string())?;
+    }
+    copy_keywords(&mut value, s);
+    pretokenized::restore(&mut value, tokens);
+    mapping::apply(unknown, &mut value, s).map_err(|e| e.to_string())?;
+    LocalIndex::parse_doc(s, &value.to_string()).map_err(|e| e.to_string())
 }
 
-async fn parsing_documents(s: Schema, ds: Sender<Document>, lr: Receiver<Vec<u8>>) -> Result<(), ()> {
+/// Why a line of a bulk insert wasn't indexed: the status to report for it, the line and the error
+type Failure = (u16, String, String);
+
+/// A document parsed for the writer and, when the index keeps a write-ahead log, the line it was
+/// parsed from to log
+type Parsed = (Document, Option<serde_json::Value>);
+
+/// Parse numbered lines into documents for the writer, returning for each line that isn't blank
+/// its number and, if it couldn't be parsed or the writer stopped before taking it, why
+async fn parsing_documents(
+    s: Schema,
+    pipeline: Option<Pipeline>,
+    unknown: UnknownFields,
+    tokenized: HashSet<String>,
+    log: bool,
+    ds: Sender<Parsed>,
+    lr: Receiver<(usize, Vec<u8>)>,
+    cancel: CancelToken,
+) -> Vec<(usize, Option<Failure>)> {
     let parsing_span = info_span!("ParsingDocs");
     let _enter = parsing_span.enter();
-    for line in lr {
-        if !line.is_empty() {
-            if let Ok(text) = from_utf8(&line) {
-                if let Ok(doc) = s.parse_document(text) {
-                    info!("Sending doc: {:?}", &doc);
-                    ds.send(doc).unwrap()
-                }
+    let nested = has_nested_fields(&s);
+    let mut parsed = Vec::new();
+    for (line_no, line) in lr {
+        if cancel.is_cancelled() {
+            break;
+        }
+        let text = String::from_utf8_lossy(&line);
+        if text.trim().is_empty() {
+            continue;
+        }
+        match parse_line(&s, nested, pipeline.as_ref(), unknown, &tokenized, &line) {
+            Ok(doc) => {
+                info!("Sending doc: {:?}", &doc);
+                // The writer only stops taking documents when it failed, the rest still get an item
+                let logged = if log { serde_json::from_slice(&line).ok() } else { None };
+                let failure = ds.send((doc, logged)).err().map(|_| {
+                    let error = "The index writer stopped before the document was indexed".to_string();
+                    (500, text.trim().to_string(), error)
+                });
+                parsed.push((line_no, failure));
             }
+            Err(e) => parsed.push((line_no, Some((400, text.trim().to_string(), e)))),
         }
     }
     info!("Done parsing docs...");
-    Ok(())
+    parsed
 }
 
-pub async fn bulk_insert(catalog: SharedCatalog, watcher: Arc<AtomicBool>, mut body: Body, index: String) -> ResponseFuture {
+pub async fn bulk_insert(
+    catalog: SharedCatalog,
+    watcher: Arc<AtomicBool>,
+    mut body: Body,
+    index: String,
+    options: QueryOptions,
+) -> ResponseFuture {
     let span = info_span!("BulkInsert");
     let _enter = span.enter();
+    let index_name = index;
+    let index_handle = catalog.get_index(&index_name).unwrap();
+    if let Err(e) = index_handle.check_writable() {
+        return Ok(Response::from(e));
+    }
+    if let Err(e) = index_handle.check_ingestion() {
+        return Ok(Response::from(e));
+    }
     watcher.store(true, Ordering::SeqCst);
-    let index_lock = catalog.lock().await;
-    let index_handle = index_lock.get_index(&index).unwrap();
     let index = index_handle.get_index();
+    // Documents are only held on to when they also have to be sent out to replicas
+    let replicating = catalog.settings.experimental && catalog.get_routing(&index_name).is_ok();
+    let mut replica_docs = Vec::new();
     let schema = index.schema();
-    let (line_sender, line_recv) = index_lock.settings.get_channel::<Vec<u8>>();
-    let (doc_sender, doc_recv) = unbounded::<Document>();
-    let writer = index_handle.get_writer();
-    let num_threads = index_lock.settings.json_parsing_threads;
+    let pipeline = match index_handle.get_pipeline() {
+        Ok(p) => p,
+        Err(e) => return Ok(Response::from(e)),
+    };
+    let tokenized = match index_handle.pre_tokenized() {
+        Ok(t) => t,
+        Err(e) => return Ok(Response::from(e)),
+    };
+    let (line_sender, line_recv) = catalog.settings.get_channel::<(usize, Vec<u8>)>();
+    let (doc_sender, doc_recv) = unbounded::<Parsed>();
+    let num_threads = catalog.settings.json_parsing_threads;
     let line_sender_clone = line_sender.clone();
     let watcher_clone = Arc::clone(&watcher);
+    // Stops parsing and indexing if this handler is dropped because the client went away
+    let cancel = CancelToken::default();
+    let guard = cancel.guard();
 
-    for _ in 0..num_threads {
-        let schema = schema.clone();
-        let doc_sender = doc_sender.clone();
-        let line_recv = line_recv.clone();
-
-        tokio::spawn(parsing_documents(schema.clone(), doc_sender.clone(), line_recv.clone()).in_current_span());
-    }
+    let parsers: Vec<_> = (0..num_threads)
+        .map(|_| {
+            tokio::spawn(
+                parsing_documents(
+                    schema.clone(),
+                    pipeline.clone(),
+                    catalog.settings.get_unknown_fields(&index_name),
+                    tokenized.clone(),
+                    index_handle.has_wal(),
+                    doc_sender.clone(),
+                    line_recv.clone(),
+                    cancel.clone(),
+                )
+                .in_current_span(),
+            )
+        })
+        .collect();
 
+    // A chunk can end partway through a line, the start of it is kept until the rest arrives
     let mut buf = Vec::new();
-    let mut remaining = vec![];
-    while let Some(Ok(line)) = body.next().await {
+    let mut line_no = 0;
+    while let Some(chunk) = body.next().await {
+        let line = match chunk {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Bulk body could not be read, cancelling insert: {}", e);
+                watcher.store(false, Ordering::SeqCst);
+                return Ok(Response::from(Error::Cancelled));
+            }
+        };
         buf.extend(line);
+        debug!("Bytes in buf: {}", buf.len());
 
-        let mut split = buf.split(|b| *b == b'\n').peekable();
+        let mut lines: Vec<&[u8]> = buf.split(|b| *b == b'\n').collect();
+        let partial = lines.pop().unwrap_or_default().to_vec();
+        for l in lines {
+            if replicating {
+                if let Ok(doc) = serde_json::from_slice::<serde_json::Value>(l) {
+                    replica_docs.push(AddDocument::new(None, doc));
+                }
+            }
+            line_sender_clone.send((line_no, l.to_vec())).expect("Line sender failed.");
+            line_no += 1;
+        }
+        buf = partial;
+    }
 
-        while let Some(l) = split.next() {
-            if split.peek().is_none() {
-                remaining = l.to_vec();
+    if !buf.is_empty() {
+        if replicating {
+            if let Ok(doc) = serde_json::from_slice::<serde_json::Value>(&buf) {
+                replica_docs.push(AddDocument::new(None, doc));
             }
-            debug!("Bytes in buf: {}", buf.len());
-            line_sender_clone.send(l.to_vec()).expect("Line sender failed.");
         }
+        line_sender.send((line_no, buf)).expect("Line sender failed #2");
     }
+    drop((line_sender, line_sender_clone, doc_sender));
+    let indexer = tokio::spawn(
+        index_documents(
+            index_handle.clone(),
+            doc_recv,
+            watcher_clone,
+            cancel.clone(),
+            options.commit_every(),
+        )
+        .in_current_span(),
+    );
 
-    if !remaining.is_empty() {
-        line_sender.send(remaining).expect("Line sender failed #2");
+    let mut parsed = Vec::new();
+    for parser in parsers {
+        parsed.extend(parser.await.unwrap_or_default());
+    }
+    parsed.sort_by_key(|(line_no, _)| *line_no);
+    // Documents are only reported as created when the request waits for them to be indexed
+    let waiting = options.commit_every() > 0;
+    let items: Vec<BulkItem> = parsed
+        .iter()
+        .map(|(_, failure)| match failure {
+            Some((status, _, error)) => BulkItem {
+                status: *status,
+                error: Some(error.clone()),
+            },
+            None => BulkItem {
+                status: if waiting { 201 } else { 202 },
+                error: None,
+            },
+        })
+        .collect();
+    let failed: Vec<DeadLetterDoc> = parsed
+        .into_iter()
+        .filter_map(|(_, failure)| failure)
+        .map(|(_, document, error)| DeadLetterDoc {
+            index: index_name.clone(),
+            document,
+            error,
+        })
+        .collect();
+    // Every document has been parsed and handed to the writer, so finish the insert even if the client goes away now
+    guard.disarm();
+    let indexed = items.iter().filter(|i| i.error.is_none()).count() as u64;
+    // Only a request that commits as it loads waits for the writer, to report its commits
+    let commits = if waiting {
+        let commits = match indexer.await {
+            Ok(Ok(commits)) => commits,
+            Ok(Err(e)) => return Ok(Response::from(e)),
+            Err(_) => return Ok(Response::from(Error::SpawnError)),
+        };
+        index_handle.record_indexed(indexed);
+        commits
+    } else {
+        let (handle, name) = (index_handle.clone(), index_name.clone());
+        tokio::spawn(
+            async move {
+                match indexer.await {
+                    Ok(Ok(_)) => handle.record_indexed(indexed),
+                    Ok(Err(e)) => error!("Bulk insert into {} failed: {}", name, e),
+                    Err(e) => error!("Bulk insert into {} panicked: {}", name, e),
+                }
+            }
+            .in_current_span(),
+        );
+        0
+    };
+    let sink = options
+        .dead_letter_index
+        .clone()
+        .map(DeadLetter::Index)
+        .or_else(|| catalog.settings.dead_letter.clone());
+    let dead_letters = match sink {
+        Some(sink) if !failed.is_empty() => match sink.send(&catalog, &failed).await {
+            Ok(sent) => sent,
+            Err(e) => return Ok(Response::from(e)),
+        },
+        _ => {
+            if !failed.is_empty() {
+                warn!(
+                    "Dropping {} documents that failed to parse, no dead letter sink is configured",
+                    failed.len()
+                );
+            }
+            0
+        }
+    };
+    if replicating {
+        if let Err(e) = replicate(&catalog, &index_name, replica_docs, options.consistency()).await {
+            return Ok(Response::from(e));
+        }
     }
-    tokio::spawn(index_documents(writer, doc_recv, watcher_clone).in_current_span());
-    Ok(empty_with_code(StatusCode::CREATED))
+    let mut resp = with_body(BulkResponse {
+        dead_letters,
+        commits,
+        errors: !failed.is_empty(),
+        items,
+    });
+    *resp.status_mut() = if waiting { StatusCode::CREATED } else { StatusCode::ACCEPTED };
+    Ok(resp)
 }
 
 #[cfg(test)]
@@ -104,6 +368,8 @@ mod tests {
     use crate::handlers::all_docs;
     use crate::handlers::summary::flush;
     use crate::index::tests::*;
+    use crate::index::IndexCatalog;
+    use crate::settings::DirectoryKind;
     use crate::SearchResults;
 
     use super::*;
@@ -118,8 +384,15 @@ mod tests {
         {"test_text": "asdf5678", "test_i64": 456, "test_u64": 678, "test_unindex": "asdf"}
         {"test_text": "asdf9012", "test_i64": -12, "test_u64": 901, "test_unindex": "asdf"}"#;
 
-        let index_docs = bulk_insert(Arc::clone(&server), lock, Body::from(body), "test_index".into()).await?;
-        assert_eq!(index_docs.status(), StatusCode::CREATED);
+        let index_docs = bulk_insert(
+            Arc::clone(&server),
+            lock,
+            Body::from(body),
+            "test_index".into(),
+            QueryOptions::default(),
+        )
+        .await?;
+        assert_eq!(index_docs.status(), StatusCode::ACCEPTED);
         sleep(Duration::from_secs_f32(0.1));
 
         let flush = flush(Arc::clone(&server), "test_index".to_string()).await?;
@@ -131,12 +404,258 @@ mod tests {
             let check_docs = all_docs(Arc::clone(&server), "test_index".into()).await?;
             let body = read_body(check_docs).await?;
             let docs: SearchResults = serde_json::from_slice(body.as_bytes())?;
-            if docs.hits == 9 {
+            if docs.hits == 8 {
                 break;
             }
             attempts += 1;
         }
         assert_eq!(attempts >= 5, false);
+
+        // The body's last line, which has no newline after it, is only indexed once
+        let last = r#"{ "query": { "term": { "test_text": "asdf9012" } } }"#;
+        let check_docs =
+            crate::handlers::doc_search(Arc::clone(&server), Body::from(last), "test_index".into(), QueryOptions::default()).await?;
+        let docs: SearchResults = serde_json::from_slice(read_body(check_docs).await?.as_bytes())?;
+        assert_eq!(docs.hits, 1);
+        Ok(())
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_bulk_writes_ahead() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::wal::{WalSettings, WriteAheadLog};
+
+        let dir = std::env::temp_dir().join(format!("toshi-wal-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir)?;
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.path = dir.to_string_lossy().into_owned();
+        catalog.settings.write_ahead_log = Some(WalSettings::default());
+        catalog.add_index("test_index".into(), toshi_test::create_test_index())?;
+        let server = Arc::new(catalog);
+        let lock = Arc::new(AtomicBool::new(false));
+
+        let body = r#"{"test_text": "logged", "test_i64": 1, "test_u64": 1, "test_unindex": "asdf"}
+        {"test_text": "also logged", "test_i64": 2, "test_u64": 2, "test_unindex": "asdf"}"#;
+        let resp = bulk_insert(
+            Arc::clone(&server),
+            Arc::clone(&lock),
+            Body::from(body),
+            "test_index".into(),
+            QueryOptions::default(),
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        for _ in 0..50 {
+            if !lock.load(Ordering::SeqCst) {
+                break;
+            }
+            sleep(Duration::from_millis(20));
+        }
+
+        // The documents weren't committed, so they're in the log to be added again after a crash
+        let path = WriteAheadLog::path_for(&server.settings.path, "test_index");
+        let mut logged: Vec<String> = WriteAheadLog::open(&path, WalSettings::default())?
+            .uncommitted(0)?
+            .into_iter()
+            .filter_map(|op| match op {
+                Logged::Add(doc) => doc.document["test_text"].as_str().map(String::from),
+                Logged::Delete(_) => None,
+            })
+            .collect();
+        logged.sort();
+        assert_eq!(logged, vec!["also logged", "logged"]);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_bulk_dead_letter() -> Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = DirectoryKind::Ram;
+        let server = Arc::new(catalog);
+        let lock = Arc::new(AtomicBool::new(false));
+
+        let body = r#"
+        {"test_text": "asdf1234", "test_i64": 123, "test_u64": 321, "test_unindex": "asdf"}
+        {"test_text": "asdf5678", "test_i64": "not a number", "test_u64": 678, "test_unindex": "asdf"}
+        {"test_text": "asdf9012", "test_i64": -12, "test_u64": 901, "test_unindex": "asdf"}"#;
+
+        let options = QueryOptions {
+            dead_letter_index: Some("dead_letters".into()),
+            ..Default::default()
+        };
+        let resp = bulk_insert(Arc::clone(&server), lock, Body::from(body), "test_index".into(), options).await?;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let bulk: BulkResponse = serde_json::from_slice(read_body(resp).await?.as_bytes())?;
+        assert_eq!(bulk.dead_letters, 1);
+        let statuses: Vec<u16> = bulk.items.iter().map(|i| i.status).collect();
+        assert_eq!(statuses, vec![202, 400, 202]);
+
+        let dead = all_docs(Arc::clone(&server), "dead_letters".into()).await?;
+        let docs: SearchResults = serde_json::from_slice(read_body(dead).await?.as_bytes())?;
+        assert_eq!(docs.hits, 1);
+        let doc = &docs.docs[0].doc;
+        assert_eq!(doc["index"][0].text(), Some("test_index"));
+        assert!(doc["document"][0].text().unwrap().contains("not a number"));
+        assert!(!doc["error"][0].text().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_bulk_item_statuses() -> Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = DirectoryKind::Ram;
+        catalog.settings.json_parsing_threads = 3;
+        let server = Arc::new(catalog);
+        let lock = Arc::new(AtomicBool::new(false));
+
+        let body = r#"{"test_text": "first", "test_i64": 1, "test_u64": 1, "test_unindex": "asdf"}
+        {"test_text": "second", "test_i64": "not a number", "test_u64": 2, "test_unindex": "asdf"}
+
+        {"test_text": "third", "test_i64": 3, "test_u64": 3, "test_unindex": "asdf"}
+        {"test_text": "fourth", "#;
+        let options = QueryOptions {
+            commit_every: Some(10),
+            ..Default::default()
+        };
+        let resp = bulk_insert(Arc::clone(&server), lock, Body::from(body), "test_index".into(), options).await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let bulk: BulkResponse = serde_json::from_slice(read_body(resp).await?.as_bytes())?;
+        assert!(bulk.errors);
+        let statuses: Vec<u16> = bulk.items.iter().map(|i| i.status).collect();
+        assert_eq!(statuses, vec![201, 400, 201, 400]);
+        assert!(bulk.items[0].error.is_none());
+        assert!(bulk.items[1].error.as_ref().unwrap().contains("test_i64"));
+        assert!(bulk.items[3].error.is_some());
+
+        // The documents after the failed one were still indexed
+        server.get_index("test_index")?.reload()?;
+        let docs = all_docs(Arc::clone(&server), "test_index".into()).await?;
+        let docs: SearchResults = serde_json::from_slice(read_body(docs).await?.as_bytes())?;
+        assert_eq!(docs.hits, 7);
+        Ok(())
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_bulk_commit_every() -> Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = DirectoryKind::Ram;
+        let server = Arc::new(catalog);
+        let lock = Arc::new(AtomicBool::new(false));
+
+        let body: String = (0..5)
+            .map(|i| {
+                format!(
+                    "{{\"test_text\": \"bulk{}\", \"test_i64\": {}, \"test_u64\": {}, \"test_unindex\": \"asdf\"}}\n",
+                    i, i, i
+                )
+            })
+            .collect();
+        let options = QueryOptions {
+            commit_every: Some(2),
+            ..Default::default()
+        };
+        let resp = bulk_insert(Arc::clone(&server), lock, Body::from(body), "test_index".into(), options).await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let bulk: BulkResponse = serde_json::from_slice(read_body(resp).await?.as_bytes())?;
+        assert_eq!(bulk.commits, 3);
+
+        // Each commit made its own batch of documents searchable, without waiting for a flush
+        let handle = server.get_index("test_index")?;
+        let index = handle.get_index();
+        let reader = index.reader().map_err(Error::from)?;
+        assert!(reader.searcher().segment_readers().len() >= 3);
+        let mut hits = 0;
+        for _ in 0..10 {
+            let check_docs = all_docs(Arc::clone(&server), "test_index".into()).await?;
+            let docs: SearchResults = serde_json::from_slice(read_body(check_docs).await?.as_bytes())?;
+            hits = docs.hits;
+            if hits == 10 {
+                break;
+            }
+            sleep(Duration::from_secs_f32(0.1));
+        }
+        assert_eq!(hits, 10);
+        Ok(())
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_bulk_trips_ingestion_breaker() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::settings::IngestionBreaker;
+
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.ingestion_breaker = IngestionBreaker {
+            high_water: 3,
+            low_water: 0,
+        };
+        catalog.add_index("test_index".into(), toshi_test::create_test_index())?;
+        let server = Arc::new(catalog);
+        let lock = Arc::new(AtomicBool::new(false));
+
+        let body = r#"{"test_text": "flood", "test_i64": 1, "test_u64": 1, "test_unindex": "asdf"}
+        {"test_text": "flood", "test_i64": 2, "test_u64": 2, "test_unindex": "asdf"}
+        {"test_text": "flood", "test_i64": 3, "test_u64": 3, "test_unindex": "asdf"}"#;
+        let insert = || {
+            bulk_insert(
+                Arc::clone(&server),
+                Arc::clone(&lock),
+                Body::from(body),
+                "test_index".into(),
+                QueryOptions::default(),
+            )
+        };
+        assert_eq!(insert().await?.status(), StatusCode::ACCEPTED);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while lock.load(Ordering::SeqCst) {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+
+        // The bulk insert's documents are waiting on a commit, so the next one is turned away
+        assert_eq!(insert().await?.status(), StatusCode::SERVICE_UNAVAILABLE);
+        flush(Arc::clone(&server), "test_index".to_string()).await?;
+        assert_eq!(insert().await?.status(), StatusCode::ACCEPTED);
+        Ok(())
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_bulk_returns_before_indexing() -> Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = DirectoryKind::Ram;
+        let server = Arc::new(catalog);
+        let lock = Arc::new(AtomicBool::new(false));
+
+        // With the writer held the documents can't be indexed, a request that doesn't commit is answered anyway
+        let writer = server.get_index("test_index")?.get_writer();
+        let held = writer.lock().await;
+        let body = r#"{"test_text": "later", "test_i64": 1, "test_u64": 1, "test_unindex": "asdf"}"#;
+        let options = QueryOptions::default();
+        let insert = bulk_insert(
+            Arc::clone(&server),
+            Arc::clone(&lock),
+            Body::from(body),
+            "test_index".into(),
+            options,
+        );
+        let resp = tokio::time::timeout(Duration::from_secs(5), insert).await??;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let bulk: BulkResponse = serde_json::from_slice(read_body(resp).await?.as_bytes())?;
+        assert_eq!(bulk.commits, 0);
+        drop(held);
+        // The writer clears the bulk flag once it has indexed the documents
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while lock.load(Ordering::SeqCst) {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+
+        let flush = flush(Arc::clone(&server), "test_index".to_string()).await?;
+        assert_eq!(flush.status(), StatusCode::OK);
+        server.get_index("test_index")?.reload()?;
+        let docs = all_docs(Arc::clone(&server), "test_index".into()).await?;
+        let docs: SearchResults = serde_json::from_slice(read_body(docs).await?.as_bytes())?;
+        assert_eq!(docs.hits, 6);
         Ok(())
     }
 }
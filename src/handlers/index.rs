@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use bytes::Buf;
@@ -5,32 +6,73 @@ use hyper::body::aggregate;
 use hyper::{Body, Response, StatusCode};
 use rand::random;
 use tantivy::schema::*;
+use tantivy::store::COMPRESSION;
 use tantivy::Index;
 
 use toshi_proto::cluster_rpc::*;
-use toshi_types::{DeleteDoc, DocsAffected, Error, SchemaBody};
+use toshi_types::{
+    DeleteDoc, DocsAffected, Error, FieldSpec, InferredSchema, MovePartition, ReindexRequest, ReindexResponse, SchemaBody, TaskStarted,
+    UpdateByQuery,
+};
 
+use crate::cluster::replication::replicate;
+use crate::cluster::routing::{IndexHealth, IndexRouting, PartitionTable};
 use crate::cluster::rpc_server::RpcClient;
 use crate::handle::IndexHandle;
 use crate::handlers::ResponseFuture;
 use crate::index::{IndexCatalog, SharedCatalog};
-use crate::utils::{empty_with_code, error_response, with_body};
+use crate::mapping;
+use crate::reindex;
+use crate::router::QueryOptions;
+use crate::tasks::TaskProgress;
+use crate::utils::{empty_with_code, with_body};
 use crate::AddDocument;
 
-#[inline]
-async fn add_index(catalog: SharedCatalog, name: String, index: Index) -> Result<(), Error> {
-    catalog.lock().await.add_index(name, index)
-}
-
-#[inline]
-async fn add_remote_index(catalog: SharedCatalog, name: String, clients: Vec<RpcClient>) -> Result<(), Error> {
-    catalog.lock().await.add_multi_remote_index(name, clients).await
+/// Register the copies of a newly placed index, the primary's as the index's one shard unless this
+/// node holds it and every other copy as one of its replicas
+async fn add_remote_index(catalog: &IndexCatalog, name: String, routing: &IndexRouting, clients: Vec<RpcClient>) -> Result<(), Error> {
+    for (node, client) in routing.nodes().into_iter().zip(clients) {
+        if node == routing.primary_node {
+            if node != catalog.settings.place_addr {
+                catalog.add_remote_index(name.clone(), client).await?;
+            }
+        } else {
+            catalog.add_replica_index(name.clone(), node, client).await?;
+        }
+    }
+    Ok(())
 }
 
-async fn delete_terms(catalog: SharedCatalog, body: DeleteDoc, index: &str) -> Result<DocsAffected, Error> {
-    let index_lock = catalog.lock().await;
-    let index_handle = index_lock.get_index(index)?;
-    index_handle.delete_term(body).await
+async fn delete_terms(catalog: SharedCatalog, body: DeleteDoc, index: &str, dry_run: bool) -> Result<DocsAffected, Error> {
+    let pattern = IndexCatalog::is_index_pattern(index);
+    let names = if pattern {
+        catalog.resolve_indexes(index)?
+    } else {
+        vec![index.to_string()]
+    };
+    if names.is_empty() {
+        return Err(Error::UnknownIndex(index.into()));
+    }
+    if dry_run {
+        let mut docs_affected = 0;
+        for name in &names {
+            docs_affected += catalog.get_index(name)?.count_deletes(&body)?;
+        }
+        return Ok(DocsAffected { docs_affected });
+    }
+    if !pattern {
+        return catalog.get_index(index)?.delete_term(body).await;
+    }
+    // Every index has to take the delete before any of them does, so a read only one doesn't leave it half applied
+    let handles = names.iter().map(|n| catalog.get_index(n)).collect::<Result<Vec<_>, _>>()?;
+    for handle in &handles {
+        handle.check_writable()?;
+    }
+    let mut docs_affected = 0;
+    for handle in handles {
+        docs_affected += handle.delete_term(body.clone()).await?.docs_affected;
+    }
+    Ok(DocsAffected { docs_affected })
 }
 
 async fn create_remote_index(nodes: &[String], index: String, schema: Schema) -> Result<Vec<RpcClient>, Error> {
@@ -49,7 +91,7 @@ async fn create_remote_index(nodes: &[String], index: String, schema: Schema) ->
     Ok(clients)
 }
 
-pub async fn delete_term(catalog: SharedCatalog, body: Body, index: String) -> ResponseFuture {
+pub async fn delete_term(catalog: SharedCatalog, body: Body, index: String, options: QueryOptions) -> ResponseFuture {
     let cat = catalog;
     let agg_body = aggregate(body).await?;
     let b = agg_body.bytes();
@@ -57,7 +99,16 @@ pub async fn delete_term(catalog: SharedCatalog, body: Body, index: String) -> R
         Ok(v) => v,
         Err(_e) => return Ok(empty_with_code(hyper::StatusCode::BAD_REQUEST)),
     };
-    let docs_affected = match delete_terms(cat, req, &index).await {
+    let shard = match options.routing() {
+        Some(routing) => cat.routed_shard(&index, routing).await.ok(),
+        None => None,
+    };
+    let deleted = match shard {
+        Some(_) if options.dry_run() => Err(Error::QueryError("A dry run can't be routed to a remote shard".into())),
+        Some(shard) => shard.delete_term(req).await,
+        None => delete_terms(cat, req, &index, options.dry_run()).await,
+    };
+    let docs_affected = match deleted {
         Ok(v) => with_body(v),
         Err(e) => return Ok(Response::from(e)),
     };
@@ -65,52 +116,286 @@ pub async fn delete_term(catalog: SharedCatalog, body: Body, index: String) -> R
     Ok(docs_affected)
 }
 
-pub async fn create_index(catalog: SharedCatalog, body: Body, index: String) -> ResponseFuture {
+/// Set fields of every document the query in the body matches, see [`LocalIndex::update_by_query`].
+/// With `dry_run=true` nothing is changed and the response is how many documents would be.
+///
+/// [`LocalIndex::update_by_query`]: crate::handle::LocalIndex::update_by_query
+pub async fn update_by_query(catalog: SharedCatalog, body: Body, index: String, options: QueryOptions) -> ResponseFuture {
+    let b = aggregate(body).await?;
+    let req = match serde_json::from_slice::<UpdateByQuery>(b.bytes()) {
+        Ok(v) => v,
+        Err(e) => return Ok(Response::from(Error::from(e))),
+    };
+    let updated = match catalog.get_index(&index) {
+        Ok(handle) => handle.update_by_query(req, options.dry_run()).await,
+        Err(e) => Err(e),
+    };
+    match updated {
+        Ok(v) => Ok(with_body(v)),
+        Err(e) => Ok(Response::from(e)),
+    }
+}
+
+/// Have another shard of a sharded index hold one of its routing partitions, responding with the
+/// partitions and the shard holding each after the move
+pub async fn move_partition(catalog: SharedCatalog, body: Body, index: String, partition: String) -> ResponseFuture {
+    let b = aggregate(body).await?;
+    let req = match serde_json::from_slice::<MovePartition>(b.bytes()) {
+        Ok(v) => v,
+        Err(e) => return Ok(Response::from(Error::from(e))),
+    };
+    let moved = match partition.parse::<usize>() {
+        Ok(partition) => catalog.move_partition(&index, partition, req.shard).await,
+        Err(_) => Err(Error::QueryError(format!("Invalid partition number '{}'", partition))),
+    };
+    match moved {
+        Ok(table) => Ok(with_body(table)),
+        Err(e) => Ok(Response::from(e)),
+    }
+}
+
+pub async fn create_index(catalog: SharedCatalog, body: Body, index: String, options: QueryOptions) -> ResponseFuture {
     let b = aggregate(body).await?;
-    let req = serde_json::from_slice::<SchemaBody>(&b.bytes()).unwrap();
-    {
-        let base_path = catalog.lock().await.base_path().clone();
-        let new_index: Index = match IndexCatalog::create_from_managed(base_path, &index, req.0.clone()) {
+    let req = match serde_json::from_slice::<SchemaBody>(&b.bytes()) {
+        Ok(v) => v,
+        Err(e) => return Ok(Response::from(Error::from(e))),
+    };
+    // The schema only knows bool fields as u64 fields and has no norms to turn off, pre-tokenized
+    // fields or limits on how long an indexed value can be, so those are kept with the index
+    let fields = serde_json::from_slice::<Vec<FieldSpec>>(b.bytes()).unwrap_or_default();
+    let req = match catalog.settings.get_default_text_analyzer(&index) {
+        Some(analyzer) => match SchemaBody::from_fields(&fields, Some(analyzer)) {
+            Ok(v) => v,
+            Err(e) => return Ok(Response::from(Error::QueryError(e))),
+        },
+        None => req,
+    };
+    let bools: Vec<String> = fields.iter().filter(|f| f.kind == "bool").map(|f| f.name.clone()).collect();
+    let pre_tokenized: Vec<String> = fields.iter().filter(|f| f.is_pre_tokenized()).map(|f| f.name.clone()).collect();
+    let ignore_above: HashMap<String, usize> = fields.iter().filter_map(FieldSpec::ignore_above).collect();
+    let without_norms: Vec<String> = fields
+        .iter()
+        .filter(|f| f.options.norms == Some(false))
+        .map(|f| f.name.clone())
+        .collect();
+    if let Err(e) = check_compression(&catalog, &index, options.compression.as_deref()) {
+        return Ok(Response::from(e));
+    }
+    if let Ok(existing) = catalog.get_index(&index) {
+        let current = match existing.without_norms() {
             Ok(v) => v,
             Err(e) => return Ok(Response::from(e)),
         };
-        match add_index(Arc::clone(&catalog), index.clone(), new_index).await {
-            Ok(_) => (),
-            Err(e) => return Ok(Response::from(e)),
-        };
+        let changed = fields.iter().find(|f| current.contains(&f.name) != without_norms.contains(&f.name));
+        if let Some(field) = changed {
+            let e = Error::QueryError(format!(
+                "Norms of field '{}' of index '{}' can't be changed in place, create a new index and reindex into it",
+                field.name, index
+            ));
+            return Ok(Response::from(e));
+        }
+    }
+    let created = catalog.create_local_index(&index, req.0.clone(), |i| {
+        if !bools.is_empty() {
+            i.set_bool_fields(&bools)?;
+        }
+        if !without_norms.is_empty() {
+            i.set_without_norms(&without_norms)?;
+        }
+        if !pre_tokenized.is_empty() {
+            i.set_pre_tokenized(&pre_tokenized)?;
+        }
+        if !ignore_above.is_empty() {
+            i.set_ignore_above(&ignore_above)?;
+        }
+        if let Some(partitions) = options.routing_partitions {
+            i.save_partitions(&PartitionTable::new(partitions, 1))?;
+        }
+        i.mark_created()
+    });
+    if let Err(e) = created.await {
+        return Ok(Response::from(e));
     }
 
-    let expir = catalog.lock().await.settings.experimental;
+    let expir = catalog.settings.experimental;
     if expir {
-        let nodes = &catalog.lock().await.settings.get_nodes();
-        let clients = create_remote_index(&nodes, index.clone(), req.0).await.unwrap();
-        add_remote_index(catalog, index, clients).await.expect("Could not create index.");
-        Ok(empty_with_code(StatusCode::CREATED))
+        let nodes = catalog.node_loads().await;
+        let routing = match catalog.plan_index(&index, options.replicas(), &nodes) {
+            Ok(v) => v,
+            Err(e) => return Ok(Response::from(e)),
+        };
+        let placed = match create_remote_index(&routing.nodes(), index.clone(), req.0).await {
+            Ok(clients) => add_remote_index(&catalog, index, &routing, clients).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = placed {
+            return Ok(Response::from(e));
+        }
+        let mut resp = empty_with_code(StatusCode::CREATED);
+        if routing.health() == IndexHealth::Yellow {
+            let warning = format!("199 toshi \"index is yellow, {} replicas are unassigned\"", routing.unassigned);
+            resp.headers_mut().insert(hyper::header::WARNING, warning.parse().unwrap());
+        }
+        Ok(resp)
     } else {
         Ok(empty_with_code(StatusCode::CREATED))
     }
 }
 
-pub async fn add_document(catalog: SharedCatalog, body: Body, index: String) -> ResponseFuture {
-    let cat_clone = catalog;
+/// Check the stored field compression `codec` asked for when creating `index` can be used. Tantivy
+/// picks the codec when it's built, so only the one Toshi was built with can be used, and an index
+/// keeps the codec it was created with.
+fn check_compression(catalog: &SharedCatalog, index: &str, codec: Option<&str>) -> Result<(), Error> {
+    let codec = match codec {
+        Some(c) => c,
+        None => return Ok(()),
+    };
+    if !["snappy", "lz4"].contains(&codec) {
+        return Err(Error::QueryError(format!("Unknown compression '{}', use snappy or lz4", codec)));
+    }
+    if let Ok(existing) = catalog.get_index(index) {
+        let current = existing.compression()?.unwrap_or_else(|| COMPRESSION.into());
+        if current != codec {
+            return Err(Error::QueryError(format!(
+                "Index '{}' is compressed with {}, its compression can't be changed in place, create a new index and reindex into it",
+                index, current
+            )));
+        }
+    }
+    if codec != COMPRESSION {
+        return Err(Error::QueryError(format!(
+            "Compression {} isn't available, Toshi was built to compress stored fields with {}",
+            codec, COMPRESSION
+        )));
+    }
+    Ok(())
+}
+
+/// Create `index` with the fields [`mapping::infer_fields`] infers from the example document in
+/// `body`, responding with the type each field was given
+pub async fn create_index_from_sample(catalog: SharedCatalog, body: Body, index: String, options: QueryOptions) -> ResponseFuture {
+    let b = aggregate(body).await?;
+    let sample = match serde_json::from_slice::<serde_json::Value>(b.bytes()) {
+        Ok(v) => v,
+        Err(e) => return Ok(Response::from(Error::from(e))),
+    };
+    let (fields, warnings) = match mapping::infer_fields(&sample) {
+        Ok(v) => v,
+        Err(e) => return Ok(Response::from(e)),
+    };
+    for warning in &warnings {
+        tracing::warn!("Creating index {} from a sample: {}", index, warning);
+    }
+    let types = fields
+        .iter()
+        .filter_map(|f| Some((f["name"].as_str()?.to_string(), f["type"].as_str()?.to_string())))
+        .collect();
+    let schema = serde_json::Value::Array(fields).to_string();
+    let resp = create_index(catalog, Body::from(schema), index, options).await?;
+    if resp.status() != StatusCode::CREATED {
+        return Ok(resp);
+    }
+    let mut created = with_body(InferredSchema { fields: types, warnings });
+    *created.status_mut() = StatusCode::CREATED;
+    Ok(created)
+}
+
+pub async fn add_document(catalog: SharedCatalog, body: Body, index: String, options: QueryOptions) -> ResponseFuture {
+    let cat = catalog;
     let full_body = aggregate(body).await?;
     let b = full_body.bytes();
-    let req = serde_json::from_slice::<AddDocument>(&b).unwrap();
-    let cat = cat_clone.lock().await;
-    let location: bool = random();
-    tracing::info!("LOCATION = {}", location);
-    if location && cat.remote_exists(&index).await {
-        tracing::info!("Pushing to remote...");
-        let add = cat.add_remote_document(&index, req).await;
-
-        add.map(|_| empty_with_code(StatusCode::CREATED))
-            .or_else(|e| Ok(error_response(StatusCode::BAD_REQUEST, e)))
+    let req = match serde_json::from_slice::<AddDocument>(&b) {
+        Ok(v) => v,
+        Err(e) => return Ok(Response::from(Error::from(e))),
+    };
+    let shard = match options.routing() {
+        Some(routing) => cat.routed_shard(&index, routing).await.ok(),
+        None => None,
+    };
+    let add = match (cat.write_node(&index), shard) {
+        // A routing value pins the document to one shard, only reads with the same value will find it
+        (_, Some(shard)) => {
+            tracing::info!("Pushing to the shard routed to...");
+            shard.add_document(req).await
+        }
+        // In a cluster writes always go to wherever the index's primary currently is, then out to its replicas
+        (Ok(node), _) if cat.settings.experimental => {
+            let doc = req.clone();
+            let primary = if node != cat.settings.place_addr {
+                tracing::info!("Pushing to primary on {}...", node);
+                cat.add_node_document(&node, &index, req).await
+            } else {
+                cat.add_local_document(&index, req).await
+            };
+            match primary {
+                Ok(_) => replicate(&cat, &index, vec![doc], options.consistency()).await.map(|_| ()),
+                Err(e) => Err(e),
+            }
+        }
+        _ => {
+            let location: bool = random();
+            tracing::info!("LOCATION = {}", location);
+            if location && cat.remote_exists(&index).await {
+                tracing::info!("Pushing to remote...");
+                cat.add_remote_document(&index, req).await
+            } else {
+                tracing::info!("Pushing to local...");
+                cat.add_local_document(&index, req).await
+            }
+        }
+    };
+
+    add.map(|_| empty_with_code(StatusCode::CREATED)).or_else(|e| Ok(Response::from(e)))
+}
+
+/// Copy every stored document of the index named in the body into `index`. With `async=true` the
+/// copy runs as a background task and the response is the task's id, with `dry_run=true` nothing
+/// is copied and the response is how many documents would be.
+pub async fn reindex(catalog: SharedCatalog, body: Body, index: String, options: QueryOptions) -> ResponseFuture {
+    let b = aggregate(body).await?;
+    let req = match serde_json::from_slice::<ReindexRequest>(b.bytes()) {
+        Ok(v) => v,
+        Err(e) => return Ok(Response::from(Error::from(e))),
+    };
+    let (source, dest) = match (catalog.get_index(&req.source), catalog.get_index(&index)) {
+        (Ok(source), Ok(dest)) => (source, dest),
+        (Err(e), _) | (_, Err(e)) => return Ok(Response::from(e)),
+    };
+    // Checked before a background copy starts, so a bad rate fails the request rather than the task
+    if let Err(e) = dest.check_writable().and_then(|_| reindex::check_rate(req.requests_per_second)) {
+        return Ok(Response::from(e));
+    }
+    if options.dry_run() {
+        let reindexed = source.searcher().num_docs();
+        return Ok(with_body(ReindexResponse { reindexed }));
+    }
+
+    if options.run_async() {
+        let started = catalog.tasks().spawn("reindex", |progress| async move {
+            reindex::reindex(source, dest, req.requests_per_second, progress).await.map(|_| ())
+        });
+        match started {
+            Ok(task) => {
+                let mut resp = with_body(TaskStarted { task });
+                *resp.status_mut() = StatusCode::ACCEPTED;
+                Ok(resp)
+            }
+            Err(e) => Ok(Response::from(e)),
+        }
     } else {
-        tracing::info!("Pushing to local...");
-        let add = cat.add_local_document(&index, req).await;
+        match reindex::reindex(source, dest, req.requests_per_second, Arc::new(TaskProgress::default())).await {
+            Ok(reindexed) => Ok(with_body(ReindexResponse { reindexed })),
+            Err(e) => Ok(Response::from(e)),
+        }
+    }
+}
 
-        add.map(|_| empty_with_code(StatusCode::CREATED))
-            .or_else(|e| Ok(error_response(StatusCode::BAD_REQUEST, e)))
+/// Copy the last commit of `source` into a new index called `dest`, to try changes on without
+/// touching the original. Nothing written to either index afterwards shows up in the other.
+pub async fn clone_index(catalog: SharedCatalog, source: String, dest: String) -> ResponseFuture {
+    match catalog.clone_index(&source, &dest).await {
+        Ok(()) => Ok(empty_with_code(StatusCode::CREATED)),
+        Err(e) => Ok(Response::from(e)),
     }
 }
 
@@ -128,6 +413,7 @@ mod tests {
 
     use crate::handlers::all_docs;
     use crate::index::tests::*;
+    use crate::settings::{DirectoryKind, IngestionBreaker, UnknownFields};
 
     use super::*;
 
@@ -146,8 +432,13 @@ mod tests {
          ]"#;
         let mut rt: Runtime = tokio::runtime::Runtime::new().unwrap();
 
-        rt.block_on(create_index(Arc::clone(&shared_cat), Body::from(schema), "new_index".into()))
-            .unwrap();
+        rt.block_on(create_index(
+            Arc::clone(&shared_cat),
+            Body::from(schema),
+            "new_index".into(),
+            QueryOptions::default(),
+        ))
+        .unwrap();
 
         let docs = async {
             let resp = all_docs(Arc::clone(&shared_cat), "new_index".into()).await.unwrap();
@@ -159,12 +450,705 @@ mod tests {
         remove_dir_all::remove_dir_all("new_index").unwrap();
     }
 
+    #[tokio::test]
+    async fn test_create_ram_index() -> Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = DirectoryKind::Ram;
+        let shared_cat = Arc::new(catalog);
+        let schema = r#"[{ "name": "test_text", "type": "text", "options": { "indexing": { "record": "position", "tokenizer": "default" }, "stored": true } }]"#;
+
+        let resp = create_index(
+            Arc::clone(&shared_cat),
+            Body::from(schema),
+            "ram_index".into(),
+            QueryOptions::default(),
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert!(!std::path::Path::new("ram_index").exists());
+
+        let doc = r#"{"options": {"commit": true }, "document": {"test_text": "Babbaboo!"} }"#;
+        add_document(
+            Arc::clone(&shared_cat),
+            Body::from(doc),
+            "ram_index".into(),
+            QueryOptions::default(),
+        )
+        .await?;
+        let resp = all_docs(Arc::clone(&shared_cat), "ram_index".into()).await?;
+        let b = wait_json::<crate::SearchResults>(resp).await;
+        assert_eq!(b.hits, 1);
+        assert!(!std::path::Path::new("ram_index").exists());
+        Ok(())
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_concurrent_create() -> Result<(), Box<dyn std::error::Error>> {
+        let base = std::env::temp_dir().join(format!("toshi-create-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&base)?;
+        let catalog = Arc::new(IndexCatalog::with_path(base.clone())?);
+        let schema = r#"[{ "name": "test_text", "type": "text", "options": { "stored": true, "indexed": true } }]"#;
+
+        let creates: Vec<_> = (0..2)
+            .map(|_| {
+                tokio::spawn(create_index(
+                    Arc::clone(&catalog),
+                    Body::from(schema),
+                    "racing".into(),
+                    QueryOptions::default(),
+                ))
+            })
+            .collect();
+        let mut statuses = Vec::new();
+        for create in creates {
+            statuses.push(create.await??.status());
+        }
+        statuses.sort();
+        assert_eq!(statuses, vec![StatusCode::CREATED, StatusCode::CONFLICT]);
+
+        // The index the winner created is whole and takes documents
+        let doc = r#"{"options": {"commit": true }, "document": {"test_text": "first"} }"#;
+        let resp = add_document(Arc::clone(&catalog), Body::from(doc), "racing".into(), QueryOptions::default()).await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp = all_docs(Arc::clone(&catalog), "racing".into()).await?;
+        assert_eq!(wait_json::<crate::SearchResults>(resp).await.hits, 1);
+        drop(catalog);
+        std::fs::remove_dir_all(&base)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_create_leaves_nothing() -> Result<(), Box<dyn std::error::Error>> {
+        let base = std::env::temp_dir().join(format!("toshi-create-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&base)?;
+        let catalog = IndexCatalog::with_path(base.clone())?;
+        let mut builder = SchemaBuilder::new();
+        builder.add_text_field("test_text", TEXT | STORED);
+        let schema = builder.build();
+
+        let failed = catalog
+            .create_local_index("broken", schema.clone(), |_| Err(Error::QueryError("bad field".into())))
+            .await;
+        assert!(matches!(failed, Err(Error::QueryError(_))));
+        assert!(!catalog.exists("broken"));
+        assert!(!base.join("broken").exists());
+
+        // Nothing is in the way of creating it again
+        catalog.create_local_index("broken", schema, |i| i.mark_created()).await?;
+        assert!(catalog.exists("broken"));
+        drop(catalog);
+        std::fs::remove_dir_all(&base)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_disabled_norms() -> Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = DirectoryKind::Ram;
+        let cat = Arc::new(catalog);
+        let schema = |norms: bool| {
+            format!(
+                r#"[{{ "name": "tag", "type": "text", "options": {{ "stored": true, "norms": {} }} }},
+                    {{ "name": "body", "type": "text", "options": {{ "stored": true, "indexed": true }} }}]"#,
+                norms
+            )
+        };
+        let resp = create_index(Arc::clone(&cat), Body::from(schema(false)), "exact".into(), QueryOptions::default()).await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        for text in &["red", "red blue green yellow purple"] {
+            let doc = format!(
+                r#"{{ "options": {{ "commit": true }}, "document": {{ "tag": "{0}", "body": "{0}" }} }}"#,
+                text
+            );
+            add_document(Arc::clone(&cat), Body::from(doc), "exact".into(), QueryOptions::default()).await?;
+        }
+
+        let scores = |field: &str| {
+            let search = format!(r#"{{ "query": {{ "raw": "{}:red" }} }}"#, field);
+            let cat = Arc::clone(&cat);
+            async move {
+                let resp = crate::handlers::doc_search(cat, Body::from(search), "exact".into(), QueryOptions::default()).await?;
+                let results: crate::SearchResults = wait_json(resp).await;
+                Ok::<_, hyper::Error>(results.docs.iter().map(|d| d.score.unwrap()).collect::<Vec<_>>())
+            }
+        };
+        let tag = scores("tag").await?;
+        assert_eq!(tag.len(), 2);
+        assert!((tag[0] - tag[1]).abs() < 1e-6);
+        let body = scores("body").await?;
+        assert!(body[0] > body[1]);
+
+        let resp = create_index(Arc::clone(&cat), Body::from(schema(true)), "exact".into(), QueryOptions::default()).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: toshi_types::ErrorResponse = wait_json(resp).await;
+        assert!(body.message.contains("reindex"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stored_compression() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::handle::{LocalIndex, METADATA_FILE};
+        use tantivy::directory::Directory;
+
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = DirectoryKind::Ram;
+        let cat = Arc::new(catalog);
+        let schema = r#"[{ "name": "body", "type": "text", "options": { "stored": true, "indexed": true } }]"#;
+        let other = if COMPRESSION == "lz4" { "snappy" } else { "lz4" };
+        let create = |name: &'static str, codec: &str| {
+            let options = QueryOptions {
+                compression: Some(codec.into()),
+                ..QueryOptions::default()
+            };
+            create_index(Arc::clone(&cat), Body::from(schema), name.into(), options)
+        };
+
+        assert_eq!(create("packed", COMPRESSION).await?.status(), StatusCode::CREATED);
+        let doc = r#"{ "options": { "commit": true }, "document": { "body": "squeezed in" } }"#;
+        add_document(Arc::clone(&cat), Body::from(doc), "packed".into(), QueryOptions::default()).await?;
+        let resp = all_docs(Arc::clone(&cat), "packed".into()).await?;
+        let results: crate::SearchResults = wait_json(resp).await;
+        assert_eq!(results.docs[0].doc["body"][0].text(), Some("squeezed in"));
+        assert_eq!(cat.get_index("packed")?.compression()?.as_deref(), Some(COMPRESSION));
+
+        // Only the codec Toshi was built with can be used, and an index keeps the one it has
+        let unavailable = create("elsewhere", other).await?;
+        assert_eq!(unavailable.status(), StatusCode::BAD_REQUEST);
+        assert!(!cat.exists("elsewhere"));
+        let changed: toshi_types::ErrorResponse = wait_json(create("packed", other).await?).await;
+        assert!(changed.message.contains("reindex"));
+        assert_eq!(create("unknown", "zstd").await?.status(), StatusCode::BAD_REQUEST);
+
+        // An index written with another codec is refused rather than read with the wrong one
+        let index = toshi_test::create_test_index();
+        let metadata = format!(r#"{{ "compression": "{}" }}"#, other);
+        index
+            .directory()
+            .clone()
+            .atomic_write(std::path::Path::new(METADATA_FILE), metadata.as_bytes())?;
+        assert!(LocalIndex::new(index, Default::default(), "foreign").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_default_text_analyzer() -> Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = DirectoryKind::Ram;
+        catalog.settings.default_text_analyzer.insert("stemmed".into(), "en_stem".into());
+        let cat = Arc::new(catalog);
+        let schema = r#"[
+            { "name": "body", "type": "text", "options": { "record": "position", "stored": true } },
+            { "name": "title", "type": "text", "options": { "tokenizer": "default", "stored": true } }
+        ]"#;
+        let resp = create_index(Arc::clone(&cat), Body::from(schema), "stemmed".into(), QueryOptions::default()).await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let doc = r#"{ "options": { "commit": true }, "document": { "body": "running dogs", "title": "running dogs" } }"#;
+        add_document(Arc::clone(&cat), Body::from(doc), "stemmed".into(), QueryOptions::default()).await?;
+
+        for (query, hits) in &[(r#"{"body": "run"}"#, 1), (r#"{"body": "dog"}"#, 1), (r#"{"title": "run"}"#, 0)] {
+            let search = format!(r#"{{ "query": {{ "term": {} }} }}"#, query);
+            let resp = crate::handlers::doc_search(Arc::clone(&cat), Body::from(search), "stemmed".into(), QueryOptions::default()).await?;
+            assert_eq!(wait_json::<crate::SearchResults>(resp).await.hits, *hits, "{}", query);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_index_from_sample() -> Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = DirectoryKind::Ram;
+        let cat = Arc::new(catalog);
+        let sample = r#"{
+            "title": "Toshi", "views": 12, "rating": 4.5, "published": "2020-01-02T03:04:05Z",
+            "draft": false, "tags": ["search", "rust"], "notes": null
+        }"#;
+        let resp = create_index_from_sample(Arc::clone(&cat), Body::from(sample), "sampled".into(), QueryOptions::default()).await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let inferred: InferredSchema = wait_json(resp).await;
+        assert_eq!(inferred.fields["draft"], "bool");
+        assert_eq!(inferred.warnings.len(), 1);
+        assert!(inferred.warnings[0].contains("notes"));
+
+        let schema = cat.get_index("sampled")?.get_index().schema();
+        let kind = |name: &str| schema.get_field_entry(schema.get_field(name).unwrap()).field_type().value_type();
+        assert_eq!(kind("title"), Type::Str);
+        assert_eq!(kind("tags"), Type::Str);
+        assert_eq!(kind("notes"), Type::Str);
+        assert_eq!(kind("views"), Type::I64);
+        assert_eq!(kind("rating"), Type::F64);
+        assert_eq!(kind("published"), Type::Date);
+        assert_eq!(kind("draft"), Type::U64);
+
+        let doc = r#"{ "options": { "commit": true }, "document": { "title": "Toshi", "views": 3, "rating": 1.5, "published": "2021-01-01T00:00:00Z", "draft": true, "tags": ["a"], "notes": "n" } }"#;
+        let resp = add_document(Arc::clone(&cat), Body::from(doc), "sampled".into(), QueryOptions::default()).await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let resp = create_index_from_sample(Arc::clone(&cat), Body::from("[1, 2]"), "listed".into(), QueryOptions::default()).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_clone_index() -> Result<(), Box<dyn std::error::Error>> {
+        let base = std::env::temp_dir().join(format!("toshi-clone-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&base)?;
+        let cat = Arc::new(IndexCatalog::new(base.clone(), crate::settings::Settings::default())?);
+        let schema = r#"[{ "name": "name", "type": "text", "options": { "stored": true, "indexed": true } },
+                         { "name": "good", "type": "bool", "options": { "stored": true } }]"#;
+        create_index(Arc::clone(&cat), Body::from(schema), "dogs".into(), QueryOptions::default()).await?;
+        let add = |index: &'static str, name: &str| {
+            let doc = format!(
+                r#"{{ "options": {{ "commit": true }}, "document": {{ "name": "{}", "good": true }} }}"#,
+                name
+            );
+            add_document(Arc::clone(&cat), Body::from(doc), index.into(), QueryOptions::default())
+        };
+        add("dogs", "shiba").await?;
+        add("dogs", "akita").await?;
+        cat.get_index("dogs")?.set_read_only(true)?;
+
+        let resp = clone_index(Arc::clone(&cat), "dogs".into(), "dogs_copy".into()).await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert!(base.join("dogs_copy").join("meta.json").exists());
+        let copy = cat.get_index("dogs_copy")?;
+        assert!(!copy.is_read_only());
+        cat.get_index("dogs")?.set_read_only(false)?;
+
+        add("dogs_copy", "corgi").await?;
+        let delete: DeleteDoc = serde_json::from_str(r#"{ "options": { "commit": true }, "terms": { "name": "shiba" } }"#)?;
+        cat.get_index("dogs")?.delete_term(delete).await?;
+
+        let names = |index: &'static str| {
+            let cat = Arc::clone(&cat);
+            async move {
+                let resp = all_docs(cat, index.into()).await?;
+                let results: crate::TypedSearchResults = wait_json(resp).await;
+                let mut names: Vec<String> = results
+                    .docs
+                    .iter()
+                    .map(|d| d.doc["name"][0].as_str().unwrap().to_string())
+                    .collect();
+                names.sort();
+                assert!(results.docs.iter().all(|d| d.doc["good"][0] == serde_json::json!(true)));
+                Ok::<_, hyper::Error>(names)
+            }
+        };
+        let source = names("dogs").await?;
+        let copied = names("dogs_copy").await?;
+        let conflict = clone_index(Arc::clone(&cat), "dogs".into(), "dogs_copy".into()).await?;
+        let missing = clone_index(Arc::clone(&cat), "cats".into(), "cats_copy".into()).await?;
+        drop(cat);
+        remove_dir_all::remove_dir_all(&base)?;
+
+        assert_eq!(source, vec!["akita"]);
+        assert_eq!(copied, vec!["akita", "corgi", "shiba"]);
+        assert_eq!(conflict.status(), StatusCode::CONFLICT);
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_clone_ram_index() -> Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = DirectoryKind::Ram;
+        let cat = Arc::new(catalog);
+        let resp = clone_index(Arc::clone(&cat), "test_index".into(), "test_copy".into()).await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert!(!std::path::Path::new("test_copy").exists());
+
+        let doc = r#"{ "options": { "commit": true }, "document": { "test_text": "Babbaboo!", "test_u64": 10, "test_i64": -10, "test_unindex": "yes" } }"#;
+        add_document(Arc::clone(&cat), Body::from(doc), "test_copy".into(), QueryOptions::default()).await?;
+        let copy = all_docs(Arc::clone(&cat), "test_copy".into()).await?;
+        assert_eq!(wait_json::<crate::SearchResults>(copy).await.hits, 6);
+        let source = all_docs(Arc::clone(&cat), "test_index".into()).await?;
+        assert_eq!(wait_json::<crate::SearchResults>(source).await.hits, 5);
+        Ok(())
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_routed_document() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::cluster::routing::HashRing;
+        use crate::cluster::rpc_server::tests::spawn_node;
+
+        let shards = vec![create_test_catalog("test_index"), create_test_catalog("test_index")];
+        let mut clients = Vec::new();
+        for shard in &shards {
+            let (uri, client) = spawn_node(Arc::clone(shard)).await?;
+            clients.push((uri.authority().unwrap().to_string(), client));
+        }
+        let nodes: Vec<String> = clients.iter().map(|(node, _)| node.clone()).collect();
+        let cat = create_test_catalog("test_index");
+        cat.add_multi_remote_index(test_index(), clients).await?;
+
+        let routed = |routing: &str| QueryOptions {
+            routing: Some(routing.into()),
+            ..Default::default()
+        };
+        let doc = r#"{"options": {"commit": true }, "document": {"test_text": "routed", "test_u64": 10, "test_i64": -10, "test_unindex": "yes"} }"#;
+        let resp = add_document(Arc::clone(&cat), Body::from(doc), test_index(), routed("user-1")).await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let hits = |cat: SharedCatalog, options: QueryOptions| async move {
+            cat.get_index("test_index").and_then(|i| i.reload()).ok();
+            let resp = crate::handlers::doc_search(cat, Body::from("{}"), test_index(), options).await?;
+            Ok::<_, hyper::Error>(wait_json::<crate::SearchResults>(resp).await.hits)
+        };
+        // Only the shard the routing value hashes to holds the document
+        let ring = HashRing::new(&nodes, cat.settings.experimental_features.virtual_nodes);
+        let owner = ring.shard_for("user-1").unwrap();
+        assert_eq!(hits(Arc::clone(&shards[owner]), QueryOptions::default()).await?, 6);
+        assert_eq!(hits(Arc::clone(&shards[1 - owner]), QueryOptions::default()).await?, 5);
+        assert_eq!(hits(Arc::clone(&cat), QueryOptions::default()).await?, 5);
+
+        assert_eq!(hits(Arc::clone(&cat), routed("user-1")).await?, 6);
+        let other = (0..)
+            .map(|i| format!("user-{}", i))
+            .find(|r| ring.shard_for(r) != Some(owner))
+            .unwrap();
+        assert_eq!(hits(Arc::clone(&cat), routed(&other)).await?, 5);
+
+        let delete = r#"{ "options": { "commit": true }, "terms": { "test_text": "routed" } }"#;
+        let resp = delete_term(Arc::clone(&cat), Body::from(delete), test_index(), routed("user-1")).await?;
+        assert_eq!(wait_json::<DocsAffected>(resp).await.docs_affected, 1);
+        Ok(())
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_move_partition() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::cluster::rpc_server::tests::spawn_node;
+
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            let (uri, client) = spawn_node(create_test_catalog("test_index")).await?;
+            clients.push((uri.authority().unwrap().to_string(), client));
+        }
+        let cat = create_test_catalog("test_index");
+        cat.get_index("test_index")?.save_partitions(&PartitionTable::new(12, 1))?;
+        cat.add_multi_remote_index(test_index(), clients).await?;
+        // The partitions are dealt out over every shard once the index is spread over them
+        let saved = cat.get_index("test_index")?.saved_partitions()?.unwrap();
+        assert_eq!(saved.shards(), 3);
+
+        let moved = r#"{ "shard": 2 }"#;
+        let resp = move_partition(Arc::clone(&cat), Body::from(moved), test_index(), "0".into()).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let table = wait_json::<PartitionTable>(resp).await;
+        assert_eq!(table.shard_of(0), Some(2));
+        assert_eq!(cat.get_index("test_index")?.saved_partitions()?, Some(table));
+
+        for (partition, body) in &[("12", moved), ("0", r#"{ "shard": 3 }"#), ("first", moved)] {
+            let resp = move_partition(Arc::clone(&cat), Body::from(*body), test_index(), (*partition).into()).await?;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+        let resp = move_partition(Arc::clone(&cat), Body::from(moved), "missing".into(), "0".into()).await?;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ingestion_breaker() -> Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.ingestion_breaker = IngestionBreaker {
+            high_water: 3,
+            low_water: 0,
+        };
+        catalog.add_index("test_index".into(), toshi_test::create_test_index())?;
+        let cat = Arc::new(catalog);
+
+        let add = |cat: SharedCatalog| async move {
+            let doc = r#"{"document": {"test_text": "flood", "test_u64": 10, "test_i64": -10}}"#;
+            add_document(cat, Body::from(doc), test_index(), QueryOptions::default()).await
+        };
+        for _ in 0..3 {
+            assert_eq!(add(Arc::clone(&cat)).await?.status(), StatusCode::CREATED);
+        }
+        let tripped = add(Arc::clone(&cat)).await?;
+        assert_eq!(tripped.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: toshi_types::ErrorResponse = wait_json(tripped).await;
+        assert_eq!(body.code, Some(toshi_types::ErrorCode::IngestionBreaker));
+        assert_eq!(add(Arc::clone(&cat)).await?.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // A commit drains the writes waiting on it and the breaker lets writes through again
+        crate::handlers::flush(Arc::clone(&cat), test_index()).await?;
+        assert_eq!(add(Arc::clone(&cat)).await?.status(), StatusCode::CREATED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_add_mismatched_document() -> Result<(), Box<dyn std::error::Error>> {
+        let shared_cat = create_test_catalog("test_index");
+        let doc = r#"{"document": {"test_text": "Babbaboo!", "test_i64": "not a number"} }"#;
+        let resp = add_document(Arc::clone(&shared_cat), Body::from(doc), test_index(), QueryOptions::default()).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: toshi_types::ErrorResponse = wait_json(resp).await;
+        assert_eq!(body.message, r#"Field 'test_i64' expects a i64 value but got "not a number""#);
+
+        let doc = r#"{"document": {"test_u64": [1, -2]} }"#;
+        let resp = add_document(Arc::clone(&shared_cat), Body::from(doc), test_index(), QueryOptions::default()).await?;
+        let body: toshi_types::ErrorResponse = wait_json(resp).await;
+        assert_eq!(body.message, "Field 'test_u64' expects a u64 value but got -2");
+
+        // The writer is still usable after rejecting the documents
+        let doc = r#"{"options": {"commit": true }, "document": {"test_text": "Babbaboo!", "test_i64": 10} }"#;
+        let resp = add_document(Arc::clone(&shared_cat), Body::from(doc), test_index(), QueryOptions::default()).await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stored_only_field() -> Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = DirectoryKind::Ram;
+        let shared_cat = Arc::new(catalog);
+
+        let schema = r#"[{ "name": "nothing", "type": "text", "options": { "stored": false, "indexed": false } }]"#;
+        let resp = create_index(
+            Arc::clone(&shared_cat),
+            Body::from(schema),
+            "bad_index".into(),
+            QueryOptions::default(),
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(!shared_cat.exists("bad_index"));
+
+        let schema = r#"[
+            { "name": "title", "type": "text", "options": { "stored": true, "record": "basic" } },
+            { "name": "secret", "type": "text", "options": { "stored": true } }
+        ]"#;
+        let resp = create_index(
+            Arc::clone(&shared_cat),
+            Body::from(schema),
+            "stored_index".into(),
+            QueryOptions::default(),
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let doc = r#"{"options": {"commit": true }, "document": {"title": "toshi", "secret": "shiba"} }"#;
+        add_document(
+            Arc::clone(&shared_cat),
+            Body::from(doc),
+            "stored_index".into(),
+            QueryOptions::default(),
+        )
+        .await?;
+        let resp = all_docs(Arc::clone(&shared_cat), "stored_index".into()).await?;
+        let b = wait_json::<crate::SearchResults>(resp).await;
+        assert_eq!(b.hits, 1);
+        assert_eq!(b.docs[0].doc["secret"][0].text(), Some("shiba"));
+
+        let index = shared_cat.get_index("stored_index")?;
+        let found = index
+            .search_index(serde_json::from_str(r#"{ "query": { "term": { "title": "toshi" } } }"#)?)
+            .await?;
+        assert_eq!(found.hits, 1);
+        let not_indexed = index
+            .search_index(serde_json::from_str(r#"{ "query": { "term": { "secret": "shiba" } } }"#)?)
+            .await;
+        assert_eq!(
+            not_indexed.unwrap_err().to_string(),
+            "Error in query execution: 'Field secret is not indexed'"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_keyword_field() -> Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = DirectoryKind::Ram;
+        let shared_cat = Arc::new(catalog);
+
+        let schema = r#"[{ "name": "title", "type": "text", "options": { "stored": true, "indexed": true, "keyword": true } }]"#;
+        let resp = create_index(
+            Arc::clone(&shared_cat),
+            Body::from(schema),
+            "keyword_index".into(),
+            QueryOptions::default(),
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let doc = r#"{"options": {"commit": true }, "document": {"title": "Hello World"} }"#;
+        add_document(
+            Arc::clone(&shared_cat),
+            Body::from(doc),
+            "keyword_index".into(),
+            QueryOptions::default(),
+        )
+        .await?;
+
+        let index = shared_cat.get_index("keyword_index")?;
+        for (term, hits) in &[
+            (r#"{"title.keyword": "Hello World"}"#, 1),
+            (r#"{"title": "world"}"#, 1),
+            (r#"{"title.keyword": "world"}"#, 0),
+        ] {
+            let search = format!(r#"{{ "query": {{ "term": {} }} }}"#, term);
+            let found = index.search_index(serde_json::from_str(&search)?).await?;
+            assert_eq!(found.hits, *hits, "{}", term);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pre_tokenized_field() -> Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = DirectoryKind::Ram;
+        let shared_cat = Arc::new(catalog);
+
+        let schema = r#"[{ "name": "place", "type": "text", "options": { "stored": true, "pre_tokenized": true } }]"#;
+        let resp = create_index(
+            Arc::clone(&shared_cat),
+            Body::from(schema),
+            "tokens".into(),
+            QueryOptions::default(),
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        // Tokens are indexed as they are given, uppercase and with a gap where a stop word was removed
+        let doc = r#"{"options": {"commit": true }, "document": {"place": [
+            { "text": "New", "position": 0 }, { "text": "York", "position": 1 }, { "text": "City", "position": 3 }
+        ]}}"#;
+        let resp = add_document(Arc::clone(&shared_cat), Body::from(doc), "tokens".into(), QueryOptions::default()).await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let index = shared_cat.get_index("tokens")?;
+        for (query, hits) in &[
+            (r#"{ "phrase": { "place": { "terms": ["New", "York"] } } }"#, 1),
+            (r#"{ "phrase": { "place": { "terms": ["new", "york"] } } }"#, 0),
+            (r#"{ "phrase": { "place": { "terms": ["York", "City"] } } }"#, 0),
+            (r#"{ "phrase": { "place": { "terms": ["York", "City"], "offsets": [0, 2] } } }"#, 1),
+        ] {
+            let search = format!(r#"{{ "query": {} }}"#, query);
+            let found = index.search_index(serde_json::from_str(&search)?).await?;
+            assert_eq!(found.hits, *hits, "{}", query);
+        }
+        let resp = all_docs(Arc::clone(&shared_cat), "tokens".into()).await?;
+        let stored: serde_json::Value = wait_json(resp).await;
+        assert_eq!(stored["docs"][0]["doc"]["place"][0], "New York City");
+
+        let text = r#"{"document": {"place": "New York City"}}"#;
+        let resp = add_document(Arc::clone(&shared_cat), Body::from(text), "tokens".into(), QueryOptions::default()).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ignore_above() -> Result<(), Box<dyn std::error::Error>> {
+        use serde_json::json;
+
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = DirectoryKind::Ram;
+        let shared_cat = Arc::new(catalog);
+
+        let schema = r#"[
+            { "name": "sku", "type": "text", "options": { "stored": true, "tokenizer": "raw", "ignore_above": 8 } },
+            { "name": "title", "type": "text", "options": { "stored": true, "keyword": true, "ignore_above": 8 } }
+        ]"#;
+        let resp = create_index(Arc::clone(&shared_cat), Body::from(schema), "skus".into(), QueryOptions::default()).await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let huge = "X".repeat(1_000);
+        for (sku, title) in &[("short-1", "Red shoe"), (huge.as_str(), "Very long red shoe title")] {
+            let doc = format!(
+                r#"{{"options": {{"commit": true }}, "document": {{"sku": ["{}", "other"], "title": "{}"}}}}"#,
+                sku, title
+            );
+            let resp = add_document(Arc::clone(&shared_cat), Body::from(doc), "skus".into(), QueryOptions::default()).await?;
+            assert_eq!(resp.status(), StatusCode::CREATED);
+        }
+
+        // The long values are kept but never became terms, the short ones next to them still did
+        let index = shared_cat.get_index("skus")?;
+        for (term, hits) in &[
+            (json!({ "sku": "short-1" }), 1),
+            (json!({ "sku": huge }), 0),
+            (json!({ "sku": "other" }), 2),
+            (json!({ "title.keyword": "Red shoe" }), 1),
+            (json!({ "title.keyword": "Very long red shoe title" }), 0),
+            (json!({ "title": "long" }), 1),
+        ] {
+            let search = json!({ "query": { "term": term } });
+            let found = index.search_index(serde_json::from_value(search)?).await?;
+            assert_eq!(found.hits, *hits, "{}", term);
+        }
+        let resp = all_docs(Arc::clone(&shared_cat), "skus".into()).await?;
+        let stored: serde_json::Value = wait_json(resp).await;
+        let skus: Vec<&serde_json::Value> = stored["docs"].as_array().unwrap().iter().map(|d| &d["doc"]["sku"][0]).collect();
+        assert!(skus.contains(&&json!(huge)));
+        assert_eq!(index.ignore_above()?["title.keyword"], 8);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unknown_fields() -> Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = DirectoryKind::Ram;
+        catalog.settings.unknown_fields.insert("rejecting".into(), UnknownFields::Reject);
+        catalog.settings.unknown_fields.insert("dynamic".into(), UnknownFields::Dynamic);
+        let shared_cat = Arc::new(catalog);
+
+        let schema = r#"[{ "name": "title", "type": "text", "options": { "stored": true, "indexed": true } }]"#;
+        let add = |index: &str, doc: &str| {
+            add_document(
+                Arc::clone(&shared_cat),
+                Body::from(doc.to_string()),
+                index.into(),
+                QueryOptions::default(),
+            )
+        };
+        let docs = |index: &str| {
+            let resp = all_docs(Arc::clone(&shared_cat), index.into());
+            async move { Ok::<_, hyper::Error>(wait_json::<crate::TypedSearchResults>(resp.await?).await) }
+        };
+        let extra = r#"{"options": {"commit": true }, "document": {"title": "toshi", "extra": "shiba", "count": -3, "flag": true} }"#;
+        for index in &["rejecting", "ignoring", "dynamic"] {
+            let resp = create_index(
+                Arc::clone(&shared_cat),
+                Body::from(schema),
+                index.to_string(),
+                QueryOptions::default(),
+            )
+            .await?;
+            assert_eq!(resp.status(), StatusCode::CREATED);
+        }
+
+        let resp = add("rejecting", extra).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: toshi_types::ErrorResponse = wait_json(resp).await;
+        assert!(body.message.contains("is not in the index's schema"), "{}", body.message);
+        assert_eq!(docs("rejecting").await?.hits, 0);
+
+        assert_eq!(add("ignoring", extra).await?.status(), StatusCode::CREATED);
+        let ignored = docs("ignoring").await?;
+        assert_eq!(ignored.hits, 1);
+        assert_eq!(ignored.docs[0].doc.keys().collect::<Vec<_>>(), vec!["title"]);
+
+        // A document waiting to be committed is kept when the schema changes under it
+        let pending = r#"{"document": {"title": "first"} }"#;
+        assert_eq!(add("dynamic", pending).await?.status(), StatusCode::CREATED);
+        assert_eq!(add("dynamic", extra).await?.status(), StatusCode::CREATED);
+        let mapped = docs("dynamic").await?;
+        assert_eq!(mapped.hits, 2);
+        let doc = mapped.docs.iter().map(|d| &d.doc).find(|d| d.contains_key("extra")).unwrap();
+        assert_eq!(doc["extra"], vec![serde_json::json!("shiba")]);
+        assert_eq!(doc["count"], vec![serde_json::json!(-3)]);
+        assert_eq!(doc["flag"], vec![serde_json::json!(true)]);
+        let saved = shared_cat.get_index("dynamic")?.get_index().load_metas()?.schema;
+        for field in &["title", "extra", "count", "flag"] {
+            assert!(saved.get_field(field).is_some(), "{}", field);
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_doc_create() {
         let shared_cat = create_test_catalog("test_index");
         let body = async {
             let q = r#" {"options": {"commit": true }, "document": {"test_text": "Babbaboo!", "test_u64": 10, "test_i64": -10} }"#;
-            let req = add_document(Arc::clone(&shared_cat), Body::from(q), test_index()).await;
+            let req = add_document(Arc::clone(&shared_cat), Body::from(q), test_index(), QueryOptions::default()).await;
 
             assert_eq!(req.is_ok(), true);
         };
@@ -172,6 +1156,49 @@ mod tests {
         rt.block_on(body);
     }
 
+    #[tokio::test(threaded_scheduler)]
+    async fn test_concurrent_versioned_updates() -> Result<(), Box<dyn std::error::Error>> {
+        let mut builder = SchemaBuilder::new();
+        builder.add_text_field("id", STRING | STORED);
+        builder.add_text_field("body", TEXT | STORED);
+        builder.add_u64_field(toshi_types::VERSION_FIELD, FAST | STORED);
+        let index = Index::create_in_ram(builder.build());
+        let shared_cat = Arc::new(IndexCatalog::with_index("versioned".into(), index)?);
+
+        let seed = r#"{"options": {"commit": true}, "key": {"id": "doc1"}, "document": {"id": "doc1", "body": "first"} }"#;
+        let resp = add_document(
+            Arc::clone(&shared_cat),
+            Body::from(seed),
+            "versioned".into(),
+            QueryOptions::default(),
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let update = |body: &'static str| {
+            let doc = format!(
+                r#"{{"options": {{"commit": true}}, "key": {{"id": "doc1"}}, "if_version": 1, "document": {{"id": "doc1", "body": "{}"}} }}"#,
+                body
+            );
+            tokio::spawn(add_document(
+                Arc::clone(&shared_cat),
+                Body::from(doc),
+                "versioned".into(),
+                QueryOptions::default(),
+            ))
+        };
+        let (first, second) = futures::future::join(update("second"), update("third")).await;
+        let mut statuses = vec![first??.status(), second??.status()];
+        statuses.sort();
+        assert_eq!(statuses, vec![StatusCode::CREATED, StatusCode::CONFLICT]);
+
+        let resp = all_docs(Arc::clone(&shared_cat), "versioned".into()).await?;
+        let b = wait_json::<crate::SearchResults>(resp).await;
+        assert_eq!(b.hits, 1);
+        assert_eq!(b.docs[0].doc[toshi_types::VERSION_FIELD][0], Value::U64(2));
+        Ok(())
+    }
+
     #[test]
     fn test_doc_delete() {
         let shared_cat = create_test_catalog("test_index");
@@ -183,27 +1210,149 @@ mod tests {
                 terms,
             };
             let body_bytes = serde_json::to_vec(&delete).unwrap();
-            let del = delete_term(Arc::clone(&shared_cat), Body::from(body_bytes), test_index()).await;
+            let del = delete_term(
+                Arc::clone(&shared_cat),
+                Body::from(body_bytes),
+                test_index(),
+                QueryOptions::default(),
+            )
+            .await;
             assert_eq!(del.is_ok(), true);
         };
         let mut rt: Runtime = tokio::runtime::Runtime::new().unwrap();
         rt.block_on(req);
     }
 
+    #[tokio::test]
+    async fn test_pattern_delete() -> Result<(), Box<dyn std::error::Error>> {
+        let catalog = IndexCatalog::with_index("logs-2024-01".into(), toshi_test::create_test_index())?;
+        catalog.add_index("logs-2024-02".into(), toshi_test::create_test_index())?;
+        catalog.add_index("old-logs-2024-03".into(), toshi_test::create_test_index())?;
+        let catalog = Arc::new(catalog);
+
+        let delete = r#"{ "options": { "commit": true }, "terms": { "test_text": "document" } }"#;
+        let resp = delete_term(
+            Arc::clone(&catalog),
+            Body::from(delete),
+            "logs-2024-*".into(),
+            QueryOptions::default(),
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        for (index, left) in &[("logs-2024-01", 2), ("logs-2024-02", 2), ("old-logs-2024-03", 5)] {
+            catalog.get_index(index)?.reload()?;
+            let docs = all_docs(Arc::clone(&catalog), (*index).into()).await?;
+            assert_eq!(wait_json::<crate::SearchResults>(docs).await.hits, *left);
+        }
+
+        let resp = delete_term(
+            Arc::clone(&catalog),
+            Body::from(delete),
+            "metrics-*".into(),
+            QueryOptions::default(),
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() -> Result<(), Box<dyn std::error::Error>> {
+        let index = toshi_test::create_test_index();
+        let catalog = IndexCatalog::with_index("test_index".into(), index.clone())?;
+        catalog.add_index("test_copy".into(), Index::create_in_ram(index.schema()))?;
+        let catalog = Arc::new(catalog);
+        let dry_run = || QueryOptions {
+            dry_run: Some(true),
+            ..Default::default()
+        };
+
+        let delete = r#"{ "options": { "commit": true }, "terms": { "test_text": "document" } }"#;
+        let resp = delete_term(Arc::clone(&catalog), Body::from(delete), test_index(), dry_run()).await?;
+        assert_eq!(wait_json::<DocsAffected>(resp).await.docs_affected, 3);
+        let reindex_body = r#"{ "source": "test_index" }"#;
+        let resp = reindex(Arc::clone(&catalog), Body::from(reindex_body), "test_copy".into(), dry_run()).await?;
+        assert_eq!(wait_json::<ReindexResponse>(resp).await.reindexed, 5);
+
+        for (index, hits) in &[("test_index", 5), ("test_copy", 0)] {
+            catalog.get_index(index)?.reload()?;
+            let docs = all_docs(Arc::clone(&catalog), (*index).into()).await?;
+            assert_eq!(wait_json::<crate::SearchResults>(docs).await.hits, *hits);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_update_by_query() -> Result<(), Box<dyn std::error::Error>> {
+        let mut builder = SchemaBuilder::new();
+        let id = builder.add_text_field("_id", STRING | STORED);
+        let team = builder.add_text_field("team", STRING | STORED);
+        let status = builder.add_text_field("status", STRING | STORED);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer(30_000_000)?;
+        for (i, t) in &[("a", "red"), ("b", "red"), ("c", "blue")] {
+            writer.add_document(tantivy::doc!(id => *i, team => *t, status => "active"));
+        }
+        writer.commit()?;
+        drop(writer);
+        let catalog = Arc::new(IndexCatalog::with_index("teams".into(), index)?);
+        let statuses = |catalog: SharedCatalog| async move {
+            let docs = wait_json::<crate::SearchResults>(all_docs(catalog, "teams".into()).await.unwrap()).await;
+            let mut statuses: Vec<(String, String)> = docs
+                .docs
+                .iter()
+                .map(|d| {
+                    (
+                        d.doc["_id"][0].text().unwrap().to_string(),
+                        d.doc["status"][0].text().unwrap().to_string(),
+                    )
+                })
+                .collect();
+            statuses.sort();
+            statuses
+        };
+        let update = r#"{ "options": { "commit": true }, "query": { "term": { "team": "red" } }, "set": { "status": "archived" } }"#;
+
+        let dry_run = QueryOptions {
+            dry_run: Some(true),
+            ..Default::default()
+        };
+        let resp = update_by_query(Arc::clone(&catalog), Body::from(update), "teams".into(), dry_run).await?;
+        assert_eq!(wait_json::<DocsAffected>(resp).await.docs_affected, 2);
+        assert!(statuses(Arc::clone(&catalog)).await.iter().all(|(_, s)| s == "active"));
+
+        // A write that isn't committed yet moves `a` to another team, so the update must not match it
+        let moved = r#"{ "key": { "_id": "a" }, "document": { "_id": "a", "team": "blue", "status": "active" } }"#;
+        let resp = add_document(Arc::clone(&catalog), Body::from(moved), "teams".into(), QueryOptions::default()).await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp = update_by_query(Arc::clone(&catalog), Body::from(update), "teams".into(), QueryOptions::default()).await?;
+        assert_eq!(wait_json::<DocsAffected>(resp).await.docs_affected, 1);
+        let expected = vec![("a", "active"), ("b", "archived"), ("c", "active")];
+        let expected: Vec<(String, String)> = expected.into_iter().map(|(i, s)| (i.into(), s.into())).collect();
+        assert_eq!(statuses(Arc::clone(&catalog)).await, expected);
+
+        let unknown = r#"{ "query": { "term": { "team": "red" } }, "set": { "color": "green" } }"#;
+        let resp = update_by_query(Arc::clone(&catalog), Body::from(unknown), "teams".into(), QueryOptions::default()).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
     #[test]
     fn test_bad_json() {
         let shared_cat = create_test_catalog("test_index");
         let bad = async {
             let bad_json: serde_json::Value = serde_json::Value::String("".into());
-            let add_doc = AddDocument {
-                document: bad_json,
-                options: None,
-            };
+            let add_doc = AddDocument::new(None, bad_json);
             let body_bytes = serde_json::to_vec(&add_doc).unwrap();
-            let req = add_document(Arc::clone(&shared_cat), Body::from(body_bytes), test_index())
-                .await
-                .unwrap()
-                .into_body();
+            let req = add_document(
+                Arc::clone(&shared_cat),
+                Body::from(body_bytes),
+                test_index(),
+                QueryOptions::default(),
+            )
+            .await
+            .unwrap()
+            .into_body();
             let req_body = hyper::body::aggregate(req).await.unwrap();
             let buf = req_body.bytes();
             println!("{}", std::str::from_utf8(&buf).unwrap());
@@ -211,4 +1360,34 @@ mod tests {
         let mut rt: Runtime = tokio::runtime::Runtime::new().unwrap();
         rt.block_on(bad);
     }
+
+    #[tokio::test]
+    async fn test_write_after_failover() -> Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.experimental = true;
+        catalog.settings.place_addr = "127.0.0.1:8082".into();
+        catalog.settings.experimental_features.nodes = vec!["127.0.0.1:8081".into(), "127.0.0.1:8082".into()];
+        let shared_cat = Arc::new(catalog);
+        let routing = shared_cat.plan_index("test_index", 1, &idle_nodes(&shared_cat))?;
+        assert_eq!(routing.nodes(), vec!["127.0.0.1:8081", "127.0.0.1:8082"]);
+
+        // The primary's node goes away, the supervisor sees it and the local replica takes over
+        shared_cat.observe_node("127.0.0.1:8081", false)?;
+        assert_eq!(shared_cat.write_node("test_index")?, "127.0.0.1:8082");
+        assert!(shared_cat.failover("test_index", routing.generation).is_err());
+
+        let doc = r#"{"options": {"commit": true }, "document": {"test_text": "Babbaboo!", "test_u64": 10, "test_i64": -10} }"#;
+        let resp = add_document(Arc::clone(&shared_cat), Body::from(doc), test_index(), QueryOptions::default()).await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp = all_docs(Arc::clone(&shared_cat), test_index()).await?;
+        let b = wait_json::<crate::SearchResults>(resp).await;
+        assert_eq!(b.hits, 6);
+
+        shared_cat.observe_node("127.0.0.1:8081", true)?;
+        assert_eq!(
+            shared_cat.get_routing("test_index")?.nodes(),
+            vec!["127.0.0.1:8082", "127.0.0.1:8081"]
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,88 @@
+use std::fmt::{Display, Write};
+
+use hyper::{Body, Response};
+
+use toshi_types::WriterStats;
+
+use crate::handlers::ResponseFuture;
+use crate::index::SharedCatalog;
+
+fn gauge<'a, I, V>(body: &mut String, metric: &str, help: &str, values: I)
+where
+    I: Iterator<Item = (&'a str, V)>,
+    V: Display,
+{
+    let _ = writeln!(body, "# HELP {} {}\n# TYPE {} gauge", metric, help, metric);
+    for (index, value) in values {
+        let _ = writeln!(body, "{}{{index=\"{}\"}} {}", metric, index, value);
+    }
+}
+
+/// Every local index's writer gauges and rates in the Prometheus text format, labelled with the index name
+pub async fn metrics(catalog: SharedCatalog) -> ResponseFuture {
+    let mut indexes: Vec<_> = catalog.get_collection().into_iter().collect();
+    indexes.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut stats = Vec::with_capacity(indexes.len());
+    for (name, index) in indexes {
+        match index.writer_stats() {
+            Ok(s) => stats.push((name, s)),
+            Err(e) => return Ok(Response::from(e)),
+        }
+    }
+
+    let mut body = String::new();
+    let values = |f: fn(&WriterStats) -> usize| stats.iter().map(move |(name, s)| (name.as_str(), f(s)));
+    gauge(
+        &mut body,
+        "toshi_active_merges",
+        "Merges of an index's segments that are running",
+        values(|s| s.active_merges),
+    );
+    gauge(
+        &mut body,
+        "toshi_pending_segments",
+        "Segments of an index that aren't being merged",
+        values(|s| s.pending_segments),
+    );
+    gauge(
+        &mut body,
+        "toshi_queued_operations",
+        "Writes to an index waiting on the next commit",
+        values(|s| s.queued_operations),
+    );
+    let rates = |f: fn(&WriterStats) -> f64| stats.iter().map(move |(name, s)| (name.as_str(), f(s)));
+    gauge(
+        &mut body,
+        "toshi_queries_per_second_1m",
+        "Searches of an index a second over the last minute",
+        rates(|s| s.rates.queries_per_sec_1m),
+    );
+    gauge(
+        &mut body,
+        "toshi_queries_per_second_5m",
+        "Searches of an index a second over the last five minutes",
+        rates(|s| s.rates.queries_per_sec_5m),
+    );
+    gauge(
+        &mut body,
+        "toshi_docs_per_second_1m",
+        "Documents added to an index a second over the last minute",
+        rates(|s| s.rates.docs_per_sec_1m),
+    );
+    gauge(
+        &mut body,
+        "toshi_docs_per_second_5m",
+        "Documents added to an index a second over the last five minutes",
+        rates(|s| s.rates.docs_per_sec_5m),
+    );
+    let _ = writeln!(
+        body,
+        "# HELP toshi_active_searches Searches that are running\n# TYPE toshi_active_searches gauge\ntoshi_active_searches {}",
+        catalog.active_searches()
+    );
+
+    Ok(Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(body))
+        .unwrap())
+}
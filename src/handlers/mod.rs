@@ -1,11 +1,14 @@
 use hyper::Body;
 
-pub use {bulk::*, index::*, root::*, search::*, summary::*};
+pub use {bulk::*, index::*, metrics::*, pipeline::*, root::*, search::*, summary::*, tasks::*};
 
 pub mod bulk;
 pub mod index;
+pub mod metrics;
+pub mod pipeline;
 pub mod root;
 pub mod search;
 pub mod summary;
+pub mod tasks;
 
 pub type ResponseFuture = Result<hyper::Response<Body>, hyper::Error>;
@@ -0,0 +1,104 @@
+use bytes::Buf;
+use hyper::body::aggregate;
+use hyper::{Body, Response, StatusCode};
+
+use toshi_types::Error;
+
+use crate::handlers::ResponseFuture;
+use crate::index::SharedCatalog;
+use crate::pipeline::Pipeline;
+use crate::utils::{empty_with_code, with_body};
+
+pub async fn get_pipeline(catalog: SharedCatalog, index: String) -> ResponseFuture {
+    match catalog.get_index(&index).and_then(|i| i.get_pipeline()) {
+        Ok(Some(pipeline)) => Ok(with_body(pipeline)),
+        Ok(None) => Ok(empty_with_code(StatusCode::NOT_FOUND)),
+        Err(e) => Ok(Response::from(e)),
+    }
+}
+
+pub async fn set_pipeline(catalog: SharedCatalog, body: Body, index: String) -> ResponseFuture {
+    let b = aggregate(body).await?;
+    let pipeline = match serde_json::from_slice::<Pipeline>(b.bytes()) {
+        Ok(v) => v,
+        Err(e) => return Ok(Response::from(Error::from(e))),
+    };
+    match catalog.get_index(&index).and_then(|i| {
+        i.set_pipeline(Some(pipeline))?;
+        i.mark_modified()
+    }) {
+        Ok(_) => Ok(empty_with_code(StatusCode::OK)),
+        Err(e) => Ok(Response::from(e)),
+    }
+}
+
+pub async fn delete_pipeline(catalog: SharedCatalog, index: String) -> ResponseFuture {
+    match catalog.get_index(&index).and_then(|i| {
+        i.set_pipeline(None)?;
+        i.mark_modified()
+    }) {
+        Ok(_) => Ok(empty_with_code(StatusCode::OK)),
+        Err(e) => Ok(Response::from(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    use toshi_test::read_body;
+
+    use crate::handlers::{add_document, all_docs, bulk_insert};
+    use crate::index::IndexCatalog;
+    use crate::router::QueryOptions;
+    use crate::settings::DirectoryKind;
+    use crate::SearchResults;
+
+    use super::*;
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_ingest_timestamp() -> Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = DirectoryKind::Ram;
+        let catalog = Arc::new(catalog);
+        let pipeline = r#"{ "processors": [ { "now": { "field": "test_text" } }, { "lowercase": { "field": "test_unindex" } } ] }"#;
+        let resp = set_pipeline(Arc::clone(&catalog), Body::from(pipeline), "test_index".into()).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = get_pipeline(Arc::clone(&catalog), "test_index".into()).await?;
+        let stored: Pipeline = serde_json::from_slice(read_body(resp).await?.as_bytes())?;
+        assert_eq!(stored.processors.len(), 2);
+
+        let body = r#"{"options": {"commit": true}, "document": {"test_i64": 2020, "test_u64": 1, "test_unindex": "PIPELINE"}}"#;
+        let resp = add_document(Arc::clone(&catalog), Body::from(body), "test_index".into(), QueryOptions::default()).await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let resp = all_docs(Arc::clone(&catalog), "test_index".into()).await?;
+        let docs: SearchResults = serde_json::from_slice(read_body(resp).await?.as_bytes())?;
+        let doc = docs
+            .docs
+            .iter()
+            .map(|d| &d.doc)
+            .find(|d| d["test_unindex"][0].text() == Some("pipeline"))
+            .expect("Document was not indexed");
+        let ingested = doc["test_text"][0].text().unwrap();
+        assert!(tantivy::chrono::DateTime::parse_from_rfc3339(ingested).is_ok());
+
+        let body = "{\"test_i64\": 1, \"test_unindex\": 10}\n{\"test_i64\": 1, \"test_unindex\": \"Bulk\"}";
+        let options = QueryOptions {
+            dead_letter_index: Some("failed".into()),
+            ..Default::default()
+        };
+        let resp = bulk_insert(
+            catalog,
+            Arc::new(AtomicBool::new(false)),
+            Body::from(body),
+            "test_index".into(),
+            options,
+        )
+        .await?;
+        let body = read_body(resp).await?;
+        assert!(body.contains(r#""dead_letters":1"#));
+        Ok(())
+    }
+}
@@ -1,28 +1,359 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Instant;
+
 use bytes::Buf;
+use futures::{future, stream, StreamExt};
 use hyper::body::aggregate;
 use hyper::Response;
 use hyper::{Body, StatusCode};
+use tantivy::{LeasedItem, Searcher};
 use tracing::*;
 
-use toshi_types::Search;
+use toshi_types::{
+    Error, ErrorResponse, MgetDoc, MgetRequest, MgetResponse, Query, ScoreNormalization, ScoredDoc, Search, SearchProfile,
+    ValidationResponse,
+};
 
+use crate::handle::{IndexHandle, LocalIndex};
 use crate::handlers::ResponseFuture;
-use crate::index::SharedCatalog;
+use crate::index::{IndexCatalog, SharedCatalog};
+use crate::router::QueryOptions;
+use crate::settings::Settings;
 use crate::utils::{empty_with_code, with_body};
-use crate::SearchResults;
+use crate::{SearchResults, TypedSearchResults};
 
+/// Merge the results of an index's shards, then rescale the scores of the whole index with
+/// `normalization` so they can be merged with the scores of other indexes
 #[inline]
-pub fn fold_results(results: Vec<SearchResults>) -> SearchResults {
-    results.into_iter().sum()
+pub fn fold_results(results: Vec<SearchResults>, normalization: ScoreNormalization) -> SearchResults {
+    results
+        .into_iter()
+        .sum::<SearchResults>()
+        .normalize_scores(normalization)
+        .sort_hits()
+}
+
+/// Fill in the configured default limit for searches that omit one, and clamp or reject searches
+/// asking for more documents than the server allows. Searches paging past the configured
+/// `max_result_window` are rejected, collecting every hit before the page is what makes deep pages slow.
+fn apply_search_size(mut search: Search, settings: &Settings) -> Result<Search, Error> {
+    match search.limit {
+        None => search.limit = Some(settings.default_search_size),
+        Some(limit) if limit > settings.max_search_size => {
+            if settings.reject_oversized_search {
+                return Err(Error::QueryError(format!(
+                    "Limit {} exceeds the max search size of {}",
+                    limit, settings.max_search_size
+                )));
+            }
+            search.limit = Some(settings.max_search_size);
+        }
+        Some(_) => (),
+    }
+    let end = search.from.unwrap_or(0).saturating_add(search.get_limit());
+    if end > settings.max_result_window {
+        return Err(Error::QueryError(format!(
+            "from + limit of {} exceeds the max result window of {}, sort on a field and filter out the values seen to page further",
+            end, settings.max_result_window
+        )));
+    }
+    Ok(search)
+}
+
+/// Stream the hits of a search back as newline delimited JSON, one [`toshi_types::ScoredDoc`] per
+/// line. If a hit cannot be serialized an [`ErrorResponse`] is written as the final line instead.
+fn ndjson_body(results: TypedSearchResults) -> Response<Body> {
+    let lines = results.docs.into_iter().scan(false, |failed, doc| {
+        if *failed {
+            return None;
+        }
+        let line = serde_json::to_vec(&doc).unwrap_or_else(|e| {
+            *failed = true;
+            serde_json::to_vec(&ErrorResponse::new(e)).unwrap()
+        });
+        Some(Ok::<_, Error>([line, b"\n".to_vec()].concat()))
+    });
+
+    Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "application/x-ndjson")
+        .body(Body::wrap_stream(stream::iter(lines)))
+        .unwrap()
+}
+
+/// The stored fields of a hit by field name, before they're typed for the response
+type NamedDoc = BTreeMap<String, Vec<tantivy::schema::Value>>;
+
+/// How many hits of a streamed search are loaded at a time
+const NDJSON_CHUNK: usize = 64;
+
+/// Stream the hits of a search of a local index back as newline delimited JSON like [`ndjson_body`],
+/// loading the stored fields of a few hits at a time as the response is written instead of holding
+/// every hit in memory first. If a hit can't be loaded an [`ErrorResponse`] is the final line.
+fn ndjson_stream(handle: LocalIndex, searcher: Arc<LeasedItem<Searcher>>, hits: Vec<ScoredDoc<NamedDoc>>) -> Response<Body> {
+    let mut hits = hits.into_iter().peekable();
+    let chunks = std::iter::from_fn(move || {
+        hits.peek()?;
+        Some(hits.by_ref().take(NDJSON_CHUNK).collect::<Vec<_>>())
+    });
+    let lines = stream::iter(chunks)
+        .then(move |chunk| {
+            let (handle, searcher) = (handle.clone(), Arc::clone(&searcher));
+            async move {
+                tokio::task::spawn_blocking(move || ndjson_lines(&handle, &searcher, chunk))
+                    .await
+                    .unwrap_or_else(|_| (error_line(Error::SpawnError), true))
+            }
+        })
+        .scan(false, |failed, (lines, chunk_failed)| {
+            let line = if *failed { None } else { Some(Ok::<_, Error>(lines)) };
+            *failed = chunk_failed;
+            future::ready(line)
+        });
+
+    Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "application/x-ndjson")
+        .body(Body::wrap_stream(lines))
+        .unwrap()
+}
+
+/// The lines of a chunk of hits, and whether loading one of them failed and ended the chunk early
+fn ndjson_lines(handle: &LocalIndex, searcher: &Searcher, hits: Vec<ScoredDoc<NamedDoc>>) -> (Vec<u8>, bool) {
+    let mut lines = Vec::new();
+    for hit in hits {
+        let line = hit
+            .address
+            .as_ref()
+            .ok_or(Error::UnknownError)
+            .and_then(|address| handle.load_hit(searcher, address))
+            .and_then(|doc| {
+                let line = ScoredDoc {
+                    sort: hit.sort,
+                    ..ScoredDoc::new(hit.score, doc)
+                };
+                Ok(serde_json::to_vec(&line)?)
+            });
+        match line {
+            Ok(line) => {
+                lines.extend(line);
+                lines.push(b'\n');
+            }
+            Err(e) => {
+                lines.extend(error_line(e));
+                return (lines, true);
+            }
+        }
+    }
+    (lines, false)
+}
+
+fn error_line(e: Error) -> Vec<u8> {
+    let mut line = serde_json::to_vec(&ErrorResponse::from(e)).unwrap_or_default();
+    line.push(b'\n');
+    line
+}
+
+/// The response to a search: its hits as newline delimited JSON, the bare array of its hits when
+/// the search asked for them raw, or else the full results with the hit count and facets.
+fn results_body(results: TypedSearchResults, ndjson: bool, raw: bool) -> Response<Body> {
+    if ndjson {
+        ndjson_body(results)
+    } else if raw {
+        with_body(results.docs)
+    } else {
+        with_body(results)
+    }
 }
 
-pub async fn doc_search(catalog: SharedCatalog, body: Body, index: String) -> ResponseFuture {
-    let span = span!(Level::INFO, "search_handler", ?index);
+/// Search every local index a list of names or patterns refers to and merge the best scoring hits
+/// of all of them. Indexes the query doesn't fit, because they lack a field it uses or the field
+/// has a different type there, are skipped. A pattern matching no indexes finds no documents. The
+/// scores of each index are rescaled with the search's `score_normalization` once all of the
+/// index's hits are in, and only then merged with the other indexes.
+async fn search_many(catalog: &SharedCatalog, indexes: &str, search: Search) -> Result<TypedSearchResults, Error> {
+    let names = catalog.resolve_indexes(indexes)?;
+    let limit = search.get_limit();
+    let normalization = search.score_normalization;
+    let mut results = Vec::with_capacity(names.len());
+    for name in names {
+        match catalog.search_local_index(&name, search.clone()).await {
+            // Each index's hits are typed by its own fields before they're merged, the same field
+            // can be a bool in one index and a number in another
+            Ok(r) => results.push(catalog.typed_results(&name, fold_results(vec![r], normalization))),
+            Err(e @ Error::QueryError(_)) | Err(e @ Error::UnknownIndexField(_)) => {
+                warn!("Skipping index {} in a multi index search: {}", name, e)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(results.into_iter().sum::<TypedSearchResults>().top(limit))
+}
+
+pub async fn doc_search(catalog: SharedCatalog, body: Body, index: String, options: QueryOptions) -> ResponseFuture {
+    search(catalog, body, index, options, false).await
+}
+
+pub async fn doc_search_ndjson(catalog: SharedCatalog, body: Body, index: String, options: QueryOptions) -> ResponseFuture {
+    search(catalog, body, index, options, true).await
+}
+
+async fn search(catalog: SharedCatalog, body: Body, index: String, options: QueryOptions, ndjson: bool) -> ResponseFuture {
+    let span = span!(Level::INFO, "search_handler", ?index, ndjson);
     let _enter = span.enter();
+    let parse_start = Instant::now();
     let b = aggregate(body).await?;
-    let req = serde_json::from_slice::<Search>(b.bytes()).unwrap();
-    let c = catalog.lock().await;
-    let req = if req.query.is_none() { Search::all_docs() } else { req };
+    // A body with nothing in it searches every document, just like an empty object does
+    let req = if b.bytes().iter().all(u8::is_ascii_whitespace) {
+        Search::all_docs()
+    } else {
+        match serde_json::from_slice::<Search>(b.bytes()) {
+            Ok(v) => v,
+            Err(e) => return Ok(Response::from(Error::from(e))),
+        }
+    };
+    let parse_ms = SearchProfile::millis(parse_start.elapsed());
+    // Slow searches are logged as the body they were sent as
+    let logged = move || String::from_utf8_lossy(b.bytes()).into_owned();
+    run_search(catalog, req, index, options, ndjson, parse_ms, logged).await
+}
+
+/// Search with a Lucene style query string given as `q`, like `title:fox AND body:quick~`, see
+/// [`Query::from_query_string`]. Terms without a field are searched for in the field given as `df`.
+pub async fn query_string_search(catalog: SharedCatalog, index: String, options: QueryOptions) -> ResponseFuture {
+    let parse_start = Instant::now();
+    let query = match Query::from_query_string(options.q(), options.df.as_deref()) {
+        Ok(v) => v,
+        Err(e) => return Ok(Response::from(e)),
+    };
+    let req = Search {
+        limit: options.limit,
+        ..Search::with_query(query)
+    };
+    let parse_ms = SearchProfile::millis(parse_start.elapsed());
+    let q = options.q().to_string();
+    run_search(catalog, req, index, options, false, parse_ms, move || format!("q={}", q)).await
+}
+
+/// Log how long a search of `index` took, at WARN along with the search as `query` gives it when
+/// it took at least the configured `slow_query_threshold` and at DEBUG otherwise. The search is
+/// only written out for slow ones.
+fn log_search<F>(threshold: Option<u64>, index: &str, query: F, elapsed_ms: f64)
+where
+    F: FnOnce() -> String,
+{
+    match threshold {
+        Some(threshold) if elapsed_ms >= threshold as f64 => warn!("Slow query on {} took {:.3}ms: {}", index, elapsed_ms, query()),
+        _ => debug!("Query on {} took {:.3}ms", index, elapsed_ms),
+    }
+}
+
+async fn run_search<F>(
+    catalog: SharedCatalog,
+    req: Search,
+    index: String,
+    options: QueryOptions,
+    ndjson: bool,
+    parse_ms: f64,
+    logged: F,
+) -> ResponseFuture
+where
+    F: FnOnce() -> String,
+{
+    let start = Instant::now();
+    let threshold = catalog.settings.slow_query_threshold;
+    let response = execute_search(catalog, req, index.clone(), options, ndjson, parse_ms).await;
+    log_search(threshold, &index, logged, parse_ms + SearchProfile::millis(start.elapsed()));
+    response
+}
+
+async fn execute_search(
+    catalog: SharedCatalog,
+    req: Search,
+    index: String,
+    options: QueryOptions,
+    ndjson: bool,
+    parse_ms: f64,
+) -> ResponseFuture {
+    let c = catalog;
+    let from = req.from.unwrap_or(0);
+    let typed = |results: SearchResults| c.typed_results(&index, results);
+    let profile = |mut results: TypedSearchResults| {
+        results.docs.drain(..from.min(results.docs.len()));
+        results.hits = results.docs.len();
+        results.profile = match results.profile {
+            Some(p) if options.profile() => Some(SearchProfile { parse_ms, ..p }),
+            _ => None,
+        };
+        results
+    };
+    // A search without a query matches everything, the rest of it is kept as it was sent
+    let req = if req.query.is_none() {
+        Search {
+            query: Some(Query::All),
+            ..req
+        }
+    } else {
+        req
+    };
+    let req = match apply_search_size(req, &c.settings) {
+        Ok(v) => v,
+        Err(e) => return Ok(Response::from(e)),
+    };
+    // A preference that means nothing is rejected whether or not the index has copies to pick from
+    let (preference, session) = match options.preference() {
+        Ok(v) => v,
+        Err(e) => return Ok(Response::from(e)),
+    };
+    // Every index is asked for the hits up to the end of the page, the ones before it are dropped
+    // once they're merged
+    let req = Search {
+        skip_source: req.skip_source || !options.source(),
+        limit: Some(from + req.get_limit()),
+        from: None,
+        ..req
+    };
+
+    if IndexCatalog::is_index_pattern(&index) {
+        return match search_many(&c, &index, req).await.map(profile) {
+            Ok(v) => Ok(results_body(v, ndjson, options.raw())),
+            Err(e) => Ok(Response::from(e)),
+        };
+    }
+
+    // A routing value pins the read to the one shard documents written with it were sent to
+    if let Some(routing) = options.routing() {
+        if let Ok(shard) = c.routed_shard(&index, routing).await {
+            info!("Routing query to the shard for: {}", routing);
+            return match shard.search_index(req).await.map(typed).map(profile) {
+                Ok(v) => Ok(results_body(v, ndjson, options.raw())),
+                Err(e) => Ok(Response::from(e)),
+            };
+        }
+    }
+
+    // In a cluster the index's routing decides which copy serves the read, unless that's this node
+    if c.settings.experimental {
+        if let Ok(node) = c.read_node(&index, preference, session) {
+            if node != c.settings.place_addr {
+                info!("Routing query to: {}", node);
+                return match c.search_node(&node, &index, req).await.map(typed).map(profile) {
+                    Ok(v) => Ok(results_body(v, ndjson, options.raw())),
+                    Err(e) => Ok(Response::from(e)),
+                };
+            }
+        }
+    }
+
+    // Streamed hits are loaded as they're written, unless they need highlighting or no fields at all
+    if ndjson && c.exists(&index) && req.highlight.is_empty() && !req.skip_source {
+        return match c.search_local_addresses(&index, req).await {
+            Ok((handle, searcher, mut results)) => {
+                results.docs.drain(..from.min(results.docs.len()));
+                Ok(ndjson_stream(handle, searcher, results.docs))
+            }
+            Err(e) => Ok(Response::from(e)),
+        };
+    }
 
     if c.exists(&index) {
         info!("Query: {:?}", req);
@@ -37,8 +368,8 @@ pub async fn doc_search(catalog: SharedCatalog, body: Body, index: String) -> Re
         //        }
         //
         //        let response = fold_results(results);
-        match c.search_local_index(&index, req.clone()).await {
-            Ok(v) => Ok(with_body(v)),
+        match c.search_local_index(&index, req.clone()).await.map(typed).map(profile) {
+            Ok(v) => Ok(results_body(v, ndjson, options.raw())),
             Err(e) => Ok(Response::from(e)),
         }
     } else {
@@ -46,9 +377,110 @@ pub async fn doc_search(catalog: SharedCatalog, body: Body, index: String) -> Re
     }
 }
 
+/// Return the canonical form of a search the way the server would run it, with the server's search
+/// size applied and the query's synonyms expanded before it's normalized
+pub async fn normalize_search(catalog: SharedCatalog, body: Body, index: String) -> ResponseFuture {
+    if !catalog.exists(&index) {
+        return Ok(empty_with_code(StatusCode::NOT_FOUND));
+    }
+    let b = aggregate(body).await?;
+    let req = match serde_json::from_slice::<Search>(b.bytes()) {
+        // A search without a query matches everything, the rest of it is kept as it was sent
+        Ok(v) if v.query.is_none() => Search {
+            query: Some(Query::All),
+            ..v
+        },
+        Ok(v) => v,
+        Err(e) => return Ok(Response::from(Error::from(e))),
+    };
+    let synonyms = catalog.settings.get_synonyms(&index);
+    let req = Search {
+        query: req.query.map(|q| q.expand_synonyms(&synonyms)),
+        post_filter: req.post_filter.map(|q| q.expand_synonyms(&synonyms)),
+        ..req
+    };
+    match apply_search_size(req, &catalog.settings) {
+        Ok(v) => Ok(with_body(v.normalize())),
+        Err(e) => Ok(Response::from(e)),
+    }
+}
+
+/// Check whether a search could be run against an index without running it. The search is parsed
+/// and its queries built against the index's schema, so unknown fields and values of the wrong type
+/// for their field are caught, and the error running it would fail with is explained.
+pub async fn validate_query(catalog: SharedCatalog, index: String, body: Body) -> ResponseFuture {
+    let handle = match catalog.get_index(&index) {
+        Ok(v) => v,
+        Err(e) => return Ok(Response::from(e)),
+    };
+    let b = aggregate(body).await?;
+    let req = match serde_json::from_slice::<Search>(b.bytes()) {
+        Ok(v) if v.query.is_none() => Search {
+            query: Some(Query::All),
+            ..v
+        },
+        Ok(v) => v,
+        Err(e) => return Ok(with_body(ValidationResponse::invalid(&Error::from(e)))),
+    };
+    let validation = match apply_search_size(req, &catalog.settings).and_then(|req| handle.validate(&req)) {
+        Ok(()) => ValidationResponse::valid(),
+        Err(e) => ValidationResponse::invalid(&e),
+    };
+    Ok(with_body(validation))
+}
+
 pub async fn all_docs(catalog: SharedCatalog, index: String) -> ResponseFuture {
     let body = Body::from(serde_json::to_vec(&Search::all_docs()).unwrap());
-    doc_search(catalog, body, index).await
+    doc_search(catalog, body, index, QueryOptions::default()).await
+}
+
+/// Fetch the documents with the ids listed in the body with a single query, in the order they're
+/// listed. An id no document has gets an entry saying it wasn't found.
+pub async fn mget(catalog: SharedCatalog, index: String, body: Body) -> ResponseFuture {
+    let b = aggregate(body).await?;
+    let req = match serde_json::from_slice::<MgetRequest>(b.bytes()) {
+        Ok(v) => v,
+        Err(e) => return Ok(Response::from(Error::from(e))),
+    };
+    let handle = match catalog.get_index(&index) {
+        Ok(v) => v,
+        Err(e) => return Ok(Response::from(e)),
+    };
+    let (found, bools) = match handle.get_by_ids(&req.ids).and_then(|found| Ok((found, handle.bool_fields()?))) {
+        Ok(v) => v,
+        Err(e) => return Ok(Response::from(e)),
+    };
+    let docs = req
+        .ids
+        .into_iter()
+        .map(|id| {
+            let doc = found.get(&id).cloned().map(|d| LocalIndex::typed_doc(d, &bools));
+            MgetDoc {
+                found: doc.is_some(),
+                id,
+                doc,
+            }
+        })
+        .collect();
+    Ok(with_body(MgetResponse { docs }))
+}
+
+/// The documents committed to an index after the sequence number `?since=`, lowest number first,
+/// and the number to ask for the changes after next. Returns at most `?limit=` documents, the
+/// server's default search size when it's left out.
+pub async fn changes(catalog: SharedCatalog, index: String, options: QueryOptions) -> ResponseFuture {
+    let handle = match catalog.get_index(&index) {
+        Ok(v) => v,
+        Err(e) => return Ok(Response::from(e)),
+    };
+    let limit = options
+        .limit
+        .unwrap_or(catalog.settings.default_search_size)
+        .min(catalog.settings.max_search_size);
+    match handle.changes(options.since(), limit) {
+        Ok(v) => Ok(with_body(v)),
+        Err(e) => Ok(Response::from(e)),
+    }
 }
 
 #[cfg(test)]
@@ -60,9 +492,12 @@ pub mod tests {
     use pretty_assertions::assert_eq;
 
     use toshi_test::{cmp_float, read_body, wait_json, TestServer};
-    use toshi_types::{ErrorResponse, ExactTerm, FuzzyQuery, FuzzyTerm, KeyValue, PhraseQuery, Query, TermPair};
+    use toshi_types::{
+        AggregationResult, DefaultOperator, ErrorCode, ErrorResponse, ExactTerm, FuzzyQuery, FuzzyTerm, KeyValue, PhraseQuery, Query,
+        TermPair,
+    };
 
-    use crate::handlers::ResponseFuture;
+    use crate::handlers::{add_document, create_index, ResponseFuture};
     use crate::index::tests::*;
     use crate::router::Router;
     use crate::SearchResults;
@@ -73,7 +508,13 @@ pub mod tests {
 
     pub async fn run_query(req: Search, index: &str) -> ResponseFuture {
         let cat = create_test_catalog(index);
-        doc_search(Arc::clone(&cat), Body::from(serde_json::to_vec(&req).unwrap()), index.into()).await
+        doc_search(
+            Arc::clone(&cat),
+            Body::from(serde_json::to_vec(&req).unwrap()),
+            index.into(),
+            QueryOptions::default(),
+        )
+        .await
     }
 
     #[tokio::test]
@@ -115,7 +556,7 @@ pub mod tests {
     async fn test_bad_raw_query_syntax() -> ReturnUnit {
         let cat = create_test_catalog("test_index");
         let body = r#"{ "query" : { "raw": "asd*(@sq__" } }"#;
-        let err = doc_search(Arc::clone(&cat), Body::from(body), "test_index".into()).await?;
+        let err = doc_search(Arc::clone(&cat), Body::from(body), "test_index".into(), QueryOptions::default()).await?;
         let body: ErrorResponse = wait_json::<ErrorResponse>(err).await;
         assert_eq!(body.message, "Error in query execution: \'Syntax error in query\'");
         Ok(())
@@ -125,9 +566,10 @@ pub mod tests {
     async fn test_unindexed_field() -> ReturnUnit {
         let cat = create_test_catalog("test_index");
         let body = r#"{ "query" : { "raw": "test_unindex:yes" } }"#;
-        let r = doc_search(Arc::clone(&cat), Body::from(body), "test_index".into()).await?;
+        let r = doc_search(Arc::clone(&cat), Body::from(body), "test_index".into(), QueryOptions::default()).await?;
         let b = read_body(r).await?;
-        let expected = "{\"message\":\"Error in query execution: 'Query on un-indexed field test_unindex'\"}";
+        let expected =
+            "{\"message\":\"Error in query execution: 'Query on un-indexed field test_unindex'\",\"code\":\"QUERY_PARSE_ERROR\"}";
         assert_eq!(b, expected);
         Ok(())
     }
@@ -136,7 +578,7 @@ pub mod tests {
     async fn test_bad_term_field_syntax() -> ReturnUnit {
         let cat = create_test_catalog("test_index");
         let body = r#"{ "query" : { "term": { "asdf": "Document" } } }"#;
-        let q = doc_search(Arc::clone(&cat), Body::from(body), "test_index".into()).await?;
+        let q = doc_search(Arc::clone(&cat), Body::from(body), "test_index".into(), QueryOptions::default()).await?;
         let b: ErrorResponse = wait_json(q).await;
         assert_eq!(b.message, "Error in query execution: 'Unknown field: asdf'");
         Ok(())
@@ -165,6 +607,361 @@ pub mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_search_profile() -> ReturnUnit {
+        let cat = create_test_catalog("test_index");
+        let body = r#"{ "query": { "term": { "test_text": "document" } } }"#;
+        let options = QueryOptions {
+            profile: Some(true),
+            ..Default::default()
+        };
+        let q = doc_search(Arc::clone(&cat), Body::from(body), "test_index".into(), options).await?;
+        let results: serde_json::Value = wait_json(q).await;
+        for stage in &["parse_ms", "build_ms", "collect_ms", "fetch_ms"] {
+            let ms = results["profile"][stage].as_f64();
+            assert!(ms.map(|ms| ms >= 0.0).unwrap_or(false), "{} is missing from {}", stage, results);
+        }
+
+        let q = doc_search(Arc::clone(&cat), Body::from(body), "test_index".into(), QueryOptions::default()).await?;
+        let results: serde_json::Value = wait_json(q).await;
+        assert!(results.get("profile").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_search() -> ReturnUnit {
+        let cat = create_test_catalog("test_index");
+        let body = r#"{ "query": { "term": { "test_text": "document" } } }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(body), "test_index".into(), QueryOptions::default()).await?;
+        let wrapped: serde_json::Value = wait_json(q).await;
+        assert!(wrapped["hits"].is_u64());
+        assert!(wrapped["docs"].is_array());
+
+        let options = QueryOptions {
+            raw: Some(true),
+            ..Default::default()
+        };
+        let q = doc_search(Arc::clone(&cat), Body::from(body), "test_index".into(), options).await?;
+        let raw: serde_json::Value = wait_json(q).await;
+        assert_eq!(raw, wrapped["docs"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_match_all_query() -> ReturnUnit {
+        let req: Search = serde_json::from_str(r#"{ "query": { "match_all": { "boost": 2.0 } }, "limit": 10 }"#)?;
+        let q = run_query(req, "test_index").await?;
+        let body: SearchResults = wait_json(q).await;
+        assert_eq!(body.hits, 5);
+        assert!(body.docs.iter().all(|d| d.score == Some(2.0)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nested_field_query() -> ReturnUnit {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = crate::settings::DirectoryKind::Ram;
+        let cat = Arc::new(catalog);
+        let schema = r#"[
+            { "name": "title", "type": "text", "options": { "indexing": { "record": "position", "tokenizer": "default" }, "stored": true } },
+            { "name": "user.name", "type": "text", "options": { "indexing": { "record": "position", "tokenizer": "default" }, "stored": true } }
+        ]"#;
+        create_index(Arc::clone(&cat), Body::from(schema), "nested".into(), QueryOptions::default()).await?;
+        let doc = r#"{ "options": { "commit": true }, "document": { "title": "toshi", "user": { "name": "shiba" } } }"#;
+        add_document(Arc::clone(&cat), Body::from(doc), "nested".into(), QueryOptions::default()).await?;
+
+        let body = r#"{ "query": { "term": { "user.name": "shiba" } } }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(body), "nested".into(), QueryOptions::default()).await?;
+        let results: SearchResults = wait_json(q).await;
+        assert_eq!(results.hits, 1);
+        assert_eq!(results.docs[0].doc["user.name"][0].text(), Some("shiba"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_typed_hits() -> ReturnUnit {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = crate::settings::DirectoryKind::Ram;
+        let cat = Arc::new(catalog);
+        let schema = r#"[
+            { "name": "name", "type": "text", "options": { "stored": true } },
+            { "name": "count", "type": "i64", "options": { "stored": true } },
+            { "name": "active", "type": "bool", "options": { "stored": true } },
+            { "name": "flags", "type": "bool", "options": { "stored": true } }
+        ]"#;
+        create_index(Arc::clone(&cat), Body::from(schema), "typed".into(), QueryOptions::default()).await?;
+        let doc =
+            r#"{ "options": { "commit": true }, "document": { "name": "shiba", "count": -3, "active": true, "flags": [false, true] } }"#;
+        add_document(Arc::clone(&cat), Body::from(doc), "typed".into(), QueryOptions::default()).await?;
+
+        let q = all_docs(Arc::clone(&cat), "typed".into()).await?;
+        let results: crate::TypedSearchResults = wait_json(q).await;
+        assert_eq!(results.hits, 1);
+        let doc = &results.docs[0].doc;
+        assert_eq!(doc["count"], vec![serde_json::json!(-3)]);
+        assert_eq!(doc["active"], vec![serde_json::json!(true)]);
+        assert_eq!(doc["flags"], vec![serde_json::json!(false), serde_json::json!(true)]);
+        assert_eq!(doc["name"], vec![serde_json::json!("shiba")]);
+
+        // Searching several indexes types each hit by the fields of the index it came from
+        let schema = r#"[{ "name": "active", "type": "u64", "options": { "stored": true } }]"#;
+        create_index(Arc::clone(&cat), Body::from(schema), "typed_counts".into(), QueryOptions::default()).await?;
+        let doc = r#"{ "options": { "commit": true }, "document": { "active": 5 } }"#;
+        add_document(Arc::clone(&cat), Body::from(doc), "typed_counts".into(), QueryOptions::default()).await?;
+        let q = doc_search(Arc::clone(&cat), Body::from("{}"), "typed*".into(), QueryOptions::default()).await?;
+        let results: crate::TypedSearchResults = wait_json(q).await;
+        let mut active: Vec<_> = results.docs.iter().map(|d| d.doc["active"][0].to_string()).collect();
+        active.sort();
+        assert_eq!(active, vec!["5", "true"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mget() -> ReturnUnit {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = crate::settings::DirectoryKind::Ram;
+        let cat = Arc::new(catalog);
+        let schema = r#"[
+            { "name": "_id", "type": "text", "options": { "indexing": { "record": "basic", "tokenizer": "raw" }, "stored": true } },
+            { "name": "name", "type": "text", "options": { "stored": true } },
+            { "name": "good", "type": "bool", "options": { "stored": true } }
+        ]"#;
+        create_index(Arc::clone(&cat), Body::from(schema), "dogs".into(), QueryOptions::default()).await?;
+        for (id, name) in &[("d1", "shiba"), ("d2", "akita"), ("d3", "corgi")] {
+            let doc = format!(
+                r#"{{ "options": {{ "commit": true }}, "document": {{ "_id": "{}", "name": "{}", "good": true }} }}"#,
+                id, name
+            );
+            add_document(Arc::clone(&cat), Body::from(doc), "dogs".into(), QueryOptions::default()).await?;
+        }
+
+        let body = r#"{ "ids": ["d3", "missing", "d1"] }"#;
+        let resp = mget(Arc::clone(&cat), "dogs".into(), Body::from(body)).await?;
+        let docs = wait_json::<MgetResponse>(resp).await.docs;
+        let ids: Vec<(&str, bool)> = docs.iter().map(|d| (d.id.as_str(), d.found)).collect();
+        assert_eq!(ids, vec![("d3", true), ("missing", false), ("d1", true)]);
+        let first = docs[0].doc.as_ref().unwrap();
+        assert_eq!(first["name"], vec![serde_json::json!("corgi")]);
+        assert_eq!(first["good"], vec![serde_json::json!(true)]);
+        assert!(docs[1].doc.is_none());
+
+        let options = QueryOptions {
+            source: Some(false),
+            ..QueryOptions::default()
+        };
+        let req = r#"{ "query": { "term": { "name": "shiba" } } }"#;
+        let resp = doc_search(Arc::clone(&cat), Body::from(req), "dogs".into(), options).await?;
+        let hits = wait_json::<SearchResults>(resp).await.docs;
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].id.as_deref(), Some("d1"));
+        assert!(hits[0].doc.is_empty());
+
+        let resp = mget(Arc::clone(&cat), "test_index".into(), Body::from(body)).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scoreless_sorted_search() -> ReturnUnit {
+        use tantivy::doc;
+        use tantivy::schema::{SchemaBuilder, FAST, INDEXED, STORED, TEXT};
+
+        use crate::handle::IndexHandle;
+
+        let mut builder = SchemaBuilder::new();
+        let n = builder.add_u64_field("n", INDEXED | FAST | STORED);
+        let body = builder.add_text_field("body", TEXT | STORED);
+        let index = tantivy::Index::create_in_ram(builder.build());
+        // A single thread keeps every document in one segment, in the order they were added
+        let mut writer = index.writer_with_num_threads(1, 30_000_000).map_err(Error::from)?;
+        for i in 0..5_000u64 {
+            let text = if i % 3 == 0 { "shiba inu shiba" } else { "inu" };
+            writer.add_document(doc!(n => (i * 7919) % 5_000, body => text));
+        }
+        writer.commit().map_err(Error::from)?;
+        drop(writer);
+        let cat = Arc::new(IndexCatalog::with_index("dogs".into(), index)?);
+        let handle = cat.get_index("dogs")?;
+
+        let search = |sort_by: &str, track_scores: bool| {
+            let query = r#"{ "query": { "bool": { "must": [ { "term": { "body": "shiba" } } ] } }, "limit": 50 }"#;
+            let mut search: Search = serde_json::from_str(query).unwrap();
+            search.sort_by = Some(sort_by.into());
+            search.track_scores = track_scores;
+            search
+        };
+        let ids = |results: &SearchResults| -> Vec<u64> { results.docs.iter().map(|d| d.doc["n"][0].u64_value()).collect() };
+        for sort_by in &["n", Search::DOC_ORDER] {
+            let scored = handle.search_index(search(sort_by, true)).await?;
+            let scoreless = handle.search_index(search(sort_by, false)).await?;
+            assert_eq!(ids(&scored), ids(&scoreless), "sorted by {}", sort_by);
+            assert_eq!(scored.hits, 50);
+            assert!(scored.docs.iter().all(|d| d.score.map(|s| s > 0.0).unwrap_or(false)));
+            assert!(scoreless.docs.iter().all(|d| d.score.is_none()));
+        }
+        // Hits sorted by a field carry its value, highest first
+        let sorted = handle.search_index(search("n", false)).await?;
+        let values: Vec<_> = sorted.docs.iter().map(|d| d.sort).collect();
+        assert_eq!(values[0], Some(toshi_types::SortValue::U64(ids(&sorted)[0])));
+        assert!(values.windows(2).all(|w| w[0] >= w[1]));
+
+        let ordered = handle.search_index(search(Search::DOC_ORDER, false)).await?;
+        let first: Vec<u64> = (0..50u64).map(|i| (i * 3 * 7919) % 5_000).collect();
+        assert_eq!(ids(&ordered), first);
+
+        let body = serde_json::to_vec(&search("n", false))?;
+        let q = doc_search(Arc::clone(&cat), Body::from(body), "dogs".into(), QueryOptions::default()).await?;
+        let results: serde_json::Value = wait_json(q).await;
+        assert!(results["docs"][0].get("score").is_none());
+
+        // Sorting a query every document matches is quicker without scoring them, the fastest of a few
+        // runs of each is compared so a slow run doesn't decide it
+        let collect_ms = |track_scores: bool| {
+            let handle = handle.clone();
+            async move {
+                let mut fastest = std::f64::MAX;
+                for _ in 0..5 {
+                    let mut every = search("n", track_scores);
+                    every.query = Some(serde_json::from_str(r#"{ "term": { "body": "inu" } }"#).unwrap());
+                    let results = handle.search_index(every).await?;
+                    assert_eq!(results.hits, 50);
+                    fastest = fastest.min(results.profile.as_ref().unwrap().collect_ms);
+                }
+                Ok::<_, Error>(fastest)
+            }
+        };
+        let scored_ms = collect_ms(true).await?;
+        let scoreless_ms = collect_ms(false).await?;
+        assert!(scoreless_ms < scored_ms, "scoreless {}ms, scored {}ms", scoreless_ms, scored_ms);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_has_child_query() -> ReturnUnit {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = crate::settings::DirectoryKind::Ram;
+        let cat = Arc::new(catalog);
+        let schema = r#"[
+            { "name": "_id", "type": "text", "options": { "indexing": { "record": "basic", "tokenizer": "raw" }, "stored": true } },
+            { "name": "_parent", "type": "text", "options": { "indexing": { "record": "basic", "tokenizer": "raw" }, "stored": true } },
+            { "name": "body", "type": "text", "options": { "indexing": { "record": "position", "tokenizer": "default" }, "stored": true } }
+        ]"#;
+        create_index(Arc::clone(&cat), Body::from(schema), "threads".into(), QueryOptions::default()).await?;
+        let docs = [
+            r#"{ "_id": "q1", "body": "how do I sort" }"#,
+            r#"{ "_id": "q2", "body": "how do I filter" }"#,
+            r#"{ "_id": "q3", "body": "unanswered" }"#,
+            r#"{ "_id": "a1", "_parent": "q1", "body": "use sort_by" }"#,
+            r#"{ "_id": "a2", "_parent": "q1", "body": "accepted answer" }"#,
+            r#"{ "_id": "a3", "_parent": "q2", "body": "accepted answer" }"#,
+        ];
+        for doc in &docs {
+            let body = format!(r#"{{ "options": {{ "commit": true }}, "document": {} }}"#, doc);
+            add_document(Arc::clone(&cat), Body::from(body), "threads".into(), QueryOptions::default()).await?;
+        }
+
+        let ids = |query: &'static str| {
+            let cat = Arc::clone(&cat);
+            async move {
+                let q = doc_search(cat, Body::from(query), "threads".into(), QueryOptions::default()).await?;
+                let results: SearchResults = wait_json(q).await;
+                let mut ids: Vec<String> = results
+                    .docs
+                    .iter()
+                    .filter_map(|d| d.doc["_id"][0].text().map(String::from))
+                    .collect();
+                ids.sort();
+                Ok::<_, hyper::Error>(ids)
+            }
+        };
+        let answered = ids(r#"{ "query": { "has_child": { "query": { "term": { "body": "accepted" } } } } }"#).await?;
+        assert_eq!(answered, vec!["q1", "q2"]);
+        let sorting = ids(r#"{ "query": { "has_child": { "query": { "term": { "body": "sort_by" } } } } }"#).await?;
+        assert_eq!(sorting, vec!["q1"]);
+        let answers = ids(r#"{ "query": { "has_parent": { "query": { "term": { "body": "filter" } } } } }"#).await?;
+        assert_eq!(answers, vec!["a3"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_multi_value_field_query() -> ReturnUnit {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = crate::settings::DirectoryKind::Ram;
+        let cat = Arc::new(catalog);
+        let schema = r#"[{ "name": "tags", "type": "text", "options": { "record": "basic", "tokenizer": "raw", "stored": true } }]"#;
+        create_index(Arc::clone(&cat), Body::from(schema), "tagged".into(), QueryOptions::default()).await?;
+        for doc in &[
+            r#"{ "document": { "tags": ["rust", "search"] } }"#,
+            r#"{ "options": { "commit": true }, "document": { "tags": ["go"] } }"#,
+        ] {
+            add_document(Arc::clone(&cat), Body::from(*doc), "tagged".into(), QueryOptions::default()).await?;
+        }
+
+        let body = r#"{ "query": { "term": { "tags": "search" } } }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(body), "tagged".into(), QueryOptions::default()).await?;
+        let results: SearchResults = wait_json(q).await;
+        assert_eq!(results.hits, 1);
+        assert_eq!(results.docs[0].doc["tags"].len(), 2);
+
+        let body = r#"{ "query": { "terms": { "tags": ["search", "go", "java"] } } }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(body), "tagged".into(), QueryOptions::default()).await?;
+        let results: SearchResults = wait_json(q).await;
+        assert_eq!(results.hits, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_normalize_search() -> ReturnUnit {
+        let cat = create_test_catalog("test_index");
+        let first = r#"{ "query": { "bool": { "must": [ { "term": { "test_text": "document" } } ], "boost": 1.0 } } }"#;
+        let second = r#"{ "limit": 100, "query": { "term": { "test_text": "document" } } }"#;
+        let first = normalize_search(Arc::clone(&cat), Body::from(first), "test_index".into()).await?;
+        let second = normalize_search(Arc::clone(&cat), Body::from(second), "test_index".into()).await?;
+        assert_eq!(read_body(first).await?, read_body(second).await?);
+
+        let first = r#"{ "query": { "bool": { "should": [ { "term": { "test_text": "a" } }, { "term": { "test_text": "b" } } ] } } }"#;
+        let second = r#"{ "query": { "bool": { "should": [ { "term": { "test_text": "b" } }, { "term": { "test_text": "a" } } ] } } }"#;
+        let first = normalize_search(Arc::clone(&cat), Body::from(first), "test_index".into()).await?;
+        let second = normalize_search(Arc::clone(&cat), Body::from(second), "test_index".into()).await?;
+        assert_eq!(read_body(first).await?, read_body(second).await?);
+
+        let all = normalize_search(Arc::clone(&cat), Body::from("{}"), "test_index".into()).await?;
+        let all: Search = wait_json(all).await;
+        assert_eq!(serde_json::to_string(&all.query)?, r#"{"match_all":{}}"#);
+
+        // Searches without a query keep the page they asked for
+        let paged = normalize_search(Arc::clone(&cat), Body::from(r#"{ "from": 20, "limit": 10 }"#), "test_index".into()).await?;
+        let paged: Search = wait_json(paged).await;
+        assert_eq!((paged.from, paged.limit), (Some(20), Some(10)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_validate_query() -> ReturnUnit {
+        let cat = create_test_catalog("test_index");
+        let validate = |body: &'static str| validate_query(Arc::clone(&cat), "test_index".into(), Body::from(body));
+
+        let good: ValidationResponse = wait_json(validate(r#"{ "query": { "term": { "test_i64": "2014" } } }"#).await?).await;
+        assert!(good.valid);
+        assert_eq!(good.explanation, None);
+
+        let unknown: ValidationResponse = wait_json(validate(r#"{ "query": { "term": { "asdf": "Document" } } }"#).await?).await;
+        assert!(!unknown.valid);
+        assert_eq!(unknown.error, Some(ErrorCode::QueryParseError));
+        assert_eq!(
+            unknown.explanation.as_deref(),
+            Some("Error in query execution: 'Unknown field: asdf'")
+        );
+
+        let mismatch: ValidationResponse = wait_json(validate(r#"{ "query": { "regex": { "test_i64": "20.*" } } }"#).await?).await;
+        assert!(!mismatch.valid);
+        assert!(mismatch.explanation.unwrap().contains("only searches text fields"));
+
+        let missing = validate_query(Arc::clone(&cat), "missing".into(), Body::from("{}")).await?;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_fuzzy_term_query() -> ReturnUnit {
         let fuzzy = KeyValue::new("test_text".into(), FuzzyTerm::new("document".into(), 0, false));
@@ -223,4 +1020,1142 @@ pub mod tests {
         assert_eq!(body.hits, 2);
         Ok(())
     }
+
+    fn sized_catalog(default_size: usize, max_size: usize, reject: bool) -> Result<SharedCatalog, Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.default_search_size = default_size;
+        catalog.settings.max_search_size = max_size;
+        catalog.settings.reject_oversized_search = reject;
+        Ok(Arc::new(catalog))
+    }
+
+    #[tokio::test]
+    async fn test_default_search_size() -> ReturnUnit {
+        let cat = sized_catalog(2, 10, false)?;
+        let q = all_docs(Arc::clone(&cat), "test_index".into()).await?;
+        let body: SearchResults = wait_json(q).await;
+        assert_eq!(body.hits, 2);
+
+        let req = r#"{ "query" : { "term" : { "test_text" : "document" } } }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "test_index".into(), QueryOptions::default()).await?;
+        let body: SearchResults = wait_json(q).await;
+        assert_eq!(body.hits, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_search_size() -> ReturnUnit {
+        let req = r#"{ "query" : { "term" : { "test_text" : "document" } }, "limit": 1000000 }"#;
+        let cat = sized_catalog(2, 3, false)?;
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "test_index".into(), QueryOptions::default()).await?;
+        assert_eq!(q.status(), StatusCode::OK);
+        let body: SearchResults = wait_json(q).await;
+        assert_eq!(body.hits, 3);
+
+        let cat = sized_catalog(2, 3, true)?;
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "test_index".into(), QueryOptions::default()).await?;
+        assert_eq!(q.status(), StatusCode::BAD_REQUEST);
+        let body: ErrorResponse = wait_json(q).await;
+        assert_eq!(
+            body.message,
+            "Error in query execution: 'Limit 1000000 exceeds the max search size of 3'"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_result_window() -> ReturnUnit {
+        use tantivy::doc;
+        use tantivy::schema::{SchemaBuilder, FAST, INDEXED, STORED};
+
+        let mut builder = SchemaBuilder::new();
+        let rank = builder.add_u64_field("rank", INDEXED | FAST | STORED);
+        let index = tantivy::Index::create_in_ram(builder.build());
+        let mut writer = index.writer(30_000_000).map_err(Error::from)?;
+        for r in 1..=6u64 {
+            writer.add_document(doc!(rank => r));
+        }
+        writer.commit().map_err(Error::from)?;
+        let mut catalog = IndexCatalog::with_index("ranked".into(), index)?;
+        catalog.settings.max_result_window = 4;
+        let cat = Arc::new(catalog);
+        let page = |req: &'static str| {
+            let cat = Arc::clone(&cat);
+            async move {
+                doc_search(cat, Body::from(req), "ranked".into(), QueryOptions::default())
+                    .await
+                    .unwrap()
+            }
+        };
+        let ranks = |results: SearchResults| -> Vec<u64> { results.docs.iter().map(|d| d.doc["rank"][0].u64_value()).collect() };
+
+        let first = page(r#"{ "sort_by": "rank", "limit": 2 }"#).await;
+        assert_eq!(ranks(wait_json(first).await), vec![6, 5]);
+        let second = page(r#"{ "sort_by": "rank", "from": 2, "limit": 2 }"#).await;
+        assert_eq!(ranks(wait_json(second).await), vec![4, 3]);
+
+        let past = page(r#"{ "sort_by": "rank", "from": 3, "limit": 2 }"#).await;
+        assert_eq!(past.status(), StatusCode::BAD_REQUEST);
+        let body: ErrorResponse = wait_json(past).await;
+        assert!(body.message.contains("from + limit of 5 exceeds the max result window of 4"));
+
+        // Filtering out the ranks already seen keeps paging without reaching past the window
+        let next = page(r#"{ "query": { "range": { "rank": { "lt": 3 } } }, "sort_by": "rank", "limit": 2 }"#).await;
+        assert_eq!(next.status(), StatusCode::OK);
+        assert_eq!(ranks(wait_json(next).await), vec![2, 1]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker() -> ReturnUnit {
+        let mut catalog = IndexCatalog::with_index("unused".into(), toshi_test::create_test_index())?;
+        catalog.settings.max_search_size = 1_000_000_000_000_000;
+        catalog.settings.circuit_breaker.budget = 1024 * 1024;
+        catalog.add_index("test_index".into(), toshi_test::create_test_index())?;
+        let cat = Arc::new(catalog);
+
+        let req = r#"{ "query" : { "term": { "test_text": "document" } }, "facets": { "test_facet": ["/cat"] }, "limit": 1000000000000 }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "test_index".into(), QueryOptions::default()).await?;
+        assert_eq!(q.status(), StatusCode::TOO_MANY_REQUESTS);
+        let body: ErrorResponse = wait_json(q).await;
+        assert!(body.message.starts_with("Search would use an estimated"));
+
+        let req = r#"{ "query" : { "term": { "test_text": "document" } }, "facets": { "test_facet": ["/cat"] }, "limit": 10 }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "test_index".into(), QueryOptions::default()).await?;
+        assert_eq!(q.status(), StatusCode::OK);
+        let body: SearchResults = wait_json(q).await;
+        assert_eq!(body.hits, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sorted_multi_index_search() -> ReturnUnit {
+        let temps = |values: &[i64]| -> Result<tantivy::Index, Error> {
+            let mut builder = tantivy::schema::SchemaBuilder::new();
+            let t = builder.add_i64_field("t", tantivy::schema::INDEXED | tantivy::schema::FAST | tantivy::schema::STORED);
+            let index = tantivy::Index::create_in_ram(builder.build());
+            let mut writer = index.writer(30_000_000)?;
+            for v in values {
+                writer.add_document(tantivy::doc!(t => *v));
+            }
+            writer.commit()?;
+            Ok(index)
+        };
+        let catalog = IndexCatalog::with_index("temps-a".into(), temps(&[-5, 10, 3])?)?;
+        catalog.add_index("temps-b".into(), temps(&[7, -20, 12])?)?;
+        catalog.add_index("logs".into(), text_index("text", &["rust"]))?;
+        let cat = Arc::new(catalog);
+
+        // Hits sorted by a field aren't scored, they're merged by the field's value instead
+        let req = r#"{ "query": { "match_all": {} }, "sort_by": "t", "limit": 4 }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "temps-*".into(), QueryOptions::default()).await?;
+        let results: SearchResults = wait_json(q).await;
+        let values: Vec<i64> = results.docs.iter().map(|d| d.doc["t"][0].i64_value()).collect();
+        assert_eq!(values, vec![12, 10, 7, 3]);
+
+        let req = r#"{ "query": { "match_all": {} }, "sort_by": "t", "limit": 2 }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "temps-a".into(), QueryOptions::default()).await?;
+        let results: SearchResults = wait_json(q).await;
+        let sorts: Vec<_> = results.docs.iter().map(|d| d.sort).collect();
+        assert_eq!(
+            sorts,
+            vec![Some(toshi_types::SortValue::I64(10)), Some(toshi_types::SortValue::I64(3))]
+        );
+
+        // Only fast integer fields can be sorted by
+        for field in &["text", "missing"] {
+            let req = format!(r#"{{ "query": {{ "match_all": {{}} }}, "sort_by": "{}" }}"#, field);
+            let q = doc_search(Arc::clone(&cat), Body::from(req), "logs".into(), QueryOptions::default()).await?;
+            assert_eq!(q.status(), StatusCode::BAD_REQUEST, "{}", field);
+        }
+        Ok(())
+    }
+
+    fn text_index(field: &str, texts: &[&str]) -> tantivy::Index {
+        let mut builder = tantivy::schema::SchemaBuilder::new();
+        let text = builder.add_text_field(field, tantivy::schema::TEXT | tantivy::schema::STORED);
+        let index = tantivy::Index::create_in_ram(builder.build());
+        let mut writer = index.writer(30_000_000).unwrap();
+        for t in texts {
+            writer.add_document(tantivy::doc!(text => *t));
+        }
+        writer.commit().unwrap();
+        index
+    }
+
+    #[tokio::test]
+    async fn test_multi_index_search() -> ReturnUnit {
+        let catalog = IndexCatalog::with_index("logs-a".into(), text_index("text", &["rust rust rust", "go"]))?;
+        catalog.add_index(
+            "logs-b".into(),
+            text_index("text", &["rust rust", "rust and a much longer go text"]),
+        )?;
+        catalog.add_index("logs-other".into(), text_index("title", &["rust"]))?;
+        let cat = Arc::new(catalog);
+
+        let req = r#"{ "query" : { "term" : { "text" : "rust" } }, "limit": 2 }"#;
+        for indexes in &["logs-a,logs-b,logs-other", "logs-*"] {
+            let q = doc_search(Arc::clone(&cat), Body::from(req), (*indexes).into(), QueryOptions::default()).await?;
+            let body: SearchResults = wait_json(q).await;
+            assert_eq!(body.hits, 2);
+            let texts: Vec<_> = body.docs.iter().map(|d| d.doc["text"][0].text().unwrap().to_string()).collect();
+            assert_eq!(texts, vec!["rust rust rust", "rust rust"]);
+            assert!(body.docs[0].score >= body.docs[1].score);
+        }
+
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "metrics-*".into(), QueryOptions::default()).await?;
+        assert_eq!(q.status(), StatusCode::OK);
+        assert_eq!(wait_json::<SearchResults>(q).await.hits, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_multi_index_score_normalization() -> ReturnUnit {
+        // Rust is rare in one index and in every document of the other, so the first scores far higher
+        let catalog = IndexCatalog::with_index(
+            "scores-rare".into(),
+            text_index("text", &["rust a1", "rust rust a2", "go", "go", "go", "go", "go", "go"]),
+        )?;
+        catalog.add_index(
+            "scores-common".into(),
+            text_index("text", &["rust b1", "rust rust b2", "rust c", "rust d", "rust e"]),
+        )?;
+        let cat = Arc::new(catalog);
+
+        let top = |normalization: &'static str| {
+            let cat = Arc::clone(&cat);
+            async move {
+                let req = format!(
+                    r#"{{ "query" : {{ "term" : {{ "text" : "rust" }} }}, "limit": 2, "score_normalization": "{}" }}"#,
+                    normalization
+                );
+                let q = doc_search(cat, Body::from(req), "scores-*".into(), QueryOptions::default()).await?;
+                let body: SearchResults = wait_json(q).await;
+                let mut texts: Vec<_> = body.docs.iter().map(|d| d.doc["text"][0].text().unwrap().to_string()).collect();
+                texts.sort();
+                Ok::<_, hyper::Error>(texts)
+            }
+        };
+        assert_eq!(top("none").await?, vec!["rust a1", "rust rust a2"]);
+        assert_eq!(top("minmax").await?, vec!["rust rust a2", "rust rust b2"]);
+        assert_eq!(top("zscore").await?, vec!["rust rust a2", "rust rust b2"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fold_results_normalizes_the_merged_shards() {
+        let shard = |scores: &[f32]| SearchResults::new(scores.iter().map(|s| ScoredDoc::new(Some(*s), BTreeMap::new())).collect());
+        // Each shard on its own would rescale its best hit to 1
+        let folded = fold_results(vec![shard(&[2.0, 4.0]), shard(&[6.0])], ScoreNormalization::MinMax);
+        let scores: Vec<_> = folded.docs.iter().map(|d| d.score.unwrap()).collect();
+        assert_eq!(scores, vec![1.0, 0.5, 0.0]);
+    }
+
+    #[tokio::test]
+    async fn test_script_score() -> ReturnUnit {
+        use tantivy::schema::{SchemaBuilder, FAST, STORED, TEXT};
+        let mut builder = SchemaBuilder::new();
+        let text = builder.add_text_field("text", TEXT | STORED);
+        let views = builder.add_u64_field("views", FAST | STORED);
+        let index = tantivy::Index::create_in_ram(builder.build());
+        let mut writer = index.writer(30_000_000).unwrap();
+        writer.add_document(tantivy::doc!(text => "rust rust rust", views => 0u64));
+        writer.add_document(tantivy::doc!(text => "rust and go", views => 1000u64));
+        writer.commit().unwrap();
+        let cat = Arc::new(IndexCatalog::with_index("scripted".into(), index)?);
+
+        let top = |script: Option<&'static str>| {
+            let cat = Arc::clone(&cat);
+            async move {
+                let script = script.map(|s| format!(r#", "script_score": "{}""#, s)).unwrap_or_default();
+                let req = format!(r#"{{ "query" : {{ "term" : {{ "text" : "rust" }} }}{} }}"#, script);
+                let q = doc_search(cat, Body::from(req), "scripted".into(), QueryOptions::default()).await?;
+                assert_eq!(q.status(), StatusCode::OK);
+                let body: SearchResults = wait_json(q).await;
+                Ok::<_, hyper::Error>(body.docs.iter().map(|d| d.doc["views"][0].u64_value()).collect::<Vec<_>>())
+            }
+        };
+        assert_eq!(top(None).await?, vec![0, 1000]);
+        // The unviewed document scores 0 once scaled by its views
+        assert_eq!(top(Some("score * log(views + 1)")).await?, vec![1000, 0]);
+
+        for bad in &["score * log(views + 1", "score * text", "system(1)"] {
+            let req = format!(r#"{{ "query" : {{ "term" : {{ "text" : "rust" }} }}, "script_score": "{}" }}"#, bad);
+            let q = doc_search(Arc::clone(&cat), Body::from(req), "scripted".into(), QueryOptions::default()).await?;
+            assert_eq!(q.status(), StatusCode::BAD_REQUEST);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_decay() -> ReturnUnit {
+        use tantivy::chrono::{DateTime, Utc};
+        use tantivy::schema::{SchemaBuilder, FAST, STORED, STRING, TEXT};
+        let date = |d: &str| DateTime::parse_from_rfc3339(d).unwrap().with_timezone(&Utc);
+        let mut builder = SchemaBuilder::new();
+        let name = builder.add_text_field("name", STRING | STORED);
+        let text = builder.add_text_field("text", TEXT | STORED);
+        let published = builder.add_date_field("published", FAST | STORED);
+        let index = tantivy::Index::create_in_ram(builder.build());
+        let mut writer = index.writer(30_000_000).unwrap();
+        writer.add_document(tantivy::doc!(name => "old", text => "rust guide", published => date("2020-01-01T00:00:00Z")));
+        writer.add_document(tantivy::doc!(name => "new", text => "rust guide", published => date("2020-05-25T00:00:00Z")));
+        writer.add_document(tantivy::doc!(name => "undated", text => "rust guide"));
+        writer.commit().unwrap();
+        let cat = Arc::new(IndexCatalog::with_index("dated".into(), index)?);
+
+        let decayed = |decay: &str| {
+            let req = format!(r#"{{ "query" : {{ "term" : {{ "text" : "rust" }} }}, "decay": {} }}"#, decay);
+            doc_search(Arc::clone(&cat), Body::from(req), "dated".into(), QueryOptions::default())
+        };
+        let decay = r#"{ "function": "gauss", "field": "published", "origin": "2020-06-01T00:00:00Z", "scale": "30d", "missing": 0.1 }"#;
+        let body: SearchResults = wait_json(decayed(decay).await?).await;
+        let names: Vec<_> = body.docs.iter().map(|d| d.doc["name"][0].text().unwrap().to_string()).collect();
+        // All three match the query equally well, the week old document loses little to the decay
+        assert_eq!(names, vec!["new", "undated", "old"]);
+        let ratio = body.docs[0].score.unwrap() / body.docs[1].score.unwrap();
+        assert!(ratio > 9.0 && ratio < 10.0, "{}", ratio);
+
+        // A search without a query matches everything and still keeps its decay
+        let req = format!(r#"{{ "decay": {} }}"#, decay);
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "dated".into(), QueryOptions::default()).await?;
+        let body: SearchResults = wait_json(q).await;
+        let names: Vec<_> = body.docs.iter().map(|d| d.doc["name"][0].text().unwrap().to_string()).collect();
+        assert_eq!(names, vec!["new", "undated", "old"]);
+
+        for bad in &[
+            r#"{ "function": "exp", "field": "name", "scale": "30d" }"#,
+            r#"{ "function": "exp", "field": "published", "scale": "thirty days" }"#,
+            r#"{ "function": "exp", "field": "published", "scale": "30d", "origin": "yesterday" }"#,
+        ] {
+            assert_eq!(decayed(bad).await?.status(), StatusCode::BAD_REQUEST, "{}", bad);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_highlight_offsets() -> ReturnUnit {
+        let index = text_index("text", &["héllo wörld, rust 日本 rust", "go"]);
+        let cat = Arc::new(IndexCatalog::with_index("highlighted".into(), index)?);
+        let req = r#"{ "query" : { "term" : { "text" : "rust" } }, "highlight": ["text"] }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "highlighted".into(), QueryOptions::default()).await?;
+        assert_eq!(q.status(), StatusCode::OK);
+        let body: SearchResults = wait_json(q).await;
+        let expected = vec![
+            toshi_types::Highlight { start: 13, end: 17 },
+            toshi_types::Highlight { start: 21, end: 25 },
+        ];
+        assert_eq!(body.docs[0].highlights["text"], vec![expected]);
+
+        let req = r#"{ "query" : { "term" : { "text" : "rust" } }, "highlight": ["nope"] }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "highlighted".into(), QueryOptions::default()).await?;
+        assert_eq!(q.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_default_operator() -> ReturnUnit {
+        let mut catalog = IndexCatalog::with_index("loose".into(), toshi_test::create_test_index())?;
+        catalog.settings.default_operator.insert("strict".into(), DefaultOperator::And);
+        catalog.add_index("strict".into(), toshi_test::create_test_index())?;
+        let cat = Arc::new(catalog);
+
+        let hits = |index: &'static str, operator: &'static str| {
+            let cat = Arc::clone(&cat);
+            async move {
+                let req = format!(r#"{{ "query" : {{ "raw": "test_text:document test_text:1" }}{} }}"#, operator);
+                let q = doc_search(cat, Body::from(req), index.into(), QueryOptions::default()).await?;
+                Ok::<_, hyper::Error>(wait_json::<SearchResults>(q).await.hits)
+            }
+        };
+        assert_eq!(hits("loose", "").await?, 3);
+        assert_eq!(hits("strict", "").await?, 1);
+        assert_eq!(hits("loose", r#", "default_operator": "and""#).await?, 1);
+        assert_eq!(hits("strict", r#", "default_operator": "or""#).await?, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_query_string_search() -> ReturnUnit {
+        let cat = create_test_catalog("test_index");
+        let search = |q: &str| {
+            let options = QueryOptions {
+                q: Some(q.into()),
+                df: Some("test_text".into()),
+                ..QueryOptions::default()
+            };
+            query_string_search(Arc::clone(&cat), "test_index".into(), options)
+        };
+        let q = search("document AND NOT test_text:1").await?;
+        assert_eq!(q.status(), StatusCode::OK);
+        let body: SearchResults = wait_json(q).await;
+        assert_eq!(body.hits, 2);
+        // Terms are lowercased by the field's tokenizer and an excluded term narrows down the ones next to it
+        let body: SearchResults = wait_json(search("DOCUMENT -test_text:1").await?).await;
+        assert_eq!(body.hits, 2);
+
+        let q = search("test_text:(document OR dockument~1").await?;
+        assert_eq!(q.status(), StatusCode::BAD_REQUEST);
+        let body: ErrorResponse = wait_json(q).await;
+        assert_eq!(
+            body.message,
+            "Error in query execution: 'Syntax error at character 34: missing ')''"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_clause_count() -> ReturnUnit {
+        let mut catalog = IndexCatalog::with_index("unused".into(), toshi_test::create_test_index())?;
+        catalog.settings.max_clause_count = 3;
+        catalog.add_index("test_index".into(), toshi_test::create_test_index())?;
+        let cat = Arc::new(catalog);
+
+        let term = r#"{ "term": { "test_text": "document" } }"#;
+        let search = |clauses: usize| {
+            let should = vec![term; clauses - 1].join(",");
+            let req = format!(
+                r#"{{ "query": {{ "bool": {{ "should": [ {} ], "must": [ {{ "bool": {{ "should": [ {} ] }} }} ] }} }} }}"#,
+                should, term
+            );
+            doc_search(Arc::clone(&cat), Body::from(req), "test_index".into(), QueryOptions::default())
+        };
+        // The nested boolean query and its own clause both count
+        assert_eq!(search(2).await?.status(), StatusCode::OK);
+        let q = search(3).await?;
+        assert_eq!(q.status(), StatusCode::BAD_REQUEST);
+        let body: ErrorResponse = wait_json(q).await;
+        assert_eq!(
+            body.message,
+            "Error in query execution: 'Query has 4 boolean clauses, more than the limit of 3'"
+        );
+
+        // Each term of a raw query is a clause of the boolean query it's parsed into
+        let raw = |terms: &str| {
+            let req = format!(r#"{{ "query": {{ "raw": "{}" }} }}"#, terms);
+            doc_search(Arc::clone(&cat), Body::from(req), "test_index".into(), QueryOptions::default())
+        };
+        assert_eq!(raw("test_text:a test_text:b test_text:c").await?.status(), StatusCode::OK);
+        assert_eq!(
+            raw("test_text:a test_text:b test_text:c test_text:d").await?.status(),
+            StatusCode::BAD_REQUEST
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_forbidden_query_type() -> ReturnUnit {
+        let mut catalog = IndexCatalog::with_index("unused".into(), toshi_test::create_test_index())?;
+        let types = crate::settings::QueryTypes {
+            deny: vec!["regex".into()],
+            ..Default::default()
+        };
+        catalog.settings.query_types.insert("test_index".into(), types);
+        catalog.add_index("test_index".into(), toshi_test::create_test_index())?;
+        let cat = Arc::new(catalog);
+
+        let req = r#"{ "query" : { "regex" : { "test_text" : "d[ou]{1}c[k]?ument" } } }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "test_index".into(), QueryOptions::default()).await?;
+        assert_eq!(q.status(), StatusCode::BAD_REQUEST);
+        let body: ErrorResponse = wait_json(q).await;
+        assert_eq!(body.message, "Query type 'regex' is not allowed on index 'test_index'");
+
+        let req = r#"{ "query" : { "term" : { "test_text" : "document" } } }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "test_index".into(), QueryOptions::default()).await?;
+        assert_eq!(q.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_query_synonyms() -> ReturnUnit {
+        use tantivy::schema::{SchemaBuilder, STORED, TEXT};
+        let mut builder = SchemaBuilder::new();
+        let text = builder.add_text_field("text", TEXT | STORED);
+        let index = tantivy::Index::create_in_ram(builder.build());
+        let mut writer = index.writer(30_000_000).unwrap();
+        writer.add_document(tantivy::doc!(text => "Moving to New York City"));
+        writer.add_document(tantivy::doc!(text => "A new city in York"));
+        writer.commit().unwrap();
+        drop(writer);
+
+        let mut catalog = IndexCatalog::with_index("unused".into(), toshi_test::create_test_index())?;
+        let group = vec!["nyc".to_string(), "new york city".to_string()];
+        catalog.settings.synonyms.insert("places".into(), vec![group]);
+        catalog.add_index("places".into(), index)?;
+        let cat = Arc::new(catalog);
+
+        let req = r#"{ "query": { "term": { "text": "nyc" } } }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "places".into(), QueryOptions::default()).await?;
+        let body: SearchResults = wait_json(q).await;
+        assert_eq!(body.hits, 1);
+        let found = serde_json::to_value(&body.docs[0].doc)?;
+        assert_eq!(found["text"][0], "Moving to New York City");
+
+        let q = normalize_search(Arc::clone(&cat), Body::from(req), "places".into()).await?;
+        let normalized: serde_json::Value = wait_json(q).await;
+        let should = &normalized["query"]["bool"]["should"];
+        assert_eq!(
+            should[0],
+            serde_json::json!({ "phrase": { "text": { "terms": ["new", "york", "city"] } } })
+        );
+        assert_eq!(should[1], serde_json::json!({ "term": { "text": "nyc" } }));
+
+        // Raw and query string searches are expanded too, whatever the case of their terms
+        let req = r#"{ "query": { "raw": "text:NYC" } }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "places".into(), QueryOptions::default()).await?;
+        let body: SearchResults = wait_json(q).await;
+        assert_eq!(body.hits, 1);
+        let options = QueryOptions {
+            q: Some("Nyc".into()),
+            df: Some("text".into()),
+            ..QueryOptions::default()
+        };
+        let body: SearchResults = wait_json(query_string_search(Arc::clone(&cat), "places".into(), options).await?).await;
+        assert_eq!(body.hits, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_date_histogram() -> ReturnUnit {
+        use tantivy::schema::{SchemaBuilder, FAST, STORED, TEXT};
+        let mut builder = SchemaBuilder::new();
+        let text = builder.add_text_field("text", TEXT | STORED);
+        let time = builder.add_date_field("time", FAST | STORED);
+        let index = tantivy::Index::create_in_ram(builder.build());
+        let mut writer = index.writer(30_000_000).unwrap();
+        for (stamp, body) in &[
+            ("2020-01-01T10:05:00Z", "login"),
+            ("2020-01-01T10:59:59Z", "login"),
+            ("2020-01-01T11:00:00Z", "logout"),
+            ("2020-01-01T13:30:00Z", "login"),
+        ] {
+            let stamp = tantivy::DateTime::from(tantivy::chrono::DateTime::parse_from_rfc3339(stamp)?);
+            writer.add_document(tantivy::doc!(text => *body, time => stamp));
+        }
+        writer.commit().unwrap();
+        let cat = Arc::new(IndexCatalog::with_index("events".into(), index)?);
+
+        let buckets = |query: &'static str, histogram: &'static str| {
+            let cat = Arc::clone(&cat);
+            async move {
+                let req = format!(
+                    r#"{{ "query": {}, "aggs": {{ "per_hour": {{ "date_histogram": {} }} }} }}"#,
+                    query, histogram
+                );
+                let q = doc_search(cat, Body::from(req), "events".into(), QueryOptions::default()).await?;
+                assert_eq!(q.status(), StatusCode::OK);
+                let body: SearchResults = wait_json(q).await;
+                let buckets: Vec<(String, u64)> = match &body.aggregations["per_hour"] {
+                    AggregationResult::Buckets { buckets } => buckets.iter().map(|b| (b.key_as_string.clone(), b.doc_count)).collect(),
+                    other => panic!("Expected buckets, got {:?}", other),
+                };
+                Ok::<_, hyper::Error>(buckets)
+            }
+        };
+        let all = r#"{ "match_all": {} }"#;
+        assert_eq!(
+            buckets(all, r#"{ "field": "time", "interval": "1h" }"#).await?,
+            vec![
+                ("2020-01-01T10:00:00+00:00".to_string(), 2),
+                ("2020-01-01T11:00:00+00:00".to_string(), 1),
+                ("2020-01-01T13:00:00+00:00".to_string(), 1),
+            ]
+        );
+        assert_eq!(
+            buckets(
+                all,
+                r#"{ "field": "time", "interval": "1h", "min_doc_count": 0, "time_zone": "+05:30" }"#
+            )
+            .await?,
+            vec![
+                ("2020-01-01T15:00:00+05:30".to_string(), 1),
+                ("2020-01-01T16:00:00+05:30".to_string(), 2),
+                ("2020-01-01T17:00:00+05:30".to_string(), 0),
+                ("2020-01-01T18:00:00+05:30".to_string(), 0),
+                ("2020-01-01T19:00:00+05:30".to_string(), 1),
+            ]
+        );
+        // Only the documents the query matches are counted
+        assert_eq!(
+            buckets(r#"{ "term": { "text": "logout" } }"#, r#"{ "field": "time", "interval": "1d" }"#).await?,
+            vec![("2020-01-01T00:00:00+00:00".to_string(), 1)]
+        );
+
+        let req = r#"{ "query": { "match_all": {} }, "aggs": { "bad": { "date_histogram": { "field": "text", "interval": "1h" } } } }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "events".into(), QueryOptions::default()).await?;
+        assert_eq!(q.status(), StatusCode::BAD_REQUEST);
+
+        // Seconds too close to the limits of an i64 to shift into the time zone are a bad request
+        let mut builder = SchemaBuilder::new();
+        let secs = builder.add_i64_field("secs", FAST | STORED);
+        let index = tantivy::Index::create_in_ram(builder.build());
+        let mut writer = index.writer(30_000_000).unwrap();
+        writer.add_document(tantivy::doc!(secs => i64::max_value()));
+        writer.commit().unwrap();
+        let cat = Arc::new(IndexCatalog::with_index("far".into(), index)?);
+        let req = r#"{ "query": { "match_all": {} }, "aggs": { "far": { "date_histogram": { "field": "secs", "interval": "1h", "time_zone": "+01:00" } } } }"#;
+        let q = doc_search(cat, Body::from(req), "far".into(), QueryOptions::default()).await?;
+        assert_eq!(q.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nested_aggregations() -> ReturnUnit {
+        use tantivy::schema::{SchemaBuilder, FAST, STORED};
+        let mut builder = SchemaBuilder::new();
+        let time = builder.add_date_field("time", FAST | STORED);
+        let price = builder.add_f64_field("price", FAST | STORED);
+        let index = tantivy::Index::create_in_ram(builder.build());
+        let mut writer = index.writer(30_000_000).unwrap();
+        for (stamp, amount) in &[
+            ("2020-03-01T09:00:00Z", 10.0),
+            ("2020-03-01T17:00:00Z", 20.0),
+            ("2020-03-01T23:59:59Z", 60.0),
+            ("2020-03-03T08:00:00Z", 5.5),
+        ] {
+            let stamp = tantivy::DateTime::from(tantivy::chrono::DateTime::parse_from_rfc3339(stamp)?);
+            writer.add_document(tantivy::doc!(time => stamp, price => *amount));
+        }
+        writer.commit().unwrap();
+        let cat = Arc::new(IndexCatalog::with_index("sales".into(), index)?);
+
+        let req = r#"{ "query": { "match_all": {} }, "aggs": { "daily": { "date_histogram": {
+            "field": "time", "interval": "1d", "min_doc_count": 0,
+            "aggs": { "avg_price": { "avg": { "field": "price" } }, "median": { "percentiles": { "field": "price", "percents": [50] } } }
+        } } } }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "sales".into(), QueryOptions::default()).await?;
+        assert_eq!(q.status(), StatusCode::OK);
+        let body: SearchResults = wait_json(q).await;
+        let buckets = match &body.aggregations["daily"] {
+            AggregationResult::Buckets { buckets } => buckets.clone(),
+            other => panic!("Expected buckets, got {:?}", other),
+        };
+        let averages: Vec<(u64, AggregationResult)> = buckets.iter().map(|b| (b.doc_count, b.aggregations["avg_price"].clone())).collect();
+        assert_eq!(
+            averages,
+            vec![
+                (
+                    3,
+                    AggregationResult::Average {
+                        value: Some(30.0),
+                        count: 3
+                    }
+                ),
+                (0, AggregationResult::Average { value: None, count: 0 }),
+                (
+                    1,
+                    AggregationResult::Average {
+                        value: Some(5.5),
+                        count: 1
+                    }
+                ),
+            ]
+        );
+        match &buckets[0].aggregations["median"] {
+            AggregationResult::Percentiles { values, .. } => assert_eq!(values[0].value, Some(20.0)),
+            other => panic!("Expected percentiles, got {:?}", other),
+        }
+
+        let req = r#"{ "query": { "match_all": {} }, "aggs": { "daily": { "date_histogram": {
+            "field": "time", "interval": "1d", "aggs": { "hourly": { "date_histogram": { "field": "time", "interval": "1h" } } }
+        } } } }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "sales".into(), QueryOptions::default()).await?;
+        assert_eq!(q.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_terms_aggregation() -> ReturnUnit {
+        use tantivy::schema::{SchemaBuilder, FAST, STORED, STRING};
+        let mut builder = SchemaBuilder::new();
+        let category = builder.add_text_field("category", STRING | STORED);
+        let price = builder.add_f64_field("price", FAST | STORED);
+        let index = tantivy::Index::create_in_ram(builder.build());
+        let mut writer = index.writer(30_000_000).unwrap();
+        for (name, amount) in &[
+            ("books", 10.0),
+            ("books", 20.0),
+            ("toys", 5.0),
+            ("food", 1.0),
+            ("books", 30.0),
+            ("toys", 15.0),
+        ] {
+            writer.add_document(tantivy::doc!(category => *name, price => *amount));
+        }
+        writer.commit().unwrap();
+        let cat = Arc::new(IndexCatalog::with_index("products".into(), index)?);
+
+        let req = r#"{ "query": { "bool": { "must": [ { "match_all": {} } ], "must_not": [ { "term": { "category": "toys" } } ] } },
+            "aggs": { "categories": { "terms": {
+            "field": "category", "size": 2, "aggs": { "avg_price": { "avg": { "field": "price" } } }
+        } } } }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "products".into(), QueryOptions::default()).await?;
+        assert_eq!(q.status(), StatusCode::OK);
+        let body: SearchResults = wait_json(q).await;
+        let terms: Vec<(String, u64, AggregationResult)> = match &body.aggregations["categories"] {
+            AggregationResult::Terms { terms } => terms
+                .iter()
+                .map(|t| (t.key.clone(), t.doc_count, t.aggregations["avg_price"].clone()))
+                .collect(),
+            other => panic!("Expected terms, got {:?}", other),
+        };
+        // Toys would be second, but only the documents the query matches are counted
+        let average = |value, count| AggregationResult::Average { value: Some(value), count };
+        assert_eq!(
+            terms,
+            vec![("books".into(), 3, average(20.0, 3)), ("food".into(), 1, average(1.0, 1))]
+        );
+
+        for bad in &[r#""field": "price""#, r#""field": "category", "size": 0"#] {
+            let req = format!(
+                r#"{{ "query": {{ "match_all": {{}} }}, "aggs": {{ "bad": {{ "terms": {{ {} }} }} }} }}"#,
+                bad
+            );
+            let q = doc_search(Arc::clone(&cat), Body::from(req), "products".into(), QueryOptions::default()).await?;
+            assert_eq!(q.status(), StatusCode::BAD_REQUEST);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_percentiles() -> ReturnUnit {
+        use tantivy::schema::{SchemaBuilder, FAST, STORED, TEXT};
+        let mut builder = SchemaBuilder::new();
+        let path = builder.add_text_field("path", TEXT | STORED);
+        let took = builder.add_f64_field("took", FAST | STORED);
+        let index = tantivy::Index::create_in_ram(builder.build());
+        let mut writer = index.writer(30_000_000).unwrap();
+        // Response times of 1 to 2,000 milliseconds, every one equally common
+        for i in 0..2_000u64 {
+            let ms = ((i * 7_919) % 2_000 + 1) as f64;
+            writer.add_document(tantivy::doc!(path => if i % 2 == 0 { "search" } else { "index" }, took => ms));
+        }
+        writer.commit().unwrap();
+        let cat = Arc::new(IndexCatalog::with_index("latency".into(), index)?);
+
+        let req = r#"{ "query": { "match_all": {} }, "limit": 1, "aggs": { "took": { "percentiles": { "field": "took", "percents": [50, 95] } } } }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "latency".into(), QueryOptions::default()).await?;
+        assert_eq!(q.status(), StatusCode::OK);
+        let body: SearchResults = wait_json(q).await;
+        match &body.aggregations["took"] {
+            AggregationResult::Percentiles { values, count, .. } => {
+                assert_eq!(*count, 2_000);
+                assert_eq!(values.iter().map(|v| v.percent).collect::<Vec<_>>(), vec![50.0, 95.0]);
+                for (value, exact) in values.iter().zip(&[1_000.5, 1_900.05]) {
+                    assert!((value.value.unwrap() - exact).abs() / exact < 0.01, "{:?}", value);
+                }
+            }
+            other => panic!("Expected percentiles, got {:?}", other),
+        }
+
+        for bad in &[r#""field": "path""#, r#""field": "took", "percents": [150]"#] {
+            let req = format!(
+                r#"{{ "query": {{ "match_all": {{}} }}, "aggs": {{ "bad": {{ "percentiles": {{ {} }} }} }} }}"#,
+                bad
+            );
+            let q = doc_search(Arc::clone(&cat), Body::from(req), "latency".into(), QueryOptions::default()).await?;
+            assert_eq!(q.status(), StatusCode::BAD_REQUEST);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cardinality() -> ReturnUnit {
+        use tantivy::schema::{SchemaBuilder, FAST, STORED, TEXT};
+        let mut builder = SchemaBuilder::new();
+        let path = builder.add_text_field("path", TEXT | STORED);
+        let user = builder.add_u64_field("user", FAST | STORED);
+        let index = tantivy::Index::create_in_ram(builder.build());
+        let mut writer = index.writer(30_000_000).unwrap();
+        // 20,000 requests from 5,000 users, every user making four of them
+        for i in 0..20_000u64 {
+            writer.add_document(tantivy::doc!(path => if i < 10_000 { "search" } else { "index" }, user => (i * 7_919) % 5_000));
+        }
+        writer.commit().unwrap();
+        let cat = Arc::new(IndexCatalog::with_index("requests".into(), index)?);
+
+        let count = |query: &'static str, precision: u8| {
+            let cat = Arc::clone(&cat);
+            async move {
+                let req = format!(
+                    r#"{{ "query": {}, "limit": 1, "aggs": {{ "users": {{ "cardinality": {{ "field": "user", "precision": {} }} }} }} }}"#,
+                    query, precision
+                );
+                let q = doc_search(cat, Body::from(req), "requests".into(), QueryOptions::default()).await?;
+                assert_eq!(q.status(), StatusCode::OK);
+                let body: SearchResults = wait_json(q).await;
+                let value = match body.aggregations["users"] {
+                    AggregationResult::Cardinality { value, .. } => value,
+                    ref other => panic!("Expected a count, got {:?}", other),
+                };
+                Ok::<_, hyper::Error>(value)
+            }
+        };
+        // Few enough users to count exactly at a precision of 16
+        assert_eq!(count(r#"{ "term": { "path": "search" } }"#, 16).await?, 5_000);
+        // At a precision of 10 the estimate's standard error is about 3%
+        let estimate = count(r#"{ "match_all": {} }"#, 10).await?;
+        assert!((estimate as f64 - 5_000.0).abs() / 5_000.0 < 0.13, "{}", estimate);
+
+        let req = r#"{ "query": { "match_all": {} }, "aggs": { "bad": { "cardinality": { "field": "user", "precision": 30 } } } }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "requests".into(), QueryOptions::default()).await?;
+        assert_eq!(q.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_range_aggregation() -> ReturnUnit {
+        use tantivy::schema::{SchemaBuilder, FAST, STORED, TEXT};
+        let mut builder = SchemaBuilder::new();
+        let name = builder.add_text_field("name", TEXT | STORED);
+        let price = builder.add_f64_field("price", FAST | STORED);
+        let index = tantivy::Index::create_in_ram(builder.build());
+        let mut writer = index.writer(30_000_000).unwrap();
+        for p in &[2.5, 9.99, 10.0, 25.0, 49.5, 50.0, 120.0] {
+            writer.add_document(tantivy::doc!(name => "item", price => *p));
+        }
+        writer.commit().unwrap();
+        let cat = Arc::new(IndexCatalog::with_index("prices".into(), index)?);
+
+        let req = r#"{ "query": { "match_all": {} }, "limit": 1, "aggs": { "bands": { "range": { "field": "price", "ranges": [{ "to": 10 }, { "from": 10, "to": 50 }, { "from": 50 }] } } } }"#;
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "prices".into(), QueryOptions::default()).await?;
+        assert_eq!(q.status(), StatusCode::OK);
+        let body: SearchResults = wait_json(q).await;
+        match &body.aggregations["bands"] {
+            AggregationResult::Ranges { ranges } => {
+                let counts: Vec<(Option<f64>, Option<f64>, u64)> = ranges.iter().map(|r| (r.from, r.to, r.doc_count)).collect();
+                assert_eq!(
+                    counts,
+                    vec![(None, Some(10.0), 2), (Some(10.0), Some(50.0), 3), (Some(50.0), None, 2)]
+                );
+            }
+            other => panic!("Expected ranges, got {:?}", other),
+        }
+
+        for bad in &[
+            r#""field": "name", "interval": 10"#,
+            r#""field": "price", "ranges": [{ "from": 50, "to": 10 }]"#,
+        ] {
+            let req = format!(
+                r#"{{ "query": {{ "match_all": {{}} }}, "aggs": {{ "bad": {{ "range": {{ {} }} }} }} }}"#,
+                bad
+            );
+            let q = doc_search(Arc::clone(&cat), Body::from(req), "prices".into(), QueryOptions::default()).await?;
+            assert_eq!(q.status(), StatusCode::BAD_REQUEST);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_post_filter() -> ReturnUnit {
+        use tantivy::schema::{Facet, SchemaBuilder, FAST, STORED, STRING, TEXT};
+        let mut builder = SchemaBuilder::new();
+        let name = builder.add_text_field("name", STRING | STORED);
+        let title = builder.add_text_field("title", TEXT | STORED);
+        let color = builder.add_text_field("color", STRING | STORED);
+        let brand = builder.add_facet_field("brand");
+        let price = builder.add_f64_field("price", FAST | STORED);
+        let index = tantivy::Index::create_in_ram(builder.build());
+        let mut writer = index.writer(30_000_000).unwrap();
+        for (n, t, c, b, p) in &[
+            ("a", "shoe", "red", "/brand/acme", 10.0),
+            ("b", "shoe shoe", "blue", "/brand/acme", 20.0),
+            ("c", "running shoe", "red", "/brand/zenith", 30.0),
+            ("d", "shoe shoe shoe", "red", "/brand/zenith", 60.0),
+            ("e", "shoe", "blue", "/brand/zenith", 70.0),
+        ] {
+            writer.add_document(tantivy::doc!(name => *n, title => *t, color => *c, brand => Facet::from(*b), price => *p));
+        }
+        writer.commit().unwrap();
+        let cat = Arc::new(IndexCatalog::with_index("shoes".into(), index)?);
+
+        let search = |post_filter: &'static str| {
+            let cat = Arc::clone(&cat);
+            async move {
+                let req = format!(
+                    r#"{{ "query": {{ "term": {{ "title": "shoe" }} }}, "facets": {{ "brand": ["/brand"] }},
+                        "aggs": {{ "bands": {{ "range": {{ "field": "price", "ranges": [{{ "to": 50 }}, {{ "from": 50 }}] }} }} }}
+                        {} }}"#,
+                    post_filter
+                );
+                let q = doc_search(cat, Body::from(req), "shoes".into(), QueryOptions::default()).await?;
+                assert_eq!(q.status(), StatusCode::OK);
+                Ok::<_, hyper::Error>(wait_json::<SearchResults>(q).await)
+            }
+        };
+        let broad = search("").await?;
+        let narrowed = search(r#", "post_filter": { "term": { "color": "red" } }"#).await?;
+
+        // The facet counts and aggregations still cover every shoe, the hits are only the red ones
+        let facets = |results: &SearchResults| results.facets.iter().map(|f| (f.field.clone(), f.value)).collect::<Vec<_>>();
+        assert_eq!(
+            facets(&narrowed),
+            vec![("/brand/acme".to_string(), 2), ("/brand/zenith".to_string(), 3)]
+        );
+        assert_eq!(facets(&narrowed), facets(&broad));
+        match &narrowed.aggregations["bands"] {
+            AggregationResult::Ranges { ranges } => assert_eq!(ranges.iter().map(|r| r.doc_count).collect::<Vec<_>>(), vec![3, 2]),
+            other => panic!("Expected ranges, got {:?}", other),
+        }
+        assert_eq!(broad.hits, 5);
+        assert_eq!(narrowed.hits, 3);
+
+        // Filtering doesn't change how the hits that are left score
+        let hits = |results: &SearchResults| {
+            results
+                .docs
+                .iter()
+                .map(|d| (d.doc["name"][0].text().unwrap().to_string(), d.score))
+                .collect::<Vec<_>>()
+        };
+        let kept: Vec<_> = hits(&broad)
+            .into_iter()
+            .filter(|(n, _)| ["a", "c", "d"].contains(&n.as_str()))
+            .collect();
+        assert_eq!(hits(&narrowed), kept);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_skip_source() -> ReturnUnit {
+        use tantivy::schema::{SchemaBuilder, STORED, STRING, TEXT};
+        use toshi_types::ID_FIELD;
+
+        let text = "a long stored body that takes a while to load and serialize ".repeat(50);
+        let mut builder = SchemaBuilder::new();
+        let body = builder.add_text_field("body", TEXT | STORED);
+        let index = tantivy::Index::create_in_ram(builder.build());
+        let mut writer = index.writer(30_000_000).unwrap();
+        for _ in 0..5_000 {
+            writer.add_document(tantivy::doc!(body => text.as_str()));
+        }
+        writer.commit().unwrap();
+        let catalog = IndexCatalog::with_index("docs".into(), index)?;
+
+        let mut builder = SchemaBuilder::new();
+        let id = builder.add_text_field(ID_FIELD, STRING | STORED);
+        let body = builder.add_text_field("body", TEXT | STORED);
+        let index = tantivy::Index::create_in_ram(builder.build());
+        let mut writer = index.writer(30_000_000).unwrap();
+        for i in 0..5_000 {
+            writer.add_document(tantivy::doc!(id => format!("doc-{}", i), body => text.as_str()));
+        }
+        writer.commit().unwrap();
+        catalog.add_index("ids".into(), index)?;
+        let cat = Arc::new(catalog);
+
+        let search = |name: &'static str, source: bool| {
+            let cat = Arc::clone(&cat);
+            async move {
+                let options = QueryOptions {
+                    profile: Some(true),
+                    source: Some(source),
+                    ..QueryOptions::default()
+                };
+                let req = r#"{ "query": { "match_all": {} }, "limit": 5000 }"#;
+                let q = doc_search(cat, Body::from(req), name.into(), options).await?;
+                assert_eq!(q.status(), StatusCode::OK);
+                Ok::<_, hyper::Error>(wait_json::<SearchResults>(q).await)
+            }
+        };
+        let with_source = search("docs", true).await?;
+        let without_source = search("docs", false).await?;
+        assert_eq!(without_source.docs.len(), 5_000);
+        for doc in &without_source.docs {
+            assert!(doc.doc.is_empty());
+            assert!(doc.score.is_some());
+            assert!(doc.id.is_none());
+        }
+        assert!(with_source.docs.iter().all(|d| d.doc.contains_key("body")));
+        let fetch_ms = |results: &SearchResults| results.profile.as_ref().unwrap().fetch_ms;
+        assert!(fetch_ms(&without_source) < fetch_ms(&with_source));
+
+        // Ids are read from the index's terms, so returning them still doesn't load the stored fields
+        let with_source = search("ids", true).await?;
+        let without_source = search("ids", false).await?;
+        assert_eq!(without_source.docs.len(), 5_000);
+        assert!(without_source.docs.iter().all(|d| d.doc.is_empty()));
+        let mut ids: Vec<String> = without_source.docs.iter().map(|d| d.id.clone().expect("Hit has its id")).collect();
+        ids.sort();
+        let mut expected: Vec<String> = (0..5_000).map(|i| format!("doc-{}", i)).collect();
+        expected.sort();
+        assert_eq!(ids, expected);
+        assert!(fetch_ms(&without_source) < fetch_ms(&with_source));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_changes_since() -> ReturnUnit {
+        use tantivy::schema::{SchemaBuilder, FAST, INDEXED, STORED, STRING};
+        use toshi_types::{ChangesResponse, SEQUENCE_FIELD, TOMBSTONE_FIELD};
+
+        let mut builder = SchemaBuilder::new();
+        builder.add_text_field("title", STRING | STORED);
+        builder.add_u64_field(SEQUENCE_FIELD, INDEXED | FAST);
+        builder.add_u64_field(TOMBSTONE_FIELD, INDEXED | FAST);
+        let index = tantivy::Index::create_in_ram(builder.build());
+        let cat = Arc::new(IndexCatalog::with_index("feed".into(), index)?);
+
+        let add = |title: &'static str| {
+            let cat = Arc::clone(&cat);
+            async move {
+                let doc = format!(r#"{{ "options": {{ "commit": true }}, "document": {{ "title": "{}" }} }}"#, title);
+                add_document(cat, Body::from(doc), "feed".into(), QueryOptions::default()).await
+            }
+        };
+        let feed = |since: u64| {
+            let cat = Arc::clone(&cat);
+            async move {
+                let options = QueryOptions {
+                    since: Some(since),
+                    ..QueryOptions::default()
+                };
+                let resp = changes(cat, "feed".into(), options).await?;
+                assert_eq!(resp.status(), StatusCode::OK);
+                Ok::<_, hyper::Error>(wait_json::<ChangesResponse>(resp).await)
+            }
+        };
+        let titles = |feed: &ChangesResponse| {
+            feed.changes
+                .iter()
+                .map(|c| (c.doc["title"][0].as_str().unwrap().to_string(), c.deleted))
+                .collect::<Vec<_>>()
+        };
+
+        add("first").await?;
+        add("second").await?;
+        let first_batch = feed(0).await?;
+        assert_eq!(titles(&first_batch), vec![("first".into(), false), ("second".into(), false)]);
+
+        add("third").await?;
+        let delete = r#"{ "options": { "commit": true }, "terms": { "title": "first" } }"#;
+        crate::handlers::delete_term(Arc::clone(&cat), Body::from(delete), "feed".into(), QueryOptions::default()).await?;
+        cat.get_index("feed")?.reload()?;
+        let second_batch = feed(first_batch.watermark).await?;
+        assert_eq!(titles(&second_batch), vec![("third".into(), false), ("first".into(), true)]);
+        assert!(second_batch.watermark > first_batch.watermark);
+
+        // Nothing committed since the last watermark leaves it where it was
+        let caught_up = feed(second_batch.watermark).await?;
+        assert!(caught_up.changes.is_empty());
+        assert_eq!(caught_up.watermark, second_batch.watermark);
+
+        let untracked = changes(create_test_catalog("test_index"), "test_index".into(), QueryOptions::default()).await?;
+        assert_eq!(untracked.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_search_bodies() -> ReturnUnit {
+        let cat = create_test_catalog("test_index");
+        let search = |body: &'static str| doc_search(Arc::clone(&cat), Body::from(body), "test_index".into(), QueryOptions::default());
+
+        for body in &["", "  \n", "{}"] {
+            let resp = search(body).await?;
+            assert_eq!(resp.status(), StatusCode::OK, "{:?}", body);
+            let results = wait_json::<SearchResults>(resp).await;
+            assert_eq!(results.hits, 5, "{:?}", body);
+        }
+        for body in &["{", "not json", r#"{ "limit": "ten" }"#] {
+            let resp = search(body).await?;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{:?}", body);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_single_node_shards() -> ReturnUnit {
+        let cat = create_test_catalog("test_index");
+        let q = all_docs(cat, "test_index".into()).await?;
+        let info = wait_json::<SearchResults>(q).await.shards.unwrap();
+        assert_eq!((info.total, info.successful, info.skipped, info.failed), (1, 1, 0, 0));
+        assert_eq!(info.shards.len(), 1);
+        assert_eq!(info.shards[0].index, "test_index");
+        assert_eq!(info.shards[0].shard, 0);
+        assert!(info.shards[0].error.is_none());
+        Ok(())
+    }
+
+    /// Collects the level and message of every event logged while it's the default subscriber
+    struct Captured(Arc<std::sync::Mutex<Vec<(Level, String)>>>);
+
+    struct Message(String);
+
+    impl tracing::field::Visit for Message {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                self.0 = format!("{:?}", value);
+            }
+        }
+    }
+
+    impl tracing::Subscriber for Captured {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+            span::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut message = Message(String::new());
+            event.record(&mut message);
+            self.0.lock().unwrap().push((*event.metadata().level(), message.0));
+        }
+
+        fn enter(&self, _: &span::Id) {}
+
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    #[tokio::test]
+    async fn test_slow_query_log() -> ReturnUnit {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let _guard = tracing::subscriber::set_default(Captured(Arc::clone(&events)));
+        let req = r#"{ "query" : { "term" : { "test_text" : "document" } } }"#;
+        let slow = |(level, message): &(Level, String)| *level == Level::WARN && message.starts_with("Slow query on test_index took");
+
+        let cat = create_test_catalog("test_index");
+        let q = doc_search(Arc::clone(&cat), Body::from(req), "test_index".into(), QueryOptions::default()).await?;
+        assert_eq!(q.status(), StatusCode::OK);
+        assert!(!events.lock().unwrap().iter().any(slow));
+
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.slow_query_threshold = Some(0);
+        let q = doc_search(Arc::new(catalog), Body::from(req), "test_index".into(), QueryOptions::default()).await?;
+        assert_eq!(q.status(), StatusCode::OK);
+        let events = events.lock().unwrap();
+        let warning = events.iter().find(|e| slow(e)).expect("No slow query warning was logged");
+        assert!(warning.1.ends_with(req));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ndjson_search() -> ReturnUnit {
+        let cat = create_test_catalog("test_index");
+        let req = r#"{ "query" : { "term" : { "test_text" : "document" } } }"#;
+        let q = doc_search_ndjson(Arc::clone(&cat), Body::from(req), "test_index".into(), QueryOptions::default()).await?;
+        assert_eq!(q.headers()[hyper::header::CONTENT_TYPE], "application/x-ndjson");
+        let bytes = hyper::body::to_bytes(q.into_body()).await?;
+        let body = std::str::from_utf8(&bytes)?;
+        assert!(body.ends_with('\n'));
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines.len(), 3);
+        for line in lines {
+            let doc: toshi_types::ScoredDoc<serde_json::Value> = serde_json::from_str(line)?;
+            assert!(doc.doc["test_text"][0].as_str().unwrap().contains("Document"));
+        }
+
+        // The hits before the page are dropped before any of them are loaded
+        let req = r#"{ "query" : { "term" : { "test_text" : "document" } }, "from": 1 }"#;
+        let q = doc_search_ndjson(Arc::clone(&cat), Body::from(req), "test_index".into(), QueryOptions::default()).await?;
+        let bytes = hyper::body::to_bytes(q.into_body()).await?;
+        assert_eq!(std::str::from_utf8(&bytes)?.lines().count(), 2);
+        Ok(())
+    }
 }
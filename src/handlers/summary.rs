@@ -1,57 +1,325 @@
+use std::collections::BTreeMap;
 use std::time::Instant;
 
 use hyper::{Body, Response, StatusCode};
 use tracing::*;
 
-use toshi_types::{Error, SummaryResponse};
+use toshi_types::{Error, FlushResponse, IndexListing, IndexState, PurgeResponse, RefreshResponse, SummaryResponse};
 
+use crate::cluster::routing::IndexHealth;
+use crate::commit::committed_at;
+use crate::handle::LocalIndex;
 use crate::handlers::ResponseFuture;
-use crate::index::SharedCatalog;
+use crate::index::{IndexCatalog, SharedCatalog};
 use crate::router::QueryOptions;
+use crate::settings::Settings;
 use crate::utils::{empty_with_code, with_body};
 
+fn summarize(index: &LocalIndex, options: &QueryOptions, settings: &Settings) -> Result<SummaryResponse, Error> {
+    let metas = index.get_index().load_metas()?;
+    let last_commit_time = committed_at(&metas);
+    let mut summary = if options.include_sizes() {
+        SummaryResponse::new(metas, Some(index.get_space()))
+    } else {
+        SummaryResponse::new(metas, None)
+    };
+    let (created_at, modified_at) = index.audit_times()?;
+    summary = summary
+        .with_last_commit_time(last_commit_time)
+        .with_audit_times(created_at, modified_at)
+        .with_optimize_threshold(f64::from(settings.optimize_threshold));
+    if options.include_settings() {
+        summary = summary.with_settings(serde_json::to_value(index.get_settings().redacted())?);
+    }
+    Ok(summary)
+}
+
+/// Every local index with its state, including the ones quarantined because they were corrupted
+/// when they were opened, sorted by name
+fn index_listings(catalog: &IndexCatalog) -> Result<Vec<IndexListing>, Error> {
+    let mut listings = Vec::new();
+    for (name, index) in catalog.get_collection() {
+        let (created_at, modified_at) = index.audit_times()?;
+        listings.push(IndexListing {
+            name,
+            state: IndexState::Open,
+            error: None,
+            created_at,
+            modified_at,
+        });
+    }
+    let errored = catalog.quarantined_indexes().into_iter().map(|(name, reason)| IndexListing {
+        name,
+        state: IndexState::Errored,
+        error: Some(reason),
+        created_at: None,
+        modified_at: None,
+    });
+    listings.extend(errored);
+    listings.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(listings)
+}
+
+pub async fn list_indexes(catalog: SharedCatalog) -> ResponseFuture {
+    match index_listings(&catalog) {
+        Ok(listings) => Ok(with_body(listings)),
+        Err(e) => Ok(Response::from(e)),
+    }
+}
+
+/// A byte count the way people read it, like `208b` or `1.5mb`
+fn human_size(bytes: usize) -> String {
+    const UNITS: [&str; 5] = ["b", "kb", "mb", "gb", "tb"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}b", bytes)
+    } else {
+        format!("{:.1}{}", size, UNITS[unit])
+    }
+}
+
+/// Lay rows out in columns as wide as their widest value
+fn columns(rows: &[Vec<String>]) -> String {
+    let widths: Vec<usize> = (0..rows.first().map_or(0, Vec::len))
+        .map(|c| rows.iter().map(|r| r[c].chars().count()).max().unwrap_or(0))
+        .collect();
+    let mut out = String::new();
+    for row in rows {
+        let cells: Vec<String> = row.iter().zip(&widths).map(|(v, w)| format!("{:<1$}", v, w)).collect();
+        out.push_str(cells.join(" ").trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// Every index as a line of plaintext columns, its name, health, live and deleted documents and
+/// size, like Elasticsearch's `_cat/indices`. `?v` adds a header line. An index without shards in a
+/// cluster is green, a quarantined one red and has no counts.
+pub async fn cat_indices(catalog: SharedCatalog, options: QueryOptions) -> ResponseFuture {
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    if options.verbose() {
+        rows.push(
+            ["index", "health", "docs", "deleted", "size"]
+                .iter()
+                .map(|h| h.to_string())
+                .collect(),
+        );
+    }
+    let listings = match index_listings(&catalog) {
+        Ok(v) => v,
+        Err(e) => return Ok(Response::from(e)),
+    };
+    for listing in listings {
+        let row = match (listing.state, catalog.get_index(&listing.name)) {
+            (IndexState::Open, Ok(index)) => {
+                let searcher = index.searcher();
+                let deleted: u64 = searcher.segment_readers().iter().map(|r| u64::from(r.num_deleted_docs())).sum();
+                let health = match catalog.get_routing(&listing.name).map(|r| r.health()) {
+                    Ok(IndexHealth::Yellow) => "yellow",
+                    Ok(IndexHealth::Red) => "red",
+                    _ => "green",
+                };
+                vec![
+                    listing.name,
+                    health.into(),
+                    searcher.num_docs().to_string(),
+                    deleted.to_string(),
+                    human_size(index.get_space().total()),
+                ]
+            }
+            _ => vec![listing.name, "red".into(), "-".into(), "-".into(), "-".into()],
+        };
+        rows.push(row);
+    }
+    Ok(Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(columns(&rows)))
+        .unwrap())
+}
+
 pub async fn index_summary(catalog: SharedCatalog, index: String, options: QueryOptions) -> ResponseFuture {
     let start = Instant::now();
     let span = span!(Level::INFO, "summary_handler", ?index, ?options);
     let _enter = span.enter();
 
-    let index_lock = catalog.lock().await;
-    if index_lock.exists(&index) {
-        let index = index_lock.get_index(&index).unwrap();
-        let metas = index.get_index().load_metas().unwrap();
-        let summary = if options.include_sizes() {
-            SummaryResponse::new(metas, Some(index.get_space()))
-        } else {
-            SummaryResponse::new(metas, None)
-        };
+    if IndexCatalog::is_index_pattern(&index) {
+        // Summaries of several indexes are keyed by index name, a pattern matching none gives an empty object
+        let summaries: Result<BTreeMap<String, SummaryResponse>, Error> = catalog.resolve_indexes(&index).and_then(|names| {
+            names
+                .into_iter()
+                .map(|name| Ok((name.clone(), summarize(&catalog.get_index(&name)?, &options, &catalog.settings)?)))
+                .collect()
+        });
         tracing::info!("Took: {:?}", start.elapsed());
-        Ok(with_body(summary))
+        match summaries {
+            Ok(s) => Ok(with_body(s)),
+            Err(e) => Ok(Response::from(e)),
+        }
     } else {
-        let err = Error::IOError(format!("Index {} does not exist", index));
-        let resp: Response<Body> = Response::from(err);
+        let summary = catalog
+            .get_index(&index)
+            .and_then(|index| summarize(&index, &options, &catalog.settings));
         tracing::info!("Took: {:?}", start.elapsed());
-        Ok(resp)
+        match summary {
+            Ok(s) => Ok(with_body(s)),
+            Err(e) => Ok(Response::from(e)),
+        }
     }
 }
 
 pub async fn flush(catalog: SharedCatalog, index: String) -> ResponseFuture {
     let span = span!(Level::INFO, "flush_handler", ?index);
     let _enter = span.enter();
-    let index_lock = catalog.lock().await;
-    if index_lock.exists(&index) {
-        let local_index = index_lock.get_index(&index).unwrap();
-        let writer = local_index.get_writer();
-        let mut write = writer.lock().await;
-
-        write.commit().unwrap();
-        info!("Successful commit: {}", index);
-        Ok(empty_with_code(StatusCode::OK))
+    if catalog.exists(&index) {
+        let local_index = match catalog.get_index(&index) {
+            Ok(v) => v,
+            Err(e) => return Ok(Response::from(e)),
+        };
+        if let Err(e) = local_index.check_writable() {
+            return Ok(Response::from(e));
+        }
+        match local_index.commit().await {
+            Ok(opstamp) => {
+                info!("Successful commit: {}, opstamp={}", index, opstamp);
+                Ok(with_body(FlushResponse { opstamp }))
+            }
+            Err(e) => {
+                error!("Could not commit index: {}, {:?}", index, e);
+                Ok(empty_with_code(StatusCode::INTERNAL_SERVER_ERROR))
+            }
+        }
     } else {
         error!("Could not find index: {}", index);
         Ok(empty_with_code(StatusCode::NOT_FOUND))
     }
 }
 
+pub async fn reload(catalog: SharedCatalog, index: String) -> ResponseFuture {
+    let span = span!(Level::INFO, "reload_handler", ?index);
+    let _enter = span.enter();
+    match catalog.get_index(&index) {
+        Ok(local_index) => match local_index.reload() {
+            Ok(_) => {
+                info!("Reloaded reader of {}", index);
+                Ok(empty_with_code(StatusCode::OK))
+            }
+            Err(e) => Ok(Response::from(e)),
+        },
+        Err(_) => Ok(empty_with_code(StatusCode::NOT_FOUND)),
+    }
+}
+
+/// Make every commit of the indexes a list of names or patterns refers to visible to searches,
+/// without committing anything new. Cheaper than a flush for a `Manual` or `Interval` reload
+/// policy where commits happen on their own.
+pub async fn refresh(catalog: SharedCatalog, indexes: String) -> ResponseFuture {
+    let span = span!(Level::INFO, "refresh_handler", ?indexes);
+    let _enter = span.enter();
+    let names = match catalog.resolve_indexes(&indexes) {
+        Ok(v) if v.is_empty() => return Ok(empty_with_code(StatusCode::NOT_FOUND)),
+        Ok(v) => v,
+        Err(e) => return Ok(Response::from(e)),
+    };
+    let mut response = RefreshResponse::default();
+    for name in names {
+        let local_index = match catalog.get_index(&name) {
+            Ok(v) => v,
+            Err(e) => return Ok(Response::from(e)),
+        };
+        if let Err(e) = local_index.reload() {
+            return Ok(Response::from(e));
+        }
+        info!("Refreshed reader of {}", name);
+        response.indexes.insert(name, local_index.num_docs());
+    }
+    Ok(with_body(response))
+}
+
+/// What an index's writer is busy with, see [`toshi_types::WriterStats`]
+pub async fn index_stats(catalog: SharedCatalog, index: String) -> ResponseFuture {
+    match catalog.get_index(&index) {
+        Ok(local_index) => match local_index.writer_stats() {
+            Ok(stats) => Ok(with_body(stats)),
+            Err(e) => Ok(Response::from(e)),
+        },
+        Err(_) => Ok(empty_with_code(StatusCode::NOT_FOUND)),
+    }
+}
+
+/// Merge every segment of an index into one, responding with the index's writer stats once the
+/// merge is done
+pub async fn merge(catalog: SharedCatalog, index: String) -> ResponseFuture {
+    let span = span!(Level::INFO, "merge_handler", ?index);
+    let _enter = span.enter();
+    let local_index = match catalog.get_index(&index) {
+        Ok(i) => i,
+        Err(_) => return Ok(empty_with_code(StatusCode::NOT_FOUND)),
+    };
+    let merged = match local_index.start_merge().await {
+        Ok(Some(merge)) => merge.await,
+        Ok(None) => Ok(()),
+        Err(e) => Err(e),
+    };
+    match merged.and_then(|_| local_index.writer_stats()) {
+        Ok(stats) => Ok(with_body(stats)),
+        Err(e) => Ok(Response::from(e)),
+    }
+}
+
+/// Remove soft deleted documents for good, only the ones deleted at least `older_than` seconds ago
+/// when it is given
+pub async fn purge(catalog: SharedCatalog, index: String, options: QueryOptions) -> ResponseFuture {
+    let span = span!(Level::INFO, "purge_handler", ?index);
+    let _enter = span.enter();
+    let local_index = match catalog.get_index(&index) {
+        Ok(i) => i,
+        Err(_) => return Ok(empty_with_code(StatusCode::NOT_FOUND)),
+    };
+    match local_index.purge(options.older_than()).await {
+        Ok(purged) => Ok(with_body(PurgeResponse { purged })),
+        Err(e) => Ok(Response::from(e)),
+    }
+}
+
+/// How many of a field's most frequent terms are returned by [`field_stats`]
+const TOP_TERMS: usize = 10;
+
+/// How many distinct terms a field of an index holds and which of them are found in the most
+/// documents, see [`toshi_types::FieldStats`]. A field that isn't indexed has no terms to look at.
+pub async fn field_stats(catalog: SharedCatalog, index: String, field: String) -> ResponseFuture {
+    let span = span!(Level::INFO, "field_stats_handler", ?index, ?field);
+    let _enter = span.enter();
+    let local_index = match catalog.get_index(&index) {
+        Ok(i) => i,
+        Err(_) => return Ok(empty_with_code(StatusCode::NOT_FOUND)),
+    };
+    match local_index.field_stats(&field, TOP_TERMS) {
+        Ok(stats) => Ok(with_body(stats)),
+        Err(e) => Ok(Response::from(e)),
+    }
+}
+
+/// Freeze an index so every write to it is rejected with `423 Locked`, or unfreeze it again
+pub async fn set_read_only(catalog: SharedCatalog, index: String, read_only: bool) -> ResponseFuture {
+    let span = span!(Level::INFO, "read_only_handler", ?index, read_only);
+    let _enter = span.enter();
+    match catalog.get_index(&index) {
+        Ok(local_index) => match local_index.set_read_only(read_only) {
+            Ok(_) => {
+                info!("Set {} read only: {}", index, read_only);
+                Ok(empty_with_code(StatusCode::OK))
+            }
+            Err(e) => Ok(Response::from(e)),
+        },
+        Err(_) => Ok(empty_with_code(StatusCode::NOT_FOUND)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::atomic::AtomicBool;
@@ -60,10 +328,19 @@ mod tests {
     use http::Request;
     use hyper::Body;
 
-    use toshi_test::{read_body, TestServer};
+    use tantivy::doc;
+    use tantivy::schema::{SchemaBuilder, FAST, INDEXED, STORED, STRING, TEXT};
+    use toshi_test::{cmp_float, read_body, wait_json, TestServer};
+    use toshi_types::{AddDocument, DeleteDoc, FlushResponse, Search, WriterStats, TOMBSTONE_FIELD};
 
+    use crate::handle::IndexHandle;
+    use crate::handlers::{add_document, all_docs, create_index, metrics};
     use crate::index::tests::create_test_catalog;
     use crate::router::Router;
+    use crate::settings::{CorruptionPolicy, DirectoryKind, ReaderReloadPolicy, Settings};
+    use crate::SearchResults;
+
+    use super::*;
 
     #[tokio::test]
     async fn get_summary_data() -> Result<(), Box<dyn std::error::Error>> {
@@ -75,4 +352,547 @@ mod tests {
         let _body = read_body(req).await?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_summary_missing_index() -> Result<(), Box<dyn std::error::Error>> {
+        let catalog = create_test_catalog("test_index");
+        let resp = index_summary(Arc::clone(&catalog), "missing".into(), QueryOptions::default()).await?;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body = wait_json::<toshi_types::ErrorResponse>(resp).await;
+        assert_eq!(body.code, Some(toshi_types::ErrorCode::IndexNotFound));
+        assert_eq!(body.message, "Unknown Index: 'missing' does not exist");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_summary_settings() -> Result<(), Box<dyn std::error::Error>> {
+        let catalog = create_test_catalog("test_index");
+        let options = QueryOptions {
+            include_settings: Some(true),
+            ..Default::default()
+        };
+        let resp = index_summary(Arc::clone(&catalog), "test_index".into(), options).await?;
+        let body = wait_json::<serde_json::Value>(resp).await;
+        assert_eq!(body["settings"]["writer_memory"], 200_000_000);
+        assert_eq!(body["settings"]["merge_policy"]["kind"], "log");
+        assert_eq!(body["settings"]["path"], Settings::REDACTED);
+        assert_eq!(body["settings"]["place_addr"], Settings::REDACTED);
+
+        let resp = index_summary(Arc::clone(&catalog), "test_index".into(), QueryOptions::default()).await?;
+        let body = wait_json::<serde_json::Value>(resp).await;
+        assert!(body.get("settings").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_flush_opstamp() -> Result<(), Box<dyn std::error::Error>> {
+        let catalog = create_test_catalog("test_index");
+        let doc = r#"{"document": {"test_text": "Babbaboo!", "test_u64": 10, "test_i64": -10}}"#;
+        add_document(Arc::clone(&catalog), Body::from(doc), "test_index".into(), QueryOptions::default()).await?;
+
+        let resp = flush(Arc::clone(&catalog), "test_index".into()).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let first = wait_json::<FlushResponse>(resp).await;
+
+        add_document(Arc::clone(&catalog), Body::from(doc), "test_index".into(), QueryOptions::default()).await?;
+        let second = wait_json::<FlushResponse>(flush(Arc::clone(&catalog), "test_index".into()).await?).await;
+        assert!(second.opstamp > first.opstamp);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_summary_last_commit() -> Result<(), Box<dyn std::error::Error>> {
+        let mut builder = SchemaBuilder::new();
+        builder.add_text_field("test_text", TEXT | STORED);
+        let catalog = Arc::new(IndexCatalog::with_index(
+            "fresh".into(),
+            tantivy::Index::create_in_ram(builder.build()),
+        )?);
+        let summary = |catalog: SharedCatalog| async move {
+            let resp = index_summary(catalog, "fresh".into(), QueryOptions::default()).await.unwrap();
+            wait_json::<serde_json::Value>(resp).await
+        };
+        let never = summary(Arc::clone(&catalog)).await;
+        assert!(never["last_commit_time"].is_null());
+        assert_eq!(never["last_opstamp"], 0);
+
+        let doc = r#"{"document": {"test_text": "Babbaboo!"}}"#;
+        add_document(Arc::clone(&catalog), Body::from(doc), "fresh".into(), QueryOptions::default()).await?;
+        flush(Arc::clone(&catalog), "fresh".into()).await?;
+        let first = summary(Arc::clone(&catalog)).await;
+        let first_time = first["last_commit_time"].as_u64().expect("a commit time");
+
+        tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+        add_document(Arc::clone(&catalog), Body::from(doc), "fresh".into(), QueryOptions::default()).await?;
+        flush(Arc::clone(&catalog), "fresh".into()).await?;
+        let second = summary(Arc::clone(&catalog)).await;
+        assert!(second["last_commit_time"].as_u64().unwrap() > first_time);
+        assert!(second["last_opstamp"].as_u64().unwrap() > first["last_opstamp"].as_u64().unwrap());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deleted_docs_ratio() -> Result<(), Box<dyn std::error::Error>> {
+        let mut builder = SchemaBuilder::new();
+        let tag = builder.add_text_field("tag", STRING | STORED);
+        let index = tantivy::Index::create_in_ram(builder.build());
+        let mut writer = index.writer(15_000_000).map_err(Error::from)?;
+        for t in &["keep", "keep", "drop", "gone"] {
+            writer.add_document(doc!(tag => *t));
+        }
+        writer.commit().map_err(Error::from)?;
+        drop(writer);
+        let mut catalog = IndexCatalog::with_index("deleted".into(), index)?;
+        catalog.settings.optimize_threshold = 0.3;
+        let catalog = Arc::new(catalog);
+        let handle = catalog.get_index("deleted")?;
+        let summary = |catalog: SharedCatalog| async move {
+            let resp = index_summary(catalog, "deleted".into(), QueryOptions::default()).await.unwrap();
+            wait_json::<serde_json::Value>(resp).await
+        };
+        let delete =
+            |t: &str| serde_json::from_str::<DeleteDoc>(&format!(r#"{{"options": {{"commit": true}}, "terms": {{"tag": "{}"}}}}"#, t));
+
+        let clean = summary(Arc::clone(&catalog)).await;
+        assert_eq!(clean["deleted_docs_ratio"], 0.0);
+        assert_eq!(clean["should_optimize"], false);
+
+        handle.delete_term(delete("drop")?).await?;
+        let below = summary(Arc::clone(&catalog)).await;
+        assert_eq!(below["deleted_docs_ratio"], 0.25);
+        assert_eq!(below["should_optimize"], false);
+
+        handle.delete_term(delete("gone")?).await?;
+        let above = summary(Arc::clone(&catalog)).await;
+        assert_eq!(above["deleted_docs_ratio"], 0.5);
+        assert_eq!(above["should_optimize"], true);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_commit_does_not_block_summary() -> Result<(), Box<dyn std::error::Error>> {
+        let mut builder = SchemaBuilder::new();
+        let text = builder.add_text_field("test_text", TEXT | STORED);
+        let catalog = Arc::new(IndexCatalog::with_index(
+            "slow".into(),
+            tantivy::Index::create_in_ram(builder.build()),
+        )?);
+        let handle = catalog.get_index("slow")?;
+        {
+            let writer = handle.get_writer();
+            let writer = writer.lock().await;
+            for i in 0..20_000 {
+                writer.add_document(doc!(text => format!("a document that takes a while to commit {}", i)));
+            }
+        }
+        handle.set_opstamp(20_000);
+
+        // Both run on this test's single thread, a commit blocking it would hold the summary up until it's done
+        let start = std::time::Instant::now();
+        let (flushed, summary_took) = tokio::join!(flush(Arc::clone(&catalog), "slow".into()), async {
+            let resp = index_summary(Arc::clone(&catalog), "slow".into(), QueryOptions::default()).await;
+            assert_eq!(resp.unwrap().status(), StatusCode::OK);
+            start.elapsed()
+        });
+        assert_eq!(flushed?.status(), StatusCode::OK);
+        assert!(
+            summary_took < std::time::Duration::from_millis(500),
+            "Summary took {:?} while committing",
+            summary_took
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_manual_reload() -> Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("manual_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.reader_reload_policy = ReaderReloadPolicy::Manual;
+        catalog.add_index("manual_index".into(), toshi_test::create_test_index())?;
+        let catalog = Arc::new(catalog);
+
+        let doc = r#"{"options": {"commit": true}, "document": {"test_text": "Babbaboo!", "test_u64": 10, "test_i64": -10}}"#;
+        add_document(
+            Arc::clone(&catalog),
+            Body::from(doc),
+            "manual_index".into(),
+            QueryOptions::default(),
+        )
+        .await?;
+        let hits = |resp| async { wait_json::<SearchResults>(resp).await.hits };
+        assert_eq!(hits(all_docs(Arc::clone(&catalog), "manual_index".into()).await?).await, 5);
+
+        let resp = reload(Arc::clone(&catalog), "manual_index".into()).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(hits(all_docs(Arc::clone(&catalog), "manual_index".into()).await?).await, 6);
+
+        let resp = reload(Arc::clone(&catalog), "missing".into()).await?;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refresh() -> Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("manual_a".into(), toshi_test::create_test_index())?;
+        catalog.settings.reader_reload_policy = ReaderReloadPolicy::Manual;
+        catalog.add_index("manual_a".into(), toshi_test::create_test_index())?;
+        catalog.add_index("manual_b".into(), toshi_test::create_test_index())?;
+        let catalog = Arc::new(catalog);
+
+        let doc = r#"{"options": {"commit": true}, "document": {"test_text": "Babbaboo!", "test_u64": 10, "test_i64": -10}}"#;
+        add_document(Arc::clone(&catalog), Body::from(doc), "manual_a".into(), QueryOptions::default()).await?;
+        let hits = |resp| async { wait_json::<SearchResults>(resp).await.hits };
+        assert_eq!(hits(all_docs(Arc::clone(&catalog), "manual_a".into()).await?).await, 5);
+
+        let resp = refresh(Arc::clone(&catalog), "manual_*".into()).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let refreshed = wait_json::<RefreshResponse>(resp).await;
+        assert_eq!(refreshed.indexes.get("manual_a"), Some(&6));
+        assert_eq!(refreshed.indexes.get("manual_b"), Some(&5));
+        assert_eq!(hits(all_docs(Arc::clone(&catalog), "manual_a".into()).await?).await, 6);
+
+        let resp = refresh(Arc::clone(&catalog), "missing".into()).await?;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_only() -> Result<(), Box<dyn std::error::Error>> {
+        let index = toshi_test::create_test_index();
+        let catalog = Arc::new(IndexCatalog::with_index("frozen".into(), index.clone())?);
+        let resp = set_read_only(Arc::clone(&catalog), "frozen".into(), true).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let doc = r#"{"document": {"test_text": "Babbaboo!", "test_u64": 10, "test_i64": -10}}"#;
+        let resp = add_document(Arc::clone(&catalog), Body::from(doc), "frozen".into(), QueryOptions::default()).await?;
+        assert_eq!(resp.status(), StatusCode::LOCKED);
+        assert_eq!(flush(Arc::clone(&catalog), "frozen".into()).await?.status(), StatusCode::LOCKED);
+        let resp = all_docs(Arc::clone(&catalog), "frozen".into()).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(wait_json::<SearchResults>(resp).await.hits, 5);
+
+        // The flag is kept with the index, so it is still frozen when it is opened again
+        catalog.clear().await;
+        catalog.add_index("frozen".into(), index)?;
+        assert!(catalog.get_index("frozen")?.is_read_only());
+        set_read_only(Arc::clone(&catalog), "frozen".into(), false).await?;
+        let resp = add_document(Arc::clone(&catalog), Body::from(doc), "frozen".into(), QueryOptions::default()).await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_audit_times() -> Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.directory_kind = DirectoryKind::Ram;
+        let catalog = Arc::new(catalog);
+        let schema = r#"[{ "name": "test_text", "type": "text", "options": { "indexing": { "record": "position", "tokenizer": "default" }, "stored": true } }]"#;
+        let resp = create_index(Arc::clone(&catalog), Body::from(schema), "audited".into(), QueryOptions::default()).await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let summary = |catalog: SharedCatalog| async move {
+            let resp = index_summary(catalog, "audited".into(), QueryOptions::default()).await.unwrap();
+            wait_json::<serde_json::Value>(resp).await
+        };
+        let created = summary(Arc::clone(&catalog)).await;
+        let created_at = created["created_at"].as_u64().expect("a creation time");
+        assert_eq!(created["modified_at"].as_u64(), Some(created_at));
+        let listings: Vec<IndexListing> = wait_json(list_indexes(Arc::clone(&catalog)).await?).await;
+        let listing = listings.iter().find(|l| l.name == "audited").unwrap();
+        assert_eq!(listing.created_at, Some(created_at));
+        // Indexes that were added without being created through Toshi have no times
+        assert_eq!(listings.iter().find(|l| l.name == "test_index").unwrap().created_at, None);
+
+        // Every change is recorded as later than the last, however soon after it comes
+        set_read_only(Arc::clone(&catalog), "audited".into(), true).await?;
+        let modified = summary(Arc::clone(&catalog)).await;
+        assert_eq!(modified["created_at"].as_u64(), Some(created_at));
+        assert!(modified["modified_at"].as_u64().unwrap() > created_at);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_quarantine_corrupted_index() -> Result<(), Box<dyn std::error::Error>> {
+        let base = std::env::temp_dir().join(format!("toshi-quarantine-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&base)?;
+        for name in &["healthy", "damaged"] {
+            let mut builder = SchemaBuilder::new();
+            let body = builder.add_text_field("body", TEXT);
+            let index = IndexCatalog::create_from_managed(base.clone(), name, builder.build(), DirectoryKind::Mmap)?;
+            let mut writer = index.writer(15_000_000).map_err(Error::from)?;
+            for text in &["first commit", "second commit"] {
+                writer.add_document(doc!(body => *text));
+                writer.commit().map_err(Error::from)?;
+            }
+        }
+        // Lose the files of one of the damaged index's two segments
+        let index = IndexCatalog::load_index(&base.join("damaged").to_string_lossy())?;
+        let lost = index.searchable_segment_ids().map_err(Error::from)?[0].uuid_string();
+        for file in std::fs::read_dir(base.join("damaged"))? {
+            let file = file?;
+            if file.file_name().to_string_lossy().starts_with(&lost) {
+                std::fs::remove_file(file.path())?;
+            }
+        }
+
+        let quarantined = Arc::new(IndexCatalog::new(base.clone(), Settings::default())?);
+        let listings: Vec<IndexListing> = wait_json(list_indexes(Arc::clone(&quarantined)).await?).await;
+        let healthy = wait_json::<SearchResults>(all_docs(Arc::clone(&quarantined), "healthy".into()).await?).await;
+        let damaged = all_docs(Arc::clone(&quarantined), "damaged".into()).await?.status();
+        let lookup = quarantined.get_index("damaged").err();
+        // A quarantined index's name stays taken, so its files aren't created over
+        let recreated = quarantined
+            .create_local_index("damaged", SchemaBuilder::new().build(), |_| Ok(()))
+            .await
+            .err();
+        drop(quarantined);
+
+        let settings = Settings {
+            on_corruption: CorruptionPolicy::Truncate,
+            ..Settings::default()
+        };
+        let truncated = Arc::new(IndexCatalog::new(base.clone(), settings)?);
+        let kept = wait_json::<SearchResults>(all_docs(Arc::clone(&truncated), "damaged".into()).await?).await;
+        let truncated_listings: Vec<IndexListing> = wait_json(list_indexes(Arc::clone(&truncated)).await?).await;
+        drop(truncated);
+        remove_dir_all::remove_dir_all(&base)?;
+
+        assert_eq!(listings.len(), 2);
+        assert_eq!((listings[0].name.as_str(), listings[0].state), ("damaged", IndexState::Errored));
+        assert!(listings[0].error.as_ref().unwrap().contains("unreadable"));
+        assert_eq!((listings[1].name.as_str(), listings[1].state), ("healthy", IndexState::Open));
+        assert_eq!(healthy.hits, 2);
+        assert_eq!(damaged, StatusCode::NOT_FOUND);
+        match lookup {
+            Some(Error::Quarantined { index, .. }) => assert_eq!(index, "damaged"),
+            other => panic!("Expected the index to be quarantined, got {:?}", other.map(|e| e.to_string())),
+        }
+        assert!(matches!(recreated, Some(Error::IndexExists(_))));
+
+        assert!(truncated_listings.iter().all(|l| l.state == IndexState::Open));
+        assert_eq!(kept.hits, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cat_indices() -> Result<(), Box<dyn std::error::Error>> {
+        let catalog = create_test_catalog("test_index");
+        let resp = cat_indices(Arc::clone(&catalog), QueryOptions::default()).await?;
+        assert_eq!(resp.headers()[hyper::header::CONTENT_TYPE], "text/plain; charset=utf-8");
+        let body = read_body(resp).await?;
+        let row: Vec<&str> = body
+            .lines()
+            .find(|l| l.starts_with("test_index"))
+            .unwrap()
+            .split_whitespace()
+            .collect();
+        assert_eq!(&row[..4], &["test_index", "green", "5", "0"]);
+        assert!(!body.contains("health"));
+
+        let options = QueryOptions {
+            v: Some(String::new()),
+            ..Default::default()
+        };
+        let body = read_body(cat_indices(catalog, options).await?).await?;
+        let lines: Vec<&str> = body.lines().collect();
+        assert!(lines[0].starts_with("index"));
+        // Every column starts at the same place on every line
+        assert_eq!(lines[0].find("health"), lines[1].find("green"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_summary_pattern() -> Result<(), Box<dyn std::error::Error>> {
+        let catalog = IndexCatalog::with_index("logs-2024-01".into(), toshi_test::create_test_index())?;
+        catalog.add_index("logs-2024-02".into(), toshi_test::create_test_index())?;
+        catalog.add_index("old-logs-2024-03".into(), toshi_test::create_test_index())?;
+        let catalog = Arc::new(catalog);
+
+        let resp = index_summary(Arc::clone(&catalog), "logs-2024-*".into(), QueryOptions::default()).await?;
+        let body = wait_json::<serde_json::Map<String, serde_json::Value>>(resp).await;
+        let names: Vec<_> = body.keys().collect();
+        assert_eq!(names, vec!["logs-2024-01", "logs-2024-02"]);
+
+        let resp = index_summary(Arc::clone(&catalog), "metrics-*".into(), QueryOptions::default()).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(wait_json::<serde_json::Map<String, serde_json::Value>>(resp).await.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_field_stats() -> Result<(), Box<dyn std::error::Error>> {
+        let mut builder = SchemaBuilder::new();
+        let tag = builder.add_text_field("tag", STRING | STORED);
+        let count = builder.add_i64_field("count", INDEXED);
+        let note = builder.add_text_field("note", STORED);
+        let index = tantivy::Index::create_in_ram(builder.build());
+        let mut writer = index.writer(15_000_000).map_err(Error::from)?;
+        for (t, c) in &[("rust", -1), ("rust", -1), ("rust", 2), ("go", 2), ("go", 3), ("java", 3)] {
+            writer.add_document(doc!(tag => *t, count => *c as i64, note => "unindexed"));
+        }
+        writer.commit().map_err(Error::from)?;
+        drop(writer);
+        let catalog = Arc::new(IndexCatalog::with_index("stats".into(), index)?);
+
+        let resp = field_stats(Arc::clone(&catalog), "stats".into(), "tag".into()).await?;
+        let stats: toshi_types::FieldStats = wait_json(resp).await;
+        assert_eq!(stats.distinct_terms, 3);
+        let ranked: Vec<(&str, u64)> = stats.top_terms.iter().map(|t| (t.term.as_str(), t.doc_freq)).collect();
+        assert_eq!(ranked, vec![("rust", 3), ("go", 2), ("java", 1)]);
+
+        let resp = field_stats(Arc::clone(&catalog), "stats".into(), "count".into()).await?;
+        let stats: toshi_types::FieldStats = wait_json(resp).await;
+        assert_eq!(stats.top_terms[0].term, "-1");
+
+        let resp = field_stats(Arc::clone(&catalog), "stats".into(), "note".into()).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = field_stats(Arc::clone(&catalog), "missing".into(), "tag".into()).await?;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_soft_delete_and_purge() -> Result<(), Box<dyn std::error::Error>> {
+        let mut builder = SchemaBuilder::new();
+        let id = builder.add_text_field("id", STRING | STORED);
+        let body = builder.add_text_field("body", TEXT | STORED);
+        builder.add_u64_field(TOMBSTONE_FIELD, INDEXED | FAST);
+        let index = tantivy::Index::create_in_ram(builder.build());
+        let mut writer = index.writer(15_000_000).map_err(Error::from)?;
+        writer.add_document(doc!(id => "a", body => "keep me around"));
+        writer.add_document(doc!(id => "b", body => "throw me away"));
+        writer.commit().map_err(Error::from)?;
+        drop(writer);
+        let catalog = Arc::new(IndexCatalog::with_index("soft".into(), index)?);
+        let handle = catalog.get_index("soft")?;
+        let search = |include_deleted: bool| {
+            let handle = handle.clone();
+            async move {
+                handle.reload()?;
+                let search = Search {
+                    include_deleted,
+                    ..Search::all_docs()
+                };
+                handle.search_index(search).await.map(|r| r.hits)
+            }
+        };
+
+        let delete: DeleteDoc = serde_json::from_str(r#"{"options": {"commit": true}, "terms": {"id": "b"}}"#)?;
+        assert_eq!(handle.delete_term(delete).await?.docs_affected, 1);
+        assert_eq!(search(false).await?, 1);
+        assert_eq!(search(true).await?, 2);
+
+        // A document that was never committed is committed before it's soft deleted, so it's kept too
+        let add: AddDocument<serde_json::Value> = serde_json::from_str(r#"{"document": {"id": "c", "body": "added just now"}}"#)?;
+        handle.add_document(add).await?;
+        let delete: DeleteDoc = serde_json::from_str(r#"{"options": {"commit": true}, "terms": {"id": "c"}}"#)?;
+        assert_eq!(handle.delete_term(delete).await?.docs_affected, 1);
+        assert_eq!(search(false).await?, 1);
+        assert_eq!(search(true).await?, 3);
+
+        // Deleted too recently to be purged yet
+        let options = QueryOptions {
+            older_than: Some(3600),
+            ..QueryOptions::default()
+        };
+        let resp = purge(Arc::clone(&catalog), "soft".into(), options).await?;
+        assert_eq!(wait_json::<PurgeResponse>(resp).await.purged, 0);
+        assert_eq!(search(true).await?, 3);
+
+        let resp = purge(Arc::clone(&catalog), "soft".into(), QueryOptions::default()).await?;
+        assert_eq!(wait_json::<PurgeResponse>(resp).await.purged, 2);
+        assert_eq!(search(true).await?, 1);
+        assert_eq!(search(false).await?, 1);
+        let metas = handle.get_index().searchable_segment_metas().map_err(Error::from)?;
+        assert_eq!(metas.iter().map(|m| m.num_deleted_docs()).sum::<u32>(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_soft_delete_unstored_field() -> Result<(), Box<dyn std::error::Error>> {
+        let mut builder = SchemaBuilder::new();
+        let id = builder.add_text_field("id", STRING | STORED);
+        let body = builder.add_text_field("body", TEXT);
+        builder.add_u64_field(TOMBSTONE_FIELD, INDEXED | FAST);
+        let index = tantivy::Index::create_in_ram(builder.build());
+        let mut writer = index.writer(15_000_000).map_err(Error::from)?;
+        writer.add_document(doc!(id => "a", body => "only indexed"));
+        writer.commit().map_err(Error::from)?;
+        drop(writer);
+        let catalog = Arc::new(IndexCatalog::with_index("unstored".into(), index)?);
+        let handle = catalog.get_index("unstored")?;
+
+        // The copy would lose the body, so nothing is deleted
+        let delete: DeleteDoc = serde_json::from_str(r#"{"options": {"commit": true}, "terms": {"id": "a"}}"#)?;
+        match handle.delete_term(delete).await {
+            Err(Error::QueryError(e)) => assert!(e.contains("body"), "{}", e),
+            other => panic!("Expected a query error, got {:?}", other),
+        }
+        handle.reload()?;
+        assert_eq!(handle.search_index(Search::all_docs()).await?.hits, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merge_gauges() -> Result<(), Box<dyn std::error::Error>> {
+        let index = toshi_test::create_test_index();
+        let mut writer = index.writer(30_000_000).map_err(Error::from)?;
+        let text = index.schema().get_field("test_text").unwrap();
+        for i in 0..3 {
+            writer.add_document(tantivy::doc!(text => format!("segment {}", i)));
+            writer.commit().map_err(Error::from)?;
+        }
+        drop(writer);
+        let catalog = Arc::new(IndexCatalog::with_index("merging".into(), index)?);
+        let stats = |resp| async { wait_json::<WriterStats>(resp).await };
+
+        let before = stats(index_stats(Arc::clone(&catalog), "merging".into()).await?).await;
+        assert_eq!(before.active_merges, 0);
+        assert!(before.pending_segments >= 4);
+
+        // A merge that hasn't finished yet, its segments are still part of the index
+        let handle = catalog.get_index("merging")?;
+        let segments = handle.get_index().searchable_segment_ids().map_err(Error::from)?;
+        handle.merge_tracker().started(segments.clone());
+        let during = stats(index_stats(Arc::clone(&catalog), "merging".into()).await?).await;
+        assert_eq!(during.active_merges, 1);
+        assert_eq!(during.pending_segments, 0);
+        let body = read_body(metrics(Arc::clone(&catalog)).await?).await?;
+        assert!(body.contains("toshi_active_merges{index=\"merging\"} 1"));
+        handle.merge_tracker().failed(&segments);
+
+        // Once a merge is done its segments are gone and it's no longer counted
+        let merge = handle.start_merge().await?.expect("There are segments to merge");
+        merge.await?;
+        let after = stats(index_stats(Arc::clone(&catalog), "merging".into()).await?).await;
+        assert_eq!(
+            after,
+            WriterStats {
+                pending_segments: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            index_stats(Arc::clone(&catalog), "missing".into()).await?.status(),
+            StatusCode::NOT_FOUND
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_query_rates() -> Result<(), Box<dyn std::error::Error>> {
+        let catalog = create_test_catalog("test_index");
+        for _ in 0..30 {
+            all_docs(Arc::clone(&catalog), "test_index".into()).await?;
+        }
+        let doc = r#"{ "options": { "commit": true }, "document": { "test_text": "Babbaboo!", "test_u64": 10, "test_i64": -10, "test_unindex": "yes" } }"#;
+        add_document(Arc::clone(&catalog), Body::from(doc), "test_index".into(), QueryOptions::default()).await?;
+
+        let stats = wait_json::<WriterStats>(index_stats(Arc::clone(&catalog), "test_index".into()).await?).await;
+        assert!(cmp_float(stats.rates.queries_per_sec_1m as f32, 0.5));
+        assert!(cmp_float(stats.rates.queries_per_sec_5m as f32, 0.1));
+        assert!(cmp_float(stats.rates.docs_per_sec_1m as f32, 1.0 / 60.0));
+        let body = read_body(metrics(Arc::clone(&catalog)).await?).await?;
+        assert!(body.contains("toshi_queries_per_second_1m{index=\"test_index\"} 0.5"));
+        Ok(())
+    }
 }
@@ -0,0 +1,89 @@
+use hyper::{Response, StatusCode};
+
+use toshi_types::Error;
+
+use crate::handlers::ResponseFuture;
+use crate::index::SharedCatalog;
+use crate::utils::{error_response, with_body};
+
+/// The state, progress and any error of a background task started with `async=true`
+pub async fn task_status(catalog: SharedCatalog, task_id: String) -> ResponseFuture {
+    let id = match task_id.parse::<u64>() {
+        Ok(id) => id,
+        Err(_) => return Ok(Response::from(Error::QueryError(format!("Invalid task id: {}", task_id)))),
+    };
+    match catalog.tasks().status(id) {
+        Ok(Some(status)) => Ok(with_body(status)),
+        // Tasks have no error code of their own, an unknown one is still not found
+        Ok(None) => Ok(error_response(
+            StatusCode::NOT_FOUND,
+            Error::QueryError(format!("Unknown task: {}", id)),
+        )),
+        Err(e) => Ok(Response::from(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use hyper::Body;
+    use tantivy::Index;
+    use tokio::time;
+
+    use toshi_test::wait_json;
+    use toshi_types::{TaskStarted, TaskState, TaskStatus};
+
+    use crate::handlers::{all_docs, reindex};
+    use crate::index::IndexCatalog;
+    use crate::router::QueryOptions;
+    use crate::SearchResults;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_async_reindex() -> Result<(), Box<dyn std::error::Error>> {
+        let source = toshi_test::create_test_index();
+        let catalog = IndexCatalog::with_index("source".into(), source.clone())?;
+        catalog.add_index("dest".into(), Index::create_in_ram(source.schema()))?;
+        let catalog = Arc::new(catalog);
+
+        let options = || QueryOptions {
+            run_async: Some(true),
+            ..QueryOptions::default()
+        };
+        // A bad rate is turned away before a task is started for it
+        let body = Body::from(r#"{"source": "source", "requests_per_second": 0}"#);
+        let resp = reindex(Arc::clone(&catalog), body, "dest".into(), options()).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let body = Body::from(r#"{"source": "source"}"#);
+        let resp = reindex(Arc::clone(&catalog), body, "dest".into(), options()).await?;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let TaskStarted { task } = wait_json(resp).await;
+
+        let status = loop {
+            let resp = task_status(Arc::clone(&catalog), task.to_string()).await?;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let status: TaskStatus = wait_json(resp).await;
+            if status.state != TaskState::Running {
+                break status;
+            }
+            time::delay_for(Duration::from_millis(10)).await;
+        };
+        assert_eq!(
+            (status.kind.as_str(), status.state, status.error),
+            ("reindex", TaskState::Done, None)
+        );
+        assert_eq!(status.progress, 100.0);
+
+        let results: SearchResults = wait_json(all_docs(Arc::clone(&catalog), "dest".into()).await?).await;
+        assert_eq!(results.hits, 5);
+
+        let missing = task_status(Arc::clone(&catalog), (task + 1).to_string()).await?;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+        assert_eq!(task_status(catalog, "nope".into()).await?.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+}
@@ -1,34 +1,71 @@
 use std::clone::Clone;
 use std::fs;
 use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use http::uri::Scheme;
 use http::Uri;
-use tantivy::directory::MmapDirectory;
-use tantivy::schema::Schema;
-use tantivy::Index;
+use tantivy::directory::Directory;
+use tantivy::schema::{FieldEntry, FieldType, Schema};
+use tantivy::{Index, LeasedItem, Searcher, SegmentId, SegmentReader};
 use tokio::sync::Mutex;
 use tonic::Status;
 
 use toshi_proto::cluster_rpc::*;
 use toshi_types::{DeleteDoc, DocsAffected, Error, Search};
 
+use crate::blocking::BlockingPool;
+use crate::cancel::CancelToken;
+use crate::cluster::codec::{FormatCache, WireFormat};
+use crate::cluster::placement::{NodeLoad, Placement};
 use crate::cluster::remote_handle::RemoteIndex;
+use crate::cluster::routing::{IndexHealth, IndexRouting, PartitionTable, ReadPreference};
 use crate::cluster::rpc_server::{RpcClient, RpcServer};
 use crate::handle::{IndexHandle, LocalIndex};
-use crate::settings::Settings;
-use crate::{AddDocument, Result, SearchResults};
+use crate::settings::{CorruptionPolicy, DirectoryKind, Settings, UnknownFields};
+use crate::storage::{LocalStorage, StorageBackend};
+use crate::tasks::TaskRegistry;
+use crate::{AddDocument, Result, SearchResults, TypedSearchResults};
 
-pub type SharedCatalog = Arc<tokio::sync::Mutex<IndexCatalog>>;
+pub type SharedCatalog = Arc<IndexCatalog>;
 
+/// The file tantivy keeps the segments of an index's last commit in
+pub(crate) const META_FILE: &str = "meta.json";
+
+/// The catalog only locks its map of handles long enough to register or look up an index, the
+/// handles themselves are cheap to clone and carry their own writer lock. This lets operations on
+/// different indexes, and any number of reads on the same index, proceed in parallel.
 pub struct IndexCatalog {
     pub settings: Settings,
     base_path: PathBuf,
-    local_handles: HashMap<String, LocalIndex>,
+    local_handles: RwLock<HashMap<String, LocalIndex>>,
     remote_handles: Arc<Mutex<HashMap<String, RemoteIndex>>>,
+    /// The replicas of each remote index by node, kept apart from its shards so writes are only
+    /// copied to them rather than spread over them
+    replica_handles: Mutex<HashMap<String, HashMap<String, RemoteIndex>>>,
+    wire_formats: FormatCache,
+    placement: std::sync::Mutex<Box<dyn Placement + Send>>,
+    routing: RwLock<HashMap<String, IndexRouting>>,
+    /// Indexes found corrupted when they were opened, keyed by name, with what was wrong with them
+    quarantined: RwLock<HashMap<String, String>>,
+    tasks: TaskRegistry,
+    /// Where new local indexes are kept, `None` keeps them under `base_path` as `directory_kind` says
+    storage: Option<Arc<dyn StorageBackend>>,
+    unhealthy_nodes: RwLock<HashSet<String>>,
+    read_turn: AtomicUsize,
+    active_searches: Arc<AtomicUsize>,
+    blocking: BlockingPool,
+    /// Held while fields are added to the schema of an index, so two documents adding the same
+    /// field don't both reopen the index for it
+    schema_changes: Mutex<()>,
+    /// Held while a local index is created, so two requests creating the same index can't both
+    /// create it
+    index_creation: Mutex<()>,
 }
 
 impl IndexCatalog {
@@ -38,13 +75,26 @@ impl IndexCatalog {
 
     pub fn new(base_path: PathBuf, settings: Settings) -> Result<Self> {
         let remote_idxs = Arc::new(Mutex::new(HashMap::new()));
-        let local_idxs = HashMap::new();
+        let local_idxs = RwLock::new(HashMap::new());
 
-        let mut index_cat = IndexCatalog {
+        let index_cat = IndexCatalog {
+            placement: std::sync::Mutex::new(settings.get_placement()),
+            tasks: TaskRegistry::new(Duration::from_secs(settings.task_retention)),
+            blocking: BlockingPool::new(settings.blocking_pool_size),
+            storage: None,
             settings,
             base_path,
             local_handles: local_idxs,
             remote_handles: remote_idxs,
+            replica_handles: Mutex::new(HashMap::new()),
+            wire_formats: FormatCache::default(),
+            routing: RwLock::new(HashMap::new()),
+            quarantined: RwLock::new(HashMap::new()),
+            unhealthy_nodes: RwLock::new(HashSet::new()),
+            read_turn: AtomicUsize::new(0),
+            active_searches: Arc::new(AtomicUsize::new(0)),
+            schema_changes: Mutex::new(()),
+            index_creation: Mutex::new(()),
         };
         index_cat.refresh_catalog()?;
 
@@ -75,21 +125,73 @@ impl IndexCatalog {
             .unwrap_or_else(|_| panic!("Unable to open index: {} because it's locked", name));
         map.insert(name, new_index);
 
+        let settings = Settings::default();
         Ok(IndexCatalog {
-            settings: Settings::default(),
+            placement: std::sync::Mutex::new(settings.get_placement()),
+            tasks: TaskRegistry::new(Duration::from_secs(settings.task_retention)),
+            blocking: BlockingPool::new(settings.blocking_pool_size),
+            storage: None,
+            settings,
             base_path: PathBuf::new(),
-            local_handles: map,
+            local_handles: RwLock::new(map),
             remote_handles: Arc::new(Mutex::new(remote_map)),
+            replica_handles: Mutex::new(HashMap::new()),
+            wire_formats: FormatCache::default(),
+            routing: RwLock::new(HashMap::new()),
+            quarantined: RwLock::new(HashMap::new()),
+            unhealthy_nodes: RwLock::new(HashSet::new()),
+            read_turn: AtomicUsize::new(0),
+            active_searches: Arc::new(AtomicUsize::new(0)),
+            schema_changes: Mutex::new(()),
+            index_creation: Mutex::new(()),
         })
     }
 
-    pub fn create_from_managed(mut base_path: PathBuf, index_path: &str, schema: Schema) -> Result<Index> {
-        base_path.push(index_path);
-        if !base_path.exists() {
-            fs::create_dir(&base_path).map_err(|e| Error::IOError(e.to_string()))?;
+    pub fn create_from_managed(base_path: PathBuf, index_path: &str, schema: Schema, kind: DirectoryKind) -> Result<Index> {
+        LocalStorage::new(base_path, kind).open_index(index_path, schema)
+    }
+
+    /// Keep the local indexes created from now on in `storage` instead of the local filesystem
+    pub fn set_storage(&mut self, storage: Arc<dyn StorageBackend>) {
+        self.storage = Some(storage);
+    }
+
+    /// Open the local index called `name` from the catalog's storage, creating it with `schema` if
+    /// it doesn't exist yet
+    pub fn open_index(&self, name: &str, schema: Schema) -> Result<Index> {
+        match &self.storage {
+            Some(storage) => storage.open_index(name, schema),
+            None => self.local_storage().open_index(name, schema),
         }
-        let dir = MmapDirectory::open(base_path).map_err(|e| Error::IOError(e.to_string()))?;
-        Index::open_or_create(dir, schema).map_err(|e| Error::IOError(e.to_string()))
+    }
+
+    /// The local filesystem storage indexes are kept in when no other storage is set
+    fn local_storage(&self) -> LocalStorage {
+        LocalStorage::new(self.base_path.clone(), self.settings.directory_kind).with_sync(self.settings.commit_sync)
+    }
+
+    /// The storage indexes are kept in, the local filesystem when no other storage is set
+    fn storage_backend(&self) -> Arc<dyn StorageBackend> {
+        match &self.storage {
+            Some(storage) => Arc::clone(storage),
+            None => Arc::new(self.local_storage()),
+        }
+    }
+
+    /// Copy the last commit of the local index `source` into a new local index called `dest` and
+    /// register it. The copy shares nothing with the source, writes to either never show in the other.
+    /// The copy is made under the same lock as creating an index, so it can't race a create of `dest`.
+    pub async fn clone_index(&self, source: &str, dest: &str) -> Result<()> {
+        let _creating = self.index_creation.lock().await;
+        let source_index = self.get_index(source)?.get_index().clone();
+        if self.exists(dest) {
+            return Err(Error::IndexExists(dest.into()));
+        }
+        let storage = self.storage_backend();
+        let (source, name) = (source.to_string(), dest.to_string());
+        let index = self.blocking.run(move || storage.copy_index(&source_index, &source, &name)).await?;
+        self.add_index(dest.into(), index)?;
+        self.get_index(dest)?.detach_copy()
     }
 
     pub fn load_index(path: &str) -> Result<Index> {
@@ -103,57 +205,352 @@ impl IndexCatalog {
         }
     }
 
-    pub fn add_index(&mut self, name: String, index: Index) -> Result<()> {
-        let handle = LocalIndex::new(index, self.settings.clone(), &name)?;
-        self.local_handles.insert(name, handle);
+    pub fn add_index(&self, name: String, index: Index) -> Result<()> {
+        let handle = LocalIndex::new(index, self.settings.clone(), &name)?.with_blocking(self.blocking.clone());
+        self.local_handles.write().map_err(|_| Error::PoisonedError)?.insert(name, handle);
         Ok(())
     }
 
-    pub async fn add_remote_index(&mut self, name: String, remote: RpcClient) -> Result<()> {
+    /// Create the local index `name` with `schema`, set it up with `configure` and register it,
+    /// failing with [`Error::IndexExists`] if it already exists. Checking for the index, creating it
+    /// and registering it all happen under one lock, so when two requests create the same index
+    /// only the first does and the second finds it there.
+    pub async fn create_local_index<F>(&self, name: &str, schema: Schema, configure: F) -> Result<LocalIndex>
+    where
+        F: FnOnce(&LocalIndex) -> Result<()>,
+    {
+        let _creating = self.index_creation.lock().await;
+        if self.exists(name) {
+            return Err(Error::IndexExists(name.into()));
+        }
+        let index = self.open_index(name, schema)?;
+        let configured = LocalIndex::new(index, self.settings.clone(), name).and_then(|handle| {
+            let handle = handle.with_blocking(self.blocking.clone());
+            configure(&handle)?;
+            Ok(handle)
+        });
+        // An index that couldn't be set up is removed again so nothing half made is left behind,
+        // and creating it again starts over
+        let handle = match configured {
+            Ok(handle) => handle,
+            Err(e) => {
+                if let Err(removed) = self.storage_backend().remove_index(name) {
+                    tracing::warn!("Could not remove index {} after failing to create it: {}", name, removed);
+                }
+                return Err(e);
+            }
+        };
+        self.local_handles
+            .write()
+            .map_err(|_| Error::PoisonedError)?
+            .insert(name.into(), handle.clone());
+        Ok(handle)
+    }
+
+    pub async fn add_remote_index(&self, name: String, remote: RpcClient) -> Result<()> {
         let ri = RemoteIndex::new(name.clone(), remote);
         self.remote_handles.lock().await.entry(name).or_insert(ri);
         Ok(())
     }
 
-    pub async fn add_multi_remote_index(&mut self, name: String, remote: Vec<RpcClient>) -> Result<()> {
+    /// Register the remote index `name` spread over one shard on each node of `remote`, by the id of the node.
+    /// Routing values are pinned to the partitions saved with the local copy of the index if it was
+    /// created with routing partitions, and placed on the shards directly otherwise.
+    pub async fn add_multi_remote_index(&self, name: String, remote: Vec<(String, RpcClient)>) -> Result<()> {
         let ri = RemoteIndex::with_clients(name.clone(), remote);
+        let saved = match self.get_index(&name) {
+            Ok(handle) => handle.saved_partitions()?,
+            Err(_) => None,
+        };
+        let ri = match saved {
+            Some(table) => {
+                let ri = ri.with_partitions(table);
+                if let Some(table) = ri.partitions() {
+                    self.save_partitions(&name, table)?;
+                }
+                ri
+            }
+            None => ri.with_virtual_nodes(self.settings.experimental_features.virtual_nodes),
+        };
         self.remote_handles.lock().await.entry(name).or_insert(ri);
         Ok(())
     }
 
-    pub fn get_collection(&self) -> &HashMap<String, LocalIndex> {
-        &self.local_handles
+    /// Register the copy of the remote index `name` on `node` as one of its replicas
+    pub async fn add_replica_index(&self, name: String, node: String, mut remote: RpcClient) -> Result<()> {
+        let format = self.wire_format(&node, &mut remote).await;
+        let ri = RemoteIndex::new(name.clone(), remote).with_format(format);
+        self.replica_handles.lock().await.entry(name).or_default().insert(node, ri);
+        Ok(())
     }
 
-    pub fn get_remote_collection(&self) -> Arc<Mutex<HashMap<String, RemoteIndex>>> {
-        Arc::clone(&self.remote_handles)
+    /// The replica of the remote index `name` on `node`, if it was registered with this node
+    pub async fn get_replica_index(&self, name: &str, node: &str) -> Option<RemoteIndex> {
+        self.replica_handles.lock().await.get(name).and_then(|r| r.get(node)).cloned()
+    }
+
+    /// Have `shard` of the remote index `name` hold its routing partition `partition` from now on,
+    /// returning the partitions and the shards holding them after the move
+    pub async fn move_partition(&self, name: &str, partition: usize, shard: usize) -> Result<PartitionTable> {
+        let mut handles = self.remote_handles.lock().await;
+        let remote = handles.get_mut(name).ok_or_else(|| Error::UnknownIndex(name.into()))?;
+        remote.move_partition(partition, shard)?;
+        let table = remote
+            .partitions()
+            .cloned()
+            .ok_or_else(|| Error::QueryError(format!("Index {} has no routing partitions", name)))?;
+        self.save_partitions(name, &table)?;
+        Ok(table)
+    }
+
+    /// Persist the routing partitions of the index `name` with its local copy, if this node holds one
+    fn save_partitions(&self, name: &str, table: &PartitionTable) -> Result<()> {
+        match self.get_index(name) {
+            Ok(handle) => handle.save_partitions(table),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// The one shard of the remote index `name` that operations with `routing` are pinned to
+    pub async fn routed_shard(&self, name: &str, routing: &str) -> Result<RemoteIndex> {
+        Ok(self.get_remote_index(name).await?.routed(routing))
+    }
+
+    /// The documents and bytes of every local index together, which is the load this node reports
+    /// to the others
+    pub fn local_load(&self) -> NodeLoad {
+        let handles = self.get_collection();
+        let num_docs = handles.values().map(LocalIndex::num_docs).sum();
+        let disk_size = handles.values().map(|h| h.get_space().total() as u64).sum();
+        NodeLoad {
+            node: self.settings.place_addr.clone(),
+            num_docs,
+            disk_size,
+        }
+    }
+
+    /// The load of every node in the cluster, asked of each over rpc. A node that can't be reached
+    /// is left out so nothing new gets placed on it.
+    pub async fn node_loads(&self) -> Vec<NodeLoad> {
+        let mut loads = Vec::new();
+        for node in self.settings.get_nodes() {
+            if node == self.settings.place_addr {
+                loads.push(self.local_load());
+                continue;
+            }
+            let reply = match IndexCatalog::create_client(node.clone()).await {
+                Ok(mut client) => client.ping(tonic::Request::new(PingRequest {})).await.map_err(Error::from),
+                Err(e) => Err(e),
+            };
+            match reply {
+                Ok(reply) => {
+                    let PingReply { num_docs, disk_size, .. } = reply.into_inner();
+                    loads.push(NodeLoad { node, num_docs, disk_size });
+                }
+                Err(e) => tracing::warn!("Could not get the load of node {}, not placing on it: {}", node, e),
+            }
+        }
+        loads
+    }
+
+    /// Decide which of the `nodes` host a new index and `replicas` copies of it. If there aren't
+    /// enough nodes to keep every copy on its own node the index is still placed, but is left
+    /// Yellow with the missing replicas unassigned.
+    pub fn plan_index(&self, name: &str, replicas: usize, nodes: &[NodeLoad]) -> Result<IndexRouting> {
+        let placement = self
+            .placement
+            .lock()
+            .map_err(|_| Error::PoisonedError)?
+            .place_shard(nodes, replicas)?;
+        let routing = IndexRouting::from(placement);
+        if routing.health() == IndexHealth::Yellow {
+            tracing::warn!(
+                "Index {} is yellow, {} of {} replicas could not be assigned to a node",
+                name,
+                routing.unassigned,
+                replicas
+            );
+        }
+        self.save_routing(name, &routing);
+        self.routing
+            .write()
+            .map_err(|_| Error::PoisonedError)?
+            .insert(name.into(), routing.clone());
+        Ok(routing)
+    }
+
+    /// Persist an index's routing with its local copy, if this node holds one, so recovery can put
+    /// it back. Failing to save is logged rather than failing the change itself.
+    fn save_routing(&self, name: &str, routing: &IndexRouting) {
+        if let Ok(handle) = self.get_index(name) {
+            if let Err(e) = handle.save_routing(routing) {
+                tracing::warn!("Could not save routing of index {}: {}", name, e);
+            }
+        }
+    }
+
+    pub fn get_routing(&self, name: &str) -> Result<IndexRouting> {
+        self.routing
+            .read()
+            .map_err(|_| Error::PoisonedError)?
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::UnknownIndex(name.into()))
     }
 
-    pub fn get_mut_collection(&mut self) -> &mut HashMap<String, LocalIndex> {
-        &mut self.local_handles
+    pub fn set_node_health(&self, node: &str, healthy: bool) -> Result<()> {
+        let mut unhealthy = self.unhealthy_nodes.write().map_err(|_| Error::PoisonedError)?;
+        if healthy {
+            unhealthy.remove(node);
+        } else {
+            unhealthy.insert(node.into());
+        }
+        Ok(())
     }
 
+    /// Record the result of a health check. A node going down fails over every index it is the
+    /// primary of, a node coming back rejoins the indexes it held copies of as a replica. Returns
+    /// the indexes the node rejoined, which it has to bootstrap from a snapshot of.
+    pub fn observe_node(&self, node: &str, healthy: bool) -> Result<Vec<String>> {
+        let mut rejoined = Vec::new();
+        if healthy == self.is_node_healthy(node) {
+            return Ok(rejoined);
+        }
+        self.set_node_health(node, healthy)?;
+        self.wire_formats.forget(node);
+        let mut routing = self.routing.write().map_err(|_| Error::PoisonedError)?;
+        for (index, r) in routing.iter_mut() {
+            if healthy {
+                if r.rejoin(node) {
+                    tracing::info!("Node {} rejoined index {} as a replica", node, index);
+                    self.save_routing(index, r);
+                    rejoined.push(index.clone());
+                }
+            } else if r.primary_node == node {
+                let generation = r.generation;
+                match r.promote(generation, |n| n != node && self.is_node_healthy(n)) {
+                    Ok(primary) => {
+                        tracing::warn!("Primary of {} on {} is unreachable, promoted {}", index, node, primary);
+                        self.save_routing(index, r);
+                    }
+                    Err(e) => tracing::error!("Could not fail over index {}: {}", index, e),
+                }
+            }
+        }
+        Ok(rejoined)
+    }
+
+    /// Fail an index over to its most caught up replica, `generation` is the routing generation the
+    /// caller saw the failed primary at so concurrent failovers only promote once
+    pub fn failover(&self, index: &str, generation: u64) -> Result<String> {
+        let mut routing = self.routing.write().map_err(|_| Error::PoisonedError)?;
+        let r = routing.get_mut(index).ok_or_else(|| Error::UnknownIndex(index.into()))?;
+        let primary = r.promote(generation, |n| self.is_node_healthy(n))?;
+        self.save_routing(index, r);
+        Ok(primary)
+    }
+
+    pub fn set_replica_lag(&self, index: &str, node: &str, lag: u64) -> Result<()> {
+        let mut routing = self.routing.write().map_err(|_| Error::PoisonedError)?;
+        let r = routing.get_mut(index).ok_or_else(|| Error::UnknownIndex(index.into()))?;
+        r.replica_lag.insert(node.into(), lag);
+        if r.catch_up(node, self.settings.experimental_features.max_bootstrap_lag) {
+            tracing::info!("Replica of {} on {} caught up and is serving reads", index, node);
+            self.save_routing(index, r);
+        }
+        Ok(())
+    }
+
+    /// Record that the replica of `index` on `node` finished loading the snapshot it bootstraps
+    /// from, it serves reads once it's caught up with the primary
+    pub fn snapshot_restored(&self, index: &str, node: &str) -> Result<()> {
+        let mut routing = self.routing.write().map_err(|_| Error::PoisonedError)?;
+        let r = routing.get_mut(index).ok_or_else(|| Error::UnknownIndex(index.into()))?;
+        if r.snapshot_restored(node, self.settings.experimental_features.max_bootstrap_lag) {
+            self.save_routing(index, r);
+        }
+        Ok(())
+    }
+
+    /// The node holding the primary copy of `index`, which every write goes to
+    pub fn write_node(&self, index: &str) -> Result<String> {
+        self.get_routing(index).map(|r| r.primary_node)
+    }
+
+    pub fn is_node_healthy(&self, node: &str) -> bool {
+        self.unhealthy_nodes.read().map(|u| !u.contains(node)).unwrap_or(false)
+    }
+
+    /// The node that should serve a read of `index` given the caller's read preference, or the node
+    /// every read of the caller's `session` goes to when it has one
+    pub fn read_node(&self, index: &str, preference: ReadPreference, session: Option<&str>) -> Result<String> {
+        let routing = self.get_routing(index)?;
+        let healthy = |n: &str| self.is_node_healthy(n);
+        if let Some(session) = session {
+            return Ok(routing.session_node(session, healthy));
+        }
+        let turn = self.read_turn.fetch_add(1, Ordering::Relaxed);
+        Ok(routing.read_node(preference, &self.settings.place_addr, turn, healthy))
+    }
+
+    /// A snapshot of the local indexes, the handles share their underlying writer and reader
+    /// with the catalog so this is cheap and never holds the catalog lock.
+    pub fn get_collection(&self) -> HashMap<String, LocalIndex> {
+        self.local_handles.read().map(|h| h.clone()).unwrap_or_default()
+    }
+
+    pub fn get_remote_collection(&self) -> Arc<Mutex<HashMap<String, RemoteIndex>>> {
+        Arc::clone(&self.remote_handles)
+    }
+
+    /// Whether there is a local index called `index`, counting ones that were quarantined because
+    /// they couldn't be read, since their files are still there under that name
     pub fn exists(&self, index: &str) -> bool {
-        self.get_collection().contains_key(index)
+        self.local_handles.read().map(|h| h.contains_key(index)).unwrap_or(false)
+            || self.quarantined.read().map(|q| q.contains_key(index)).unwrap_or(false)
     }
 
-    pub async fn remote_exists(&self, index: &str) -> bool {
-        self.get_remote_collection().lock().await.contains_key(index)
+    /// Whether an index name given to a handler is really a list of names or a pattern that has to
+    /// be resolved with [`IndexCatalog::resolve_indexes`]
+    pub fn is_index_pattern(index: &str) -> bool {
+        index.contains(',') || index.contains('*')
     }
 
-    pub fn get_mut_index(&mut self, name: &str) -> Result<&mut LocalIndex> {
-        self.local_handles.get_mut(name).ok_or_else(|| Error::UnknownIndex(name.into()))
+    /// The local indexes a comma separated list of names or patterns refers to, in the order they
+    /// are listed. A `*` in a pattern matches any run of characters, `logs-*` matches every index
+    /// whose name starts with `logs-`. Names that aren't indexes here are left out.
+    pub fn resolve_indexes(&self, indexes: &str) -> Result<Vec<String>> {
+        let handles = self.local_handles.read().map_err(|_| Error::PoisonedError)?;
+        let mut names: Vec<&String> = handles.keys().collect();
+        names.sort();
+        let mut resolved: Vec<String> = Vec::new();
+        for pattern in indexes.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            for name in names.iter().filter(|n| matches_pattern(pattern, n)) {
+                if !resolved.contains(name) {
+                    resolved.push((*name).clone());
+                }
+            }
+        }
+        Ok(resolved)
     }
 
-    pub fn get_index(&self, name: &str) -> Result<&LocalIndex> {
-        self.local_handles.get(name).ok_or_else(|| Error::UnknownIndex(name.into()))
+    pub async fn remote_exists(&self, index: &str) -> bool {
+        self.get_remote_collection().lock().await.contains_key(index)
     }
 
-    pub fn get_owned_index(&self, name: &str) -> Result<LocalIndex> {
+    pub fn get_index(&self, name: &str) -> Result<LocalIndex> {
         self.local_handles
+            .read()
+            .map_err(|_| Error::PoisonedError)?
             .get(name)
             .cloned()
-            .ok_or_else(|| Error::UnknownIndex(name.into()))
+            .ok_or_else(|| match self.quarantined.read().ok().and_then(|q| q.get(name).cloned()) {
+                Some(reason) => Error::Quarantined {
+                    index: name.into(),
+                    reason,
+                },
+                None => Error::UnknownIndex(name.into()),
+            })
     }
 
     pub async fn get_remote_index(&self, name: &str) -> Result<RemoteIndex> {
@@ -165,21 +562,118 @@ impl IndexCatalog {
             .ok_or_else(|| Error::UnknownIndex(name.into()))
     }
 
-    pub fn refresh_catalog(&mut self) -> Result<()> {
-        self.local_handles.clear();
+    /// Background tasks started on this node with `async=true`
+    pub fn tasks(&self) -> &TaskRegistry {
+        &self.tasks
+    }
+
+    /// Where commits, copies and other heavy operations on this node's indexes are run
+    pub fn blocking(&self) -> &BlockingPool {
+        &self.blocking
+    }
+
+    /// Indexes that were found corrupted when they were opened and are kept out of service, keyed
+    /// by name with the reason they were quarantined
+    pub fn quarantined_indexes(&self) -> HashMap<String, String> {
+        self.quarantined.read().map(|q| q.clone()).unwrap_or_default()
+    }
 
+    /// Rebuild the local catalog from the data directory. Every directory holding a tantivy index
+    /// is opened and has the routing saved with it put back. An index that can't be opened, or has
+    /// segments that can't be read under [`CorruptionPolicy::Quarantine`], is quarantined instead so
+    /// one corrupted index doesn't keep the node from starting.
+    pub fn refresh_catalog(&self) -> Result<()> {
+        self.local_handles.write().map_err(|_| Error::PoisonedError)?.clear();
+        self.quarantined.write().map_err(|_| Error::PoisonedError)?.clear();
+
+        let (mut recovered, mut quarantined) = (0, 0);
         for dir in fs::read_dir(self.base_path.clone())? {
             let entry = dir?.path();
-            if let Some(entry_str) = entry.to_str() {
-                if !entry_str.ends_with(".node_id") {
-                    let pth: String = entry_str.rsplit('/').take(1).collect();
-                    let idx = IndexCatalog::load_index(entry_str)?;
-                    self.add_index(pth.clone(), idx)?;
+            let name = match entry.file_name().and_then(|n| n.to_str()) {
+                Some(n) if !n.starts_with('.') && entry.is_dir() => n.to_string(),
+                Some(_) => continue,
+                None => {
+                    tracing::warn!("Skipping {}, it is not a valid unicode path", entry.display());
+                    continue;
+                }
+            };
+            match self.recover_index(&name, &entry) {
+                Ok(()) => recovered += 1,
+                Err(e) => {
+                    tracing::error!("Quarantining index {} at {}: {}", name, entry.display(), e);
+                    self.quarantined
+                        .write()
+                        .map_err(|_| Error::PoisonedError)?
+                        .insert(name, e.to_string());
+                    quarantined += 1;
+                }
+            }
+        }
+        tracing::info!(
+            "Recovered {} indexes from {}, quarantined {}",
+            recovered,
+            self.base_path.display(),
+            quarantined
+        );
+        Ok(())
+    }
+
+    fn recover_index(&self, name: &str, path: &Path) -> Result<()> {
+        let index = self.local_storage().load_index(path)?;
+        let corrupted = IndexCatalog::corrupted_segments(&index)?;
+        if !corrupted.is_empty() {
+            let reason = corrupted.iter().map(|(_, e)| e.as_str()).collect::<Vec<_>>().join(", ");
+            match self.settings.on_corruption {
+                CorruptionPolicy::Quarantine => return Err(Error::IOError(reason)),
+                CorruptionPolicy::Truncate => {
+                    tracing::warn!("Dropping {} unreadable segments of index {}: {}", corrupted.len(), name, reason);
+                    let ids: HashSet<SegmentId> = corrupted.into_iter().map(|(id, _)| id).collect();
+                    IndexCatalog::drop_segments(&index, &ids)?;
                 }
-            } else {
-                return Err(Error::IOError(format!("Path {} is not a valid unicode path", entry.display())));
             }
         }
+        self.add_index(name.into(), index)?;
+        let replayed = self.get_index(name)?.replay_wal()?;
+        if replayed > 0 {
+            tracing::info!(
+                "Added {} uncommitted documents of index {} back from its write-ahead log",
+                replayed,
+                name
+            );
+        }
+        if let Some(routing) = self.get_index(name)?.saved_routing()? {
+            self.routing.write().map_err(|_| Error::PoisonedError)?.insert(name.into(), routing);
+        }
+        Ok(())
+    }
+
+    /// Open every segment of the last commit of `index`, returning the ones that can't be read and
+    /// why. Tantivy panics on some kinds of damaged files so panics are caught and count as
+    /// unreadable too.
+    fn corrupted_segments(index: &Index) -> Result<Vec<(SegmentId, String)>> {
+        let segments = index.searchable_segments().map_err(Error::from)?;
+        Ok(segments
+            .into_iter()
+            .filter_map(|segment| {
+                let id = segment.id();
+                let opened = panic::catch_unwind(AssertUnwindSafe(|| SegmentReader::open(&segment).map(|_| ())));
+                match opened {
+                    Ok(Ok(())) => None,
+                    Ok(Err(e)) => Some((id, format!("segment {} is unreadable: {}", id.short_uuid_string(), e))),
+                    Err(_) => Some((id, format!("segment {} is unreadable", id.short_uuid_string()))),
+                }
+            })
+            .collect())
+    }
+
+    /// Rewrite the last commit of `index` without the segments in `drop`. Their files are left for
+    /// tantivy's garbage collection.
+    fn drop_segments(index: &Index, drop: &HashSet<SegmentId>) -> Result<()> {
+        let mut metas = index.load_metas().map_err(Error::from)?;
+        metas.segments.retain(|s| !drop.contains(&s.id()));
+        let mut buffer = serde_json::to_vec_pretty(&metas)?;
+        buffer.push(b'\n');
+        index.directory().clone().atomic_write(Path::new(META_FILE), &buffer)?;
         Ok(())
     }
 
@@ -216,9 +710,89 @@ impl IndexCatalog {
         Ok((client, r.indexes))
     }
 
+    /// Searches run on the blocking pool so the handler waiting on them stays cancellable, if the
+    /// handler is dropped because its client went away the search is stopped.
     pub async fn search_local_index(&self, index: &str, search: Search) -> Result<SearchResults> {
+        self.search_local_index_with_cancel(index, search, CancelToken::default()).await
+    }
+
+    /// [`IndexCatalog::search_local_index`] with the token that's cancelled if the search is dropped
+    pub async fn search_local_index_with_cancel(&self, index: &str, search: Search, cancel: CancelToken) -> Result<SearchResults> {
         let hand = self.get_index(index)?;
-        hand.search_index(search).await
+        let guard = cancel.guard();
+        let active = Arc::clone(&self.active_searches);
+        active.fetch_add(1, Ordering::SeqCst);
+        let results = tokio::task::spawn_blocking(move || {
+            let results = hand.search_with_cancel(search, &cancel);
+            active.fetch_sub(1, Ordering::SeqCst);
+            results
+        })
+        .await
+        .map_err(|_| Error::SpawnError)?;
+        guard.disarm();
+        results
+    }
+
+    /// Run a search of a local index for where its hits are without loading them, returning the
+    /// hits with the index and the searcher that found them so they can be loaded a few at a time
+    /// with [`LocalIndex::load_hit`]
+    pub async fn search_local_addresses(
+        &self,
+        index: &str,
+        search: Search,
+    ) -> Result<(LocalIndex, Arc<LeasedItem<Searcher>>, SearchResults)> {
+        let hand = self.get_index(index)?;
+        let searching = hand.clone();
+        let cancel = CancelToken::default();
+        let guard = cancel.guard();
+        let active = Arc::clone(&self.active_searches);
+        active.fetch_add(1, Ordering::SeqCst);
+        let found = tokio::task::spawn_blocking(move || {
+            let searcher = searching.searcher();
+            let results = searching.search_with_searcher(&searcher, search, true, &cancel);
+            active.fetch_sub(1, Ordering::SeqCst);
+            results.map(|results| (searcher, results))
+        })
+        .await
+        .map_err(|_| Error::SpawnError)?;
+        guard.disarm();
+        let (searcher, results) = found?;
+        Ok((hand, Arc::new(searcher), results))
+    }
+
+    /// Search results the way they're returned to clients, every value as the JSON type of its
+    /// field in `index`
+    pub fn typed_results(&self, index: &str, results: SearchResults) -> TypedSearchResults {
+        let bools = self.get_index(index).and_then(|i| i.bool_fields()).unwrap_or_default();
+        results.map_docs(|doc| LocalIndex::typed_doc(doc, &bools))
+    }
+
+    /// The number of local searches still running, including ones whose client has gone away
+    pub fn active_searches(&self) -> usize {
+        self.active_searches.load(Ordering::SeqCst)
+    }
+
+    /// A handle on a copy of `index` held by another node, talking to it in the configured wire
+    /// format when the node supports it
+    pub async fn node_handle(&self, node: &str, index: &str) -> Result<RemoteIndex> {
+        let mut client = IndexCatalog::create_client(node.into()).await?;
+        let format = self.wire_format(node, &mut client).await;
+        Ok(RemoteIndex::new(index.into(), client).with_format(format))
+    }
+
+    /// The format to talk to `node` in, only asking it which formats it reads the first time
+    pub async fn wire_format(&self, node: &str, client: &mut RpcClient) -> WireFormat {
+        let preferred = self.settings.experimental_features.wire_format;
+        self.wire_formats.format(node, preferred, client).await
+    }
+
+    /// The formats negotiated with other nodes, for talking to them outside of a borrow of the catalog
+    pub fn wire_formats(&self) -> FormatCache {
+        self.wire_formats.clone()
+    }
+
+    pub async fn search_node(&self, node: &str, index: &str, search: Search) -> Result<SearchResults> {
+        self.node_handle(node, index).await?.search_index(search).await
     }
 
     pub async fn search_remote_index(&self, index: &str, search: Search) -> Result<Vec<SearchResults>> {
@@ -227,37 +801,404 @@ impl IndexCatalog {
     }
 
     pub async fn add_remote_document(&self, index: &str, doc: AddDocument) -> Result<()> {
-        let handle = self.get_remote_index(index).await.map_err(|e| Error::IOError(e.to_string()))?;
+        let handle = self.get_remote_index(index).await?;
         handle.add_document(doc).await
     }
 
+    pub async fn add_node_document(&self, node: &str, index: &str, doc: AddDocument) -> Result<()> {
+        self.node_handle(node, index).await?.add_document(doc).await
+    }
+
     pub async fn add_local_document(&self, index: &str, doc: AddDocument) -> Result<()> {
-        let handle = self.get_owned_index(index).map_err(|e| Error::IOError(e.to_string()))?;
+        let mut handle = self.get_index(index)?;
+        if self.settings.get_unknown_fields(index) == UnknownFields::Dynamic {
+            let fields = handle.dynamic_fields(&doc.document)?;
+            if !fields.is_empty() {
+                handle = self.add_fields(index, fields).await?;
+            }
+        }
         handle.add_document(doc).await
     }
 
+    /// Add `fields` to the end of the schema of the local index `name` and persist the new schema
+    /// with the index, fields that have been added in the meantime are skipped. Returns the index's
+    /// handle for the new schema.
+    pub async fn add_fields(&self, name: &str, fields: Vec<FieldEntry>) -> Result<LocalIndex> {
+        let _changing = self.schema_changes.lock().await;
+        let handle = self.get_index(name)?;
+        handle.check_writable()?;
+        let schema = handle.get_index().schema();
+        let mut entries: Vec<FieldEntry> = schema.fields().map(|(_, entry)| entry.clone()).collect();
+        let mut bools = handle.bool_fields()?;
+        for field in fields {
+            if entries.iter().any(|e| e.name() == field.name()) {
+                continue;
+            }
+            if let FieldType::U64(_) = field.field_type() {
+                bools.insert(field.name().to_string());
+            }
+            entries.push(field);
+        }
+        if entries.len() == schema.fields().count() {
+            return Ok(handle);
+        }
+        let schema: Schema = serde_json::from_value(serde_json::to_value(entries)?)?;
+        let reopened = handle.reopen(|index| self.reopen_index(index, name, schema)).await?;
+        reopened.set_bool_fields(&bools.into_iter().collect::<Vec<_>>())?;
+        tracing::info!("Added fields to the schema of {}", name);
+        self.local_handles
+            .write()
+            .map_err(|_| Error::PoisonedError)?
+            .insert(name.into(), reopened.clone());
+        Ok(reopened)
+    }
+
+    fn reopen_index(&self, index: &Index, name: &str, schema: Schema) -> Result<Index> {
+        match &self.storage {
+            Some(storage) => storage.reopen_index(index, name, schema),
+            None => self.local_storage().reopen_index(index, name, schema),
+        }
+    }
+
     pub async fn delete_local_term(&self, index: &str, term: DeleteDoc) -> Result<DocsAffected> {
-        let handle = self.get_remote_index(index).await.map_err(|e| Error::IOError(e.to_string()))?;
+        let handle = self.get_remote_index(index).await?;
         handle.delete_term(term).await
     }
 
-    pub async fn clear(&mut self) {
-        self.local_handles.clear();
+    /// Stop every local index before the process exits. Writes that haven't been committed yet are
+    /// committed if `commit_on_shutdown` is set and thrown away otherwise.
+    pub async fn shutdown(&self) {
+        let commit = self.settings.commit_on_shutdown;
+        for (name, handle) in self.get_collection() {
+            let result = if commit { handle.commit().await } else { handle.rollback().await };
+            match result {
+                Ok(_) if commit => tracing::info!("Committed outstanding writes to {}", name),
+                Ok(_) => tracing::info!("Discarded uncommitted writes to {}", name),
+                Err(e) => tracing::error!("Could not shut down {} cleanly: {}", name, e),
+            }
+        }
+        self.clear().await;
+    }
+
+    pub async fn clear(&self) {
+        if let Ok(mut local) = self.local_handles.write() {
+            local.clear();
+        }
         self.remote_handles.lock().await.clear()
     }
 }
 
+/// Whether `name` is matched in full by `pattern`, where each `*` matches any run of characters
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    if !name.starts_with(first) {
+        return false;
+    }
+    let mut rest = &name[first.len()..];
+    let mut parts: Vec<&str> = parts.collect();
+    let last = match parts.pop() {
+        Some(last) => last,
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
 #[cfg(test)]
 pub mod tests {
     use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::future;
+    use tokio::time;
 
-    use tokio::sync::Mutex;
+    use tantivy::doc;
+    use tantivy::schema::{SchemaBuilder, STORED, STRING, TEXT};
+
+    use crate::cluster::shard::Shard;
 
     use super::*;
 
     pub fn create_test_catalog(name: &str) -> SharedCatalog {
         let idx = toshi_test::create_test_index();
         let catalog = IndexCatalog::with_index(name.into(), idx).unwrap();
-        Arc::new(Mutex::new(catalog))
+        Arc::new(catalog)
+    }
+
+    /// The catalog's nodes as if none of them held anything yet
+    pub fn idle_nodes(catalog: &IndexCatalog) -> Vec<NodeLoad> {
+        catalog.settings.get_nodes().into_iter().map(NodeLoad::new).collect()
+    }
+
+    #[test]
+    fn test_resolve_indexes() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let catalog = IndexCatalog::with_index("logs-2024-01".into(), toshi_test::create_test_index())?;
+        catalog.add_index("logs-2024-02".into(), toshi_test::create_test_index())?;
+        catalog.add_index("old-logs-2024-03".into(), toshi_test::create_test_index())?;
+
+        assert_eq!(catalog.resolve_indexes("logs-2024-*")?, vec!["logs-2024-01", "logs-2024-02"]);
+        assert_eq!(catalog.resolve_indexes("*-03")?, vec!["old-logs-2024-03"]);
+        assert_eq!(
+            catalog.resolve_indexes("logs-*-02,logs-2024-0*")?,
+            vec!["logs-2024-02", "logs-2024-01"]
+        );
+        assert!(catalog.resolve_indexes("logs")?.is_empty());
+        assert!(catalog.resolve_indexes("logs-2024")?.is_empty());
+        assert!(catalog.resolve_indexes("ogs-*")?.is_empty());
+        assert!(catalog.resolve_indexes("*-2024-01-*")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_index_replicas() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.experimental_features.nodes = vec!["127.0.0.1:8081".into(), "127.0.0.1:8082".into()];
+
+        let routing = catalog.plan_index("replicated", 1, &idle_nodes(&catalog))?;
+        assert_eq!(routing.health(), IndexHealth::Green);
+        assert_eq!(routing.replicas.len(), 1);
+        assert_ne!(routing.primary_node, routing.replicas[0].0);
+        assert_eq!(routing.primary.replicas(), &[routing.replicas[0].1.shard_id()]);
+        assert_eq!(catalog.get_routing("replicated")?.nodes(), routing.nodes());
+
+        let degraded = catalog.plan_index("degraded", 2, &idle_nodes(&catalog))?;
+        assert_eq!(degraded.health(), IndexHealth::Yellow);
+        assert_eq!(degraded.nodes().len(), 2);
+        assert_eq!(degraded.unassigned, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_local_load() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.place_addr = "127.0.0.1:8081".into();
+        let load = catalog.local_load();
+        assert_eq!((load.node.as_str(), load.num_docs), ("127.0.0.1:8081", 5));
+        assert!(load.disk_size > 0);
+
+        // The node already holding an index is the last one a least loaded placement picks
+        let nodes = vec![load, NodeLoad::new("127.0.0.1:8082".into())];
+        let placement = crate::cluster::placement::LeastLoaded.place_shard(&nodes, 1)?;
+        assert_eq!(placement.nodes(), vec!["127.0.0.1:8082", "127.0.0.1:8081"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_session_read_node() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.settings.experimental_features.nodes = vec!["127.0.0.1:8081".into(), "127.0.0.1:8082".into(), "127.0.0.1:8083".into()];
+        catalog.plan_index("replicated", 2, &idle_nodes(&catalog))?;
+
+        let sticky = catalog.read_node("replicated", ReadPreference::Replica, Some("session-1"))?;
+        assert_eq!(catalog.read_node("replicated", ReadPreference::Replica, Some("session-1"))?, sticky);
+        let spread: Vec<String> = (0..2)
+            .map(|_| catalog.read_node("replicated", ReadPreference::Replica, None))
+            .collect::<Result<_>>()?;
+        assert_ne!(spread[0], spread[1]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cold_start_recovery() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let base = std::env::temp_dir().join(format!("toshi-recovery-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(base.join("broken"))?;
+        fs::write(base.join("broken").join("meta.json"), b"not an index")?;
+        fs::write(base.join("stray.txt"), b"not a directory")?;
+
+        let mut builder = SchemaBuilder::new();
+        let body = builder.add_text_field("body", TEXT | STORED);
+        let index = IndexCatalog::create_from_managed(base.clone(), "recovered", builder.build(), DirectoryKind::Mmap)?;
+        let mut writer = index.writer(15_000_000).map_err(Error::from)?;
+        writer.add_document(doc!(body => "written before the restart"));
+        writer.commit().map_err(Error::from)?;
+        drop(writer);
+
+        let mut settings = Settings::default();
+        settings.experimental_features.nodes = vec!["127.0.0.1:8081".into(), "127.0.0.1:8082".into()];
+        let catalog = IndexCatalog::new(base.clone(), settings.clone())?;
+        let routing = catalog.plan_index("recovered", 1, &idle_nodes(&catalog))?;
+        drop(catalog);
+
+        let recovered = IndexCatalog::new(base.clone(), settings)?;
+        let results = recovered.search_local_index("recovered", Search::all_docs()).await;
+        let restored = recovered.get_routing("recovered");
+        let broken = recovered.exists("broken");
+        remove_dir_all::remove_dir_all(&base)?;
+
+        assert_eq!(results?.hits, 1);
+        let restored = restored?;
+        assert_eq!(restored.primary_node, routing.primary_node);
+        assert_eq!(restored.nodes(), routing.nodes());
+        assert!(!broken);
+        Ok(())
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_concurrent_indexes() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let catalog = create_test_catalog("test_index");
+        catalog.add_index("test_index_2".into(), toshi_test::create_test_index())?;
+
+        // Holding one index's writer, like a long running flush would, must not stall reads on it or on other indexes
+        let writer = catalog.get_index("test_index")?.get_writer();
+        let _held = writer.lock().await;
+
+        let first = catalog.search_local_index("test_index", Search::all_docs());
+        let second = catalog.search_local_index("test_index_2", Search::all_docs());
+        let (first, second) = time::timeout(Duration::from_secs(5), future::join(first, second)).await?;
+        assert_eq!(first?.hits, 5);
+        assert_eq!(second?.hits, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dropped_search_finishes() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        // One blocking thread, held up so the search is still queued for it when it's dropped
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().max_threads(1).build()?;
+        rt.block_on(async {
+            let catalog = create_test_catalog("test_index");
+            let (release, held) = std::sync::mpsc::channel::<()>();
+            let blocker = tokio::task::spawn_blocking(move || held.recv());
+
+            let cancel = CancelToken::default();
+            let mut search = Box::pin(catalog.search_local_index_with_cancel("test_index", Search::all_docs(), cancel.clone()));
+            assert!(futures::poll!(&mut search).is_pending());
+            assert_eq!(catalog.active_searches(), 1);
+            // Dropping the search before it resolves, like hyper does when the client disconnects
+            drop(search);
+            assert!(cancel.is_cancelled());
+
+            release.send(())?;
+            blocker.await??;
+            // The queue is first in first out, so the dropped search has finished once this has
+            tokio::task::spawn_blocking(|| ()).await?;
+            assert_eq!(catalog.active_searches(), 0);
+            let stopped = catalog.get_index("test_index")?.search_with_cancel(Search::all_docs(), &cancel);
+            assert!(matches!(stopped, Err(Error::Cancelled)));
+            Ok::<_, Box<dyn std::error::Error>>(())
+        })
+    }
+
+    async fn docs_after_restart(commit_on_shutdown: bool) -> std::result::Result<u64, Box<dyn std::error::Error>> {
+        let index = toshi_test::create_test_index();
+        let mut catalog = IndexCatalog::with_index("test_index".into(), index.clone())?;
+        catalog.settings.commit_on_shutdown = commit_on_shutdown;
+        let doc = serde_json::from_str(r#"{ "document": { "test_text": "uncommitted" } }"#)?;
+        catalog.add_local_document("test_index", doc).await?;
+        catalog.shutdown().await;
+        assert!(!catalog.exists("test_index"));
+        drop(catalog);
+
+        let restarted = LocalIndex::new(index, Settings::default(), "test_index")?;
+        Ok(restarted.num_docs())
+    }
+
+    #[test]
+    fn test_ram_index_has_no_wal() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("toshi-wal-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir)?;
+        let settings = Settings {
+            path: dir.to_string_lossy().into_owned(),
+            write_ahead_log: Some(crate::wal::WalSettings::default()),
+            directory_kind: crate::settings::DirectoryKind::Ram,
+            ..Settings::default()
+        };
+        let handle = LocalIndex::new(toshi_test::create_test_index(), settings.clone(), "test_index")?;
+        assert_eq!(handle.replay_wal()?, 0);
+        assert!(!crate::wal::WriteAheadLog::path_for(&settings.path, "test_index").exists());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replay_write_ahead_log() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        use toshi_types::{ExactTerm, Query};
+
+        let dir = std::env::temp_dir().join(format!("toshi-wal-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir)?;
+        let settings = Settings {
+            path: dir.to_string_lossy().into_owned(),
+            write_ahead_log: Some(crate::wal::WalSettings::default()),
+            ..Settings::default()
+        };
+        let index = toshi_test::create_test_index();
+        {
+            let handle = LocalIndex::new(index.clone(), settings.clone(), "test_index")?;
+            let doc = serde_json::from_str(r#"{ "document": { "test_text": "buffered" } }"#)?;
+            handle.add_document(doc).await?;
+            let delete = serde_json::from_str(r#"{ "terms": { "test_text": "duckiment" } }"#)?;
+            handle.delete_term(delete).await?;
+            // Dropped without a commit, like a process that crashed before the next auto commit
+        }
+
+        let restarted = LocalIndex::new(index.clone(), settings.clone(), "test_index")?;
+        assert_eq!(restarted.num_docs(), 5);
+        assert_eq!(restarted.replay_wal()?, 2);
+        restarted.reload()?;
+        assert_eq!(restarted.num_docs(), 5);
+        let search = |text: &str| Search::with_query(Query::Exact(ExactTerm::with_term("test_text", text)));
+        assert_eq!(restarted.search_index(search("buffered")).await?.hits, 1);
+        assert_eq!(restarted.search_index(search("duckiment")).await?.hits, 0);
+
+        // The replayed writes were committed, so they aren't made a second time, and writes that
+        // were rolled back aren't replayed either
+        let doc = serde_json::from_str(r#"{ "document": { "test_text": "rolled back" } }"#)?;
+        restarted.add_document(doc).await?;
+        restarted.rollback().await?;
+        drop(restarted);
+        let again = LocalIndex::new(index, settings, "test_index")?;
+        assert_eq!(again.replay_wal()?, 0);
+        assert_eq!(again.num_docs(), 5);
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replay_updated_documents() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        use toshi_types::{ExactTerm, Query};
+
+        let dir = std::env::temp_dir().join(format!("toshi-wal-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir)?;
+        let settings = Settings {
+            path: dir.to_string_lossy().into_owned(),
+            write_ahead_log: Some(crate::wal::WalSettings::default()),
+            ..Settings::default()
+        };
+        let mut builder = SchemaBuilder::new();
+        builder.add_text_field(toshi_types::ID_FIELD, STRING | STORED);
+        builder.add_text_field("status", STRING | STORED);
+        let index = Index::create_in_ram(builder.build());
+        {
+            let handle = LocalIndex::new(index.clone(), settings.clone(), "test_index")?;
+            for id in &["a", "b"] {
+                let doc = serde_json::json!({ "options": { "commit": true }, "document": { "_id": id, "status": "active" } });
+                handle.add_document(serde_json::from_value(doc)?).await?;
+            }
+            let update = serde_json::from_str(r#"{ "query": { "term": { "_id": "a" } }, "set": { "status": "archived" } }"#)?;
+            assert_eq!(handle.update_by_query(update, false).await?.docs_affected, 1);
+        }
+
+        // The new copy replaces the old one when the log is replayed, rather than being added next to it
+        let restarted = LocalIndex::new(index, settings, "test_index")?;
+        assert_eq!(restarted.replay_wal()?, 1);
+        restarted.reload()?;
+        assert_eq!(restarted.num_docs(), 2);
+        let search = |status: &str| Search::with_query(Query::Exact(ExactTerm::with_term("status", status)));
+        assert_eq!(restarted.search_index(search("archived")).await?.hits, 1);
+        assert_eq!(restarted.search_index(search("active")).await?.hits, 1);
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_commit_on_shutdown() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(docs_after_restart(true).await?, 6);
+        assert_eq!(docs_after_restart(false).await?, 5);
+        Ok(())
     }
 }
@@ -9,17 +9,39 @@ use tantivy::schema::Value;
 use toshi_types::AddDocument as AD;
 use toshi_types::SearchResults as SD;
 
+pub mod aggregation;
+pub mod blocking;
+pub mod cancel;
 pub mod cluster;
 pub mod commit;
+pub mod dead_letter;
+pub mod decay;
 pub mod handle;
 pub mod handlers;
 pub mod index;
+pub mod mapping;
+pub mod merge;
+pub mod nested;
+pub mod pipeline;
+pub mod pretokenized;
+pub mod rate;
+pub mod reindex;
 pub mod router;
+pub mod script;
+pub mod sequence;
 pub mod settings;
 pub mod shutdown;
+pub mod similarity;
+pub mod storage;
 pub mod support;
+pub mod tasks;
+pub mod ttl;
 pub mod utils;
+pub mod version;
+pub mod wal;
 
 pub type Result<T> = std::result::Result<T, toshi_types::Error>;
 pub type AddDocument = AD<serde_json::Value>;
 pub type SearchResults = SD<BTreeMap<String, Vec<Value>>>;
+/// Search results the way they're returned to clients, every value as the JSON type of its field
+pub type TypedSearchResults = SD<BTreeMap<String, Vec<serde_json::Value>>>;
@@ -0,0 +1,167 @@
+use serde_json::{json, Value};
+use tantivy::chrono::DateTime;
+use tantivy::schema::{FieldEntry, IntOptions, Schema, TextOptions};
+
+use toshi_types::Error;
+
+use crate::nested;
+use crate::settings::UnknownFields;
+use crate::Result;
+
+/// The fields of a flattened document that aren't in `schema`
+pub fn unknown_fields<'a>(doc: &'a Value, schema: &Schema) -> Vec<(&'a str, &'a Value)> {
+    match doc {
+        Value::Object(fields) => fields
+            .iter()
+            .filter(|(k, _)| schema.get_field(k).is_none())
+            .map(|(k, v)| (k.as_str(), v))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Apply `policy` to the fields of a flattened `doc` that aren't in `schema`, ignored fields are
+/// removed from the document and otherwise the first one fails it. Only the catalog adds fields to
+/// a schema, so `Dynamic` fields that are still unknown by the time a document is indexed are rejected.
+pub fn apply(policy: UnknownFields, doc: &mut Value, schema: &Schema) -> Result<()> {
+    let unknown: Vec<String> = unknown_fields(doc, schema).into_iter().map(|(k, _)| k.to_string()).collect();
+    match (policy, unknown.first()) {
+        (_, None) => Ok(()),
+        (UnknownFields::Ignore, Some(_)) => {
+            if let Value::Object(fields) = doc {
+                for name in &unknown {
+                    fields.remove(name);
+                }
+            }
+            Ok(())
+        }
+        (_, Some(name)) => Err(Error::InvalidDocument(format!("Field '{}' is not in the index's schema", name))),
+    }
+}
+
+/// The field a dynamic mapping adds for `name` going by its value. Strings become text, whole
+/// numbers i64, other numbers f64 and booleans u64 fields that hold booleans. The field is only
+/// stored, tantivy can't search or merge a field the index's older segments weren't indexed with.
+/// A value with no type to go by, a null or an empty list, adds no field.
+pub fn dynamic_field(name: &str, value: &Value) -> Option<FieldEntry> {
+    let name = name.to_string();
+    let stored = IntOptions::default().set_stored();
+    match value {
+        Value::Array(values) => values.iter().find_map(|v| dynamic_field(&name, v)),
+        Value::String(_) => Some(FieldEntry::new_text(name, TextOptions::default().set_stored())),
+        Value::Number(n) if n.is_i64() || n.is_u64() => Some(FieldEntry::new_i64(name, stored)),
+        Value::Number(_) => Some(FieldEntry::new_f64(name, stored)),
+        Value::Bool(_) => Some(FieldEntry::new_u64(name, stored)),
+        Value::Null | Value::Object(_) => None,
+    }
+}
+
+/// The type of field a value of a sample document suggests. Strings that parse as RFC 3339
+/// timestamps are dates, and an array takes the type its values agree on, whole numbers mixed
+/// with other numbers making it f64. A null, an empty array or one whose values disagree suggests none.
+fn sample_type(value: &Value) -> Option<&'static str> {
+    match value {
+        Value::Array(values) => {
+            let mut kinds = values.iter().filter(|v| !v.is_null()).map(sample_type);
+            let mut kind = kinds.next()??;
+            for other in kinds {
+                kind = match (kind, other?) {
+                    (a, b) if a == b => a,
+                    ("i64", "f64") | ("f64", "i64") => "f64",
+                    _ => return None,
+                };
+            }
+            Some(kind)
+        }
+        Value::String(s) if DateTime::parse_from_rfc3339(s).is_ok() => Some("date"),
+        Value::String(_) => Some("text"),
+        Value::Number(n) if n.is_i64() || n.is_u64() => Some("i64"),
+        Value::Number(_) => Some("f64"),
+        Value::Bool(_) => Some("bool"),
+        Value::Null | Value::Object(_) => None,
+    }
+}
+
+/// The fields of an index inferred from the example document `sample`, in the form `_create`
+/// takes them, and a warning for every field mapped as text because its value had no type to go
+/// by. Nested objects are flattened into dotted fields the way documents added to the index are.
+/// Text fields are stored and indexed with the default tokenizer, every other field is stored,
+/// indexed and a fast field, multi valued when the sample gave it an array.
+pub fn infer_fields(sample: &Value) -> Result<(Vec<Value>, Vec<String>)> {
+    if !sample.is_object() {
+        return Err(Error::InvalidDocument("A sample document must be a JSON object".into()));
+    }
+    let mut doc = sample.clone();
+    nested::flatten(&mut doc)?;
+    let mut fields = Vec::new();
+    let mut warnings = Vec::new();
+    if let Value::Object(values) = &doc {
+        for (name, value) in values {
+            let kind = sample_type(value).unwrap_or_else(|| {
+                warnings.push(format!("Field '{}' has no value to infer a type from, it was mapped as text", name));
+                "text"
+            });
+            let options = match kind {
+                "text" => json!({ "stored": true, "indexed": true }),
+                _ => json!({ "stored": true, "indexed": true, "fast": if value.is_array() { "multi" } else { "single" } }),
+            };
+            fields.push(json!({ "name": name, "type": kind, "options": options }));
+        }
+    }
+    Ok((fields, warnings))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tantivy::schema::{SchemaBuilder, TEXT};
+
+    use super::*;
+
+    fn schema() -> Schema {
+        let mut builder = SchemaBuilder::new();
+        builder.add_text_field("title", TEXT);
+        builder.build()
+    }
+
+    #[test]
+    fn test_apply() {
+        let schema = schema();
+        let doc = json!({ "title": "toshi", "extra": 1 });
+
+        let mut ignored = doc.clone();
+        apply(UnknownFields::Ignore, &mut ignored, &schema).unwrap();
+        assert_eq!(ignored, json!({ "title": "toshi" }));
+
+        for policy in &[UnknownFields::Reject, UnknownFields::Dynamic] {
+            let mut rejected = doc.clone();
+            assert!(apply(*policy, &mut rejected, &schema).is_err());
+            assert_eq!(rejected, doc);
+        }
+    }
+
+    #[test]
+    fn test_dynamic_field() {
+        let stored = || IntOptions::default().set_stored();
+        let field = |value: Value| dynamic_field("f", &value);
+        assert_eq!(
+            field(json!("text")),
+            Some(FieldEntry::new_text("f".into(), TextOptions::default().set_stored()))
+        );
+        assert_eq!(field(json!(-3)), Some(FieldEntry::new_i64("f".into(), stored())));
+        assert_eq!(field(json!(2.5)), Some(FieldEntry::new_f64("f".into(), stored())));
+        assert_eq!(field(json!([null, true])), Some(FieldEntry::new_u64("f".into(), stored())));
+        assert_eq!(field(json!(null)), None);
+        assert_eq!(field(json!([])), None);
+    }
+
+    #[test]
+    fn test_sample_type() {
+        assert_eq!(sample_type(&json!("2020-01-02T03:04:05Z")), Some("date"));
+        assert_eq!(sample_type(&json!("2020-01-02")), Some("text"));
+        assert_eq!(sample_type(&json!([1, 2.5])), Some("f64"));
+        assert_eq!(sample_type(&json!([null, false])), Some("bool"));
+        assert_eq!(sample_type(&json!([1, "one"])), None);
+        assert_eq!(sample_type(&json!([])), None);
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use tantivy::SegmentId;
+
+/// Keeps track of the merges Toshi starts on an index. Each one is remembered by the segments it
+/// merges and counted as running until those segments are no longer part of the index's last
+/// commit. Merges tantivy's merge policy starts on its own can't be seen from outside of tantivy.
+#[derive(Debug, Default)]
+pub struct MergeTracker(Mutex<Vec<Vec<SegmentId>>>);
+
+impl MergeTracker {
+    pub fn started(&self, segments: Vec<SegmentId>) {
+        if let Ok(mut merges) = self.0.lock() {
+            merges.push(segments);
+        }
+    }
+
+    /// Forget a merge that failed, its segments stay in the index
+    pub fn failed(&self, segments: &[SegmentId]) {
+        if let Ok(mut merges) = self.0.lock() {
+            merges.retain(|m| m.as_slice() != segments);
+        }
+    }
+
+    /// The number of merges still running, given the segments currently in the index
+    pub fn active(&self, live: &HashSet<SegmentId>) -> usize {
+        match self.0.lock() {
+            Ok(mut merges) => {
+                merges.retain(|m| m.iter().all(|id| live.contains(id)));
+                merges.len()
+            }
+            Err(_) => 0,
+        }
+    }
+
+    /// Every segment that is part of a merge that is still running
+    pub fn merging(&self) -> HashSet<SegmentId> {
+        self.0.lock().map(|m| m.iter().flatten().cloned().collect()).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_finished_merges_are_dropped() {
+        let tracker = MergeTracker::default();
+        let (a, b, c) = (
+            SegmentId::generate_random(),
+            SegmentId::generate_random(),
+            SegmentId::generate_random(),
+        );
+        tracker.started(vec![a, b]);
+        tracker.started(vec![c]);
+        assert_eq!(tracker.active(&[a, b, c].iter().cloned().collect()), 2);
+        assert_eq!(tracker.merging().len(), 3);
+
+        tracker.failed(&[c]);
+        assert_eq!(tracker.active(&[a, b, c].iter().cloned().collect()), 1);
+        assert_eq!(tracker.active(&[b, c].iter().cloned().collect()), 0);
+        assert!(tracker.merging().is_empty());
+    }
+}
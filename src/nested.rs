@@ -0,0 +1,149 @@
+use serde_json::{Map, Value};
+use tantivy::schema::Schema;
+
+use toshi_types::{Error, KEYWORD_SUFFIX};
+
+use crate::Result;
+
+/// How many objects deep a document can nest before it is rejected
+pub const MAX_NESTED_DEPTH: usize = 20;
+
+/// Whether an index has any dotted fields that nested documents are flattened into
+pub fn has_nested_fields(schema: &Schema) -> bool {
+    schema.fields().any(|(_, entry)| entry.name().contains('.'))
+}
+
+fn insert(out: &mut Map<String, Value>, key: String, value: Value, multi: bool) {
+    match out.get_mut(&key) {
+        Some(Value::Array(values)) => values.push(value),
+        Some(existing) => {
+            let first = existing.take();
+            *existing = Value::Array(vec![first, value]);
+        }
+        None if multi => {
+            out.insert(key, Value::Array(vec![value]));
+        }
+        None => {
+            out.insert(key, value);
+        }
+    }
+}
+
+fn nests(value: &Value) -> bool {
+    match value {
+        Value::Object(_) => true,
+        Value::Array(values) => values.iter().any(Value::is_object),
+        _ => false,
+    }
+}
+
+fn flatten_into(out: &mut Map<String, Value>, key: String, value: Value, depth: usize, multi: bool) -> Result<()> {
+    if depth > MAX_NESTED_DEPTH {
+        return Err(Error::InvalidDocument(format!(
+            "Document field '{}' is nested deeper than {} levels",
+            key, MAX_NESTED_DEPTH
+        )));
+    }
+    match value {
+        Value::Object(fields) => {
+            for (k, v) in fields {
+                flatten_into(out, format!("{}.{}", key, k), v, depth + 1, multi)?;
+            }
+        }
+        Value::Array(values) => {
+            for v in values {
+                flatten_into(out, key.clone(), v, depth, true)?;
+            }
+        }
+        v => insert(out, key, v, multi),
+    }
+    Ok(())
+}
+
+/// Flatten the nested objects of a document into dotted field names, `{"user": {"name": "x"}}`
+/// becomes `{"user.name": "x"}`. Objects inside arrays are flattened the same way and their
+/// values are collected per field, so `{"tags": [{"id": 1}, {"id": 2}]}` becomes
+/// `{"tags.id": [1, 2]}`. Anything reached through an array is always an array.
+pub fn flatten(doc: &mut Value) -> Result<()> {
+    if let Value::Object(fields) = doc {
+        if !fields.values().any(nests) {
+            return Ok(());
+        }
+        let mut out = Map::new();
+        for (k, v) in std::mem::replace(fields, Map::new()) {
+            flatten_into(&mut out, k, v, 1, false)?;
+        }
+        *fields = out;
+    }
+    Ok(())
+}
+
+/// Copy the values of text fields with a keyword field into it, so one value in a document is
+/// indexed both tokenized and whole. A value given for the keyword field itself is left as it is.
+pub fn copy_keywords(doc: &mut Value, schema: &Schema) {
+    if let Value::Object(fields) = doc {
+        let copies: Vec<(String, Value)> = fields
+            .iter()
+            .map(|(k, v)| (format!("{}{}", k, KEYWORD_SUFFIX), v))
+            .filter(|(k, _)| !fields.contains_key(k) && schema.get_field(k).is_some())
+            .map(|(k, v)| (k, v.clone()))
+            .collect();
+        fields.extend(copies);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_flatten() -> Result<()> {
+        let mut doc = json!({
+            "title": "toshi",
+            "user": { "name": "shiba", "address": { "city": "tokyo" } },
+            "tags": [ { "id": 1 }, { "id": 2, "extra": [3, 4] } ],
+            "scores": [5]
+        });
+        flatten(&mut doc)?;
+        assert_eq!(
+            doc,
+            json!({
+                "title": "toshi",
+                "user.name": "shiba",
+                "user.address.city": "tokyo",
+                "tags.id": [1, 2],
+                "tags.extra": [3, 4],
+                "scores": [5]
+            })
+        );
+
+        let mut deep = json!("leaf");
+        for _ in 0..=MAX_NESTED_DEPTH {
+            deep = json!({ "a": deep });
+        }
+        assert!(flatten(&mut deep).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_keywords() {
+        let mut builder = tantivy::schema::SchemaBuilder::new();
+        builder.add_text_field("title", tantivy::schema::TEXT);
+        builder.add_text_field("title.keyword", tantivy::schema::STRING);
+        builder.add_text_field("body", tantivy::schema::TEXT);
+        let schema = builder.build();
+
+        let mut doc = json!({ "title": "Hello World", "body": "text" });
+        copy_keywords(&mut doc, &schema);
+        assert_eq!(
+            doc,
+            json!({ "title": "Hello World", "title.keyword": "Hello World", "body": "text" })
+        );
+
+        let mut doc = json!({ "title": "Hello World", "title.keyword": "hello" });
+        copy_keywords(&mut doc, &schema);
+        assert_eq!(doc["title.keyword"], "hello");
+    }
+}
@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tantivy::chrono::Utc;
+use tantivy::schema::{FieldType, Schema};
+
+use toshi_types::Error;
+
+use crate::Result;
+
+/// A single step of an ingest pipeline
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Processor {
+    /// Set a field to a value, `{{field}}` in a string value is replaced with that field's value
+    Set { field: String, value: Value },
+    /// Lowercase a text field
+    Lowercase { field: String },
+    /// Trim leading and trailing whitespace from a text field
+    Trim { field: String },
+    /// Move a field's value to another field
+    Rename { field: String, target_field: String },
+    /// Set a field to the time the document was ingested
+    Now { field: String },
+}
+
+/// An ordered list of processors that every document added to an index is run through before it
+/// is parsed against the index's schema
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct Pipeline {
+    pub processors: Vec<Processor>,
+}
+
+fn processor_error<M: ToString>(processor: &str, message: M) -> Error {
+    Error::ProcessorError {
+        processor: processor.into(),
+        reason: message.to_string(),
+    }
+}
+
+fn render(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Array(values) => values.iter().map(render).collect::<Vec<_>>().join(" "),
+        v => v.to_string(),
+    }
+}
+
+fn map_text<F>(doc: &mut Map<String, Value>, processor: &str, field: &str, f: F) -> Result<()>
+where
+    F: Fn(&str) -> String,
+{
+    let value = doc
+        .get_mut(field)
+        .ok_or_else(|| processor_error(processor, format!("field '{}' is missing", field)))?;
+    let values = match value {
+        Value::Array(values) => values.iter_mut().collect(),
+        v => vec![v],
+    };
+    for v in values {
+        match v {
+            Value::String(s) => *s = f(s),
+            _ => return Err(processor_error(processor, format!("field '{}' is not text", field))),
+        }
+    }
+    Ok(())
+}
+
+impl Processor {
+    fn name(&self) -> &'static str {
+        match self {
+            Processor::Set { .. } => "set",
+            Processor::Lowercase { .. } => "lowercase",
+            Processor::Trim { .. } => "trim",
+            Processor::Rename { .. } => "rename",
+            Processor::Now { .. } => "now",
+        }
+    }
+
+    pub fn apply(&self, doc: &mut Map<String, Value>, schema: &Schema) -> Result<()> {
+        let name = self.name();
+        match self {
+            Processor::Set { field, value } => {
+                let value = match value {
+                    Value::String(template) => Value::String(
+                        doc.iter()
+                            .fold(template.clone(), |t, (k, v)| t.replace(&format!("{{{{{}}}}}", k), &render(v))),
+                    ),
+                    v => v.clone(),
+                };
+                doc.insert(field.clone(), value);
+            }
+            Processor::Lowercase { field } => map_text(doc, name, field, str::to_lowercase)?,
+            Processor::Trim { field } => map_text(doc, name, field, |s| s.trim().to_string())?,
+            Processor::Rename { field, target_field } => {
+                let value = doc
+                    .remove(field)
+                    .ok_or_else(|| processor_error(name, format!("field '{}' is missing", field)))?;
+                doc.insert(target_field.clone(), value);
+            }
+            Processor::Now { field } => {
+                let now = Utc::now();
+                let field_type = schema.get_field(field).map(|f| schema.get_field_entry(f).field_type());
+                let value = match field_type {
+                    Some(FieldType::I64(_)) => Value::from(now.timestamp()),
+                    Some(FieldType::U64(_)) => Value::from(now.timestamp() as u64),
+                    _ => Value::from(now.to_rfc3339()),
+                };
+                doc.insert(field.clone(), value);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Pipeline {
+    /// Run a document through every processor in order, stopping at the first one that fails
+    pub fn apply(&self, doc: &mut Value, schema: &Schema) -> Result<()> {
+        let doc = doc
+            .as_object_mut()
+            .ok_or_else(|| processor_error("pipeline", "document is not a json object"))?;
+        for processor in &self.processors {
+            processor.apply(doc, schema)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tantivy::schema::{SchemaBuilder, STORED, TEXT};
+
+    use super::*;
+
+    #[test]
+    fn test_processors() -> Result<()> {
+        let mut builder = SchemaBuilder::new();
+        builder.add_text_field("key", TEXT | STORED);
+        let schema = builder.build();
+
+        let pipeline: Pipeline = serde_json::from_value(json!({
+            "processors": [
+                { "trim": { "field": "name" } },
+                { "lowercase": { "field": "name" } },
+                { "set": { "field": "key", "value": "{{name}}-{{id}}" } },
+                { "rename": { "field": "id", "target_field": "doc_id" } }
+            ]
+        }))?;
+        let mut doc = json!({ "name": "  Toshi ", "id": 7 });
+        pipeline.apply(&mut doc, &schema)?;
+        assert_eq!(doc, json!({ "name": "toshi", "key": "toshi-7", "doc_id": 7 }));
+
+        let err = pipeline.apply(&mut json!({ "name": 1 }), &schema).unwrap_err();
+        assert_eq!(err.to_string(), "Processor 'trim' failed: field 'name' is not text");
+        Ok(())
+    }
+}
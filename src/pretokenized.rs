@@ -0,0 +1,133 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tantivy::tokenizer::{PreTokenizedString, Token};
+
+use toshi_types::Error;
+
+use crate::Result;
+
+/// A token of a pre-tokenized field the way it's given in a document. Offsets are only kept for
+/// highlighting, a token without them gets the offsets of its text in the tokens joined by spaces.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InputToken {
+    text: String,
+    position: usize,
+    #[serde(default)]
+    offset_from: Option<usize>,
+    #[serde(default)]
+    offset_to: Option<usize>,
+}
+
+/// The value of the pre-tokenized field `name`, an array of tokens, as the tokens tantivy indexes
+/// verbatim. Tokens are indexed in the order of their positions, the text stored for the field is
+/// their text joined by spaces.
+pub fn tokenize(name: &str, value: Value) -> Result<PreTokenizedString> {
+    let mut given: Vec<InputToken> = serde_json::from_value(value).map_err(|e| {
+        Error::InvalidDocument(format!(
+            "Field '{}' is pre-tokenized and takes an array of tokens with a text and position: {}",
+            name, e
+        ))
+    })?;
+    given.sort_by_key(|t| t.position);
+    let mut text = String::new();
+    let mut tokens = Vec::with_capacity(given.len());
+    for token in given {
+        if !text.is_empty() {
+            text.push(' ');
+        }
+        let start = text.len();
+        text.push_str(&token.text);
+        tokens.push(Token {
+            offset_from: token.offset_from.unwrap_or(start),
+            offset_to: token.offset_to.unwrap_or_else(|| text.len()),
+            position: token.position,
+            text: token.text,
+            position_length: 1,
+        });
+    }
+    Ok(PreTokenizedString { text, tokens })
+}
+
+/// Take the pre-tokenized `fields` out of a document, turning their tokens into the form tantivy
+/// parses them from, so they are left alone while the rest of the document is rewritten
+pub fn take(doc: &mut Value, fields: &HashSet<String>) -> Result<Map<String, Value>> {
+    let mut taken = Map::new();
+    if let Value::Object(values) = doc {
+        for name in fields {
+            if let Some(value) = values.remove(name) {
+                taken.insert(name.clone(), serde_json::to_value(tokenize(name, value)?)?);
+            }
+        }
+    }
+    Ok(taken)
+}
+
+/// Put the fields [`take`] took out of a document back into it
+pub fn restore(doc: &mut Value, taken: Map<String, Value>) {
+    if let Value::Object(values) = doc {
+        values.extend(taken);
+    }
+}
+
+/// Turn every value of the text fields in `limits` that's longer than its field's limit, counted in
+/// characters, into a pre-tokenized value without any tokens. The value is still stored but none of
+/// it is indexed, so it can't bloat the field's terms.
+pub fn ignore_above(doc: &mut Value, limits: &HashMap<String, usize>) -> Result<()> {
+    fn skip(value: &mut Value, limit: usize) -> Result<()> {
+        match value {
+            Value::String(text) if text.chars().count() > limit => {
+                let unindexed = PreTokenizedString {
+                    text: std::mem::take(text),
+                    tokens: Vec::new(),
+                };
+                *value = serde_json::to_value(unindexed)?;
+            }
+            Value::Array(values) => {
+                for value in values {
+                    skip(value, limit)?;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+    if let Value::Object(values) = doc {
+        for (name, limit) in limits {
+            if let Some(value) = values.get_mut(name) {
+                skip(value, *limit)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_tokenize() -> Result<()> {
+        let tokens = json!([
+            { "text": "york", "position": 1 },
+            { "text": "New", "position": 0 },
+            { "text": "city", "position": 3, "offset_from": 20, "offset_to": 24 }
+        ]);
+        let tokenized = tokenize("place", tokens)?;
+        assert_eq!(tokenized.text, "New york city");
+        let positions: Vec<(&str, usize, usize, usize)> = tokenized
+            .tokens
+            .iter()
+            .map(|t| (t.text.as_str(), t.position, t.offset_from, t.offset_to))
+            .collect();
+        assert_eq!(positions, vec![("New", 0, 0, 3), ("york", 1, 4, 8), ("city", 3, 20, 24)]);
+
+        assert!(tokenize("place", json!("New york city")).is_err());
+        assert!(tokenize("place", json!([{ "text": "New" }])).is_err());
+        Ok(())
+    }
+}
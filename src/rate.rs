@@ -0,0 +1,90 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How many one second buckets a [`RateCounter`] keeps, the longest window it can average over
+const SLOTS: u64 = 300;
+
+/// Counts events, like searches or indexed documents, in a ring buffer of one second buckets so
+/// their rate over the last few minutes can be read without remembering every event. A bucket is
+/// reused once the second it counted is more than [`SLOTS`] seconds ago.
+#[derive(Debug)]
+pub struct RateCounter {
+    started: Instant,
+    /// The second each bucket last counted, since `started`, and how many events it counted
+    slots: Mutex<Vec<(u64, u64)>>,
+}
+
+impl Default for RateCounter {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            slots: Mutex::new(vec![(0, 0); SLOTS as usize]),
+        }
+    }
+}
+
+impl RateCounter {
+    /// Count `n` events happening now
+    pub fn record(&self, n: u64) {
+        self.record_at(self.now(), n);
+    }
+
+    /// The average number of events a second over the last `window`, which is capped at five minutes
+    pub fn per_second(&self, window: Duration) -> f64 {
+        self.per_second_at(self.now(), window)
+    }
+
+    /// The second it is since the counter started, counted from 1 so no event lands in the
+    /// second the empty buckets start out at
+    fn now(&self) -> u64 {
+        self.started.elapsed().as_secs() + 1
+    }
+
+    fn record_at(&self, second: u64, n: u64) {
+        if let Ok(mut slots) = self.slots.lock() {
+            let slot = &mut slots[(second % SLOTS) as usize];
+            if slot.0 != second {
+                *slot = (second, 0);
+            }
+            slot.1 += n;
+        }
+    }
+
+    fn per_second_at(&self, now: u64, window: Duration) -> f64 {
+        let window = window.as_secs().min(SLOTS).max(1);
+        let events: u64 = match self.slots.lock() {
+            Ok(slots) => slots
+                .iter()
+                .filter(|(second, _)| *second <= now && second + window > now)
+                .map(|(_, n)| n)
+                .sum(),
+            Err(_) => 0,
+        };
+        events as f64 / window as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use toshi_test::cmp_float;
+
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+    const FIVE_MINUTES: Duration = Duration::from_secs(300);
+
+    #[test]
+    fn test_rate_windows() {
+        let counter = RateCounter::default();
+        counter.record_at(10, 120);
+        counter.record_at(200, 30);
+        counter.record_at(230, 30);
+        assert!(cmp_float(counter.per_second_at(230, MINUTE) as f32, 1.0));
+        assert!(cmp_float(counter.per_second_at(230, FIVE_MINUTES) as f32, 0.6));
+
+        // Once a bucket's second is five minutes ago it's counted again from zero
+        counter.record_at(310, 6);
+        assert!(cmp_float(counter.per_second_at(310, FIVE_MINUTES) as f32, 0.22));
+        assert!(cmp_float(counter.per_second_at(400, MINUTE) as f32, 0.0));
+    }
+}
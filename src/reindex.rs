@@ -0,0 +1,146 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tantivy::DocAddress;
+use tokio::time;
+use tracing::*;
+
+use toshi_types::Error;
+
+use crate::handle::{IndexHandle, LocalIndex};
+use crate::tasks::TaskProgress;
+use crate::{AddDocument, Result};
+
+/// The longest a rate limited copy sleeps at once, a very low rate sleeps this long over and over
+const MAX_SLEEP_SECS: f64 = 60.0 * 60.0;
+
+/// Check a copy's rate is a number of documents a second above 0
+pub fn check_rate(rate: Option<f32>) -> Result<()> {
+    match rate {
+        Some(r) if !(r.is_finite() && r > 0.0) => Err(Error::QueryError(format!(
+            "requests_per_second must be a number greater than 0, not {}",
+            r
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Copy every document of `source` into `dest` and commit them. Only a document's stored fields
+/// can be read back out of an index, so fields `source` doesn't store are not copied. Documents go
+/// through `dest`'s ingest pipeline like any other insert. With `rate` set, at most that many
+/// documents are copied a second.
+pub async fn reindex(source: LocalIndex, dest: LocalIndex, rate: Option<f32>, progress: Arc<TaskProgress>) -> Result<u64> {
+    dest.check_writable()?;
+    check_rate(rate)?;
+    let schema = source.get_index().schema();
+    let searcher = source.searcher();
+    progress.set_total(searcher.num_docs());
+
+    let started = Instant::now();
+    let mut copied = 0;
+    for (ord, segment) in searcher.segment_readers().iter().enumerate() {
+        for doc_id in (0..segment.max_doc()).filter(|d| !segment.is_deleted(*d)) {
+            let doc = searcher.doc(DocAddress(ord as u32, doc_id))?;
+            let document = serde_json::to_value(schema.to_named_doc(&doc))?;
+            dest.add_document(AddDocument {
+                options: None,
+                document,
+                key: None,
+                if_version: None,
+            })
+            .await?;
+            copied += 1;
+            progress.advance(1);
+            if let Some(rate) = rate {
+                // Sleep off however far ahead of the rate the copy has gotten
+                let due = copied as f64 / f64::from(rate);
+                loop {
+                    let ahead = due - started.elapsed().as_secs_f64();
+                    if ahead <= 0.0 {
+                        break;
+                    }
+                    time::delay_for(Duration::from_secs_f64(ahead.min(MAX_SLEEP_SECS))).await;
+                }
+            }
+        }
+    }
+
+    let opstamp = dest.commit().await?;
+    info!(
+        "Reindexed {} documents from {} into {}, opstamp={}",
+        copied,
+        source.get_name(),
+        dest.get_name(),
+        opstamp
+    );
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::Index;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reindex_rate() -> Result<()> {
+        let index = toshi_test::create_test_index();
+        let source = LocalIndex::new(index.clone(), Default::default(), "source")?;
+        let dest = LocalIndex::new(Index::create_in_ram(index.schema()), Default::default(), "dest")?;
+
+        let started = Instant::now();
+        let progress = Arc::new(TaskProgress::default());
+        let copied = reindex(source.clone(), dest.clone(), Some(10.0), Arc::clone(&progress)).await?;
+        assert_eq!(copied, 5);
+        assert!(started.elapsed() >= Duration::from_millis(500));
+        assert_eq!(progress.percent(), 100.0);
+
+        let zero = reindex(source.clone(), dest.clone(), Some(0.0), Arc::clone(&progress)).await;
+        assert!(zero.is_err());
+        assert!(check_rate(Some(f32::NAN)).is_err());
+        assert!(check_rate(Some(-1.0)).is_err());
+
+        // A rate so low the copy's next document is due centuries from now sleeps rather than overflowing
+        let tiny = reindex(source, dest, Some(1e-30), progress);
+        assert!(time::timeout(Duration::from_millis(50), tiny).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reindex_and_update_are_sequenced() -> Result<()> {
+        use tantivy::schema::{SchemaBuilder, FAST, INDEXED, STORED, STRING};
+        use toshi_types::{UpdateByQuery, ID_FIELD, SEQUENCE_FIELD};
+
+        let mut builder = SchemaBuilder::new();
+        builder.add_text_field(ID_FIELD, STRING | STORED);
+        builder.add_text_field("title", STRING | STORED);
+        builder.add_u64_field(SEQUENCE_FIELD, INDEXED | FAST | STORED);
+        let schema = builder.build();
+        let source = LocalIndex::new(Index::create_in_ram(schema.clone()), Default::default(), "source")?;
+        let dest = LocalIndex::new(Index::create_in_ram(schema), Default::default(), "dest")?;
+        for id in &["a", "b"] {
+            let doc = serde_json::json!({ ID_FIELD: id, "title": "draft" });
+            source.add_document(AddDocument::new(None, doc)).await?;
+        }
+        source.commit().await?;
+        source.reload()?;
+
+        // Copied documents are new writes to the destination and get its own numbers
+        reindex(source, dest.clone(), None, Arc::new(TaskProgress::default())).await?;
+        dest.reload()?;
+        let copied = dest.changes(0, 10)?;
+        assert_eq!(copied.changes.len(), 2);
+
+        let update: UpdateByQuery = serde_json::from_value(serde_json::json!({
+            "options": { "commit": true },
+            "query": { "term": { ID_FIELD: "a" } },
+            "set": { "title": "published" }
+        }))?;
+        dest.update_by_query(update, false).await?;
+        dest.reload()?;
+        let updated = dest.changes(copied.watermark, 10)?;
+        assert_eq!(updated.changes.len(), 1);
+        assert_eq!(updated.changes[0].doc["title"][0], "published");
+        Ok(())
+    }
+}
@@ -2,6 +2,7 @@ use std::convert::Infallible;
 use std::net::{SocketAddr, TcpListener};
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
+use std::time::Duration;
 
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, Server};
@@ -9,14 +10,40 @@ use serde::Deserialize;
 use tower_util::BoxService;
 use tracing::info;
 
+use toshi_types::Error;
+
+use crate::cluster::replication::Consistency;
+use crate::cluster::routing::ReadPreference;
 use crate::handlers::*;
 use crate::index::SharedCatalog;
-use crate::utils::{not_found, parse_path};
+use crate::utils::{decode_body, not_found, parse_path};
 
 #[derive(Deserialize, Debug, Default)]
 pub struct QueryOptions {
     pub pretty: Option<bool>,
     pub include_sizes: Option<bool>,
+    pub include_settings: Option<bool>,
+    pub profile: Option<bool>,
+    pub replicas: Option<usize>,
+    pub preference: Option<String>,
+    pub consistency: Option<Consistency>,
+    pub dead_letter_index: Option<String>,
+    pub commit_every: Option<usize>,
+    #[serde(rename = "async")]
+    pub run_async: Option<bool>,
+    pub older_than: Option<u64>,
+    pub routing: Option<String>,
+    pub raw: Option<bool>,
+    pub q: Option<String>,
+    pub df: Option<String>,
+    pub limit: Option<usize>,
+    pub v: Option<String>,
+    pub dry_run: Option<bool>,
+    #[serde(rename = "_source")]
+    pub source: Option<bool>,
+    pub since: Option<u64>,
+    pub compression: Option<String>,
+    pub routing_partitions: Option<usize>,
 }
 
 impl QueryOptions {
@@ -25,12 +52,109 @@ impl QueryOptions {
         self.include_sizes.unwrap_or(false)
     }
 
+    #[inline]
+    pub fn include_settings(&self) -> bool {
+        self.include_settings.unwrap_or(false)
+    }
+
+    #[inline]
+    pub fn profile(&self) -> bool {
+        self.profile.unwrap_or(false)
+    }
+
+    #[inline]
+    pub fn replicas(&self) -> usize {
+        self.replicas.unwrap_or(0)
+    }
+
+    /// The read preference of the request and the session it pins reads to, see [`ReadPreference::parse`]
+    #[inline]
+    pub fn preference(&self) -> Result<(ReadPreference, Option<&str>), Error> {
+        self.preference
+            .as_deref()
+            .map_or(Ok((ReadPreference::Replica, None)), ReadPreference::parse)
+    }
+
+    #[inline]
+    pub fn consistency(&self) -> Consistency {
+        self.consistency.unwrap_or(Consistency::One)
+    }
+
+    #[inline]
+    pub fn commit_every(&self) -> usize {
+        self.commit_every.unwrap_or(0)
+    }
+
+    /// Whether a long running operation should be started as a background task instead of being
+    /// waited on
+    #[inline]
+    pub fn run_async(&self) -> bool {
+        self.run_async.unwrap_or(false)
+    }
+
+    /// How long ago, in seconds, a soft deleted document must have been deleted to be purged
+    #[inline]
+    pub fn older_than(&self) -> Duration {
+        Duration::from_secs(self.older_than.unwrap_or(0))
+    }
+
+    /// Whether a delete or reindex should only report how many documents it would affect
+    #[inline]
+    pub fn dry_run(&self) -> bool {
+        self.dry_run.unwrap_or(false)
+    }
+
+    /// The sequence number a `_changes` request wants the documents committed after
+    #[inline]
+    pub fn since(&self) -> u64 {
+        self.since.unwrap_or(0)
+    }
+
+    /// Whether a search should load the stored fields of its hits, without them it only returns
+    /// where each hit is in the index and its score
+    #[inline]
+    pub fn source(&self) -> bool {
+        self.source.unwrap_or(true)
+    }
+
+    /// The value that pins a read or write to a single shard of a sharded index
+    #[inline]
+    pub fn routing(&self) -> Option<&str> {
+        self.routing.as_deref()
+    }
+
+    /// Whether a search should return the bare array of its hits instead of the full results
+    #[inline]
+    pub fn raw(&self) -> bool {
+        self.raw.unwrap_or(false)
+    }
+
+    /// The query string of a query string search
+    #[inline]
+    pub fn q(&self) -> &str {
+        self.q.as_deref().unwrap_or("")
+    }
+
+    /// Whether plaintext output should have a header line, `?v` on its own turns it on
+    #[inline]
+    pub fn verbose(&self) -> bool {
+        self.v.as_ref().map_or(false, |v| v != "false")
+    }
+
     #[inline]
     pub fn pretty(&self) -> bool {
         self.pretty.unwrap_or(false)
     }
 }
 
+fn accepts_ndjson(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all(hyper::header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.contains("application/x-ndjson"))
+}
+
 pub type BoxedFn = BoxService<Request<Body>, Response<Body>, hyper::Error>;
 
 #[derive(Clone)]
@@ -52,17 +176,56 @@ impl Router {
             .and_then(|q| serde_urlencoded::from_str(q).ok())
             .unwrap_or_default();
 
+        let body = match decode_body(&parts.headers, body, catalog.settings.max_body_size).await {
+            Ok(v) => v,
+            Err(e) => return Ok(Response::from(e)),
+        };
+
         let method = parts.method;
         let path = parse_path(parts.uri.path());
 
         match (&method, &path[..]) {
-            (m, [idx, "_create"]) if m == Method::PUT => create_index(catalog, body, (*idx).to_string()).await,
+            (m, [idx, "_create"]) if m == Method::PUT => create_index(catalog, body, (*idx).to_string(), query_options).await,
+            (m, [idx, "_create_from_sample"]) if m == Method::PUT => {
+                create_index_from_sample(catalog, body, (*idx).to_string(), query_options).await
+            }
             (m, [idx, "_summary"]) if m == Method::GET => index_summary(catalog, (*idx).to_string(), query_options).await,
             (m, [idx, "_flush"]) if m == Method::GET => flush(catalog, (*idx).to_string()).await,
-            (m, [idx, "_bulk"]) if m == Method::POST => bulk_insert(catalog, watcher.clone(), body, (*idx).to_string()).await,
-            (m, [idx]) if m == Method::POST => doc_search(catalog, body, (*idx).to_string()).await,
-            (m, [idx]) if m == Method::PUT => add_document(catalog, body, (*idx).to_string()).await,
-            (m, [idx]) if m == Method::DELETE => delete_term(catalog, body, (*idx).to_string()).await,
+            (m, [idx, "_stats"]) if m == Method::GET => index_stats(catalog, (*idx).to_string()).await,
+            (m, [idx, "_merge"]) if m == Method::POST => merge(catalog, (*idx).to_string()).await,
+            (m, [idx, "_purge"]) if m == Method::POST => purge(catalog, (*idx).to_string(), query_options).await,
+            (m, [idx, "_reindex"]) if m == Method::POST => reindex(catalog, body, (*idx).to_string(), query_options).await,
+            (m, [idx, "_update_by_query"]) if m == Method::POST => update_by_query(catalog, body, (*idx).to_string(), query_options).await,
+            (m, [idx, "_partitions", partition]) if m == Method::PUT => {
+                move_partition(catalog, body, (*idx).to_string(), (*partition).to_string()).await
+            }
+            (m, [idx, "_clone", dest]) if m == Method::POST => clone_index(catalog, (*idx).to_string(), (*dest).to_string()).await,
+            (m, [idx, "_field_stats", field]) if m == Method::GET => field_stats(catalog, (*idx).to_string(), (*field).to_string()).await,
+            (m, [idx, "_reload"]) if m == Method::GET => reload(catalog, (*idx).to_string()).await,
+            (m, [idx, "_refresh"]) if m == Method::POST => refresh(catalog, (*idx).to_string()).await,
+            (m, [idx, "_read_only"]) if m == Method::PUT => set_read_only(catalog, (*idx).to_string(), true).await,
+            (m, [idx, "_read_only"]) if m == Method::DELETE => set_read_only(catalog, (*idx).to_string(), false).await,
+            (m, [idx, "_pipeline"]) if m == Method::GET => get_pipeline(catalog, (*idx).to_string()).await,
+            (m, [idx, "_pipeline"]) if m == Method::PUT => set_pipeline(catalog, body, (*idx).to_string()).await,
+            (m, [idx, "_pipeline"]) if m == Method::DELETE => delete_pipeline(catalog, (*idx).to_string()).await,
+            (m, [idx, "_mget"]) if m == Method::POST => mget(catalog, (*idx).to_string(), body).await,
+            (m, [idx, "_changes"]) if m == Method::GET => changes(catalog, (*idx).to_string(), query_options).await,
+            (m, [idx, "_normalize"]) if m == Method::POST => normalize_search(catalog, body, (*idx).to_string()).await,
+            (m, [idx, "_validate"]) if m == Method::POST => validate_query(catalog, (*idx).to_string(), body).await,
+            (m, [idx, "_search"]) if m == Method::GET => query_string_search(catalog, (*idx).to_string(), query_options).await,
+            (m, [idx, "_bulk"]) if m == Method::POST => {
+                bulk_insert(catalog, watcher.clone(), body, (*idx).to_string(), query_options).await
+            }
+            (m, ["_metrics"]) if m == Method::GET => metrics(catalog).await,
+            (m, ["_indexes"]) if m == Method::GET => list_indexes(catalog).await,
+            (m, ["_cat", "indices"]) if m == Method::GET => cat_indices(catalog, query_options).await,
+            (m, ["_tasks", id]) if m == Method::GET => task_status(catalog, (*id).to_string()).await,
+            (m, [idx]) if m == Method::POST && accepts_ndjson(&parts.headers) => {
+                doc_search_ndjson(catalog, body, (*idx).to_string(), query_options).await
+            }
+            (m, [idx]) if m == Method::POST => doc_search(catalog, body, (*idx).to_string(), query_options).await,
+            (m, [idx]) if m == Method::PUT => add_document(catalog, body, (*idx).to_string(), query_options).await,
+            (m, [idx]) if m == Method::DELETE => delete_term(catalog, body, (*idx).to_string(), query_options).await,
             (m, [idx]) if m == Method::GET => {
                 if idx == &"favicon.ico" {
                     not_found().await
@@ -138,4 +301,39 @@ pub mod tests {
         assert_eq!(req.status(), StatusCode::NOT_FOUND);
         Ok(())
     }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_gzip_bulk() -> Result<(), Box<dyn std::error::Error>> {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        let catalog = crate::index::tests::create_test_catalog("test_index");
+        let watcher = Arc::new(AtomicBool::new(false));
+        let lines = r#"{"test_text": "zipped1", "test_i64": 123, "test_u64": 321, "test_unindex": "asdf"}
+{"test_text": "zipped2", "test_i64": 456, "test_u64": 678, "test_unindex": "asdf"}"#;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(lines.as_bytes())?;
+        let req = Request::post("/test_index/_bulk")
+            .header(hyper::header::CONTENT_ENCODING, "gzip")
+            .body(Body::from(encoder.finish()?))?;
+        let resp = Router::route(Arc::clone(&catalog), Arc::clone(&watcher), req).await?;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        // The documents are indexed in the background, the bulk flag clears once the writer has them
+        while watcher.load(std::sync::atomic::Ordering::SeqCst) {
+            tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+        }
+
+        crate::handlers::flush(Arc::clone(&catalog), "test_index".into()).await?;
+        catalog.get_index("test_index")?.reload()?;
+        let resp = crate::handlers::all_docs(Arc::clone(&catalog), "test_index".into()).await?;
+        assert_eq!(toshi_test::wait_json::<crate::SearchResults>(resp).await.hits, 7);
+
+        let req = Request::post("/test_index/_bulk")
+            .header(hyper::header::CONTENT_ENCODING, "gzip")
+            .body(Body::from(lines))?;
+        let resp = Router::route(Arc::clone(&catalog), watcher, req).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
 }
@@ -0,0 +1,374 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+use tantivy::fastfield::FastFieldReader;
+use tantivy::schema::{Cardinality, Field, FieldType, Schema};
+use tantivy::{DocId, Score, SegmentReader};
+
+use toshi_types::Error;
+
+use crate::Result;
+
+/// Scripts longer than this are rejected before they are parsed
+const MAX_SCRIPT_LEN: usize = 1024;
+/// How deeply parentheses and function calls can be nested in a script
+const MAX_SCRIPT_DEPTH: usize = 32;
+
+/// An arithmetic expression that computes the score of every hit of a search in place of the
+/// query's score, like `score * log(views + 1)`. A script can only do arithmetic on numbers, the
+/// query's `score` and the values of the index's single valued numeric fast fields, and call a
+/// few math functions, so it can't do anything but return a number.
+#[derive(Debug, Clone)]
+pub struct Script {
+    expr: Expr,
+    fields: Vec<(Field, FieldType)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Num(f64),
+    Score,
+    /// The position of the field in [`Script::fields`]
+    Field(usize),
+    Neg(Box<Expr>),
+    Op(char, Box<Expr>, Box<Expr>),
+    Call(Func, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Func {
+    Log,
+    Log10,
+    Sqrt,
+    Abs,
+    Min,
+    Max,
+    Pow,
+}
+
+impl Func {
+    fn named(name: &str) -> Option<(Self, usize)> {
+        match name {
+            "log" => Some((Func::Log, 1)),
+            "log10" => Some((Func::Log10, 1)),
+            "sqrt" => Some((Func::Sqrt, 1)),
+            "abs" => Some((Func::Abs, 1)),
+            "min" => Some((Func::Min, 2)),
+            "max" => Some((Func::Max, 2)),
+            "pow" => Some((Func::Pow, 2)),
+            _ => None,
+        }
+    }
+
+    fn apply(self, args: &[f64]) -> f64 {
+        match self {
+            Func::Log => args[0].ln(),
+            Func::Log10 => args[0].log10(),
+            Func::Sqrt => args[0].sqrt(),
+            Func::Abs => args[0].abs(),
+            Func::Min => args[0].min(args[1]),
+            Func::Max => args[0].max(args[1]),
+            Func::Pow => args[0].powf(args[1]),
+        }
+    }
+}
+
+impl Script {
+    /// Parse `source` against the fields of `schema`, naming a field that isn't a single valued
+    /// u64, i64 or f64 fast field is an error
+    pub fn parse(source: &str, schema: &Schema) -> Result<Self> {
+        if source.len() > MAX_SCRIPT_LEN {
+            return Err(Error::QueryError(format!("Script is longer than {} characters", MAX_SCRIPT_LEN)));
+        }
+        let mut parser = Parser {
+            chars: source.chars().peekable(),
+            schema,
+            fields: Vec::new(),
+            depth: 0,
+        };
+        let expr = parser.sum()?;
+        parser.skip_whitespace();
+        if let Some(c) = parser.chars.next() {
+            return Err(Error::QueryError(format!("Unexpected '{}' in script", c)));
+        }
+        Ok(Self {
+            expr,
+            fields: parser.fields,
+        })
+    }
+
+    /// The function that scores the documents of one segment, it's given each hit's id and query score
+    pub fn for_segment(&self, reader: &SegmentReader) -> impl FnMut(DocId, Score) -> Score {
+        let columns: Vec<Column> = self
+            .fields
+            .iter()
+            .map(|(field, kind)| {
+                let fast = reader.fast_fields();
+                let column = match kind {
+                    FieldType::I64(_) => fast.i64(*field).map(Column::I64),
+                    FieldType::F64(_) => fast.f64(*field).map(Column::F64),
+                    _ => fast.u64(*field).map(Column::U64),
+                };
+                column.expect("Script field is not a fast field")
+            })
+            .collect();
+        let expr = self.expr.clone();
+        move |doc, score| {
+            let values: Vec<f64> = columns.iter().map(|c| c.get(doc)).collect();
+            let scored = expr.eval(f64::from(score), &values) as Score;
+            if scored.is_nan() {
+                0.0
+            } else {
+                scored
+            }
+        }
+    }
+}
+
+enum Column {
+    U64(FastFieldReader<u64>),
+    I64(FastFieldReader<i64>),
+    F64(FastFieldReader<f64>),
+}
+
+impl Column {
+    fn get(&self, doc: DocId) -> f64 {
+        match self {
+            Column::U64(r) => r.get(doc) as f64,
+            Column::I64(r) => r.get(doc) as f64,
+            Column::F64(r) => r.get(doc),
+        }
+    }
+}
+
+impl Expr {
+    fn eval(&self, score: f64, values: &[f64]) -> f64 {
+        match self {
+            Expr::Num(n) => *n,
+            Expr::Score => score,
+            Expr::Field(i) => values[*i],
+            Expr::Neg(e) => -e.eval(score, values),
+            Expr::Op(op, l, r) => {
+                let (l, r) = (l.eval(score, values), r.eval(score, values));
+                match op {
+                    '+' => l + r,
+                    '-' => l - r,
+                    '*' => l * r,
+                    _ => l / r,
+                }
+            }
+            Expr::Call(func, args) => {
+                let args: Vec<f64> = args.iter().map(|a| a.eval(score, values)).collect();
+                func.apply(&args)
+            }
+        }
+    }
+}
+
+/// A recursive descent parser over `sum := product (('+' | '-') product)*`,
+/// `product := unary (('*' | '/') unary)*` and `unary := '-' unary | number | name | name '(' args ')' | '(' sum ')'`
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    schema: &'a Schema,
+    fields: Vec<(Field, FieldType)>,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().map_or(false, |c| c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    fn next_is(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&c) {
+            self.chars.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expr> {
+        let mut expr = self.product()?;
+        loop {
+            let op = if self.next_is('+') {
+                '+'
+            } else if self.next_is('-') {
+                '-'
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Op(op, Box::new(expr), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+        loop {
+            let op = if self.next_is('*') {
+                '*'
+            } else if self.next_is('/') {
+                '/'
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Op(op, Box::new(expr), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        self.depth += 1;
+        if self.depth > MAX_SCRIPT_DEPTH {
+            return Err(Error::QueryError(format!(
+                "Script is nested deeper than {} levels",
+                MAX_SCRIPT_DEPTH
+            )));
+        }
+        let expr = self.term();
+        self.depth -= 1;
+        expr
+    }
+
+    fn term(&mut self) -> Result<Expr> {
+        if self.next_is('-') {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        if self.next_is('(') {
+            let expr = self.sum()?;
+            return self.close(expr);
+        }
+        match self.chars.peek().copied() {
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let number = self.take_while(|c| c.is_ascii_digit() || c == '.');
+                number
+                    .parse()
+                    .map(Expr::Num)
+                    .map_err(|_| Error::QueryError(format!("'{}' is not a number", number)))
+            }
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                let name = self.take_while(|c| c.is_alphanumeric() || c == '_' || c == '.');
+                if self.next_is('(') {
+                    self.call(&name)
+                } else {
+                    self.variable(name)
+                }
+            }
+            Some(c) => Err(Error::QueryError(format!("Unexpected '{}' in script", c))),
+            None => Err(Error::QueryError("Script ended unexpectedly".into())),
+        }
+    }
+
+    fn take_while<F: Fn(char) -> bool>(&mut self, keep: F) -> String {
+        let mut taken = String::new();
+        while let Some(c) = self.chars.peek().copied().filter(|c| keep(*c)) {
+            taken.push(c);
+            self.chars.next();
+        }
+        taken
+    }
+
+    fn close(&mut self, expr: Expr) -> Result<Expr> {
+        if self.next_is(')') {
+            Ok(expr)
+        } else {
+            Err(Error::QueryError("Missing ')' in script".into()))
+        }
+    }
+
+    fn call(&mut self, name: &str) -> Result<Expr> {
+        let (func, arity) = Func::named(name).ok_or_else(|| Error::QueryError(format!("Unknown function '{}' in script", name)))?;
+        let mut args = vec![self.sum()?];
+        while self.next_is(',') {
+            args.push(self.sum()?);
+        }
+        if args.len() != arity {
+            return Err(Error::QueryError(format!(
+                "{} takes {} arguments but got {}",
+                name,
+                arity,
+                args.len()
+            )));
+        }
+        self.close(Expr::Call(func, args))
+    }
+
+    fn variable(&mut self, name: String) -> Result<Expr> {
+        if name == "score" {
+            return Ok(Expr::Score);
+        }
+        let field = self.schema.get_field(&name).ok_or_else(|| Error::UnknownIndexField(name.clone()))?;
+        let kind = self.schema.get_field_entry(field).field_type().clone();
+        let single_fast = match &kind {
+            FieldType::U64(o) | FieldType::I64(o) | FieldType::F64(o) => o.get_fastfield_cardinality() == Some(Cardinality::SingleValue),
+            _ => false,
+        };
+        if !single_fast {
+            return Err(Error::QueryError(format!(
+                "{} must be a single valued numeric fast field to use in a script",
+                name
+            )));
+        }
+        let position = match self.fields.iter().position(|(f, _)| *f == field) {
+            Some(p) => p,
+            None => {
+                self.fields.push((field, kind));
+                self.fields.len() - 1
+            }
+        };
+        Ok(Expr::Field(position))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::schema::{SchemaBuilder, FAST, TEXT};
+
+    use super::*;
+
+    fn schema() -> Schema {
+        let mut builder = SchemaBuilder::new();
+        builder.add_text_field("text", TEXT);
+        builder.add_u64_field("views", FAST);
+        builder.add_i64_field("rank", FAST);
+        builder.add_u64_field("plain", tantivy::schema::INDEXED);
+        builder.build()
+    }
+
+    fn eval(source: &str, score: f64, values: &[f64]) -> f64 {
+        Script::parse(source, &schema()).unwrap().expr.eval(score, values)
+    }
+
+    #[test]
+    fn test_eval() {
+        assert_eq!(eval("1 + 2 * 3", 0.0, &[]), 7.0);
+        assert_eq!(eval("(1 + 2) * 3", 0.0, &[]), 9.0);
+        assert_eq!(eval("8 / 2 / 2 - -1", 0.0, &[]), 3.0);
+        assert_eq!(eval("score * 2", 1.5, &[]), 3.0);
+        assert_eq!(eval("max(views, rank) + min(1, 2)", 0.0, &[4.0, 10.0]), 11.0);
+        assert!((eval("score * log(views + 1)", 2.0, &[std::f64::consts::E - 1.0]) - 2.0).abs() < 1e-9);
+        assert_eq!(eval("pow(2, 3) + sqrt(16) + abs(-1)", 0.0, &[]), 13.0);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let schema = schema();
+        for bad in &[
+            "score *",
+            "log(score",
+            "exec(1)",
+            "text * 2",
+            "plain",
+            "missing + 1",
+            "1 $ 2",
+            "min(1)",
+            "1.2.3",
+        ] {
+            assert!(Script::parse(bad, &schema).is_err(), "{}", bad);
+        }
+        let deep = format!("{}1{}", "(".repeat(100), ")".repeat(100));
+        assert!(Script::parse(&deep, &schema).is_err());
+    }
+}
@@ -0,0 +1,69 @@
+use std::cmp::Reverse;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use tantivy::collector::TopDocs;
+use tantivy::query::RangeQuery;
+use tantivy::schema::{Field, FieldType};
+use tantivy::{DocAddress, DocId, Document, Index, Searcher, SegmentReader};
+
+use toshi_types::{Error, SEQUENCE_FIELD};
+
+use crate::Result;
+
+/// Stamps every document written to an index whose schema has a [`SEQUENCE_FIELD`] with the next
+/// number of a sequence that only goes up, so the documents committed after a point can be found
+/// by their numbers. Numbers are handed out under the index's writer lock, so a commit makes every
+/// number below the highest one it commits visible at once.
+///
+/// The sequence starts after the opstamp of the index's last commit. Every stamped document is also
+/// an operation of the writer, so no committed number is past its commit's opstamp and numbers
+/// aren't handed out twice when the index is opened again.
+#[derive(Clone)]
+pub struct Sequencer {
+    field: Field,
+    next: Arc<AtomicU64>,
+}
+
+impl Sequencer {
+    /// The sequence of `index`, `None` when its schema has no [`SEQUENCE_FIELD`]
+    pub fn open(index: &Index) -> Result<Option<Self>> {
+        let schema = index.schema();
+        let field = match schema.get_field(SEQUENCE_FIELD) {
+            Some(field) => field,
+            None => return Ok(None),
+        };
+        let entry = schema.get_field_entry(field);
+        match entry.field_type() {
+            FieldType::U64(_) if entry.is_indexed() && entry.is_int_fast() => (),
+            _ => return Err(Error::QueryError(format!("{} must be an indexed u64 fast field", SEQUENCE_FIELD))),
+        }
+        let committed = index.load_metas()?.opstamp;
+        Ok(Some(Self {
+            field,
+            next: Arc::new(AtomicU64::new(committed + 1)),
+        }))
+    }
+
+    /// Give `doc` the next number of the sequence, replacing any it was sent with
+    pub fn stamp(&self, doc: &mut Document) {
+        let field = self.field;
+        doc.filter_fields(|f| f != field);
+        doc.add_u64(field, self.next.fetch_add(1, Ordering::SeqCst));
+    }
+
+    /// The first `limit` documents `searcher` sees that were stamped after `since`, with their
+    /// numbers, lowest number first
+    pub fn since(&self, searcher: &Searcher, since: u64, limit: usize) -> Result<Vec<(u64, DocAddress)>> {
+        let field = self.field;
+        let query = RangeQuery::new_u64_bounds(field, Bound::Excluded(since), Bound::Unbounded);
+        // The collector keeps the highest scores, so the numbers are reversed to keep the lowest
+        let collector = TopDocs::with_limit(limit.max(1)).custom_score(move |reader: &SegmentReader| {
+            let values = reader.fast_fields().u64(field).expect("Sequence field is not a u64 fast field");
+            move |doc: DocId| Reverse(values.get(doc))
+        });
+        let found = searcher.search(&query, &collector)?;
+        Ok(found.into_iter().map(|(Reverse(seq), addr)| (seq, addr)).collect())
+    }
+}
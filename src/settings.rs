@@ -1,11 +1,20 @@
+use std::collections::HashMap;
 use std::str::FromStr;
 
 use clap::ArgMatches;
 use config::{Config, ConfigError, File, FileFormat, Source};
 use crossbeam::channel::{bounded, unbounded, Receiver, Sender};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tantivy::merge_policy::*;
 
+use toshi_types::{DefaultOperator, Query};
+
+use crate::cluster::codec::WireFormat;
+use crate::cluster::placement::{LeastLoaded, Placement, RoundRobin};
+use crate::dead_letter::DeadLetter;
+use crate::similarity::Similarity;
+use crate::wal::WalSettings;
+
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub const HEADER: &str = r#"
@@ -30,7 +39,7 @@ pub enum MergePolicyType {
     NoMerge,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct ConfigMergePolicy {
     kind: String,
     min_merge_size: Option<usize>,
@@ -48,7 +57,145 @@ impl ConfigMergePolicy {
     }
 }
 
-#[derive(Deserialize, Clone, Debug)]
+/// The kind of tantivy Directory newly created indexes live in. Ram indexes never touch the disk,
+/// which makes them non-durable, everything in them is gone on restart so they should only be used
+/// for ephemeral or test data.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum DirectoryKind {
+    Mmap,
+    Ram,
+}
+
+impl DirectoryKind {
+    pub fn is_durable(self) -> bool {
+        self == DirectoryKind::Mmap
+    }
+}
+
+/// What to do with an index found to be corrupted when it is opened. `Quarantine` keeps the whole
+/// index out of service until it's repaired by hand, `Truncate` drops the segments that can't be
+/// read and opens the index with the rest, losing the documents in those segments. An index whose
+/// metadata can't be read is always quarantined.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum CorruptionPolicy {
+    Quarantine,
+    Truncate,
+}
+
+/// When an index's reader picks up new commits. `OnCommit` reloads as soon as a commit lands,
+/// `Manual` only when an index's `_reload` route is called and `Interval` at most once every so many
+/// milliseconds, checked when the index is searched. The less often a reader reloads the staler
+/// searches can be.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum ReaderReloadPolicy {
+    OnCommit,
+    Manual,
+    Interval(u64),
+}
+
+/// How hard a commit pushes the files of the segments it writes to disk. `Always` fsyncs every
+/// file before the commit is acknowledged, so a commit survives the machine losing power. `Os`
+/// hands the files to the operating system and only fsyncs the file listing the commit's segments,
+/// a commit survives the process crashing but not the machine going down.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CommitSync {
+    Always,
+    Os,
+}
+
+/// What happens to the fields of a document that aren't in its index's schema. `Reject` fails the
+/// document, `Ignore` indexes it without them and `Dynamic` adds them to the schema before indexing
+/// it, typed after their values.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownFields {
+    Reject,
+    Ignore,
+    Dynamic,
+}
+
+impl Default for UnknownFields {
+    fn default() -> Self {
+        UnknownFields::Ignore
+    }
+}
+
+/// A memory budget for a single search. Before a search allocates its collectors the memory it
+/// would need is estimated from the number of hits it asks for and the number of facet values in the
+/// fields it counts, a search estimated to go over `budget` bytes is rejected. A `budget` of 0 turns
+/// the breaker off.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CircuitBreaker {
+    #[serde(default = "Settings::default_breaker_budget")]
+    pub budget: u64,
+    /// Bytes counted for every hit a search asks for, which covers collecting and fetching its document
+    #[serde(default = "Settings::default_breaker_bytes_per_hit")]
+    pub bytes_per_hit: u64,
+    /// Bytes counted for every distinct value of a field a search counts facets of
+    #[serde(default = "Settings::default_breaker_bytes_per_facet_value")]
+    pub bytes_per_facet_value: u64,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self {
+            budget: Settings::default_breaker_budget(),
+            bytes_per_hit: Settings::default_breaker_bytes_per_hit(),
+            bytes_per_facet_value: Settings::default_breaker_bytes_per_facet_value(),
+        }
+    }
+}
+
+/// How far an index's writer may fall behind before writes to the index are turned away. Once
+/// `high_water` writes are waiting on the index's next commit further writes fail with a `503`, until
+/// a commit brings the writes waiting down to `low_water` or fewer. A `high_water` of 0 turns the
+/// breaker off.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct IngestionBreaker {
+    #[serde(default)]
+    pub high_water: usize,
+    #[serde(default)]
+    pub low_water: usize,
+}
+
+/// How long documents of an index are kept. A document whose `field`, a u64, i64 or date holding
+/// seconds since the epoch, is more than `max_age` seconds in the past is deleted by the next sweep.
+/// Documents without a value for `field` are kept.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Ttl {
+    pub field: String,
+    pub max_age: u64,
+}
+
+/// Which kinds of query, named the way they're keyed in a search (`term`, `regex`, `bool`...), can be
+/// run against an index. Without an `allow` list every kind not in `deny` is allowed.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct QueryTypes {
+    #[serde(default)]
+    pub allow: Option<Vec<String>>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl QueryTypes {
+    pub fn allows(&self, kind: &str) -> bool {
+        let allowed = match &self.allow {
+            Some(allow) => allow.iter().any(|k| k == kind),
+            None => true,
+        };
+        allowed && !self.deny.iter().any(|k| k == kind)
+    }
+}
+
+/// The strategy the cluster uses to decide which nodes host a new index's shards
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum PlacementKind {
+    RoundRobin,
+    LeastLoaded,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Experimental {
     #[serde(default = "Settings::default_consul_addr")]
     pub consul_addr: String,
@@ -58,6 +205,28 @@ pub struct Experimental {
     pub master: bool,
     #[serde(default = "Settings::default_nodes")]
     pub nodes: Vec<String>,
+    #[serde(default = "Settings::default_placement")]
+    pub placement: PlacementKind,
+    #[serde(default = "Settings::default_health_check_interval")]
+    pub health_check_interval: f32,
+    #[serde(default = "Settings::default_health_check_timeout")]
+    pub health_check_timeout: f32,
+    /// How many health checks in a row a node has to miss before the indexes it's the primary of
+    /// fail over, so one slow answer doesn't promote a replica
+    #[serde(default = "Settings::default_health_check_misses")]
+    pub health_check_misses: usize,
+    #[serde(default = "Settings::default_replication_timeout")]
+    pub replication_timeout: f32,
+    #[serde(default = "Settings::default_wire_format")]
+    pub wire_format: WireFormat,
+    /// How many points each shard gets on the ring routing values are placed on, more spread
+    /// routing values more evenly over the shards
+    #[serde(default = "Settings::default_virtual_nodes")]
+    pub virtual_nodes: usize,
+    /// How many operations behind its primary a replica that bootstrapped from a snapshot can be
+    /// and still start serving reads
+    #[serde(default = "Settings::default_max_bootstrap_lag")]
+    pub max_bootstrap_lag: u64,
 }
 
 impl Default for Experimental {
@@ -67,11 +236,19 @@ impl Default for Experimental {
             cluster_name: Settings::default_cluster_name(),
             master: Settings::default_master(),
             nodes: Settings::default_nodes(),
+            placement: Settings::default_placement(),
+            health_check_interval: Settings::default_health_check_interval(),
+            health_check_timeout: Settings::default_health_check_timeout(),
+            health_check_misses: Settings::default_health_check_misses(),
+            replication_timeout: Settings::default_replication_timeout(),
+            wire_format: Settings::default_wire_format(),
+            virtual_nodes: Settings::default_virtual_nodes(),
+            max_bootstrap_lag: Settings::default_max_bootstrap_lag(),
         }
     }
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Settings {
     #[serde(default = "Settings::default_host")]
     pub host: String,
@@ -91,8 +268,85 @@ pub struct Settings {
     pub auto_commit_duration: f32,
     #[serde(default = "Settings::default_bulk_buffer_size")]
     pub bulk_buffer_size: usize,
+    /// How many commits, copies and other heavy index operations may run at once on the blocking pool
+    #[serde(default = "Settings::default_blocking_pool_size")]
+    pub blocking_pool_size: usize,
     #[serde(default = "Settings::default_merge_policy")]
     pub merge_policy: ConfigMergePolicy,
+    #[serde(default = "Settings::default_directory_kind")]
+    pub directory_kind: DirectoryKind,
+    #[serde(default = "Settings::default_search_size")]
+    pub default_search_size: usize,
+    #[serde(default = "Settings::default_max_search_size")]
+    pub max_search_size: usize,
+    #[serde(default = "Settings::default_reject_oversized_search")]
+    pub reject_oversized_search: bool,
+    /// The furthest into a search's hits a page may reach, the `from` of a search plus its `limit`
+    #[serde(default = "Settings::default_max_result_window")]
+    pub max_result_window: usize,
+    /// The most clauses the boolean queries of a single search may have between them
+    #[serde(default = "Settings::default_max_clause_count")]
+    pub max_clause_count: usize,
+    /// Searches taking at least this many milliseconds are logged at WARN, none are when it's not set
+    #[serde(default = "Settings::default_slow_query_threshold")]
+    pub slow_query_threshold: Option<u64>,
+    /// The most bytes a compressed request body may decompress to
+    #[serde(default = "Settings::default_max_body_size")]
+    pub max_body_size: u64,
+    #[serde(default = "Settings::default_reader_reload_policy")]
+    pub reader_reload_policy: ReaderReloadPolicy,
+    #[serde(default = "Settings::default_commit_on_shutdown")]
+    pub commit_on_shutdown: bool,
+    #[serde(default = "Settings::default_commit_sync")]
+    pub commit_sync: CommitSync,
+    #[serde(default = "Settings::default_on_corruption")]
+    pub on_corruption: CorruptionPolicy,
+    /// The share of an index's documents that can be deleted before its summary suggests optimizing it
+    #[serde(default = "Settings::default_optimize_threshold")]
+    pub optimize_threshold: f32,
+    /// How many seconds a finished background task's status is kept around for
+    #[serde(default = "Settings::default_task_retention")]
+    pub task_retention: u64,
+    #[serde(default = "Settings::default_dead_letter")]
+    pub dead_letter: Option<DeadLetter>,
+    /// Log every document added to an index until it's committed, so it can be added again after a crash
+    #[serde(default = "Settings::default_write_ahead_log")]
+    pub write_ahead_log: Option<WalSettings>,
+    #[serde(default = "Settings::default_similarity")]
+    pub similarity: Similarity,
+    /// Similarities for specific indexes, keyed by index name, that override `similarity`
+    #[serde(default)]
+    pub index_similarity: HashMap<String, Similarity>,
+    /// Static boosts for fields of specific indexes, keyed by index name and then field name
+    #[serde(default)]
+    pub field_boosts: HashMap<String, HashMap<String, f32>>,
+    /// The kinds of query allowed on specific indexes, keyed by index name
+    #[serde(default)]
+    pub query_types: HashMap<String, QueryTypes>,
+    /// Groups of terms and phrases that mean the same, term queries on specific indexes are
+    /// expanded into the rest of their group when they're searched, keyed by index name
+    #[serde(default)]
+    pub synonyms: HashMap<String, Vec<Vec<String>>>,
+    /// How the terms of raw queries are combined on specific indexes, keyed by index name
+    #[serde(default)]
+    pub default_operator: HashMap<String, DefaultOperator>,
+    /// The tokenizer text fields of specific indexes are indexed with when they don't name one,
+    /// keyed by index name
+    #[serde(default)]
+    pub default_text_analyzer: HashMap<String, String>,
+    /// What happens to fields documents of specific indexes have that aren't in their schema, keyed by index name
+    #[serde(default)]
+    pub unknown_fields: HashMap<String, UnknownFields>,
+    #[serde(default = "CircuitBreaker::default")]
+    pub circuit_breaker: CircuitBreaker,
+    #[serde(default = "IngestionBreaker::default")]
+    pub ingestion_breaker: IngestionBreaker,
+    /// How long documents are kept in specific indexes, keyed by index name
+    #[serde(default)]
+    pub ttl: HashMap<String, Ttl>,
+    /// How many seconds apart expired documents are swept
+    #[serde(default = "Settings::default_ttl_sweep_interval")]
+    pub ttl_sweep_interval: f32,
     #[serde(default = "Settings::default_experimental")]
     pub experimental: bool,
     #[serde(default = "Experimental::default")]
@@ -111,7 +365,36 @@ impl Default for Settings {
             json_parsing_threads: Settings::default_json_parsing_threads(),
             auto_commit_duration: Settings::default_auto_commit_duration(),
             bulk_buffer_size: Settings::default_bulk_buffer_size(),
+            blocking_pool_size: Settings::default_blocking_pool_size(),
             merge_policy: Settings::default_merge_policy(),
+            directory_kind: Settings::default_directory_kind(),
+            default_search_size: Settings::default_search_size(),
+            max_search_size: Settings::default_max_search_size(),
+            reject_oversized_search: Settings::default_reject_oversized_search(),
+            max_result_window: Settings::default_max_result_window(),
+            max_clause_count: Settings::default_max_clause_count(),
+            slow_query_threshold: Settings::default_slow_query_threshold(),
+            max_body_size: Settings::default_max_body_size(),
+            reader_reload_policy: Settings::default_reader_reload_policy(),
+            commit_on_shutdown: Settings::default_commit_on_shutdown(),
+            commit_sync: Settings::default_commit_sync(),
+            on_corruption: Settings::default_on_corruption(),
+            optimize_threshold: Settings::default_optimize_threshold(),
+            task_retention: Settings::default_task_retention(),
+            dead_letter: Settings::default_dead_letter(),
+            write_ahead_log: Settings::default_write_ahead_log(),
+            similarity: Settings::default_similarity(),
+            index_similarity: HashMap::new(),
+            field_boosts: HashMap::new(),
+            query_types: HashMap::new(),
+            synonyms: HashMap::new(),
+            default_operator: HashMap::new(),
+            default_text_analyzer: HashMap::new(),
+            unknown_fields: HashMap::new(),
+            circuit_breaker: CircuitBreaker::default(),
+            ingestion_breaker: IngestionBreaker::default(),
+            ttl: HashMap::new(),
+            ttl_sweep_interval: Settings::default_ttl_sweep_interval(),
             experimental: Settings::default_experimental(),
             experimental_features: Experimental::default(),
         }
@@ -127,6 +410,9 @@ impl FromStr for Settings {
 }
 
 impl Settings {
+    /// What redacted settings are replaced with, see [`Settings::redacted`]
+    pub const REDACTED: &'static str = "<redacted>";
+
     pub fn new(path: &str) -> Result<Self, ConfigError> {
         Self::from_config(File::with_name(path))
     }
@@ -137,6 +423,14 @@ impl Settings {
             cluster_name: args.value_of("cluster-name").unwrap().to_string(),
             master: args.value_of("master").unwrap().parse().unwrap(),
             nodes: args.values_of("nodes").unwrap().map(ToString::to_string).collect(),
+            placement: Settings::default_placement(),
+            health_check_interval: Settings::default_health_check_interval(),
+            health_check_timeout: Settings::default_health_check_timeout(),
+            health_check_misses: Settings::default_health_check_misses(),
+            replication_timeout: Settings::default_replication_timeout(),
+            wire_format: Settings::default_wire_format(),
+            virtual_nodes: Settings::default_virtual_nodes(),
+            max_bootstrap_lag: Settings::default_max_bootstrap_lag(),
         };
         Self {
             host: args.value_of("host").unwrap().to_string(),
@@ -155,7 +449,53 @@ impl Settings {
             Ok(_) => {}
             Err(e) => panic!("Problem with config file: {}", e),
         };
-        cfg.try_into()
+        let settings: Settings = cfg.try_into()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let similarities =
+            std::iter::once(("similarity", &self.similarity)).chain(self.index_similarity.iter().map(|(k, v)| (k.as_str(), v)));
+        for (name, similarity) in similarities {
+            similarity
+                .validate()
+                .map_err(|e| ConfigError::Message(format!("Invalid similarity for {}: {}", name, e)))?;
+        }
+        for (index, boosts) in &self.field_boosts {
+            if let Some((field, boost)) = boosts.iter().find(|(_, b)| !b.is_finite() || **b < 0.0) {
+                return Err(ConfigError::Message(format!(
+                    "Invalid boost for {}.{}: must be a positive number, got {}",
+                    index, field, boost
+                )));
+            }
+        }
+        for (index, types) in &self.query_types {
+            let listed = types.allow.iter().flatten().chain(types.deny.iter());
+            if let Some(kind) = listed.into_iter().find(|k| !Query::KINDS.contains(&k.as_str())) {
+                return Err(ConfigError::Message(format!("Unknown query type for {}: {}", index, kind)));
+            }
+        }
+        if let Some(index) = self.ttl.iter().find(|(_, t)| t.max_age == 0).map(|(i, _)| i) {
+            return Err(ConfigError::Message(format!(
+                "Invalid ttl for {}: max_age must be at least 1 second",
+                index
+            )));
+        }
+        let breaker = &self.ingestion_breaker;
+        if breaker.high_water > 0 && breaker.low_water > breaker.high_water {
+            return Err(ConfigError::Message(format!(
+                "Invalid ingestion_breaker: low_water ({}) must not be above high_water ({})",
+                breaker.low_water, breaker.high_water
+            )));
+        }
+        if !self.ttl_sweep_interval.is_finite() || self.ttl_sweep_interval <= 0.0 {
+            return Err(ConfigError::Message(format!(
+                "Invalid ttl_sweep_interval: must be a positive number of seconds, got {}",
+                self.ttl_sweep_interval
+            )));
+        }
+        Ok(())
     }
 
     pub fn default_pretty() -> bool {
@@ -198,6 +538,10 @@ impl Settings {
         10000
     }
 
+    pub fn default_blocking_pool_size() -> usize {
+        4
+    }
+
     pub fn default_auto_commit_duration() -> f32 {
         10.0
     }
@@ -211,6 +555,90 @@ impl Settings {
         }
     }
 
+    pub fn default_directory_kind() -> DirectoryKind {
+        DirectoryKind::Mmap
+    }
+
+    pub fn default_search_size() -> usize {
+        100
+    }
+
+    pub fn default_max_search_size() -> usize {
+        10_000
+    }
+
+    pub fn default_reject_oversized_search() -> bool {
+        false
+    }
+
+    pub fn default_max_result_window() -> usize {
+        10_000
+    }
+
+    pub fn default_max_clause_count() -> usize {
+        1024
+    }
+
+    pub fn default_max_body_size() -> u64 {
+        104_857_600
+    }
+
+    pub fn default_slow_query_threshold() -> Option<u64> {
+        None
+    }
+
+    pub fn default_reader_reload_policy() -> ReaderReloadPolicy {
+        ReaderReloadPolicy::OnCommit
+    }
+
+    pub fn default_commit_on_shutdown() -> bool {
+        true
+    }
+
+    pub fn default_commit_sync() -> CommitSync {
+        CommitSync::Always
+    }
+
+    pub fn default_on_corruption() -> CorruptionPolicy {
+        CorruptionPolicy::Quarantine
+    }
+
+    pub fn default_optimize_threshold() -> f32 {
+        0.2
+    }
+
+    pub fn default_task_retention() -> u64 {
+        3600
+    }
+
+    pub fn default_ttl_sweep_interval() -> f32 {
+        60.0
+    }
+
+    pub fn default_dead_letter() -> Option<DeadLetter> {
+        None
+    }
+
+    pub fn default_write_ahead_log() -> Option<WalSettings> {
+        None
+    }
+
+    pub fn default_similarity() -> Similarity {
+        Similarity::default()
+    }
+
+    pub fn default_breaker_budget() -> u64 {
+        512 * 1024 * 1024
+    }
+
+    pub fn default_breaker_bytes_per_hit() -> u64 {
+        2048
+    }
+
+    pub fn default_breaker_bytes_per_facet_value() -> u64 {
+        64
+    }
+
     pub fn default_consul_addr() -> String {
         "127.0.0.1:8500".to_string()
     }
@@ -227,6 +655,38 @@ impl Settings {
         Vec::new()
     }
 
+    pub fn default_placement() -> PlacementKind {
+        PlacementKind::RoundRobin
+    }
+
+    pub fn default_health_check_interval() -> f32 {
+        5.0
+    }
+
+    pub fn default_health_check_timeout() -> f32 {
+        2.0
+    }
+
+    pub fn default_health_check_misses() -> usize {
+        3
+    }
+
+    pub fn default_replication_timeout() -> f32 {
+        5.0
+    }
+
+    pub fn default_wire_format() -> WireFormat {
+        WireFormat::Json
+    }
+
+    pub fn default_virtual_nodes() -> usize {
+        128
+    }
+
+    pub fn default_max_bootstrap_lag() -> u64 {
+        100
+    }
+
     pub fn default_experimental() -> bool {
         false
     }
@@ -243,6 +703,72 @@ impl Settings {
         self.experimental_features.nodes.clone()
     }
 
+    pub fn get_placement(&self) -> Box<dyn Placement + Send> {
+        match self.experimental_features.placement {
+            PlacementKind::RoundRobin => Box::new(RoundRobin::default()),
+            PlacementKind::LeastLoaded => Box::new(LeastLoaded),
+        }
+    }
+
+    /// The similarity documents in `index` are scored with
+    pub fn get_similarity(&self, index: &str) -> Similarity {
+        self.index_similarity.get(index).copied().unwrap_or(self.similarity)
+    }
+
+    /// How the terms of a raw query on `index` are combined, a search's own operator takes
+    /// precedence over the one configured for the index
+    pub fn get_default_operator(&self, index: &str, requested: Option<DefaultOperator>) -> DefaultOperator {
+        requested.or_else(|| self.default_operator.get(index).copied()).unwrap_or_default()
+    }
+
+    /// The tokenizer text fields of `index` that don't name one are indexed with, when one is configured
+    pub fn get_default_text_analyzer(&self, index: &str) -> Option<&str> {
+        self.default_text_analyzer.get(index).map(String::as_str)
+    }
+
+    /// Every term or phrase of the synonym groups of `index`, lowercased, mapped to the others in its group
+    pub fn get_synonyms(&self, index: &str) -> HashMap<String, Vec<String>> {
+        let mut synonyms: HashMap<String, Vec<String>> = HashMap::new();
+        for group in self.synonyms.get(index).into_iter().flatten() {
+            for term in group {
+                let others = group.iter().filter(|t| *t != term).cloned();
+                synonyms.entry(term.to_lowercase()).or_default().extend(others);
+            }
+        }
+        synonyms
+    }
+
+    /// What happens to fields of documents added to `index` that aren't in its schema
+    pub fn get_unknown_fields(&self, index: &str) -> UnknownFields {
+        self.unknown_fields.get(index).copied().unwrap_or_default()
+    }
+
+    /// The boosts for fields of `index`, with the boosts given for a single search taking
+    /// precedence over the ones configured for the index
+    pub fn get_field_boosts(&self, index: &str, overrides: &HashMap<String, f32>) -> HashMap<String, f32> {
+        let mut boosts = self.field_boosts.get(index).cloned().unwrap_or_default();
+        boosts.extend(overrides.iter().map(|(k, v)| (k.clone(), *v)));
+        boosts
+    }
+
+    /// The first kind of query used in `query` that isn't allowed on `index`
+    pub fn forbidden_query_kind(&self, index: &str, query: &Query) -> Option<&'static str> {
+        let types = self.query_types.get(index)?;
+        query.kinds().into_iter().find(|k| !types.allows(k))
+    }
+
+    /// These settings with the addresses of this node and the cluster and the data path replaced,
+    /// for showing to clients
+    pub fn redacted(&self) -> Self {
+        let hidden = || Settings::REDACTED.to_string();
+        let mut settings = self.clone();
+        settings.path = hidden();
+        settings.place_addr = hidden();
+        settings.experimental_features.consul_addr = hidden();
+        settings.experimental_features.nodes = settings.experimental_features.nodes.iter().map(|_| hidden()).collect();
+        settings
+    }
+
     pub fn get_merge_policy(&self) -> Box<dyn MergePolicy> {
         match self.merge_policy.get_kind() {
             MergePolicyType::Log => {
@@ -282,8 +808,20 @@ mod tests {
         assert_eq!(default.merge_policy.level_log_size, None);
         assert_eq!(default.merge_policy.min_layer_size, None);
         assert_eq!(default.merge_policy.min_merge_size, None);
+        assert_eq!(default.directory_kind, DirectoryKind::Mmap);
+        assert_eq!(default.default_search_size, 100);
+        assert_eq!(default.max_search_size, 10_000);
+        assert_eq!(default.max_result_window, 10_000);
+        assert_eq!(default.max_clause_count, 1024);
+        assert_eq!(default.slow_query_threshold, None);
+        assert_eq!(default.max_body_size, 104_857_600);
+        assert!(cmp_float(default.optimize_threshold, 0.2));
+        assert!(!default.reject_oversized_search);
+        assert_eq!(default.get_unknown_fields("test_index"), UnknownFields::Ignore);
         assert_eq!(default.experimental, false);
         assert_eq!(default.experimental_features.master, false);
+        assert_eq!(default.experimental_features.virtual_nodes, 128);
+        assert_eq!(default.experimental_features.max_bootstrap_lag, 100);
     }
 
     #[test]
@@ -316,6 +854,162 @@ mod tests {
         assert_eq!(config.merge_policy.min_merge_size, None);
     }
 
+    #[test]
+    fn valid_ram_directory() {
+        let config = Settings::from_str(r#"directory_kind = "Ram""#).unwrap();
+        assert_eq!(config.directory_kind, DirectoryKind::Ram);
+        assert!(!config.directory_kind.is_durable());
+    }
+
+    #[test]
+    fn ingestion_breaker_config() {
+        assert_eq!(Settings::default().ingestion_breaker, IngestionBreaker::default());
+        let config = Settings::from_str("[ingestion_breaker]\nhigh_water = 10000\nlow_water = 2000").unwrap();
+        assert_eq!(config.ingestion_breaker.high_water, 10_000);
+        assert_eq!(config.ingestion_breaker.low_water, 2_000);
+        assert!(Settings::from_str("[ingestion_breaker]\nhigh_water = 100\nlow_water = 200").is_err());
+    }
+
+    #[test]
+    fn reader_reload_policy_config() {
+        assert_eq!(Settings::default().reader_reload_policy, ReaderReloadPolicy::OnCommit);
+        let config = Settings::from_str(r#"reader_reload_policy = "Manual""#).unwrap();
+        assert_eq!(config.reader_reload_policy, ReaderReloadPolicy::Manual);
+        let config = Settings::from_str(r#"reader_reload_policy = { Interval = 500 }"#).unwrap();
+        assert_eq!(config.reader_reload_policy, ReaderReloadPolicy::Interval(500));
+    }
+
+    #[test]
+    fn corruption_policy_config() {
+        assert_eq!(Settings::default().on_corruption, CorruptionPolicy::Quarantine);
+        let config = Settings::from_str(r#"on_corruption = "Truncate""#).unwrap();
+        assert_eq!(config.on_corruption, CorruptionPolicy::Truncate);
+    }
+
+    #[test]
+    fn ttl_config() {
+        let cfg = r#"
+            ttl_sweep_interval = 30
+
+            [ttl.logs]
+            field = "timestamp"
+            max_age = 86400"#;
+        let config = Settings::from_str(cfg).unwrap();
+        assert_eq!(config.ttl_sweep_interval, 30.0);
+        assert_eq!(
+            config.ttl["logs"],
+            Ttl {
+                field: "timestamp".into(),
+                max_age: 86400
+            }
+        );
+
+        assert!(Settings::from_str("[ttl.logs]\nfield = \"timestamp\"\nmax_age = 0").is_err());
+        assert!(Settings::from_str("ttl_sweep_interval = 0").is_err());
+    }
+
+    #[test]
+    fn similarity_config() {
+        let cfg = r#"
+            [similarity]
+            kind = "tfidf"
+
+            [index_similarity.tuned]
+            kind = "bm25"
+            k1 = 2"#;
+        let config = Settings::from_str(cfg).unwrap();
+        assert_eq!(config.get_similarity("other"), Similarity::TfIdf);
+        assert_eq!(config.get_similarity("tuned"), Similarity::BM25 { k1: 2.0, b: 0.75 });
+
+        let cfg = r#"
+            [index_similarity.tuned]
+            kind = "bm25"
+            b = 3.0"#;
+        assert!(Settings::from_str(cfg).is_err());
+    }
+
+    #[test]
+    fn query_types_config() {
+        let cfg = r#"
+            [query_types.huge]
+            deny = ["regex", "fuzzy"]
+
+            [query_types.strict]
+            allow = ["term", "bool"]"#;
+        let config = Settings::from_str(cfg).unwrap();
+        let query = |q: &str| serde_json::from_str::<Query>(q).unwrap();
+        let regex = query(r#"{ "regex": { "f": "a.*" } }"#);
+        assert_eq!(config.forbidden_query_kind("huge", &regex), Some("regex"));
+        assert_eq!(config.forbidden_query_kind("other", &regex), None);
+        let nested = query(r#"{ "bool": { "must": [ { "term": { "f": "a" } }, { "fuzzy": { "f": { "value": "a" } } } ] } }"#);
+        assert_eq!(config.forbidden_query_kind("strict", &nested), Some("fuzzy"));
+        assert_eq!(config.forbidden_query_kind("huge", &nested), Some("fuzzy"));
+
+        assert!(Settings::from_str("[query_types.huge]\ndeny = [\"wildcard\"]").is_err());
+    }
+
+    #[test]
+    fn field_boosts_config() {
+        let cfg = r#"
+            [field_boosts.docs]
+            title = 3.0
+            body = 0.5"#;
+        let config = Settings::from_str(cfg).unwrap();
+        let overrides = vec![("body".to_string(), 2.0)].into_iter().collect();
+        let boosts = config.get_field_boosts("docs", &overrides);
+        assert_eq!(boosts.get("title"), Some(&3.0));
+        assert_eq!(boosts.get("body"), Some(&2.0));
+        assert!(config.get_field_boosts("other", &HashMap::new()).is_empty());
+
+        let cfg = r#"
+            [field_boosts.docs]
+            title = -1.0"#;
+        assert!(Settings::from_str(cfg).is_err());
+    }
+
+    #[test]
+    fn synonyms_config() {
+        let cfg = r#"
+            [synonyms]
+            places = [["nyc", "new york city"], ["la", "los angeles", "LAX"]]"#;
+        let config = Settings::from_str(cfg).unwrap();
+        let synonyms = config.get_synonyms("places");
+        assert_eq!(synonyms["nyc"], vec!["new york city"]);
+        assert_eq!(synonyms["new york city"], vec!["nyc"]);
+        assert_eq!(synonyms["lax"], vec!["la", "los angeles"]);
+        assert_eq!(synonyms["la"], vec!["los angeles", "LAX"]);
+        assert!(config.get_synonyms("other").is_empty());
+    }
+
+    #[test]
+    fn default_text_analyzer_config() {
+        let cfg = r#"
+            [default_text_analyzer]
+            articles = "en_stem""#;
+        let config = Settings::from_str(cfg).unwrap();
+        assert_eq!(config.get_default_text_analyzer("articles"), Some("en_stem"));
+        assert_eq!(config.get_default_text_analyzer("other"), None);
+    }
+
+    #[test]
+    fn commit_sync_config() {
+        let config = Settings::from_str(r#"commit_sync = "os""#).unwrap();
+        assert_eq!(config.commit_sync, CommitSync::Os);
+        assert_eq!(Settings::default().commit_sync, CommitSync::Always);
+    }
+
+    #[test]
+    fn write_ahead_log_config() {
+        let cfg = r#"
+            [write_ahead_log]
+            sync = "os""#;
+        let config = Settings::from_str(cfg).unwrap();
+        let wal = config.write_ahead_log.unwrap();
+        assert_eq!(wal.sync, crate::wal::WalSync::Os);
+        assert_eq!(wal.max_bytes, 64 * 1024 * 1024);
+        assert_eq!(Settings::default().write_ahead_log, None);
+    }
+
     #[test]
     #[should_panic]
     fn bad_config_file() {
@@ -0,0 +1,378 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use tantivy::fieldnorm::FieldNormReader;
+use tantivy::postings::SegmentPostings;
+use tantivy::query::{Explanation, Query, Scorer, Weight};
+use tantivy::schema::{Field, IndexRecordOption};
+use tantivy::{DocId, DocSet, Postings, Score, Searcher, SegmentReader, SkipResult, TantivyError, Term};
+
+/// How documents matching a query are scored against each other. Only the terms of term, phrase,
+/// boolean and raw queries are rescored, other queries keep tantivy's own scores. Field boosts are
+/// folded into the score of each term in that field the same way, and fields without norms are
+/// scored as if every value had the average length.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Similarity {
+    /// Okapi BM25, `k1` controls how quickly repeated terms stop adding to the score and `b` how
+    /// much longer fields are penalized
+    BM25 {
+        #[serde(default = "Similarity::default_k1")]
+        k1: f32,
+        #[serde(default = "Similarity::default_b")]
+        b: f32,
+    },
+    /// Classic TF-IDF as Lucene used to score before switching to BM25
+    TfIdf,
+}
+
+impl Default for Similarity {
+    fn default() -> Self {
+        Similarity::BM25 {
+            k1: Similarity::default_k1(),
+            b: Similarity::default_b(),
+        }
+    }
+}
+
+impl Similarity {
+    pub fn default_k1() -> f32 {
+        1.2
+    }
+
+    pub fn default_b() -> f32 {
+        0.75
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            Similarity::BM25 { k1, .. } if !k1.is_finite() || k1 < 0.0 => Err(format!("BM25 k1 must be a positive number, got {}", k1)),
+            Similarity::BM25 { b, .. } if !(0.0..=1.0).contains(&b) => Err(format!("BM25 b must be between 0 and 1, got {}", b)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether this is the similarity tantivy already scores with, so queries don't need rescoring
+    pub fn is_default(&self) -> bool {
+        *self == Similarity::default()
+    }
+
+    fn idf(&self, doc_freq: u64, num_docs: u64) -> f32 {
+        match self {
+            Similarity::BM25 { .. } => (1.0 + (num_docs.saturating_sub(doc_freq) as f32 + 0.5) / (doc_freq as f32 + 0.5)).ln(),
+            Similarity::TfIdf => 1.0 + (num_docs as f32 / (doc_freq as f32 + 1.0)).ln(),
+        }
+    }
+
+    fn score(&self, term_freq: u32, fieldnorm: f32, idf: f32, average_fieldnorm: f32) -> Score {
+        let tf = term_freq as f32;
+        match *self {
+            Similarity::BM25 { k1, b } => {
+                let norm = k1 * (1.0 - b + b * fieldnorm / average_fieldnorm);
+                idf * (k1 + 1.0) * tf / (tf + norm)
+            }
+            Similarity::TfIdf => tf.sqrt() * idf * idf / fieldnorm.max(1.0).sqrt(),
+        }
+    }
+
+    /// Wrap a query so the documents it matches are scored with this similarity, with the score of
+    /// a term multiplied by the boost of its field and the length of `without_norms` fields ignored
+    pub fn wrap(self, query: Box<dyn Query>, boosts: HashMap<Field, f32>, without_norms: HashSet<Field>) -> Box<dyn Query> {
+        if self.is_default() && boosts.is_empty() && without_norms.is_empty() {
+            query
+        } else {
+            Box::new(SimilarityQuery {
+                inner: query,
+                similarity: self,
+                boosts,
+                without_norms,
+            })
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SimilarityQuery {
+    inner: Box<dyn Query>,
+    similarity: Similarity,
+    boosts: HashMap<Field, f32>,
+    without_norms: HashSet<Field>,
+}
+
+impl Clone for SimilarityQuery {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.box_clone(),
+            similarity: self.similarity,
+            boosts: self.boosts.clone(),
+            without_norms: self.without_norms.clone(),
+        }
+    }
+}
+
+struct TermStats {
+    term: Term,
+    idf: f32,
+    average_fieldnorm: f32,
+    boost: f32,
+    norms: bool,
+}
+
+impl Query for SimilarityQuery {
+    fn weight(&self, searcher: &Searcher, scoring_enabled: bool) -> tantivy::Result<Box<dyn Weight>> {
+        let inner = self.inner.weight(searcher, scoring_enabled)?;
+        let mut terms = BTreeSet::new();
+        self.inner.query_terms(&mut terms);
+        if !scoring_enabled || terms.is_empty() {
+            return Ok(inner);
+        }
+
+        let num_docs: u64 = searcher.segment_readers().iter().map(|r| u64::from(r.max_doc())).sum();
+        let mut average_fieldnorms: HashMap<Field, f32> = HashMap::new();
+        let terms = terms
+            .into_iter()
+            .map(|term| {
+                let average_fieldnorm = *average_fieldnorms.entry(term.field()).or_insert_with(|| {
+                    let tokens: u64 = searcher
+                        .segment_readers()
+                        .iter()
+                        .map(|r| r.inverted_index(term.field()).total_num_tokens())
+                        .sum();
+                    tokens as f32 / num_docs.max(1) as f32
+                });
+                TermStats {
+                    idf: self.similarity.idf(searcher.doc_freq(&term), num_docs),
+                    average_fieldnorm,
+                    boost: self.boosts.get(&term.field()).copied().unwrap_or(1.0),
+                    norms: !self.without_norms.contains(&term.field()),
+                    term,
+                }
+            })
+            .collect();
+        Ok(Box::new(SimilarityWeight {
+            inner,
+            terms,
+            similarity: self.similarity,
+        }))
+    }
+
+    fn query_terms(&self, term_set: &mut BTreeSet<Term>) {
+        self.inner.query_terms(term_set)
+    }
+}
+
+struct SimilarityWeight {
+    inner: Box<dyn Weight>,
+    terms: Vec<TermStats>,
+    similarity: Similarity,
+}
+
+impl Weight for SimilarityWeight {
+    fn scorer(&self, reader: &SegmentReader) -> tantivy::Result<Box<dyn Scorer>> {
+        let terms = self
+            .terms
+            .iter()
+            .filter_map(|stats| {
+                let field = stats.term.field();
+                reader
+                    .inverted_index(field)
+                    .read_postings(&stats.term, IndexRecordOption::WithFreqs)
+                    .map(|postings| TermScorer {
+                        postings,
+                        fieldnorms: reader.get_fieldnorms_reader(field),
+                        idf: stats.idf,
+                        average_fieldnorm: stats.average_fieldnorm,
+                        boost: stats.boost,
+                        norms: stats.norms,
+                        current: None,
+                        done: false,
+                    })
+            })
+            .collect();
+        Ok(Box::new(SimilarityScorer {
+            inner: self.inner.scorer(reader)?,
+            terms,
+            similarity: self.similarity,
+        }))
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> tantivy::Result<Explanation> {
+        let mut scorer = self.scorer(reader)?;
+        match scorer.skip_next(doc) {
+            SkipResult::Reached => Ok(Explanation::new(format!("{:?}", self.similarity), scorer.score())),
+            _ => Err(TantivyError::InvalidArgument(format!("Document #({}) does not match", doc))),
+        }
+    }
+}
+
+/// Walks one term's postings alongside the documents the query matches
+struct TermScorer {
+    postings: SegmentPostings,
+    fieldnorms: FieldNormReader,
+    idf: f32,
+    average_fieldnorm: f32,
+    boost: f32,
+    norms: bool,
+    current: Option<DocId>,
+    done: bool,
+}
+
+impl TermScorer {
+    fn term_freq(&mut self, doc: DocId) -> Option<u32> {
+        if self.done {
+            return None;
+        }
+        let behind = match self.current {
+            Some(d) => d < doc,
+            None => true,
+        };
+        if behind {
+            match self.postings.skip_next(doc) {
+                SkipResult::End => {
+                    self.done = true;
+                    return None;
+                }
+                _ => self.current = Some(self.postings.doc()),
+            }
+        }
+        if self.current == Some(doc) {
+            Some(self.postings.term_freq())
+        } else {
+            None
+        }
+    }
+}
+
+struct SimilarityScorer {
+    inner: Box<dyn Scorer>,
+    terms: Vec<TermScorer>,
+    similarity: Similarity,
+}
+
+impl DocSet for SimilarityScorer {
+    fn advance(&mut self) -> bool {
+        self.inner.advance()
+    }
+
+    fn skip_next(&mut self, target: DocId) -> SkipResult {
+        self.inner.skip_next(target)
+    }
+
+    fn doc(&self) -> DocId {
+        self.inner.doc()
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.inner.size_hint()
+    }
+}
+
+impl Scorer for SimilarityScorer {
+    fn score(&mut self) -> Score {
+        let doc = self.inner.doc();
+        let similarity = self.similarity;
+        self.terms
+            .iter_mut()
+            .filter_map(|t| {
+                let tf = t.term_freq(doc)?;
+                let fieldnorm = if t.norms {
+                    t.fieldnorms.fieldnorm(doc) as f32
+                } else {
+                    t.average_fieldnorm
+                };
+                Some(t.boost * similarity.score(tf, fieldnorm, t.idf, t.average_fieldnorm))
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::schema::{SchemaBuilder, STORED, TEXT};
+    use tantivy::{doc, Index};
+
+    use toshi_types::Search;
+
+    use crate::handle::{IndexHandle, LocalIndex};
+    use crate::settings::Settings;
+
+    use super::*;
+
+    fn ranked_index(k1: f32) -> LocalIndex {
+        let mut builder = SchemaBuilder::new();
+        let text = builder.add_text_field("text", TEXT | STORED);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer(30_000_000).unwrap();
+        for d in &[
+            "rare x x x",
+            "common common common common common common",
+            "common y",
+            "z",
+            "z",
+            "z",
+            "z",
+            "z",
+        ] {
+            writer.add_document(doc!(text => *d));
+        }
+        writer.commit().unwrap();
+        drop(writer);
+
+        let mut settings = Settings::default();
+        settings.index_similarity.insert("ranked".into(), Similarity::BM25 { k1, b: 0.75 });
+        LocalIndex::new(index, settings, "ranked").unwrap()
+    }
+
+    async fn top_doc(index: &LocalIndex) -> String {
+        let search: Search = serde_json::from_str(r#"{ "query": { "raw": "text:rare text:common" } }"#).unwrap();
+        let results = index.search_index(search).await.unwrap();
+        results.docs[0].doc["text"][0].text().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_k1_changes_ranking() {
+        assert_eq!(top_doc(&ranked_index(0.1)).await, "rare x x x");
+        assert_eq!(top_doc(&ranked_index(5.0)).await, "common common common common common common");
+    }
+
+    fn boosted_index(boosts: &[(&str, f32)]) -> LocalIndex {
+        let mut builder = SchemaBuilder::new();
+        let title = builder.add_text_field("title", TEXT | STORED);
+        let body = builder.add_text_field("body", TEXT | STORED);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer(30_000_000).unwrap();
+        writer.add_document(doc!(title => "rust", body => "gardening tips for spring"));
+        writer.add_document(doc!(title => "gardening", body => "rust"));
+        writer.commit().unwrap();
+        drop(writer);
+
+        let mut settings = Settings::default();
+        let boosts = boosts.iter().map(|(f, b)| (f.to_string(), *b)).collect();
+        settings.field_boosts.insert("boosted".into(), boosts);
+        LocalIndex::new(index, settings, "boosted").unwrap()
+    }
+
+    async fn top_title(index: &LocalIndex, search: &str) -> String {
+        let search: Search = serde_json::from_str(search).unwrap();
+        let results = index.search_index(search).await.unwrap();
+        results.docs[0].doc["title"][0].text().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_field_boost_changes_ranking() {
+        let query = r#"{ "query": { "raw": "title:rust body:rust" } }"#;
+        assert_eq!(top_title(&boosted_index(&[]), query).await, "gardening");
+        assert_eq!(top_title(&boosted_index(&[("title", 3.0)]), query).await, "rust");
+
+        let overridden = r#"{ "query": { "raw": "title:rust body:rust" }, "boosts": { "title": 1.0 } }"#;
+        assert_eq!(top_title(&boosted_index(&[("title", 3.0)]), overridden).await, "gardening");
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Similarity::default().validate().is_ok());
+        assert!(Similarity::TfIdf.validate().is_ok());
+        assert!(Similarity::BM25 { k1: -1.0, b: 0.5 }.validate().is_err());
+        assert!(Similarity::BM25 { k1: 1.0, b: 1.5 }.validate().is_err());
+    }
+}
@@ -0,0 +1,391 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use tantivy::directory::error::{DeleteError, IOError, LockError, OpenReadError, OpenWriteError};
+use tantivy::directory::{
+    AntiCallToken, Directory, DirectoryLock, Lock, MmapDirectory, RAMDirectory, ReadOnlySource, TerminatingWrite, WatchCallback,
+    WatchHandle, WritePtr,
+};
+use tantivy::schema::Schema;
+use tantivy::{Index, IndexMeta, SegmentMeta};
+
+use toshi_types::Error;
+
+use crate::handle::METADATA_FILE;
+use crate::index::META_FILE;
+use crate::settings::{CommitSync, DirectoryKind};
+use crate::Result;
+
+/// The file tantivy lists the files it is allowed to clean up from an index's directory in
+const MANAGED_FILE: &str = ".managed.json";
+
+/// Where the files of local indexes are kept. A backend hands out the directory an index is kept
+/// in by the index's name, so indexes can be kept somewhere other than the local filesystem
+/// without the handlers knowing about it.
+pub trait StorageBackend: Send + Sync {
+    /// The directory of the index called `name`, created empty if there is no such index yet
+    fn directory(&self, name: &str) -> Result<Box<dyn Directory>>;
+
+    /// Open the index called `name`, creating it with `schema` if it doesn't exist yet
+    fn open_index(&self, name: &str, schema: Schema) -> Result<Index> {
+        let dir = StorageDirectory(self.directory(name)?);
+        Index::open_or_create(dir, schema).map_err(|e| Error::IOError(e.to_string()))
+    }
+
+    /// Copy the last commit of `source`, an index called `source_name`, into a new index called
+    /// `name` and open it. By default every segment file is read out of the source and written
+    /// into the new index's directory.
+    fn copy_index(&self, source: &Index, _source_name: &str, name: &str) -> Result<Index> {
+        let (meta, files) = last_commit(source)?;
+        copy_files(source, &meta, &files, self.directory(name)?)
+    }
+
+    /// Remove every file of the index called `name`, which has to be closed already. By default
+    /// nothing is removed, for backends whose directories only live as long as they're open.
+    fn remove_index(&self, _name: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Open the index called `name` again with `schema`, its current schema with fields added to the
+    /// end, by rewriting its last commit. `source` is the index as it's open now, it has to have no
+    /// writer left.
+    fn reopen_index(&self, source: &Index, name: &str, schema: Schema) -> Result<Index> {
+        let mut meta = source.load_metas().map_err(Error::from)?;
+        meta.schema = schema;
+        reopen(&meta, self.directory(name)?)
+    }
+}
+
+/// The last commit of `index` and the segment files it's made of, segment files are never changed
+/// once written so they can be copied while the index keeps taking writes
+fn last_commit(index: &Index) -> Result<(IndexMeta, Vec<PathBuf>)> {
+    let meta = index.load_metas().map_err(Error::from)?;
+    let files = meta
+        .segments
+        .iter()
+        .flat_map(SegmentMeta::list_files)
+        .filter(|f| index.directory().exists(f))
+        .collect();
+    Ok((meta, files))
+}
+
+/// Copy the segment `files` of `meta` out of `source` into `dir` and open the copy
+fn copy_files(source: &Index, meta: &IndexMeta, files: &[PathBuf], mut dir: Box<dyn Directory>) -> Result<Index> {
+    for file in files {
+        // Read as is, opening the file for reading would strip the footer tantivy checks it by
+        let data = source.directory().atomic_read(file).map_err(|e| Error::IOError(e.to_string()))?;
+        dir.atomic_write(file, &data)?;
+    }
+    finish_copy(source, meta, files, &mut *dir)?;
+    Index::open(StorageDirectory(dir)).map_err(Error::from)
+}
+
+/// Turn a directory holding the segment `files` of `meta` into an index, by writing the commit
+/// itself, the files tantivy may clean up once they're merged away and Toshi's own metadata
+fn finish_copy(source: &Index, meta: &IndexMeta, files: &[PathBuf], dir: &mut dyn Directory) -> Result<()> {
+    dir.atomic_write(Path::new(MANAGED_FILE), &serde_json::to_vec(files)?)?;
+    if let Ok(metadata) = source.directory().atomic_read(Path::new(METADATA_FILE)) {
+        dir.atomic_write(Path::new(METADATA_FILE), &metadata)?;
+    }
+    write_commit(meta, dir)
+}
+
+fn write_commit(meta: &IndexMeta, dir: &mut dyn Directory) -> Result<()> {
+    let mut commit = serde_json::to_vec_pretty(meta)?;
+    writeln!(&mut commit)?;
+    dir.atomic_write(Path::new(META_FILE), &commit)?;
+    Ok(())
+}
+
+/// Open the index in `dir` with `meta` as its last commit
+fn reopen(meta: &IndexMeta, mut dir: Box<dyn Directory>) -> Result<Index> {
+    write_commit(meta, &mut *dir)?;
+    Index::open(StorageDirectory(dir)).map_err(Error::from)
+}
+
+/// The default backend, indexes are kept in a directory named after them under `base_path`, or in
+/// memory when `kind` is `Ram`. Commits fsync the files they write as `sync` says.
+pub struct LocalStorage {
+    base_path: PathBuf,
+    kind: DirectoryKind,
+    sync: CommitSync,
+}
+
+impl LocalStorage {
+    pub fn new(base_path: PathBuf, kind: DirectoryKind) -> Self {
+        Self {
+            base_path,
+            kind,
+            sync: CommitSync::Always,
+        }
+    }
+
+    /// Fsync the files commits write as `sync` says
+    pub fn with_sync(self, sync: CommitSync) -> Self {
+        Self { sync, ..self }
+    }
+
+    /// Open the index kept on disk at `path`, whatever kind of directory new indexes are kept in
+    pub fn load_index(&self, path: &Path) -> Result<Index> {
+        if !path.exists() {
+            return Err(Error::UnknownIndex(path.display().to_string()));
+        }
+        let dir = self.disk_directory(path.to_path_buf())?;
+        Index::open(StorageDirectory(dir)).map_err(|_| Error::UnknownIndex(path.display().to_string()))
+    }
+
+    fn disk_directory(&self, path: PathBuf) -> Result<Box<dyn Directory>> {
+        let dir = MmapDirectory::open(&path).map_err(|e| Error::IOError(e.to_string()))?;
+        Ok(match self.sync {
+            CommitSync::Always => Box::new(dir),
+            CommitSync::Os => Box::new(UnsyncedDirectory { root: path, mmap: dir }),
+        })
+    }
+}
+
+impl StorageBackend for LocalStorage {
+    fn directory(&self, name: &str) -> Result<Box<dyn Directory>> {
+        if !self.kind.is_durable() {
+            return Ok(Box::new(RAMDirectory::create()));
+        }
+        let path = self.base_path.join(name);
+        if !path.exists() {
+            fs::create_dir(&path).map_err(|e| Error::IOError(e.to_string()))?;
+        }
+        self.disk_directory(path)
+    }
+
+    fn remove_index(&self, name: &str) -> Result<()> {
+        let path = self.base_path.join(name);
+        if self.kind.is_durable() && path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Segment files are hard linked into the new index when the source is kept under the same
+    /// path, so the copy takes no space until either index merges or deletes from its segments
+    fn copy_index(&self, source: &Index, source_name: &str, name: &str) -> Result<Index> {
+        let source_path = self.base_path.join(source_name);
+        if self.base_path.join(name).join(META_FILE).exists() {
+            return Err(Error::IndexExists(name.into()));
+        }
+        let (meta, files) = last_commit(source)?;
+        let linkable = self.kind.is_durable() && files.iter().all(|f| source_path.join(f).exists());
+        if !linkable {
+            return copy_files(source, &meta, &files, self.directory(name)?);
+        }
+        let mut dir = self.directory(name)?;
+        let path = self.base_path.join(name);
+        for file in &files {
+            if fs::hard_link(source_path.join(file), path.join(file)).is_err() {
+                fs::copy(source_path.join(file), path.join(file))?;
+            }
+        }
+        finish_copy(source, &meta, &files, &mut *dir)?;
+        Index::open(StorageDirectory(dir)).map_err(Error::from)
+    }
+
+    /// A Ram index has nothing to open again, its last commit is copied into a new one instead
+    fn reopen_index(&self, source: &Index, name: &str, schema: Schema) -> Result<Index> {
+        let (mut meta, files) = last_commit(source)?;
+        meta.schema = schema;
+        if !self.kind.is_durable() {
+            return copy_files(source, &meta, &files, self.directory(name)?);
+        }
+        reopen(&meta, self.directory(name)?)
+    }
+}
+
+/// A directory handed out by a backend. Tantivy opens indexes over a concrete directory type, this
+/// passes every call through to the backend's directory.
+#[derive(Debug)]
+struct StorageDirectory(Box<dyn Directory>);
+
+impl Clone for StorageDirectory {
+    fn clone(&self) -> Self {
+        StorageDirectory(self.0.box_clone())
+    }
+}
+
+impl Directory for StorageDirectory {
+    fn open_read(&self, path: &Path) -> std::result::Result<ReadOnlySource, OpenReadError> {
+        self.0.open_read(path)
+    }
+
+    fn delete(&self, path: &Path) -> std::result::Result<(), DeleteError> {
+        self.0.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.0.exists(path)
+    }
+
+    fn open_write(&mut self, path: &Path) -> std::result::Result<WritePtr, OpenWriteError> {
+        self.0.open_write(path)
+    }
+
+    fn atomic_read(&self, path: &Path) -> std::result::Result<Vec<u8>, OpenReadError> {
+        self.0.atomic_read(path)
+    }
+
+    fn atomic_write(&mut self, path: &Path, data: &[u8]) -> io::Result<()> {
+        self.0.atomic_write(path, data)
+    }
+
+    fn acquire_lock(&self, lock: &Lock) -> std::result::Result<DirectoryLock, LockError> {
+        self.0.acquire_lock(lock)
+    }
+
+    fn watch(&self, watch_callback: WatchCallback) -> tantivy::Result<WatchHandle> {
+        self.0.watch(watch_callback)
+    }
+}
+
+/// A directory on disk that leaves the files of new segments for the operating system to write
+/// out, where tantivy's own fsyncs every one of them as it's finished. Everything else, including
+/// writing the file that lists a commit's segments, goes through to the mmap directory as is.
+#[derive(Clone, Debug)]
+struct UnsyncedDirectory {
+    root: PathBuf,
+    mmap: MmapDirectory,
+}
+
+/// A new file of an [`UnsyncedDirectory`], flushing it only hands what was written to the
+/// operating system
+struct UnsyncedFile(File);
+
+impl Write for UnsyncedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl TerminatingWrite for UnsyncedFile {
+    fn terminate_ref(&mut self, _: AntiCallToken) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl Directory for UnsyncedDirectory {
+    fn open_read(&self, path: &Path) -> std::result::Result<ReadOnlySource, OpenReadError> {
+        self.mmap.open_read(path)
+    }
+
+    fn delete(&self, path: &Path) -> std::result::Result<(), DeleteError> {
+        self.mmap.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.mmap.exists(path)
+    }
+
+    fn open_write(&mut self, path: &Path) -> std::result::Result<WritePtr, OpenWriteError> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.root.join(path))
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::AlreadyExists => OpenWriteError::FileAlreadyExists(path.to_path_buf()),
+                _ => OpenWriteError::IOError(IOError::from(e)),
+            })?;
+        Ok(BufWriter::new(Box::new(UnsyncedFile(file))))
+    }
+
+    fn atomic_read(&self, path: &Path) -> std::result::Result<Vec<u8>, OpenReadError> {
+        self.mmap.atomic_read(path)
+    }
+
+    fn atomic_write(&mut self, path: &Path, data: &[u8]) -> io::Result<()> {
+        self.mmap.atomic_write(path, data)
+    }
+
+    fn acquire_lock(&self, lock: &Lock) -> std::result::Result<DirectoryLock, LockError> {
+        self.mmap.acquire_lock(lock)
+    }
+
+    fn watch(&self, watch_callback: WatchCallback) -> tantivy::Result<WatchHandle> {
+        self.mmap.watch(watch_callback)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use hyper::Body;
+
+    use toshi_test::wait_json;
+
+    use crate::handlers::{add_document, all_docs, create_index};
+    use crate::index::IndexCatalog;
+    use crate::router::QueryOptions;
+    use crate::SearchResults;
+
+    use super::*;
+
+    /// Keeps every index in memory, handing out the same directory each time an index is opened
+    #[derive(Default)]
+    struct MemoryStorage {
+        dirs: Mutex<HashMap<String, RAMDirectory>>,
+    }
+
+    impl StorageBackend for MemoryStorage {
+        fn directory(&self, name: &str) -> Result<Box<dyn Directory>> {
+            let mut dirs = self.dirs.lock().map_err(|_| Error::PoisonedError)?;
+            Ok(Box::new(dirs.entry(name.into()).or_insert_with(RAMDirectory::create).clone()))
+        }
+
+        fn remove_index(&self, name: &str) -> Result<()> {
+            self.dirs.lock().map_err(|_| Error::PoisonedError)?.remove(name);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_backend() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let storage = Arc::new(MemoryStorage::default());
+        let mut catalog = IndexCatalog::with_index("test_index".into(), toshi_test::create_test_index())?;
+        catalog.set_storage(Arc::clone(&storage) as Arc<dyn StorageBackend>);
+        let catalog = Arc::new(catalog);
+
+        let schema = r#"[{ "name": "test_text", "type": "text", "options": { "indexing": { "record": "position", "tokenizer": "default" }, "stored": true } }]"#;
+        create_index(Arc::clone(&catalog), Body::from(schema), "stashed".into(), QueryOptions::default()).await?;
+        let doc = r#"{"options": {"commit": true }, "document": {"test_text": "Babbaboo!"} }"#;
+        add_document(Arc::clone(&catalog), Body::from(doc), "stashed".into(), QueryOptions::default()).await?;
+
+        let resp = all_docs(Arc::clone(&catalog), "stashed".into()).await?;
+        assert_eq!(wait_json::<SearchResults>(resp).await.hits, 1);
+        assert!(!Path::new("stashed").exists());
+
+        // The committed document is in the backend's directory, not just the open handle
+        let reopened = storage.open_index("stashed", serde_json::from_str(schema)?)?;
+        assert_eq!(reopened.reader().map_err(Error::from)?.searcher().num_docs(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsynced_commits() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let base = std::env::temp_dir().join(format!("toshi-sync-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&base)?;
+        let storage = LocalStorage::new(base.clone(), DirectoryKind::Mmap).with_sync(CommitSync::Os);
+        let index = storage.open_index("unsynced", toshi_test::create_test_index().schema())?;
+        let text = index.schema().get_field("test_text").unwrap();
+        let mut writer = index.writer(30_000_000).map_err(Error::from)?;
+        writer.add_document(tantivy::doc!(text => "written"));
+        writer.commit().map_err(Error::from)?;
+        drop(writer);
+
+        let loaded = storage.load_index(&base.join("unsynced"))?;
+        assert_eq!(loaded.reader().map_err(Error::from)?.searcher().num_docs(), 1);
+        assert!(storage.load_index(&base.join("missing")).is_err());
+        fs::remove_dir_all(&base)?;
+        Ok(())
+    }
+}
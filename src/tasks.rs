@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::*;
+
+use toshi_types::{Error, TaskState, TaskStatus};
+
+use crate::Result;
+
+/// How far along a background task is, shared between the task and the registry it was started in
+#[derive(Debug, Default)]
+pub struct TaskProgress {
+    done: AtomicU64,
+    total: AtomicU64,
+}
+
+impl TaskProgress {
+    pub fn set_total(&self, total: u64) {
+        self.total.store(total, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, n: u64) {
+        self.done.fetch_add(n, Ordering::SeqCst);
+    }
+
+    /// Percent of the work done, a task that hasn't said how much work it has is at 0
+    pub fn percent(&self) -> f32 {
+        let total = self.total.load(Ordering::SeqCst);
+        if total == 0 {
+            return 0.0;
+        }
+        let done = self.done.load(Ordering::SeqCst).min(total);
+        (done as f64 * 100.0 / total as f64) as f32
+    }
+}
+
+struct Task {
+    kind: String,
+    progress: Arc<TaskProgress>,
+    outcome: Option<(Instant, std::result::Result<(), String>)>,
+}
+
+impl Task {
+    fn status(&self, id: u64) -> TaskStatus {
+        let (state, progress, error) = match &self.outcome {
+            None => (TaskState::Running, self.progress.percent(), None),
+            Some((_, Ok(()))) => (TaskState::Done, 100.0, None),
+            Some((_, Err(e))) => (TaskState::Failed, self.progress.percent(), Some(e.clone())),
+        };
+        TaskStatus {
+            id,
+            kind: self.kind.clone(),
+            state,
+            progress,
+            error,
+        }
+    }
+}
+
+/// Background jobs started with `async=true`. Tasks are kept while they run and for `retention`
+/// after they finish so their outcome can still be looked up, expired tasks are dropped the next
+/// time the registry is used.
+pub struct TaskRegistry {
+    tasks: Arc<Mutex<HashMap<u64, Task>>>,
+    next_id: AtomicU64,
+    retention: Duration,
+}
+
+impl TaskRegistry {
+    pub fn new(retention: Duration) -> Self {
+        Self {
+            tasks: Arc::new(Mutex::new(HashMap::new())),
+            next_id: AtomicU64::new(1),
+            retention,
+        }
+    }
+
+    /// Run `job` in the background, returning the id its status can be looked up by
+    pub fn spawn<F, Fut>(&self, kind: &str, job: F) -> Result<u64>
+    where
+        F: FnOnce(Arc<TaskProgress>) -> Fut,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let progress = Arc::new(TaskProgress::default());
+        let task = Task {
+            kind: kind.into(),
+            progress: Arc::clone(&progress),
+            outcome: None,
+        };
+        {
+            let mut tasks = self.tasks.lock().map_err(|_| Error::PoisonedError)?;
+            self.expire(&mut tasks);
+            tasks.insert(id, task);
+        }
+
+        let job = job(progress);
+        let tasks = Arc::clone(&self.tasks);
+        let kind = kind.to_string();
+        tokio::spawn(async move {
+            let outcome = job.await.map_err(|e| e.to_string());
+            match &outcome {
+                Ok(()) => info!("Task {} ({}) finished", id, kind),
+                Err(e) => error!("Task {} ({}) failed: {}", id, kind, e),
+            }
+            if let Ok(mut tasks) = tasks.lock() {
+                if let Some(task) = tasks.get_mut(&id) {
+                    task.outcome = Some((Instant::now(), outcome));
+                }
+            }
+        });
+        Ok(id)
+    }
+
+    /// The status of a task, `None` if there never was such a task or it expired
+    pub fn status(&self, id: u64) -> Result<Option<TaskStatus>> {
+        let mut tasks = self.tasks.lock().map_err(|_| Error::PoisonedError)?;
+        self.expire(&mut tasks);
+        Ok(tasks.get(&id).map(|t| t.status(id)))
+    }
+
+    fn expire(&self, tasks: &mut HashMap<u64, Task>) {
+        let retention = self.retention;
+        tasks.retain(|_, t| match &t.outcome {
+            Some((finished, _)) => finished.elapsed() < retention,
+            None => true,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time;
+
+    use super::*;
+
+    async fn wait_for(registry: &TaskRegistry, id: u64) -> Result<TaskStatus> {
+        loop {
+            match registry.status(id)? {
+                Some(status) if status.state != TaskState::Running => return Ok(status),
+                _ => time::delay_for(Duration::from_millis(10)).await,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tasks_expire_after_retention() -> Result<()> {
+        let registry = TaskRegistry::new(Duration::from_millis(50));
+        let ok = registry.spawn("test", |progress| async move {
+            progress.set_total(4);
+            progress.advance(4);
+            Ok(())
+        })?;
+        let failed = registry.spawn("test", |progress| async move {
+            progress.set_total(4);
+            progress.advance(1);
+            Err(Error::IOError("Disk full".into()))
+        })?;
+
+        let status = wait_for(&registry, ok).await?;
+        assert_eq!((status.state, status.progress), (TaskState::Done, 100.0));
+        let status = wait_for(&registry, failed).await?;
+        assert_eq!((status.state, status.progress), (TaskState::Failed, 25.0));
+        assert!(status.error.unwrap().contains("Disk full"));
+
+        time::delay_for(Duration::from_millis(60)).await;
+        assert!(registry.status(ok)?.is_none());
+        assert!(registry.status(failed)?.is_none());
+        Ok(())
+    }
+}
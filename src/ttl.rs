@@ -0,0 +1,86 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::time;
+use tracing::*;
+
+use crate::index::{IndexCatalog, SharedCatalog};
+
+/// Delete the expired documents of every index that has a ttl configured, returning how many were
+/// deleted. An index that fails to sweep is logged and left for the next sweep.
+pub async fn sweep(cat: &IndexCatalog) -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) as i64;
+    let mut swept = 0;
+    for (name, ttl) in &cat.settings.ttl {
+        let index = match cat.get_index(name) {
+            Ok(index) => index,
+            Err(_) => continue,
+        };
+        let cutoff = now.saturating_sub(ttl.max_age as i64);
+        match index.expire(&ttl.field, cutoff).await {
+            Ok(0) => debug!("Nothing expired in index={}", name),
+            Ok(n) => {
+                info!("Expired {} documents older than {} from index={}", n, cutoff, name);
+                swept += n;
+            }
+            Err(e) => error!("Could not expire documents from index={}: {}", name, e),
+        }
+    }
+    swept
+}
+
+pub async fn sweeper(cat: SharedCatalog, interval: f32) -> Result<(), ()> {
+    let mut interval = time::interval(Duration::from_secs_f32(interval));
+    loop {
+        interval.tick().await;
+        if !cat.settings.ttl.is_empty() {
+            sweep(&cat).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::schema::*;
+    use tantivy::{doc, Index};
+
+    use toshi_types::{Error, Search};
+
+    use crate::handle::IndexHandle;
+    use crate::settings::Ttl;
+    use crate::Result;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sweep_expired_docs() -> Result<()> {
+        let mut builder = SchemaBuilder::new();
+        let ts = builder.add_i64_field("ts", INDEXED | FAST | STORED);
+        let body = builder.add_text_field("body", TEXT | STORED);
+        let index = Index::create_in_ram(builder.build());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        {
+            let mut writer = index.writer(30_000_000).map_err(Error::from)?;
+            writer.add_document(doc!(ts => now - 7200, body => "old"));
+            writer.add_document(doc!(ts => now - 10, body => "new"));
+            writer.add_document(doc!(body => "timeless"));
+            writer.commit().map_err(Error::from)?;
+        }
+
+        let mut catalog = IndexCatalog::with_index("logs".into(), index)?;
+        let ttl = Ttl {
+            field: "ts".into(),
+            max_age: 3600,
+        };
+        catalog.settings.ttl.insert("logs".into(), ttl);
+        assert_eq!(sweep(&catalog).await, 1);
+        assert_eq!(sweep(&catalog).await, 0);
+
+        let logs = catalog.get_index("logs")?;
+        logs.reload()?;
+        let results = logs.search_index(Search::all_docs()).await?;
+        let mut left: Vec<&str> = results.docs.iter().filter_map(|d| d.doc["body"][0].text()).collect();
+        left.sort();
+        assert_eq!(left, vec!["new", "timeless"]);
+        Ok(())
+    }
+}
@@ -1,4 +1,9 @@
-use hyper::Body;
+use std::io::{self, Write};
+
+use flate2::write::{MultiGzDecoder, ZlibDecoder};
+use futures::StreamExt;
+use hyper::header::CONTENT_ENCODING;
+use hyper::{Body, HeaderMap};
 use serde::Serialize;
 
 use toshi_types::{Error, ErrorResponse};
@@ -16,7 +21,7 @@ where
 }
 
 pub fn error_response(code: hyper::StatusCode, e: Error) -> hyper::Response<Body> {
-    let mut resp = with_body(ErrorResponse { message: e.to_string() });
+    let mut resp = with_body(ErrorResponse::from(e));
     *resp.status_mut() = code;
     resp
 }
@@ -29,6 +34,95 @@ pub async fn not_found() -> Result<hyper::Response<Body>, hyper::Error> {
     Ok(empty_with_code(hyper::StatusCode::NOT_FOUND))
 }
 
+/// Where a compressed body is decompressed to, refusing to hold more than `max` bytes
+struct Capped {
+    decoded: Vec<u8>,
+    max: u64,
+}
+
+/// Why a [`Capped`] body stopped taking bytes
+#[derive(Debug)]
+struct TooLarge;
+
+impl std::fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "body too large")
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+impl Write for Capped {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.decoded.len() as u64 + buf.len() as u64 > self.max {
+            return Err(io::Error::new(io::ErrorKind::Other, TooLarge));
+        }
+        self.decoded.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Feed the chunks of `body` to `decoder` as they arrive
+async fn decode_chunks<D: Write>(decoder: &mut D, mut body: Body) -> io::Result<()> {
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        decoder.write_all(&chunk)?;
+    }
+    Ok(())
+}
+
+/// The body of a request sent with a `Content-Encoding` of `gzip` or `deflate`, decompressed so
+/// handlers can read it like any other body. Compressed bodies are decompressed chunk by chunk as
+/// they arrive, and fail with [`Error::PayloadTooLarge`] once they decompress to more than
+/// `max_size` bytes. Bodies without an encoding are passed through untouched.
+pub async fn decode_body(headers: &HeaderMap, body: Body, max_size: u64) -> Result<Body, Error> {
+    let encoding = match headers.get(CONTENT_ENCODING) {
+        Some(v) => v
+            .to_str()
+            .map_err(|_| Error::InvalidDocument("Content-Encoding is not valid text".into()))?
+            .trim()
+            .to_ascii_lowercase(),
+        None => return Ok(body),
+    };
+    if encoding.is_empty() || encoding == "identity" {
+        return Ok(body);
+    }
+    let capped = Capped {
+        decoded: Vec::new(),
+        max: max_size,
+    };
+    let decoded = match encoding.as_str() {
+        // Concatenated gzip members, like appended log files, are decompressed one after the other
+        "gzip" | "x-gzip" => {
+            let mut decoder = MultiGzDecoder::new(capped);
+            match decode_chunks(&mut decoder, body).await {
+                Ok(()) => decoder.finish(),
+                Err(e) => Err(e),
+            }
+        }
+        "deflate" => {
+            let mut decoder = ZlibDecoder::new(capped);
+            match decode_chunks(&mut decoder, body).await {
+                Ok(()) => decoder.finish(),
+                Err(e) => Err(e),
+            }
+        }
+        other => return Err(Error::InvalidDocument(format!("Unsupported Content-Encoding: {}", other))),
+    };
+    match decoded {
+        Ok(capped) => Ok(Body::from(capped.decoded)),
+        Err(e) if e.get_ref().map_or(false, |e| e.is::<TooLarge>()) => Err(Error::PayloadTooLarge(max_size)),
+        Err(e) => Err(Error::InvalidDocument(format!(
+            "Body could not be decompressed as {}: {}",
+            encoding, e
+        ))),
+    }
+}
+
 pub fn parse_path(path: &str) -> Vec<&str> {
     path.trim_matches('/').split('/').filter(|s| !s.is_empty()).collect()
 }
@@ -54,4 +148,41 @@ mod tests {
         assert_eq!(parsed_two[0], "path");
         assert_eq!(parsed_two[1], "two");
     }
+
+    #[tokio::test]
+    async fn test_decode_body() -> Result<(), Box<dyn std::error::Error>> {
+        use flate2::write::{GzEncoder, ZlibEncoder};
+        use flate2::Compression;
+        use std::io::Write;
+
+        let text = b"{\"test_text\": \"squeezed\"}";
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(text)?;
+        let mut deflate = ZlibEncoder::new(Vec::new(), Compression::default());
+        deflate.write_all(text)?;
+        let decode = |encoding: &str, body: Vec<u8>| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_ENCODING, encoding.parse().unwrap());
+            async move {
+                let decoded = decode_body(&headers, Body::from(body), 1024).await?;
+                Ok::<_, Error>(hyper::body::to_bytes(decoded).await.unwrap().to_vec())
+            }
+        };
+        assert_eq!(decode("gzip", gzip.finish()?).await?, text.to_vec());
+        assert_eq!(decode("Deflate", deflate.finish()?).await?, text.to_vec());
+        assert_eq!(decode("identity", text.to_vec()).await?, text.to_vec());
+        assert!(decode("gzip", text.to_vec()).await.is_err());
+        assert!(decode("br", text.to_vec()).await.is_err());
+
+        // A small body that decompresses to more than the limit is turned away without holding all of it
+        let mut bomb = GzEncoder::new(Vec::new(), Compression::best());
+        bomb.write_all(&[b' '; 64 * 1024])?;
+        let bomb = bomb.finish()?;
+        assert!(bomb.len() < 1024);
+        match decode("gzip", bomb).await {
+            Err(Error::PayloadTooLarge(1024)) => {}
+            other => panic!("Expected the body to be too large, got {:?}", other),
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+use tantivy::Term;
+
+use toshi_types::Error;
+
+use crate::Result;
+
+type Versions = HashMap<Term, u64>;
+
+/// The versions of documents written to an index that its reader may not have seen yet, so a
+/// version is never handed out twice. Versions are pending until the commit they're written in,
+/// then committed until a reload of the reader that started after that commit. Everything but
+/// [`VersionCache::reloaded`] has to be called with the index's writer lock held.
+#[derive(Debug, Default)]
+pub struct VersionCache {
+    pending: Mutex<Versions>,
+    committed: Mutex<Versions>,
+}
+
+impl VersionCache {
+    fn lock(versions: &Mutex<Versions>) -> Result<MutexGuard<'_, Versions>> {
+        versions.lock().map_err(|_| Error::PoisonedError)
+    }
+
+    /// The latest version written for `key` that the reader may not have seen
+    pub fn get(&self, key: &Term) -> Result<Option<u64>> {
+        if let Some(v) = Self::lock(&self.pending)?.get(key) {
+            return Ok(Some(*v));
+        }
+        Ok(Self::lock(&self.committed)?.get(key).copied())
+    }
+
+    pub fn insert(&self, key: Term, version: u64) -> Result<()> {
+        Self::lock(&self.pending)?.insert(key, version);
+        Ok(())
+    }
+
+    /// Forget the versions of a deleted document
+    pub fn remove(&self, key: &Term) -> Result<()> {
+        Self::lock(&self.pending)?.remove(key);
+        Self::lock(&self.committed)?.remove(key);
+        Ok(())
+    }
+
+    /// Record that every pending version was just committed
+    pub fn committed(&self) -> Result<()> {
+        let pending = std::mem::take(&mut *Self::lock(&self.pending)?);
+        Self::lock(&self.committed)?.extend(pending);
+        Ok(())
+    }
+
+    /// Run `reload`, then forget the committed versions it was sure to load. Versions committed
+    /// while it ran are kept, the reload may have started before their commit.
+    pub fn reloaded<F>(&self, reload: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        let seen = Self::lock(&self.committed)?.clone();
+        reload()?;
+        Self::lock(&self.committed)?.retain(|key, version| seen.get(key) != Some(version));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::schema::Field;
+
+    use super::*;
+
+    #[test]
+    fn test_versions_outlive_their_commit_until_a_reload() -> Result<()> {
+        let cache = VersionCache::default();
+        let (a, b) = (
+            Term::from_field_text(Field::from_field_id(0), "a"),
+            Term::from_field_text(Field::from_field_id(0), "b"),
+        );
+        cache.insert(a.clone(), 1)?;
+        cache.committed()?;
+        cache.insert(b.clone(), 1)?;
+
+        // A reload only forgets what was committed, `b` hasn't been yet
+        cache.reloaded(|| Ok(()))?;
+        assert_eq!((cache.get(&a)?, cache.get(&b)?), (None, Some(1)));
+
+        // A commit that lands while a reload runs may not be part of it
+        cache.reloaded(|| cache.committed())?;
+        assert_eq!(cache.get(&b)?, Some(1));
+        cache.reloaded(|| Ok(()))?;
+        assert_eq!(cache.get(&b)?, None);
+
+        cache.insert(a.clone(), 2)?;
+        cache.committed()?;
+        cache.remove(&a)?;
+        assert_eq!(cache.get(&a)?, None);
+        Ok(())
+    }
+}
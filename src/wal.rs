@@ -0,0 +1,194 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use tantivy::Opstamp;
+
+use toshi_types::{DeleteDoc, Error};
+
+use crate::{AddDocument, Result};
+
+/// How hard the write-ahead log pushes every document appended to it to disk
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WalSync {
+    /// Fsync the log after every document, an acknowledged document survives the machine losing power
+    Always,
+    /// Hand every document to the operating system without waiting for it to reach the disk, an
+    /// acknowledged document survives the process crashing but not the machine going down
+    Os,
+}
+
+/// The settings of an index's write-ahead log, see [`WriteAheadLog`]
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct WalSettings {
+    /// How large the log can grow, in bytes, before the index is committed to empty it
+    #[serde(default = "WalSettings::default_max_bytes")]
+    pub max_bytes: u64,
+    /// Whether every document is fsynced to the log before its add is acknowledged
+    #[serde(default = "WalSettings::default_sync")]
+    pub sync: WalSync,
+}
+
+impl WalSettings {
+    pub fn default_max_bytes() -> u64 {
+        64 * 1024 * 1024
+    }
+
+    pub fn default_sync() -> WalSync {
+        WalSync::Always
+    }
+}
+
+impl Default for WalSettings {
+    fn default() -> Self {
+        Self {
+            max_bytes: WalSettings::default_max_bytes(),
+            sync: WalSettings::default_sync(),
+        }
+    }
+}
+
+/// A write to an index that the log keeps until it's committed
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Logged {
+    /// A document added on its own or by a bulk insert
+    Add(AddDocument),
+    /// A delete by term
+    Delete(DeleteDoc),
+}
+
+/// A write in the log with the opstamp the index's writer gave it
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    opstamp: Opstamp,
+    #[serde(flatten)]
+    op: Logged,
+}
+
+/// Every write to an index since its last commit, kept in a file next to the index as a line of
+/// JSON each so they can be made again if the process dies before they're committed. The log is
+/// emptied on every commit and rollback, and a write the index committed before the log could be
+/// emptied is told apart by its opstamp being no later than the commit's.
+pub struct WriteAheadLog {
+    path: PathBuf,
+    settings: WalSettings,
+    file: Mutex<File>,
+}
+
+impl WriteAheadLog {
+    /// Open the log at `path`, keeping whatever it already holds so it can be replayed
+    pub fn open(path: &Path, settings: WalSettings) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            settings,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// The log of the index `name` under the data directory `base_path`
+    pub fn path_for(base_path: &str, name: &str) -> PathBuf {
+        Path::new(base_path).join(format!("{}.wal", name))
+    }
+
+    /// Append `op`, which the writer gave `opstamp`, returning whether the log has grown past its
+    /// limit and the index should be committed
+    pub fn append(&self, opstamp: Opstamp, op: Logged) -> Result<bool> {
+        let entry = Entry { opstamp, op };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().map_err(|_| Error::PoisonedError)?;
+        file.write_all(&line)?;
+        if self.settings.sync == WalSync::Always {
+            file.sync_data()?;
+        }
+        Ok(file.metadata()?.len() >= self.settings.max_bytes)
+    }
+
+    /// Empty the log, once everything in it has been committed or rolled back
+    pub fn truncate(&self) -> Result<()> {
+        let file = self.file.lock().map_err(|_| Error::PoisonedError)?;
+        file.set_len(0)?;
+        if self.settings.sync == WalSync::Always {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// The writes in the log that were made after the commit at `committed`, in the order they
+    /// were made. A line cut short by a crash part way through writing it is skipped.
+    pub fn uncommitted(&self, committed: Opstamp) -> Result<Vec<Logged>> {
+        let _file = self.file.lock().map_err(|_| Error::PoisonedError)?;
+        let reader = BufReader::new(File::open(&self.path)?);
+        let mut ops = Vec::new();
+        for line in reader.lines() {
+            match serde_json::from_str::<Entry>(&line?) {
+                Ok(entry) if entry.opstamp > committed => ops.push(entry.op),
+                Ok(_) => (),
+                Err(e) => tracing::warn!("Skipping an unreadable entry of {}: {}", self.path.display(), e),
+            }
+        }
+        Ok(ops)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn doc(text: &str) -> Logged {
+        Logged::Add(AddDocument {
+            options: None,
+            document: json!({ "test_text": text }),
+            key: None,
+            if_version: None,
+        })
+    }
+
+    #[test]
+    fn test_uncommitted() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("toshi-wal-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir)?;
+        let path = WriteAheadLog::path_for(&dir.to_string_lossy(), "test_index");
+        let settings = WalSettings {
+            max_bytes: 100,
+            sync: WalSync::Os,
+        };
+        let wal = WriteAheadLog::open(&path, settings.clone())?;
+        assert!(!wal.append(3, doc("first"))?);
+        assert!(wal.append(4, doc("second"))?);
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(b"{\"opstamp\": 5, \"doc")?;
+
+        let reopened = WriteAheadLog::open(&path, settings)?;
+        let texts = |ops: Vec<Logged>| {
+            ops.into_iter()
+                .map(|op| match op {
+                    Logged::Add(d) => d.document["test_text"].clone(),
+                    Logged::Delete(d) => json!(d.terms),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(texts(reopened.uncommitted(0)?), vec![json!("first"), json!("second")]);
+        assert_eq!(texts(reopened.uncommitted(3)?), vec![json!("second")]);
+        let delete = DeleteDoc {
+            options: None,
+            terms: vec![("test_text".to_string(), "first".to_string())].into_iter().collect(),
+        };
+        reopened.append(6, Logged::Delete(delete))?;
+        let last = texts(reopened.uncommitted(4)?);
+        assert_eq!(last, vec![json!({ "test_text": "first" })]);
+        reopened.truncate()?;
+        assert!(reopened.uncommitted(0)?.is_empty());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -68,7 +68,7 @@ impl ToshiClient {
         D: Serialize,
     {
         let uri = self.uri(index);
-        let body = serde_json::to_vec(&AddDocument { options, document })?;
+        let body = serde_json::to_vec(&AddDocument::new(options, document))?;
         self.client.post(uri, body).map_err(Into::into)
     }
 
@@ -1,28 +1,115 @@
+use std::collections::BTreeMap;
 use std::iter::Sum;
 use std::ops::Add;
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 use tantivy::space_usage::SearcherSpaceUsage;
 use tantivy::IndexMeta;
 
 use crate::query::KeyValue;
+use crate::sketch::{HyperLogLog, TDigest};
 
 /// A single document returned from a Tantivy Index
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ScoredDoc<D: Clone> {
-    /// The document's relevancy score
+    /// The document's relevancy score, left out when the search wasn't scored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub score: Option<f32>,
     /// The actual document
     pub doc: D,
+    /// Where the search's terms matched in each value of the fields the search asked to highlight,
+    /// `highlights["body"][0]` are the matches in the document's first `body` value
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub highlights: BTreeMap<String, Vec<Vec<Highlight>>>,
+    /// The document's `_id`, only given when the search skipped loading the rest of its stored
+    /// fields and `doc` is left empty
+    #[serde(rename = "_id", default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Where the document is in the index it was found in, for hits that are loaded after the
+    /// search. It's never sent anywhere, the index's segments may have changed by the time it's read.
+    #[serde(skip)]
+    pub address: Option<DocRef>,
+    /// The document's value for the field the search was sorted by, left out when it wasn't
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sort: Option<SortValue>,
 }
 
 impl<D: Clone> ScoredDoc<D> {
     /// Constructor for a new ScoredDoc
     pub fn new(score: Option<f32>, doc: D) -> Self {
-        Self { score, doc }
+        Self {
+            score,
+            doc,
+            highlights: BTreeMap::new(),
+            id: None,
+            address: None,
+            sort: None,
+        }
+    }
+}
+
+/// The value of a `u64` or `i64` field a search was sorted by, which orders the hits of several
+/// searches merged together
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(untagged)]
+pub enum SortValue {
+    /// The value of a `u64` field, or of an `i64` field that isn't negative once it's been sent
+    U64(u64),
+    /// The value of an `i64` field
+    I64(i64),
+}
+
+impl SortValue {
+    fn key(self) -> i128 {
+        match self {
+            SortValue::U64(v) => i128::from(v),
+            SortValue::I64(v) => i128::from(v),
+        }
+    }
+}
+
+// Values are compared by number, a value sent as an `i64` comes back as a `u64` when it isn't negative
+impl PartialEq for SortValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for SortValue {}
+
+impl PartialOrd for SortValue {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SortValue {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
     }
 }
 
+/// Where a document is in an index, the segment it's in and its id in that segment. It only
+/// identifies the document until the index's segments are next merged.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DocRef {
+    /// The position of the document's segment among the segments of the searched index
+    pub segment: u32,
+    /// The document's id within its segment
+    pub doc: u32,
+}
+
+/// Where a term of a search matched in a field's value, counted in characters rather than bytes
+/// from the start of the value, with `end` one past the match's last character
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Highlight {
+    /// The first character of the match
+    pub start: usize,
+    /// The character after the match
+    pub end: usize,
+}
+
 /// The Search response object from Toshi
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SearchResults<D: Clone> {
@@ -32,6 +119,350 @@ pub struct SearchResults<D: Clone> {
     pub docs: Vec<ScoredDoc<D>>,
     /// The, if any, facets returned
     pub facets: Vec<KeyValue<String, u64>>,
+    /// Where the time of the search went, only returned when the search asked to be profiled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<SearchProfile>,
+    /// The results of each aggregation the search asked for, by the name it was given
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub aggregations: BTreeMap<String, AggregationResult>,
+    /// Which shards the search ran on and how long each of them took
+    #[serde(rename = "_shards", default, skip_serializing_if = "Option::is_none")]
+    pub shards: Option<ShardsInfo>,
+}
+
+/// How many of the shards a search was sent to answered it, see [`ShardResult`] for each of them
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ShardsInfo {
+    /// Every shard of the indexes searched, including the ones skipped
+    pub total: usize,
+    /// The shards whose results are in the response
+    pub successful: usize,
+    /// The shards the search wasn't sent to, like the ones a routing value rules out
+    pub skipped: usize,
+    /// The shards that failed to answer, their documents are missing from the response
+    pub failed: usize,
+    /// How each shard the search was sent to did
+    #[serde(default)]
+    pub shards: Vec<ShardResult>,
+}
+
+/// How one shard did in a search
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShardResult {
+    /// The index the shard belongs to
+    pub index: String,
+    /// The number of the shard within its index
+    pub shard: usize,
+    /// How long the shard took to answer, or to fail, in milliseconds
+    pub took_ms: f64,
+    /// Why the shard failed, left out when it answered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ShardsInfo {
+    /// A search of shard `shard` of `index` that answered in `took_ms`
+    pub fn single(index: &str, shard: usize, took_ms: f64) -> Self {
+        Self {
+            total: 1,
+            successful: 1,
+            skipped: 0,
+            failed: 0,
+            shards: vec![ShardResult {
+                index: index.into(),
+                shard,
+                took_ms,
+                error: None,
+            }],
+        }
+    }
+
+    /// A search of shard `shard` of `index` that failed with `error` after `took_ms`
+    pub fn failure(index: &str, shard: usize, took_ms: f64, error: String) -> Self {
+        Self {
+            total: 1,
+            successful: 0,
+            skipped: 0,
+            failed: 1,
+            shards: vec![ShardResult {
+                index: index.into(),
+                shard,
+                took_ms,
+                error: Some(error),
+            }],
+        }
+    }
+
+    /// The shards of both searches, for results merged from several searches
+    pub fn merge(mut self, mut other: ShardsInfo) -> Self {
+        self.total += other.total;
+        self.successful += other.successful;
+        self.skipped += other.skipped;
+        self.failed += other.failed;
+        self.shards.append(&mut other.shards);
+        self
+    }
+
+    /// Merge the shards of two results, either of which may not have reported any
+    pub fn merge_opt(left: Option<ShardsInfo>, right: Option<ShardsInfo>) -> Option<ShardsInfo> {
+        match (left, right) {
+            (Some(l), Some(r)) => Some(l.merge(r)),
+            (l, r) => l.or(r),
+        }
+    }
+}
+
+/// The result of one of a search's aggregations
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum AggregationResult {
+    /// The buckets of a [`crate::DateHistogram`], in order of their keys
+    Buckets {
+        /// The buckets
+        buckets: Vec<HistogramBucket>,
+    },
+    /// The values of a [`crate::Percentiles`] aggregation, in the order the percentiles were asked for
+    Percentiles {
+        /// The value at each percentile
+        values: Vec<PercentileValue>,
+        /// How many values the percentiles were computed from
+        count: u64,
+        /// The digest the percentiles were estimated from, which the percentiles of several
+        /// searches are merged by
+        #[serde(default)]
+        digest: TDigest,
+    },
+    /// The mean of the values of an [`crate::Average`] aggregation
+    Average {
+        /// The mean, `null` when no document had a value
+        value: Option<f64>,
+        /// How many values the mean is of
+        count: u64,
+    },
+    /// The estimated number of distinct values of a [`crate::DistinctCount`]
+    Cardinality {
+        /// The estimate
+        value: u64,
+        /// The count the estimate was made from, which the distinct counts of several searches
+        /// are merged by
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hll: Option<HyperLogLog>,
+    },
+    /// The ranges of a [`crate::RangeAggregation`], in the order they were listed or of their values
+    Ranges {
+        /// The ranges
+        ranges: Vec<RangeBucket>,
+    },
+    /// The terms of a [`crate::TermsAggregation`] held by the most documents, those held by more first
+    Terms {
+        /// The terms
+        terms: Vec<TermsBucket>,
+    },
+}
+
+impl AggregationResult {
+    /// Combine the results of two searches. Buckets with the same key have their counts added up,
+    /// percentiles are estimated again from both digests merged and averages combine exactly.
+    /// Distinct counts are estimated again from both counts merged, only without one of them is
+    /// the larger of the two kept, which undercounts when the searches saw different values.
+    pub fn merge(self, other: AggregationResult) -> Self {
+        match (self, other) {
+            (AggregationResult::Buckets { buckets }, AggregationResult::Buckets { buckets: others }) => {
+                let mut merged: BTreeMap<i64, HistogramBucket> = BTreeMap::new();
+                for bucket in buckets.into_iter().chain(others) {
+                    match merged.get_mut(&bucket.key) {
+                        Some(existing) => {
+                            existing.doc_count += bucket.doc_count;
+                            AggregationResult::merge_all(&mut existing.aggregations, bucket.aggregations);
+                        }
+                        None => {
+                            merged.insert(bucket.key, bucket);
+                        }
+                    }
+                }
+                AggregationResult::Buckets {
+                    buckets: merged.into_iter().map(|(_, b)| b).collect(),
+                }
+            }
+            (AggregationResult::Percentiles { values, digest, .. }, AggregationResult::Percentiles { digest: other, .. }) => {
+                AggregationResult::percentiles(digest.merge(other), values.iter().map(|v| v.percent))
+            }
+            (
+                AggregationResult::Average { value, count },
+                AggregationResult::Average {
+                    value: other,
+                    count: other_count,
+                },
+            ) => {
+                let value = match (value, other) {
+                    (Some(x), Some(y)) => Some((x * count as f64 + y * other_count as f64) / (count + other_count) as f64),
+                    (x, y) => x.or(y),
+                };
+                AggregationResult::Average {
+                    value,
+                    count: count + other_count,
+                }
+            }
+            (AggregationResult::Cardinality { hll: Some(hll), .. }, AggregationResult::Cardinality { hll: Some(other), .. }) => {
+                let hll = hll.merge(other);
+                AggregationResult::Cardinality {
+                    value: hll.count(),
+                    hll: Some(hll),
+                }
+            }
+            // The values counted in each search can overlap, so the larger count is all that's
+            // known to be in both
+            (AggregationResult::Cardinality { value, .. }, AggregationResult::Cardinality { value: other, .. }) => {
+                AggregationResult::Cardinality {
+                    value: value.max(other),
+                    hll: None,
+                }
+            }
+            // Listed ranges are the same in both, fixed width ones are matched up by where they
+            // start and put back in order of it when either search had ones the other didn't
+            (AggregationResult::Ranges { mut ranges }, AggregationResult::Ranges { ranges: others }) => {
+                let mut added = false;
+                for other in others {
+                    match ranges.iter_mut().find(|r| r.from == other.from && r.to == other.to) {
+                        Some(range) => range.doc_count += other.doc_count,
+                        None => {
+                            ranges.push(other);
+                            added = true;
+                        }
+                    }
+                }
+                if added {
+                    ranges.sort_by(|a, b| a.from.partial_cmp(&b.from).unwrap_or(std::cmp::Ordering::Equal));
+                }
+                AggregationResult::Ranges { ranges }
+            }
+            // Each search returns its own top terms, of which as many as the longer list are kept
+            (AggregationResult::Terms { terms }, AggregationResult::Terms { terms: others }) => {
+                let size = terms.len().max(others.len());
+                let mut merged: BTreeMap<String, TermsBucket> = BTreeMap::new();
+                for bucket in terms.into_iter().chain(others) {
+                    match merged.get_mut(&bucket.key) {
+                        Some(existing) => {
+                            existing.doc_count += bucket.doc_count;
+                            AggregationResult::merge_all(&mut existing.aggregations, bucket.aggregations);
+                        }
+                        None => {
+                            merged.insert(bucket.key.clone(), bucket);
+                        }
+                    }
+                }
+                let mut terms: Vec<TermsBucket> = merged.into_iter().map(|(_, b)| b).collect();
+                TermsBucket::sort(&mut terms);
+                terms.truncate(size);
+                AggregationResult::Terms { terms }
+            }
+            // Aggregations of different kinds under the same name can't be combined
+            (this, _) => this,
+        }
+    }
+
+    /// The percentiles `digest` estimates at each of `percents`
+    pub fn percentiles<I: IntoIterator<Item = f64>>(mut digest: TDigest, percents: I) -> Self {
+        digest.compress();
+        AggregationResult::Percentiles {
+            count: digest.count(),
+            values: percents
+                .into_iter()
+                .map(|percent| PercentileValue {
+                    percent,
+                    value: digest.percentile(percent),
+                })
+                .collect(),
+            digest,
+        }
+    }
+
+    /// Merge each of `other`'s results into the result of the same name in `results`
+    pub fn merge_all(results: &mut BTreeMap<String, AggregationResult>, other: BTreeMap<String, AggregationResult>) {
+        for (name, result) in other {
+            let merged = match results.remove(&name) {
+                Some(existing) => existing.merge(result),
+                None => result,
+            };
+            results.insert(name, merged);
+        }
+    }
+}
+
+/// The value at one percentile of a [`crate::Percentiles`] aggregation
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PercentileValue {
+    /// The percentile, between 0 and 100
+    pub percent: f64,
+    /// The value, `null` when no document had a value to compute it from
+    pub value: Option<f64>,
+}
+
+/// The number of documents in one interval of a [`crate::DateHistogram`]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistogramBucket {
+    /// When the interval starts, in seconds since the epoch
+    pub key: i64,
+    /// When the interval starts as an RFC 3339 time in the histogram's time zone
+    pub key_as_string: String,
+    /// How many documents fell in the interval
+    pub doc_count: u64,
+    /// The results of the aggregations nested in the histogram over the documents in the interval
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub aggregations: BTreeMap<String, AggregationResult>,
+}
+
+/// The number of documents in one range of a [`crate::RangeAggregation`], from `from` up to but
+/// not including `to`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RangeBucket {
+    /// The lowest value in the range, left out when it has no lower bound
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<f64>,
+    /// The value the range ends before, left out when it has no upper bound
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<f64>,
+    /// How many documents had a value in the range
+    pub doc_count: u64,
+}
+
+/// The number of documents holding one term of a [`crate::TermsAggregation`]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TermsBucket {
+    /// The term
+    pub key: String,
+    /// How many documents held the term
+    pub doc_count: u64,
+    /// The results of the aggregations nested in the terms aggregation over the documents holding the term
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub aggregations: BTreeMap<String, AggregationResult>,
+}
+
+impl TermsBucket {
+    /// Put buckets in order of how many documents they hold, most first, ties going to the lowest term
+    pub fn sort(buckets: &mut Vec<TermsBucket>) {
+        buckets.sort_by(|a, b| b.doc_count.cmp(&a.doc_count).then_with(|| a.key.cmp(&b.key)));
+    }
+}
+
+/// How long each stage of a search took, in milliseconds
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct SearchProfile {
+    /// Reading the search out of the request
+    pub parse_ms: f64,
+    /// Turning the search into a query against the index
+    pub build_ms: f64,
+    /// Running the query and collecting the matching documents
+    pub collect_ms: f64,
+    /// Loading the stored fields of the documents returned
+    pub fetch_ms: f64,
+}
+
+impl SearchProfile {
+    /// The milliseconds in a duration, as they're reported in a profile
+    pub fn millis(duration: Duration) -> f64 {
+        duration.as_secs_f64() * 1000.0
+    }
 }
 
 impl<D: Clone> Add for SearchResults<D> {
@@ -40,11 +471,22 @@ impl<D: Clone> Add for SearchResults<D> {
     fn add(self, mut rhs: SearchResults<D>) -> Self::Output {
         let mut docs = self.docs;
         let mut facets = self.facets;
+        let mut aggregations = self.aggregations;
         let hits = self.hits + rhs.hits;
         facets.append(&mut rhs.facets);
+        AggregationResult::merge_all(&mut aggregations, std::mem::replace(&mut rhs.aggregations, BTreeMap::new()));
+        let shards = ShardsInfo::merge_opt(self.shards, rhs.shards.take());
         docs.append(&mut rhs.get_docs());
 
-        Self { hits, docs, facets }
+        // Results merged from several searches don't have a single breakdown of where time went
+        Self {
+            hits,
+            docs,
+            facets,
+            profile: None,
+            aggregations,
+            shards,
+        }
     }
 }
 
@@ -66,7 +508,91 @@ impl<D: Clone> SearchResults<D> {
             hits: docs.len(),
             docs,
             facets: Vec::new(),
+            profile: None,
+            aggregations: BTreeMap::new(),
+            shards: None,
+        }
+    }
+
+    /// Convert every document, keeping the scores, facets, profile and shards of the results
+    pub fn map_docs<E: Clone, F: FnMut(D) -> E>(self, mut f: F) -> SearchResults<E> {
+        SearchResults {
+            hits: self.hits,
+            docs: self
+                .docs
+                .into_iter()
+                .map(|d| ScoredDoc {
+                    score: d.score,
+                    doc: f(d.doc),
+                    highlights: d.highlights,
+                    id: d.id,
+                    address: d.address,
+                    sort: d.sort,
+                })
+                .collect(),
+            facets: self.facets,
+            profile: self.profile,
+            aggregations: self.aggregations,
+            shards: self.shards,
+        }
+    }
+
+    /// Put the documents of results merged from several searches back in order, highest value of
+    /// the field they were sorted by first when they were sorted by one and best score first
+    /// otherwise. Documents without either keep the order they were merged in.
+    pub fn sort_hits(mut self) -> Self {
+        if self.docs.iter().any(|d| d.sort.is_some()) {
+            self.docs.sort_by(|a, b| b.sort.cmp(&a.sort));
+        } else {
+            // Documents without a score sort after every scored one
+            self.docs
+                .sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+        }
+        self
+    }
+
+    /// Keep only the `limit` best documents of results merged from several searches, see
+    /// [`SearchResults::sort_hits`]
+    pub fn top(self, limit: usize) -> Self {
+        let mut results = self.sort_hits();
+        results.docs.truncate(limit);
+        results.hits = results.docs.len();
+        results
+    }
+
+    /// Rescale the scores of these results with `strategy`, so results of searches whose scores
+    /// are on different scales can be merged. Documents without a score are left as they are.
+    pub fn normalize_scores(mut self, strategy: ScoreNormalization) -> Self {
+        let scores: Vec<f32> = self.docs.iter().filter_map(|d| d.score).collect();
+        if scores.is_empty() {
+            return self;
+        }
+        let rescale: Box<dyn Fn(f32) -> f32> = match strategy {
+            ScoreNormalization::None => return self,
+            ScoreNormalization::MinMax => {
+                let min = scores.iter().cloned().fold(f32::INFINITY, f32::min);
+                let max = scores.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+                // Results that all score the same are all as good as the best of them
+                if max > min {
+                    Box::new(move |s| (s - min) / (max - min))
+                } else {
+                    Box::new(|_| 1.0)
+                }
+            }
+            ScoreNormalization::ZScore => {
+                let mean = scores.iter().sum::<f32>() / scores.len() as f32;
+                let deviation = (scores.iter().map(|s| (s - mean).powi(2)).sum::<f32>() / scores.len() as f32).sqrt();
+                if deviation > 0.0 {
+                    Box::new(move |s| (s - mean) / deviation)
+                } else {
+                    Box::new(|_| 0.0)
+                }
+            }
+        };
+        for doc in &mut self.docs {
+            doc.score = doc.score.map(&rescale);
         }
+        self
     }
 
     /// Constructor for documents with facets
@@ -75,21 +601,253 @@ impl<D: Clone> SearchResults<D> {
             hits: docs.len(),
             docs,
             facets,
+            profile: None,
+            aggregations: BTreeMap::new(),
+            shards: None,
         }
     }
 }
 
+/// How the scores of each index's results are rescaled before the results of a search over several
+/// indexes are merged, see [`SearchResults::normalize_scores`]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScoreNormalization {
+    /// Merge the scores as they are
+    None,
+    /// Scale every index's scores to between 0 and 1, its best hit scoring 1
+    MinMax,
+    /// Replace scores with how many standard deviations they are from the mean score of their index
+    ZScore,
+}
+
+impl Default for ScoreNormalization {
+    fn default() -> Self {
+        ScoreNormalization::None
+    }
+}
+
+impl ScoreNormalization {
+    pub(crate) fn is_none(&self) -> bool {
+        *self == ScoreNormalization::None
+    }
+}
+
 /// A response gotten from the _summary route for an index
 #[derive(Debug, Serialize)]
 pub struct SummaryResponse {
     summaries: IndexMeta,
+    /// When the index was last committed, in milliseconds since the epoch, null if it never was
+    last_commit_time: Option<u64>,
+    /// The opstamp of the index's last commit, 0 if it was never committed
+    last_opstamp: u64,
+    /// The share of the documents in the index's segments that are deleted but not yet merged away,
+    /// 0 from servers that don't report it
+    #[serde(default)]
+    deleted_docs_ratio: f64,
+    /// Whether so many documents are deleted that merging the index's segments is worth it
+    #[serde(default)]
+    should_optimize: bool,
+    /// When the index was created, in milliseconds since the epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<u64>,
+    /// When the index's schema or settings were last changed, in milliseconds since the epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    modified_at: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     segment_sizes: Option<SearcherSpaceUsage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    settings: Option<serde_json::Value>,
 }
 
 impl SummaryResponse {
     /// Constructor for a new summary response
     pub fn new(summaries: IndexMeta, segment_sizes: Option<SearcherSpaceUsage>) -> Self {
-        Self { summaries, segment_sizes }
+        let deleted: u64 = summaries.segments.iter().map(|s| u64::from(s.num_deleted_docs())).sum();
+        let total: u64 = summaries.segments.iter().map(|s| u64::from(s.max_doc())).sum();
+        Self {
+            last_commit_time: None,
+            last_opstamp: summaries.opstamp,
+            deleted_docs_ratio: if total == 0 { 0.0 } else { deleted as f64 / total as f64 },
+            should_optimize: false,
+            created_at: None,
+            modified_at: None,
+            summaries,
+            segment_sizes,
+            settings: None,
+        }
     }
+
+    /// Attach the time of the index's last commit to the summary
+    pub fn with_last_commit_time(mut self, last_commit_time: Option<u64>) -> Self {
+        self.last_commit_time = last_commit_time;
+        self
+    }
+
+    /// Flag the index as worth optimizing when more than `threshold` of its documents are deleted
+    pub fn with_optimize_threshold(mut self, threshold: f64) -> Self {
+        self.should_optimize = self.deleted_docs_ratio > threshold;
+        self
+    }
+
+    /// Attach when the index was created and last changed to the summary
+    pub fn with_audit_times(mut self, created_at: Option<u64>, modified_at: Option<u64>) -> Self {
+        self.created_at = created_at;
+        self.modified_at = modified_at;
+        self
+    }
+
+    /// Attach the settings the index is running with to the summary
+    pub fn with_settings(mut self, settings: serde_json::Value) -> Self {
+        self.settings = Some(settings);
+        self
+    }
+}
+
+/// The terms of a field, gotten from an index's `_field_stats` route
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FieldStats {
+    /// The name of the field
+    pub field: String,
+    /// How many different terms the field holds
+    pub distinct_terms: u64,
+    /// The terms found in the most documents, most frequent first
+    pub top_terms: Vec<TermCount>,
+}
+
+/// A term of a field and how many documents it is found in. Numbers are written out in decimal and
+/// dates as seconds since the epoch.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TermCount {
+    /// The term
+    pub term: String,
+    /// How many documents hold the term, documents deleted since the last merge still count
+    pub doc_freq: u64,
+}
+
+/// A response gotten from the _bulk route for an index
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct BulkResponse {
+    /// The number of documents that failed to index and were sent to the dead letter sink
+    pub dead_letters: usize,
+    /// The number of commits made while loading the documents when `commit_every` was set
+    #[serde(default)]
+    pub commits: usize,
+    /// Whether any of the documents failed to index
+    #[serde(default)]
+    pub errors: bool,
+    /// What happened to each document, in the order they were sent
+    #[serde(default)]
+    pub items: Vec<BulkItem>,
+}
+
+/// What happened to a single document of a bulk insert
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BulkItem {
+    /// `201` for a document that was indexed, `202` for one left to be indexed in the background, `400`
+    /// for one that couldn't be parsed and `500` for one the writer stopped before taking
+    pub status: u16,
+    /// Why the document couldn't be indexed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A response gotten from the _stats route for an index, what its writer is busy with and how
+/// busy the index has been lately
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub struct WriterStats {
+    /// The number of merges of the index's segments that are running
+    pub active_merges: usize,
+    /// The number of segments of the index that aren't part of a running merge
+    pub pending_segments: usize,
+    /// The number of writes that are waiting on the next commit
+    pub queued_operations: usize,
+    /// How many searches and documents the index has taken a second lately
+    #[serde(default)]
+    pub rates: IndexRates,
+}
+
+/// The number of searches run on an index and documents added to it a second, averaged over the
+/// last minute and the last five minutes
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub struct IndexRates {
+    /// Searches a second over the last minute
+    pub queries_per_sec_1m: f64,
+    /// Searches a second over the last five minutes
+    pub queries_per_sec_5m: f64,
+    /// Documents added a second over the last minute
+    pub docs_per_sec_1m: f64,
+    /// Documents added a second over the last five minutes
+    pub docs_per_sec_5m: f64,
+}
+
+/// Whether an index is serving requests or was quarantined when it was opened
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexState {
+    /// The index opened cleanly and is serving requests
+    Open,
+    /// The index was corrupted and has been quarantined
+    Errored,
+}
+
+/// An index's entry in the response gotten from the _indexes route
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct IndexListing {
+    /// The name of the index
+    pub name: String,
+    /// Whether the index is serving requests
+    pub state: IndexState,
+    /// Why the index was quarantined, only set for errored indexes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// When the index was created, in milliseconds since the epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    /// When the index's schema or settings were last changed, in milliseconds since the epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_at: Option<u64>,
+}
+
+/// Where a background task is at
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskState {
+    /// The task is still running
+    Running,
+    /// The task finished successfully
+    Done,
+    /// The task stopped with an error
+    Failed,
+}
+
+/// A response gotten from the _tasks route, the status of a background task
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TaskStatus {
+    /// The id the task was started with
+    pub id: u64,
+    /// What the task does, such as `reindex`
+    pub kind: String,
+    /// Where the task is at
+    pub state: TaskState,
+    /// How much of its work the task has done, in percent
+    pub progress: f32,
+    /// Why the task failed, only set for failed tasks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A response gotten from the _flush route for an index
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct FlushResponse {
+    /// The opstamp of the commit, every operation up to and including it is durable
+    pub opstamp: u64,
+}
+
+/// A response gotten from the _refresh route, the number of documents searches of each refreshed
+/// index now see, keyed by index name
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RefreshResponse {
+    /// The searchable documents of every index refreshed
+    pub indexes: BTreeMap<String, u64>,
 }
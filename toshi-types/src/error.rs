@@ -4,6 +4,7 @@
 
 use std::fmt::Debug;
 
+use http::StatusCode;
 use serde::{Deserialize, Serialize};
 use tantivy::query::QueryParserError;
 use tantivy::schema::DocParsingError;
@@ -15,6 +16,10 @@ use thiserror::Error;
 pub struct ErrorResponse {
     /// The human-readable message given back
     pub message: String,
+    /// What kind of error this is, for clients to branch on. Only left out of errors that didn't
+    /// come from an [`Error`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
 }
 
 impl ErrorResponse {
@@ -22,6 +27,87 @@ impl ErrorResponse {
     pub fn new<M: ToString>(message: M) -> Self {
         Self {
             message: message.to_string(),
+            code: None,
+        }
+    }
+}
+
+impl From<Error> for ErrorResponse {
+    fn from(err: Error) -> Self {
+        Self {
+            code: Some(err.code()),
+            message: err.to_string(),
+        }
+    }
+}
+
+/// A stable, machine readable name for each kind of [`Error`], unlike the error's message it never
+/// changes between versions. Every code has one HTTP status errors of its kind are returned with.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// Reading or writing an index's files failed
+    IoError,
+    /// A field that isn't in the index's schema was used
+    UnknownField,
+    /// The index doesn't exist
+    IndexNotFound,
+    /// A query or request body could not be parsed or isn't valid
+    QueryParseError,
+    /// Something went wrong inside Toshi, this is a bug
+    InternalError,
+    /// A call to another node of the cluster failed
+    RpcError,
+    /// A versioned write expected a different version of the document
+    VersionConflict,
+    /// A write didn't reach enough replicas in time
+    PartialReplication,
+    /// The request was stopped before it finished
+    Cancelled,
+    /// A value can't be stored in its field's type
+    SchemaMismatch,
+    /// A search would use more memory than it is allowed to
+    CircuitBreaker,
+    /// A kind of query the index doesn't allow was used
+    ForbiddenQuery,
+    /// An index with the name already exists
+    IndexExists,
+    /// The index is read only
+    IndexReadOnly,
+    /// The index was corrupted and is kept out of service
+    IndexQuarantined,
+    /// A document could not be run through the index's ingest pipeline
+    ProcessorError,
+    /// A document isn't valid JSON or has a value that can't be read
+    InvalidDocument,
+    /// The index's writer is held by someone else and could not be taken
+    WriterTimeout,
+    /// The index's writer has fallen too far behind to take more writes for now
+    IngestionBreaker,
+    /// The request's body is larger than a body is allowed to be
+    PayloadTooLarge,
+}
+
+impl ErrorCode {
+    /// The HTTP status errors with this code are returned with
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::IndexNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::UnknownField
+            | ErrorCode::QueryParseError
+            | ErrorCode::Cancelled
+            | ErrorCode::SchemaMismatch
+            | ErrorCode::ForbiddenQuery
+            | ErrorCode::ProcessorError
+            | ErrorCode::InvalidDocument => StatusCode::BAD_REQUEST,
+            ErrorCode::VersionConflict | ErrorCode::IndexExists => StatusCode::CONFLICT,
+            ErrorCode::IndexReadOnly => StatusCode::LOCKED,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::CircuitBreaker => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::PartialReplication => StatusCode::GATEWAY_TIMEOUT,
+            ErrorCode::RpcError => StatusCode::BAD_GATEWAY,
+            ErrorCode::IndexQuarantined | ErrorCode::WriterTimeout | ErrorCode::IngestionBreaker => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::IoError | ErrorCode::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
@@ -53,6 +139,121 @@ pub enum Error {
     /// An error occured in Toshi's internal RPC communications
     #[error("An RPC error occurred: '{0}'")]
     RPCError(String),
+    /// A versioned write expected a different version of the document than the one stored
+    #[error("Version conflict: expected version {expected} but found {found}")]
+    VersionConflict {
+        /// The version the write was conditioned on
+        expected: u64,
+        /// The version currently stored for the document
+        found: u64,
+    },
+    /// A write was applied on the primary but not enough replicas confirmed it before timing out
+    #[error("Write only reached {acknowledged} of the {required} required replicas")]
+    PartialReplication {
+        /// The number of replicas that confirmed the write
+        acknowledged: usize,
+        /// The number of replicas the write's consistency level needed
+        required: usize,
+    },
+    /// Whoever asked for the work went away before it finished, so it was stopped early
+    #[error("The request was cancelled before it finished")]
+    Cancelled,
+    /// A document had a value that can't be stored in its field's type
+    #[error("Field '{field}' expects a {expected} value but got {found}")]
+    SchemaMismatch {
+        /// The field the value was for
+        field: String,
+        /// The type of the field in the index's schema
+        expected: String,
+        /// The value that could not be parsed
+        found: String,
+    },
+    /// A search was estimated to need more memory than a single search is allowed to use
+    #[error("Search would use an estimated {estimated} bytes, more than the budget of {budget} bytes")]
+    CircuitBreaker {
+        /// The memory the search was estimated to need
+        estimated: u64,
+        /// The memory a single search is allowed to use
+        budget: u64,
+    },
+    /// A search used a kind of query its index is configured not to allow
+    #[error("Query type '{kind}' is not allowed on index '{index}'")]
+    ForbiddenQuery {
+        /// The kind of query that isn't allowed
+        kind: String,
+        /// The index the search was sent to
+        index: String,
+    },
+    /// An index was to be created under a name another index already has
+    #[error("Index '{0}' already exists")]
+    IndexExists(String),
+    /// A write was sent to an index that has been made read only
+    #[error("Index '{0}' is read only")]
+    ReadOnly(String),
+    /// An index was found to be corrupted when it was opened and is kept out of service
+    #[error("Index '{index}' is quarantined: {reason}")]
+    Quarantined {
+        /// The corrupted index
+        index: String,
+        /// What was wrong with the index when it was opened
+        reason: String,
+    },
+    /// A document could not be run through an index's ingest pipeline
+    #[error("Processor '{processor}' failed: {reason}")]
+    ProcessorError {
+        /// The processor that failed
+        processor: String,
+        /// Why the processor could not be applied to the document
+        reason: String,
+    },
+    /// A document was not valid JSON or had a value that could not be read
+    #[error("Invalid document: {0}")]
+    InvalidDocument(String),
+    /// The index's writer could not be taken because another writer holds it
+    #[error("Index writer is busy: {0}")]
+    WriterTimeout(String),
+    /// A write was sent to an index whose writer has more writes waiting on a commit than it is
+    /// allowed to fall behind by
+    #[error("Index '{index}' has {queued} writes waiting on a commit, writes resume once it has at most {resume_at}")]
+    IngestionBreaker {
+        /// The index that is behind
+        index: String,
+        /// The writes waiting on the index's next commit
+        queued: usize,
+        /// How few writes have to be waiting before the index takes writes again
+        resume_at: usize,
+    },
+    /// A request's body, once decompressed, was larger than the most a body is allowed to be
+    #[error("Request body is larger than the limit of {0} bytes")]
+    PayloadTooLarge(u64),
+}
+
+impl Error {
+    /// The code clients can tell this kind of error apart by, see [`ErrorCode`]
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::IOError(_) => ErrorCode::IoError,
+            Error::UnknownIndexField(_) => ErrorCode::UnknownField,
+            Error::UnknownIndex(_) => ErrorCode::IndexNotFound,
+            Error::QueryError(_) => ErrorCode::QueryParseError,
+            Error::SpawnError | Error::UnknownError | Error::PoisonedError => ErrorCode::InternalError,
+            Error::RPCError(_) => ErrorCode::RpcError,
+            Error::VersionConflict { .. } => ErrorCode::VersionConflict,
+            Error::PartialReplication { .. } => ErrorCode::PartialReplication,
+            Error::Cancelled => ErrorCode::Cancelled,
+            Error::SchemaMismatch { .. } => ErrorCode::SchemaMismatch,
+            Error::CircuitBreaker { .. } => ErrorCode::CircuitBreaker,
+            Error::ForbiddenQuery { .. } => ErrorCode::ForbiddenQuery,
+            Error::IndexExists(_) => ErrorCode::IndexExists,
+            Error::ReadOnly(_) => ErrorCode::IndexReadOnly,
+            Error::Quarantined { .. } => ErrorCode::IndexQuarantined,
+            Error::ProcessorError { .. } => ErrorCode::ProcessorError,
+            Error::InvalidDocument(_) => ErrorCode::InvalidDocument,
+            Error::WriterTimeout(_) => ErrorCode::WriterTimeout,
+            Error::IngestionBreaker { .. } => ErrorCode::IngestionBreaker,
+            Error::PayloadTooLarge(_) => ErrorCode::PayloadTooLarge,
+        }
+    }
 }
 
 impl From<QueryParserError> for Error {
@@ -79,10 +280,10 @@ impl From<QueryParserError> for Error {
 impl From<DocParsingError> for Error {
     fn from(err: DocParsingError) -> Self {
         match err {
-            DocParsingError::NotJSON(e) => Error::IOError(format!("Document: '{}' is not valid JSON", e)),
+            DocParsingError::NotJSON(e) => Error::InvalidDocument(format!("'{}' is not valid JSON", e)),
             DocParsingError::NoSuchFieldInSchema(e) => Error::UnknownIndexField(e),
             DocParsingError::ValueError(e, r) => {
-                Error::IOError(format!("A value in the JSON '{}' could not be parsed, reason: {:?}", e, r))
+                Error::InvalidDocument(format!("A value in the JSON '{}' could not be parsed, reason: {:?}", e, r))
             }
         }
     }
@@ -113,7 +314,7 @@ impl From<TantivyError> for Error {
             TantivyError::PathDoesNotExist(e) => Error::IOError(format!("Path does not exist: {:?}", e)),
             TantivyError::FileAlreadyExists(e) => Error::IOError(format!("File already exists: {:?}", e)),
             TantivyError::IndexAlreadyExists => Error::IOError(e.to_string()),
-            TantivyError::LockFailure(e, _) => Error::IOError(e.to_string()),
+            TantivyError::LockFailure(e, _) => Error::WriterTimeout(e.to_string()),
             TantivyError::Poisoned => Error::PoisonedError,
             TantivyError::InvalidArgument(e) => Error::IOError(e),
             TantivyError::ErrorInThread(e) => Error::IOError(e),
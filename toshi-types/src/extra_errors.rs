@@ -1,11 +1,16 @@
 use crate::error::{Error, ErrorResponse};
 use tonic::{Code, Status};
 
+/// The status of the response is the one of the error's [`crate::ErrorCode`]
 impl From<Error> for http::Response<hyper::Body> {
     fn from(err: Error) -> Self {
-        let body = ErrorResponse::new(err);
-        let bytes = serde_json::to_vec(&body).unwrap();
-        http::Response::new(hyper::Body::from(bytes))
+        let status = err.code().status();
+        let bytes = serde_json::to_vec(&ErrorResponse::from(err)).unwrap();
+        http::Response::builder()
+            .status(status)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(hyper::Body::from(bytes))
+            .unwrap()
     }
 }
 
@@ -26,3 +31,86 @@ impl From<tonic::transport::Error> for Error {
         Error::RPCError(e.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+
+    use super::*;
+
+    #[test]
+    fn test_error_statuses() {
+        let cases = vec![
+            (Error::IOError("disk full".into()), StatusCode::INTERNAL_SERVER_ERROR),
+            (Error::UnknownIndexField("nope".into()), StatusCode::BAD_REQUEST),
+            (Error::UnknownIndex("nope".into()), StatusCode::NOT_FOUND),
+            (Error::QueryError("bad".into()), StatusCode::BAD_REQUEST),
+            (Error::SpawnError, StatusCode::INTERNAL_SERVER_ERROR),
+            (Error::UnknownError, StatusCode::INTERNAL_SERVER_ERROR),
+            (Error::PoisonedError, StatusCode::INTERNAL_SERVER_ERROR),
+            (Error::RPCError("down".into()), StatusCode::BAD_GATEWAY),
+            (Error::VersionConflict { expected: 1, found: 2 }, StatusCode::CONFLICT),
+            (
+                Error::PartialReplication {
+                    acknowledged: 1,
+                    required: 2,
+                },
+                StatusCode::GATEWAY_TIMEOUT,
+            ),
+            (Error::Cancelled, StatusCode::BAD_REQUEST),
+            (
+                Error::SchemaMismatch {
+                    field: "f".into(),
+                    expected: "i64".into(),
+                    found: "\"a\"".into(),
+                },
+                StatusCode::BAD_REQUEST,
+            ),
+            (Error::CircuitBreaker { estimated: 2, budget: 1 }, StatusCode::TOO_MANY_REQUESTS),
+            (
+                Error::ForbiddenQuery {
+                    kind: "regex".into(),
+                    index: "i".into(),
+                },
+                StatusCode::BAD_REQUEST,
+            ),
+            (Error::IndexExists("i".into()), StatusCode::CONFLICT),
+            (Error::ReadOnly("i".into()), StatusCode::LOCKED),
+            (
+                Error::Quarantined {
+                    index: "i".into(),
+                    reason: "corrupt".into(),
+                },
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (
+                Error::ProcessorError {
+                    processor: "p".into(),
+                    reason: "r".into(),
+                },
+                StatusCode::BAD_REQUEST,
+            ),
+            (Error::InvalidDocument("not json".into()), StatusCode::BAD_REQUEST),
+            (Error::WriterTimeout("locked".into()), StatusCode::SERVICE_UNAVAILABLE),
+            (
+                Error::IngestionBreaker {
+                    index: "i".into(),
+                    queued: 10,
+                    resume_at: 5,
+                },
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (Error::PayloadTooLarge(1024), StatusCode::PAYLOAD_TOO_LARGE),
+        ];
+        for (err, status) in cases {
+            let name = format!("{:?}", err);
+            assert_eq!(http::Response::<hyper::Body>::from(err).status(), status, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_doc_parsing_errors() {
+        let err = Error::from(tantivy::schema::DocParsingError::NotJSON("{".into()));
+        assert_eq!(err.code(), crate::ErrorCode::InvalidDocument);
+    }
+}
@@ -6,13 +6,22 @@
 //! looking for Toshi's protobuf types then you will want to look in the toshi-proto module
 //! of Toshi's source code.
 
-pub use client::{ScoredDoc, SearchResults, SummaryResponse};
-pub use error::{Error, ErrorResponse};
+pub use client::{
+    AggregationResult, BulkItem, BulkResponse, DocRef, FieldStats, FlushResponse, Highlight, HistogramBucket, IndexListing, IndexRates,
+    IndexState, PercentileValue, RangeBucket, RefreshResponse, ScoreNormalization, ScoredDoc, SearchProfile, SearchResults, ShardResult,
+    ShardsInfo, SortValue, SummaryResponse, TaskState, TaskStatus, TermCount, TermsBucket, WriterStats,
+};
+pub use error::{Error, ErrorCode, ErrorResponse};
 pub use query::{
-    boolean::BoolQuery, facet::FacetQuery, fuzzy::FuzzyQuery, fuzzy::FuzzyTerm, phrase::PhraseQuery, phrase::TermPair, range::RangeQuery,
-    range::Ranges, regex::RegexQuery, term::ExactTerm, CreateQuery, KeyValue, Query, Search,
+    aggregation::Aggregation, aggregation::Average, aggregation::DateHistogram, aggregation::DistinctCount, aggregation::NumericRange,
+    aggregation::Percentiles, aggregation::RangeAggregation, aggregation::TermsAggregation, boolean::BoolQuery, decay::Decay,
+    decay::DecayFunction, facet::FacetQuery, fuzzy::FuzzyQuery, fuzzy::FuzzyTerm, join::HasChildQuery, join::HasParentQuery,
+    match_all::MatchAllQuery, phrase::PhraseQuery, phrase::TermPair, phrase_prefix::PhrasePrefix, phrase_prefix::PhrasePrefixQuery,
+    range::RangeQuery, range::Ranges, regex::RegexQuery, term::ExactTerm, terms::TermsQuery, CreateQuery, DefaultOperator, KeyValue, Query,
+    Search,
 };
 pub use server::*;
+pub use sketch::{HyperLogLog, TDigest};
 
 type Result<T> = std::result::Result<T, error::Error>;
 
@@ -28,6 +37,9 @@ mod query;
 /// Types related to the POST bodies that Toshi accepts for requests
 mod server;
 
+/// Summaries of numbers that aggregations keep, which the results of several searches are merged by
+mod sketch;
+
 /// Extra error conversions Toshi uses, if users want they can omit this feature to not pull in
 /// hyper and tonic dependencies
 #[cfg(feature = "extra-errors")]
@@ -0,0 +1,678 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use tantivy::chrono::{FixedOffset, LocalResult, NaiveDateTime, TimeZone};
+
+use crate::client::{AggregationResult, HistogramBucket, RangeBucket, TermsBucket};
+use crate::error::Error;
+use crate::Result;
+
+/// The most buckets a date histogram returns, so a tiny interval over a long span of time can't
+/// build an enormous response
+pub const MAX_BUCKETS: usize = 10_000;
+
+/// An aggregation computed over every document a search matches
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregation {
+    /// Count the matching documents in each fixed interval of time, see [`DateHistogram`]
+    DateHistogram(DateHistogram),
+    /// Estimate percentiles of the values of a numeric field, see [`Percentiles`]
+    Percentiles(Percentiles),
+    /// Estimate how many distinct values a field holds, see [`DistinctCount`]
+    Cardinality(DistinctCount),
+    /// The mean of a field's values, see [`Average`]
+    Avg(Average),
+    /// Count the matching documents in each range of a numeric field's values, see [`RangeAggregation`]
+    Range(RangeAggregation),
+    /// Count the matching documents holding each term of a text field, see [`TermsAggregation`]
+    Terms(TermsAggregation),
+}
+
+impl Aggregation {
+    /// Whether the aggregation computes values from the documents rather than sorting them into
+    /// buckets, only these can be nested in a bucket aggregation
+    pub fn is_metric(&self) -> bool {
+        match self {
+            Aggregation::DateHistogram(_) | Aggregation::Range(_) | Aggregation::Terms(_) => false,
+            Aggregation::Percentiles(_) | Aggregation::Cardinality(_) | Aggregation::Avg(_) => true,
+        }
+    }
+
+    /// The field the aggregation is computed over
+    pub fn field(&self) -> &str {
+        match self {
+            Aggregation::DateHistogram(h) => &h.field,
+            Aggregation::Percentiles(p) => &p.field,
+            Aggregation::Cardinality(c) => &c.field,
+            Aggregation::Avg(a) => &a.field,
+            Aggregation::Range(r) => &r.field,
+            Aggregation::Terms(t) => &t.field,
+        }
+    }
+}
+
+/// The mean of the values of a single valued u64, i64, f64 or date fast field in the matching documents
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Average {
+    /// The field the mean is of
+    pub field: String,
+}
+
+/// Estimates how many distinct values a single valued u64, i64, f64 or date fast field holds in the
+/// matching documents, like the number of users that made requests. Counts stay exact until there
+/// are too many values to keep track of each one, and are estimated from a HyperLogLog after that.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DistinctCount {
+    /// The field whose distinct values are counted
+    pub field: String,
+    /// How many bits of each value's hash pick the HyperLogLog register it's counted in. Each
+    /// one more doubles the memory used, 2 to the power of `precision` bytes, and makes the
+    /// estimate more accurate, its standard error is 1.04 over the square root of that memory.
+    #[serde(default = "DistinctCount::default_precision")]
+    pub precision: u8,
+}
+
+impl DistinctCount {
+    /// The fewest bits of precision a count can ask for
+    pub const MIN_PRECISION: u8 = 4;
+    /// The most bits of precision a count can ask for
+    pub const MAX_PRECISION: u8 = 18;
+
+    /// Constructor for a count of `field` with the default precision
+    pub fn new(field: String) -> Self {
+        Self {
+            field,
+            precision: Self::default_precision(),
+        }
+    }
+
+    fn default_precision() -> u8 {
+        14
+    }
+
+    /// Check the precision asked for is one the count can be made with
+    pub fn validate(&self) -> Result<()> {
+        if self.precision < Self::MIN_PRECISION || self.precision > Self::MAX_PRECISION {
+            return Err(Error::QueryError(format!(
+                "Precision {} is not between {} and {}",
+                self.precision,
+                Self::MIN_PRECISION,
+                Self::MAX_PRECISION
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Estimates the values of a field below which given percentages of the matching documents'
+/// values fall, like the median or 95th percentile of response times. The field must be a single
+/// valued u64, i64 or f64 fast field.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Percentiles {
+    /// The field the percentiles are of
+    pub field: String,
+    /// The percentiles to return, each between 0 and 100
+    #[serde(default = "Percentiles::default_percents")]
+    pub percents: Vec<f64>,
+}
+
+impl Percentiles {
+    /// Constructor for the default percentiles of `field`
+    pub fn new(field: String) -> Self {
+        Self {
+            field,
+            percents: Self::default_percents(),
+        }
+    }
+
+    fn default_percents() -> Vec<f64> {
+        vec![1.0, 5.0, 25.0, 50.0, 75.0, 95.0, 99.0]
+    }
+
+    /// Check the percentiles asked for are ones that can be computed
+    pub fn validate(&self) -> Result<()> {
+        if self.percents.is_empty() {
+            return Err(Error::QueryError(format!("No percentiles of {} were asked for", self.field)));
+        }
+        match self.percents.iter().find(|p| !(0.0..=100.0).contains(*p)) {
+            Some(p) => Err(Error::QueryError(format!("Percentile {} is not between 0 and 100", p))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Counts documents by the range a single valued u64, i64 or f64 fast field's value falls in, like
+/// price bands. The ranges are either listed in `ranges` or are every `interval` wide, starting at
+/// multiples of it. A range includes its `from` and excludes its `to`, so a value on the boundary
+/// of two neighbouring ranges is only counted in the upper one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RangeAggregation {
+    /// The field the documents are bucketed by
+    pub field: String,
+    /// How wide each range is, for ranges of the same width
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<f64>,
+    /// The ranges to count documents in, returned in the order they're listed. Ranges can overlap,
+    /// a document is counted in every one its value falls in.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ranges: Vec<NumericRange>,
+}
+
+/// One range of a [`RangeAggregation`], from its `from` up to but not including its `to`. A range
+/// without a `from` or `to` has no lower or upper bound.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct NumericRange {
+    /// The lowest value in the range
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<f64>,
+    /// The value the range ends before
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<f64>,
+}
+
+impl NumericRange {
+    /// Whether `value` is in the range
+    pub fn contains(&self, value: f64) -> bool {
+        self.from.map_or(true, |from| value >= from) && self.to.map_or(true, |to| value < to)
+    }
+}
+
+impl RangeAggregation {
+    /// Check the aggregation asks for either an interval or ranges, and that they can hold values
+    pub fn validate(&self) -> Result<()> {
+        match (self.interval, self.ranges.is_empty()) {
+            (Some(_), false) | (None, true) => Err(Error::QueryError(format!(
+                "A range aggregation on {} takes either an interval or ranges",
+                self.field
+            ))),
+            (Some(interval), true) if !(interval.is_finite() && interval > 0.0) => {
+                Err(Error::QueryError(format!("Interval {} is not a positive number", interval)))
+            }
+            _ => match self.ranges.iter().find(|r| holds_nothing(r)) {
+                Some(r) => Err(Error::QueryError(format!(
+                    "Range from {:?} to {:?} of {} can't hold any values",
+                    r.from, r.to, self.field
+                ))),
+                None => Ok(()),
+            },
+        }
+    }
+
+    /// Write the keys of the buckets `value` is counted in to `keys`, the index of every listed
+    /// range it falls in or the number of intervals it's above 0. `keys` is cleared first, so one
+    /// buffer can be reused for every value.
+    pub fn keys(&self, value: f64, keys: &mut Vec<i64>) {
+        keys.clear();
+        if value.is_nan() {
+            return;
+        }
+        match self.interval {
+            Some(interval) => keys.push((value / interval).floor() as i64),
+            None => keys.extend((0..self.ranges.len() as i64).filter(|i| self.ranges[*i as usize].contains(value))),
+        }
+    }
+
+    /// Check `count` buckets are within [`MAX_BUCKETS`], listed ranges always are
+    pub fn check_bucket_count(&self, count: usize) -> Result<()> {
+        if self.interval.is_some() && count > MAX_BUCKETS {
+            return Err(Error::QueryError(format!(
+                "Range aggregation on {} would return more than the limit of {} buckets",
+                self.field, MAX_BUCKETS
+            )));
+        }
+        Ok(())
+    }
+
+    /// The buckets of the aggregation from the number of documents counted under each key. Every
+    /// listed range is returned, and with an interval only the ranges that hold documents are, up
+    /// to [`MAX_BUCKETS`] of them.
+    pub fn buckets(&self, counts: &BTreeMap<i64, u64>) -> Result<Vec<RangeBucket>> {
+        self.check_bucket_count(counts.len())?;
+        let buckets = match self.interval {
+            Some(interval) => counts
+                .iter()
+                .map(|(key, doc_count)| RangeBucket {
+                    from: Some(*key as f64 * interval),
+                    to: Some((key + 1) as f64 * interval),
+                    doc_count: *doc_count,
+                })
+                .collect(),
+            None => self
+                .ranges
+                .iter()
+                .enumerate()
+                .map(|(i, range)| RangeBucket {
+                    from: range.from,
+                    to: range.to,
+                    doc_count: counts.get(&(i as i64)).copied().unwrap_or(0),
+                })
+                .collect(),
+        };
+        Ok(buckets)
+    }
+}
+
+/// Counts documents by the terms an indexed text field holds, like the number of products in each
+/// category, and returns the terms the most documents hold. A document is counted under every term
+/// of the field it holds.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TermsAggregation {
+    /// The field whose terms the documents are bucketed by
+    pub field: String,
+    /// How many terms are returned, those held by the most documents
+    #[serde(default = "TermsAggregation::default_size")]
+    pub size: usize,
+    /// Metric aggregations computed over the documents holding each term, by the name their
+    /// results are returned under in the bucket
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub aggs: BTreeMap<String, Aggregation>,
+}
+
+impl TermsAggregation {
+    /// Constructor for the 10 terms of `field` held by the most documents
+    pub fn new(field: String) -> Self {
+        Self {
+            field,
+            size: Self::default_size(),
+            aggs: BTreeMap::new(),
+        }
+    }
+
+    fn default_size() -> usize {
+        10
+    }
+
+    /// Check the number of terms asked for is between 1 and [`MAX_BUCKETS`]
+    pub fn validate(&self) -> Result<()> {
+        if self.size == 0 || self.size > MAX_BUCKETS {
+            return Err(Error::QueryError(format!(
+                "Terms aggregation on {} asks for {} terms, it can return between 1 and {}",
+                self.field, self.size, MAX_BUCKETS
+            )));
+        }
+        Ok(())
+    }
+
+    /// The `size` buckets holding the most documents, ties going to the lowest term
+    pub fn top(&self, mut buckets: Vec<TermsBucket>) -> Vec<TermsBucket> {
+        TermsBucket::sort(&mut buckets);
+        buckets.truncate(self.size);
+        buckets
+    }
+}
+
+/// Whether a range is one no value can be in, because it ends before it starts or a bound isn't a number
+fn holds_nothing(range: &NumericRange) -> bool {
+    let nan = range.from.map_or(false, f64::is_nan) || range.to.map_or(false, f64::is_nan);
+    match (range.from, range.to) {
+        (Some(from), Some(to)) => nan || from >= to,
+        _ => nan,
+    }
+}
+
+/// The number of seconds in a length of time written as a number followed by `s`, `m`, `h` or `d`
+/// such as `1h`, `what` names the value in the error when it isn't one
+pub(crate) fn duration_secs(duration: &str, what: &str) -> Result<i64> {
+    let invalid = || {
+        Error::QueryError(format!(
+            "Invalid {} '{}', expected a number followed by s, m, h or d",
+            what, duration
+        ))
+    };
+    let split = duration.char_indices().last().map_or(0, |(i, _)| i);
+    let (count, unit) = (&duration[..split], &duration[split..]);
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    match count.parse::<i64>() {
+        Ok(count) if count > 0 => count.checked_mul(unit).ok_or_else(invalid),
+        _ => Err(invalid()),
+    }
+}
+
+/// Counts documents by the interval of time a field falls in, like the number of events per hour.
+/// The field must be a single valued date fast field, or an i64 fast field of seconds since the epoch.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DateHistogram {
+    /// The field the documents are bucketed by
+    pub field: String,
+    /// How long each bucket is, a number followed by `s`, `m`, `h` or `d` such as `1h`
+    pub interval: String,
+    /// Buckets with fewer documents than this are left out, with 0 every bucket between the
+    /// first and last document is returned
+    #[serde(default = "DateHistogram::default_min_doc_count")]
+    pub min_doc_count: u64,
+    /// The time zone buckets start in, `UTC` or a fixed offset from it like `+02:00`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_zone: Option<String>,
+    /// Metric aggregations computed over the documents of each bucket, by the name their results
+    /// are returned under in the bucket
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub aggs: BTreeMap<String, Aggregation>,
+}
+
+impl DateHistogram {
+    /// Constructor for a histogram of `field` in UTC that leaves out empty buckets
+    pub fn new(field: String, interval: String) -> Self {
+        Self {
+            field,
+            interval,
+            min_doc_count: Self::default_min_doc_count(),
+            time_zone: None,
+            aggs: BTreeMap::new(),
+        }
+    }
+
+    fn default_min_doc_count() -> u64 {
+        1
+    }
+
+    /// The length of a bucket in seconds
+    pub fn interval_secs(&self) -> Result<i64> {
+        duration_secs(&self.interval, "interval")
+    }
+
+    /// How far ahead of UTC the histogram's time zone is, in seconds
+    pub fn offset_secs(&self) -> Result<i32> {
+        let zone = match self.time_zone.as_deref() {
+            None | Some("UTC") | Some("Z") => return Ok(0),
+            Some(zone) => zone,
+        };
+        let invalid = || Error::QueryError(format!("Invalid time zone '{}', expected UTC or an offset like +02:00", zone));
+        let (sign, offset) = match zone.chars().next() {
+            Some('+') => (1, &zone[1..]),
+            Some('-') => (-1, &zone[1..]),
+            _ => return Err(invalid()),
+        };
+        let mut parts = offset.splitn(2, ':');
+        let hours: i32 = parts.next().and_then(|h| h.parse().ok()).ok_or_else(invalid)?;
+        let minutes: i32 = match parts.next() {
+            Some(m) => m.parse().map_err(|_| invalid())?,
+            None => 0,
+        };
+        if !offset.chars().next().map_or(false, |c| c.is_ascii_digit()) || hours > 23 || !(0..60).contains(&minutes) {
+            return Err(invalid());
+        }
+        Ok(sign * (hours * 60 * 60 + minutes * 60))
+    }
+
+    /// The start of the bucket `timestamp` falls in, both in seconds since the epoch. Buckets are
+    /// aligned to midnight in the time zone `offset` seconds ahead of UTC, a timestamp too close to
+    /// the limits of an i64 for its bucket to start within them is an error.
+    pub fn bucket_start(timestamp: i64, interval: i64, offset: i32) -> Result<i64> {
+        timestamp
+            .checked_add(i64::from(offset))
+            .and_then(|local| local.checked_sub(local.rem_euclid(interval)))
+            .and_then(|start| start.checked_sub(i64::from(offset)))
+            .ok_or_else(|| Error::QueryError(format!("Timestamp {} is out of range for a date histogram", timestamp)))
+    }
+
+    /// A bucket's key written as a date in the time zone `zone`
+    fn key_as_string(zone: FixedOffset, key: i64) -> Result<String> {
+        let local = key.checked_add(i64::from(zone.local_minus_utc()));
+        match (
+            local.and_then(|l| NaiveDateTime::from_timestamp_opt(l, 0)),
+            zone.timestamp_opt(key, 0),
+        ) {
+            (Some(_), LocalResult::Single(date)) => Ok(date.to_rfc3339()),
+            _ => Err(Error::QueryError(format!("Timestamp {} is out of range for a date histogram", key))),
+        }
+    }
+
+    /// The buckets of the histogram from the number of documents counted in each, keyed by the
+    /// start of the bucket, with the results of the nested aggregations `nested` gives for the
+    /// start of each bucket returned
+    pub fn buckets<F>(&self, counts: &BTreeMap<i64, u64>, mut nested: F) -> Result<Vec<HistogramBucket>>
+    where
+        F: FnMut(i64) -> BTreeMap<String, AggregationResult>,
+    {
+        let interval = self.interval_secs()?;
+        let zone = FixedOffset::east(self.offset_secs()?);
+        let keys: Vec<i64> = match (counts.keys().next(), counts.keys().next_back()) {
+            (Some(first), Some(last)) if self.min_doc_count == 0 => {
+                let buckets = last.checked_sub(*first).map_or(i64::max_value(), |span| span / interval + 1);
+                if buckets > MAX_BUCKETS as i64 {
+                    return Err(Error::QueryError(format!(
+                        "Date histogram on {} would return {} buckets, more than the limit of {}",
+                        self.field, buckets, MAX_BUCKETS
+                    )));
+                }
+                (0..buckets).map(|i| first + i * interval).collect()
+            }
+            _ => counts.keys().cloned().collect(),
+        };
+        keys.into_iter()
+            .map(|key| (key, counts.get(&key).cloned().unwrap_or(0)))
+            .filter(|(_, doc_count)| *doc_count >= self.min_doc_count)
+            .map(|(key, doc_count)| {
+                Ok(HistogramBucket {
+                    key,
+                    key_as_string: Self::key_as_string(zone, key)?,
+                    doc_count,
+                    aggregations: nested(key),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn histogram(interval: &str, time_zone: Option<&str>) -> DateHistogram {
+        DateHistogram {
+            time_zone: time_zone.map(String::from),
+            ..DateHistogram::new("time".into(), interval.into())
+        }
+    }
+
+    #[test]
+    fn test_parse_histogram() {
+        let parsed: Aggregation =
+            serde_json::from_str(r#"{ "date_histogram": { "field": "time", "interval": "1h", "time_zone": "-05:30" } }"#).unwrap();
+        assert_eq!(parsed, Aggregation::DateHistogram(histogram("1h", Some("-05:30"))));
+
+        assert_eq!(histogram("90s", None).interval_secs().unwrap(), 90);
+        assert_eq!(histogram("1d", None).interval_secs().unwrap(), 86_400);
+        for bad in &["", "h", "0h", "-1h", "1w", "1.5h"] {
+            assert!(histogram(bad, None).interval_secs().is_err(), "{}", bad);
+        }
+        assert_eq!(histogram("1h", Some("UTC")).offset_secs().unwrap(), 0);
+        assert_eq!(histogram("1h", Some("+02:00")).offset_secs().unwrap(), 7200);
+        assert_eq!(histogram("1h", Some("-05:30")).offset_secs().unwrap(), -19_800);
+        for bad in &["EST", "+24:00", "+01:60", "02:00", "+-1"] {
+            assert!(histogram("1h", Some(bad)).offset_secs().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_percentiles() {
+        let parsed: Aggregation = serde_json::from_str(r#"{ "percentiles": { "field": "took" } }"#).unwrap();
+        assert_eq!(parsed, Aggregation::Percentiles(Percentiles::new("took".into())));
+        let parsed: Percentiles = serde_json::from_str(r#"{ "field": "took", "percents": [50, 99.9] }"#).unwrap();
+        assert_eq!(parsed.percents, vec![50.0, 99.9]);
+        assert!(parsed.validate().is_ok());
+
+        for bad in &[vec![], vec![50.0, 101.0], vec![-1.0], vec![std::f64::NAN]] {
+            let percentiles = Percentiles {
+                percents: bad.clone(),
+                ..Percentiles::new("took".into())
+            };
+            assert!(percentiles.validate().is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_cardinality() {
+        let parsed: Aggregation = serde_json::from_str(r#"{ "cardinality": { "field": "user" } }"#).unwrap();
+        assert_eq!(parsed, Aggregation::Cardinality(DistinctCount::new("user".into())));
+        for (precision, valid) in &[(3, false), (4, true), (18, true), (19, false)] {
+            let count = DistinctCount {
+                precision: *precision,
+                ..DistinctCount::new("user".into())
+            };
+            assert_eq!(count.validate().is_ok(), *valid, "{}", precision);
+        }
+    }
+
+    #[test]
+    fn test_parse_nested() {
+        let parsed: Aggregation = serde_json::from_str(
+            r#"{ "date_histogram": { "field": "time", "interval": "1d", "aggs": { "price": { "avg": { "field": "price" } } } } }"#,
+        )
+        .unwrap();
+        let mut daily = histogram("1d", None);
+        daily
+            .aggs
+            .insert("price".into(), Aggregation::Avg(Average { field: "price".into() }));
+        assert_eq!(parsed, Aggregation::DateHistogram(daily));
+        assert!(!parsed.is_metric());
+        assert!(Aggregation::Avg(Average { field: "price".into() }).is_metric());
+    }
+
+    #[test]
+    fn test_parse_terms() {
+        let parsed: Aggregation = serde_json::from_str(
+            r#"{ "terms": { "field": "category", "size": 2, "aggs": { "price": { "avg": { "field": "price" } } } } }"#,
+        )
+        .unwrap();
+        let categories = match parsed {
+            Aggregation::Terms(t) => t,
+            other => panic!("Parsed as {:?}", other),
+        };
+        assert!(categories.validate().is_ok());
+        assert_eq!(categories.aggs["price"], Aggregation::Avg(Average { field: "price".into() }));
+        for size in &[0, MAX_BUCKETS + 1] {
+            let terms = TermsAggregation {
+                size: *size,
+                ..TermsAggregation::new("category".into())
+            };
+            assert!(terms.validate().is_err(), "{}", size);
+        }
+
+        let bucket = |key: &str, doc_count| TermsBucket {
+            key: key.into(),
+            doc_count,
+            aggregations: BTreeMap::new(),
+        };
+        let top = categories.top(vec![bucket("toys", 1), bucket("food", 3), bucket("books", 3)]);
+        assert_eq!(top, vec![bucket("books", 3), bucket("food", 3)]);
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        let parsed: Aggregation = serde_json::from_str(
+            r#"{ "range": { "field": "price", "ranges": [{ "to": 10 }, { "from": 10, "to": 50 }, { "from": 50 }] } }"#,
+        )
+        .unwrap();
+        let prices = match parsed {
+            Aggregation::Range(r) => r,
+            other => panic!("Parsed as {:?}", other),
+        };
+        assert!(prices.validate().is_ok());
+        let keys = |ranges: &RangeAggregation, value| {
+            let mut keys = vec![7];
+            ranges.keys(value, &mut keys);
+            keys
+        };
+        assert_eq!(keys(&prices, 10.0), vec![1]);
+        assert_eq!(keys(&prices, 9.99), vec![0]);
+        assert_eq!(keys(&prices, 50.0), vec![2]);
+        assert!(keys(&prices, std::f64::NAN).is_empty());
+        assert!(prices.check_bucket_count(MAX_BUCKETS + 1).is_ok());
+
+        let counts: BTreeMap<i64, u64> = vec![(0, 3), (2, 1)].into_iter().collect();
+        let buckets = prices.buckets(&counts).unwrap();
+        assert_eq!(buckets.iter().map(|b| b.doc_count).collect::<Vec<_>>(), vec![3, 0, 1]);
+        assert_eq!((buckets[1].from, buckets[1].to), (Some(10.0), Some(50.0)));
+
+        let fixed: RangeAggregation = serde_json::from_str(r#"{ "field": "price", "interval": 25 }"#).unwrap();
+        assert!(fixed.validate().is_ok());
+        assert_eq!(keys(&fixed, -0.5), vec![-1]);
+        assert_eq!(keys(&fixed, 25.0), vec![1]);
+        assert!(fixed.check_bucket_count(MAX_BUCKETS + 1).is_err());
+        let buckets = fixed.buckets(&vec![(-1, 2), (1, 4)].into_iter().collect()).unwrap();
+        assert_eq!(
+            buckets.iter().map(|b| (b.from, b.to, b.doc_count)).collect::<Vec<_>>(),
+            vec![(Some(-25.0), Some(0.0), 2), (Some(25.0), Some(50.0), 4)]
+        );
+        // Ranges only one of two searches had are merged in order of where they start
+        let other = fixed.buckets(&vec![(0, 1), (1, 1)].into_iter().collect()).unwrap();
+        let merged = match (AggregationResult::Ranges { ranges: buckets }).merge(AggregationResult::Ranges { ranges: other }) {
+            AggregationResult::Ranges { ranges } => ranges,
+            other => panic!("Merged into {:?}", other),
+        };
+        assert_eq!(
+            merged.iter().map(|b| (b.from, b.doc_count)).collect::<Vec<_>>(),
+            vec![(Some(-25.0), 2), (Some(0.0), 1), (Some(25.0), 5)]
+        );
+
+        for bad in &[
+            r#"{ "field": "price" }"#,
+            r#"{ "field": "price", "interval": 0 }"#,
+            r#"{ "field": "price", "interval": 5, "ranges": [{ "to": 5 }] }"#,
+            r#"{ "field": "price", "ranges": [{ "from": 5, "to": 5 }] }"#,
+            r#"{ "field": "price", "ranges": [{ "from": 5 }, { "from": 9, "to": 1 }] }"#,
+        ] {
+            let range: RangeAggregation = serde_json::from_str(bad).unwrap();
+            assert!(range.validate().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_bucket_start() {
+        let day = 86_400;
+        assert_eq!(DateHistogram::bucket_start(day + 3_599, 3_600, 0).unwrap(), day);
+        assert_eq!(DateHistogram::bucket_start(-1, 3_600, 0).unwrap(), -3_600);
+        // A day starting at midnight two hours ahead of UTC starts at 22:00 UTC
+        assert_eq!(DateHistogram::bucket_start(day + 3_600, day, 7_200).unwrap(), day - 7_200);
+        assert_eq!(DateHistogram::bucket_start(day - 3_600, day, 7_200).unwrap(), day - 7_200);
+        // Neither the shift into the time zone nor the one back out of it may overflow
+        assert!(DateHistogram::bucket_start(i64::max_value(), 3_600, 7_200).is_err());
+        assert!(DateHistogram::bucket_start(i64::min_value(), 3_600, -7_200).is_err());
+        assert!(DateHistogram::bucket_start(i64::min_value() + 1, day, 0).is_err());
+    }
+
+    #[test]
+    fn test_empty_buckets() {
+        let counts: BTreeMap<i64, u64> = vec![(0, 2), (7_200, 1)].into_iter().collect();
+        let sparse = histogram("1h", None).buckets(&counts, |_| BTreeMap::new()).unwrap();
+        assert_eq!(
+            sparse.iter().map(|b| (b.key, b.doc_count)).collect::<Vec<_>>(),
+            vec![(0, 2), (7_200, 1)]
+        );
+
+        let filled = DateHistogram {
+            min_doc_count: 0,
+            ..histogram("1h", Some("+01:00"))
+        };
+        let filled = filled.buckets(&counts, |_| BTreeMap::new()).unwrap();
+        assert_eq!(
+            filled.iter().map(|b| (b.key, b.doc_count)).collect::<Vec<_>>(),
+            vec![(0, 2), (3_600, 0), (7_200, 1)]
+        );
+        assert_eq!(filled[1].key_as_string, "1970-01-01T02:00:00+01:00");
+
+        let huge: BTreeMap<i64, u64> = vec![(0, 1), (86_400 * 365, 1)].into_iter().collect();
+        let too_many = DateHistogram {
+            min_doc_count: 0,
+            ..histogram("1s", None)
+        };
+        assert!(too_many.buckets(&huge, |_| BTreeMap::new()).is_err());
+
+        // Spans and dates past what can be represented are errors rather than overflows
+        let extremes: BTreeMap<i64, u64> = vec![(i64::min_value(), 1), (i64::max_value(), 1)].into_iter().collect();
+        let filled = DateHistogram {
+            min_doc_count: 0,
+            ..histogram("1h", None)
+        };
+        assert!(filled.buckets(&extremes, |_| BTreeMap::new()).is_err());
+        assert!(histogram("1h", None).buckets(&extremes, |_| BTreeMap::new()).is_err());
+    }
+}
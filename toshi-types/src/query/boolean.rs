@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use tantivy::query::{BooleanQuery, Occur, Query as TQuery};
+use tantivy::query::{AllQuery, BooleanQuery, Occur, Query as TQuery};
 use tantivy::schema::Schema;
 
 use crate::error::Error;
@@ -38,10 +38,49 @@ impl BoolQuery {
         }
     }
 
+    /// Every clause of this query, whether it must, must not or should match
+    pub(crate) fn clauses(&self) -> impl Iterator<Item = &Query> {
+        self.must.iter().chain(self.must_not.iter()).chain(self.should.iter())
+    }
+
+    /// This query with `f` applied to every one of its clauses
+    pub(crate) fn map<F: FnMut(Query) -> Query>(self, mut f: F) -> Self {
+        Self {
+            must: self.must.into_iter().map(&mut f).collect(),
+            must_not: self.must_not.into_iter().map(&mut f).collect(),
+            should: self.should.into_iter().map(&mut f).collect(),
+            ..self
+        }
+    }
+
     /// Create a builder instance for a BoolQuery
     pub fn builder() -> BoolQueryBuilder {
         BoolQueryBuilder::default()
     }
+
+    /// Clause order doesn't change what a boolean query matches, so clauses are sorted by their
+    /// JSON. A query with a single must clause and nothing else is the same as that clause alone.
+    pub(crate) fn normalize(self) -> Query {
+        let sorted = |queries: Vec<Query>| {
+            let mut keyed: Vec<_> = queries
+                .into_iter()
+                .map(Query::normalize)
+                .map(|q| (serde_json::to_string(&q).unwrap_or_default(), q))
+                .collect();
+            keyed.sort_by(|a, b| a.0.cmp(&b.0));
+            keyed.into_iter().map(|(_, q)| q).collect::<Vec<_>>()
+        };
+        let mut must = sorted(self.must);
+        let must_not = sorted(self.must_not);
+        let should = sorted(self.should);
+        let boost = self.boost.filter(|b| *b != 1.0);
+        if must.len() == 1 && must_not.is_empty() && should.is_empty() && boost.is_none() {
+            return must.remove(0);
+        }
+        Query::Boolean {
+            bool: BoolQuery::new(must, must_not, should, self.minimum_should_match, boost),
+        }
+    }
 }
 
 impl CreateQuery for BoolQuery {
@@ -66,9 +105,16 @@ fn parse_queries(schema: &Schema, occur: Occur, queries: Vec<Query>) -> Result<V
         .map(|q| match q {
             Query::Fuzzy(f) => Ok((occur, f.create_query(&schema)?)),
             Query::Exact(q) => Ok((occur, q.create_query(&schema)?)),
+            Query::Terms(q) => Ok((occur, q.create_query(schema)?)),
             Query::Range(r) => Ok((occur, r.create_query(&schema)?)),
             Query::Phrase(p) => Ok((occur, p.create_query(&schema)?)),
+            Query::PhrasePrefix(p) => Ok((occur, p.create_query(schema)?)),
             Query::Regex(r) => Ok((occur, r.create_query(&schema)?)),
+            Query::HasChild { has_child } => Ok((occur, has_child.create_query(schema)?)),
+            Query::HasParent { has_parent } => Ok((occur, has_parent.create_query(schema)?)),
+            Query::Boolean { bool } => Ok((occur, bool.create_query(schema)?)),
+            Query::MatchAll { match_all } => Ok((occur, match_all.create_query(schema)?)),
+            Query::All => Ok((occur, Box::new(AllQuery) as Box<dyn TQuery>)),
             _ => Err(Error::QueryError("Invalid type for boolean query".into())),
         })
         .collect::<Result<Vec<(Occur, Box<dyn TQuery>)>>>()
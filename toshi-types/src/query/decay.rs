@@ -0,0 +1,133 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::query::aggregation::duration_secs;
+use crate::Result;
+
+/// The curve a [`Decay`] lowers scores along as a document's date gets further from the origin
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DecayFunction {
+    /// A bell curve, scores barely drop close to the origin and fall off quickly past the scale
+    Gauss,
+    /// A straight line down to 0, reaching it a little past the scale
+    Linear,
+    /// A curve that drops the fastest close to the origin and never reaches 0
+    Exp,
+}
+
+/// Multiplies the score of every hit by how close a date field of the hit is to an origin, so older
+/// documents rank below newer ones that match just as well. At `scale` past `offset` from the origin
+/// a document's score is multiplied by `decay`, closer ones less and further ones more.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Decay {
+    /// The curve scores are lowered along
+    pub function: DecayFunction,
+    /// An indexed date field, or an indexed i64 field of seconds since the epoch
+    pub field: String,
+    /// The date scores are highest at, in RFC 3339 format, left out it's the time of the search
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    /// How far from the origin scores are multiplied by `decay`, a number followed by `s`, `m`, `h`
+    /// or `d` such as `7d`
+    pub scale: String,
+    /// How far from the origin scores start to drop, none of them do when left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<String>,
+    /// What scores are multiplied by at `scale` from the origin, between 0 and 1
+    #[serde(default = "Decay::default_decay")]
+    pub decay: f64,
+    /// What the scores of documents without a value for the field are multiplied by
+    #[serde(default = "Decay::default_missing")]
+    pub missing: f64,
+}
+
+impl Decay {
+    /// Constructor for a decay of `field` from the time of the search that halves scores at `scale`
+    pub fn new(function: DecayFunction, field: String, scale: String) -> Self {
+        Self {
+            function,
+            field,
+            origin: None,
+            scale,
+            offset: None,
+            decay: Self::default_decay(),
+            missing: Self::default_missing(),
+        }
+    }
+
+    fn default_decay() -> f64 {
+        0.5
+    }
+
+    fn default_missing() -> f64 {
+        1.0
+    }
+
+    /// The scale and offset in seconds, after checking the decay is between 0 and 1 and the scores
+    /// of documents without the field aren't multiplied by a negative number
+    pub fn scale_and_offset_secs(&self) -> Result<(i64, i64)> {
+        if !(self.decay > 0.0 && self.decay < 1.0) {
+            return Err(Error::QueryError(format!("Decay {} is not between 0 and 1", self.decay)));
+        }
+        if !(self.missing.is_finite() && self.missing >= 0.0) {
+            return Err(Error::QueryError(format!("Missing {} is not a positive number", self.missing)));
+        }
+        let offset = match &self.offset {
+            Some(offset) => duration_secs(offset, "offset")?,
+            None => 0,
+        };
+        Ok((duration_secs(&self.scale, "scale")?, offset))
+    }
+
+    /// What a score is multiplied by for a document `distance` seconds from the origin, scale and
+    /// offset being in seconds too
+    pub fn factor(&self, distance: i64, scale: i64, offset: i64) -> f64 {
+        let distance = (distance.abs() - offset).max(0) as f64 / scale as f64;
+        match self.function {
+            DecayFunction::Gauss => self.decay.powf(distance * distance),
+            DecayFunction::Linear => (1.0 - (1.0 - self.decay) * distance).max(0.0),
+            DecayFunction::Exp => self.decay.powf(distance),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_factor() -> Result<()> {
+        let decay: Decay = serde_json::from_str(r#"{ "function": "gauss", "field": "published", "scale": "10d", "offset": "1d" }"#)?;
+        let (scale, offset) = decay.scale_and_offset_secs()?;
+        let day = 24 * 60 * 60;
+        assert_eq!((scale, offset), (10 * day, day));
+        for function in &[DecayFunction::Gauss, DecayFunction::Linear, DecayFunction::Exp] {
+            let decay = Decay {
+                function: *function,
+                ..decay.clone()
+            };
+            // Nothing drops within the offset, at the scale past it scores are halved either side of the origin
+            assert_eq!(decay.factor(day, scale, offset), 1.0);
+            assert!((decay.factor(11 * day, scale, offset) - 0.5).abs() < 1e-9);
+            assert!((decay.factor(-11 * day, scale, offset) - 0.5).abs() < 1e-9);
+            assert!(decay.factor(5 * day, scale, offset) > decay.factor(6 * day, scale, offset));
+        }
+        let linear = Decay {
+            function: DecayFunction::Linear,
+            ..decay
+        };
+        assert_eq!(linear.factor(100 * day, scale, offset), 0.0);
+
+        for bad in &[
+            r#""scale": "1d", "decay": 1.5"#,
+            r#""scale": "1d", "decay": 0"#,
+            r#""scale": "1d", "missing": -1"#,
+            r#""scale": "10""#,
+        ] {
+            let body = format!(r#"{{ "function": "exp", "field": "published", {} }}"#, bad);
+            assert!(serde_json::from_str::<Decay>(&body)?.scale_and_offset_secs().is_err(), "{}", bad);
+        }
+        Ok(())
+    }
+}
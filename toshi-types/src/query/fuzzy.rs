@@ -3,8 +3,9 @@ use std::fmt;
 use serde::{Deserialize, Serialize};
 use tantivy::query::{FuzzyTermQuery, Query as TantivyQuery};
 use tantivy::schema::Schema;
+use tantivy::Term;
 
-use crate::query::{make_field_value, CreateQuery, KeyValue, Query};
+use crate::query::{text_field, CreateQuery, KeyValue, Query};
 use crate::Result;
 
 /// A query where terms can have distance between them, but still be a match
@@ -27,7 +28,7 @@ impl FuzzyQuery {
 impl CreateQuery for FuzzyQuery {
     fn create_query(self, schema: &Schema) -> Result<Box<dyn TantivyQuery>> {
         let KeyValue { field, value } = self.fuzzy;
-        let term = make_field_value(schema, &field, &value.value)?;
+        let term = Term::from_field_text(text_field(schema, &field, "fuzzy")?, &value.value);
         Ok(Box::new(FuzzyTermQuery::new(term, value.distance, value.transposition)))
     }
 }
@@ -98,3 +99,23 @@ impl FuzzyQueryBuilder {
         Query::Fuzzy(query)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tantivy::schema::*;
+
+    use super::*;
+
+    #[test]
+    fn test_non_text_field() {
+        let body = r#"{ "fuzzy": { "test_u64": { "value": "10", "distance": 1 } } }"#;
+        let mut schema = SchemaBuilder::new();
+        schema.add_u64_field("test_u64", INDEXED);
+        let query = serde_json::from_str::<FuzzyQuery>(body).unwrap().create_query(&schema.build());
+
+        assert_eq!(
+            query.unwrap_err().to_string(),
+            "Error in query execution: 'A fuzzy query only searches text fields, test_u64 isn't one'"
+        );
+    }
+}
@@ -0,0 +1,329 @@
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tantivy::query::{BooleanQuery, EmptyQuery, Query as TQuery, Scorer, Weight};
+use tantivy::schema::{Field, FieldType, Schema, Value};
+use tantivy::{DocAddress, DocSet, Searcher, TantivyError, Term};
+
+use crate::error::Error;
+use crate::query::{CreateQuery, Query};
+use crate::{Result, ID_FIELD, PARENT_FIELD};
+
+/// The most documents the query of a join can match, every one of them is loaded to look up what
+/// it's joined to
+pub const MAX_JOIN_DOCS: usize = 10_000;
+
+/// Matches the parents of the documents `query` matches, the documents whose [`ID_FIELD`] is in
+/// the [`PARENT_FIELD`] of a matching child
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HasChildQuery {
+    query: Box<Query>,
+    #[serde(skip)]
+    cancelled: Option<Arc<AtomicBool>>,
+}
+
+/// Matches the children of the documents `query` matches, the documents whose [`PARENT_FIELD`]
+/// holds the [`ID_FIELD`] of a matching parent
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HasParentQuery {
+    query: Box<Query>,
+    #[serde(skip)]
+    cancelled: Option<Arc<AtomicBool>>,
+}
+
+impl HasChildQuery {
+    /// Constructor for the parents of the children `query` matches
+    pub fn new(query: Query) -> Self {
+        Self {
+            query: Box::new(query),
+            cancelled: None,
+        }
+    }
+
+    pub(crate) fn query(&self) -> &Query {
+        &self.query
+    }
+
+    pub(crate) fn normalize(self) -> Self {
+        self.map(Query::normalize)
+    }
+
+    pub(crate) fn map<F: FnOnce(Query) -> Query>(self, f: F) -> Self {
+        Self {
+            query: Box::new(f(*self.query)),
+            cancelled: self.cancelled,
+        }
+    }
+}
+
+impl HasParentQuery {
+    /// Constructor for the children of the parents `query` matches
+    pub fn new(query: Query) -> Self {
+        Self {
+            query: Box::new(query),
+            cancelled: None,
+        }
+    }
+
+    pub(crate) fn query(&self) -> &Query {
+        &self.query
+    }
+
+    pub(crate) fn normalize(self) -> Self {
+        self.map(Query::normalize)
+    }
+
+    pub(crate) fn map<F: FnOnce(Query) -> Query>(self, f: F) -> Self {
+        Self {
+            query: Box::new(f(*self.query)),
+            cancelled: self.cancelled,
+        }
+    }
+}
+
+impl Query {
+    /// Have the joins in this query stop looking up documents once `cancelled` is set. A join runs
+    /// its query while the search is being set up, before anything cancelling the search sees it.
+    pub fn cancel_joins(self, cancelled: &Arc<AtomicBool>) -> Self {
+        match self {
+            Query::Boolean { bool } => Query::Boolean {
+                bool: bool.map(|q| q.cancel_joins(cancelled)),
+            },
+            Query::HasChild { has_child } => Query::HasChild {
+                has_child: HasChildQuery {
+                    cancelled: Some(Arc::clone(cancelled)),
+                    ..has_child.map(|q| q.cancel_joins(cancelled))
+                },
+            },
+            Query::HasParent { has_parent } => Query::HasParent {
+                has_parent: HasParentQuery {
+                    cancelled: Some(Arc::clone(cancelled)),
+                    ..has_parent.map(|q| q.cancel_joins(cancelled))
+                },
+            },
+            q => q,
+        }
+    }
+}
+
+impl CreateQuery for HasChildQuery {
+    fn create_query(self, schema: &Schema) -> Result<Box<dyn TQuery>> {
+        join(*self.query, schema, PARENT_FIELD, ID_FIELD, self.cancelled)
+    }
+}
+
+impl CreateQuery for HasParentQuery {
+    fn create_query(self, schema: &Schema) -> Result<Box<dyn TQuery>> {
+        join(*self.query, schema, ID_FIELD, PARENT_FIELD, self.cancelled)
+    }
+}
+
+/// A field a join goes through. It has to be indexed and stored, and a text field has to be indexed
+/// whole with the `raw` tokenizer so the values stored in it are also its terms.
+fn relation_field(schema: &Schema, name: &str) -> Result<Field> {
+    let invalid = || Error::QueryError(format!("Joining needs an indexed and stored u64, i64 or raw text {} field", name));
+    let field = schema.get_field(name).ok_or_else(invalid)?;
+    let entry = schema.get_field_entry(field);
+    let whole = match entry.field_type() {
+        FieldType::Str(options) => options.get_indexing_options().map_or(false, |o| o.tokenizer() == "raw"),
+        FieldType::U64(_) | FieldType::I64(_) => true,
+        _ => false,
+    };
+    if whole && entry.is_indexed() && entry.is_stored() {
+        Ok(field)
+    } else {
+        Err(invalid())
+    }
+}
+
+fn join(query: Query, schema: &Schema, from: &str, to: &str, cancelled: Option<Arc<AtomicBool>>) -> Result<Box<dyn TQuery>> {
+    let (from_field, to_field) = (relation_field(schema, from)?, relation_field(schema, to)?);
+    let value_type = |f: Field| schema.get_field_entry(f).field_type().value_type();
+    if value_type(from_field) != value_type(to_field) {
+        return Err(Error::QueryError(format!("Joining needs {} and {} to be the same type", from, to)));
+    }
+    Ok(Box::new(JoinQuery {
+        inner: query.create_query(schema)?,
+        from: from_field,
+        to: to_field,
+        cancelled,
+    }))
+}
+
+/// The term of `field` a value stored in a field of the same type is indexed as
+fn relation_term(field: Field, value: &Value) -> Option<Term> {
+    match value {
+        Value::Str(v) => Some(Term::from_field_text(field, v)),
+        Value::U64(v) => Some(Term::from_field_u64(field, *v)),
+        Value::I64(v) => Some(Term::from_field_i64(field, *v)),
+        _ => None,
+    }
+}
+
+/// The two phases of a join. The documents `inner` matches are looked up first and the values of
+/// their `from` field collected, then the documents with any of those values in `to` are matched.
+#[derive(Debug)]
+struct JoinQuery {
+    inner: Box<dyn TQuery>,
+    from: Field,
+    to: Field,
+    cancelled: Option<Arc<AtomicBool>>,
+}
+
+impl Clone for JoinQuery {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.box_clone(),
+            from: self.from,
+            to: self.to,
+            cancelled: self.cancelled.clone(),
+        }
+    }
+}
+
+impl TQuery for JoinQuery {
+    fn weight(&self, searcher: &Searcher, scoring_enabled: bool) -> tantivy::Result<Box<dyn Weight>> {
+        let cancelled = || self.cancelled.as_ref().map_or(false, |c| c.load(Ordering::Relaxed));
+        let inner = self.inner.weight(searcher, false)?;
+        let mut terms = BTreeSet::new();
+        let mut matched = 0;
+        for (segment, reader) in searcher.segment_readers().iter().enumerate() {
+            let mut scorer = inner.scorer(reader)?;
+            while scorer.advance() {
+                // The search checks for itself whether it was cancelled, the join only has to stop early
+                if cancelled() {
+                    return EmptyQuery.weight(searcher, scoring_enabled);
+                }
+                let doc = scorer.doc();
+                if reader.is_deleted(doc) {
+                    continue;
+                }
+                matched += 1;
+                if matched > MAX_JOIN_DOCS {
+                    return Err(TantivyError::SchemaError(format!(
+                        "The query of a join matched more than {} documents",
+                        MAX_JOIN_DOCS
+                    )));
+                }
+                let stored = searcher.doc(DocAddress(segment as u32, doc))?;
+                terms.extend(stored.get_all(self.from).into_iter().filter_map(|v| relation_term(self.to, v)));
+            }
+        }
+        if terms.is_empty() {
+            return EmptyQuery.weight(searcher, scoring_enabled);
+        }
+        BooleanQuery::new_multiterms_query(terms.into_iter().collect()).weight(searcher, scoring_enabled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::collector::TopDocs;
+    use tantivy::schema::{SchemaBuilder, INDEXED, STORED, STRING, TEXT};
+    use tantivy::{doc, Index};
+
+    use super::*;
+
+    #[test]
+    fn test_join_parse() {
+        let query: Query = serde_json::from_str(r#"{ "has_child": { "query": { "term": { "body": "red" } } } }"#).unwrap();
+        match query {
+            Query::HasChild { has_child } => assert_eq!(has_child.query().kind(), "term"),
+            q => panic!("Parsed as {:?}", q),
+        }
+        let query: Query = serde_json::from_str(r#"{ "has_parent": { "query": { "match_all": {} } } }"#).unwrap();
+        assert_eq!(query.kinds(), vec!["has_parent", "match_all"]);
+    }
+
+    #[test]
+    fn test_join() -> Result<()> {
+        let mut builder = SchemaBuilder::new();
+        let id = builder.add_text_field(ID_FIELD, STRING | STORED);
+        let parent = builder.add_text_field(PARENT_FIELD, STRING | STORED);
+        let body = builder.add_text_field("body", TEXT | STORED);
+        let schema = builder.build();
+        let index = Index::create_in_ram(schema.clone());
+        let mut writer = index.writer(15_000_000)?;
+        writer.add_document(doc!(id => "p1", body => "question one"));
+        writer.add_document(doc!(id => "p2", body => "question two"));
+        writer.add_document(doc!(id => "c1", parent => "p1", body => "red answer"));
+        writer.add_document(doc!(id => "c2", parent => "p2", body => "blue answer"));
+        writer.commit()?;
+        let searcher = index.reader()?.searcher();
+        let ids = |query: Query| -> Vec<String> {
+            let query = query.create_query(&schema).unwrap();
+            let mut ids: Vec<String> = searcher
+                .search(&*query, &TopDocs::with_limit(10))
+                .unwrap()
+                .into_iter()
+                .map(|(_, addr)| searcher.doc(addr).unwrap().get_first(id).unwrap().text().unwrap().to_string())
+                .collect();
+            ids.sort();
+            ids
+        };
+        let term =
+            |field: &str, value: &str| serde_json::from_str::<Query>(&format!(r#"{{ "term": {{ "{}": "{}" }} }}"#, field, value)).unwrap();
+
+        let has_child = |q: Query| Query::HasChild {
+            has_child: HasChildQuery::new(q),
+        };
+        let has_parent = |q: Query| Query::HasParent {
+            has_parent: HasParentQuery::new(q),
+        };
+        assert_eq!(ids(has_child(term("body", "red"))), vec!["p1"]);
+        assert_eq!(ids(has_child(term("body", "answer"))), vec!["p1", "p2"]);
+        assert!(ids(has_child(term("body", "green"))).is_empty());
+        assert_eq!(ids(has_parent(term("body", "two"))), vec!["c2"]);
+
+        let no_relation = SchemaBuilder::new().build();
+        assert!(HasChildQuery::new(Query::All).create_query(&no_relation).is_err());
+
+        // A cancelled search stops joining and matches nothing
+        let cancelled = Arc::new(AtomicBool::new(true));
+        assert!(ids(has_child(term("body", "red")).cancel_joins(&cancelled)).is_empty());
+        cancelled.store(false, Ordering::SeqCst);
+        assert_eq!(ids(has_child(term("body", "red")).cancel_joins(&cancelled)), vec!["p1"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_join_fields() -> Result<()> {
+        // Ids of any type are joined on their terms, as long as both fields have the same type
+        let mut builder = SchemaBuilder::new();
+        let id = builder.add_u64_field(ID_FIELD, INDEXED | STORED);
+        let parent = builder.add_u64_field(PARENT_FIELD, INDEXED | STORED);
+        let body = builder.add_text_field("body", TEXT | STORED);
+        let schema = builder.build();
+        let index = Index::create_in_ram(schema.clone());
+        let mut writer = index.writer(15_000_000)?;
+        writer.add_document(doc!(id => 1u64, body => "question"));
+        writer.add_document(doc!(id => 2u64, parent => 1u64, body => "answer"));
+        for n in 0..=MAX_JOIN_DOCS as u64 {
+            writer.add_document(doc!(id => 10 + n, body => "filler"));
+        }
+        writer.commit()?;
+        let searcher = index.reader()?.searcher();
+        let query = |text: &str| HasChildQuery::new(serde_json::from_str(&format!(r#"{{ "term": {{ "body": "{}" }} }}"#, text)).unwrap());
+        let parents = searcher.search(&*query("answer").create_query(&schema)?, &TopDocs::with_limit(10))?;
+        assert_eq!(parents.len(), 1);
+        assert_eq!(searcher.doc(parents[0].1)?.get_first(id).map(Value::u64_value), Some(1));
+
+        // A join whose query matches too many documents is refused rather than loading them all
+        let too_many = searcher.search(&*query("filler").create_query(&schema)?, &TopDocs::with_limit(10));
+        assert!(matches!(too_many, Err(TantivyError::SchemaError(_))));
+
+        for (id_options, parent_options) in &[(TEXT | STORED, TEXT | STORED), (STRING | STORED, STRING)] {
+            let mut builder = SchemaBuilder::new();
+            builder.add_text_field(ID_FIELD, id_options.clone());
+            builder.add_text_field(PARENT_FIELD, parent_options.clone());
+            assert!(query("answer").create_query(&builder.build()).is_err());
+        }
+        let mut builder = SchemaBuilder::new();
+        builder.add_text_field(ID_FIELD, STRING | STORED);
+        builder.add_u64_field(PARENT_FIELD, INDEXED | STORED);
+        assert!(query("answer").create_query(&builder.build()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+use tantivy::query::{AllQuery, AllWeight, Explanation, Query, Scorer, Weight};
+use tantivy::schema::Schema;
+use tantivy::{DocId, DocSet, Score, Searcher, SegmentReader, SkipResult};
+
+use crate::query::CreateQuery;
+use crate::Result;
+
+/// A query that matches every document in an index, all documents are given the same score which
+/// defaults to 1
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MatchAllQuery {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    boost: Option<f32>,
+}
+
+impl MatchAllQuery {
+    /// Constructor for a match all query that scores every document with `boost`
+    pub fn with_boost(boost: f32) -> Self {
+        Self { boost: Some(boost) }
+    }
+}
+
+impl CreateQuery for MatchAllQuery {
+    fn create_query(self, _: &Schema) -> Result<Box<dyn Query>> {
+        match self.boost {
+            Some(boost) => Ok(Box::new(BoostedAllQuery(boost))),
+            None => Ok(Box::new(AllQuery)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct BoostedAllQuery(f32);
+
+impl Query for BoostedAllQuery {
+    fn weight(&self, _: &Searcher, _: bool) -> tantivy::Result<Box<dyn Weight>> {
+        Ok(Box::new(BoostedAllWeight(self.0)))
+    }
+}
+
+struct BoostedAllWeight(f32);
+
+impl Weight for BoostedAllWeight {
+    fn scorer(&self, reader: &SegmentReader) -> tantivy::Result<Box<dyn Scorer>> {
+        Ok(Box::new(BoostedAllScorer {
+            scorer: AllWeight.scorer(reader)?,
+            boost: self.0,
+        }))
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> tantivy::Result<Explanation> {
+        AllWeight.explain(reader, doc)?;
+        Ok(Explanation::new("MatchAllQuery", self.0))
+    }
+}
+
+// tantivy's ConstScorer ignores the score it is given, so the boost is applied here instead
+struct BoostedAllScorer {
+    scorer: Box<dyn Scorer>,
+    boost: Score,
+}
+
+impl DocSet for BoostedAllScorer {
+    fn advance(&mut self) -> bool {
+        self.scorer.advance()
+    }
+
+    fn skip_next(&mut self, target: DocId) -> SkipResult {
+        self.scorer.skip_next(target)
+    }
+
+    fn doc(&self) -> DocId {
+        self.scorer.doc()
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.scorer.size_hint()
+    }
+}
+
+impl Scorer for BoostedAllScorer {
+    fn score(&mut self) -> Score {
+        self.boost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::query::Query;
+
+    #[test]
+    fn test_match_all_parse() {
+        let query: Query = serde_json::from_str(r#"{ "match_all": {} }"#).unwrap();
+        match query {
+            Query::MatchAll { match_all } => assert_eq!(match_all.boost, None),
+            q => panic!("Parsed as {:?}", q),
+        }
+        let query: Query = serde_json::from_str(r#"{ "match_all": { "boost": 2.5 } }"#).unwrap();
+        match query {
+            Query::MatchAll { match_all } => assert_eq!(match_all.boost, Some(2.5)),
+            q => panic!("Parsed as {:?}", q),
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::marker::PhantomData;
 
@@ -5,22 +6,33 @@ use serde::de::{DeserializeOwned, Deserializer, Error as SerdeError, MapAccess,
 use serde::ser::SerializeMap;
 use serde::Serializer;
 use serde::{Deserialize, Serialize};
-use tantivy::query::Query as TantivyQuery;
-use tantivy::schema::Schema;
+use tantivy::query::{AllQuery, Query as TantivyQuery};
+use tantivy::schema::{Field, FieldType, Schema};
 use tantivy::Term;
 
+use crate::client::ScoreNormalization;
 use crate::error::Error;
 use crate::query::{
-    boolean::BoolQuery, facet::FacetQuery, fuzzy::FuzzyQuery, phrase::PhraseQuery, range::RangeQuery, regex::RegexQuery, term::ExactTerm,
+    aggregation::Aggregation, boolean::BoolQuery, decay::Decay, facet::FacetQuery, fuzzy::FuzzyQuery, join::HasChildQuery,
+    join::HasParentQuery, match_all::MatchAllQuery, phrase::PhraseQuery, phrase_prefix::PhrasePrefixQuery, range::RangeQuery,
+    regex::RegexQuery, term::ExactTerm, terms::TermsQuery,
 };
 
+pub(crate) mod aggregation;
 pub(crate) mod boolean;
+pub(crate) mod decay;
 pub(crate) mod facet;
 pub(crate) mod fuzzy;
+pub(crate) mod join;
+pub(crate) mod match_all;
 pub(crate) mod phrase;
+pub(crate) mod phrase_prefix;
+pub(crate) mod query_string;
 pub(crate) mod range;
 pub(crate) mod regex;
+pub(crate) mod synonyms;
 pub(crate) mod term;
+pub(crate) mod terms;
 
 /// Trait that generically represents Tantivy queries
 pub trait CreateQuery {
@@ -36,8 +48,12 @@ pub enum Query {
     Fuzzy(FuzzyQuery),
     /// [`tantivy::query::TermQuery`]: TermQuery
     Exact(ExactTerm),
+    /// A [`tantivy::query::BooleanQuery`]: BooleanQuery matching any of a list of terms
+    Terms(TermsQuery),
     /// [`tantivy::query::PhraseQuery`]: PhraseQuery
     Phrase(PhraseQuery),
+    /// A phrase whose last word is a prefix, for searching as someone types
+    PhrasePrefix(PhrasePrefixQuery),
     /// [`tantivy::query::RegexQuery`]: RegexQuery
     Regex(RegexQuery),
     /// [`tantivy::query::RangeQuery`]: RangeQuery
@@ -47,15 +63,132 @@ pub enum Query {
         /// Collection of boolean clauses
         bool: BoolQuery,
     },
+    /// The parents of the child documents a query matches, see [`crate::PARENT_FIELD`]
+    HasChild {
+        /// The query the children have to match
+        has_child: HasChildQuery,
+    },
+    /// The children of the parent documents a query matches, see [`crate::PARENT_FIELD`]
+    HasParent {
+        /// The query the parents have to match
+        has_parent: HasParentQuery,
+    },
     /// Raw is a query that passes by the query parser and is just executed directly against the index
     Raw {
         /// The actual query to be ran
         raw: String,
     },
+    /// [`tantivy::query::AllQuery`]: AllQuery, with an optional boost applied to every document
+    MatchAll {
+        /// The options of the match all query
+        match_all: MatchAllQuery,
+    },
     /// [`tantivy::query::AllQuery`]: AllQuery
     All,
 }
 
+impl Query {
+    /// The name of every kind of query, as it's keyed in a search's JSON
+    pub const KINDS: &'static [&'static str] = &[
+        "fuzzy",
+        "term",
+        "terms",
+        "phrase",
+        "match_phrase_prefix",
+        "regex",
+        "range",
+        "bool",
+        "has_child",
+        "has_parent",
+        "raw",
+        "match_all",
+        "all",
+    ];
+
+    /// The kind of this query, as it's keyed in a search's JSON
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Query::Fuzzy(_) => "fuzzy",
+            Query::Exact(_) => "term",
+            Query::Terms(_) => "terms",
+            Query::Phrase(_) => "phrase",
+            Query::PhrasePrefix(_) => "match_phrase_prefix",
+            Query::Regex(_) => "regex",
+            Query::Range(_) => "range",
+            Query::Boolean { .. } => "bool",
+            Query::HasChild { .. } => "has_child",
+            Query::HasParent { .. } => "has_parent",
+            Query::Raw { .. } => "raw",
+            Query::MatchAll { .. } => "match_all",
+            Query::All => "all",
+        }
+    }
+
+    /// The kinds of this query and of every query nested in it, in the order they appear
+    pub fn kinds(&self) -> Vec<&'static str> {
+        let mut kinds = vec![self.kind()];
+        match self {
+            Query::Boolean { bool } => kinds.extend(bool.clauses().flat_map(Query::kinds)),
+            Query::HasChild { has_child } => kinds.extend(has_child.query().kinds()),
+            Query::HasParent { has_parent } => kinds.extend(has_parent.query().kinds()),
+            _ => (),
+        }
+        kinds
+    }
+
+    /// The number of clauses in this query's boolean queries, counting those of boolean queries
+    /// nested in them and in join queries. A raw query counts none here, how many clauses it has is
+    /// only known once it's parsed against an index.
+    pub fn clause_count(&self) -> usize {
+        match self {
+            Query::Boolean { bool } => bool.clauses().map(|q| 1 + q.clause_count()).sum(),
+            Query::HasChild { has_child } => has_child.query().clause_count(),
+            Query::HasParent { has_parent } => has_parent.query().clause_count(),
+            _ => 0,
+        }
+    }
+
+    /// The canonical form of this query, queries that match the same documents the same way
+    /// normalize to the same value so their JSON can be compared or used as a cache key
+    pub fn normalize(self) -> Self {
+        match self {
+            Query::All => Query::MatchAll {
+                match_all: MatchAllQuery::default(),
+            },
+            Query::Boolean { bool } => bool.normalize(),
+            Query::HasChild { has_child } => Query::HasChild {
+                has_child: has_child.normalize(),
+            },
+            Query::HasParent { has_parent } => Query::HasParent {
+                has_parent: has_parent.normalize(),
+            },
+            q => q,
+        }
+    }
+}
+
+/// Every query but a raw one can be created from the schema alone, a raw query is parsed against
+/// the index it's run on
+impl CreateQuery for Query {
+    fn create_query(self, schema: &Schema) -> crate::Result<Box<dyn TantivyQuery>> {
+        match self {
+            Query::Fuzzy(q) => q.create_query(schema),
+            Query::Exact(q) => q.create_query(schema),
+            Query::Terms(q) => q.create_query(schema),
+            Query::Phrase(q) => q.create_query(schema),
+            Query::PhrasePrefix(q) => q.create_query(schema),
+            Query::Regex(q) => q.create_query(schema),
+            Query::Range(q) => q.create_query(schema),
+            Query::Boolean { bool } => bool.create_query(schema),
+            Query::HasChild { has_child } => has_child.create_query(schema),
+            Query::HasParent { has_parent } => has_parent.create_query(schema),
+            Query::MatchAll { match_all } => match_all.create_query(schema),
+            Query::All => Ok(Box::new(AllQuery)),
+            Query::Raw { .. } => Err(Error::QueryError("A raw query can't be nested in another query".into())),
+        }
+    }
+}
+
 /// The request body of a search POST in Toshi
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Search {
@@ -63,22 +196,101 @@ pub struct Search {
     pub query: Option<Query>,
     /// Optional facets of a query
     pub facets: Option<FacetQuery>,
-    /// Max number of documents to return
-    #[serde(default = "Search::default_limit")]
-    pub limit: usize,
-    /// Field to sort results by
+    /// Max number of documents to return, when omitted the server's default is used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    /// How many of the best hits to skip before the ones returned, for paging through hits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<usize>,
+    /// Field to sort results by, or [`Search::DOC_ORDER`] for the order documents are kept in
     #[serde(default)]
     pub sort_by: Option<String>,
+    /// Also score the hits of a sorted search. A search sorted by a field or in document order
+    /// doesn't need scores to rank its hits, so without this it skips scoring and its hits have no score.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub track_scores: bool,
+    /// Boosts for fields in this search, these take precedence over the boosts configured for the index
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub boosts: HashMap<String, f32>,
+    /// Also return documents that were soft deleted, see [`crate::TOMBSTONE_FIELD`]
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub include_deleted: bool,
+    /// How the scores of each index are rescaled before the hits of a search over several indexes
+    /// are merged
+    #[serde(default, skip_serializing_if = "ScoreNormalization::is_none")]
+    pub score_normalization: ScoreNormalization,
+    /// An arithmetic expression each hit is scored with in place of the query's score, such as
+    /// `score * log(views + 1)`. It can use `score` and the index's single valued numeric fast fields.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script_score: Option<String>,
+    /// Lowers the score of every hit the further a date field of it is from an origin, after any
+    /// `script_score` is applied, see [`Decay`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decay: Option<Decay>,
+    /// Text fields to return the positions of the query's matching terms in, see [`crate::Highlight`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub highlight: Vec<String>,
+    /// How the terms of a raw query with no operator between them are combined, overriding the
+    /// operator configured for the index
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_operator: Option<DefaultOperator>,
+    /// Aggregations computed over every document the query matches, by the name their results are returned under
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub aggs: BTreeMap<String, Aggregation>,
+    /// A query the returned hits must also match, applied after the facets and aggregations are
+    /// computed so they still count every document `query` matches. It doesn't change the hits' scores.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_filter: Option<Query>,
+    /// Return only each hit's `_id` and score, skipping loading the rest of the hits' stored fields.
+    /// Set by the `_source=false` option of a search rather than its body.
+    #[serde(skip)]
+    pub skip_source: bool,
+}
+
+/// Whether a document must match every term of a raw query or only one of them, when no operator
+/// is written between the terms
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DefaultOperator {
+    /// Any of the terms
+    Or,
+    /// All of the terms
+    And,
+}
+
+impl Default for DefaultOperator {
+    fn default() -> Self {
+        DefaultOperator::Or
+    }
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
 }
 
 impl Search {
+    /// The `sort_by` value that returns hits in the order they are kept in the index
+    pub const DOC_ORDER: &'static str = "_doc";
+
     /// Construct a new Search query
     pub fn new(query: Option<Query>, facets: Option<FacetQuery>, limit: usize) -> Self {
         Search {
             query,
             facets,
-            limit,
+            limit: Some(limit),
+            from: None,
             sort_by: None,
+            track_scores: false,
+            boosts: HashMap::new(),
+            include_deleted: false,
+            score_normalization: ScoreNormalization::None,
+            script_score: None,
+            decay: None,
+            highlight: Vec::new(),
+            default_operator: None,
+            aggs: BTreeMap::new(),
+            post_filter: None,
+            skip_source: false,
         }
     }
 
@@ -92,11 +304,23 @@ impl Search {
         Self::new(Some(query), None, Self::default_limit())
     }
 
+    /// The number of docs to return, falling back to [`Search::default_limit`] when unset
+    pub fn get_limit(&self) -> usize {
+        self.limit.unwrap_or_else(Self::default_limit)
+    }
+
     /// The default limit for docs to return
     pub const fn default_limit() -> usize {
         100
     }
 
+    /// This search with its query normalized, see [`Query::normalize`]
+    pub fn normalize(mut self) -> Self {
+        self.query = self.query.map(Query::normalize);
+        self.post_filter = self.post_filter.map(Query::normalize);
+        self
+    }
+
     pub(crate) fn all_query() -> Option<Query> {
         Some(Query::All)
     }
@@ -106,8 +330,20 @@ impl Search {
         Self {
             query: Self::all_query(),
             facets: None,
-            limit: Self::default_limit(),
+            limit: None,
+            from: None,
             sort_by: None,
+            track_scores: false,
+            boosts: HashMap::new(),
+            include_deleted: false,
+            score_normalization: ScoreNormalization::None,
+            script_score: None,
+            decay: None,
+            highlight: Vec::new(),
+            default_operator: None,
+            aggs: BTreeMap::new(),
+            post_filter: None,
+            skip_source: false,
         }
     }
 }
@@ -151,11 +387,50 @@ impl SearchBuilder {
     }
 }
 
-fn make_field_value(schema: &Schema, k: &str, v: &str) -> crate::Result<Term> {
+/// Look up a field a query searches, fields that are stored but not indexed can't be searched
+fn indexed_field(schema: &Schema, k: &str) -> crate::Result<Field> {
     let field = schema
         .get_field(k)
         .ok_or_else(|| Error::QueryError(format!("Unknown field: {}", k)))?;
-    Ok(Term::from_field_text(field, v))
+    if !schema.get_field_entry(field).is_indexed() {
+        return Err(Error::QueryError(format!("Field {} is not indexed", k)));
+    }
+    Ok(field)
+}
+
+fn make_field_value(schema: &Schema, k: &str, v: &str) -> crate::Result<Term> {
+    Ok(Term::from_field_text(indexed_field(schema, k)?, v))
+}
+
+/// The indexed text field `k` that a `kind` query, which only matches text, searches
+fn text_field(schema: &Schema, k: &str, kind: &str) -> crate::Result<Field> {
+    let field = indexed_field(schema, k)?;
+    match schema.get_field_entry(field).field_type() {
+        FieldType::Str(_) => Ok(field),
+        _ => Err(Error::QueryError(format!(
+            "A {} query only searches text fields, {} isn't one",
+            kind, k
+        ))),
+    }
+}
+
+/// The terms the tokenizer of `k` splits `text` into, the way the text of an indexed document is
+/// split. Text a field doesn't tokenize, and the values of fields that aren't text, are kept whole.
+fn analyze(schema: &Schema, k: &str, text: &str) -> crate::Result<Vec<String>> {
+    let field = indexed_field(schema, k)?;
+    let tokenizer = match schema.get_field_entry(field).field_type() {
+        FieldType::Str(options) => match options.get_indexing_options() {
+            Some(indexing) => indexing.tokenizer().to_string(),
+            None => return Ok(vec![text.into()]),
+        },
+        _ => return Ok(vec![text.into()]),
+    };
+    let analyzer = tantivy::tokenizer::TokenizerManager::default()
+        .get(&tokenizer)
+        .ok_or_else(|| Error::QueryError(format!("Unknown tokenizer {} for field {}", tokenizer, k)))?;
+    let mut terms = Vec::new();
+    analyzer.token_stream(text).process(&mut |token| terms.push(token.text.clone()));
+    Ok(terms)
 }
 
 /// A single key/value pair, this struct is used when we want to accept only single key/value pairs
@@ -266,4 +541,16 @@ mod tests {
         let expected = r#"{"test_field":1}"#;
         assert_eq!(expected, serde_json::to_string(&kv).unwrap());
     }
+
+    #[test]
+    fn test_query_kinds() {
+        let query: Query = serde_json::from_str(
+            r#"{ "bool": { "must": [ { "term": { "f": "a" } } ], "should": [ { "bool": { "must_not": [ { "regex": { "f": "a.*" } } ] } } ] } }"#,
+        )
+        .unwrap();
+        assert_eq!(query.kind(), "bool");
+        assert_eq!(query.kinds(), vec!["bool", "term", "bool", "regex"]);
+        assert!(query.kinds().iter().all(|k| Query::KINDS.contains(k)));
+        assert_eq!(query.clause_count(), 3);
+    }
 }
@@ -3,7 +3,7 @@ use tantivy::query::{PhraseQuery as TantivyPhraseQuery, Query};
 use tantivy::schema::Schema;
 use tantivy::Term;
 
-use crate::query::{make_field_value, CreateQuery, KeyValue};
+use crate::query::{text_field, CreateQuery, KeyValue};
 use crate::{error::Error, Result};
 
 /// A query for a phrase of terms, see [`tantivy::query::PhraseQuery`] for more info on what
@@ -55,23 +55,22 @@ impl CreateQuery for PhraseQuery {
                     offsets.len()
                 )));
             }
-            let paired_terms = value
-                .terms
-                .iter()
-                .zip(offsets)
-                .map(|(t, o)| match make_field_value(schema, &field, &t) {
-                    Ok(f) => Ok((*o, f)),
-                    Err(e) => Err(e),
-                })
-                .collect::<Result<Vec<(usize, Term)>>>()?;
-            Ok(Box::new(TantivyPhraseQuery::new_with_offset(paired_terms)))
-        } else {
-            let terms = value
-                .terms
-                .into_iter()
-                .map(|t| make_field_value(schema, &field, &t))
-                .collect::<Result<Vec<Term>>>()?;
-            Ok(Box::new(TantivyPhraseQuery::new(terms)))
+        }
+        let field = text_field(schema, &field, "phrase")?;
+        match value.offsets {
+            Some(offsets) => {
+                let paired_terms = value
+                    .terms
+                    .iter()
+                    .zip(offsets)
+                    .map(|(t, o)| (o, Term::from_field_text(field, t)))
+                    .collect::<Vec<(usize, Term)>>();
+                Ok(Box::new(TantivyPhraseQuery::new_with_offset(paired_terms)))
+            }
+            None => {
+                let terms = value.terms.iter().map(|t| Term::from_field_text(field, t)).collect();
+                Ok(Box::new(TantivyPhraseQuery::new(terms)))
+            }
         }
     }
 }
@@ -112,4 +111,17 @@ mod tests {
             "Error in query execution: 'Differing numbers of offsets and query terms (2 and 1)'"
         );
     }
+
+    #[test]
+    fn test_non_text_field() {
+        let body = r#"{ "phrase": { "test_u64": { "terms": ["1", "2"] } } }"#;
+        let mut schema = SchemaBuilder::new();
+        schema.add_u64_field("test_u64", INDEXED);
+        let query = serde_json::from_str::<PhraseQuery>(body).unwrap().create_query(&schema.build());
+
+        assert_eq!(
+            query.unwrap_err().to_string(),
+            "Error in query execution: 'A phrase query only searches text fields, test_u64 isn't one'"
+        );
+    }
 }
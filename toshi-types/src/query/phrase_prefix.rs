@@ -0,0 +1,212 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+use tantivy::query::{BooleanQuery, EmptyQuery, Occur, PhraseQuery as TantivyPhraseQuery, Query, TermQuery, Weight};
+use tantivy::schema::{Field, IndexRecordOption, Schema};
+use tantivy::{Searcher, Term};
+
+use crate::query::{analyze, indexed_field, CreateQuery, KeyValue};
+use crate::{error::Error, Result};
+
+/// A query for a phrase whose last word is still being typed, the words before it have to match as
+/// a phrase followed by any term starting with the last one
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PhrasePrefixQuery {
+    match_phrase_prefix: KeyValue<String, PhrasePrefix>,
+}
+
+impl PhrasePrefixQuery {
+    /// Constructor to create the key value for the user
+    pub fn with_phrase(field: String, value: PhrasePrefix) -> Self {
+        Self {
+            match_phrase_prefix: KeyValue::new(field, value),
+        }
+    }
+}
+
+/// The text of a phrase prefix query and how many terms its last word can stand for
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PhrasePrefix {
+    query: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_expansions: Option<usize>,
+}
+
+impl PhrasePrefix {
+    /// The most terms the last word is expanded to when a query doesn't say
+    pub const DEFAULT_MAX_EXPANSIONS: usize = 50;
+
+    /// Constructor for the text typed so far, expanding the last word to at most `max_expansions` terms
+    pub fn new(query: String, max_expansions: Option<usize>) -> Self {
+        Self { query, max_expansions }
+    }
+}
+
+impl CreateQuery for PhrasePrefixQuery {
+    fn create_query(self, schema: &Schema) -> Result<Box<dyn Query>> {
+        let KeyValue { field: name, value } = self.match_phrase_prefix;
+        let field = indexed_field(schema, &name)?;
+        // The words are split and normalized by the field's tokenizer, the way its text was indexed
+        let mut words = analyze(schema, &name, &value.query)?;
+        let prefix = words
+            .pop()
+            .ok_or_else(|| Error::QueryError("Phrase prefix query must have at least 1 word".into()))?;
+        Ok(Box::new(PrefixExpansion {
+            field,
+            terms: words.iter().map(|w| Term::from_field_text(field, w)).collect(),
+            prefix,
+            max_expansions: value.max_expansions.unwrap_or(PhrasePrefix::DEFAULT_MAX_EXPANSIONS).max(1),
+            expanded: Arc::new(RwLock::new(Vec::new())),
+        }))
+    }
+}
+
+/// The completed terms of a phrase and the prefix that follows them. The prefix can only be expanded
+/// once the term dictionaries of the segments being searched are known, so the phrases it stands for
+/// are built when the query is weighted.
+#[derive(Debug, Clone)]
+struct PrefixExpansion {
+    field: Field,
+    terms: Vec<Term>,
+    prefix: String,
+    max_expansions: usize,
+    /// The terms the prefix stood for the last time the query was weighted, they're among the
+    /// query's terms once it has been, for highlighting and scoring
+    expanded: Arc<RwLock<Vec<Term>>>,
+}
+
+impl PrefixExpansion {
+    /// The first `max_expansions` terms in the searched segments that start with the prefix, in order
+    fn expansions(&self, searcher: &Searcher) -> Vec<String> {
+        let mut expansions = BTreeSet::new();
+        for reader in searcher.segment_readers() {
+            let inverted_index = reader.inverted_index(self.field);
+            let mut stream = inverted_index.terms().range().ge(self.prefix.as_bytes()).into_stream();
+            // Every segment's terms are sorted, so the first ones overall are among the first ones of each
+            let mut taken = 0;
+            while taken < self.max_expansions && stream.advance() {
+                let key = stream.key();
+                if !key.starts_with(self.prefix.as_bytes()) {
+                    break;
+                }
+                if let Ok(term) = std::str::from_utf8(key) {
+                    expansions.insert(term.to_string());
+                    taken += 1;
+                }
+            }
+        }
+        expansions.into_iter().take(self.max_expansions).collect()
+    }
+}
+
+impl Query for PrefixExpansion {
+    fn weight(&self, searcher: &Searcher, scoring_enabled: bool) -> tantivy::Result<Box<dyn Weight>> {
+        let expanded: Vec<Term> = self
+            .expansions(searcher)
+            .iter()
+            .map(|expansion| Term::from_field_text(self.field, expansion))
+            .collect();
+        if let Ok(mut last) = self.expanded.write() {
+            *last = expanded.clone();
+        }
+        let clauses: Vec<(Occur, Box<dyn Query>)> = expanded
+            .into_iter()
+            .map(|last| {
+                let query: Box<dyn Query> = if self.terms.is_empty() {
+                    Box::new(TermQuery::new(last, IndexRecordOption::WithFreqs))
+                } else {
+                    let mut terms = self.terms.clone();
+                    terms.push(last);
+                    Box::new(TantivyPhraseQuery::new(terms))
+                };
+                (Occur::Should, query)
+            })
+            .collect();
+        if clauses.is_empty() {
+            return EmptyQuery.weight(searcher, scoring_enabled);
+        }
+        BooleanQuery::from(clauses).weight(searcher, scoring_enabled)
+    }
+
+    fn query_terms(&self, terms: &mut BTreeSet<Term>) {
+        terms.extend(self.terms.iter().cloned());
+        if let Ok(expanded) = self.expanded.read() {
+            terms.extend(expanded.iter().cloned());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::collector::Count;
+    use tantivy::schema::{SchemaBuilder, TextFieldIndexing, TextOptions, TEXT};
+    use tantivy::{doc, Index};
+
+    use super::*;
+
+    #[test]
+    fn test_phrase_prefix() -> Result<()> {
+        let mut builder = SchemaBuilder::new();
+        let title = builder.add_text_field("title", TEXT);
+        let schema = builder.build();
+        let index = Index::create_in_ram(schema.clone());
+        let mut writer = index.writer(15_000_000)?;
+        writer.add_document(doc!(title => "The quick brown fox"));
+        writer.add_document(doc!(title => "A quick brownie"));
+        writer.add_document(doc!(title => "The quick broom"));
+        writer.add_document(doc!(title => "Brown and quick"));
+        writer.commit()?;
+        let searcher = index.reader()?.searcher();
+        let count = |text: &str, max_expansions: Option<usize>| -> Result<usize> {
+            let query = PhrasePrefixQuery::with_phrase("title".into(), PhrasePrefix::new(text.into(), max_expansions));
+            Ok(searcher.search(&*query.create_query(&schema)?, &Count)?)
+        };
+
+        // Each keystroke of "quick brown" matches the phrases it's a start of
+        assert_eq!(count("qu", None)?, 4);
+        assert_eq!(count("Quick", None)?, 4);
+        assert_eq!(count("quick b", None)?, 3);
+        assert_eq!(count("quick bro", None)?, 3);
+        assert_eq!(count("quick brow", None)?, 2);
+        assert_eq!(count("quick brown", None)?, 2);
+        assert_eq!(count("quick brown f", None)?, 1);
+        assert_eq!(count("brown quick", None)?, 0);
+        assert_eq!(count("quick z", None)?, 0);
+
+        // "bro" stands for "broom", "brown" and "brownie" in that order, the cap keeps the first ones
+        assert_eq!(count("quick bro", Some(1))?, 1);
+        assert_eq!(count("quick bro", Some(2))?, 2);
+        assert!(count(" ", None).is_err());
+
+        // The prefix's expansions are among the query's terms once it has been weighted
+        let query = PhrasePrefixQuery::with_phrase("title".into(), PhrasePrefix::new("quick bro".into(), None)).create_query(&schema)?;
+        searcher.search(&*query, &Count)?;
+        let mut terms = BTreeSet::new();
+        query.query_terms(&mut terms);
+        let terms: Vec<&str> = terms.iter().map(|t| t.text()).collect();
+        assert_eq!(terms, vec!["broom", "brown", "brownie", "quick"]);
+
+        // Words before the prefix are normalized like the field's text, a stemmed field finds "dogs" as "dog"
+        let mut builder = SchemaBuilder::new();
+        let indexing = TextFieldIndexing::default()
+            .set_tokenizer("en_stem")
+            .set_index_option(IndexRecordOption::WithFreqsAndPositions);
+        let body = builder.add_text_field("body", TextOptions::default().set_indexing_options(indexing));
+        let stemmed = builder.build();
+        let index = Index::create_in_ram(stemmed.clone());
+        let mut writer = index.writer(15_000_000)?;
+        writer.add_document(doc!(body => "Dogs running home"));
+        writer.commit()?;
+        let query = PhrasePrefixQuery::with_phrase("body".into(), PhrasePrefix::new("dogs ru".into(), None));
+        assert_eq!(index.reader()?.searcher().search(&*query.create_query(&stemmed)?, &Count)?, 1);
+
+        let parsed: crate::Query =
+            serde_json::from_str(r#"{ "match_phrase_prefix": { "title": { "query": "quick bro", "max_expansions": 1 } } }"#)?;
+        match parsed {
+            crate::Query::PhrasePrefix(q) => assert_eq!(searcher.search(&*q.create_query(&schema)?, &Count)?, 1),
+            q => panic!("Parsed as {:?}", q),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,337 @@
+use crate::error::Error;
+use crate::query::fuzzy::FuzzyTerm;
+use crate::query::{BoolQuery, ExactTerm, FuzzyQuery, KeyValue, Query};
+use crate::Result;
+
+/// The edit distance of a fuzzy term written without one, like `quick~`
+const DEFAULT_FUZZY_DISTANCE: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Phrase(String),
+    Colon,
+    Tilde(Option<u8>),
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+}
+
+impl Query {
+    /// Parse a Lucene style query string such as `title:fox AND body:quick~` into a query. Terms can
+    /// be prefixed with the field they're in, terms without one are searched for in `default_field`.
+    /// Terms next to each other without an operator between them match if either does, `AND` binds
+    /// more tightly than `OR`, `NOT` or a leading `-` excludes a term from the query around it, `~`
+    /// makes a term fuzzy and quotes make a phrase. Terms and phrases are run through the tokenizer of
+    /// their field when the query is run. A query that doesn't parse is a [`Error::QueryError`]
+    /// giving the character the problem was found at.
+    pub fn from_query_string(input: &str, default_field: Option<&str>) -> Result<Self> {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            end: input.chars().count(),
+        };
+        let query = parser.or(default_field)?;
+        match parser.tokens.get(parser.pos) {
+            Some((at, token)) => Err(syntax_error(*at, &format!("unexpected {:?}", token))),
+            None => Ok(query),
+        }
+    }
+}
+
+fn syntax_error(at: usize, message: &str) -> Error {
+    Error::QueryError(format!("Syntax error at character {}: {}", at, message))
+}
+
+fn is_word_char(c: char) -> bool {
+    !c.is_whitespace() && !"():\"~".contains(c)
+}
+
+/// Split the query string into tokens, each with the character it starts at
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().enumerate().peekable();
+    while let Some((at, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            ':' => Token::Colon,
+            '-' => Token::Not,
+            '"' => {
+                let mut phrase = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, c)) => phrase.push(c),
+                        None => return Err(syntax_error(at, "unclosed phrase")),
+                    }
+                }
+                Token::Phrase(phrase)
+            }
+            '~' => {
+                let mut digits = String::new();
+                while let Some((_, d)) = chars.peek().filter(|(_, d)| d.is_ascii_digit()) {
+                    digits.push(*d);
+                    chars.next();
+                }
+                match digits.as_str() {
+                    "" => Token::Tilde(None),
+                    d => Token::Tilde(Some(d.parse().map_err(|_| syntax_error(at, "fuzzy distance is too large"))?)),
+                }
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some((_, w)) = chars.peek().filter(|(_, w)| is_word_char(*w)) {
+                    word.push(*w);
+                    chars.next();
+                }
+                match word.as_str() {
+                    "AND" | "&&" => Token::And,
+                    "OR" | "||" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => Token::Word(word),
+                }
+            }
+        };
+        tokens.push((at, token));
+    }
+    Ok(tokens)
+}
+
+/// A recursive descent parser over `or := and (OR? and)*`, `and := not (AND not)*`,
+/// `not := NOT not | term` and `term := '(' or ')' | (word ':')? (word '~'? | phrase | '(' or ')')`
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    /// Where the query string ends, for errors about a query that stops too early
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, t)| t)
+    }
+
+    fn at(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.end, |(at, _)| *at)
+    }
+
+    fn next_is(&mut self, token: &Token) -> bool {
+        if self.peek() == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Alternatives, any of which has to match. A negated term among them isn't an alternative, it
+    /// excludes documents from all of them.
+    fn or(&mut self, field: Option<&str>) -> Result<Query> {
+        let mut groups = vec![self.and(field)?];
+        loop {
+            let implicit = match self.peek() {
+                Some(Token::Word(_)) | Some(Token::Phrase(_)) | Some(Token::Open) | Some(Token::Not) => true,
+                _ => false,
+            };
+            if self.next_is(&Token::Or) || implicit {
+                groups.push(self.and(field)?);
+            } else {
+                break;
+            }
+        }
+        let (excluded, alternatives): (Vec<_>, Vec<_>) = groups.into_iter().partition(|(negated, _)| *negated);
+        let mut should: Vec<Query> = alternatives.into_iter().map(|(_, q)| q).collect();
+        if excluded.is_empty() && should.len() == 1 {
+            return Ok(should.remove(0));
+        }
+        let must_not = excluded.into_iter().map(|(_, q)| q).collect();
+        // Excluding documents only narrows down others, so a query of nothing but exclusions
+        // excludes them from every document
+        let must = if should.is_empty() { vec![Query::All] } else { Vec::new() };
+        Ok(Query::Boolean {
+            bool: BoolQuery::new(must, must_not, should, None, None),
+        })
+    }
+
+    /// Terms that all have to match, and whether it's a single negated term
+    fn and(&mut self, field: Option<&str>) -> Result<(bool, Query)> {
+        let mut clauses = vec![self.not(field)?];
+        while self.next_is(&Token::And) {
+            clauses.push(self.not(field)?);
+        }
+        if clauses.len() == 1 {
+            return Ok(clauses.remove(0));
+        }
+        let (excluded, required): (Vec<_>, Vec<_>) = clauses.into_iter().partition(|(negated, _)| *negated);
+        let mut must: Vec<Query> = required.into_iter().map(|(_, q)| q).collect();
+        if must.is_empty() {
+            must.push(Query::All);
+        }
+        let bool = BoolQuery::new(must, excluded.into_iter().map(|(_, q)| q).collect(), Vec::new(), None, None);
+        Ok((false, Query::Boolean { bool }))
+    }
+
+    /// A term, and whether it's negated
+    fn not(&mut self, field: Option<&str>) -> Result<(bool, Query)> {
+        if self.next_is(&Token::Not) {
+            let (negated, query) = self.not(field)?;
+            return Ok((!negated, query));
+        }
+        self.term(field).map(|q| (false, q))
+    }
+
+    fn term(&mut self, field: Option<&str>) -> Result<Query> {
+        let at = self.at();
+        if self.next_is(&Token::Open) {
+            let query = self.or(field)?;
+            if !self.next_is(&Token::Close) {
+                return Err(syntax_error(self.at(), "missing ')'"));
+            }
+            return Ok(query);
+        }
+        let token = self.tokens.get(self.pos).map(|(_, t)| t.clone());
+        self.pos += 1;
+        match token {
+            Some(Token::Word(word)) if self.next_is(&Token::Colon) => match self.peek() {
+                Some(Token::Word(_)) | Some(Token::Phrase(_)) | Some(Token::Open) => self.term(Some(&word)),
+                _ => Err(syntax_error(self.at(), &format!("expected a term for field '{}'", word))),
+            },
+            Some(Token::Word(word)) => {
+                let field = field.ok_or_else(|| syntax_error(at, &format!("no field given for '{}'", word)))?;
+                match self.peek() {
+                    Some(Token::Tilde(distance)) => {
+                        let distance = distance.unwrap_or(DEFAULT_FUZZY_DISTANCE);
+                        self.pos += 1;
+                        let term = FuzzyTerm::new(word, distance, true);
+                        Ok(Query::Fuzzy(FuzzyQuery::new(KeyValue::new(field.into(), term))))
+                    }
+                    _ => Ok(Query::Exact(ExactTerm::with_term(field, word).with_analyze(true))),
+                }
+            }
+            Some(Token::Phrase(phrase)) => {
+                let field = field.ok_or_else(|| syntax_error(at, &format!("no field given for \"{}\"", phrase)))?;
+                if phrase.trim().is_empty() {
+                    return Err(syntax_error(at, "empty phrase"));
+                }
+                // The field's tokenizer splits the phrase into its terms, searched for in order
+                Ok(Query::Exact(ExactTerm::with_term(field, phrase).with_analyze(true)))
+            }
+            Some(token) => Err(syntax_error(at, &format!("unexpected {:?}", token))),
+            None => Err(syntax_error(at, "query ended early")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(query: &Query) -> serde_json::Value {
+        serde_json::to_value(query).unwrap()
+    }
+
+    fn term(field: &str, value: &str) -> Query {
+        Query::Exact(ExactTerm::with_term(field, value).with_analyze(true))
+    }
+
+    #[test]
+    fn test_query_string() {
+        let parsed = Query::from_query_string(r#"title:fox AND body:quick~ OR "lazy dog" -cat"#, Some("body")).unwrap();
+        let quick = Query::Fuzzy(FuzzyQuery::new(KeyValue::new(
+            "body".into(),
+            FuzzyTerm::new("quick".into(), 2, true),
+        )));
+        // The excluded term narrows down every alternative rather than being one of its own
+        let expected = Query::Boolean {
+            bool: BoolQuery::new(
+                vec![],
+                vec![term("body", "cat")],
+                vec![
+                    Query::Boolean {
+                        bool: BoolQuery::new(vec![term("title", "fox"), quick], vec![], vec![], None, None),
+                    },
+                    term("body", "lazy dog"),
+                ],
+                None,
+                None,
+            ),
+        };
+        assert_eq!(json(&parsed), json(&expected));
+
+        let parsed = Query::from_query_string("fox -cat", Some("body")).unwrap();
+        let expected = Query::Boolean {
+            bool: BoolQuery::new(vec![], vec![term("body", "cat")], vec![term("body", "fox")], None, None),
+        };
+        assert_eq!(json(&parsed), json(&expected));
+
+        let parsed = Query::from_query_string("-cat", Some("body")).unwrap();
+        let expected = Query::Boolean {
+            bool: BoolQuery::new(vec![Query::All], vec![term("body", "cat")], vec![], None, None),
+        };
+        assert_eq!(json(&parsed), json(&expected));
+    }
+
+    #[test]
+    fn test_grouped_fields() {
+        let dog = Query::Fuzzy(FuzzyQuery::new(KeyValue::new(
+            "title".into(),
+            FuzzyTerm::new("dog".into(), 1, true),
+        )));
+        let parsed = Query::from_query_string("title:(fox AND NOT dog~1)", None).unwrap();
+        let expected = Query::Boolean {
+            bool: BoolQuery::new(vec![term("title", "fox")], vec![dog.clone()], vec![], None, None),
+        };
+        assert_eq!(json(&parsed), json(&expected));
+        // Without an operator the NOT clause excludes documents from the terms next to it
+        let parsed = Query::from_query_string("title:(fox NOT dog~1)", None).unwrap();
+        let or_not = Query::Boolean {
+            bool: BoolQuery::new(vec![], vec![dog], vec![term("title", "fox")], None, None),
+        };
+        assert_eq!(json(&parsed), json(&or_not));
+    }
+
+    #[test]
+    fn test_terms_are_analyzed() -> Result<()> {
+        use crate::query::CreateQuery;
+        use tantivy::collector::Count;
+        use tantivy::schema::{SchemaBuilder, TEXT};
+        use tantivy::{doc, Index};
+
+        let mut builder = SchemaBuilder::new();
+        let body = builder.add_text_field("body", TEXT);
+        let schema = builder.build();
+        let index = Index::create_in_ram(schema.clone());
+        let mut writer = index.writer(15_000_000)?;
+        writer.add_document(doc!(body => "The Quick brown fox"));
+        writer.add_document(doc!(body => "brown and quick"));
+        writer.commit()?;
+        let searcher = index.reader()?.searcher();
+        let count = |q: &str| -> Result<usize> {
+            let query = Query::from_query_string(q, Some("body"))?.create_query(&schema)?;
+            Ok(searcher.search(&*query, &Count)?)
+        };
+        // Terms and phrases are lowercased and split the way the field's text was
+        assert_eq!(count("QUICK")?, 2);
+        assert_eq!(count(r#""Quick, Brown""#)?, 1);
+        assert_eq!(count("quick -Fox")?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        let error = |input: &str| match Query::from_query_string(input, Some("body")) {
+            Err(Error::QueryError(e)) => e,
+            other => panic!("{} parsed to {:?}", input, other),
+        };
+        assert_eq!(error("fox AND"), "Syntax error at character 7: query ended early");
+        assert_eq!(error("(fox OR dog"), "Syntax error at character 11: missing ')'");
+        assert_eq!(error(r#"fox "lazy dog"#), "Syntax error at character 4: unclosed phrase");
+        assert_eq!(error("title: fox)"), "Syntax error at character 10: unexpected Close");
+        assert!(Query::from_query_string("fox", None).is_err());
+    }
+}
@@ -2,8 +2,8 @@ use serde::{Deserialize, Serialize};
 use tantivy::query::{Query, RegexQuery as TantivyRegexQuery};
 use tantivy::schema::Schema;
 
-use crate::query::{CreateQuery, KeyValue};
-use crate::{error::Error, Result};
+use crate::query::{text_field, CreateQuery, KeyValue};
+use crate::Result;
 
 /// A search query based around a regular expression
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -25,9 +25,27 @@ impl RegexQuery {
 impl CreateQuery for RegexQuery {
     fn create_query(self, schema: &Schema) -> Result<Box<dyn Query>> {
         let KeyValue { field, value, .. } = self.regex;
-        let field = schema
-            .get_field(&field)
-            .ok_or_else(|| Error::QueryError(format!("Field: {} does not exist", field)))?;
+        let field = text_field(schema, &field, "regex")?;
         Ok(Box::new(TantivyRegexQuery::from_pattern(&value, field)?))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tantivy::schema::*;
+
+    use super::*;
+
+    #[test]
+    fn test_non_text_field() {
+        let body = r#"{ "regex": { "test_u64": "1.*" } }"#;
+        let mut schema = SchemaBuilder::new();
+        schema.add_u64_field("test_u64", INDEXED);
+        let query = serde_json::from_str::<RegexQuery>(body).unwrap().create_query(&schema.build());
+
+        assert_eq!(
+            query.unwrap_err().to_string(),
+            "Error in query execution: 'A regex query only searches text fields, test_u64 isn't one'"
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use crate::query::phrase::TermPair;
+use crate::query::{BoolQuery, PhraseQuery, Query};
+
+impl Query {
+    /// Expand the term queries in this query into every synonym of their term, `synonyms` maps a
+    /// lowercase term to the terms and phrases that mean the same, terms are looked up in it
+    /// whatever their case. A term with synonyms becomes a boolean query matching the term or any
+    /// of them, a synonym of several words is searched for as a phrase in the term's field. The
+    /// words of a raw query are expanded the same way, see [`expand_raw`].
+    pub fn expand_synonyms(self, synonyms: &HashMap<String, Vec<String>>) -> Self {
+        if synonyms.is_empty() {
+            return self;
+        }
+        match self {
+            Query::Exact(term) => {
+                let (field, value) = term.term();
+                let field = field.to_string();
+                let alternatives = match synonyms.get(&value.to_lowercase()) {
+                    Some(alternatives) => alternatives,
+                    None => return Query::Exact(term),
+                };
+                let mut should = Vec::with_capacity(alternatives.len() + 1);
+                for alternative in alternatives {
+                    let mut words: Vec<String> = alternative.split_whitespace().map(String::from).collect();
+                    match words.len() {
+                        0 => (),
+                        1 => should.push(Query::Exact(term.sibling(words.remove(0)))),
+                        _ => should.push(Query::Phrase(PhraseQuery::with_phrase(field.clone(), TermPair::new(words, None)))),
+                    }
+                }
+                should.insert(0, Query::Exact(term));
+                Query::Boolean {
+                    bool: BoolQuery::new(Vec::new(), Vec::new(), should, None, None),
+                }
+            }
+            Query::Boolean { bool } => Query::Boolean {
+                bool: bool.map(|q| q.expand_synonyms(synonyms)),
+            },
+            Query::HasChild { has_child } => Query::HasChild {
+                has_child: has_child.map(|q| q.expand_synonyms(synonyms)),
+            },
+            Query::HasParent { has_parent } => Query::HasParent {
+                has_parent: has_parent.map(|q| q.expand_synonyms(synonyms)),
+            },
+            Query::Raw { raw } => Query::Raw {
+                raw: expand_raw(&raw, synonyms),
+            },
+            q => q,
+        }
+    }
+}
+
+/// Characters that end a word of a raw query
+const RAW_SPECIAL: &[char] = &['(', ')', '"', '[', ']', '{', '}'];
+
+/// Replace every word of the raw query `raw` that has synonyms with a group matching the word or
+/// any of them, in the word's field when it names one, `+nyc` becoming `+(nyc OR "new york city")`.
+/// Phrases and ranges are left as they are.
+fn expand_raw(raw: &str, synonyms: &HashMap<String, Vec<String>>) -> String {
+    let mut expanded = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(c) = rest.chars().next() {
+        let end = match c {
+            '"' => rest[1..].find('"').map_or(rest.len(), |i| i + 2),
+            '[' | '{' => rest.find(|c| c == ']' || c == '}').map_or(rest.len(), |i| i + 1),
+            c if c.is_whitespace() || RAW_SPECIAL.contains(&c) => c.len_utf8(),
+            _ => {
+                let end = rest
+                    .find(|c: char| c.is_whitespace() || RAW_SPECIAL.contains(&c))
+                    .unwrap_or_else(|| rest.len());
+                expand_raw_word(&rest[..end], synonyms, &mut expanded);
+                rest = &rest[end..];
+                continue;
+            }
+        };
+        expanded.push_str(&rest[..end]);
+        rest = &rest[end..];
+    }
+    expanded
+}
+
+/// Write `word` to `expanded`, grouped with its synonyms if it has any
+fn expand_raw_word(word: &str, synonyms: &HashMap<String, Vec<String>>, expanded: &mut String) {
+    let term = word.trim_start_matches(|c| c == '+' || c == '-');
+    let occur = &word[..word.len() - term.len()];
+    // A field name is a letter followed by letters, digits or underscores
+    let (field, value) = match term.find(':') {
+        Some(i)
+            if term[..i].chars().next().map_or(false, char::is_alphabetic)
+                && term[..i].chars().all(|c| c.is_alphanumeric() || c == '_') =>
+        {
+            term.split_at(i + 1)
+        }
+        _ => ("", term),
+    };
+    let alternatives = match synonyms.get(&value.to_lowercase()) {
+        Some(alternatives) if !value.is_empty() && !["AND", "OR", "NOT"].contains(&value) => alternatives,
+        _ => {
+            expanded.push_str(word);
+            return;
+        }
+    };
+    let mut group = vec![format!("{}{}", field, value)];
+    for alternative in alternatives {
+        let words: Vec<&str> = alternative.split_whitespace().collect();
+        match words.len() {
+            0 => (),
+            1 => group.push(format!("{}{}", field, words[0])),
+            _ => group.push(format!("{}\"{}\"", field, words.join(" "))),
+        }
+    }
+    expanded.push_str(&format!("{}({})", occur, group.join(" OR ")));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::query::ExactTerm;
+
+    use super::*;
+
+    #[test]
+    fn test_expand_synonyms() {
+        let mut synonyms = HashMap::new();
+        synonyms.insert("nyc".to_string(), vec!["new york city".to_string(), "ny".to_string()]);
+        let query = Query::Boolean {
+            bool: BoolQuery::new(
+                vec![Query::Exact(ExactTerm::with_term("city", "nyc"))],
+                vec![Query::Exact(ExactTerm::with_term("city", "boston"))],
+                vec![],
+                None,
+                None,
+            ),
+        };
+        let expanded = serde_json::to_value(query.expand_synonyms(&synonyms)).unwrap();
+        let expected = serde_json::json!({ "bool": {
+            "must": [{ "bool": {
+                "must": [],
+                "must_not": [],
+                "should": [
+                    { "term": { "city": "nyc" } },
+                    { "phrase": { "city": { "terms": ["new", "york", "city"] } } },
+                    { "term": { "city": "ny" } }
+                ],
+                "minimum_should_match": null,
+                "boost": null
+            } }],
+            "must_not": [{ "term": { "city": "boston" } }],
+            "should": [],
+            "minimum_should_match": null,
+            "boost": null
+        } });
+        assert_eq!(expanded, expected);
+
+        // Terms are looked up whatever their case
+        let upper = serde_json::to_value(Query::Exact(ExactTerm::with_term("city", "NYC")).expand_synonyms(&synonyms)).unwrap();
+        assert_eq!(upper["bool"]["should"].as_array().map(Vec::len), Some(3));
+    }
+
+    #[test]
+    fn test_expand_raw_synonyms() {
+        let mut synonyms = HashMap::new();
+        synonyms.insert("nyc".to_string(), vec!["new york city".to_string(), "ny".to_string()]);
+        let expand = |raw: &str| {
+            let query = Query::Raw { raw: raw.into() };
+            match query.expand_synonyms(&synonyms) {
+                Query::Raw { raw } => raw,
+                other => panic!("Expected a raw query, got {:?}", other),
+            }
+        };
+        assert_eq!(expand("nyc"), r#"(nyc OR "new york city" OR ny)"#);
+        assert_eq!(
+            expand("+city:NYC -boston"),
+            r#"+(city:NYC OR city:"new york city" OR city:ny) -boston"#
+        );
+        assert_eq!(expand("(a OR nyc)"), r#"(a OR (nyc OR "new york city" OR ny))"#);
+        // Phrases, ranges and operators are left alone
+        for raw in &[r#""moving to nyc""#, "city:[nyc TO ny]", "a AND b", "boston"] {
+            assert_eq!(expand(raw), *raw);
+        }
+    }
+}
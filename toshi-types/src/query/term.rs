@@ -1,22 +1,35 @@
 use std::fmt;
 
 use serde::{Deserialize, Serialize};
-use tantivy::query::{Query, TermQuery};
-use tantivy::schema::{IndexRecordOption, Schema};
+use tantivy::query::{PhraseQuery, Query, TermQuery};
+use tantivy::schema::{FieldType, IndexRecordOption, Schema};
 
 use crate::query::*;
-use crate::Result;
+use crate::{Result, KEYWORD_SUFFIX};
 
 /// An exact term to search for
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ExactTerm {
     term: KeyValue<String, String>,
+    /// Whether the term has to match with the same case. Searching case sensitively needs a field
+    /// indexed untokenized, the field itself or its keyword field, and searching case insensitively
+    /// a field whose tokenizer lowercases. Left out, the term is searched for as it's given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    case_sensitive: Option<bool>,
+    /// Whether the term is run through the field's tokenizer first, like the text of an indexed
+    /// document is. Text the tokenizer splits into several terms is searched for as a phrase of them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    analyze: Option<bool>,
 }
 
 impl ExactTerm {
     /// Constructor with a known KeyValue
     pub fn new(term: KeyValue<String, String>) -> Self {
-        Self { term }
+        Self {
+            term,
+            case_sensitive: None,
+            analyze: None,
+        }
     }
 
     /// Constructor to create the key value for the user
@@ -27,14 +40,143 @@ impl ExactTerm {
     {
         Self {
             term: KeyValue::new(field.to_string(), value.to_string()),
+            case_sensitive: None,
+            analyze: None,
         }
     }
+
+    /// Set whether the term has to match with the same case
+    pub fn with_case_sensitive(mut self, case_sensitive: bool) -> Self {
+        self.case_sensitive = Some(case_sensitive);
+        self
+    }
+
+    /// Set whether the term is run through the field's tokenizer first
+    pub fn with_analyze(mut self, analyze: bool) -> Self {
+        self.analyze = Some(analyze);
+        self
+    }
+}
+
+impl ExactTerm {
+    /// The field searched and the term searched for in it
+    pub(crate) fn term(&self) -> (&str, &str) {
+        (&self.term.field, &self.term.value)
+    }
+
+    /// The same kind of term query for another term in the same field
+    pub(crate) fn sibling(&self, value: String) -> Self {
+        Self {
+            term: KeyValue::new(self.term.field.clone(), value),
+            case_sensitive: self.case_sensitive,
+            analyze: self.analyze,
+        }
+    }
+
+    /// The query for the terms the field's tokenizer splits the term into
+    fn create_analyzed(self, schema: &Schema) -> Result<Box<dyn Query>> {
+        let KeyValue { field, value, .. } = self.term;
+        if self.case_sensitive.is_some() {
+            return Err(Error::QueryError(format!(
+                "The term '{}' can't be analyzed and searched by case, its case is the tokenizer's",
+                value
+            )));
+        }
+        let mut terms = analyze(schema, &field, &value)?
+            .iter()
+            .map(|t| make_field_value(schema, &field, t))
+            .collect::<Result<Vec<_>>>()?;
+        match terms.len() {
+            0 => Err(Error::QueryError(format!("'{}' has no terms to search {} for", value, field))),
+            1 => Ok(Box::new(TermQuery::new(terms.remove(0), IndexRecordOption::Basic))),
+            _ => Ok(Box::new(PhraseQuery::new(terms))),
+        }
+    }
+}
+
+/// Whether `field` is a text field indexed untokenized, so its terms keep their case
+pub(crate) fn is_raw(schema: &Schema, field: &str) -> bool {
+    let entry = match schema.get_field(field) {
+        Some(f) => schema.get_field_entry(f),
+        None => return false,
+    };
+    match entry.field_type() {
+        FieldType::Str(options) => options.get_indexing_options().map_or(false, |i| i.tokenizer() == "raw"),
+        _ => false,
+    }
 }
 
 impl CreateQuery for ExactTerm {
     fn create_query(self, schema: &Schema) -> Result<Box<dyn Query>> {
+        if self.analyze == Some(true) {
+            return self.create_analyzed(schema);
+        }
         let KeyValue { field, value, .. } = self.term;
+        let (field, value) = match self.case_sensitive {
+            None => (field, value),
+            Some(true) if is_raw(schema, &field) => (field, value),
+            Some(true) => {
+                let keyword = format!("{}{}", field, KEYWORD_SUFFIX);
+                if !is_raw(schema, &keyword) {
+                    return Err(Error::QueryError(format!(
+                        "Field {} can't be searched case sensitively, it has no keyword field",
+                        field
+                    )));
+                }
+                (keyword, value)
+            }
+            Some(false) if is_raw(schema, &field) => {
+                return Err(Error::QueryError(format!(
+                    "Field {} can't be searched case insensitively, it isn't lowercased",
+                    field
+                )));
+            }
+            Some(false) => (field, value.to_lowercase()),
+        };
         let term = make_field_value(schema, &field, &value)?;
         Ok(Box::new(TermQuery::new(term, IndexRecordOption::Basic)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tantivy::collector::Count;
+    use tantivy::schema::{SchemaBuilder, STRING, TEXT};
+    use tantivy::{doc, Index};
+
+    use super::*;
+
+    #[test]
+    fn test_case_sensitive() -> Result<()> {
+        let mut builder = SchemaBuilder::new();
+        let title = builder.add_text_field("title", TEXT);
+        let keyword = builder.add_text_field("title.keyword", STRING);
+        let body = builder.add_text_field("body", TEXT);
+        let schema = builder.build();
+        let index = Index::create_in_ram(schema.clone());
+        let mut writer = index.writer(15_000_000)?;
+        writer.add_document(doc!(title => "Apple", keyword => "Apple", body => "Apple"));
+        writer.add_document(doc!(title => "apple", keyword => "apple", body => "apple"));
+        writer.commit()?;
+        let searcher = index.reader()?.searcher();
+        let count = |term: ExactTerm| -> Result<usize> {
+            let query = term.create_query(&schema)?;
+            Ok(searcher.search(&*query, &Count)?)
+        };
+
+        assert_eq!(count(ExactTerm::with_term("title", "Apple").with_case_sensitive(true))?, 1);
+        assert_eq!(count(ExactTerm::with_term("title", "Apple").with_case_sensitive(false))?, 2);
+        assert_eq!(count(ExactTerm::with_term("title.keyword", "Apple").with_case_sensitive(true))?, 1);
+        // Without the option the term is searched for as it's given, the tokenizer lowercased it
+        assert_eq!(count(ExactTerm::with_term("title", "Apple"))?, 0);
+        assert!(count(ExactTerm::with_term("body", "Apple").with_case_sensitive(true)).is_err());
+        assert!(count(ExactTerm::with_term("title.keyword", "Apple").with_case_sensitive(false)).is_err());
+
+        let parsed: crate::Query = serde_json::from_str(r#"{ "term": { "title": "Apple" }, "case_sensitive": true }"#)?;
+        match parsed {
+            crate::Query::Exact(term) => assert_eq!(count(term)?, 1),
+            q => panic!("Parsed as {:?}", q),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use tantivy::query::{BooleanQuery, Query};
+use tantivy::schema::Schema;
+
+use crate::error::Error;
+use crate::query::{make_field_value, CreateQuery, KeyValue};
+use crate::Result;
+
+/// Matches documents where a field contains any of a list of exact terms, multi valued fields
+/// match when any one of their values is in the list
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TermsQuery {
+    terms: KeyValue<String, Vec<String>>,
+}
+
+impl TermsQuery {
+    /// Constructor with a known KeyValue
+    pub fn new(terms: KeyValue<String, Vec<String>>) -> Self {
+        Self { terms }
+    }
+
+    /// Constructor to create the key value for the user
+    pub fn with_terms<K, V>(field: K, values: Vec<V>) -> Self
+    where
+        K: fmt::Display,
+        V: fmt::Display,
+    {
+        Self {
+            terms: KeyValue::new(field.to_string(), values.iter().map(ToString::to_string).collect()),
+        }
+    }
+}
+
+impl CreateQuery for TermsQuery {
+    fn create_query(self, schema: &Schema) -> Result<Box<dyn Query>> {
+        let KeyValue { field, value } = self.terms;
+        if value.is_empty() {
+            return Err(Error::QueryError(format!("Terms query on {} needs at least one term", field)));
+        }
+        let terms = value
+            .iter()
+            .map(|v| make_field_value(schema, &field, v))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Box::new(BooleanQuery::new_multiterms_query(terms)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::schema::{SchemaBuilder, TEXT};
+
+    use super::*;
+    use crate::query::Query;
+
+    #[test]
+    fn test_terms_query() {
+        let mut builder = SchemaBuilder::new();
+        builder.add_text_field("tags", TEXT);
+        let schema = builder.build();
+
+        let query: Query = serde_json::from_str(r#"{ "terms": { "tags": ["a", "b"] } }"#).unwrap();
+        match query {
+            Query::Terms(terms) => assert!(terms.create_query(&schema).is_ok()),
+            q => panic!("Parsed as the wrong query: {:?}", q),
+        }
+
+        let empty = TermsQuery::with_terms("tags", Vec::<String>::new()).create_query(&schema);
+        assert_eq!(
+            empty.unwrap_err().to_string(),
+            "Error in query execution: 'Terms query on tags needs at least one term'"
+        );
+    }
+}
@@ -1,17 +1,177 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt::Formatter;
 
 use serde::{Deserialize, Serialize};
-use std::fmt::Formatter;
-use tantivy::schema::Schema;
+use tantivy::schema::{Cardinality, IndexRecordOption, IntOptions, Schema, SchemaBuilder, TextFieldIndexing, TextOptions};
+
+use crate::{Error, ErrorCode, KeyValue, Query};
+
+/// The name of the u64 fast field an index uses to version its documents. Indexes whose schema
+/// contain this field get optimistic concurrency control on keyed inserts.
+pub const VERSION_FIELD: &str = "_version";
+
+/// The name of the u64 field an index uses to tombstone documents. In an index whose schema has
+/// this field, indexed and fast, deletes only mark documents with the time they were deleted and
+/// hide them from searches until they are purged.
+pub const TOMBSTONE_FIELD: &str = "_deleted";
+
+/// The name of the text field a document's id is kept in, a child document refers to its parent
+/// by this id. Indexes whose schema has this field and [`PARENT_FIELD`], both indexed and stored,
+/// can be searched with `has_child` and `has_parent` queries.
+pub const ID_FIELD: &str = "_id";
+
+/// The name of the text field a child document keeps the [`ID_FIELD`] of its parent in, parents
+/// and their children are kept in the same index
+pub const PARENT_FIELD: &str = "_parent";
+
+/// The name of the u64 field an index stamps every document written to it with a number that only
+/// goes up. In an index whose schema has this field, indexed and fast, the documents committed after
+/// a number can be read from `_changes`.
+pub const SEQUENCE_FIELD: &str = "_seq";
+
+/// The suffix of the field a text field declared with `keyword` also indexes its values in whole,
+/// untokenized, so `title.keyword` matches only the exact title
+pub const KEYWORD_SUFFIX: &str = ".keyword";
 
 /// In a delete query, this is returned indicating the number of documents that were removed
 /// by the delete.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DocsAffected {
-    /// The number of documents removed by the query
+    /// The number of documents removed, or changed by an update, by the query
     pub docs_affected: u64,
 }
 
+/// The response from purging the soft deleted documents of an index
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct PurgeResponse {
+    /// The number of documents removed for good
+    pub purged: u64,
+}
+
+/// The request body for copying every stored document of one index into another
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReindexRequest {
+    /// The index to copy the documents from
+    pub source: String,
+    /// The most documents a second to copy, so a large reindex doesn't take all of the disk away
+    /// from searches. Unset copies as fast as it can.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requests_per_second: Option<f32>,
+}
+
+/// The request body for moving a routing partition of an index to another of its shards
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct MovePartition {
+    /// The shard to hold the partition from now on
+    pub shard: usize,
+}
+
+/// The response from a reindex that ran to completion before responding
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct ReindexResponse {
+    /// The number of documents copied
+    pub reindexed: u64,
+}
+
+/// A document committed to an index after the number a `_changes` request asked for
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChangedDoc {
+    /// The document's [`SEQUENCE_FIELD`] number
+    pub seq: u64,
+    /// Whether the document is the tombstone of a soft deleted document
+    pub deleted: bool,
+    /// The stored fields of the document
+    pub doc: BTreeMap<String, Vec<serde_json::Value>>,
+}
+
+/// The documents committed to an index after a number, lowest number first
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChangesResponse {
+    /// The documents, up to the limit the request asked for
+    pub changes: Vec<ChangedDoc>,
+    /// The number to ask for the changes after next time, the highest one returned or the one asked
+    /// for when there were no changes
+    pub watermark: u64,
+}
+
+/// The response from creating an index from a sample document
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InferredSchema {
+    /// The type each field of the index was given
+    pub fields: BTreeMap<String, String>,
+    /// Why fields were mapped as text for lack of a type to go by
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+/// The request body for fetching documents by their [`ID_FIELD`] all at once
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MgetRequest {
+    /// The ids of the documents to fetch
+    pub ids: Vec<String>,
+}
+
+/// A document fetched by its id, `doc` is left out when no document has the id
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MgetDoc {
+    /// The id that was asked for
+    pub id: String,
+    /// Whether a document with the id was found
+    pub found: bool,
+    /// The stored fields of the document
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub doc: Option<BTreeMap<String, Vec<serde_json::Value>>>,
+}
+
+/// The response from fetching documents by id, in the order their ids were asked for
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MgetResponse {
+    /// A document for every id asked for
+    pub docs: Vec<MgetDoc>,
+}
+
+/// The response from validating a search without running it, `error` and `explanation` say what's
+/// wrong with a search that isn't valid
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ValidationResponse {
+    /// Whether the search could be run against the index
+    pub valid: bool,
+    /// The code of the error running the search would fail with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorCode>,
+    /// The message of the error running the search would fail with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub explanation: Option<String>,
+}
+
+impl ValidationResponse {
+    /// A search that can be run
+    pub fn valid() -> Self {
+        Self {
+            valid: true,
+            error: None,
+            explanation: None,
+        }
+    }
+
+    /// A search that running would fail with `err`
+    pub fn invalid(err: &Error) -> Self {
+        Self {
+            valid: false,
+            error: Some(err.code()),
+            explanation: Some(err.to_string()),
+        }
+    }
+}
+
+/// The response from starting a task with `async=true`, its status is at `/_tasks/{task}`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct TaskStarted {
+    /// The id of the task
+    pub task: u64,
+}
+
 /// Indicates whether or not a commit should be done at the end of a document insert, the default
 /// is false
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -28,12 +188,237 @@ pub struct AddDocument<D> {
     pub options: Option<IndexOptions>,
     /// The actual document to insert
     pub document: D,
+    /// The term identifying the document, when set any existing document with this term is replaced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<KeyValue<String, String>>,
+    /// Only replace the document if its stored version matches this one, requires a key and a
+    /// [`VERSION_FIELD`] in the index's schema
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub if_version: Option<u64>,
+}
+
+impl<D> AddDocument<D> {
+    /// Construct an unkeyed insert of a document
+    pub fn new(options: Option<IndexOptions>, document: D) -> Self {
+        Self {
+            options,
+            document,
+            key: None,
+            if_version: None,
+        }
+    }
 }
 
 /// A wrapper around Tantivy's schema for when an index is created. [`tantivy::schema::Schema`]
+/// It is read from a list of [`FieldSpec`] so every option of a field can be left out.
 #[derive(Serialize, Deserialize, Clone)]
+#[serde(try_from = "Vec<FieldSpec>")]
 pub struct SchemaBody(pub Schema);
 
+/// A single field of a [`SchemaBody`]. This accepts Tantivy's own format for a schema's fields, but
+/// none of the options are required and text fields can set `record` and `tokenizer` directly
+/// instead of through `indexing`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct FieldSpec {
+    /// The name of the field
+    pub name: String,
+    /// One of `text`, `u64`, `i64`, `f64`, `date`, `bool`, `hierarchical_facet` or `bytes`. Tantivy
+    /// has no boolean fields, a `bool` field is a `u64` field holding 0 or 1.
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// How the field is stored and indexed
+    #[serde(default)]
+    pub options: FieldOptions,
+}
+
+/// The options of a [`FieldSpec`]. Fields are only stored when `stored` is set. Numeric and date
+/// fields are only indexed when `indexed` is set, text fields are indexed when `indexed` is set or
+/// any of its indexing options are given.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct FieldOptions {
+    /// Whether the field's values are returned with a document
+    pub stored: Option<bool>,
+    /// Whether the field can be searched
+    pub indexed: Option<bool>,
+    /// Whether a numeric or date field is a fast field, either `single` or `multi` valued
+    pub fast: Option<Cardinality>,
+    /// Tantivy's indexing options for a text field
+    pub indexing: Option<TextFieldIndexing>,
+    /// How much is recorded about a text field's terms, `basic`, `freq` or `position`
+    pub record: Option<IndexRecordOption>,
+    /// The tokenizer a text field is indexed with
+    pub tokenizer: Option<String>,
+    /// Whether a text field's length counts toward its score, turning norms off suits fields that
+    /// are matched exactly. Norms of an existing field can only be changed by reindexing.
+    pub norms: Option<bool>,
+    /// Also index a text field's values untokenized in a field named with [`KEYWORD_SUFFIX`], the
+    /// values are copied into it when documents are added
+    pub keyword: Option<bool>,
+    /// Whether a text field's values are given as the tokens they're indexed as instead of text
+    /// for the field's tokenizer to analyze, a pre-tokenized field records positions by default
+    pub pre_tokenized: Option<bool>,
+    /// Values longer than this many characters are stored but not indexed, for a text field indexed
+    /// whole with the `raw` tokenizer or the keyword field of a text field
+    pub ignore_above: Option<usize>,
+}
+
+impl FieldSpec {
+    fn has_keyword(&self) -> bool {
+        self.options.keyword.unwrap_or(false)
+    }
+
+    /// Whether the field's values are given as tokens
+    pub fn is_pre_tokenized(&self) -> bool {
+        self.options.pre_tokenized.unwrap_or(false)
+    }
+
+    fn keyword_name(&self) -> String {
+        format!("{}{}", self.name, KEYWORD_SUFFIX)
+    }
+
+    /// The field whose values are only indexed up to a length and that length, the keyword field
+    /// when the field has one
+    pub fn ignore_above(&self) -> Option<(String, usize)> {
+        let name = if self.has_keyword() {
+            self.keyword_name()
+        } else {
+            self.name.clone()
+        };
+        self.options.ignore_above.map(|limit| (name, limit))
+    }
+
+    fn add_to(&self, builder: &mut SchemaBuilder, default_tokenizer: Option<&str>) -> Result<(), String> {
+        let FieldOptions {
+            stored,
+            indexed,
+            fast,
+            indexing,
+            record,
+            tokenizer,
+            norms,
+            keyword,
+            pre_tokenized,
+            ignore_above,
+        } = &self.options;
+        let stored = stored.unwrap_or(false);
+        let text_indexing = indexing.is_some() || record.is_some() || tokenizer.is_some() || norms.is_some() || pre_tokenized.is_some();
+        if text_indexing && self.kind != "text" {
+            return Err(format!("Field '{}': indexing options only apply to text fields", self.name));
+        }
+        if keyword.is_some() && self.kind != "text" {
+            return Err(format!("Field '{}': only text fields can have a keyword field", self.name));
+        }
+        if self.is_pre_tokenized() && self.has_keyword() {
+            return Err(format!("Field '{}': a pre-tokenized field can't have a keyword field", self.name));
+        }
+        let raw = tokenizer.as_deref().or_else(|| indexing.as_ref().map(TextFieldIndexing::tokenizer)) == Some("raw");
+        if ignore_above.is_some() && (self.kind != "text" || self.is_pre_tokenized() || !(raw || self.has_keyword())) {
+            return Err(format!(
+                "Field '{}': ignore_above only applies to text fields indexed with the raw tokenizer or with a keyword field",
+                self.name
+            ));
+        }
+
+        match self.kind.as_str() {
+            "text" => {
+                if fast.is_some() {
+                    return Err(format!("Field '{}': text fields can't be fast fields", self.name));
+                }
+                let indexed = indexed.unwrap_or(text_indexing);
+                if !indexed && text_indexing {
+                    return Err(format!("Field '{}': indexing options were given but indexed is false", self.name));
+                }
+                if !indexed && !stored {
+                    return Err(format!("Field '{}' is neither stored nor indexed", self.name));
+                }
+                let mut options = TextOptions::default();
+                if stored {
+                    options = options.set_stored();
+                }
+                if indexed {
+                    let mut text = indexing.clone().unwrap_or_default();
+                    if self.is_pre_tokenized() && indexing.is_none() {
+                        text = text.set_index_option(IndexRecordOption::WithFreqsAndPositions);
+                    }
+                    if let Some(record) = record {
+                        text = text.set_index_option(*record);
+                    }
+                    // Indexing options carry a tokenizer of their own, and pre-tokenized fields aren't analyzed
+                    let default_tokenizer = default_tokenizer.filter(|_| indexing.is_none() && !self.is_pre_tokenized());
+                    if let Some(tokenizer) = tokenizer.as_deref().or(default_tokenizer) {
+                        text = text.set_tokenizer(tokenizer);
+                    }
+                    options = options.set_indexing_options(text);
+                }
+                builder.add_text_field(&self.name, options);
+                if self.has_keyword() {
+                    let raw = TextFieldIndexing::default()
+                        .set_tokenizer("raw")
+                        .set_index_option(IndexRecordOption::Basic);
+                    builder.add_text_field(&self.keyword_name(), TextOptions::default().set_indexing_options(raw));
+                }
+            }
+            "u64" | "i64" | "f64" | "date" | "bool" => {
+                let indexed = indexed.unwrap_or(false);
+                if !indexed && !stored && fast.is_none() {
+                    return Err(format!("Field '{}' is neither stored, indexed nor fast", self.name));
+                }
+                let mut options = IntOptions::default();
+                if stored {
+                    options = options.set_stored();
+                }
+                if indexed {
+                    options = options.set_indexed();
+                }
+                if let Some(cardinality) = fast {
+                    options = options.set_fast(*cardinality);
+                }
+                match self.kind.as_str() {
+                    "u64" | "bool" => builder.add_u64_field(&self.name, options),
+                    "i64" => builder.add_i64_field(&self.name, options),
+                    "f64" => builder.add_f64_field(&self.name, options),
+                    _ => builder.add_date_field(&self.name, options),
+                };
+            }
+            "hierarchical_facet" => {
+                builder.add_facet_field(&self.name);
+            }
+            "bytes" => {
+                builder.add_bytes_field(&self.name);
+            }
+            kind => return Err(format!("Field '{}' has an unknown type: {}", self.name, kind)),
+        }
+        Ok(())
+    }
+}
+
+impl SchemaBody {
+    /// Build a schema from its fields, text fields that don't name a tokenizer are indexed with
+    /// `default_tokenizer` instead of tantivy's `default` when it's given
+    pub fn from_fields(fields: &[FieldSpec], default_tokenizer: Option<&str>) -> Result<Self, String> {
+        let mut builder = SchemaBuilder::new();
+        let mut names = HashSet::new();
+        for field in fields {
+            let keyword = Some(field.keyword_name()).filter(|_| field.has_keyword());
+            for name in std::iter::once(field.name.clone()).chain(keyword) {
+                if !names.insert(name) {
+                    return Err(format!("Field '{}' is defined more than once", field.name));
+                }
+            }
+            field.add_to(&mut builder, default_tokenizer)?;
+        }
+        Ok(SchemaBody(builder.build()))
+    }
+}
+
+impl TryFrom<Vec<FieldSpec>> for SchemaBody {
+    type Error = String;
+
+    fn try_from(fields: Vec<FieldSpec>) -> Result<Self, Self::Error> {
+        SchemaBody::from_fields(&fields, None)
+    }
+}
+
 impl std::fmt::Debug for SchemaBody {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
         f.write_str("Schema {\n")?;
@@ -54,3 +439,83 @@ pub struct DeleteDoc {
     /// KeyValue like a lot of other queries do that only accept a single term pair at a time
     pub terms: HashMap<String, String>,
 }
+
+/// The request body for changing every document a query matches in place
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UpdateByQuery {
+    /// Options surrounding the update [`IndexOptions`]
+    #[serde(default)]
+    pub options: Option<IndexOptions>,
+    /// The documents to change
+    pub query: Query,
+    /// The values to give each field of the documents, replacing the values they had for it
+    pub set: serde_json::Map<String, serde_json::Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field_options() {
+        let body: SchemaBody = serde_json::from_str(
+            r#"[
+                { "name": "tantivy", "type": "text", "options": { "indexing": { "record": "position", "tokenizer": "default" }, "stored": true } },
+                { "name": "stored_only", "type": "text", "options": { "stored": true } },
+                { "name": "freqs", "type": "text", "options": { "record": "freq" } },
+                { "name": "exact", "type": "text", "options": { "stored": true, "norms": false } },
+                { "name": "fast_only", "type": "u64", "options": { "fast": "single" } },
+                { "name": "tags", "type": "hierarchical_facet" },
+                { "name": "flag", "type": "bool", "options": { "stored": true, "indexed": true } },
+                { "name": "title", "type": "text", "options": { "stored": true, "keyword": true } },
+                { "name": "tokens", "type": "text", "options": { "pre_tokenized": true } },
+                { "name": "sku", "type": "text", "options": { "stored": true, "tokenizer": "raw", "ignore_above": 32 } }
+            ]"#,
+        )
+        .unwrap();
+        let schema = body.0;
+        let options = |name: &str| match schema.get_field_entry(schema.get_field(name).unwrap()).field_type() {
+            tantivy::schema::FieldType::Str(options) => options.clone(),
+            _ => panic!("{} is not a text field", name),
+        };
+        assert!(options("tantivy").is_stored());
+        assert!(options("stored_only").get_indexing_options().is_none());
+        let freqs = options("freqs");
+        assert!(!freqs.is_stored());
+        assert_eq!(freqs.get_indexing_options().unwrap().index_option(), IndexRecordOption::WithFreqs);
+        assert!(options("exact").get_indexing_options().is_some());
+        assert!(schema.get_field_entry(schema.get_field("fast_only").unwrap()).is_int_fast());
+        let flag = schema.get_field_entry(schema.get_field("flag").unwrap());
+        assert!(flag.is_indexed() && flag.is_stored());
+        match flag.field_type() {
+            tantivy::schema::FieldType::U64(_) => (),
+            other => panic!("flag is a {:?} field", other),
+        }
+        let keyword = options("title.keyword");
+        assert!(!keyword.is_stored());
+        assert_eq!(keyword.get_indexing_options().unwrap().tokenizer(), "raw");
+        let tokens = options("tokens");
+        assert_eq!(
+            tokens.get_indexing_options().unwrap().index_option(),
+            IndexRecordOption::WithFreqsAndPositions
+        );
+
+        for bad in &[
+            r#"[{ "name": "nothing", "type": "text" }]"#,
+            r#"[{ "name": "nothing", "type": "i64", "options": { "stored": false, "indexed": false } }]"#,
+            r#"[{ "name": "contradiction", "type": "text", "options": { "indexed": false, "record": "basic" } }]"#,
+            r#"[{ "name": "fast_text", "type": "text", "options": { "stored": true, "fast": "single" } }]"#,
+            r#"[{ "name": "unknown", "type": "uuid", "options": { "stored": true } }]"#,
+            r#"[{ "name": "count", "type": "u64", "options": { "stored": true, "norms": false } }]"#,
+            r#"[{ "name": "twice", "type": "u64", "options": { "stored": true } }, { "name": "twice", "type": "u64", "options": { "stored": true } }]"#,
+            r#"[{ "name": "n", "type": "u64", "options": { "stored": true, "keyword": true } }]"#,
+            r#"[{ "name": "t", "type": "text", "options": { "stored": true, "keyword": true } }, { "name": "t.keyword", "type": "text", "options": { "stored": true } }]"#,
+            r#"[{ "name": "n", "type": "u64", "options": { "stored": true, "pre_tokenized": true } }]"#,
+            r#"[{ "name": "t", "type": "text", "options": { "keyword": true, "pre_tokenized": true } }]"#,
+            r#"[{ "name": "t", "type": "text", "options": { "stored": true, "ignore_above": 10 } }]"#,
+            r#"[{ "name": "n", "type": "u64", "options": { "stored": true, "ignore_above": 10 } }]"#,
+        ] {
+            assert!(serde_json::from_str::<SchemaBody>(bad).is_err(), "{} should not parse", bad);
+        }
+    }
+}
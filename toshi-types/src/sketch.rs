@@ -0,0 +1,372 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+/// How finely a [`TDigest`] keeps the values it has seen, it keeps at most a few times this many centroids
+const COMPRESSION: f64 = 100.0;
+
+/// A t-digest, a summary of a stream of numbers that percentiles can be estimated from in a
+/// bounded amount of memory. Values are kept as centroids, a mean and how many values it stands
+/// for, and centroids are only merged while they stay small next to how far their values are
+/// from the median, so the tails stay precise. Until there are enough values for any to be
+/// merged every value is its own centroid and the percentiles are exact.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct TDigest {
+    /// The mean of each centroid and how many values it stands for, in order of their means
+    centroids: Vec<(f64, f64)>,
+    /// Values added since the centroids were last compressed, a digest is always compressed
+    /// before it's sent anywhere
+    #[serde(skip)]
+    buffer: Vec<f64>,
+    count: u64,
+    min: f64,
+    max: f64,
+}
+
+impl TDigest {
+    /// Add a value to the digest, NaNs are skipped
+    pub fn add(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        if self.count == 0 || value < self.min {
+            self.min = value;
+        }
+        if self.count == 0 || value > self.max {
+            self.max = value;
+        }
+        self.count += 1;
+        self.buffer.push(value);
+        if self.buffer.len() >= COMPRESSION as usize * 10 {
+            self.compress();
+        }
+    }
+
+    /// How many values have been added
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Combine two digests into one of the values added to either
+    pub fn merge(mut self, other: TDigest) -> Self {
+        if other.count == 0 {
+            return self;
+        }
+        if self.count == 0 {
+            return other;
+        }
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.count += other.count;
+        self.centroids.extend(other.centroids);
+        self.buffer.extend(other.buffer);
+        self.compress();
+        self
+    }
+
+    /// Merge neighbouring centroids, and the values not yet made centroids, while each stays under
+    /// the most values a centroid at its place in the distribution may stand for
+    pub(crate) fn compress(&mut self) {
+        let mut all = std::mem::replace(&mut self.centroids, Vec::new());
+        all.extend(self.buffer.drain(..).map(|v| (v, 1.0)));
+        all.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        let total = self.count as f64;
+        let mut before = 0.0;
+        for (mean, weight) in all {
+            if let Some((last_mean, last_weight)) = self.centroids.last_mut() {
+                let merged = *last_weight + weight;
+                let q = (before + merged / 2.0) / total;
+                if merged <= (4.0 * total * q * (1.0 - q) / COMPRESSION).max(1.0) {
+                    *last_mean += (mean - *last_mean) * weight / merged;
+                    *last_weight = merged;
+                    continue;
+                }
+                before += *last_weight;
+            }
+            self.centroids.push((mean, weight));
+        }
+    }
+
+    /// Estimate the value below which `percent` of the values fall, interpolating between the
+    /// centroids the way the exact percentile interpolates between the two values nearest it
+    pub fn percentile(&mut self, percent: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        if !self.buffer.is_empty() {
+            self.compress();
+        }
+        let last = (self.count - 1) as f64;
+        let rank = percent / 100.0 * last;
+        // Each centroid sits at the middle rank of the values it stands for, with the smallest and
+        // largest values pinned to the first and last ranks
+        let mut points = vec![(0.0, self.min)];
+        let mut before = 0.0;
+        for (mean, weight) in &self.centroids {
+            points.push((before + (weight - 1.0) / 2.0, *mean));
+            before += weight;
+        }
+        points.push((last, self.max));
+        let value = points
+            .windows(2)
+            .find(|w| rank <= w[1].0)
+            .map(|w| {
+                let ((r0, v0), (r1, v1)) = (w[0], w[1]);
+                if r1 > r0 {
+                    v0 + (v1 - v0) * (rank - r0) / (r1 - r0)
+                } else {
+                    v1
+                }
+            })
+            .unwrap_or(self.max);
+        Some(value)
+    }
+}
+
+/// A HyperLogLog, a count of distinct values in a fixed amount of memory. Each value's hash picks
+/// a register with its first `precision` bits, and the register keeps the longest run of leading
+/// zeros seen in the rest, from which the number of distinct hashes can be estimated. Until the
+/// hashes seen would take more memory than the registers they're kept as they are and counted exactly.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HyperLogLog {
+    precision: u8,
+    /// The hashes of the values, while they're still counted exactly
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exact: Option<HashSet<u64>>,
+    /// The longest run of leading zeros in each register, once the values are estimated
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    /// An empty count that estimates with `precision` bits of each hash once it has too many values to count exactly
+    pub fn new(precision: u8) -> Self {
+        Self {
+            precision,
+            exact: Some(HashSet::new()),
+            registers: Vec::new(),
+        }
+    }
+
+    /// Count a value by its bits, like a u64 or the bits of an f64
+    pub fn insert(&mut self, value: u64) {
+        let hash = mix(value);
+        match &mut self.exact {
+            Some(hashes) => {
+                hashes.insert(hash);
+            }
+            None => self.insert_hash(hash),
+        }
+        if self.too_many() {
+            self.to_registers();
+        }
+    }
+
+    /// Whether the hashes kept to count exactly take more memory than the registers would, each
+    /// one takes 8 bytes where a register takes 1
+    fn too_many(&self) -> bool {
+        self.exact.as_ref().map_or(false, |hashes| hashes.len() * 8 > 1 << self.precision)
+    }
+
+    fn insert_hash(&mut self, hash: u64) {
+        let register = (hash >> (64 - self.precision)) as usize;
+        // The marker bit caps the run at the number of bits left after the register's
+        let rest = (hash << self.precision) | (1 << (self.precision - 1));
+        let zeros = rest.leading_zeros() as u8 + 1;
+        if zeros > self.registers[register] {
+            self.registers[register] = zeros;
+        }
+    }
+
+    fn to_registers(&mut self) {
+        if let Some(hashes) = self.exact.take() {
+            self.registers = vec![0; 1 << self.precision];
+            for hash in hashes {
+                self.insert_hash(hash);
+            }
+        }
+    }
+
+    /// Combine two counts of the same precision into a count of the values added to either
+    pub fn merge(mut self, mut other: HyperLogLog) -> Self {
+        // Counts of different precisions don't share registers, and only come from different searches
+        if self.precision != other.precision {
+            return self;
+        }
+        match (self.exact.take(), other.exact.take()) {
+            (Some(mut hashes), Some(others)) => {
+                hashes.extend(others);
+                self.exact = Some(hashes);
+                if self.too_many() {
+                    self.to_registers();
+                }
+            }
+            (hashes, others) => {
+                self.exact = hashes;
+                other.exact = others;
+                self.to_registers();
+                other.to_registers();
+                for (register, theirs) in self.registers.iter_mut().zip(other.registers) {
+                    *register = (*register).max(theirs);
+                }
+            }
+        }
+        self
+    }
+
+    /// The number of distinct values added
+    pub fn count(&self) -> u64 {
+        if let Some(hashes) = &self.exact {
+            return hashes.len() as u64;
+        }
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let sum: f64 = self.registers.iter().map(|r| 2f64.powi(-i32::from(*r))).sum();
+        let estimate = alpha * m * m / sum;
+        let empty = self.registers.iter().filter(|r| **r == 0).count();
+        // Small counts leave registers empty, and counting those is more accurate than the estimate
+        if estimate <= 2.5 * m && empty > 0 {
+            (m * (m / empty as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+}
+
+/// Spread the bits of a value over its hash, so values that differ in only a few bits, like
+/// consecutive ids, land in unrelated registers
+fn mix(mut x: u64) -> u64 {
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    x ^ (x >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AggregationResult;
+
+    fn digest<I: IntoIterator<Item = f64>>(values: I) -> TDigest {
+        let mut digest = TDigest::default();
+        for value in values {
+            digest.add(value);
+        }
+        digest
+    }
+
+    #[test]
+    fn test_exact_percentiles() {
+        let mut small = digest(vec![15.0, 20.0, 35.0, 40.0, 50.0]);
+        assert_eq!(small.percentile(0.0), Some(15.0));
+        assert_eq!(small.percentile(50.0), Some(35.0));
+        assert_eq!(small.percentile(100.0), Some(50.0));
+        // Between the 2nd and 3rd values, like the exact percentile
+        assert!((small.percentile(40.0).unwrap() - 29.0).abs() < 1e-9);
+        assert_eq!(digest(vec![7.0]).percentile(99.0), Some(7.0));
+        assert_eq!(TDigest::default().percentile(50.0), None);
+    }
+
+    #[test]
+    fn test_approximate_percentiles() {
+        // 1 to 100,000 in a scrambled order, split over several digests as it would be over segments
+        let values: Vec<f64> = (0..100_000u64).map(|i| ((i * 7_919) % 100_000 + 1) as f64).collect();
+        let mut merged = values
+            .chunks(30_000)
+            .map(|c| digest(c.to_vec()))
+            .fold(TDigest::default(), TDigest::merge);
+        assert_eq!(merged.count(), 100_000);
+        assert!(merged.centroids.len() < 1_000);
+        for (percent, exact) in &[(50.0, 50_000.5), (95.0, 95_000.05), (99.0, 99_000.01)] {
+            let estimate = merged.percentile(*percent).unwrap();
+            assert!((estimate - exact).abs() / exact < 0.01, "p{} was {}", percent, estimate);
+        }
+        assert_eq!(merged.percentile(0.0), Some(1.0));
+        assert_eq!(merged.percentile(100.0), Some(100_000.0));
+    }
+
+    #[test]
+    fn test_percentiles_merge_by_digest() -> Result<(), serde_json::Error> {
+        // Each search's result makes the trip to the node merging them as JSON
+        let result = |values: std::ops::RangeInclusive<u64>| {
+            let result = AggregationResult::percentiles(digest(values.map(|v| v as f64)), vec![90.0]);
+            serde_json::from_value::<AggregationResult>(serde_json::to_value(result)?)
+        };
+        match result(1..=50)?.merge(result(51..=100)?) {
+            AggregationResult::Percentiles { values, count, .. } => {
+                assert_eq!(count, 100);
+                assert!((values[0].value.unwrap() - 90.1).abs() < 1e-9, "{:?}", values);
+            }
+            other => panic!("Expected percentiles, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    fn distinct(precision: u8, values: std::ops::Range<u64>) -> HyperLogLog {
+        let mut count = HyperLogLog::new(precision);
+        for value in values {
+            count.insert(value);
+            count.insert(value);
+        }
+        count
+    }
+
+    #[test]
+    fn test_exact_distinct_count() {
+        let count = distinct(14, 0..2_000);
+        assert!(count.exact.is_some());
+        assert_eq!(count.count(), 2_000);
+        assert_eq!(HyperLogLog::new(14).count(), 0);
+        assert_eq!(distinct(14, 0..1_000).merge(distinct(14, 500..1_500)).count(), 1_500);
+    }
+
+    #[test]
+    fn test_estimated_distinct_count() {
+        for precision in &[10u8, 14] {
+            let standard_error = 1.04 / f64::from(1u32 << precision).sqrt();
+            for total in &[5_000u64, 300_000] {
+                // Split over several counts as values would be over segments, with some counted in two
+                let count = (0..4u64)
+                    .map(|i| distinct(*precision, i * total / 4..((i + 1) * total / 4 + 100).min(*total)))
+                    .fold(HyperLogLog::new(*precision), HyperLogLog::merge);
+                assert!(count.exact.is_none());
+                let error = (count.count() as f64 - *total as f64).abs() / *total as f64;
+                assert!(
+                    error < 4.0 * standard_error,
+                    "{} of {} at precision {}",
+                    count.count(),
+                    total,
+                    precision
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_distinct_counts_merge_by_registers() -> Result<(), serde_json::Error> {
+        let result = |values: std::ops::Range<u64>| {
+            let count = distinct(10, values);
+            let result = AggregationResult::Cardinality {
+                value: count.count(),
+                hll: Some(count),
+            };
+            serde_json::from_value::<AggregationResult>(serde_json::to_value(result)?)
+        };
+        // Two searches that saw different values count more than either did, exactly and estimated
+        for (left, right, total) in &[(0..100, 50..150, 150.0), (0..20_000, 10_000..40_000, 40_000.0)] {
+            match result(left.clone())?.merge(result(right.clone())?) {
+                AggregationResult::Cardinality { value, .. } => {
+                    assert!((value as f64 - total).abs() / total < 0.1, "{} of {}", value, total);
+                }
+                other => panic!("Expected a distinct count, got {:?}", other),
+            }
+        }
+        Ok(())
+    }
+}